@@ -0,0 +1,93 @@
+//! Reads the documents of files to import, and the schemas of the indexes they create
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::Context;
+use docatlas_core::document::Document;
+use docatlas_core::fields::FieldKind;
+use docatlas_core::schema::{Schema, SchemaField};
+
+/// Reads the documents of a file, which holds either a json array of documents or a json
+/// document per line
+pub fn read_documents(path: &Path) -> anyhow::Result<Vec<Document>> {
+    let text =
+        fs::read_to_string(path).with_context(|| format!("could not read {}", path.display()))?;
+    parse_documents(&text).with_context(|| format!("could not parse {}", path.display()))
+}
+
+/// Parses either a json array of documents or a json document per line. Blank lines are skipped.
+pub fn parse_documents(text: &str) -> anyhow::Result<Vec<Document>> {
+    if text.trim_start().starts_with('[') {
+        return Ok(serde_json::from_str(text)?);
+    }
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line)
+                .with_context(|| format!("invalid document on line {}", i + 1))
+        })
+        .collect()
+}
+
+/// Parses the kind given to a field, written as `name=kind`
+pub fn parse_field_kind(text: &str) -> anyhow::Result<(String, FieldKind)> {
+    let (name, kind) = text
+        .split_once('=')
+        .context("expected a field written as name=kind")?;
+    Ok((name.trim().to_string(), kind.trim().parse()?))
+}
+
+/// Gives fields of an inferred schema other kinds, adding the fields it does not have
+pub fn override_kinds(schema: &mut Schema, kinds: impl IntoIterator<Item = (String, FieldKind)>) {
+    for (name, kind) in kinds {
+        match schema.get_mut(&name) {
+            Some(field) => field.kind = kind,
+            None => schema.extend([SchemaField { name, kind }]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use docatlas_core::fields::{Field, FieldData, FieldKind, Fields};
+
+    use super::*;
+
+    fn document(id: &str) -> String {
+        let mut fields = Fields::new();
+        let data = FieldData::Bytes(id.as_bytes().into());
+        fields.insert("id", Field::new(FieldKind::Keyword(8), [data]));
+        serde_json::to_string(&Document::from(fields)).unwrap()
+    }
+
+    #[test]
+    fn arrays_and_lines() {
+        let lines = format!("{}\n\n{}\n", document("1"), document("2"));
+        assert_eq!(parse_documents(&lines).unwrap().len(), 2);
+        let array = format!("[{}, {}]", document("1"), document("2"));
+        assert_eq!(parse_documents(&array).unwrap().len(), 2);
+
+        let error = parse_documents(&format!("{}\nnot json", document("1"))).unwrap_err();
+        assert_eq!(error.to_string(), "invalid document on line 2");
+    }
+
+    #[test]
+    fn override_inferred_kinds() {
+        let mut schema = Schema::from_iter([SchemaField {
+            name: "year".to_string(),
+            kind: FieldKind::I64,
+        }]);
+        let kinds = ["year = keyword:8", "isbn=keyword:16"]
+            .into_iter()
+            .map(parse_field_kind)
+            .collect::<anyhow::Result<Vec<_>>>()
+            .unwrap();
+        override_kinds(&mut schema, kinds);
+        assert_eq!(schema["year"].kind, FieldKind::Keyword(8));
+        assert_eq!(schema["isbn"].kind, FieldKind::Keyword(16));
+        assert!(parse_field_kind("year").is_err());
+        assert!(parse_field_kind("year=date").is_err());
+    }
+}
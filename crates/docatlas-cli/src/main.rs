@@ -0,0 +1,1004 @@
+//! The command line client of docatlas, which speaks the binary protocol of the daemon.
+//!
+//! ```text
+//! docatlas index create books --schema books.json
+//! docatlas import books books.jsonl
+//! docatlas import books export.ndjson --format json-lines --create
+//! docatlas import books books.csv --format csv --create --field isbn=keyword:16
+//! docatlas export books books.parquet --field title --query dune
+//! docatlas export books books.csv --fields id,title,author.name
+//! docatlas search books title "dune"
+//! docatlas changes books --kinds added,updated --query "year:[1960 TO *]"
+//! docatlas admin snapshot create backups nightly
+//! ```
+//!
+//! Every response is printed as json.
+
+use std::fs;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use clap::{Parser, Subcommand, ValueEnum};
+use docatlas_client::{Client, ClientBuilder, ClientError, Cursor, Index, Query};
+use docatlas_core::document::Document;
+use docatlas_core::error::DocatlasError;
+use docatlas_core::export::csv::CsvWriter;
+use docatlas_core::export::json_lines::JsonLinesWriter;
+use docatlas_core::export::parquet::ParquetWriter;
+use docatlas_core::export::{json_value, DocumentWriter};
+use docatlas_core::fields::FieldData;
+use docatlas_core::fields::FieldKind;
+use docatlas_core::import::csv::{self, Csv, CsvOptions, MalformedRows};
+use docatlas_core::import::json_lines::{self, JsonLines};
+use docatlas_core::import::parquet::{self, Parquet};
+use docatlas_core::import::{ImportSummary, Record};
+use docatlas_core::persist::{Checker, Fix};
+use docatlas_core::query::string::Operator;
+use docatlas_core::schema::Schema;
+use docatlas_core::sql::Table;
+use docatlas_daemon::audit::AuditQuery;
+use docatlas_daemon::changes::{ChangeFilter, ChangeKind};
+use docatlas_daemon::client::{ClientRequest, ClientResponse, Secret};
+use docatlas_daemon::config::DEFAULT_PORT;
+use docatlas_daemon::handlers;
+use docatlas_daemon::pid_file::{self, PID_FILE};
+
+mod import;
+
+/// A client of the docatlas daemon
+#[derive(Debug, Parser)]
+#[clap(version, about)]
+struct Cli {
+    /// The address of the daemon
+    #[clap(long, short, default_value_t = format!("localhost:{DEFAULT_PORT}"))]
+    connect: String,
+    /// The user to authenticate as
+    #[clap(long, short, requires = "password")]
+    user: Option<String>,
+    /// The password of the user
+    #[clap(long, requires = "user")]
+    password: Option<String>,
+    /// A token to authenticate with instead of a user
+    #[clap(long, conflicts_with = "user")]
+    token: Option<String>,
+    /// How long the request may run, such as `5s`. The daemon caps this by its own maximum.
+    #[clap(long, global = true)]
+    timeout: Option<humantime::Duration>,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+impl Cli {
+    /// Configures a client of the daemon, with the credentials passed on the command line
+    fn client(&self) -> ClientBuilder {
+        let builder = Client::builder(&self.connect);
+        match (&self.user, &self.password, &self.token) {
+            (Some(username), Some(password), _) => builder.with_basic_auth(username, password),
+            (_, _, Some(token)) => builder.with_token(token),
+            _ => builder,
+        }
+    }
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Creates, drops and lists indexes
+    #[clap(subcommand)]
+    Index(IndexCommand),
+    /// Gets and pushes the schemas of indexes
+    #[clap(subcommand)]
+    Schema(SchemaCommand),
+    /// Imports a file of documents, or of records such as json objects, delimited values or
+    /// parquet rows, converted according to the schema of the index
+    Import {
+        index: String,
+        file: PathBuf,
+        /// The number of documents sent in a single request
+        #[clap(long, default_value_t = 1000)]
+        batch_size: usize,
+        #[clap(long, value_enum, default_value_t = ImportFormat::Documents)]
+        format: ImportFormat,
+        /// Creates the index if it does not exist, with a schema inferred from the first records
+        /// of the file. Files of documents can not be used to create an index.
+        #[clap(long)]
+        create: bool,
+        /// The number of records the schema of a created index is inferred from
+        #[clap(long, default_value_t = 1000)]
+        sample: usize,
+        /// The primary key of a created index
+        #[clap(long, requires = "create")]
+        primary_key: Option<String>,
+        /// Gives a field of a created index a kind other than the inferred one, written like
+        /// `isbn=keyword:16`. May be given more than once.
+        #[clap(long = "field", value_parser = import::parse_field_kind, requires = "create")]
+        fields: Vec<(String, FieldKind)>,
+        /// The character separating the cells of csv and tsv files, instead of a comma or a tab
+        #[clap(long)]
+        delimiter: Option<char>,
+        /// The first row of csv and tsv files holds values rather than the names of fields, which
+        /// are named `column_1`, `column_2` and so on instead
+        #[clap(long)]
+        no_headers: bool,
+        /// What is done with rows of csv and tsv files that are malformed
+        #[clap(long, value_enum, default_value_t = Malformed::Report)]
+        malformed: Malformed,
+    },
+    /// Exports the documents of an index, or the documents found by a search, into a file
+    Export {
+        index: String,
+        file: PathBuf,
+        /// The format of the file, guessed from its extension if not given
+        #[clap(long, value_enum)]
+        format: Option<ExportFormat>,
+        /// The fields exported, separated by commas, instead of every field of the schema
+        #[clap(long, value_delimiter = ',')]
+        fields: Vec<String>,
+        /// Only exports the documents whose field contains every term of `--query`
+        #[clap(long, requires = "query")]
+        field: Option<String>,
+        #[clap(long, requires = "field")]
+        query: Option<String>,
+        /// The most documents a search exports, instead of every document it finds
+        #[clap(long, requires = "query")]
+        limit: Option<usize>,
+        /// The number of documents read in a single request
+        #[clap(long, default_value_t = 1000)]
+        batch_size: usize,
+    },
+    /// Upserts a document given as json
+    Put {
+        index: String,
+        document: String,
+        /// Only updates the fields present in the document if the document already exists
+        #[clap(long)]
+        partial: bool,
+    },
+    /// Gets a document by its primary key
+    Get { index: String, key: String },
+    /// Deletes a document by its primary key
+    Delete { index: String, key: String },
+    /// Finds the documents whose field contains every term of a query
+    Search {
+        index: String,
+        field: String,
+        query: String,
+        #[clap(long, default_value_t = 10)]
+        limit: usize,
+        /// Prints the hits found so far if the search times out, instead of failing
+        #[clap(long)]
+        partial: bool,
+    },
+    /// Finds the documents matching a query string, such as `title:dune AND year:[1960 TO *]`
+    Query {
+        index: String,
+        query: String,
+        /// The field searched by terms written without one
+        #[clap(long)]
+        default_field: Option<String>,
+        /// How clauses written next to each other are joined
+        #[clap(long, value_enum, default_value_t = DefaultOperator::Or)]
+        default_operator: DefaultOperator,
+        #[clap(long, default_value_t = 10)]
+        limit: usize,
+    },
+    /// Runs a sql `SELECT` statement, printing the names of its columns and then every row as a
+    /// json array
+    Sql { statement: String },
+    /// Prints every change made to the documents of an index as a json line, until the log of
+    /// the index starts again
+    Changes {
+        index: String,
+        /// Resumes after the change with this sequence number, instead of starting with the next
+        /// change
+        #[clap(long)]
+        after: Option<u64>,
+        /// Only prints changes of these kinds, separated by commas
+        #[clap(long, value_enum, value_delimiter = ',')]
+        kinds: Vec<Change>,
+        /// Only prints the documents matching a query string, which must name the field of every
+        /// term. Deletions are printed whatever the query.
+        #[clap(long)]
+        query: Option<String>,
+    },
+    /// Changes the password of the user given by `--user` and `--password`
+    Passwd {
+        /// The new password
+        #[clap(long)]
+        new_password: String,
+    },
+    /// Administers the daemon
+    #[clap(subcommand)]
+    Admin(AdminCommand),
+    /// Checks the integrity of data directories while the daemon is stopped, printing every
+    /// problem found as json. Fails if a problem was not fixed. Nothing is fixed in a directory
+    /// while a running daemon holds its pid file.
+    Check {
+        /// The data directories, such as the path of the daemon
+        #[clap(required = true)]
+        paths: Vec<PathBuf>,
+        /// Cuts logs before their first broken frame and removes orphaned files
+        #[clap(long, conflicts_with = "quarantine")]
+        repair: bool,
+        /// Repairs logs after copying them into `quarantine` within the data directory, and moves
+        /// orphaned and corrupt files into it
+        #[clap(long)]
+        quarantine: bool,
+    },
+}
+
+/// The format of a file to import
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ImportFormat {
+    /// Documents as printed by `docatlas get`, in a json array or one per line
+    Documents,
+    /// A json object per line, converted according to the schema of the index
+    JsonLines,
+    /// Comma separated values, converted according to the schema of the index
+    Csv,
+    /// Tab separated values, converted according to the schema of the index
+    Tsv,
+    /// A parquet file, whose columns are converted according to the schema of the index
+    Parquet,
+}
+
+/// The format of an exported file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum ExportFormat {
+    /// A json object per line, with nested fields written under their dotted names
+    JsonLines,
+    /// Comma separated values, with a header row naming the fields
+    Csv,
+    /// Tab separated values, with a header row naming the fields
+    Tsv,
+    /// A parquet file, with a column for every field
+    Parquet,
+}
+
+impl ExportFormat {
+    /// Guesses the format of a file from its extension, defaulting to json lines
+    fn of(file: &Path) -> Self {
+        let extension = file.extension().and_then(|extension| extension.to_str());
+        match extension.map(str::to_ascii_lowercase).as_deref() {
+            Some("csv") => ExportFormat::Csv,
+            Some("tsv") => ExportFormat::Tsv,
+            Some("parquet") => ExportFormat::Parquet,
+            _ => ExportFormat::JsonLines,
+        }
+    }
+}
+
+/// How clauses of a query string written next to each other are joined
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum DefaultOperator {
+    /// Documents must match any of the clauses
+    Or,
+    /// Documents must match every clause
+    And,
+}
+
+impl From<DefaultOperator> for Operator {
+    fn from(value: DefaultOperator) -> Self {
+        match value {
+            DefaultOperator::Or => Operator::Or,
+            DefaultOperator::And => Operator::And,
+        }
+    }
+}
+
+/// What happened to a document
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Change {
+    Added,
+    Updated,
+    Deleted,
+}
+
+impl From<Change> for ChangeKind {
+    fn from(value: Change) -> Self {
+        match value {
+            Change::Added => ChangeKind::Added,
+            Change::Updated => ChangeKind::Updated,
+            Change::Deleted => ChangeKind::Deleted,
+        }
+    }
+}
+
+/// What is done with malformed rows of csv and tsv files
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Malformed {
+    /// Reports the row as failed, and carries on
+    Report,
+    /// Skips the row without reporting it
+    Skip,
+    /// Stops the import at the row
+    Abort,
+}
+
+impl From<Malformed> for MalformedRows {
+    fn from(value: Malformed) -> Self {
+        match value {
+            Malformed::Report => MalformedRows::Report,
+            Malformed::Skip => MalformedRows::Skip,
+            Malformed::Abort => MalformedRows::Abort,
+        }
+    }
+}
+
+/// How an index that does not exist yet is created by an import
+#[derive(Debug)]
+struct CreateIndex {
+    /// The number of records its schema is inferred from
+    sample: usize,
+    primary_key: Option<String>,
+    /// The kinds given to fields instead of the inferred ones
+    fields: Vec<(String, FieldKind)>,
+}
+
+#[derive(Debug, Subcommand)]
+enum IndexCommand {
+    /// Creates an empty index
+    Create {
+        index: String,
+        /// A json file holding the schema of the index
+        #[clap(long)]
+        schema: PathBuf,
+    },
+    /// Drops an index and every document in it
+    Drop { index: String },
+    /// Lists the names of every index
+    List,
+    /// Gets a summary of an index, or of every index
+    Stats { index: Option<String> },
+}
+
+#[derive(Debug, Subcommand)]
+enum SchemaCommand {
+    /// Gets the schema of an index
+    Get { index: String },
+    /// Sets the schema of an index from a json file, creating the index if it does not exist
+    Put { index: String, file: PathBuf },
+}
+
+#[derive(Debug, Subcommand)]
+enum AdminCommand {
+    /// Checks whether the daemon is ready to serve requests
+    Health,
+    /// Gets the status of the cluster, and of every node in it
+    Cluster,
+    /// Gets how much of every directory indexes are stored in is used
+    Paths,
+    /// Stops following the primary, so the replica accepts changes
+    Promote,
+    /// Ends every session of a user, who must authenticate again
+    RevokeSessions { user: String },
+    /// Adds, removes and lists users, and resets their passwords
+    #[clap(subcommand)]
+    User(UserCommand),
+    /// Creates, changes and lists groups of users
+    #[clap(subcommand)]
+    Group(GroupCommand),
+    /// Takes, restores and lists snapshots
+    #[clap(subcommand)]
+    Snapshot(SnapshotCommand),
+    /// Exports entries of the audit log as json lines, the oldest first
+    Audit {
+        /// Only entries after this sequence number
+        #[clap(long)]
+        after: Option<u64>,
+        /// Only entries of this user
+        #[clap(long)]
+        user: Option<String>,
+        #[clap(long)]
+        limit: Option<usize>,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum UserCommand {
+    /// Adds a user authenticated by a password
+    Create {
+        user: String,
+        #[clap(long)]
+        new_password: String,
+    },
+    /// Replaces the password of a user, ending their sessions
+    ResetPassword {
+        user: String,
+        #[clap(long)]
+        new_password: String,
+    },
+    /// Removes a user, ending their sessions
+    Delete { user: String },
+    /// Lists every user whose password is stored
+    List,
+}
+
+#[derive(Debug, Subcommand)]
+enum GroupCommand {
+    /// Creates a group without members or roles
+    Create { group: String },
+    /// Deletes a group, whose members lose its roles
+    Delete { group: String },
+    /// Adds a user to a group
+    AddMember { group: String, user: String },
+    /// Removes a user from a group
+    RemoveMember { group: String, user: String },
+    /// Replaces the roles given to every member of a group
+    SetRoles { group: String, roles: Vec<String> },
+    /// Lists every group, with their members and roles
+    List,
+}
+
+#[derive(Debug, Subcommand)]
+enum SnapshotCommand {
+    /// Takes a snapshot of every index
+    Create {
+        repository: String,
+        snapshot: String,
+    },
+    /// Restores every index of a snapshot
+    Restore {
+        repository: String,
+        snapshot: String,
+    },
+    /// Lists the snapshots in a repository
+    List { repository: String },
+}
+
+fn main() -> Result<(), anyhow::Error> {
+    let cli = Cli::parse();
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(cli))
+}
+
+async fn run(cli: Cli) -> anyhow::Result<()> {
+    if let Command::Check {
+        paths,
+        repair,
+        quarantine,
+    } = &cli.command
+    {
+        let fix = match (repair, quarantine) {
+            (_, true) => Some(Fix::Quarantine),
+            (true, _) => Some(Fix::Repair),
+            _ => None,
+        };
+        return check(paths, fix);
+    }
+    let client = cli
+        .client()
+        .connect()
+        .await
+        .with_context(|| format!("could not connect to the daemon at {}", cli.connect))?;
+    let partial = matches!(cli.command, Command::Search { partial: true, .. });
+    let request = match cli.command {
+        Command::Index(command) => match command {
+            IndexCommand::Create { index, schema } => ClientRequest::CreateIndex {
+                index,
+                schema: read_schema(&schema)?,
+            },
+            IndexCommand::Drop { index } => ClientRequest::DeleteIndex { index },
+            IndexCommand::List => ClientRequest::ListIndexes,
+            IndexCommand::Stats { index } => ClientRequest::Stats { index },
+        },
+        Command::Schema(command) => match command {
+            SchemaCommand::Get { index } => ClientRequest::GetMapping { index },
+            SchemaCommand::Put { index, file } => ClientRequest::PutSchema {
+                index,
+                schema: read_schema(&file)?,
+            },
+        },
+        Command::Import {
+            index,
+            file,
+            batch_size,
+            format: ImportFormat::Documents,
+            ..
+        } => return import(&client, &index, &file, batch_size).await,
+        Command::Import {
+            index,
+            file,
+            batch_size,
+            format,
+            create,
+            sample,
+            primary_key,
+            fields,
+            delimiter,
+            no_headers,
+            malformed,
+        } => {
+            let index = client.index(index);
+            let create = create.then_some(CreateIndex {
+                sample,
+                primary_key,
+                fields,
+            });
+            match format {
+                ImportFormat::JsonLines => {
+                    return import_json_lines(&index, &file, batch_size, create).await
+                }
+                ImportFormat::Parquet => {
+                    return import_parquet(&index, &file, batch_size, create).await
+                }
+                _ => {}
+            }
+            let mut options = match format {
+                ImportFormat::Tsv => CsvOptions::tsv(),
+                _ => CsvOptions::new(),
+            };
+            if let Some(delimiter) = delimiter {
+                options = options.with_delimiter(delimiter);
+            }
+            let options = options
+                .with_headers(!no_headers)
+                .with_malformed_rows(malformed.into());
+            return import_csv(&index, &file, batch_size, options, create).await;
+        }
+        Command::Export {
+            index,
+            file,
+            format,
+            fields,
+            field,
+            query,
+            limit,
+            batch_size,
+        } => {
+            let format = format.unwrap_or_else(|| ExportFormat::of(&file));
+            let index = client.index(index);
+            let mut cursor = index.cursor(batch_size);
+            if let Some((field, query)) = field.zip(query) {
+                let limit = limit.unwrap_or(usize::MAX);
+                cursor = cursor.with_query(Query::new(field, query).with_limit(limit));
+            }
+            return export_documents(&index, &file, format, fields, cursor).await;
+        }
+        Command::Put {
+            index,
+            document,
+            partial,
+        } => ClientRequest::IndexDocument {
+            index,
+            document: serde_json::from_str::<Document>(&document).context("invalid document")?,
+            partial,
+        },
+        Command::Get { index, key } => ClientRequest::Get {
+            key: parse_key(&client, &index, &key).await?,
+            index,
+        },
+        Command::Delete { index, key } => ClientRequest::Delete {
+            key: parse_key(&client, &index, &key).await?,
+            index,
+        },
+        Command::Search {
+            index,
+            field,
+            query,
+            limit,
+            ..
+        } => ClientRequest::Search {
+            index,
+            field,
+            query,
+            limit,
+        },
+        Command::Query {
+            index,
+            query,
+            default_field,
+            default_operator,
+            limit,
+        } => ClientRequest::QueryString {
+            index,
+            query,
+            default_field,
+            default_operator: default_operator.into(),
+            limit,
+        },
+        Command::Sql { statement } => ClientRequest::Sql { query: statement },
+        Command::Changes {
+            index,
+            after,
+            kinds,
+            query,
+        } => {
+            let filter = ChangeFilter {
+                kinds: kinds.into_iter().map(Into::into).collect(),
+                query,
+            };
+            return changes(&client.index(index), after, filter).await;
+        }
+        Command::Passwd { new_password } => {
+            let current = cli.password.context("the current password is required")?;
+            client.change_password(current, new_password).await?;
+            return Ok(());
+        }
+        Command::Check { .. } => unreachable!("data directories are checked without the daemon"),
+        Command::Admin(command) => match command {
+            AdminCommand::Health => ClientRequest::Health,
+            AdminCommand::Cluster => ClientRequest::ClusterHealth,
+            AdminCommand::Paths => ClientRequest::PathStats,
+            AdminCommand::Promote => ClientRequest::Promote,
+            AdminCommand::RevokeSessions { user } => ClientRequest::RevokeSessions { user },
+            AdminCommand::User(command) => match command {
+                UserCommand::Create { user, new_password } => ClientRequest::CreateUser {
+                    user,
+                    password: Secret::new(new_password),
+                },
+                UserCommand::ResetPassword { user, new_password } => ClientRequest::ResetPassword {
+                    user,
+                    password: Secret::new(new_password),
+                },
+                UserCommand::Delete { user } => ClientRequest::DeleteUser { user },
+                UserCommand::List => ClientRequest::ListUsers,
+            },
+            AdminCommand::Group(command) => match command {
+                GroupCommand::Create { group } => ClientRequest::CreateGroup { group },
+                GroupCommand::Delete { group } => ClientRequest::DeleteGroup { group },
+                GroupCommand::AddMember { group, user } => {
+                    ClientRequest::AddGroupMember { group, user }
+                }
+                GroupCommand::RemoveMember { group, user } => {
+                    ClientRequest::RemoveGroupMember { group, user }
+                }
+                GroupCommand::SetRoles { group, roles } => {
+                    ClientRequest::SetGroupRoles { group, roles }
+                }
+                GroupCommand::List => ClientRequest::ListGroups,
+            },
+            AdminCommand::Audit { after, user, limit } => {
+                let query = AuditQuery { after, user, limit };
+                return audit(&client, query).await;
+            }
+            AdminCommand::Snapshot(command) => match command {
+                SnapshotCommand::Create {
+                    repository,
+                    snapshot,
+                } => ClientRequest::CreateSnapshot {
+                    repository,
+                    snapshot,
+                },
+                SnapshotCommand::Restore {
+                    repository,
+                    snapshot,
+                } => ClientRequest::RestoreSnapshot {
+                    repository,
+                    snapshot,
+                },
+                SnapshotCommand::List { repository } => ClientRequest::ListSnapshots { repository },
+            },
+        },
+    };
+    let request = match (cli.timeout, partial) {
+        (None, false) => request,
+        (timeout, partial) => ClientRequest::Timed {
+            timeout_ms: timeout.map_or(u64::MAX, |timeout| timeout.as_millis() as u64),
+            partial,
+            request: Box::new(request),
+        },
+    };
+    match client.request(request).await? {
+        ClientResponse::Table { table } => print_table(&table),
+        response => print(&response),
+    }
+}
+
+/// Checks every data directory, fixing the problems found if asked to. Directories are only
+/// fixed if no running daemon uses them, as it could be writing to the files being fixed.
+fn check(paths: &[PathBuf], fix: Option<Fix>) -> anyhow::Result<()> {
+    let checker = match fix {
+        Some(fix) => {
+            for path in paths {
+                pid_file::check(path.join(PID_FILE))
+                    .with_context(|| format!("will not fix {path:?}"))?;
+            }
+            Checker::new().with_fix(fix)
+        }
+        None => Checker::new(),
+    };
+    let mut unfixed = 0;
+    for path in paths {
+        let report = checker
+            .check(path)
+            .with_context(|| format!("could not check {path:?}"))?;
+        unfixed += report.unfixed().count();
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    }
+    if unfixed > 0 {
+        anyhow::bail!("{unfixed} problems were not fixed");
+    }
+    Ok(())
+}
+
+/// Imports every document of a file in batches, printing how many documents were added
+async fn import(
+    client: &Client,
+    index: &str,
+    file: &Path,
+    batch_size: usize,
+) -> anyhow::Result<()> {
+    let documents = import::read_documents(file)?;
+    let summary = client
+        .index(index)
+        .bulk_batched(documents, batch_size)
+        .await?;
+    for error in &summary.failed {
+        eprintln!("could not add document: {error}");
+    }
+    println!("{}", serde_json::to_string_pretty(&summary)?);
+    Ok(())
+}
+
+/// Imports every record of a json lines file in batches. Indexes are created with a schema
+/// inferred from the file if `create` is given.
+async fn import_json_lines(
+    index: &Index,
+    file: &Path,
+    batch_size: usize,
+    create: Option<CreateIndex>,
+) -> anyhow::Result<()> {
+    let schema = import_schema(index, create, |sample| {
+        Ok(json_lines::infer_schema(open_import(file)?, sample)?)
+    })
+    .await?;
+    let summary = import_records(
+        index,
+        JsonLines::new(open_import(file)?, schema),
+        batch_size,
+    )
+    .await?;
+    println!("{}", serde_json::to_string_pretty(&summary)?);
+    Ok(())
+}
+
+/// Imports every row of a file of delimited values in batches. Indexes are created with a schema
+/// inferred from the file if `create` is given.
+async fn import_csv(
+    index: &Index,
+    file: &Path,
+    batch_size: usize,
+    options: CsvOptions,
+    create: Option<CreateIndex>,
+) -> anyhow::Result<()> {
+    let schema = import_schema(index, create, |sample| {
+        Ok(csv::infer_schema(open_import(file)?, &options, sample)?)
+    })
+    .await?;
+    let mut records = Csv::new(open_import(file)?, schema, options)
+        .with_context(|| format!("could not read the header of {}", file.display()))?;
+    let summary = import_records(index, records.by_ref(), batch_size).await?;
+    println!("{}", serde_json::to_string_pretty(&summary)?);
+    if let Some(line) = records.aborted() {
+        anyhow::bail!("aborted the import at the malformed row on line {line}");
+    }
+    Ok(())
+}
+
+/// Imports every row of a parquet file in batches. Indexes are created with a schema inferred
+/// from the file if `create` is given.
+async fn import_parquet(
+    index: &Index,
+    file: &Path,
+    batch_size: usize,
+    create: Option<CreateIndex>,
+) -> anyhow::Result<()> {
+    let open =
+        || fs::File::open(file).with_context(|| format!("could not read {}", file.display()));
+    let schema = import_schema(index, create, |sample| {
+        Ok(parquet::infer_schema(open()?, sample)?)
+    })
+    .await?;
+    let records = Parquet::new(open()?, schema)
+        .with_context(|| format!("could not read {}", file.display()))?;
+    let summary = import_records(index, records, batch_size).await?;
+    println!("{}", serde_json::to_string_pretty(&summary)?);
+    Ok(())
+}
+
+/// Exports the documents read by a cursor into a file, writing only the given fields unless
+/// there are none
+async fn export_documents(
+    index: &Index,
+    file: &Path,
+    format: ExportFormat,
+    fields: Vec<String>,
+    mut cursor: Cursor,
+) -> anyhow::Result<()> {
+    let schema = index.mapping().await?;
+    let selected = if fields.is_empty() {
+        schema.iter().cloned().collect::<Vec<_>>()
+    } else {
+        fields
+            .iter()
+            .map(|name| {
+                schema
+                    .get(name)
+                    .cloned()
+                    .with_context(|| format!("unknown field {name:?}"))
+            })
+            .collect::<anyhow::Result<_>>()?
+    };
+    let columns = selected.iter().map(|field| field.name.clone());
+    let out = fs::File::create(file)
+        .map(BufWriter::new)
+        .with_context(|| format!("could not create {}", file.display()))?;
+    let mut writer: Box<dyn DocumentWriter> = match format {
+        ExportFormat::JsonLines if fields.is_empty() => Box::new(JsonLinesWriter::new(out)),
+        ExportFormat::JsonLines => Box::new(JsonLinesWriter::new(out).with_fields(fields)),
+        ExportFormat::Csv => Box::new(CsvWriter::new(out, columns)),
+        ExportFormat::Tsv => Box::new(CsvWriter::new(out, columns).with_delimiter('\t')),
+        ExportFormat::Parquet => Box::new(ParquetWriter::new(out, Schema::from_iter(selected))?),
+    };
+    while let Some(hits) = cursor.next_batch().await? {
+        for hit in hits {
+            writer
+                .write(&hit.document)
+                .with_context(|| format!("could not export the document in row {}", hit.row))?;
+        }
+    }
+    let documents = writer.finish()?;
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&serde_json::json!({ "documents": documents }))?
+    );
+    Ok(())
+}
+
+/// Opens a file to import
+fn open_import(file: &Path) -> anyhow::Result<BufReader<fs::File>> {
+    fs::File::open(file)
+        .map(BufReader::new)
+        .with_context(|| format!("could not read {}", file.display()))
+}
+
+/// Gets the schema of the index records are imported into. Indexes that do not exist are created
+/// with the schema returned by `infer`, given the number of records to infer it from, if `create`
+/// is given.
+async fn import_schema(
+    index: &Index,
+    create: Option<CreateIndex>,
+    infer: impl FnOnce(usize) -> anyhow::Result<Schema>,
+) -> anyhow::Result<Schema> {
+    match (index.mapping().await, create) {
+        (Ok(schema), _) => Ok(schema),
+        (Err(ClientError::Failed(DocatlasError::NotFound(_))), Some(create)) => {
+            let mut schema = infer(create.sample)?;
+            import::override_kinds(&mut schema, create.fields);
+            if let Some(primary_key) = create.primary_key {
+                schema = schema.with_primary_key(primary_key);
+            }
+            index.create(schema.clone()).await?;
+            eprintln!("created {:?} with an inferred schema", index.name());
+            Ok(schema)
+        }
+        (Err(e), _) => Err(e.into()),
+    }
+}
+
+/// Imports records in batches, reporting progress and why records could not be imported as it
+/// goes
+async fn import_records(
+    index: &Index,
+    mut records: impl Iterator<Item = Record>,
+    batch_size: usize,
+) -> anyhow::Result<ImportSummary> {
+    let mut summary = ImportSummary::default();
+    loop {
+        let mut lines = vec![];
+        let mut documents = vec![];
+        for record in records.by_ref() {
+            summary.records += 1;
+            match record.document {
+                Ok(document) => {
+                    lines.push(record.line);
+                    documents.push(document);
+                }
+                Err(e) => {
+                    eprintln!("line {}: {e}", record.line);
+                    summary.fail(record.line, e);
+                }
+            }
+            if documents.len() >= batch_size {
+                break;
+            }
+        }
+        if documents.is_empty() {
+            break;
+        }
+        for (line, item) in lines.into_iter().zip(index.bulk(documents).await?) {
+            match item {
+                Ok(Some(_)) => summary.added += 1,
+                Ok(None) => summary.dropped += 1,
+                Err(e) => {
+                    eprintln!("line {line}: {e}");
+                    summary.fail(line, e);
+                }
+            }
+        }
+        eprintln!(
+            "{} records read, {} added, {} failed",
+            summary.records,
+            summary.added,
+            summary.failed.len()
+        );
+    }
+    Ok(summary)
+}
+
+/// Prints the audit log entries matching a query, one per line
+async fn audit(client: &Client, query: AuditQuery) -> anyhow::Result<()> {
+    for entry in client.audit_log(query).await? {
+        println!("{}", serde_json::to_string(&entry)?);
+    }
+    Ok(())
+}
+
+/// Prints the events of every change made to an index, one per line, until the subscription ends
+async fn changes(index: &Index, after: Option<u64>, filter: ChangeFilter) -> anyhow::Result<()> {
+    let mut subscription = index.subscribe(after, filter).await?;
+    while let Some(events) = subscription.next_batch().await? {
+        for event in events {
+            println!("{}", serde_json::to_string(&event)?);
+        }
+    }
+    Ok(())
+}
+
+/// Parses a primary key given as text, according to the schema of the index
+async fn parse_key(client: &Client, index: &str, key: &str) -> anyhow::Result<FieldData> {
+    let schema = client.index(index).mapping().await?;
+    Ok(handlers::parse_key(&schema, key)?)
+}
+
+/// Reads a schema from a json file
+fn read_schema(path: &Path) -> anyhow::Result<Schema> {
+    let json =
+        fs::read_to_string(path).with_context(|| format!("could not read {}", path.display()))?;
+    serde_json::from_str(&json).with_context(|| format!("invalid schema in {}", path.display()))
+}
+
+/// Prints a response as json
+fn print(response: &ClientResponse) -> anyhow::Result<()> {
+    println!("{}", serde_json::to_string_pretty(response)?);
+    Ok(())
+}
+
+/// Prints the names of the columns of a table, and then every row, as json arrays on lines of
+/// their own
+fn print_table(table: &Table) -> anyhow::Result<()> {
+    println!("{}", serde_json::to_string(&table.columns)?);
+    for row in &table.rows {
+        let row = row
+            .iter()
+            .map(|value| value.as_ref().map_or(serde_json::Value::Null, json_value))
+            .collect::<Vec<_>>();
+        println!("{}", serde_json::to_string(&row)?);
+    }
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fix_only_stopped_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let paths = [dir.path().to_path_buf()];
+        fs::write(dir.path().join(PID_FILE), std::process::id().to_string()).unwrap();
+        check(&paths, None).unwrap();
+        let error = check(&paths, Some(Fix::Repair)).unwrap_err();
+        assert!(
+            format!("{error:#}").contains("already running"),
+            "{error:#}"
+        );
+        assert!(check(&paths, Some(Fix::Quarantine)).is_err());
+
+        fs::remove_file(dir.path().join(PID_FILE)).unwrap();
+        check(&paths, Some(Fix::Repair)).unwrap();
+    }
+}
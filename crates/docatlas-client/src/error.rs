@@ -0,0 +1,86 @@
+//! The errors returned by the client
+
+use std::io;
+use std::time::Duration;
+
+use docatlas_core::error::DocatlasError;
+use docatlas_core::transport::handshake::HandshakeError;
+use docatlas_core::transport::mux::DispatchError;
+use docatlas_core::transport::queue::QueueError;
+use docatlas_daemon::client::ClientResponse;
+use thiserror::Error;
+
+/// A request to the daemon failed
+#[derive(Debug, Error)]
+pub enum ClientError {
+    #[error("No address found for {0:?}")]
+    NoAddress(String),
+    #[error("The daemon requires authentication, but no credentials were given")]
+    CredentialsRequired,
+    #[error("Not authenticated: {0}")]
+    Unauthenticated(String),
+    #[error("Rate limited, retry after {0:?}")]
+    RateLimited(Duration),
+    #[error("The request timed out after {0:?}")]
+    TimedOut(Duration),
+    #[error("Index {index:?} is on node {node}, which clients connect to at {address}")]
+    Moved {
+        index: String,
+        node: String,
+        address: String,
+    },
+    /// The daemon could not handle the request
+    #[error("{0}")]
+    ServerError(String),
+    /// The daemon could not handle the request, and told why
+    #[error(transparent)]
+    Failed(DocatlasError),
+    #[error("Unexpected response {0:?}")]
+    UnexpectedResponse(Box<ClientResponse>),
+    #[error("The connection to the daemon closed")]
+    Disconnected,
+    #[error(transparent)]
+    QueueError(QueueError),
+    #[error(transparent)]
+    HandshakeError(#[from] HandshakeError),
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+}
+
+impl From<DispatchError> for ClientError {
+    fn from(value: DispatchError) -> Self {
+        match value {
+            DispatchError::Closed => ClientError::Disconnected,
+            DispatchError::QueueError(e) => ClientError::QueueError(e),
+        }
+    }
+}
+
+impl ClientError {
+    /// Converts a response reporting a failure into an error
+    pub(crate) fn from_response(response: ClientResponse) -> Result<ClientResponse, Self> {
+        match response {
+            ClientResponse::Error { message } => Err(ClientError::ServerError(message)),
+            ClientResponse::Failed { error } => Err(ClientError::Failed(error)),
+            ClientResponse::Unauthenticated { message } => {
+                Err(ClientError::Unauthenticated(message))
+            }
+            ClientResponse::RateLimited { retry_after_ms } => Err(ClientError::RateLimited(
+                Duration::from_millis(retry_after_ms),
+            )),
+            ClientResponse::TimedOut { after_ms } => {
+                Err(ClientError::TimedOut(Duration::from_millis(after_ms)))
+            }
+            ClientResponse::Moved {
+                index,
+                node,
+                address,
+            } => Err(ClientError::Moved {
+                index,
+                node,
+                address,
+            }),
+            response => Ok(response),
+        }
+    }
+}
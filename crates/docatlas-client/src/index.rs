@@ -0,0 +1,493 @@
+//! Requests made on a single index
+
+use std::time::Duration;
+
+use docatlas_core::document::Document;
+use docatlas_core::fields::FieldData;
+use docatlas_core::index::{Transaction, Upserted, Written};
+use docatlas_core::query::string::QueryString;
+use docatlas_core::schema::Schema;
+use docatlas_core::transport::mux::Responses;
+use docatlas_daemon::changes::{ChangeEvent, ChangeFilter};
+use docatlas_daemon::client::{ClientRequest, ClientResponse, ScanFilter, VersionedResponse};
+use docatlas_daemon::handlers::{Hit, IndexInfo};
+use futures::StreamExt;
+use serde::Serialize;
+
+use crate::{unexpected, Client, ClientError};
+
+/// The number of hits returned by a search, unless it sets a limit
+pub const DEFAULT_SEARCH_LIMIT: usize = 10;
+
+/// A search for the documents whose field contains every term of a query
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Query {
+    field: String,
+    query: String,
+    limit: usize,
+    timeout: Option<Duration>,
+}
+
+impl Query {
+    /// Searches a field for every term of the query
+    pub fn new(field: impl Into<String>, query: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            query: query.into(),
+            limit: DEFAULT_SEARCH_LIMIT,
+            timeout: None,
+        }
+    }
+
+    /// Sets the most hits returned
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        self.limit = limit;
+        self
+    }
+
+    /// Sets how long the search may run. The daemon caps this by its own maximum, which is also
+    /// used if no timeout is set.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Gets the searched field
+    pub fn field(&self) -> &str {
+        &self.field
+    }
+
+    /// Gets the terms searched for
+    pub fn query(&self) -> &str {
+        &self.query
+    }
+
+    /// Gets the most hits returned
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Gets how long the search may run, if set
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    fn into_request(self, index: String, partial: bool) -> ClientRequest {
+        let request = ClientRequest::Search {
+            index,
+            field: self.field,
+            query: self.query,
+            limit: self.limit,
+        };
+        if self.timeout.is_none() && !partial {
+            return request;
+        }
+        ClientRequest::Timed {
+            timeout_ms: self
+                .timeout
+                .map_or(u64::MAX, |timeout| timeout.as_millis() as u64),
+            partial,
+            request: Box::new(request),
+        }
+    }
+}
+
+/// How many documents were added in bulk
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct BulkSummary {
+    pub added: usize,
+    /// Documents dropped by the ingest pipeline of the index
+    pub dropped: usize,
+    /// Why every document that could not be added failed
+    pub failed: Vec<String>,
+}
+
+/// Makes requests on a single index
+#[derive(Debug, Clone)]
+pub struct Index {
+    client: Client,
+    name: String,
+}
+
+impl Index {
+    pub(crate) fn new(client: Client, name: String) -> Self {
+        Self { client, name }
+    }
+
+    /// Gets the name of the index
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Creates the index, which must not exist yet
+    pub async fn create(&self, schema: Schema) -> Result<(), ClientError> {
+        let request = ClientRequest::CreateIndex {
+            index: self.name.clone(),
+            schema,
+        };
+        self.acknowledged(request).await
+    }
+
+    /// Sets the schema of the index, creating the index if it does not exist
+    pub async fn put_schema(&self, schema: Schema) -> Result<(), ClientError> {
+        let request = ClientRequest::PutSchema {
+            index: self.name.clone(),
+            schema,
+        };
+        self.acknowledged(request).await
+    }
+
+    /// Gets the schema of the index
+    pub async fn mapping(&self) -> Result<Schema, ClientError> {
+        let request = ClientRequest::GetMapping {
+            index: self.name.clone(),
+        };
+        match self.client.request(request).await? {
+            ClientResponse::Mapping { schema } => Ok(schema),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Deletes the index and every document in it
+    pub async fn delete(&self) -> Result<(), ClientError> {
+        let request = ClientRequest::DeleteIndex {
+            index: self.name.clone(),
+        };
+        self.acknowledged(request).await
+    }
+
+    /// Gets a summary of the index
+    pub async fn stats(&self) -> Result<IndexInfo, ClientError> {
+        let request = ClientRequest::Stats {
+            index: Some(self.name.clone()),
+        };
+        match self.client.request(request).await? {
+            ClientResponse::Stats { mut indexes } if indexes.len() == 1 => Ok(indexes.remove(0)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Inserts a document, or replaces the document sharing its primary key
+    pub async fn upsert(&self, document: Document) -> Result<Upserted, ClientError> {
+        self.index_document(document, false).await
+    }
+
+    /// Inserts a document, or only updates the fields present in it if a document shares its
+    /// primary key
+    pub async fn upsert_partial(&self, document: Document) -> Result<Upserted, ClientError> {
+        self.index_document(document, true).await
+    }
+
+    async fn index_document(
+        &self,
+        document: Document,
+        partial: bool,
+    ) -> Result<Upserted, ClientError> {
+        let request = ClientRequest::IndexDocument {
+            index: self.name.clone(),
+            document,
+            partial,
+        };
+        match self.client.request(request).await? {
+            ClientResponse::Upserted {
+                row,
+                inserted: true,
+            } => Ok(Upserted::Inserted(row)),
+            ClientResponse::Upserted {
+                row,
+                inserted: false,
+            } => Ok(Upserted::Updated(row)),
+            ClientResponse::Dropped => Ok(Upserted::Dropped),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Adds documents in a single request, returning the row of every document, `None` if it was
+    /// dropped, or why it could not be added
+    pub async fn bulk(
+        &self,
+        documents: Vec<Document>,
+    ) -> Result<Vec<Result<Option<usize>, String>>, ClientError> {
+        let request = ClientRequest::Bulk {
+            index: self.name.clone(),
+            documents,
+        };
+        match self.client.request(request).await? {
+            ClientResponse::Bulk { items } => Ok(items),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Adds any number of documents, sending up to `batch_size` documents per request
+    pub async fn bulk_batched(
+        &self,
+        documents: impl IntoIterator<Item = Document>,
+        batch_size: usize,
+    ) -> Result<BulkSummary, ClientError> {
+        let mut summary = BulkSummary::default();
+        let mut documents = documents.into_iter().peekable();
+        while documents.peek().is_some() {
+            let batch = documents.by_ref().take(batch_size.max(1)).collect();
+            for item in self.bulk(batch).await? {
+                match item {
+                    Ok(Some(_)) => summary.added += 1,
+                    Ok(None) => summary.dropped += 1,
+                    Err(error) => summary.failed.push(error),
+                }
+            }
+        }
+        Ok(summary)
+    }
+
+    /// Commits the writes of a transaction, applying all of them or none, and returns what every
+    /// write did
+    pub async fn transaction(&self, transaction: Transaction) -> Result<Vec<Written>, ClientError> {
+        let request = ClientRequest::Transaction {
+            index: self.name.clone(),
+            transaction,
+        };
+        match self.client.request(request).await? {
+            ClientResponse::Committed { written } => Ok(written),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Gets the document with the given primary key
+    pub async fn get(&self, key: FieldData) -> Result<Option<Document>, ClientError> {
+        let request = ClientRequest::Get {
+            index: self.name.clone(),
+            key,
+        };
+        match self.client.request(request).await? {
+            ClientResponse::Document { document } => Ok(document),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Reads the documents of the index `batch_size` at a time, in the order of their rows
+    pub fn cursor(&self, batch_size: usize) -> Cursor {
+        Cursor {
+            index: self.clone(),
+            batch_size: batch_size.max(1),
+            filter: None,
+            remaining: None,
+            from: Some(0),
+            at: None,
+        }
+    }
+
+    /// Subscribes to the changes made to the documents of the index that pass a filter, starting
+    /// after the change with sequence number `after`, or with the next change if none is given
+    pub async fn subscribe(
+        &self,
+        after: Option<u64>,
+        filter: ChangeFilter,
+    ) -> Result<Subscription, ClientError> {
+        let request = ClientRequest::Subscribe {
+            index: self.name.clone(),
+            after,
+            filter,
+        };
+        let mut responses = self.client.request_stream(request).await?;
+        match next_response(&mut responses).await? {
+            ClientResponse::Subscribed { log, last } => Ok(Subscription {
+                client: self.client.clone(),
+                responses,
+                log,
+                last: after.unwrap_or(last),
+            }),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Deletes the document with the given primary key, returning the row it was stored in
+    pub async fn delete_document(&self, key: FieldData) -> Result<Option<usize>, ClientError> {
+        let request = ClientRequest::Delete {
+            index: self.name.clone(),
+            key,
+        };
+        match self.client.request(request).await? {
+            ClientResponse::Deleted { row } => Ok(row),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Finds the documents matching a query, failing if the search times out
+    pub async fn search(&self, query: Query) -> Result<Vec<Hit>, ClientError> {
+        let request = query.into_request(self.name.clone(), false);
+        match self.client.request(request).await? {
+            ClientResponse::Hits { hits } => Ok(hits),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Finds the documents matching a query, returning the hits found so far if the search times
+    /// out. Returns whether the search finished along with its hits.
+    pub async fn search_partial(&self, query: Query) -> Result<(Vec<Hit>, bool), ClientError> {
+        let request = query.into_request(self.name.clone(), true);
+        match self.client.request(request).await? {
+            ClientResponse::Hits { hits } => Ok((hits, true)),
+            ClientResponse::Partial { response } => match *response {
+                ClientResponse::Hits { hits } => Ok((hits, false)),
+                response => Err(unexpected(response)),
+            },
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Finds up to `limit` documents matching a [query string](docatlas_core::query::string),
+    /// parsed by the daemon with the default field and operator of the parser
+    pub async fn query_string(
+        &self,
+        query: impl Into<String>,
+        parser: &QueryString,
+        limit: usize,
+    ) -> Result<Vec<Hit>, ClientError> {
+        let request = ClientRequest::QueryString {
+            index: self.name.clone(),
+            query: query.into(),
+            default_field: parser.default_field().map(str::to_string),
+            default_operator: parser.default_operator(),
+            limit,
+        };
+        match self.client.request(request).await? {
+            ClientResponse::Hits { hits } => Ok(hits),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    async fn acknowledged(&self, request: ClientRequest) -> Result<(), ClientError> {
+        match self.client.request(request).await? {
+            ClientResponse::Acknowledged => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+}
+
+/// Reads the documents of an index a batch at a time, in the order of their rows. Every batch is
+/// read at the sequence number the first batch was read at, so documents written while reading
+/// are not seen.
+#[derive(Debug, Clone)]
+pub struct Cursor {
+    index: Index,
+    batch_size: usize,
+    filter: Option<ScanFilter>,
+    /// How many more documents may be read, if limited
+    remaining: Option<usize>,
+    /// The row to read from next, unless every row was read
+    from: Option<usize>,
+    /// The sequence number the batches are read at, once the first batch was read
+    at: Option<u64>,
+}
+
+impl Cursor {
+    /// Only reads the documents matched by a query, up to its limit. The timeout of the query is
+    /// not used, as every batch is read by a request of its own.
+    pub fn with_query(mut self, query: Query) -> Self {
+        self.remaining = Some(query.limit);
+        self.filter = Some(ScanFilter {
+            field: query.field,
+            query: query.query,
+        });
+        self
+    }
+
+    /// Gets the sequence number the batches are read at, once the first batch was read
+    pub fn at(&self) -> Option<u64> {
+        self.at
+    }
+
+    /// Gets the most documents read at a time
+    pub fn batch_size(&self) -> usize {
+        self.batch_size
+    }
+
+    /// Reads the next batch of documents, or `None` once every document was read
+    pub async fn next_batch(&mut self) -> Result<Option<Vec<Hit>>, ClientError> {
+        let Some(from) = self.from else {
+            return Ok(None);
+        };
+        let limit = match self.remaining {
+            Some(0) => return Ok(None),
+            Some(remaining) => remaining.min(self.batch_size),
+            None => self.batch_size,
+        };
+        let request = ClientRequest::Scan {
+            index: self.index.name.clone(),
+            from,
+            limit,
+            filter: self.filter.clone(),
+            at: self.at,
+        };
+        match self.index.client.request(request).await? {
+            ClientResponse::Scanned { hits, next, at } => {
+                self.from = next;
+                self.at = self.at.or(at);
+                if let Some(remaining) = &mut self.remaining {
+                    *remaining -= hits.len().min(*remaining);
+                }
+                Ok(Some(hits))
+            }
+            response => Err(unexpected(response)),
+        }
+    }
+}
+
+/// Receives the changes made to the documents of an index, until it is cancelled or the log of
+/// the index starts again
+#[derive(Debug)]
+pub struct Subscription {
+    client: Client,
+    responses: Responses<VersionedResponse>,
+    log: u64,
+    last: u64,
+}
+
+impl Subscription {
+    /// Gets the id of the log the subscription started in
+    pub fn log(&self) -> u64 {
+        self.log
+    }
+
+    /// Gets the sequence number of the last change received, which a new subscription can resume
+    /// after
+    pub fn last(&self) -> u64 {
+        self.last
+    }
+
+    /// Waits for the events of the next changes, or returns `None` once the subscription was
+    /// cancelled
+    pub async fn next_batch(&mut self) -> Result<Option<Vec<ChangeEvent>>, ClientError> {
+        match next_response(&mut self.responses).await? {
+            ClientResponse::Changes { events } => {
+                if let Some(event) = events.last() {
+                    self.last = event.seq;
+                }
+                Ok(Some(events))
+            }
+            ClientResponse::Cancelled => Ok(None),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Asks the daemon to end the subscription, after which [`next_batch`](Self::next_batch)
+    /// returns `None`
+    pub async fn cancel(&self) -> Result<(), ClientError> {
+        let request = ClientRequest::Cancel {
+            id: self.responses.id(),
+        };
+        match self.client.request(request).await? {
+            ClientResponse::CancelRequested { .. } => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+}
+
+/// Waits for the next response of a stream, converting responses reporting a failure into errors
+async fn next_response(
+    responses: &mut Responses<VersionedResponse>,
+) -> Result<ClientResponse, ClientError> {
+    let VersionedResponse::V1(response) =
+        responses.next().await.ok_or(ClientError::Disconnected)?;
+    ClientError::from_response(response)
+}
@@ -0,0 +1,512 @@
+//! An async client of the docatlas daemon, speaking its binary protocol.
+//!
+//! ```no_run
+//! # async fn example() -> Result<(), docatlas_client::ClientError> {
+//! use docatlas_client::{Client, Query};
+//!
+//! let client = Client::connect("localhost:3676").await?;
+//! let hits = client
+//!     .index("logs")
+//!     .search(Query::new("message", "disk full").with_limit(20))
+//!     .await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! A client is cheap to clone, and every clone shares the same connection. Requests sent at the
+//! same time are multiplexed over it. Once the connection is lost, the next request reconnects
+//! and authenticates again.
+//!
+//! Authenticating starts a session on the daemon. Reconnecting resumes the session rather than
+//! sending the credentials again, and once the session ends the client authenticates with its
+//! credentials again.
+
+use std::sync::Arc;
+
+use docatlas_core::auth::users::Group;
+use docatlas_core::sql::Table;
+use docatlas_core::transport::handshake::{self, AuthMethod, ClientHello};
+use docatlas_core::transport::keepalive::Keepalive;
+use docatlas_core::transport::mux::{Dispatcher, Envelope, Responses};
+use docatlas_core::transport::queue::QueueConfig;
+use docatlas_core::transport::{wire, TcpTransport};
+use docatlas_daemon::audit::{AuditEntry, AuditQuery};
+use docatlas_daemon::client::{
+    ClientRequest, ClientResponse, Credentials, Secret, VersionedRequest, VersionedResponse,
+};
+use docatlas_daemon::cluster::ClusterHealth;
+use docatlas_daemon::handlers::IndexInfo;
+use docatlas_daemon::health::Readiness;
+use docatlas_daemon::index_manager::PathUsage;
+use docatlas_daemon::snapshot::SnapshotInfo;
+use tokio::net;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+pub use error::ClientError;
+pub use index::{BulkSummary, Cursor, Index, Query, Subscription, DEFAULT_SEARCH_LIMIT};
+
+mod error;
+mod index;
+
+/// Configures a client before it connects
+#[derive(Debug, Clone)]
+pub struct ClientBuilder {
+    address: String,
+    credentials: Option<Credentials>,
+    queue: QueueConfig,
+}
+
+impl ClientBuilder {
+    /// Authenticates as a user, if the daemon requires authentication
+    pub fn with_basic_auth(mut self, username: impl Into<String>, password: &str) -> Self {
+        self.credentials = Some(Credentials::Basic {
+            username: username.into(),
+            password: Secret::new(password),
+        });
+        self
+    }
+
+    /// Authenticates with a token, if the daemon requires authentication
+    pub fn with_token(mut self, token: &str) -> Self {
+        self.credentials = Some(Credentials::Token {
+            token: Secret::new(token),
+        });
+        self
+    }
+
+    /// Sets the size of the queue of requests waiting to be sent, and what happens once it is full
+    pub fn with_queue(mut self, queue: QueueConfig) -> Self {
+        self.queue = queue;
+        self
+    }
+
+    /// Connects to the daemon
+    pub async fn connect(self) -> Result<Client, ClientError> {
+        let connection = Connection::open(&self, None).await?;
+        Ok(Client {
+            inner: Arc::new(Inner {
+                builder: self,
+                connection: Mutex::new(connection),
+            }),
+        })
+    }
+}
+
+/// A client of the daemon
+#[derive(Debug, Clone)]
+pub struct Client {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    builder: ClientBuilder,
+    connection: Mutex<Connection>,
+}
+
+impl Client {
+    /// Connects to the daemon at the given address, without authenticating
+    pub async fn connect(address: impl Into<String>) -> Result<Self, ClientError> {
+        Self::builder(address).connect().await
+    }
+
+    /// Configures a client of the daemon at the given address
+    pub fn builder(address: impl Into<String>) -> ClientBuilder {
+        ClientBuilder {
+            address: address.into(),
+            credentials: None,
+            queue: QueueConfig::default(),
+        }
+    }
+
+    /// Gets the address of the daemon
+    pub fn address(&self) -> &str {
+        &self.inner.builder.address
+    }
+
+    /// Makes requests on a single index
+    pub fn index(&self, name: impl Into<String>) -> Index {
+        Index::new(self.clone(), name.into())
+    }
+
+    /// Sends any request, reconnecting first if the connection was lost. Responses reporting a
+    /// failure are returned as errors.
+    pub async fn request(&self, request: ClientRequest) -> Result<ClientResponse, ClientError> {
+        let (dispatcher, session) = {
+            let mut connection = self.inner.connection.lock().await;
+            if connection.is_closed() {
+                let session = connection.session.take();
+                *connection = Connection::open(&self.inner.builder, session).await?;
+            }
+            (connection.dispatcher.clone(), connection.session.is_some())
+        };
+        let retry = session.then(|| request.clone());
+        let VersionedResponse::V1(response) =
+            dispatcher.call(VersionedRequest::V1(request)).await?;
+        match (ClientError::from_response(response), retry) {
+            // the session ended, so authenticate again on a new connection
+            (Err(ClientError::Unauthenticated(_)), Some(request)) => {
+                let dispatcher = {
+                    let mut connection = self.inner.connection.lock().await;
+                    *connection = Connection::open(&self.inner.builder, None).await?;
+                    connection.dispatcher.clone()
+                };
+                let VersionedResponse::V1(response) =
+                    dispatcher.call(VersionedRequest::V1(request)).await?;
+                ClientError::from_response(response)
+            }
+            (result, _) => result,
+        }
+    }
+
+    /// Sends a request answered by a stream of responses, reconnecting first if the connection
+    /// was lost. Unlike [`request`](Self::request), the request is not retried once the session
+    /// ended.
+    pub(crate) async fn request_stream(
+        &self,
+        request: ClientRequest,
+    ) -> Result<Responses<VersionedResponse>, ClientError> {
+        let dispatcher = {
+            let mut connection = self.inner.connection.lock().await;
+            if connection.is_closed() {
+                let session = connection.session.take();
+                *connection = Connection::open(&self.inner.builder, session).await?;
+            }
+            connection.dispatcher.clone()
+        };
+        Ok(dispatcher.request(VersionedRequest::V1(request)).await?)
+    }
+
+    /// Ends the session the client authenticated with. The next request authenticates again.
+    pub async fn logout(&self) -> Result<(), ClientError> {
+        let response = self.request(ClientRequest::Logout).await?;
+        self.inner.connection.lock().await.session = None;
+        match response {
+            ClientResponse::Acknowledged => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Changes the password of the user the client authenticated as. Every other session of the
+    /// user ends, and the client continues in a new one.
+    pub async fn change_password(
+        &self,
+        current: impl Into<String>,
+        password: impl Into<String>,
+    ) -> Result<(), ClientError> {
+        let request = ClientRequest::ChangePassword {
+            current: Secret::new(current.into()),
+            password: Secret::new(password.into()),
+        };
+        match self.request(request).await? {
+            ClientResponse::SessionStarted { token, .. } => {
+                self.inner.connection.lock().await.session = Some(token);
+                Ok(())
+            }
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Ends every session of a user, returning how many were ended
+    pub async fn revoke_sessions(&self, user: impl Into<String>) -> Result<usize, ClientError> {
+        let request = ClientRequest::RevokeSessions { user: user.into() };
+        match self.request(request).await? {
+            ClientResponse::Revoked { sessions } => Ok(sessions),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Adds a user authenticated by a password
+    pub async fn create_user(
+        &self,
+        user: impl Into<String>,
+        password: impl Into<String>,
+    ) -> Result<(), ClientError> {
+        let request = ClientRequest::CreateUser {
+            user: user.into(),
+            password: Secret::new(password.into()),
+        };
+        match self.request(request).await? {
+            ClientResponse::Acknowledged => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Replaces the password of a user, returning how many of their sessions were ended
+    pub async fn reset_password(
+        &self,
+        user: impl Into<String>,
+        password: impl Into<String>,
+    ) -> Result<usize, ClientError> {
+        let request = ClientRequest::ResetPassword {
+            user: user.into(),
+            password: Secret::new(password.into()),
+        };
+        match self.request(request).await? {
+            ClientResponse::Revoked { sessions } => Ok(sessions),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Removes a user, returning how many of their sessions were ended
+    pub async fn delete_user(&self, user: impl Into<String>) -> Result<usize, ClientError> {
+        let request = ClientRequest::DeleteUser { user: user.into() };
+        match self.request(request).await? {
+            ClientResponse::Revoked { sessions } => Ok(sessions),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Lists every user whose password is stored
+    pub async fn users(&self) -> Result<Vec<String>, ClientError> {
+        match self.request(ClientRequest::ListUsers).await? {
+            ClientResponse::Users { names } => Ok(names),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Lists every group, with their members and roles
+    pub async fn groups(&self) -> Result<Vec<Group>, ClientError> {
+        match self.request(ClientRequest::ListGroups).await? {
+            ClientResponse::Groups { groups } => Ok(groups),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Adds a user to a group, who is given the roles of the group right away
+    pub async fn add_group_member(
+        &self,
+        group: impl Into<String>,
+        user: impl Into<String>,
+    ) -> Result<(), ClientError> {
+        let request = ClientRequest::AddGroupMember {
+            group: group.into(),
+            user: user.into(),
+        };
+        match self.request(request).await? {
+            ClientResponse::Acknowledged => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Removes a user from a group
+    pub async fn remove_group_member(
+        &self,
+        group: impl Into<String>,
+        user: impl Into<String>,
+    ) -> Result<(), ClientError> {
+        let request = ClientRequest::RemoveGroupMember {
+            group: group.into(),
+            user: user.into(),
+        };
+        match self.request(request).await? {
+            ClientResponse::Acknowledged => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Lists the names of every index
+    pub async fn indexes(&self) -> Result<Vec<String>, ClientError> {
+        match self.request(ClientRequest::ListIndexes).await? {
+            ClientResponse::Indexes { names } => Ok(names),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Runs a sql `SELECT` statement, which may select from and join any index the user may read
+    pub async fn sql(&self, query: impl Into<String>) -> Result<Table, ClientError> {
+        let request = ClientRequest::Sql {
+            query: query.into(),
+        };
+        match self.request(request).await? {
+            ClientResponse::Table { table } => Ok(table),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Gets a summary of every index
+    pub async fn stats(&self) -> Result<Vec<IndexInfo>, ClientError> {
+        match self.request(ClientRequest::Stats { index: None }).await? {
+            ClientResponse::Stats { indexes } => Ok(indexes),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Gets how much of every directory the daemon stores indexes in is used
+    pub async fn path_stats(&self) -> Result<Vec<PathUsage>, ClientError> {
+        match self.request(ClientRequest::PathStats).await? {
+            ClientResponse::PathStats { paths } => Ok(paths),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Checks whether the daemon is ready to serve requests
+    pub async fn health(&self) -> Result<Readiness, ClientError> {
+        match self.request(ClientRequest::Health).await? {
+            ClientResponse::Health { readiness } => Ok(readiness),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Gets the status of the cluster, and of every node in it
+    pub async fn cluster_health(&self) -> Result<ClusterHealth, ClientError> {
+        match self.request(ClientRequest::ClusterHealth).await? {
+            ClientResponse::ClusterHealth { cluster } => Ok(cluster),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Takes a snapshot of every index, storing it in a repository
+    pub async fn create_snapshot(
+        &self,
+        repository: impl Into<String>,
+        snapshot: impl Into<String>,
+    ) -> Result<SnapshotInfo, ClientError> {
+        let request = ClientRequest::CreateSnapshot {
+            repository: repository.into(),
+            snapshot: snapshot.into(),
+        };
+        match self.request(request).await? {
+            ClientResponse::Snapshot { snapshot } => Ok(snapshot),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Restores every index of a snapshot, returning the names of the restored indexes
+    pub async fn restore_snapshot(
+        &self,
+        repository: impl Into<String>,
+        snapshot: impl Into<String>,
+    ) -> Result<Vec<String>, ClientError> {
+        let request = ClientRequest::RestoreSnapshot {
+            repository: repository.into(),
+            snapshot: snapshot.into(),
+        };
+        match self.request(request).await? {
+            ClientResponse::Restored { indexes } => Ok(indexes),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Lists the snapshots stored in a repository
+    pub async fn snapshots(
+        &self,
+        repository: impl Into<String>,
+    ) -> Result<Vec<String>, ClientError> {
+        let request = ClientRequest::ListSnapshots {
+            repository: repository.into(),
+        };
+        match self.request(request).await? {
+            ClientResponse::Snapshots { names } => Ok(names),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Gets the entries of the daemon's audit log matching a query, the oldest first
+    pub async fn audit_log(&self, query: AuditQuery) -> Result<Vec<AuditEntry>, ClientError> {
+        match self.request(ClientRequest::AuditLog { query }).await? {
+            ClientResponse::AuditEntries { entries } => Ok(entries),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Promotes the daemon, if it is a replica, so it accepts changes
+    pub async fn promote(&self) -> Result<(), ClientError> {
+        match self.request(ClientRequest::Promote).await? {
+            ClientResponse::Acknowledged => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+}
+
+/// A connection to the daemon, along with the driver sending and receiving its packets
+#[derive(Debug)]
+struct Connection {
+    dispatcher: Dispatcher<VersionedRequest, VersionedResponse>,
+    driver: JoinHandle<()>,
+    /// The token of the session the connection authenticated by, if it started or resumed one
+    session: Option<Secret>,
+}
+
+impl Connection {
+    /// Connects to the daemon and authenticates, if the daemon requires it. A session is resumed
+    /// if one is given and it has not ended, otherwise the client authenticates with its
+    /// credentials.
+    async fn open(builder: &ClientBuilder, session: Option<Secret>) -> Result<Self, ClientError> {
+        let address = net::lookup_host(&builder.address)
+            .await?
+            .next()
+            .ok_or_else(|| ClientError::NoAddress(builder.address.clone()))?;
+        let mut transport = TcpTransport::connect(address).await?;
+        let server = handshake::connect(&mut transport, &ClientHello::default()).await?;
+        let transport = Keepalive::new(transport, server.keepalive);
+        let transport =
+            wire::wire_transport::<Envelope<VersionedRequest>, Envelope<VersionedResponse>, _, _>(
+                transport,
+                server.codec(),
+            );
+        let (dispatcher, driver) = Dispatcher::with_queue(transport, builder.queue);
+        let driver = tokio::spawn(async move {
+            // every request waiting for a response fails once the driver stops
+            let _ = driver.await;
+        });
+        let mut connection = Self {
+            dispatcher,
+            driver,
+            session: None,
+        };
+        if server.auth.is_empty() {
+            return Ok(connection);
+        }
+        if let Some(token) = session {
+            let credentials = Credentials::Token {
+                token: token.clone(),
+            };
+            match connection.authenticate(credentials).await {
+                Ok(ClientResponse::Authenticated { .. }) => {
+                    connection.session = Some(token);
+                    return Ok(connection);
+                }
+                Ok(response) => return Err(unexpected(response)),
+                Err(ClientError::Unauthenticated(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let credentials = match builder.credentials.clone() {
+            Some(credentials) => credentials,
+            // clients without credentials act as anonymous clients, if the daemon lets them
+            None if server.auth.contains(&AuthMethod::Anonymous) => return Ok(connection),
+            None => return Err(ClientError::CredentialsRequired),
+        };
+        match connection.authenticate(credentials).await? {
+            ClientResponse::Authenticated { .. } => {}
+            ClientResponse::SessionStarted { token, .. } => connection.session = Some(token),
+            response => return Err(unexpected(response)),
+        }
+        Ok(connection)
+    }
+
+    /// Authenticates the connection with the given credentials
+    async fn authenticate(&self, credentials: Credentials) -> Result<ClientResponse, ClientError> {
+        let request = VersionedRequest::V1(ClientRequest::Auth { credentials });
+        let VersionedResponse::V1(response) = self.dispatcher.call(request).await?;
+        ClientError::from_response(response)
+    }
+
+    /// Checks whether the connection was lost
+    fn is_closed(&self) -> bool {
+        self.driver.is_finished()
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.driver.abort();
+    }
+}
+
+/// Creates the error for a response that does not answer the request
+fn unexpected(response: ClientResponse) -> ClientError {
+    ClientError::UnexpectedResponse(Box::new(response))
+}
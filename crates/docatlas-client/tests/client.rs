@@ -0,0 +1,125 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use docatlas_client::{Client, ClientError, Query};
+use docatlas_core::document::Document;
+use docatlas_core::error::DocatlasError;
+use docatlas_core::fields::{Field, FieldData, FieldKind, Fields};
+use docatlas_core::index::Upserted;
+use docatlas_core::transport::metrics::TransportMetrics;
+use docatlas_core::transport::queue::QueueConfig;
+use docatlas_core::transport::TcpTransport;
+use docatlas_daemon::client::{self, ClientRequest, ClientResponse};
+use docatlas_daemon::config::DaemonConfig;
+use docatlas_daemon::handlers::Hit;
+use tokio::net::TcpListener;
+
+fn document(id: &str) -> Document {
+    let mut fields = Fields::new();
+    let data = FieldData::Bytes(id.as_bytes().into());
+    fields.insert("id", Field::new(FieldKind::Keyword(8), [data]));
+    Document::from(fields)
+}
+
+/// Serves a single client, answering every request as a daemon holding a `books` index would
+async fn serve() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let capabilities = client::capabilities(&DaemonConfig::default());
+        let metrics = Arc::new(TransportMetrics::new());
+        let (_, transport) =
+            client::client_transport(TcpTransport::new(stream), &capabilities, &metrics)
+                .await
+                .unwrap();
+        let (mut client, writer) = client::Client::new(transport, QueueConfig::default());
+        tokio::spawn(writer);
+        while let Some(Ok(request)) = client.poll_request().await {
+            let response = match request.body() {
+                ClientRequest::ListIndexes => ClientResponse::Indexes {
+                    names: vec!["books".to_string()],
+                },
+                ClientRequest::IndexDocument { index, .. } if index == "books" => {
+                    ClientResponse::Upserted {
+                        row: 0,
+                        inserted: true,
+                    }
+                }
+                ClientRequest::Bulk { index, documents } if index == "books" => {
+                    ClientResponse::Bulk {
+                        items: (0..documents.len()).map(|row| Ok(Some(row))).collect(),
+                    }
+                }
+                ClientRequest::Search { index, limit, .. } if index == "books" => {
+                    ClientResponse::Hits {
+                        hits: (0..*limit)
+                            .map(|row| Hit {
+                                row,
+                                document: document(&row.to_string()),
+                            })
+                            .collect(),
+                    }
+                }
+                ClientRequest::Timed {
+                    partial: true,
+                    request,
+                    ..
+                } if matches!(**request, ClientRequest::Search { .. }) => ClientResponse::Partial {
+                    response: Box::new(ClientResponse::Hits { hits: vec![] }),
+                },
+                ClientRequest::Timed { timeout_ms, .. } => ClientResponse::TimedOut {
+                    after_ms: *timeout_ms,
+                },
+                ClientRequest::Promote => ClientResponse::Indexes { names: vec![] },
+                _ => ClientResponse::Failed {
+                    error: DocatlasError::NotFound("No index named \"authors\"".to_string()),
+                },
+            };
+            client.send_response(request.id(), response).await.unwrap();
+        }
+    });
+    addr
+}
+
+#[tokio::test]
+async fn typed_requests() {
+    let addr = serve().await;
+    let client = Client::connect(addr.to_string()).await.unwrap();
+    assert_eq!(client.indexes().await.unwrap(), ["books"]);
+
+    let books = client.index("books");
+    assert_eq!(
+        books.upsert(document("1")).await.unwrap(),
+        Upserted::Inserted(0)
+    );
+    let summary = books
+        .bulk_batched((0..5).map(|id| document(&id.to_string())), 2)
+        .await
+        .unwrap();
+    assert_eq!((summary.added, summary.dropped), (5, 0));
+    let hits = books
+        .search(Query::new("title", "dune").with_limit(3))
+        .await
+        .unwrap();
+    assert_eq!(hits.len(), 3);
+    let (hits, complete) = books
+        .search_partial(Query::new("title", "dune"))
+        .await
+        .unwrap();
+    assert!(hits.is_empty() && !complete);
+    let error = books
+        .search(Query::new("title", "dune").with_timeout(Duration::from_millis(5)))
+        .await
+        .unwrap_err();
+    assert!(matches!(error, ClientError::TimedOut(after) if after.as_millis() == 5));
+
+    let error = client.index("authors").mapping().await.unwrap_err();
+    assert!(matches!(
+        error,
+        ClientError::Failed(DocatlasError::NotFound(message)) if message.contains("authors")
+    ));
+    let error = client.promote().await.unwrap_err();
+    assert!(matches!(error, ClientError::UnexpectedResponse(_)));
+}
@@ -0,0 +1,408 @@
+//! Aggregations summarize the values of many documents. Documents are put into buckets by their
+//! key, and every bucket keeps its own [metrics](Metric), such as how many documents it holds or
+//! the sum of one of their fields.
+//!
+//! Missing values are skipped by every metric but [`Metric::CountRows`], like nulls are in sql.
+//!
+//! Groupings of separate parts of the documents, such as the segments of an index, can be
+//! [merged](Grouping::merge) into the grouping of every document.
+//!
+//! [`Terms`] aggregations keep only the buckets of the most common values of a field, along with
+//! documents representing every bucket.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::fields::FieldData;
+use crate::index::{IndexReader, SEGMENT_ROWS};
+use crate::query::comparable;
+
+pub use hyperloglog::{HyperLogLog, DEFAULT_PRECISION, MAX_PRECISION, MIN_PRECISION};
+pub use terms::{
+    Terms, TermsBucket, TermsOrder, TermsResult, TermsShard, TopHit, DEFAULT_TERMS_SIZE,
+};
+
+mod hyperloglog;
+mod terms;
+
+/// Summarizes the values of the documents in a bucket
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Metric {
+    /// The number of documents
+    CountRows,
+    /// The number of values
+    Count,
+    /// The sum of numbers, as a 64-bit float
+    Sum,
+    /// The mean of numbers, as a 64-bit float
+    Avg,
+    /// The smallest value
+    Min,
+    /// The largest value
+    Max,
+    /// The approximate number of distinct values, estimated by a [`HyperLogLog`] sketch of the
+    /// given precision
+    Cardinality { precision: u8 },
+}
+
+impl Metric {
+    fn accumulator(self) -> Accumulator {
+        match self {
+            Metric::CountRows | Metric::Count => Accumulator::Count(0),
+            Metric::Sum => Accumulator::Sum(None),
+            Metric::Avg => Accumulator::Avg { sum: 0.0, count: 0 },
+            Metric::Min => Accumulator::Extreme(Ordering::Less, None),
+            Metric::Max => Accumulator::Extreme(Ordering::Greater, None),
+            Metric::Cardinality { precision } => {
+                Accumulator::Cardinality(HyperLogLog::new(precision))
+            }
+        }
+    }
+}
+
+/// The running value of a metric
+#[derive(Debug, Clone)]
+enum Accumulator {
+    Count(u64),
+    Sum(Option<f64>),
+    Avg {
+        sum: f64,
+        count: u64,
+    },
+    /// The value furthest in the given direction
+    Extreme(Ordering, Option<FieldData>),
+    Cardinality(HyperLogLog),
+}
+
+impl Accumulator {
+    fn add(&mut self, metric: Metric, value: Option<&FieldData>) {
+        let number = || value.and_then(FieldData::to_big_float).map(|n| n.to_f64());
+        match self {
+            Accumulator::Count(count) => {
+                if metric == Metric::CountRows || value.is_some() {
+                    *count += 1;
+                }
+            }
+            Accumulator::Sum(sum) => {
+                if let Some(number) = number() {
+                    *sum = Some(sum.unwrap_or(0.0) + number);
+                }
+            }
+            Accumulator::Avg { sum, count } => {
+                if let Some(number) = number() {
+                    *sum += number;
+                    *count += 1;
+                }
+            }
+            Accumulator::Extreme(direction, extreme) => {
+                let Some(value) = value.map(comparable) else {
+                    return;
+                };
+                let replace = match extreme {
+                    Some(extreme) => value.compare(extreme) == Some(*direction),
+                    None => true,
+                };
+                if replace {
+                    *extreme = Some(value.into_owned());
+                }
+            }
+            Accumulator::Cardinality(sketch) => {
+                if let Some(value) = value {
+                    sketch.add(value);
+                }
+            }
+        }
+    }
+
+    /// Adds the values accumulated by another accumulator of the same metric
+    fn merge(&mut self, other: Accumulator) {
+        match (self, other) {
+            (Accumulator::Count(count), Accumulator::Count(other)) => *count += other,
+            (Accumulator::Sum(sum), Accumulator::Sum(other)) => {
+                if let Some(other) = other {
+                    *sum = Some(sum.unwrap_or(0.0) + other);
+                }
+            }
+            (Accumulator::Avg { sum, count }, Accumulator::Avg { sum: s, count: c }) => {
+                *sum += s;
+                *count += c;
+            }
+            (Accumulator::Extreme(direction, extreme), Accumulator::Extreme(_, other)) => {
+                let replace = match (&extreme, &other) {
+                    (Some(extreme), Some(other)) => other.compare(extreme) == Some(*direction),
+                    (None, _) => true,
+                    (Some(_), None) => false,
+                };
+                if replace {
+                    *extreme = other;
+                }
+            }
+            (Accumulator::Cardinality(sketch), Accumulator::Cardinality(other)) => {
+                sketch.merge(&other)
+            }
+            (accumulator, other) => {
+                unreachable!("{accumulator:?} and {other:?} accumulate different metrics")
+            }
+        }
+    }
+
+    fn finish(self) -> Option<FieldData> {
+        match self {
+            Accumulator::Count(count) => Some(FieldData::U64(count)),
+            Accumulator::Sum(sum) => sum.map(FieldData::F64),
+            Accumulator::Avg { count: 0, .. } => None,
+            Accumulator::Avg { sum, count } => Some(FieldData::F64(sum / count as f64)),
+            Accumulator::Extreme(_, extreme) => extreme,
+            Accumulator::Cardinality(sketch) => Some(FieldData::U64(sketch.estimate())),
+        }
+    }
+}
+
+/// The documents sharing a key, summarized by every metric of a grouping
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Bucket {
+    pub key: Vec<Option<FieldData>>,
+    /// The number of documents in the bucket
+    pub count: usize,
+    /// The value of every metric, in the order they were given, or nothing if no value was seen
+    pub values: Vec<Option<FieldData>>,
+}
+
+/// Puts documents into buckets by their key, in the order the keys were first seen in
+#[derive(Debug)]
+pub struct Grouping {
+    metrics: Vec<Metric>,
+    /// The buckets by the encoded form of their key, since values can not be hashed
+    positions: HashMap<Vec<u8>, usize>,
+    buckets: Vec<(Vec<Option<FieldData>>, usize, Vec<Accumulator>)>,
+}
+
+impl Grouping {
+    /// Creates a grouping without buckets, keeping the given metrics
+    pub fn new(metrics: Vec<Metric>) -> Self {
+        Self {
+            metrics,
+            positions: HashMap::new(),
+            buckets: vec![],
+        }
+    }
+
+    /// Gets the metrics kept for every bucket
+    pub fn metrics(&self) -> &[Metric] {
+        &self.metrics
+    }
+
+    /// Adds a document to the bucket of its key, along with its value for every metric. Analyzed
+    /// text is keyed by its text alone.
+    ///
+    /// # Panics
+    /// Panics if not given exactly one value per metric.
+    pub fn add(&mut self, key: Vec<Option<FieldData>>, values: &[Option<FieldData>]) {
+        assert_eq!(values.len(), self.metrics.len(), "one value per metric");
+        let key = key
+            .into_iter()
+            .map(|data| data.map(|data| comparable(&data).into_owned()))
+            .collect::<Vec<_>>();
+        let encoded = postcard::to_stdvec(&key).expect("values can always be encoded");
+        let position = *self.positions.entry(encoded).or_insert_with(|| {
+            let accumulators = self.metrics.iter().map(|metric| metric.accumulator());
+            self.buckets.push((key, 0, accumulators.collect()));
+            self.buckets.len() - 1
+        });
+        let (_, count, accumulators) = &mut self.buckets[position];
+        *count += 1;
+        for ((accumulator, metric), value) in accumulators.iter_mut().zip(&self.metrics).zip(values)
+        {
+            accumulator.add(*metric, value.as_ref());
+        }
+    }
+
+    /// Adds the buckets of another grouping keeping the same metrics, such as one of the documents
+    /// of another segment. Buckets first seen by the other grouping come after the buckets of this
+    /// one.
+    ///
+    /// # Panics
+    /// Panics if the groupings keep different metrics.
+    pub fn merge(&mut self, other: Grouping) {
+        assert_eq!(
+            self.metrics, other.metrics,
+            "groupings keep the same metrics"
+        );
+        let mut positions = other.positions.into_iter().collect::<Vec<_>>();
+        positions.sort_by_key(|(_, position)| *position);
+        for ((encoded, _), (key, count, accumulators)) in positions.into_iter().zip(other.buckets) {
+            match self.positions.get(&encoded) {
+                Some(&position) => {
+                    let (_, merged, merged_accumulators) = &mut self.buckets[position];
+                    *merged += count;
+                    for (merged, accumulator) in merged_accumulators.iter_mut().zip(accumulators) {
+                        merged.merge(accumulator);
+                    }
+                }
+                None => {
+                    self.positions.insert(encoded, self.buckets.len());
+                    self.buckets.push((key, count, accumulators));
+                }
+            }
+        }
+    }
+
+    /// Gets every bucket, in the order their keys were first seen in
+    pub fn finish(self) -> Vec<Bucket> {
+        self.buckets
+            .into_iter()
+            .map(|(key, count, accumulators)| Bucket {
+                key,
+                count,
+                values: accumulators.into_iter().map(Accumulator::finish).collect(),
+            })
+            .collect()
+    }
+}
+
+/// Estimates the number of distinct values of a field among the documents of an index. Every
+/// segment of the index is sketched on its own, and the sketches are merged into one.
+pub fn cardinality(reader: &impl IndexReader, field: &str, precision: u8) -> HyperLogLog {
+    let mut merged = HyperLogLog::new(precision);
+    for start in (0..reader.len()).step_by(SEGMENT_ROWS) {
+        let mut sketch = HyperLogLog::new(precision);
+        for row in start..(start + SEGMENT_ROWS).min(reader.len()) {
+            let value = reader
+                .is_live(row)
+                .then(|| reader.stored(row)?.get(field).ok().flatten());
+            if let Some(value) = value.flatten() {
+                sketch.add(&value.to_field_data());
+            }
+        }
+        merged.merge(&sketch);
+    }
+    merged
+}
+
+/// Orders two values that may be missing, with missing values first. Values that can not be
+/// compared are treated as equal.
+pub fn compare_values(a: Option<&FieldData>, b: Option<&FieldData>) -> Ordering {
+    match (a, b) {
+        (Some(a), Some(b)) => comparable(a)
+            .compare(&comparable(b))
+            .unwrap_or(Ordering::Equal),
+        (a, b) => a.is_some().cmp(&b.is_some()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fields::{Field, FieldKind, Fields};
+    use crate::index::IndexWriter;
+    use crate::persist::PersistentVec;
+    use crate::schema::{Schema, SchemaField};
+
+    #[test]
+    fn group_documents() {
+        let mut grouping = Grouping::new(vec![
+            Metric::CountRows,
+            Metric::Count,
+            Metric::Sum,
+            Metric::Avg,
+            Metric::Min,
+            Metric::Max,
+        ]);
+        let text = |text: &str| Some(FieldData::Bytes(text.as_bytes().into()));
+        for (author, year) in [
+            ("herbert", Some(1965)),
+            ("austen", Some(1815)),
+            ("herbert", None),
+        ] {
+            let year = year.map(FieldData::I64);
+            grouping.add(vec![text(author)], &vec![year; 6]);
+        }
+        let buckets = grouping.finish();
+        assert_eq!(buckets.len(), 2);
+        assert_eq!(buckets[0].key, [text("herbert")]);
+        assert_eq!(buckets[0].count, 2);
+        assert_eq!(
+            buckets[0].values,
+            [
+                Some(FieldData::U64(2)),
+                Some(FieldData::U64(1)),
+                Some(FieldData::F64(1965.0)),
+                Some(FieldData::F64(1965.0)),
+                Some(FieldData::I64(1965)),
+                Some(FieldData::I64(1965)),
+            ]
+        );
+        assert_eq!(buckets[1].key, [text("austen")]);
+
+        let mut grouping = Grouping::new(vec![Metric::Sum, Metric::Max]);
+        grouping.add(vec![], &[None, None]);
+        assert_eq!(grouping.finish()[0].values, [None, None]);
+    }
+
+    #[test]
+    fn merge_groupings() {
+        let metrics = vec![
+            Metric::CountRows,
+            Metric::Avg,
+            Metric::Min,
+            Metric::Cardinality { precision: 10 },
+        ];
+        let shelf = |shelf: &str| vec![Some(FieldData::Bytes(shelf.as_bytes().into()))];
+        let values = |year: i64| vec![Some(FieldData::I64(year)); 4];
+        let mut first = Grouping::new(metrics.clone());
+        first.add(shelf("a"), &values(1965));
+        first.add(shelf("a"), &values(1965));
+        let mut second = Grouping::new(metrics);
+        second.add(shelf("b"), &values(1815));
+        second.add(shelf("a"), &values(1951));
+        first.merge(second);
+
+        let buckets = first.finish();
+        assert_eq!(buckets.len(), 2);
+        assert_eq!(buckets[0].key, shelf("a"));
+        assert_eq!(buckets[0].count, 3);
+        assert_eq!(
+            buckets[0].values,
+            [
+                Some(FieldData::U64(3)),
+                Some(FieldData::F64(1960.3333333333333)),
+                Some(FieldData::I64(1951)),
+                Some(FieldData::U64(2)),
+            ]
+        );
+        assert_eq!(buckets[1].key, shelf("b"));
+        assert_eq!(buckets[1].values[3], Some(FieldData::U64(1)));
+    }
+
+    #[test]
+    fn estimate_cardinality_by_segment() {
+        let schema = Schema::from_iter([SchemaField {
+            name: "author".to_string(),
+            kind: FieldKind::Keyword(16),
+        }]);
+        let mut writer = IndexWriter::new(schema, PersistentVec::in_memory());
+        let documents = (0..SEGMENT_ROWS + 100).map(|row| {
+            let author = FieldData::Bytes(format!("author {}", row % 1000).as_bytes().into());
+            let mut fields = Fields::new();
+            fields.insert("author", Field::new(FieldKind::Keyword(16), [author]));
+            fields.into()
+        });
+        writer.add_documents(documents);
+        let sketch = cardinality(&writer, "author", DEFAULT_PRECISION);
+        assert!((990..=1010).contains(&sketch.estimate()), "{sketch:?}");
+        assert!(cardinality(&writer, "missing", DEFAULT_PRECISION).is_empty());
+    }
+
+    #[test]
+    fn compare_missing_values() {
+        let one = FieldData::I64(1);
+        assert_eq!(compare_values(None, Some(&one)), Ordering::Less);
+        assert_eq!(compare_values(Some(&one), Some(&one)), Ordering::Equal);
+        assert_eq!(
+            compare_values(Some(&FieldData::F64(2.0)), Some(&one)),
+            Ordering::Greater
+        );
+    }
+}
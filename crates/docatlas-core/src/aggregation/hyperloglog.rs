@@ -0,0 +1,271 @@
+//! HyperLogLog sketches estimate the number of distinct values among many, in a fixed amount of
+//! memory.
+//!
+//! Every value is hashed to 64 bits. The first `precision` bits pick one of the registers of the
+//! sketch, which keeps the most leading zeros seen in the rest of the hashes it was picked by.
+//! Estimates are within about `1.04 / sqrt(2^precision)` of the actual count, so the default
+//! precision of 14 is usually within 1%, using 16kB per sketch.
+//!
+//! Like HyperLogLog++, hashes are 64 bits wide, so large counts need no correction, and small
+//! counts are estimated by linear counting up to a threshold that depends on the precision.
+//! Sketches of few values keep only their registers that are set, until that takes more room than
+//! keeping every register. The empirical bias correction of HyperLogLog++ is left out, which
+//! makes estimates just past the linear counting threshold slightly less accurate.
+//!
+//! Sketches can be [merged](HyperLogLog::merge), so every segment of an index can be sketched on
+//! its own and the sketches of the segments merged into the sketch of the index.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::fields::FieldData;
+use crate::index::fnv1a;
+use crate::query::comparable;
+
+/// The lowest precision of a sketch
+pub const MIN_PRECISION: u8 = 4;
+
+/// The highest precision of a sketch
+pub const MAX_PRECISION: u8 = 18;
+
+/// The precision used unless another one is given
+pub const DEFAULT_PRECISION: u8 = 14;
+
+/// The estimate below which linear counting is used, by precision starting at [`MIN_PRECISION`]
+const LINEAR_COUNTING_THRESHOLDS: [f64; 15] = [
+    10.0, 20.0, 40.0, 80.0, 220.0, 400.0, 900.0, 1800.0, 3100.0, 6500.0, 11500.0, 20000.0, 50000.0,
+    120000.0, 350000.0,
+];
+
+/// Estimates the number of distinct values added to it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HyperLogLog {
+    precision: u8,
+    registers: Registers,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+enum Registers {
+    /// Only the registers that are set, by their index
+    Sparse(BTreeMap<u32, u8>),
+    Dense(Vec<u8>),
+}
+
+impl HyperLogLog {
+    /// Creates an empty sketch with `2^precision` registers. The precision is clamped between
+    /// [`MIN_PRECISION`] and [`MAX_PRECISION`].
+    pub fn new(precision: u8) -> Self {
+        Self {
+            precision: precision.clamp(MIN_PRECISION, MAX_PRECISION),
+            registers: Registers::Sparse(BTreeMap::new()),
+        }
+    }
+
+    /// Gets the precision of the sketch
+    pub fn precision(&self) -> u8 {
+        self.precision
+    }
+
+    /// Checks whether nothing was added to the sketch
+    pub fn is_empty(&self) -> bool {
+        match &self.registers {
+            Registers::Sparse(registers) => registers.is_empty(),
+            Registers::Dense(registers) => registers.iter().all(|&register| register == 0),
+        }
+    }
+
+    /// Adds a value to the sketch. Analyzed text is counted by its text alone.
+    pub fn add(&mut self, value: &FieldData) {
+        let encoded =
+            postcard::to_stdvec(&*comparable(value)).expect("values can always be encoded");
+        self.add_hash(hash(&encoded));
+    }
+
+    /// Adds a value to the sketch by its 64-bit hash, which must spread values evenly over its bits
+    pub fn add_hash(&mut self, hash: u64) {
+        let index = (hash >> (64 - self.precision)) as u32;
+        // a marker bit bounds the zeros counted to the bits past the index
+        let rest = (hash << self.precision) | (1 << (self.precision - 1));
+        self.set(index, rest.leading_zeros() as u8 + 1);
+    }
+
+    /// Adds every value added to another sketch. If the sketches have different precisions, the
+    /// merged sketch has the lower of them.
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        if other.precision < self.precision {
+            self.reduce(other.precision);
+        }
+        for (index, rank) in other.folded(self.precision) {
+            self.set(index, rank);
+        }
+    }
+
+    /// Estimates the number of distinct values added to the sketch
+    pub fn estimate(&self) -> u64 {
+        let m = self.registers() as f64;
+        let (sum, zeros) = match &self.registers {
+            Registers::Sparse(registers) => {
+                let set = registers
+                    .values()
+                    .map(|&rank| 2f64.powi(-(rank as i32)))
+                    .sum::<f64>();
+                let zeros = self.registers() - registers.len();
+                (set + zeros as f64, zeros)
+            }
+            Registers::Dense(registers) => {
+                let sum = registers
+                    .iter()
+                    .map(|&rank| 2f64.powi(-(rank as i32)))
+                    .sum::<f64>();
+                (sum, registers.iter().filter(|&&rank| rank == 0).count())
+            }
+        };
+        if zeros > 0 {
+            let linear = m * (m / zeros as f64).ln();
+            if linear <= LINEAR_COUNTING_THRESHOLDS[(self.precision - MIN_PRECISION) as usize] {
+                return linear.round() as u64;
+            }
+        }
+        (alpha(m) * m * m / sum).round() as u64
+    }
+
+    fn registers(&self) -> usize {
+        1 << self.precision
+    }
+
+    /// Raises a register to a rank, unless it is higher already
+    fn set(&mut self, index: u32, rank: u8) {
+        let len = self.registers();
+        match &mut self.registers {
+            Registers::Sparse(registers) => {
+                let register = registers.entry(index).or_default();
+                *register = (*register).max(rank);
+                // a set register takes about 8 times the room of a dense one
+                if registers.len() > len / 8 {
+                    let mut dense = vec![0; len];
+                    for (&index, &rank) in registers.iter() {
+                        dense[index as usize] = rank;
+                    }
+                    self.registers = Registers::Dense(dense);
+                }
+            }
+            Registers::Dense(registers) => {
+                let register = &mut registers[index as usize];
+                *register = (*register).max(rank);
+            }
+        }
+    }
+
+    /// Gets the registers that are set as they would be at a lower precision
+    fn folded(&self, precision: u8) -> Vec<(u32, u8)> {
+        let dropped = self.precision - precision;
+        let set: Box<dyn Iterator<Item = (u32, u8)>> = match &self.registers {
+            Registers::Sparse(registers) => Box::new(registers.iter().map(|(&i, &r)| (i, r))),
+            Registers::Dense(registers) => Box::new(
+                registers
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, &rank)| rank != 0)
+                    .map(|(index, &rank)| (index as u32, rank)),
+            ),
+        };
+        set.map(|(index, rank)| {
+            // the bits dropped from the index are now the first bits counted for the rank
+            let bits = index & ((1 << dropped) - 1);
+            let rank = match bits {
+                0 => rank + dropped,
+                bits => (bits.leading_zeros() - (32 - dropped as u32)) as u8 + 1,
+            };
+            (index >> dropped, rank)
+        })
+        .collect()
+    }
+
+    /// Lowers the precision of the sketch
+    fn reduce(&mut self, precision: u8) {
+        let folded = self.folded(precision);
+        *self = Self::new(precision);
+        for (index, rank) in folded {
+            self.set(index, rank);
+        }
+    }
+}
+
+impl Default for HyperLogLog {
+    fn default() -> Self {
+        Self::new(DEFAULT_PRECISION)
+    }
+}
+
+/// Corrects the bias of the raw estimate for the number of registers
+fn alpha(m: f64) -> f64 {
+    match m as usize {
+        16 => 0.673,
+        32 => 0.697,
+        64 => 0.709,
+        _ => 0.7213 / (1.0 + 1.079 / m),
+    }
+}
+
+/// Hashes bytes into 64 bits that are spread evenly, so every bit of the hash is as likely to be
+/// set as any other
+fn hash(bytes: &[u8]) -> u64 {
+    // fnv spreads the last bytes poorly over the high bits, which pick the register
+    let mut hash = fnv1a(bytes);
+    hash ^= hash >> 30;
+    hash = hash.wrapping_mul(0xbf58476d1ce4e5b9);
+    hash ^= hash >> 27;
+    hash = hash.wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sketch(precision: u8, values: impl IntoIterator<Item = u64>) -> HyperLogLog {
+        let mut sketch = HyperLogLog::new(precision);
+        for value in values {
+            sketch.add(&FieldData::U64(value));
+        }
+        sketch
+    }
+
+    fn assert_close(estimate: u64, actual: u64, error: f64) {
+        let off = (estimate as f64 - actual as f64).abs() / actual as f64;
+        assert!(off <= error, "estimated {estimate} for {actual}");
+    }
+
+    #[test]
+    fn estimate_distinct_values() {
+        assert_eq!(HyperLogLog::default().estimate(), 0);
+        // repeated values are counted once
+        let small = sketch(14, (0..100).chain(0..100));
+        assert!(matches!(small.registers, Registers::Sparse(_)));
+        assert_close(small.estimate(), 100, 0.02);
+
+        let large = sketch(14, 0..200_000);
+        assert!(matches!(large.registers, Registers::Dense(_)));
+        assert_close(large.estimate(), 200_000, 0.03);
+        assert_close(sketch(8, 0..200_000).estimate(), 200_000, 0.2);
+        assert_eq!(HyperLogLog::new(40).precision(), MAX_PRECISION);
+    }
+
+    #[test]
+    fn merge_sketches() {
+        let mut merged = sketch(14, 0..30_000);
+        merged.merge(&sketch(14, 20_000..50_000));
+        assert_close(merged.estimate(), 50_000, 0.03);
+        assert_eq!(merged, sketch(14, 0..50_000));
+
+        // merging lowers the precision to the lowest of the sketches
+        let mut coarse = sketch(10, 0..30_000);
+        coarse.merge(&sketch(14, 20_000..50_000));
+        assert_eq!(coarse.precision(), 10);
+        assert_eq!(coarse, sketch(10, 0..50_000));
+        let mut fine = sketch(14, 20_000..50_000);
+        fine.merge(&sketch(10, 0..30_000));
+        assert_eq!(fine, sketch(10, 0..50_000));
+    }
+}
@@ -0,0 +1,471 @@
+//! Terms aggregations put documents into a bucket per value of a field, such as a keyword, and
+//! keep the buckets of the most common values.
+//!
+//! Indexes split into shards are aggregated by [collecting](Terms::collect) every shard on its
+//! own, and [reducing](Terms::reduce) the buckets of the shards into the buckets of the index.
+//! Every shard only returns its top `shard_size` buckets, so a value that is common overall but
+//! left out by some shards is undercounted. Counting more buckets per shard makes that less likely
+//! at the cost of sending more buckets to be reduced, and the reduced aggregation bounds how far
+//! off the count of any of its buckets may be.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::document::Document;
+use crate::fields::FieldData;
+use crate::index::IndexReader;
+use crate::query::comparable;
+
+use super::{compare_values, Accumulator, Metric};
+
+/// The number of buckets kept unless another number is given
+pub const DEFAULT_TERMS_SIZE: usize = 10;
+
+/// How the buckets of a terms aggregation are ordered, which decides the buckets that are kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum TermsOrder {
+    /// The buckets holding the most documents first
+    #[default]
+    Count,
+    /// The buckets in the order of their values
+    Key,
+    /// The buckets in the order of one of the metrics of the aggregation, by its position
+    Metric { metric: usize, descending: bool },
+}
+
+/// Keeps the buckets of the most common values of a field, or the buckets ordered first by
+/// another [order](TermsOrder)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Terms {
+    field: String,
+    size: usize,
+    shard_size: Option<usize>,
+    order: TermsOrder,
+    /// Every metric kept for the buckets, along with the field it summarizes
+    metrics: Vec<(Metric, String)>,
+    top_hits: usize,
+}
+
+/// A document of a bucket returned to represent it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TopHit {
+    /// The position of the shard the document was collected from, among the shards reduced
+    pub shard: usize,
+    pub row: usize,
+    pub document: Document,
+}
+
+/// The buckets collected from a single shard, before they are reduced
+#[derive(Debug, Clone)]
+pub struct TermsShard {
+    buckets: Vec<ShardBucket>,
+    /// The documents of the buckets left out
+    other_count: usize,
+    /// The number of documents of the last bucket kept, if any bucket was left out
+    cutoff: Option<usize>,
+}
+
+#[derive(Debug, Clone)]
+struct ShardBucket {
+    key: FieldData,
+    count: usize,
+    accumulators: Vec<Accumulator>,
+    hits: Vec<TopHit>,
+}
+
+/// The documents holding a value of the field aggregated
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TermsBucket {
+    pub key: FieldData,
+    /// The number of documents in the bucket
+    pub count: usize,
+    /// The value of every metric, in the order they were added, or nothing if no value was seen
+    pub values: Vec<Option<FieldData>>,
+    /// The first documents of the bucket, by shard and then by row
+    pub hits: Vec<TopHit>,
+}
+
+/// The buckets kept by a terms aggregation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TermsResult {
+    pub buckets: Vec<TermsBucket>,
+    /// The most documents any bucket may be missing, because shards left out its value. Only known
+    /// when buckets are ordered by their count.
+    pub count_error_bound: Option<usize>,
+    /// The number of documents holding a value of the field that are in none of the buckets
+    pub other_count: usize,
+}
+
+impl Terms {
+    /// Creates an aggregation keeping the [`DEFAULT_TERMS_SIZE`] most common values of a field
+    pub fn new(field: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            size: DEFAULT_TERMS_SIZE,
+            shard_size: None,
+            order: TermsOrder::Count,
+            metrics: vec![],
+            top_hits: 0,
+        }
+    }
+
+    /// Sets the number of buckets kept
+    pub fn with_size(mut self, size: usize) -> Self {
+        self.size = size;
+        self
+    }
+
+    /// Sets the number of buckets every shard returns, which is never less than the number of
+    /// buckets kept
+    pub fn with_shard_size(mut self, shard_size: usize) -> Self {
+        self.shard_size = Some(shard_size);
+        self
+    }
+
+    /// Sets how buckets are ordered
+    pub fn with_order(mut self, order: TermsOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Adds a metric of a field kept for every bucket
+    pub fn with_metric(mut self, metric: Metric, field: impl Into<String>) -> Self {
+        self.metrics.push((metric, field.into()));
+        self
+    }
+
+    /// Sets the number of documents returned with every bucket
+    pub fn with_top_hits(mut self, top_hits: usize) -> Self {
+        self.top_hits = top_hits;
+        self
+    }
+
+    /// Gets the field aggregated
+    pub fn field(&self) -> &str {
+        &self.field
+    }
+
+    /// Gets the number of buckets kept
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Gets the number of buckets every shard returns. Unless it was set, shards return half again
+    /// as many buckets as are kept, and 10 more, so fewer common values are left out.
+    pub fn shard_size(&self) -> usize {
+        self.shard_size
+            .unwrap_or(self.size + self.size / 2 + 10)
+            .max(self.size)
+    }
+
+    /// Gets how buckets are ordered
+    pub fn order(&self) -> TermsOrder {
+        self.order
+    }
+
+    /// Gets the metrics kept for every bucket, along with the fields they summarize
+    pub fn metrics(&self) -> &[(Metric, String)] {
+        &self.metrics
+    }
+
+    /// Gets the number of documents returned with every bucket
+    pub fn top_hits(&self) -> usize {
+        self.top_hits
+    }
+
+    /// Aggregates the given rows of a single index, as its only shard
+    pub fn aggregate(
+        &self,
+        reader: &impl IndexReader,
+        rows: impl IntoIterator<Item = usize>,
+    ) -> TermsResult {
+        self.reduce([self.collect(reader, rows, 0)])
+    }
+
+    /// Collects the buckets of the given rows of a shard, keeping the top `shard_size` of them.
+    /// The shard is numbered by its position among the shards that are reduced.
+    pub fn collect(
+        &self,
+        reader: &impl IndexReader,
+        rows: impl IntoIterator<Item = usize>,
+        shard: usize,
+    ) -> TermsShard {
+        let mut positions = HashMap::new();
+        let mut buckets: Vec<ShardBucket> = vec![];
+        for row in rows {
+            let Some(stored) = reader.stored(row).filter(|_| reader.is_live(row)) else {
+                continue;
+            };
+            let Ok(Some(value)) = stored.get(&self.field) else {
+                continue;
+            };
+            let key = comparable(&value.to_field_data()).into_owned();
+            let encoded = postcard::to_stdvec(&key).expect("values can always be encoded");
+            let position = *positions.entry(encoded).or_insert_with(|| {
+                buckets.push(ShardBucket {
+                    key,
+                    count: 0,
+                    accumulators: self.metrics.iter().map(|(m, _)| m.accumulator()).collect(),
+                    hits: vec![],
+                });
+                buckets.len() - 1
+            });
+            let bucket = &mut buckets[position];
+            bucket.count += 1;
+            for (accumulator, (metric, field)) in bucket.accumulators.iter_mut().zip(&self.metrics)
+            {
+                let value = stored.get(field).ok().flatten();
+                accumulator.add(*metric, value.map(|value| value.to_field_data()).as_ref());
+            }
+            if bucket.hits.len() < self.top_hits {
+                if let Ok(document) = stored.to_document() {
+                    bucket.hits.push(TopHit {
+                        shard,
+                        row,
+                        document,
+                    });
+                }
+            }
+        }
+        let (buckets, other_count) = self.top(buckets, self.shard_size());
+        let cutoff = match other_count {
+            0 => None,
+            _ => buckets.last().map(|bucket| bucket.count),
+        };
+        TermsShard {
+            buckets,
+            other_count,
+            cutoff,
+        }
+    }
+
+    /// Reduces the buckets collected from every shard into the buckets kept
+    pub fn reduce(&self, shards: impl IntoIterator<Item = TermsShard>) -> TermsResult {
+        let mut positions = HashMap::new();
+        let mut buckets: Vec<ShardBucket> = vec![];
+        let mut other_count = 0;
+        let mut count_error_bound = 0;
+        for shard in shards {
+            other_count += shard.other_count;
+            count_error_bound += shard.cutoff.unwrap_or(0);
+            for bucket in shard.buckets {
+                let encoded =
+                    postcard::to_stdvec(&bucket.key).expect("values can always be encoded");
+                match positions.get(&encoded) {
+                    Some(&position) => {
+                        let merged: &mut ShardBucket = &mut buckets[position];
+                        merged.count += bucket.count;
+                        for (merged, accumulator) in
+                            merged.accumulators.iter_mut().zip(bucket.accumulators)
+                        {
+                            merged.merge(accumulator);
+                        }
+                        merged.hits.extend(bucket.hits);
+                    }
+                    None => {
+                        positions.insert(encoded, buckets.len());
+                        buckets.push(bucket);
+                    }
+                }
+            }
+        }
+        let (buckets, left_out) = self.top(buckets, self.size);
+        TermsResult {
+            buckets: buckets
+                .into_iter()
+                .map(|mut bucket| {
+                    bucket.hits.sort_by_key(|hit| (hit.shard, hit.row));
+                    bucket.hits.truncate(self.top_hits);
+                    TermsBucket {
+                        key: bucket.key,
+                        count: bucket.count,
+                        values: bucket
+                            .accumulators
+                            .into_iter()
+                            .map(Accumulator::finish)
+                            .collect(),
+                        hits: bucket.hits,
+                    }
+                })
+                .collect(),
+            count_error_bound: match self.order {
+                TermsOrder::Count => Some(count_error_bound),
+                _ => None,
+            },
+            other_count: other_count + left_out,
+        }
+    }
+
+    /// Orders buckets and keeps the first `size` of them, returning them along with the number of
+    /// documents in the buckets left out
+    fn top(&self, buckets: Vec<ShardBucket>, size: usize) -> (Vec<ShardBucket>, usize) {
+        let mut ordered = buckets
+            .into_iter()
+            .map(|bucket| {
+                let value = match self.order {
+                    TermsOrder::Metric { metric, .. } => bucket
+                        .accumulators
+                        .get(metric)
+                        .and_then(|accumulator| accumulator.clone().finish()),
+                    _ => None,
+                };
+                (value, bucket)
+            })
+            .collect::<Vec<_>>();
+        ordered.sort_by(|(a_value, a), (b_value, b)| {
+            let by_key = || compare_values(Some(&a.key), Some(&b.key));
+            let by_count = || b.count.cmp(&a.count);
+            match self.order {
+                TermsOrder::Count => by_count().then_with(by_key),
+                TermsOrder::Key => by_key(),
+                TermsOrder::Metric { descending, .. } => {
+                    let by_value = compare_values(a_value.as_ref(), b_value.as_ref());
+                    match descending {
+                        true => by_value.reverse(),
+                        false => by_value,
+                    }
+                    .then_with(by_count)
+                    .then_with(by_key)
+                }
+            }
+        });
+        let left_out = ordered
+            .iter()
+            .skip(size)
+            .map(|(_, bucket)| bucket.count)
+            .sum();
+        ordered.truncate(size);
+        (
+            ordered.into_iter().map(|(_, bucket)| bucket).collect(),
+            left_out,
+        )
+    }
+}
+
+impl TermsShard {
+    /// Gets the number of buckets returned by the shard
+    pub fn len(&self) -> usize {
+        self.buckets.len()
+    }
+
+    /// Checks whether the shard returned no buckets
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fields::{Field, FieldKind, Fields};
+    use crate::index::IndexWriter;
+    use crate::persist::PersistentVec;
+    use crate::schema::{Schema, SchemaField};
+
+    use super::*;
+
+    fn shelf(books: &[(&str, i64)]) -> IndexWriter {
+        let schema = Schema::from_iter([
+            SchemaField {
+                name: "author".to_string(),
+                kind: FieldKind::Keyword(16),
+            },
+            SchemaField {
+                name: "year".to_string(),
+                kind: FieldKind::I64,
+            },
+        ]);
+        let mut writer = IndexWriter::new(schema, PersistentVec::in_memory());
+        writer.add_documents(books.iter().map(|&(author, year)| {
+            let mut fields = Fields::new();
+            let author = FieldData::Bytes(author.as_bytes().into());
+            fields.insert("author", Field::new(FieldKind::Keyword(16), [author]));
+            fields.insert("year", Field::new(FieldKind::I64, [FieldData::I64(year)]));
+            Document::from(fields)
+        }));
+        writer
+    }
+
+    fn keys(result: &TermsResult) -> Vec<(String, usize)> {
+        result
+            .buckets
+            .iter()
+            .map(|bucket| match &bucket.key {
+                FieldData::Bytes(key) => (String::from_utf8(key.to_vec()).unwrap(), bucket.count),
+                key => panic!("unexpected key {key:?}"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn keep_the_most_common_terms() {
+        let writer = shelf(&[
+            ("herbert", 1965),
+            ("austen", 1815),
+            ("herbert", 1969),
+            ("tolkien", 1954),
+            ("austen", 1813),
+            ("herbert", 1976),
+        ]);
+        let terms = Terms::new("author")
+            .with_size(2)
+            .with_metric(Metric::Max, "year")
+            .with_top_hits(2);
+        let result = terms.aggregate(&writer, 0..writer.len());
+        assert_eq!(keys(&result), [("herbert".into(), 3), ("austen".into(), 2)]);
+        assert_eq!(result.other_count, 1);
+        assert_eq!(result.count_error_bound, Some(0));
+        assert_eq!(result.buckets[0].values, [Some(FieldData::I64(1976))]);
+        let hits = &result.buckets[0].hits;
+        assert_eq!(hits.iter().map(|hit| hit.row).collect::<Vec<_>>(), [0, 2]);
+        assert_eq!(
+            hits[0].document.get("year").unwrap().data(),
+            [FieldData::I64(1965)]
+        );
+
+        // ordered by the earliest year of every author
+        let terms = Terms::new("author")
+            .with_metric(Metric::Min, "year")
+            .with_order(TermsOrder::Metric {
+                metric: 0,
+                descending: false,
+            });
+        let result = terms.aggregate(&writer, [0, 1, 2, 3]);
+        assert_eq!(
+            keys(&result),
+            [
+                ("austen".into(), 1),
+                ("tolkien".into(), 1),
+                ("herbert".into(), 2)
+            ]
+        );
+        assert_eq!(result.count_error_bound, None);
+    }
+
+    #[test]
+    fn reduce_shards() {
+        let first = shelf(&[("a", 1), ("a", 1), ("a", 1), ("b", 1), ("b", 1), ("c", 1)]);
+        let second = shelf(&[("c", 1), ("c", 1), ("c", 1), ("b", 1), ("b", 1), ("a", 1)]);
+        let terms = Terms::new("author")
+            .with_size(1)
+            .with_shard_size(2)
+            .with_top_hits(3);
+        let shards = [
+            terms.collect(&first, 0..first.len(), 0),
+            terms.collect(&second, 0..second.len(), 1),
+        ];
+        assert_eq!(shards[0].len(), 2);
+        let result = terms.reduce(shards);
+        // every author wrote 4 books, but each shard left out the book of another author
+        assert_eq!(keys(&result), [("b".into(), 4)]);
+        assert_eq!(result.other_count, 8);
+        assert_eq!(result.count_error_bound, Some(4));
+        let hits = &result.buckets[0].hits;
+        assert_eq!(
+            hits.iter()
+                .map(|hit| (hit.shard, hit.row))
+                .collect::<Vec<_>>(),
+            [(0, 3), (0, 4), (1, 3)]
+        );
+    }
+}
@@ -0,0 +1,124 @@
+//! Analysis splits text into the tokens that are indexed.
+//!
+//! Text is split at every non alphanumeric character and lowercased. Every token keeps its
+//! position within the text and the byte range it was taken from, so phrase matching and
+//! highlighting can be done without analyzing the text again.
+
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+
+/// A single token of analyzed text
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Token {
+    /// The lowercased term of the token
+    pub term: String,
+    /// The position of the token within the text, counted in tokens
+    pub position: usize,
+    /// The byte offset the token starts at in the original text
+    pub start: usize,
+    /// The byte offset the token ends at in the original text, exclusive
+    pub end: usize,
+}
+
+impl Token {
+    /// Gets the byte range of the token in the original text
+    pub fn offsets(&self) -> Range<usize> {
+        self.start..self.end
+    }
+}
+
+/// Text along with the tokens produced by analyzing it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AnalyzedText {
+    text: String,
+    tokens: Vec<Token>,
+}
+
+impl AnalyzedText {
+    /// Analyzes some text
+    pub fn new(text: impl Into<String>) -> Self {
+        let text = text.into();
+        let tokens = tokens(&text).collect();
+        Self { text, tokens }
+    }
+
+    /// Gets the original text
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Gets the tokens of the text, in order
+    pub fn tokens(&self) -> &[Token] {
+        &self.tokens
+    }
+
+    /// Finds every occurrence of a phrase, returning the byte range each one covers in the
+    /// original text. The phrase is analyzed the same way as the text.
+    pub fn find_phrase(&self, phrase: &str) -> Vec<Range<usize>> {
+        let phrase = tokens(phrase).map(|token| token.term).collect::<Vec<_>>();
+        if phrase.is_empty() {
+            return vec![];
+        }
+        self.tokens
+            .windows(phrase.len())
+            .filter(|window| {
+                window
+                    .iter()
+                    .zip(&phrase)
+                    .all(|(token, term)| &token.term == term)
+            })
+            .map(|window| window[0].start..window[window.len() - 1].end)
+            .collect()
+    }
+}
+
+/// Splits text into lowercase tokens at every non alphanumeric character
+pub fn tokens(text: &str) -> impl Iterator<Item = Token> + '_ {
+    let mut chars = text.char_indices().peekable();
+    let mut position = 0;
+    std::iter::from_fn(move || {
+        while chars.next_if(|(_, c)| !c.is_alphanumeric()).is_some() {}
+        let (start, _) = chars.next()?;
+        let mut end = text.len();
+        while let Some(&(index, c)) = chars.peek() {
+            if !c.is_alphanumeric() {
+                end = index;
+                break;
+            }
+            chars.next();
+        }
+        let token = Token {
+            term: text[start..end].to_lowercase(),
+            position,
+            start,
+            end,
+        };
+        position += 1;
+        Some(token)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_have_positions_and_offsets() {
+        let analyzed = AnalyzedText::new("Hello, Wörld!  again");
+        let tokens = analyzed.tokens();
+        assert_eq!(tokens.len(), 3);
+        assert_eq!(tokens[1].term, "wörld");
+        assert_eq!(tokens[1].position, 1);
+        assert_eq!(&analyzed.text()[tokens[1].offsets()], "Wörld");
+        assert_eq!(&analyzed.text()[tokens[2].offsets()], "again");
+    }
+
+    #[test]
+    fn find_phrases() {
+        let analyzed = AnalyzedText::new("The quick fox, the QUICK fox and a quick dog");
+        let found = analyzed.find_phrase("quick fox");
+        assert_eq!(found, [4..13, 19..28]);
+        assert!(analyzed.find_phrase("fox quick").is_empty());
+    }
+}
@@ -8,4 +8,5 @@
 
 pub mod authentication;
 pub mod authorization;
+pub mod passwords;
 pub mod users;
@@ -1,4 +1,5 @@
 use crate::auth::authentication::admin_service::AdminAuthenticationService;
+use crate::auth::passwords::{PasswordHashing, PasswordPolicy, PasswordPolicyError};
 use crate::auth::users::User;
 use argon2::password_hash::{PasswordHashString, Salt, SaltString};
 use argon2::{PasswordHash, PasswordHasher, PasswordVerifier};
@@ -6,17 +7,51 @@ use base64::Engine;
 use rand::rngs::OsRng;
 use secrecy::{ExposeSecret, SecretVec};
 use std::fmt::{Debug, Formatter};
+use std::fs::File;
 use std::io;
 use std::io::{ErrorKind, Read, Write};
+use std::net::IpAddr;
 use std::path::Path;
+use std::time::Duration;
 use thiserror::Error;
 
 mod admin_service;
+mod cache;
+mod jwt_service;
+mod password_service;
+mod session_service;
+mod throttle;
+mod token_service;
+
+pub use admin_service::DEFAULT_USER;
+pub use cache::{AuthenticationCache, DEFAULT_CACHE_TTL};
+pub use jwt_service::{
+    JwtAuthenticationService, DEFAULT_GROUPS_CLAIM, DEFAULT_ROLES_CLAIM, DEFAULT_USER_CLAIM,
+};
+pub use password_service::PasswordAuthenticationService;
+pub use session_service::{
+    SessionService, SessionToken, DEFAULT_SESSION_IDLE_TIMEOUT, DEFAULT_SESSION_TTL,
+};
+pub use throttle::{
+    LoginKey, LoginThrottle, Throttled, DEFAULT_BACKOFF, DEFAULT_LOCKOUT, DEFAULT_MAX_FAILURES,
+};
+pub use token_service::TokenAuthenticationService;
 
 /// Writes a password by first hashing the password, then converting it into base64 encoding.
-pub fn write_password<W: Write>(mut writer: W, password: impl AsRef<[u8]>) -> io::Result<()> {
+pub fn write_password<W: Write>(writer: W, password: impl AsRef<[u8]>) -> io::Result<()> {
+    write_password_with(writer, password, &PasswordHashing::default())
+}
+
+/// Writes a password hashed with the given parameters, in base64 encoding
+pub fn write_password_with<W: Write>(
+    mut writer: W,
+    password: impl AsRef<[u8]>,
+    hashing: &PasswordHashing,
+) -> io::Result<()> {
     let salt = SaltString::generate(&mut OsRng);
-    let hash = argon2::Argon2::default()
+    let hash = hashing
+        .hasher()
+        .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e.to_string()))?
         .hash_password(password.as_ref(), &salt)
         .map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))?;
     write!(
@@ -42,9 +77,28 @@ pub fn read_password_hash<R: Read>(mut reader: R) -> io::Result<PasswordHashStri
     Ok(password_hash)
 }
 
+/// Replaces a file with what is written by `write` at once, so a crash leaves either the old or
+/// the new contents
+pub(crate) fn replace_file(
+    path: &Path,
+    write: impl FnOnce(&mut File) -> io::Result<()>,
+) -> io::Result<()> {
+    let temp = path.with_extension("new");
+    {
+        let mut file = File::create(&temp)?;
+        write(&mut file)?;
+        file.flush()?;
+        file.sync_all()?;
+    }
+    std::fs::rename(&temp, path)
+}
+
 /// An authentication toolchain consists of multiple of authentication services
 pub struct AuthenticationToolchain {
     services: Vec<Box<dyn AuthenticationService>>,
+    policy: PasswordPolicy,
+    throttle: Option<LoginThrottle>,
+    cache: Option<AuthenticationCache>,
 }
 
 impl Debug for AuthenticationToolchain {
@@ -58,11 +112,64 @@ impl Debug for AuthenticationToolchain {
 impl AuthenticationToolchain {
     /// Creates a new authentication toolchain
     pub fn new(store_path: &Path) -> Self {
-        let mut toolchain = Self { services: vec![] };
+        let mut toolchain = Self {
+            services: vec![],
+            policy: PasswordPolicy::default(),
+            throttle: None,
+            cache: None,
+        };
         toolchain.push(AdminAuthenticationService::open(store_path).unwrap());
         toolchain
     }
 
+    /// Opens a toolchain authenticating the admin user, whose password is stored at the given path.
+    /// The admin is created with the default password if the path does not exist yet.
+    pub fn open(store_path: &Path) -> io::Result<Self> {
+        Self::open_with(store_path, PasswordHashing::default())
+    }
+
+    /// Opens a toolchain authenticating the admin user, whose password is hashed with the given
+    /// parameters whenever it changes
+    pub fn open_with(store_path: &Path, hashing: PasswordHashing) -> io::Result<Self> {
+        let admin = if store_path.exists() {
+            AdminAuthenticationService::open(store_path)?
+        } else {
+            AdminAuthenticationService::create(store_path, None)?
+        };
+        let mut toolchain = Self {
+            services: vec![],
+            policy: PasswordPolicy::default(),
+            throttle: None,
+            cache: None,
+        };
+        toolchain.push(admin.with_hashing(hashing));
+        Ok(toolchain)
+    }
+
+    /// Sets the rules new passwords must follow
+    pub fn with_policy(mut self, policy: PasswordPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Gets the rules new passwords must follow
+    pub fn policy(&self) -> &PasswordPolicy {
+        &self.policy
+    }
+
+    /// Slows down guessing credentials, by making attempts wait after failed ones
+    pub fn with_throttle(mut self, throttle: LoginThrottle) -> Self {
+        self.throttle = Some(throttle);
+        self
+    }
+
+    /// Remembers users who authenticated with a password for a while, so that they are not
+    /// verified against the slow hash of their password every time
+    pub fn with_cache(mut self, cache: AuthenticationCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
     /// Pushes a new authentication service to the end of the toolchain
     pub fn push(&mut self, auth: impl AuthenticationService + 'static) {
         self.services.push(Box::new(auth))
@@ -73,9 +180,91 @@ impl AuthenticationToolchain {
         &self,
         req: AuthenticationRequest,
     ) -> Result<User, Vec<AuthenticationError>> {
+        self.authenticate_from(req, None)
+    }
+
+    /// Tries to authenticate a client at the given address. If the toolchain is throttled, failed
+    /// attempts are counted against every user named by the request and against the address.
+    pub fn authenticate_from(
+        &self,
+        req: AuthenticationRequest,
+        ip: Option<IpAddr>,
+    ) -> Result<User, Vec<AuthenticationError>> {
+        let Some(throttle) = &self.throttle else {
+            return self.verify(&req);
+        };
+        let keys = req
+            .payloads()
+            .filter_map(|payload| match payload {
+                AuthenticationRequestPayload::Basic { username, .. } => {
+                    Some(LoginKey::User(username.to_string()))
+                }
+                _ => None,
+            })
+            .chain(ip.map(LoginKey::Ip))
+            .collect::<Vec<_>>();
+        if let Err(throttled) = throttle.check(&keys) {
+            return Err(vec![AuthenticationError::Throttled {
+                retry_after: throttled.retry_after,
+                locked_out: throttled.locked_out,
+            }]);
+        }
+        match self.verify(&req) {
+            Ok(user) => {
+                // the address keeps its failures, or any account would do to clear them
+                throttle.succeed(&[LoginKey::User(user.name().to_string())]);
+                Ok(user)
+            }
+            Err(mut errs) => {
+                let locked_out = throttle.fail(&keys);
+                if !locked_out.is_empty() {
+                    errs.push(AuthenticationError::LockedOut(locked_out));
+                }
+                Err(errs)
+            }
+        }
+    }
+
+    /// Tries the users who recently authenticated with the same password, then every service in
+    /// order
+    fn verify(&self, req: &AuthenticationRequest) -> Result<User, Vec<AuthenticationError>> {
+        let Some(cache) = &self.cache else {
+            return self.try_services(req);
+        };
+        let basic = req
+            .payloads()
+            .filter_map(|payload| match payload {
+                AuthenticationRequestPayload::Basic { username, password } => {
+                    Some((*username, *password))
+                }
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        if let Some(user) = basic
+            .iter()
+            .find_map(|(username, password)| cache.get(username, password))
+        {
+            return Ok(user);
+        }
+        let user = self.try_services(req)?;
+        if let Some((username, password)) = basic.iter().find(|(name, _)| *name == user.name()) {
+            cache.insert(username, password, &user);
+        }
+        Ok(user)
+    }
+
+    /// Forgets a user remembered by the cache, once their password changed or they were removed
+    fn forget(&self, username: &str) {
+        if let Some(cache) = &self.cache {
+            cache.invalidate(username);
+        }
+    }
+
+    /// Tries every service in order
+    fn try_services(&self, req: &AuthenticationRequest) -> Result<User, Vec<AuthenticationError>> {
         let mut errs = Vec::new();
         'services_loop: for service in &self.services {
-            let result = service.authenticate(&req);
+            let result = service.authenticate(req);
             match result {
                 Ok(user) => return Ok(user),
                 Err(e) => {
@@ -90,12 +279,115 @@ impl AuthenticationToolchain {
         }
         Err(errs)
     }
+
+    /// Changes the password of the user authenticated by the request, which must hold their
+    /// current password. Returns the user, who no longer needs to change their password.
+    pub fn change_password(
+        &self,
+        req: AuthenticationRequest,
+        password: &str,
+    ) -> Result<User, AuthenticationError> {
+        let reused = req.payloads().any(|payload| {
+            matches!(payload, AuthenticationRequestPayload::Basic { password: current, .. } if *current == password)
+        });
+        let user = self.authenticate(req).map_err(|errs| {
+            errs.into_iter()
+                .last()
+                .unwrap_or(AuthenticationError::UnknownIdentifier)
+        })?;
+        if reused {
+            return Err(AuthenticationError::PasswordReused);
+        }
+        self.policy.check(password)?;
+        for service in &self.services {
+            if service.set_password(user.name(), password)? {
+                self.forget(user.name());
+                return Ok(user.with_password_expired(false));
+            }
+        }
+        Err(AuthenticationError::PasswordNotStored)
+    }
+
+    /// Lists every user whose password is stored
+    pub fn users(&self) -> Vec<String> {
+        let mut users = self
+            .services
+            .iter()
+            .flat_map(|service| service.users())
+            .collect::<Vec<_>>();
+        users.sort();
+        users.dedup();
+        users
+    }
+
+    /// Adds a user authenticated by a password, which must follow the policy
+    pub fn add_user(&self, username: &str, password: &str) -> Result<(), AuthenticationError> {
+        if self.users().iter().any(|user| user == username) {
+            return Err(AuthenticationError::UserExists);
+        }
+        self.policy.check(password)?;
+        for service in &self.services {
+            if service.add_user(username, password)? {
+                return Ok(());
+            }
+        }
+        Err(AuthenticationError::PasswordNotStored)
+    }
+
+    /// Replaces the password of a user without knowing their current one, as an admin does for
+    /// users who forgot theirs. The password must follow the policy.
+    pub fn reset_password(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> Result<(), AuthenticationError> {
+        self.policy.check(password)?;
+        for service in &self.services {
+            if service.set_password(username, password)? {
+                self.forget(username);
+                return Ok(());
+            }
+        }
+        Err(AuthenticationError::UnknownIdentifier)
+    }
+
+    /// Removes a user authenticated by a password
+    pub fn remove_user(&self, username: &str) -> Result<(), AuthenticationError> {
+        for service in &self.services {
+            if service.remove_user(username)? {
+                self.forget(username);
+                return Ok(());
+            }
+        }
+        Err(AuthenticationError::UnknownIdentifier)
+    }
 }
 
 /// Used for authenticating someone into the system
-pub trait AuthenticationService {
+pub trait AuthenticationService: Send + Sync {
     /// Authenticate a request
     fn authenticate(&self, req: &AuthenticationRequest) -> Result<User, AuthenticationError>;
+
+    /// Replaces the password of a user, returning `false` if this service does not store it
+    fn set_password(&self, _username: &str, _password: &str) -> Result<bool, AuthenticationError> {
+        Ok(false)
+    }
+
+    /// Lists the users whose passwords this service stores
+    fn users(&self) -> Vec<String> {
+        vec![]
+    }
+
+    /// Adds a user authenticated by a password, returning `false` if this service does not store
+    /// the passwords of new users
+    fn add_user(&self, _username: &str, _password: &str) -> Result<bool, AuthenticationError> {
+        Ok(false)
+    }
+
+    /// Removes a user, returning `false` if this service does not store them
+    fn remove_user(&self, _username: &str) -> Result<bool, AuthenticationError> {
+        Ok(false)
+    }
 }
 
 /// An authentication request.
@@ -117,6 +409,12 @@ impl<'a> AuthenticationRequest<'a> {
             .push(AuthenticationRequestPayload::Basic { username, password });
         self
     }
+
+    pub fn with_token(mut self, token: &'a str) -> Self {
+        self.payloads
+            .push(AuthenticationRequestPayload::Token { token });
+        self
+    }
 }
 
 #[derive(Debug)]
@@ -126,6 +424,8 @@ pub enum AuthenticationRequestPayload<'a> {
         username: &'a str,
         password: &'a str,
     },
+    /// A token given to a user ahead of time, or when they started a session
+    Token { token: &'a str },
 }
 
 #[derive(Debug, Error)]
@@ -136,6 +436,36 @@ pub enum AuthenticationError {
     WrongPassword,
     #[error("Unknown username.")]
     UnknownIdentifier,
+    #[error("Invalid token.")]
+    InvalidToken,
+    #[error(
+        "Too many failed attempts to authenticate, try again in {}s.",
+        retry_after.as_secs().max(1)
+    )]
+    Throttled {
+        retry_after: Duration,
+        locked_out: bool,
+    },
+    #[error("Too many failed attempts to authenticate, locked out {}.", join(.0))]
+    LockedOut(Vec<LoginKey>),
+    #[error("The new password must differ from the current one.")]
+    PasswordReused,
+    #[error(transparent)]
+    WeakPassword(#[from] PasswordPolicyError),
+    #[error("The user already exists.")]
+    UserExists,
+    #[error("The password of the user is not stored by docatlas.")]
+    PasswordNotStored,
+    #[error("Could not store the password: {0}")]
+    StoreError(#[from] io::Error),
+}
+
+/// Joins the keys that were locked out
+fn join(keys: &[LoginKey]) -> String {
+    keys.iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(" and ")
 }
 
 impl AuthenticationError {
@@ -150,6 +480,9 @@ impl AuthenticationError {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::auth::users::UserFactory;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
 
     #[test]
     fn create_basic() {
@@ -163,4 +496,142 @@ mod tests {
         assert_eq!(username, "username");
         assert_eq!(password, "password");
     }
+
+    #[test]
+    fn change_password_by_policy() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let toolchain = AuthenticationToolchain::open(&tempdir.path().join("admin"))
+            .unwrap()
+            .with_policy(PasswordPolicy::new().with_digit(true));
+        let current = || AuthenticationRequest::new().with_basic(DEFAULT_USER, "admin");
+
+        let error = toolchain.change_password(current(), "admin").unwrap_err();
+        assert!(matches!(error, AuthenticationError::PasswordReused));
+        let error = toolchain
+            .change_password(current(), "password")
+            .unwrap_err();
+        assert!(matches!(error, AuthenticationError::WeakPassword(_)));
+        let wrong = AuthenticationRequest::new().with_basic(DEFAULT_USER, "guess");
+        let error = toolchain.change_password(wrong, "passw0rd").unwrap_err();
+        assert!(matches!(error, AuthenticationError::WrongPassword));
+
+        let admin = toolchain.change_password(current(), "passw0rd").unwrap();
+        assert!(!admin.must_change_password());
+        assert!(toolchain.authenticate(current()).is_err());
+        let request = AuthenticationRequest::new().with_basic(DEFAULT_USER, "passw0rd");
+        assert!(toolchain.authenticate(request).is_ok());
+    }
+
+    #[test]
+    fn throttled_attempts() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let throttle = LoginThrottle::new(2, Duration::ZERO, Duration::from_secs(60));
+        let toolchain = AuthenticationToolchain::open(&tempdir.path().join("admin"))
+            .unwrap()
+            .with_throttle(throttle);
+        let ip = IpAddr::from([10, 0, 0, 1]);
+        let wrong = || AuthenticationRequest::new().with_basic(DEFAULT_USER, "guess");
+        let right = || AuthenticationRequest::new().with_basic(DEFAULT_USER, "admin");
+
+        let errs = toolchain.authenticate_from(wrong(), Some(ip)).unwrap_err();
+        assert!(matches!(errs[..], [AuthenticationError::WrongPassword]));
+        let errs = toolchain.authenticate_from(wrong(), Some(ip)).unwrap_err();
+        let Some(AuthenticationError::LockedOut(keys)) = errs.last() else {
+            panic!("not locked out: {errs:?}")
+        };
+        assert_eq!(
+            keys,
+            &[LoginKey::User(DEFAULT_USER.to_string()), LoginKey::Ip(ip)]
+        );
+
+        // even the right password is turned away, from any address
+        let errs = toolchain.authenticate_from(right(), None).unwrap_err();
+        assert!(matches!(
+            errs[..],
+            [AuthenticationError::Throttled {
+                locked_out: true,
+                ..
+            }]
+        ));
+    }
+
+    #[test]
+    fn cached_authentication() {
+        /// Authenticates carol, counting how often her password is verified
+        struct Counted(Arc<AtomicUsize>);
+
+        impl AuthenticationService for Counted {
+            fn authenticate(
+                &self,
+                req: &AuthenticationRequest,
+            ) -> Result<User, AuthenticationError> {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                match req.payloads().next() {
+                    Some(AuthenticationRequestPayload::Basic {
+                        username: "carol",
+                        password: "christmas",
+                    }) => Ok(UserFactory.create("carol")),
+                    _ => Err(AuthenticationError::UnknownIdentifier),
+                }
+            }
+        }
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut toolchain = AuthenticationToolchain::open(&tempdir.path().join("admin"))
+            .unwrap()
+            .with_cache(AuthenticationCache::default());
+        let verified = Arc::new(AtomicUsize::new(0));
+        toolchain.push(Counted(verified.clone()));
+        let carol = || AuthenticationRequest::new().with_basic("carol", "christmas");
+        assert_eq!(toolchain.authenticate(carol()).unwrap().name(), "carol");
+        assert_eq!(toolchain.authenticate(carol()).unwrap().name(), "carol");
+        assert_eq!(verified.load(Ordering::SeqCst), 1);
+        let wrong = AuthenticationRequest::new().with_basic("carol", "easter");
+        assert!(toolchain.authenticate(wrong).is_err());
+
+        // changing a password forgets the old one at once
+        let current = || AuthenticationRequest::new().with_basic(DEFAULT_USER, "admin");
+        assert!(toolchain.authenticate(current()).is_ok());
+        toolchain.change_password(current(), "passw0rd").unwrap();
+        assert!(toolchain.authenticate(current()).is_err());
+        toolchain
+            .reset_password(DEFAULT_USER, "admin-again")
+            .unwrap();
+        let changed = AuthenticationRequest::new().with_basic(DEFAULT_USER, "passw0rd");
+        assert!(toolchain.authenticate(changed).is_err());
+    }
+
+    #[test]
+    fn manage_users() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let mut toolchain = AuthenticationToolchain::open(&tempdir.path().join("admin")).unwrap();
+        let store = tempdir.path().join("users.json");
+        toolchain.push(PasswordAuthenticationService::open(&store).unwrap());
+
+        toolchain.add_user("alice", "wonderland").unwrap();
+        let error = toolchain.add_user("alice", "looking-glass").unwrap_err();
+        assert!(matches!(error, AuthenticationError::UserExists));
+        let error = toolchain
+            .add_user(DEFAULT_USER, "looking-glass")
+            .unwrap_err();
+        assert!(matches!(error, AuthenticationError::UserExists));
+        let error = toolchain.add_user("bob", "short").unwrap_err();
+        assert!(matches!(error, AuthenticationError::WeakPassword(_)));
+        assert_eq!(toolchain.users(), [DEFAULT_USER, "alice"]);
+
+        toolchain.reset_password("alice", "looking-glass").unwrap();
+        let old = AuthenticationRequest::new().with_basic("alice", "wonderland");
+        assert!(toolchain.authenticate(old).is_err());
+        let new = || AuthenticationRequest::new().with_basic("alice", "looking-glass");
+        assert_eq!(toolchain.authenticate(new()).unwrap().name(), "alice");
+        let error = toolchain
+            .reset_password("bob", "looking-glass")
+            .unwrap_err();
+        assert!(matches!(error, AuthenticationError::UnknownIdentifier));
+
+        toolchain.remove_user("alice").unwrap();
+        assert!(toolchain.authenticate(new()).is_err());
+        let error = toolchain.remove_user(DEFAULT_USER).unwrap_err();
+        assert!(matches!(error, AuthenticationError::UnknownIdentifier));
+    }
 }
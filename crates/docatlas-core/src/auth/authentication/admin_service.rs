@@ -2,40 +2,47 @@ use std::fs::File;
 use std::io;
 use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
+use std::sync::RwLock;
 
 use argon2::password_hash::PasswordHashString;
 use argon2::{Argon2, PasswordVerifier};
 use base64::Engine;
 
 use crate::auth::authentication::{
-    write_password, AuthenticationError, AuthenticationRequest, AuthenticationRequestPayload,
-    AuthenticationService,
+    replace_file, write_password, write_password_with, AuthenticationError, AuthenticationRequest,
+    AuthenticationRequestPayload, AuthenticationService,
 };
+use crate::auth::passwords::PasswordHashing;
 use crate::auth::users::{User, UserFactory};
 
 pub const DEFAULT_USER: &str = "admin";
 pub const DEFAULT_PASSWORD: &[u8] = b"admin";
 
+/// Authenticates the admin user, whose password is stored in a file. The admin must change the
+/// default password the first time they authenticate with it.
 #[derive(Debug)]
 pub struct AdminAuthenticationService {
-    hashed_password: PasswordHashString,
+    hashed_password: RwLock<PasswordHashString>,
     file_path: PathBuf,
+    hashing: PasswordHashing,
 }
 
 impl AdminAuthenticationService {
     /// Open an admin auth service at a given path
     pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
-        let hashed = std::fs::read(path.as_ref())?;
-        let decoded = base64::engine::general_purpose::STANDARD
-            .decode(hashed)
-            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))?;
         Ok(Self {
-            hashed_password: PasswordHashString::new(&String::from_utf8_lossy(&decoded))
-                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))?,
+            hashed_password: RwLock::new(read_hash(path.as_ref())?),
             file_path: path.as_ref().to_path_buf(),
+            hashing: PasswordHashing::default(),
         })
     }
 
+    /// Sets how new passwords are hashed
+    pub fn with_hashing(mut self, hashing: PasswordHashing) -> Self {
+        self.hashing = hashing;
+        self
+    }
+
     /// create an admin auth service at a given path with a password, or the default `admin` password
     /// if none is supplied. If the file already exists, an error is returned.
     pub fn create<P: AsRef<Path>, A: Into<Option<Vec<u8>>>>(
@@ -64,10 +71,14 @@ impl AuthenticationService for AdminAuthenticationService {
             _ => None,
         }) {
             admin_check = true;
-            if let Ok(()) = Argon2::default()
-                .verify_password(pass.as_bytes(), &self.hashed_password.password_hash())
+            let hashed_password = self.hashed_password.read().expect("poisoned");
+            if let Ok(()) =
+                Argon2::default().verify_password(pass.as_bytes(), &hashed_password.password_hash())
             {
-                return Ok(UserFactory.create("admin"));
+                let expired = pass.as_bytes() == DEFAULT_PASSWORD;
+                return Ok(UserFactory
+                    .create(DEFAULT_USER)
+                    .with_password_expired(expired));
             }
         }
 
@@ -77,6 +88,32 @@ impl AuthenticationService for AdminAuthenticationService {
             Err(AuthenticationError::UnknownIdentifier)
         }
     }
+
+    fn set_password(&self, username: &str, password: &str) -> Result<bool, AuthenticationError> {
+        if username != DEFAULT_USER {
+            return Ok(false);
+        }
+        let mut hashed_password = self.hashed_password.write().expect("poisoned");
+        replace_file(&self.file_path, |file| {
+            write_password_with(file, password, &self.hashing)
+        })?;
+        *hashed_password = read_hash(&self.file_path)?;
+        Ok(true)
+    }
+
+    fn users(&self) -> Vec<String> {
+        vec![DEFAULT_USER.to_string()]
+    }
+}
+
+/// Reads the hash of a password stored at the given path
+fn read_hash(path: &Path) -> io::Result<PasswordHashString> {
+    let hashed = std::fs::read(path)?;
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(hashed)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))?;
+    PasswordHashString::new(&String::from_utf8_lossy(&decoded))
+        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))
 }
 
 #[cfg(test)]
@@ -89,9 +126,34 @@ mod tests {
         let tempdir = tempdir().unwrap();
         let svc = AdminAuthenticationService::create(tempdir.path().join("admin"), None).unwrap();
 
-        let ref request = AuthenticationRequest::new().with_basic("admin", "admin");
-        let admin = svc.authenticate(request).unwrap();
+        let request = AuthenticationRequest::new().with_basic("admin", "admin");
+        let admin = svc.authenticate(&request).unwrap();
         assert_eq!(admin.name(), "admin");
+        assert!(admin.must_change_password());
+    }
+
+    #[test]
+    fn change_admin_password() {
+        let tempdir = tempdir().unwrap();
+        let path = tempdir.path().join("admin");
+        let hashing = PasswordHashing::new().with_memory_kib(8 * 1024);
+        let svc = AdminAuthenticationService::create(&path, None)
+            .unwrap()
+            .with_hashing(hashing);
+        assert!(!svc.set_password("reader", "n3w-password").unwrap());
+        assert!(svc.set_password("admin", "n3w-password").unwrap());
+
+        let request = AuthenticationRequest::new().with_basic("admin", "admin");
+        let error = svc.authenticate(&request).unwrap_err();
+        assert!(matches!(error, AuthenticationError::WrongPassword));
+        let request = AuthenticationRequest::new().with_basic("admin", "n3w-password");
+        assert!(!svc.authenticate(&request).unwrap().must_change_password());
+
+        // the new password is stored, hashed with the given parameters
+        let svc = AdminAuthenticationService::open(&path).unwrap();
+        assert!(svc.authenticate(&request).is_ok());
+        let hash = svc.hashed_password.read().unwrap().to_string();
+        assert!(hash.contains("m=8192"), "{hash}");
     }
 
     #[test]
@@ -99,8 +161,8 @@ mod tests {
         let tempdir = tempdir().unwrap();
         let svc = AdminAuthenticationService::create(tempdir.path().join("admin#1"), None).unwrap();
 
-        let ref request = AuthenticationRequest::new().with_basic("blahblagbalg", "admin");
-        let error = svc.authenticate(request).unwrap_err();
+        let request = AuthenticationRequest::new().with_basic("blahblagbalg", "admin");
+        let error = svc.authenticate(&request).unwrap_err();
         assert!(matches!(error, AuthenticationError::UnknownIdentifier));
     }
 }
@@ -0,0 +1,132 @@
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use blake2::digest::Mac;
+use blake2::Blake2bMac512;
+
+use crate::auth::users::User;
+
+/// How long a user is remembered after authenticating with a password unless configured
+/// otherwise
+pub const DEFAULT_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// Remembers the users who recently authenticated with a password, so that authenticating with
+/// the same password again does not verify it against its slow hash again. Passwords are never
+/// kept, only a digest keyed by a secret the cache draws when it is created.
+pub struct AuthenticationCache {
+    ttl: Duration,
+    key: [u8; 64],
+    entries: Mutex<HashMap<String, Cached>>,
+}
+
+/// A user who authenticated with a password
+struct Cached {
+    digest: Vec<u8>,
+    user: User,
+    expires: Instant,
+}
+
+impl Debug for AuthenticationCache {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuthenticationCache")
+            .field("ttl", &self.ttl)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Default for AuthenticationCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_CACHE_TTL)
+    }
+}
+
+impl AuthenticationCache {
+    /// Creates a cache remembering users for `ttl` after they authenticated
+    pub fn new(ttl: Duration) -> Self {
+        let mut key = [0; 64];
+        rand::Rng::fill(&mut rand::rngs::OsRng, &mut key[..]);
+        Self {
+            ttl,
+            key,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Digests the password of a user with the key of the cache
+    fn digest(&self, username: &str, password: &str) -> Vec<u8> {
+        let mut mac = Blake2bMac512::new_from_slice(&self.key).expect("the key is never too long");
+        mac.update(&(username.len() as u64).to_le_bytes());
+        mac.update(username.as_bytes());
+        mac.update(password.as_bytes());
+        mac.finalize().into_bytes().to_vec()
+    }
+
+    /// Gets the user who recently authenticated with the same password, if any
+    pub fn get(&self, username: &str, password: &str) -> Option<User> {
+        self.get_at(username, password, Instant::now())
+    }
+
+    /// Gets the user who authenticated with the same password, if they are still remembered as
+    /// of the given time
+    pub fn get_at(&self, username: &str, password: &str, now: Instant) -> Option<User> {
+        let digest = self.digest(username, password);
+        let entries = self.entries.lock().expect("poisoned");
+        entries
+            .get(username)
+            .filter(|cached| cached.expires > now && cached.digest == digest)
+            .map(|cached| cached.user.clone())
+    }
+
+    /// Remembers that a user authenticated with a password
+    pub fn insert(&self, username: &str, password: &str, user: &User) {
+        self.insert_at(username, password, user, Instant::now())
+    }
+
+    /// Remembers that a user authenticated with a password at the given time
+    pub fn insert_at(&self, username: &str, password: &str, user: &User, now: Instant) {
+        let digest = self.digest(username, password);
+        let mut entries = self.entries.lock().expect("poisoned");
+        entries.retain(|_, cached| cached.expires > now);
+        entries.insert(
+            username.to_string(),
+            Cached {
+                digest,
+                user: user.clone(),
+                expires: now + self.ttl,
+            },
+        );
+    }
+
+    /// Forgets a user, once their password changed or they were removed
+    pub fn invalidate(&self, username: &str) {
+        self.entries.lock().expect("poisoned").remove(username);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::users::UserFactory;
+
+    #[test]
+    fn remembers_passwords_until_expired() {
+        let cache = AuthenticationCache::new(Duration::from_secs(60));
+        let alice = UserFactory.create("alice");
+        let start = Instant::now();
+        assert!(cache.get_at("alice", "wonderland", start).is_none());
+
+        cache.insert_at("alice", "wonderland", &alice, start);
+        let cached = cache.get_at("alice", "wonderland", start + Duration::from_secs(59));
+        assert_eq!(cached.unwrap().name(), "alice");
+        assert!(cache.get_at("alice", "looking-glass", start).is_none());
+        assert!(cache.get_at("bob", "wonderland", start).is_none());
+        let expired = start + Duration::from_secs(60);
+        assert!(cache.get_at("alice", "wonderland", expired).is_none());
+
+        cache.insert_at("alice", "wonderland", &alice, start);
+        cache.invalidate("alice");
+        assert!(cache.get_at("alice", "wonderland", start).is_none());
+    }
+}
@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::io;
+use std::io::{ErrorKind, Read};
+
+use jsonwebtoken::jwk::JwkSet;
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde_json::Value;
+use tracing::debug;
+
+use crate::auth::authentication::{
+    AuthenticationError, AuthenticationRequest, AuthenticationRequestPayload, AuthenticationService,
+};
+use crate::auth::users::{User, UserFactory};
+
+/// The claim naming the user unless configured otherwise
+pub const DEFAULT_USER_CLAIM: &str = "sub";
+/// The claim listing the groups of the user unless configured otherwise
+pub const DEFAULT_GROUPS_CLAIM: &str = "groups";
+/// The claim listing the roles of the user unless configured otherwise
+pub const DEFAULT_ROLES_CLAIM: &str = "roles";
+
+/// The algorithms tokens signed with a shared secret may use
+const SECRET_ALGORITHMS: [Algorithm; 3] = [Algorithm::HS256, Algorithm::HS384, Algorithm::HS512];
+
+/// Authenticates users by JSON web tokens, such as the ones issued by a single sign-on provider.
+///
+/// Tokens must be signed with a shared secret, or with one of the keys of a JWK set, and must not
+/// have expired. The user is named by a claim of the token, and their groups and roles are listed
+/// by two more claims, each holding either a single string or an array of them.
+pub struct JwtAuthenticationService {
+    keys: JwtKeys,
+    issuer: Option<String>,
+    audience: Option<String>,
+    user_claim: String,
+    groups_claim: String,
+    roles_claim: String,
+}
+
+/// The keys tokens are signed with
+enum JwtKeys {
+    Secret(DecodingKey),
+    Jwks(JwkSet),
+}
+
+impl Debug for JwtAuthenticationService {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let keys = match &self.keys {
+            JwtKeys::Secret(_) => "secret".to_string(),
+            JwtKeys::Jwks(jwks) => format!("{} keys", jwks.keys.len()),
+        };
+        f.debug_struct("JwtAuthenticationService")
+            .field("keys", &keys)
+            .field("issuer", &self.issuer)
+            .field("audience", &self.audience)
+            .finish()
+    }
+}
+
+impl JwtAuthenticationService {
+    fn new(keys: JwtKeys) -> Self {
+        Self {
+            keys,
+            issuer: None,
+            audience: None,
+            user_claim: DEFAULT_USER_CLAIM.to_string(),
+            groups_claim: DEFAULT_GROUPS_CLAIM.to_string(),
+            roles_claim: DEFAULT_ROLES_CLAIM.to_string(),
+        }
+    }
+
+    /// Creates a service accepting tokens signed with a shared secret
+    pub fn with_secret(secret: &[u8]) -> Self {
+        Self::new(JwtKeys::Secret(DecodingKey::from_secret(secret)))
+    }
+
+    /// Creates a service accepting tokens signed with any key of a JWK set
+    pub fn with_jwks(jwks: JwkSet) -> Self {
+        Self::new(JwtKeys::Jwks(jwks))
+    }
+
+    /// Reads a JWK set, as published by the issuer of tokens
+    pub fn read_jwks<R: Read>(reader: R) -> io::Result<Self> {
+        let jwks = serde_json::from_reader(reader)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+        Ok(Self::with_jwks(jwks))
+    }
+
+    /// Only accepts tokens issued by the given issuer
+    pub fn with_issuer(mut self, issuer: impl Into<String>) -> Self {
+        self.issuer = Some(issuer.into());
+        self
+    }
+
+    /// Only accepts tokens issued for the given audience
+    pub fn with_audience(mut self, audience: impl Into<String>) -> Self {
+        self.audience = Some(audience.into());
+        self
+    }
+
+    /// Sets the claim naming the user
+    pub fn with_user_claim(mut self, claim: impl Into<String>) -> Self {
+        self.user_claim = claim.into();
+        self
+    }
+
+    /// Sets the claim listing the groups of the user
+    pub fn with_groups_claim(mut self, claim: impl Into<String>) -> Self {
+        self.groups_claim = claim.into();
+        self
+    }
+
+    /// Sets the claim listing the roles of the user
+    pub fn with_roles_claim(mut self, claim: impl Into<String>) -> Self {
+        self.roles_claim = claim.into();
+        self
+    }
+
+    /// Verifies a token, returning its claims
+    fn verify(&self, token: &str) -> jsonwebtoken::errors::Result<HashMap<String, Value>> {
+        let header = jsonwebtoken::decode_header(token)?;
+        let (key, algorithms) = match &self.keys {
+            JwtKeys::Secret(key) => (key.clone(), SECRET_ALGORITHMS.to_vec()),
+            JwtKeys::Jwks(jwks) => {
+                let jwk = match &header.kid {
+                    Some(kid) => jwks.find(kid),
+                    None if jwks.keys.len() == 1 => jwks.keys.first(),
+                    None => None,
+                }
+                .ok_or(jsonwebtoken::errors::ErrorKind::InvalidSignature)?;
+                // keys naming their algorithm may only be used with it
+                let algorithm = match jwk.common.key_algorithm {
+                    Some(algorithm) => algorithm.to_string().parse()?,
+                    None => header.alg,
+                };
+                (DecodingKey::from_jwk(jwk)?, vec![algorithm])
+            }
+        };
+        let mut validation = Validation::new(algorithms[0]);
+        validation.algorithms = algorithms;
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+            validation.set_required_spec_claims(&["exp", "iss"]);
+        }
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        Ok(jsonwebtoken::decode(token, &key, &validation)?.claims)
+    }
+
+    /// Creates the user named by the claims of a token
+    fn user(&self, claims: &HashMap<String, Value>) -> Option<User> {
+        let name = claims.get(&self.user_claim)?.as_str()?;
+        let strings = |claim: &str| match claims.get(claim) {
+            Some(Value::String(value)) => vec![value.clone()],
+            Some(Value::Array(values)) => values
+                .iter()
+                .filter_map(|value| value.as_str().map(str::to_string))
+                .collect(),
+            _ => vec![],
+        };
+        Some(
+            UserFactory
+                .create(name)
+                .with_groups(strings(&self.groups_claim))
+                .with_roles(strings(&self.roles_claim)),
+        )
+    }
+}
+
+impl AuthenticationService for JwtAuthenticationService {
+    fn authenticate(&self, req: &AuthenticationRequest) -> Result<User, AuthenticationError> {
+        let mut token_check = false;
+        for token in req.payloads().filter_map(|req| match req {
+            AuthenticationRequestPayload::Token { token } => Some(*token),
+            _ => None,
+        }) {
+            token_check = true;
+            match self.verify(token) {
+                Ok(claims) => match self.user(&claims) {
+                    Some(user) => return Ok(user),
+                    None => debug!("json web token has no {:?} claim", self.user_claim),
+                },
+                Err(e) => debug!("json web token rejected: {e}"),
+            }
+        }
+
+        if token_check {
+            Err(AuthenticationError::InvalidToken)
+        } else {
+            Err(AuthenticationError::UnsupportedRequestKind)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+
+    fn sign(claims: Value, secret: &[u8]) -> String {
+        jsonwebtoken::encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(secret),
+        )
+        .unwrap()
+    }
+
+    fn expires() -> u64 {
+        jsonwebtoken::get_current_timestamp() + 600
+    }
+
+    #[test]
+    fn maps_claims_to_users() {
+        let svc = JwtAuthenticationService::with_secret(b"s3cr3t")
+            .with_issuer("https://sso.example.com")
+            .with_roles_claim("docatlas_roles");
+        let token = sign(
+            json!({
+                "sub": "alice",
+                "iss": "https://sso.example.com",
+                "exp": expires(),
+                "groups": ["ops", "dev"],
+                "docatlas_roles": "reader",
+            }),
+            b"s3cr3t",
+        );
+        let request = AuthenticationRequest::new().with_token(&token);
+        let user = svc.authenticate(&request).unwrap();
+        assert_eq!(user.name(), "alice");
+        assert_eq!(user.groups(), ["ops", "dev"]);
+        assert_eq!(user.roles(), ["reader"]);
+    }
+
+    #[test]
+    fn rejects_invalid_tokens() {
+        let svc = JwtAuthenticationService::with_secret(b"s3cr3t").with_audience("docatlas");
+        let claims = json!({"sub": "alice", "aud": "docatlas", "exp": expires()});
+        let rejected = [
+            sign(claims.clone(), b"wrong"),
+            sign(
+                json!({"sub": "alice", "aud": "docatlas", "exp": 1}),
+                b"s3cr3t",
+            ),
+            sign(
+                json!({"sub": "alice", "aud": "other", "exp": expires()}),
+                b"s3cr3t",
+            ),
+            sign(json!({"aud": "docatlas", "exp": expires()}), b"s3cr3t"),
+            "not.a.token".to_string(),
+        ];
+        for token in &rejected {
+            let request = AuthenticationRequest::new().with_token(token);
+            let error = svc.authenticate(&request).unwrap_err();
+            assert!(
+                matches!(error, AuthenticationError::InvalidToken),
+                "{token}"
+            );
+        }
+        let token = sign(claims, b"s3cr3t");
+        let request = AuthenticationRequest::new().with_token(&token);
+        assert!(svc.authenticate(&request).is_ok());
+    }
+
+    #[test]
+    fn keys_of_a_jwk_set() {
+        let jwks = json!({"keys": [{
+            "kty": "oct",
+            "kid": "primary",
+            "alg": "HS256",
+            "k": "czNjcjN0",
+        }]});
+        let svc = JwtAuthenticationService::read_jwks(jwks.to_string().as_bytes()).unwrap();
+        let claims = json!({"sub": "bob", "exp": expires()});
+        let header = Header {
+            kid: Some("primary".to_string()),
+            ..Header::default()
+        };
+        let token =
+            jsonwebtoken::encode(&header, &claims, &EncodingKey::from_secret(b"s3cr3t")).unwrap();
+        let request = AuthenticationRequest::new().with_token(&token);
+        assert_eq!(svc.authenticate(&request).unwrap().name(), "bob");
+
+        let header = Header {
+            kid: Some("retired".to_string()),
+            ..Header::default()
+        };
+        let token =
+            jsonwebtoken::encode(&header, &claims, &EncodingKey::from_secret(b"s3cr3t")).unwrap();
+        let request = AuthenticationRequest::new().with_token(&token);
+        assert!(svc.authenticate(&request).is_err());
+    }
+}
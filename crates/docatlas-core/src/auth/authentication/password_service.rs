@@ -0,0 +1,189 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use argon2::password_hash::{PasswordHashString, SaltString};
+use argon2::{Argon2, PasswordHasher, PasswordVerifier};
+use rand::rngs::OsRng;
+
+use crate::auth::authentication::{
+    replace_file, AuthenticationError, AuthenticationRequest, AuthenticationRequestPayload,
+    AuthenticationService, DEFAULT_USER,
+};
+use crate::auth::passwords::PasswordHashing;
+use crate::auth::users::{User, UserFactory, ANONYMOUS_USER};
+
+/// Authenticates users by passwords stored in a json file, mapping the name of every user to the
+/// hash of their password. The file is rewritten at once whenever a user is added, removed, or
+/// has their password changed.
+#[derive(Debug)]
+pub struct PasswordAuthenticationService {
+    users: RwLock<BTreeMap<String, PasswordHashString>>,
+    file_path: PathBuf,
+    hashing: PasswordHashing,
+}
+
+impl PasswordAuthenticationService {
+    /// Opens the users stored at the given path, which has no users if it does not exist yet
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let users = match std::fs::read(path) {
+            Ok(json) => serde_json::from_slice::<BTreeMap<String, String>>(&json)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?
+                .into_iter()
+                .map(|(user, hash)| {
+                    let hash = PasswordHashString::new(&hash)
+                        .map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))?;
+                    Ok((user, hash))
+                })
+                .collect::<io::Result<_>>()?,
+            Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            users: RwLock::new(users),
+            file_path: path.to_path_buf(),
+            hashing: PasswordHashing::default(),
+        })
+    }
+
+    /// Sets how new passwords are hashed
+    pub fn with_hashing(mut self, hashing: PasswordHashing) -> Self {
+        self.hashing = hashing;
+        self
+    }
+
+    /// Hashes a new password
+    fn hash(&self, password: &str) -> io::Result<PasswordHashString> {
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = self
+            .hashing
+            .hasher()
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e.to_string()))?
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))?;
+        Ok(hash.serialize())
+    }
+
+    /// Stores every user, replacing the file at once
+    fn store(&self, users: &BTreeMap<String, PasswordHashString>) -> io::Result<()> {
+        let json = users
+            .iter()
+            .map(|(user, hash)| (user.as_str(), hash.as_str()))
+            .collect::<BTreeMap<_, _>>();
+        let json = serde_json::to_vec_pretty(&json)?;
+        replace_file(&self.file_path, |file| file.write_all(&json))
+    }
+}
+
+impl AuthenticationService for PasswordAuthenticationService {
+    fn authenticate(&self, req: &AuthenticationRequest) -> Result<User, AuthenticationError> {
+        let users = self.users.read().expect("poisoned");
+        let mut user_check = false;
+        for (username, password) in req.payloads().filter_map(|req| match req {
+            AuthenticationRequestPayload::Basic { username, password } => {
+                Some((*username, *password))
+            }
+            _ => None,
+        }) {
+            let Some(hashed_password) = users.get(username) else {
+                continue;
+            };
+            user_check = true;
+            if let Ok(()) = Argon2::default()
+                .verify_password(password.as_bytes(), &hashed_password.password_hash())
+            {
+                return Ok(UserFactory.create(username));
+            }
+        }
+
+        if user_check {
+            Err(AuthenticationError::WrongPassword)
+        } else {
+            Err(AuthenticationError::UnknownIdentifier)
+        }
+    }
+
+    fn set_password(&self, username: &str, password: &str) -> Result<bool, AuthenticationError> {
+        let mut users = self.users.write().expect("poisoned");
+        if !users.contains_key(username) {
+            return Ok(false);
+        }
+        let mut changed = users.clone();
+        changed.insert(username.to_string(), self.hash(password)?);
+        self.store(&changed)?;
+        *users = changed;
+        Ok(true)
+    }
+
+    fn users(&self) -> Vec<String> {
+        self.users
+            .read()
+            .expect("poisoned")
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    fn add_user(&self, username: &str, password: &str) -> Result<bool, AuthenticationError> {
+        let mut users = self.users.write().expect("poisoned");
+        // the admin is authenticated by its own service, and nobody may pose as anonymous clients
+        if username == DEFAULT_USER || username == ANONYMOUS_USER || users.contains_key(username) {
+            return Err(AuthenticationError::UserExists);
+        }
+        let mut changed = users.clone();
+        changed.insert(username.to_string(), self.hash(password)?);
+        self.store(&changed)?;
+        *users = changed;
+        Ok(true)
+    }
+
+    fn remove_user(&self, username: &str) -> Result<bool, AuthenticationError> {
+        let mut users = self.users.write().expect("poisoned");
+        if !users.contains_key(username) {
+            return Ok(false);
+        }
+        let mut changed = users.clone();
+        changed.remove(username);
+        self.store(&changed)?;
+        *users = changed;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn users_are_stored() {
+        let tempdir = tempdir().unwrap();
+        let path = tempdir.path().join("users.json");
+        let svc = PasswordAuthenticationService::open(&path)
+            .unwrap()
+            .with_hashing(PasswordHashing::new().with_memory_kib(8 * 1024));
+        assert!(svc.users().is_empty());
+        assert!(svc.add_user("alice", "wonderland").unwrap());
+        assert!(svc.add_user("bob", "builder").unwrap());
+        let error = svc.add_user(ANONYMOUS_USER, "nobody").unwrap_err();
+        assert!(matches!(error, AuthenticationError::UserExists));
+        assert!(!svc.set_password("carol", "christmas").unwrap());
+        assert!(svc.set_password("bob", "the-builder").unwrap());
+        assert!(svc.remove_user("alice").unwrap());
+        assert!(!svc.remove_user("alice").unwrap());
+
+        let svc = PasswordAuthenticationService::open(&path).unwrap();
+        assert_eq!(svc.users(), ["bob"]);
+        let request = AuthenticationRequest::new().with_basic("bob", "the-builder");
+        assert_eq!(svc.authenticate(&request).unwrap().name(), "bob");
+        let request = AuthenticationRequest::new().with_basic("bob", "builder");
+        let error = svc.authenticate(&request).unwrap_err();
+        assert!(matches!(error, AuthenticationError::WrongPassword));
+        let request = AuthenticationRequest::new().with_basic("alice", "wonderland");
+        let error = svc.authenticate(&request).unwrap_err();
+        assert!(matches!(error, AuthenticationError::UnknownIdentifier));
+    }
+}
@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use base64::Engine;
+use rand::rngs::OsRng;
+use rand::RngCore;
+
+use crate::auth::authentication::{
+    AuthenticationError, AuthenticationRequest, AuthenticationRequestPayload, AuthenticationService,
+};
+use crate::auth::users::User;
+
+/// How long a session lasts unless configured otherwise
+pub const DEFAULT_SESSION_TTL: Duration = Duration::from_secs(12 * 60 * 60);
+/// How long a session may go unused unless configured otherwise
+pub const DEFAULT_SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// The random bytes of a session token
+const TOKEN_BYTES: usize = 32;
+
+/// Issues session tokens to users who authenticated some other way, and authenticates them by
+/// those tokens until they expire, go unused for too long, or are revoked.
+pub struct SessionService {
+    ttl: Duration,
+    idle_timeout: Duration,
+    sessions: Mutex<HashMap<String, Session>>,
+}
+
+/// A session issued to a user
+#[derive(Debug, Clone)]
+struct Session {
+    user: User,
+    expires: Instant,
+    last_used: Instant,
+}
+
+impl Session {
+    fn is_live(&self, idle_timeout: Duration, now: Instant) -> bool {
+        now < self.expires && now.saturating_duration_since(self.last_used) < idle_timeout
+    }
+}
+
+/// A session token issued to a user
+#[derive(Clone)]
+pub struct SessionToken {
+    pub token: String,
+    /// How long the session lasts, unless it goes unused for too long first
+    pub expires_in: Duration,
+}
+
+impl Debug for SessionToken {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionToken")
+            .field("token", &"****")
+            .field("expires_in", &self.expires_in)
+            .finish()
+    }
+}
+
+impl Debug for SessionService {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SessionService")
+            .field("ttl", &self.ttl)
+            .field("idle_timeout", &self.idle_timeout)
+            .field("sessions", &self.len())
+            .finish()
+    }
+}
+
+impl Default for SessionService {
+    fn default() -> Self {
+        Self::new(DEFAULT_SESSION_TTL, DEFAULT_SESSION_IDLE_TIMEOUT)
+    }
+}
+
+impl SessionService {
+    /// Creates a service whose sessions last for `ttl`, or until they go unused for
+    /// `idle_timeout`
+    pub fn new(ttl: Duration, idle_timeout: Duration) -> Self {
+        Self {
+            ttl,
+            idle_timeout,
+            sessions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Gets how long sessions last
+    pub fn ttl(&self) -> Duration {
+        self.ttl
+    }
+
+    /// Gets how long sessions may go unused
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+    }
+
+    /// Gets the number of sessions that were issued and not yet removed
+    pub fn len(&self) -> usize {
+        self.sessions.lock().expect("poisoned").len()
+    }
+
+    /// Checks whether there are no sessions
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Starts a session for a user, returning its token
+    pub fn issue(&self, user: &User) -> SessionToken {
+        self.issue_at(user, Instant::now())
+    }
+
+    /// Starts a session for a user as of the given time
+    pub fn issue_at(&self, user: &User, now: Instant) -> SessionToken {
+        let mut bytes = [0; TOKEN_BYTES];
+        OsRng.fill_bytes(&mut bytes);
+        let token = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes);
+        let session = Session {
+            user: user.clone(),
+            expires: now + self.ttl,
+            last_used: now,
+        };
+        let mut sessions = self.sessions.lock().expect("poisoned");
+        // sessions nobody uses anymore are only removed here
+        sessions.retain(|_, session| session.is_live(self.idle_timeout, now));
+        sessions.insert(token.clone(), session);
+        SessionToken {
+            token,
+            expires_in: self.ttl,
+        }
+    }
+
+    /// Gets the user of a session, marking the session as used. Sessions that expired or went
+    /// unused for too long are removed.
+    pub fn validate(&self, token: &str) -> Option<User> {
+        self.validate_at(token, Instant::now())
+    }
+
+    /// Gets the user of a session as of the given time
+    pub fn validate_at(&self, token: &str, now: Instant) -> Option<User> {
+        let mut sessions = self.sessions.lock().expect("poisoned");
+        let session = sessions.get_mut(token)?;
+        if !session.is_live(self.idle_timeout, now) {
+            sessions.remove(token);
+            return None;
+        }
+        session.last_used = now;
+        Some(session.user.clone())
+    }
+
+    /// Ends a session, returning whether it existed
+    pub fn revoke(&self, token: &str) -> bool {
+        self.sessions
+            .lock()
+            .expect("poisoned")
+            .remove(token)
+            .is_some()
+    }
+
+    /// Ends every session of a user, returning how many were ended
+    pub fn revoke_user(&self, name: &str) -> usize {
+        let mut sessions = self.sessions.lock().expect("poisoned");
+        let before = sessions.len();
+        sessions.retain(|_, session| session.user.name() != name);
+        before - sessions.len()
+    }
+}
+
+impl AuthenticationService for SessionService {
+    fn authenticate(&self, req: &AuthenticationRequest) -> Result<User, AuthenticationError> {
+        let mut token_check = false;
+        for token in req.payloads().filter_map(|req| match req {
+            AuthenticationRequestPayload::Token { token } => Some(*token),
+            _ => None,
+        }) {
+            token_check = true;
+            if let Some(user) = self.validate(token) {
+                return Ok(user);
+            }
+        }
+
+        if token_check {
+            Err(AuthenticationError::InvalidToken)
+        } else {
+            Err(AuthenticationError::UnsupportedRequestKind)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::users::UserFactory;
+
+    #[test]
+    fn sessions_expire() {
+        let svc = SessionService::new(Duration::from_secs(60), Duration::from_secs(10));
+        let start = Instant::now();
+        let reader = UserFactory.create("reader");
+        let session = svc.issue_at(&reader, start);
+        assert_eq!(session.token.len(), 43);
+
+        let request = AuthenticationRequest::new().with_token(&session.token);
+        assert_eq!(svc.authenticate(&request).unwrap().name(), "reader");
+
+        // using a session keeps it from idling out, but not from expiring
+        for secs in [5, 14, 23, 32, 41, 50, 59] {
+            let now = start + Duration::from_secs(secs);
+            assert!(svc.validate_at(&session.token, now).is_some());
+        }
+        let expired = start + Duration::from_secs(60);
+        assert!(svc.validate_at(&session.token, expired).is_none());
+        assert!(svc.is_empty());
+
+        let idle = svc.issue_at(&reader, start);
+        assert!(svc
+            .validate_at(&idle.token, start + Duration::from_secs(10))
+            .is_none());
+    }
+
+    #[test]
+    fn sessions_are_revoked() {
+        let svc = SessionService::default();
+        let reader = UserFactory.create("reader");
+        let first = svc.issue(&reader);
+        let second = svc.issue(&reader);
+        let writer = svc.issue(&UserFactory.create("writer"));
+        assert_ne!(first.token, second.token);
+
+        assert!(svc.revoke(&first.token));
+        assert!(!svc.revoke(&first.token));
+        let request = AuthenticationRequest::new().with_token(&first.token);
+        let error = svc.authenticate(&request).unwrap_err();
+        assert!(matches!(error, AuthenticationError::InvalidToken));
+
+        assert_eq!(svc.revoke_user("reader"), 1);
+        assert!(svc.validate(&second.token).is_none());
+        assert!(svc.validate(&writer.token).is_some());
+    }
+}
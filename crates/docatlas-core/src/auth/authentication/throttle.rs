@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How many failed attempts in a row lock a user or address out unless configured otherwise
+pub const DEFAULT_MAX_FAILURES: u32 = 5;
+/// How long to wait after the first failed attempt unless configured otherwise, doubling with
+/// every other failed attempt
+pub const DEFAULT_BACKOFF: Duration = Duration::from_secs(1);
+/// How long a lockout lasts unless configured otherwise
+pub const DEFAULT_LOCKOUT: Duration = Duration::from_secs(15 * 60);
+
+/// What failed attempts to authenticate are counted against
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum LoginKey {
+    User(String),
+    Ip(IpAddr),
+}
+
+impl Display for LoginKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LoginKey::User(user) => write!(f, "user {user:?}"),
+            LoginKey::Ip(ip) => write!(f, "address {ip}"),
+        }
+    }
+}
+
+/// Slows down guessing credentials. Every failed attempt to authenticate as a user, or from an
+/// address, makes the next attempt wait twice as long as the one before, until too many attempts
+/// failed in a row and the user or address is locked out for a while.
+#[derive(Debug)]
+pub struct LoginThrottle {
+    max_failures: u32,
+    backoff: Duration,
+    lockout: Duration,
+    failures: Mutex<HashMap<LoginKey, Failures>>,
+}
+
+/// The failed attempts in a row of a user or address
+#[derive(Debug, Clone, Copy)]
+struct Failures {
+    count: u32,
+    last: Instant,
+}
+
+/// Attempts to authenticate must wait
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Throttled {
+    /// How long until the next attempt is allowed
+    pub retry_after: Duration,
+    /// Whether too many attempts failed, rather than the last one failing too recently
+    pub locked_out: bool,
+}
+
+impl Default for LoginThrottle {
+    fn default() -> Self {
+        Self::new(DEFAULT_MAX_FAILURES, DEFAULT_BACKOFF, DEFAULT_LOCKOUT)
+    }
+}
+
+impl LoginThrottle {
+    /// Creates a throttle locking users and addresses out for `lockout` after `max_failures`
+    /// failed attempts in a row, and making them wait `backoff` after the first, doubling after
+    /// every other
+    pub fn new(max_failures: u32, backoff: Duration, lockout: Duration) -> Self {
+        Self {
+            max_failures: max_failures.max(1),
+            backoff,
+            lockout,
+            failures: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Gets how long to wait after some failed attempts in a row, and whether that is a lockout
+    fn wait(&self, count: u32) -> (Duration, bool) {
+        if count >= self.max_failures {
+            return (self.lockout, true);
+        }
+        let doubled = self
+            .backoff
+            .saturating_mul(1 << (count - 1).min(31))
+            .min(self.lockout);
+        (doubled, false)
+    }
+
+    /// Checks whether attempts by any of the keys must wait, returning the longest wait
+    pub fn check(&self, keys: &[LoginKey]) -> Result<(), Throttled> {
+        self.check_at(keys, Instant::now())
+    }
+
+    /// Checks whether attempts by any of the keys must wait as of the given time
+    pub fn check_at(&self, keys: &[LoginKey], now: Instant) -> Result<(), Throttled> {
+        let failures = self.failures.lock().expect("poisoned");
+        keys.iter()
+            .filter_map(|key| failures.get(key))
+            .filter_map(|failures| {
+                let (wait, locked_out) = self.wait(failures.count);
+                let retry_after = (failures.last + wait).checked_duration_since(now)?;
+                (!retry_after.is_zero()).then_some(Throttled {
+                    retry_after,
+                    locked_out,
+                })
+            })
+            .max_by_key(|throttled| throttled.retry_after)
+            .map_or(Ok(()), Err)
+    }
+
+    /// Records a failed attempt by every key, returning the keys it locked out
+    pub fn fail(&self, keys: &[LoginKey]) -> Vec<LoginKey> {
+        self.fail_at(keys, Instant::now())
+    }
+
+    /// Records a failed attempt by every key as of the given time
+    pub fn fail_at(&self, keys: &[LoginKey], now: Instant) -> Vec<LoginKey> {
+        let mut failures = self.failures.lock().expect("poisoned");
+        // failures are forgotten once nothing could still be waiting on them
+        failures.retain(|_, failures| now.saturating_duration_since(failures.last) < self.lockout);
+        let mut locked_out = vec![];
+        for key in keys {
+            let failures = failures.entry(key.clone()).or_insert(Failures {
+                count: 0,
+                last: now,
+            });
+            failures.count += 1;
+            failures.last = now;
+            if failures.count == self.max_failures {
+                locked_out.push(key.clone());
+            }
+        }
+        locked_out
+    }
+
+    /// Forgets the failed attempts of every key, once one succeeded
+    pub fn succeed(&self, keys: &[LoginKey]) {
+        let mut failures = self.failures.lock().expect("poisoned");
+        for key in keys {
+            failures.remove(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backs_off_then_locks_out() {
+        let throttle = LoginThrottle::new(3, Duration::from_secs(1), Duration::from_secs(60));
+        let alice = [LoginKey::User("alice".to_string())];
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        assert!(throttle.check_at(&alice, start).is_ok());
+
+        assert!(throttle.fail_at(&alice, start).is_empty());
+        let throttled = throttle.check_at(&alice, start).unwrap_err();
+        assert_eq!(throttled.retry_after, Duration::from_secs(1));
+        assert!(!throttled.locked_out);
+        assert!(throttle.check_at(&alice, at(1)).is_ok());
+
+        assert!(throttle.fail_at(&alice, at(1)).is_empty());
+        assert!(throttle.check_at(&alice, at(2)).is_err());
+        assert!(throttle.check_at(&alice, at(3)).is_ok());
+
+        assert_eq!(throttle.fail_at(&alice, at(3)), alice);
+        let throttled = throttle.check_at(&alice, at(30)).unwrap_err();
+        assert_eq!(throttled.retry_after, Duration::from_secs(33));
+        assert!(throttled.locked_out);
+        assert!(throttle.check_at(&alice, at(63)).is_ok());
+
+        // other users are not held back
+        assert!(throttle
+            .check_at(&[LoginKey::User("bob".to_string())], at(30))
+            .is_ok());
+    }
+
+    #[test]
+    fn success_forgets_failures() {
+        let throttle = LoginThrottle::default();
+        let keys = [
+            LoginKey::User("alice".to_string()),
+            LoginKey::Ip([127, 0, 0, 1].into()),
+        ];
+        throttle.fail(&keys);
+        assert!(throttle.check(&keys[..1]).is_err());
+        assert!(throttle.check(&keys[1..]).is_err());
+        throttle.succeed(&keys[..1]);
+        assert!(throttle.check(&keys[..1]).is_ok());
+        assert!(throttle.check(&keys[1..]).is_err());
+    }
+}
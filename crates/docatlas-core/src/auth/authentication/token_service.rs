@@ -0,0 +1,102 @@
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::io;
+use std::io::{BufRead, ErrorKind};
+
+use crate::auth::authentication::{
+    AuthenticationError, AuthenticationRequest, AuthenticationRequestPayload, AuthenticationService,
+};
+use crate::auth::users::{User, UserFactory};
+
+/// Authenticates users by tokens given to them ahead of time
+#[derive(Default)]
+pub struct TokenAuthenticationService {
+    users: HashMap<String, String>,
+}
+
+impl Debug for TokenAuthenticationService {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TokenAuthenticationService")
+            .field("tokens", &self.users.len())
+            .finish()
+    }
+}
+
+impl TokenAuthenticationService {
+    /// Creates a service without any tokens
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads tokens from lines of a user name followed by their token, separated by whitespace.
+    /// Empty lines and lines starting with `#` are skipped.
+    pub fn read<R: BufRead>(reader: R) -> io::Result<Self> {
+        let mut service = Self::new();
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let Some((user, token)) = line.split_once(char::is_whitespace) else {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidData,
+                    format!("expected a user and a token, found {line:?}"),
+                ));
+            };
+            service = service.with_token(token.trim(), user);
+        }
+        Ok(service)
+    }
+
+    /// Adds a token authenticating the given user
+    pub fn with_token(mut self, token: impl Into<String>, user: impl Into<String>) -> Self {
+        self.users.insert(token.into(), user.into());
+        self
+    }
+}
+
+impl AuthenticationService for TokenAuthenticationService {
+    fn authenticate(&self, req: &AuthenticationRequest) -> Result<User, AuthenticationError> {
+        let mut token_check = false;
+        for token in req.payloads().filter_map(|req| match req {
+            AuthenticationRequestPayload::Token { token } => Some(*token),
+            _ => None,
+        }) {
+            token_check = true;
+            if let Some(user) = self.users.get(token) {
+                return Ok(UserFactory.create(user));
+            }
+        }
+
+        if token_check {
+            Err(AuthenticationError::InvalidToken)
+        } else {
+            Err(AuthenticationError::UnsupportedRequestKind)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_tokens() {
+        let svc = TokenAuthenticationService::read(
+            "# user token\nreader   s3cr3t\n\nwriter w0rd\n".as_bytes(),
+        )
+        .unwrap();
+
+        let request = AuthenticationRequest::new().with_token("w0rd");
+        assert_eq!(svc.authenticate(&request).unwrap().name(), "writer");
+
+        let request = AuthenticationRequest::new().with_token("reader");
+        let error = svc.authenticate(&request).unwrap_err();
+        assert!(matches!(error, AuthenticationError::InvalidToken));
+
+        let request = AuthenticationRequest::new().with_basic("reader", "s3cr3t");
+        let error = svc.authenticate(&request).unwrap_err();
+        assert!(matches!(error, AuthenticationError::UnsupportedRequestKind));
+    }
+}
@@ -1 +1,650 @@
-//! Used for determining
+//! Used for determining what an authenticated user is able to perform
+//!
+//! What a user may do is given by their roles. A role grants permissions on the indexes matching
+//! a pattern, such as `logs-*`, and on the daemon as a whole (the cluster). Roles are bound to
+//! users by name, and to the groups users belong to, or given to users when they authenticate.
+//! Groups stored by the daemon give their roles to their members as well.
+//! Users without any role are given the default roles instead.
+//!
+//! Permissions are ordered, every permission including the ones before it: managing an index
+//! includes writing its documents, which includes reading them.
+//!
+//! A grant may also restrict the fields of the indexes users may read, in which case every other
+//! field is stripped from the documents they get, and they may not search by those fields.
+//!
+//! Without any other bindings, the admin user is an `admin`, and every other user is a `writer`.
+//!
+//! An [`AuthorizationService`] outside of the daemon, such as a policy engine, may be asked as
+//! well, in which case a request is only allowed if both the roles of the user and the service
+//! allow it.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::auth::authentication::DEFAULT_USER;
+use crate::auth::users::{Groups, User};
+use crate::document::Document;
+
+/// The role of the admin user, which may do anything
+pub const ADMIN_ROLE: &str = "admin";
+/// A role allowing to read and write the documents of every index
+pub const WRITER_ROLE: &str = "writer";
+/// A role allowing to read the documents of every index
+pub const READER_ROLE: &str = "reader";
+
+/// Something a user may be allowed to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Permission {
+    /// Read and search documents
+    Read,
+    /// Add, update and delete documents
+    Write,
+    /// Create indexes and change their schemas
+    Manage,
+}
+
+impl Display for Permission {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let action = match self {
+            Permission::Read => "read documents",
+            Permission::Write => "write documents",
+            Permission::Manage => "manage indexes",
+        };
+        f.write_str(action)
+    }
+}
+
+/// What a permission is needed on
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Resource {
+    /// A single index
+    Index(String),
+    /// The daemon as a whole, or every index at once
+    Cluster,
+    /// A single field of an index
+    Field { index: String, field: String },
+}
+
+impl Resource {
+    /// Gets the index with the given name, or the cluster if there is none
+    pub fn of(index: Option<&str>) -> Self {
+        match index {
+            Some(index) => Resource::Index(index.to_string()),
+            None => Resource::Cluster,
+        }
+    }
+}
+
+/// A pattern matching the names of indexes, where `*` matches any number of characters
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct IndexPattern(String);
+
+impl IndexPattern {
+    /// Creates a pattern
+    pub fn new(pattern: impl Into<String>) -> Self {
+        Self(pattern.into())
+    }
+
+    /// Checks whether an index name matches the pattern
+    pub fn matches(&self, name: &str) -> bool {
+        let mut parts = self.0.split('*');
+        let first = parts.next().unwrap_or_default();
+        let Some(mut rest) = name.strip_prefix(first) else {
+            return false;
+        };
+        let parts = parts.collect::<Vec<_>>();
+        let Some((last, middle)) = parts.split_last() else {
+            // no wildcard, so the name must be the pattern itself
+            return rest.is_empty();
+        };
+        for part in middle {
+            match rest.find(part) {
+                Some(at) => rest = &rest[at + part.len()..],
+                None => return false,
+            }
+        }
+        rest.ends_with(last)
+    }
+}
+
+/// A permission on every index matching a pattern
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IndexGrant {
+    pub pattern: IndexPattern,
+    pub permission: Permission,
+    /// The only fields the grant covers, or every field if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fields: Option<Vec<String>>,
+}
+
+impl IndexGrant {
+    /// Checks whether the grant covers a field
+    fn covers(&self, field: &str) -> bool {
+        self.fields
+            .as_ref()
+            .is_none_or(|fields| fields.iter().any(|covered| covered == field))
+    }
+}
+
+/// The fields of an index a user may read
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FieldAccess {
+    /// Every field
+    All,
+    /// Only the given fields
+    Only(HashSet<String>),
+}
+
+impl FieldAccess {
+    /// Checks whether a field may be read
+    pub fn allows(&self, field: &str) -> bool {
+        match self {
+            FieldAccess::All => true,
+            FieldAccess::Only(fields) => fields.contains(field),
+        }
+    }
+
+    /// Removes every field of a document that may not be read
+    pub fn strip(&self, document: &mut Document) {
+        let FieldAccess::Only(_) = self else {
+            return;
+        };
+        let hidden = document
+            .fields()
+            .iter()
+            .map(|(name, _)| name)
+            .filter(|name| !self.allows(name))
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        for name in hidden {
+            document.fields_mut().remove(name);
+        }
+    }
+}
+
+/// A named set of permissions
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Role {
+    pub name: String,
+    /// The permission on the daemon as a whole, if any
+    #[serde(default)]
+    pub cluster: Option<Permission>,
+    /// The permissions on indexes
+    #[serde(default)]
+    pub indexes: Vec<IndexGrant>,
+}
+
+impl Role {
+    /// Creates a role without any permissions
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            cluster: None,
+            indexes: vec![],
+        }
+    }
+
+    /// Grants a permission on the daemon as a whole
+    pub fn with_cluster(mut self, permission: Permission) -> Self {
+        self.cluster = Some(permission);
+        self
+    }
+
+    /// Grants a permission on every index matching a pattern
+    pub fn with_indexes(mut self, pattern: impl Into<String>, permission: Permission) -> Self {
+        self.indexes.push(IndexGrant {
+            pattern: IndexPattern::new(pattern),
+            permission,
+            fields: None,
+        });
+        self
+    }
+
+    /// Grants a permission on only some fields of every index matching a pattern
+    pub fn with_index_fields<I, S>(
+        mut self,
+        pattern: impl Into<String>,
+        permission: Permission,
+        fields: I,
+    ) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.indexes.push(IndexGrant {
+            pattern: IndexPattern::new(pattern),
+            permission,
+            fields: Some(fields.into_iter().map(Into::into).collect()),
+        });
+        self
+    }
+
+    /// Gets the grants of the role on an index that include a permission
+    fn grants<'a>(
+        &'a self,
+        permission: Permission,
+        index: &'a str,
+    ) -> impl Iterator<Item = &'a IndexGrant> {
+        self.indexes
+            .iter()
+            .filter(move |grant| grant.permission >= permission && grant.pattern.matches(index))
+    }
+
+    /// Checks whether the role grants a permission on a resource
+    pub fn allows(&self, permission: Permission, resource: &Resource) -> bool {
+        match resource {
+            Resource::Cluster => self.cluster.is_some_and(|granted| granted >= permission),
+            Resource::Index(name) => self.grants(permission, name).next().is_some(),
+            Resource::Field { index, field } => self
+                .grants(permission, index)
+                .any(|grant| grant.covers(field)),
+        }
+    }
+}
+
+/// Roles, and who they are bound to, as read from a file
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RoleBindings {
+    /// Roles defined in addition to the built in ones
+    #[serde(default)]
+    pub roles: Vec<Role>,
+    /// The roles of users, by user name
+    #[serde(default)]
+    pub users: HashMap<String, Vec<String>>,
+    /// The roles of every user in a group, by group name
+    #[serde(default)]
+    pub groups: HashMap<String, Vec<String>>,
+    /// The roles of users bound to no role, replacing the default of `writer`
+    #[serde(default)]
+    pub default_roles: Option<Vec<String>>,
+}
+
+/// Decides what users may do, given their roles
+#[derive(Debug, Clone)]
+pub struct Authorizer {
+    roles: HashMap<String, Role>,
+    users: HashMap<String, Vec<String>>,
+    groups: HashMap<String, Vec<String>>,
+    default_roles: Vec<String>,
+    group_store: Option<Arc<Groups>>,
+}
+
+impl Default for Authorizer {
+    fn default() -> Self {
+        let roles = [
+            Role::new(ADMIN_ROLE)
+                .with_cluster(Permission::Manage)
+                .with_indexes("*", Permission::Manage),
+            Role::new(WRITER_ROLE)
+                .with_cluster(Permission::Write)
+                .with_indexes("*", Permission::Write),
+            Role::new(READER_ROLE)
+                .with_cluster(Permission::Read)
+                .with_indexes("*", Permission::Read),
+        ];
+        Self {
+            roles: roles
+                .into_iter()
+                .map(|role| (role.name.clone(), role))
+                .collect(),
+            users: HashMap::from([(DEFAULT_USER.to_string(), vec![ADMIN_ROLE.to_string()])]),
+            groups: HashMap::new(),
+            default_roles: vec![WRITER_ROLE.to_string()],
+            group_store: None,
+        }
+    }
+}
+
+impl Authorizer {
+    /// Creates an authorizer with only the built in roles and bindings
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a role, replacing any role with the same name
+    pub fn with_role(mut self, role: Role) -> Self {
+        self.roles.insert(role.name.clone(), role);
+        self
+    }
+
+    /// Binds roles to a user, replacing the roles the user was bound to
+    pub fn with_user_roles(mut self, user: impl Into<String>, roles: Vec<String>) -> Self {
+        self.users.insert(user.into(), roles);
+        self
+    }
+
+    /// Binds roles to every user in a group, replacing the roles the group was bound to
+    pub fn with_group_roles(mut self, group: impl Into<String>, roles: Vec<String>) -> Self {
+        self.groups.insert(group.into(), roles);
+        self
+    }
+
+    /// Sets the roles of users bound to no role
+    pub fn with_default_roles(mut self, roles: Vec<String>) -> Self {
+        self.default_roles = roles;
+        self
+    }
+
+    /// Gives the members of stored groups the roles of those groups, and the roles bound to them
+    pub fn with_group_store(mut self, groups: Arc<Groups>) -> Self {
+        self.group_store = Some(groups);
+        self
+    }
+
+    /// Adds every role and binding
+    pub fn with_bindings(self, bindings: RoleBindings) -> Self {
+        let mut authorizer = bindings.roles.into_iter().fold(self, Self::with_role);
+        for (user, roles) in bindings.users {
+            authorizer = authorizer.with_user_roles(user, roles);
+        }
+        for (group, roles) in bindings.groups {
+            authorizer = authorizer.with_group_roles(group, roles);
+        }
+        match bindings.default_roles {
+            Some(roles) => authorizer.with_default_roles(roles),
+            None => authorizer,
+        }
+    }
+
+    /// Gets every role of a user, given to them when they authenticated or bound to them directly
+    /// or through their groups, including the stored groups they are a member of. Roles that do
+    /// not exist are ignored.
+    pub fn roles_of(&self, user: &User) -> Vec<&Role> {
+        let stored = self
+            .group_store
+            .as_ref()
+            .map(|groups| groups.of(user.name()))
+            .unwrap_or_default();
+        let mut names = self
+            .users
+            .get(user.name())
+            .into_iter()
+            .chain(
+                user.groups()
+                    .iter()
+                    .chain(stored.iter().map(|group| &group.name))
+                    .filter_map(|group| self.groups.get(group)),
+            )
+            .flatten()
+            .chain(stored.iter().flat_map(|group| &group.roles))
+            .chain(user.roles())
+            .collect::<Vec<_>>();
+        if names.is_empty() {
+            names = self.default_roles.iter().collect();
+        }
+        names
+            .into_iter()
+            .filter_map(|name| self.roles.get(name))
+            .collect()
+    }
+
+    /// Checks if a user has a permission on a resource through any of their roles
+    pub fn authorize(
+        &self,
+        user: &User,
+        permission: Permission,
+        resource: &Resource,
+    ) -> Result<(), AuthorizationError> {
+        if self
+            .roles_of(user)
+            .iter()
+            .any(|role| role.allows(permission, resource))
+        {
+            return Ok(());
+        }
+        Err(AuthorizationError::new(user, permission, resource.clone()))
+    }
+}
+
+impl Authorizer {
+    /// Gets the fields of an index a user may read through any of their roles
+    pub fn field_access(&self, user: &User, index: &str) -> FieldAccess {
+        let mut fields = HashSet::new();
+        for grant in self
+            .roles_of(user)
+            .into_iter()
+            .flat_map(|role| role.grants(Permission::Read, index))
+        {
+            match &grant.fields {
+                Some(covered) => fields.extend(covered.iter().cloned()),
+                None => return FieldAccess::All,
+            }
+        }
+        FieldAccess::Only(fields)
+    }
+}
+
+/// Checks if a user has a permission on the daemon with the built in roles. The admin may do
+/// anything, while every other user may read and write documents.
+pub fn authorize(user: &User, permission: Permission) -> Result<(), AuthorizationError> {
+    Authorizer::default().authorize(user, permission, &Resource::Cluster)
+}
+
+/// Decides what users may do from outside of the daemon, such as by asking a policy engine
+#[async_trait]
+pub trait AuthorizationService: Send + Sync {
+    /// Checks if a user has a permission on a resource. Errors deny the permission.
+    async fn allows(
+        &self,
+        user: &User,
+        permission: Permission,
+        resource: &Resource,
+    ) -> io::Result<bool>;
+}
+
+/// A user does not have a permission
+#[derive(Debug, Clone, Error)]
+pub struct AuthorizationError {
+    user: String,
+    permission: Permission,
+    resource: Resource,
+}
+
+impl AuthorizationError {
+    /// Creates the error of a user not having a permission on a resource
+    pub fn new(user: &User, permission: Permission, resource: Resource) -> Self {
+        Self {
+            user: user.name().to_string(),
+            permission,
+            resource,
+        }
+    }
+}
+
+impl Display for AuthorizationError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "User {:?} may not {}", self.user, self.permission)?;
+        match &self.resource {
+            Resource::Index(index) => write!(f, " of {index:?}"),
+            Resource::Field { index, field } => write!(f, " of {index:?} by field {field:?}"),
+            Resource::Cluster => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::users::UserFactory;
+
+    #[test]
+    fn only_admin_manages() {
+        let admin = UserFactory.create(DEFAULT_USER);
+        let reader = UserFactory.create("reader");
+        assert!(authorize(&admin, Permission::Manage).is_ok());
+        assert!(authorize(&reader, Permission::Write).is_ok());
+        let error = authorize(&reader, Permission::Manage).unwrap_err();
+        assert_eq!(error.to_string(), "User \"reader\" may not manage indexes");
+    }
+
+    #[test]
+    fn index_patterns() {
+        let logs = IndexPattern::new("logs-*");
+        assert!(logs.matches("logs-2023") && logs.matches("logs-"));
+        assert!(!logs.matches("books") && !logs.matches("old-logs-1"));
+        assert!(IndexPattern::new("*").matches("anything"));
+        assert!(IndexPattern::new("a*b*c").matches("a-b-c"));
+        assert!(!IndexPattern::new("a*b*c").matches("a-c-b"));
+        assert!(IndexPattern::new("books").matches("books"));
+        assert!(!IndexPattern::new("books").matches("books2"));
+    }
+
+    #[test]
+    fn roles_bound_to_users_and_groups() {
+        let authorizer = Authorizer::new()
+            .with_role(
+                Role::new("logs")
+                    .with_cluster(Permission::Read)
+                    .with_indexes("logs-*", Permission::Write),
+            )
+            .with_user_roles("alice", vec!["logs".to_string()])
+            .with_group_roles("ops", vec![ADMIN_ROLE.to_string()])
+            .with_default_roles(vec![READER_ROLE.to_string()]);
+        let logs = Resource::Index("logs-1".to_string());
+        let books = Resource::Index("books".to_string());
+
+        let alice = UserFactory.create("alice");
+        assert!(authorizer
+            .authorize(&alice, Permission::Write, &logs)
+            .is_ok());
+        assert!(authorizer
+            .authorize(&alice, Permission::Read, &Resource::Cluster)
+            .is_ok());
+        let error = authorizer
+            .authorize(&alice, Permission::Read, &books)
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "User \"alice\" may not read documents of \"books\""
+        );
+
+        let bob = UserFactory.create("bob");
+        assert!(authorizer.authorize(&bob, Permission::Read, &books).is_ok());
+        assert!(authorizer
+            .authorize(&bob, Permission::Write, &books)
+            .is_err());
+
+        // roles given when authenticating replace the default ones
+        let dave = UserFactory.create("dave").with_roles(["logs"]);
+        assert!(authorizer
+            .authorize(&dave, Permission::Read, &books)
+            .is_err());
+
+        let carol = UserFactory.create("carol").with_groups(["ops"]);
+        assert!(authorizer
+            .authorize(&carol, Permission::Manage, &Resource::Cluster)
+            .is_ok());
+    }
+
+    #[test]
+    fn anonymous_access() {
+        let authorizer = Authorizer::new()
+            .with_role(Role::new("public").with_indexes("demo-*", Permission::Read));
+        let demo = Resource::Index("demo-books".to_string());
+        let books = Resource::Index("books".to_string());
+
+        // anonymous clients only get the roles they are given, never the default ones
+        let anonymous = UserFactory.anonymous(["public"]);
+        assert!(authorizer
+            .authorize(&anonymous, Permission::Read, &demo)
+            .is_ok());
+        assert!(authorizer
+            .authorize(&anonymous, Permission::Write, &demo)
+            .is_err());
+        assert!(authorizer
+            .authorize(&anonymous, Permission::Read, &books)
+            .is_err());
+    }
+
+    #[test]
+    fn roles_of_stored_groups() {
+        let groups = Arc::new(Groups::in_memory());
+        let authorizer = Authorizer::new()
+            .with_group_roles("ops", vec![ADMIN_ROLE.to_string()])
+            .with_group_store(groups.clone());
+        let alice = UserFactory.create("alice");
+        let books = Resource::Index("books".to_string());
+        assert!(authorizer
+            .authorize(&alice, Permission::Manage, &books)
+            .is_err());
+
+        groups.create("ops").unwrap();
+        groups.create("auditors").unwrap();
+        groups
+            .set_roles("auditors", vec![READER_ROLE.to_string()])
+            .unwrap();
+        groups.add_member("auditors", "alice").unwrap();
+        // roles given by groups replace the default ones
+        assert!(authorizer
+            .authorize(&alice, Permission::Read, &books)
+            .is_ok());
+        assert!(authorizer
+            .authorize(&alice, Permission::Write, &books)
+            .is_err());
+
+        groups.add_member("ops", "alice").unwrap();
+        assert!(authorizer
+            .authorize(&alice, Permission::Manage, &books)
+            .is_ok());
+        groups.remove_member("ops", "alice").unwrap();
+        assert!(authorizer
+            .authorize(&alice, Permission::Manage, &books)
+            .is_err());
+    }
+
+    #[test]
+    fn field_security() {
+        let authorizer = Authorizer::new()
+            .with_role(
+                Role::new("directory")
+                    .with_index_fields("staff", Permission::Read, ["name", "phone"])
+                    .with_index_fields("staff-*", Permission::Read, ["email"]),
+            )
+            .with_role(Role::new("payroll").with_indexes("staff*", Permission::Read))
+            .with_user_roles("alice", vec!["directory".to_string()])
+            .with_user_roles("bob", vec!["directory".to_string(), "payroll".to_string()]);
+        let field = |field: &str| Resource::Field {
+            index: "staff".to_string(),
+            field: field.to_string(),
+        };
+
+        let alice = UserFactory.create("alice");
+        let staff = Resource::Index("staff".to_string());
+        assert!(authorizer
+            .authorize(&alice, Permission::Read, &staff)
+            .is_ok());
+        assert!(authorizer
+            .authorize(&alice, Permission::Read, &field("name"))
+            .is_ok());
+        let error = authorizer
+            .authorize(&alice, Permission::Read, &field("salary"))
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "User \"alice\" may not read documents of \"staff\" by field \"salary\""
+        );
+        assert_eq!(
+            authorizer.field_access(&alice, "staff"),
+            FieldAccess::Only(HashSet::from(["name".to_string(), "phone".to_string()]))
+        );
+        assert!(authorizer
+            .field_access(&alice, "staff-2023")
+            .allows("email"));
+        assert!(!authorizer.field_access(&alice, "staff-2023").allows("name"));
+
+        // grants covering every field win over the ones covering some
+        let bob = UserFactory.create("bob");
+        assert_eq!(authorizer.field_access(&bob, "staff"), FieldAccess::All);
+        assert!(authorizer
+            .authorize(&bob, Permission::Read, &field("salary"))
+            .is_ok());
+    }
+}
@@ -0,0 +1,210 @@
+//! Rules for the passwords users choose, and how passwords are hashed before they are stored
+
+use std::fmt::{Display, Formatter};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use thiserror::Error;
+
+/// The fewest characters a password may have unless configured otherwise
+pub const DEFAULT_MIN_PASSWORD_LENGTH: usize = 8;
+
+/// The rules a new password must follow
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PasswordPolicy {
+    min_length: usize,
+    require_mixed_case: bool,
+    require_digit: bool,
+    require_symbol: bool,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            min_length: DEFAULT_MIN_PASSWORD_LENGTH,
+            require_mixed_case: false,
+            require_digit: false,
+            require_symbol: false,
+        }
+    }
+}
+
+impl PasswordPolicy {
+    /// Creates a policy only requiring passwords to have the default length
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the fewest characters a password may have
+    pub fn with_min_length(mut self, min_length: usize) -> Self {
+        self.min_length = min_length;
+        self
+    }
+
+    /// Requires passwords to have both lower and upper case letters
+    pub fn with_mixed_case(mut self, required: bool) -> Self {
+        self.require_mixed_case = required;
+        self
+    }
+
+    /// Requires passwords to have a digit
+    pub fn with_digit(mut self, required: bool) -> Self {
+        self.require_digit = required;
+        self
+    }
+
+    /// Requires passwords to have a character that is neither a letter nor a digit
+    pub fn with_symbol(mut self, required: bool) -> Self {
+        self.require_symbol = required;
+        self
+    }
+
+    /// Gets the fewest characters a password may have
+    pub fn min_length(&self) -> usize {
+        self.min_length
+    }
+
+    /// Checks a password against every rule, returning every rule it breaks
+    pub fn check(&self, password: &str) -> Result<(), PasswordPolicyError> {
+        let has = |predicate: fn(char) -> bool| password.chars().any(predicate);
+        let mut broken = vec![];
+        if password.chars().count() < self.min_length {
+            broken.push(PasswordRule::MinLength(self.min_length));
+        }
+        if self.require_mixed_case && !(has(char::is_lowercase) && has(char::is_uppercase)) {
+            broken.push(PasswordRule::MixedCase);
+        }
+        if self.require_digit && !has(|c| c.is_ascii_digit()) {
+            broken.push(PasswordRule::Digit);
+        }
+        if self.require_symbol && !has(|c| !c.is_alphanumeric()) {
+            broken.push(PasswordRule::Symbol);
+        }
+        match broken.is_empty() {
+            true => Ok(()),
+            false => Err(PasswordPolicyError { broken }),
+        }
+    }
+}
+
+/// A rule of a password policy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PasswordRule {
+    MinLength(usize),
+    MixedCase,
+    Digit,
+    Symbol,
+}
+
+impl Display for PasswordRule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PasswordRule::MinLength(length) => write!(f, "have at least {length} characters"),
+            PasswordRule::MixedCase => f.write_str("have both lower and upper case letters"),
+            PasswordRule::Digit => f.write_str("have a digit"),
+            PasswordRule::Symbol => f.write_str("have a character that is not a letter or digit"),
+        }
+    }
+}
+
+/// A password breaks the rules of a policy
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub struct PasswordPolicyError {
+    broken: Vec<PasswordRule>,
+}
+
+impl PasswordPolicyError {
+    /// Gets every rule the password breaks
+    pub fn broken(&self) -> &[PasswordRule] {
+        &self.broken
+    }
+}
+
+impl Display for PasswordPolicyError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let rules = self
+            .broken
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        write!(f, "The password must {}", rules.join(", and "))
+    }
+}
+
+/// The cost of hashing a password with argon2id. Higher costs make passwords harder to guess from
+/// their hashes, and slower to check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PasswordHashing {
+    memory_kib: u32,
+    iterations: u32,
+    parallelism: u32,
+}
+
+impl Default for PasswordHashing {
+    fn default() -> Self {
+        Self {
+            memory_kib: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl PasswordHashing {
+    /// Creates the hashing parameters recommended by OWASP
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the memory used to hash a password, in kibibytes
+    pub fn with_memory_kib(mut self, memory_kib: u32) -> Self {
+        self.memory_kib = memory_kib;
+        self
+    }
+
+    /// Sets the number of passes over the memory
+    pub fn with_iterations(mut self, iterations: u32) -> Self {
+        self.iterations = iterations;
+        self
+    }
+
+    /// Sets the number of lanes hashed in parallel
+    pub fn with_parallelism(mut self, parallelism: u32) -> Self {
+        self.parallelism = parallelism;
+        self
+    }
+
+    /// Creates the hasher, or returns why the parameters are invalid
+    pub fn hasher(&self) -> Result<Argon2<'static>, argon2::Error> {
+        let params = Params::new(self.memory_kib, self.iterations, self.parallelism, None)?;
+        Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_every_rule() {
+        let policy = PasswordPolicy::new()
+            .with_min_length(10)
+            .with_mixed_case(true)
+            .with_digit(true)
+            .with_symbol(true);
+        assert!(policy.check("Tr0ub4dor&3").is_ok());
+        let error = policy.check("admin").unwrap_err();
+        assert_eq!(error.broken().len(), 4);
+        assert_eq!(
+            policy.check("correcthorsebattery").unwrap_err().to_string(),
+            "The password must have both lower and upper case letters, and have a digit, and have \
+             a character that is not a letter or digit"
+        );
+        assert!(PasswordPolicy::new().check("12345678").is_ok());
+    }
+
+    #[test]
+    fn invalid_hashing() {
+        assert!(PasswordHashing::new().hasher().is_ok());
+        assert!(PasswordHashing::new().with_iterations(0).hasher().is_err());
+    }
+}
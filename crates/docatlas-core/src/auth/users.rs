@@ -1,15 +1,78 @@
 //! A user is just a representation of a person
+//!
+//! Users may belong to groups, which are given to them when they authenticate or stored by the
+//! daemon. Stored groups have members, and roles every member is given.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::RwLock;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::auth::authentication::replace_file;
+
+/// The name of the user clients act as before they authenticate, if the daemon lets them
+pub const ANONYMOUS_USER: &str = "anonymous";
 
 /// A user struct
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct User {
     name: String,
+    groups: Vec<String>,
+    roles: Vec<String>,
+    password_expired: bool,
 }
 
 impl User {
     pub fn name(&self) -> &str {
         &self.name
     }
+
+    /// Gets the groups the user belongs to
+    pub fn groups(&self) -> &[String] {
+        &self.groups
+    }
+
+    /// Adds the user to groups
+    pub fn with_groups<I, S>(mut self, groups: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.groups.extend(groups.into_iter().map(Into::into));
+        self
+    }
+
+    /// Gets the roles given to the user when they authenticated, in addition to the roles bound
+    /// to them
+    pub fn roles(&self) -> &[String] {
+        &self.roles
+    }
+
+    /// Checks whether the user authenticated with a password they must change before doing
+    /// anything else, such as the default password of the admin
+    pub fn must_change_password(&self) -> bool {
+        self.password_expired
+    }
+
+    /// Sets whether the user must change their password
+    pub fn with_password_expired(mut self, expired: bool) -> Self {
+        self.password_expired = expired;
+        self
+    }
+
+    /// Gives the user roles
+    pub fn with_roles<I, S>(mut self, roles: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.roles.extend(roles.into_iter().map(Into::into));
+        self
+    }
 }
 
 /// A user factory
@@ -21,6 +84,219 @@ impl UserFactory {
     pub fn create(&self, name: &str) -> User {
         User {
             name: name.to_string(),
+            groups: vec![],
+            roles: vec![],
+            password_expired: false,
+        }
+    }
+
+    /// Creates the user clients act as before they authenticate, with the given roles
+    pub fn anonymous<I, S>(&self, roles: I) -> User
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<String>,
+    {
+        self.create(ANONYMOUS_USER).with_roles(roles)
+    }
+}
+
+/// A named set of users, who are all given the roles of the group
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Group {
+    pub name: String,
+    /// The names of the users in the group
+    #[serde(default)]
+    pub members: BTreeSet<String>,
+    /// The roles given to every member
+    #[serde(default)]
+    pub roles: Vec<String>,
+}
+
+impl Group {
+    /// Creates a group without members or roles
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            ..Self::default()
         }
     }
 }
+
+/// Groups stored in a json file, which is rewritten at once whenever a group changes
+#[derive(Debug, Default)]
+pub struct Groups {
+    groups: RwLock<BTreeMap<String, Group>>,
+    file_path: Option<PathBuf>,
+}
+
+impl Groups {
+    /// Creates groups that only live in memory
+    pub fn in_memory() -> Self {
+        Self::default()
+    }
+
+    /// Opens the groups stored at the given path, where there are none if it does not exist yet
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let path = path.as_ref();
+        let groups = match std::fs::read(path) {
+            Ok(json) => serde_json::from_slice::<Vec<Group>>(&json)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?
+                .into_iter()
+                .map(|group| (group.name.clone(), group))
+                .collect(),
+            Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            groups: RwLock::new(groups),
+            file_path: Some(path.to_path_buf()),
+        })
+    }
+
+    /// Gets a group by name
+    pub fn get(&self, name: &str) -> Option<Group> {
+        self.groups.read().expect("poisoned").get(name).cloned()
+    }
+
+    /// Gets every group, ordered by name
+    pub fn list(&self) -> Vec<Group> {
+        self.groups
+            .read()
+            .expect("poisoned")
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    /// Gets every group a user is a member of
+    pub fn of(&self, user: &str) -> Vec<Group> {
+        self.groups
+            .read()
+            .expect("poisoned")
+            .values()
+            .filter(|group| group.members.contains(user))
+            .cloned()
+            .collect()
+    }
+
+    /// Creates an empty group
+    pub fn create(&self, name: &str) -> Result<(), GroupError> {
+        self.update(|groups| {
+            if groups.contains_key(name) {
+                return Err(GroupError::GroupExists(name.to_string()));
+            }
+            groups.insert(name.to_string(), Group::new(name));
+            Ok(())
+        })
+    }
+
+    /// Deletes a group, whose members lose its roles
+    pub fn delete(&self, name: &str) -> Result<(), GroupError> {
+        self.update(|groups| match groups.remove(name) {
+            Some(_) => Ok(()),
+            None => Err(GroupError::NoSuchGroup(name.to_string())),
+        })
+    }
+
+    /// Adds a user to a group, returning `false` if they already were a member
+    pub fn add_member(&self, group: &str, user: &str) -> Result<bool, GroupError> {
+        self.update_group(group, |group| group.members.insert(user.to_string()))
+    }
+
+    /// Removes a user from a group, returning `false` if they were not a member
+    pub fn remove_member(&self, group: &str, user: &str) -> Result<bool, GroupError> {
+        self.update_group(group, |group| group.members.remove(user))
+    }
+
+    /// Replaces the roles given to every member of a group
+    pub fn set_roles(&self, group: &str, roles: Vec<String>) -> Result<(), GroupError> {
+        self.update_group(group, |group| group.roles = roles)
+    }
+
+    /// Changes a single group
+    fn update_group<T>(
+        &self,
+        name: &str,
+        update: impl FnOnce(&mut Group) -> T,
+    ) -> Result<T, GroupError> {
+        self.update(|groups| match groups.get_mut(name) {
+            Some(group) => Ok(update(group)),
+            None => Err(GroupError::NoSuchGroup(name.to_string())),
+        })
+    }
+
+    /// Changes the groups, storing them before the change is seen
+    fn update<T>(
+        &self,
+        update: impl FnOnce(&mut BTreeMap<String, Group>) -> Result<T, GroupError>,
+    ) -> Result<T, GroupError> {
+        let mut groups = self.groups.write().expect("poisoned");
+        let mut changed = groups.clone();
+        let result = update(&mut changed)?;
+        if let Some(path) = &self.file_path {
+            let json = serde_json::to_vec_pretty(&changed.values().collect::<Vec<_>>())?;
+            replace_file(path, |file| file.write_all(&json))?;
+        }
+        *groups = changed;
+        Ok(result)
+    }
+}
+
+/// A group could not be changed
+#[derive(Debug, Error)]
+pub enum GroupError {
+    #[error("No group named {0:?}")]
+    NoSuchGroup(String),
+    #[error("A group named {0:?} already exists")]
+    GroupExists(String),
+    #[error("Could not store the groups: {0}")]
+    StoreError(#[from] io::Error),
+}
+
+impl From<serde_json::Error> for GroupError {
+    fn from(value: serde_json::Error) -> Self {
+        GroupError::StoreError(value.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn groups_are_stored() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("groups.json");
+        let groups = Groups::open(&path).unwrap();
+        groups.create("ops").unwrap();
+        groups.create("dev").unwrap();
+        assert!(matches!(
+            groups.create("ops"),
+            Err(GroupError::GroupExists(_))
+        ));
+        assert!(groups.add_member("ops", "alice").unwrap());
+        assert!(!groups.add_member("ops", "alice").unwrap());
+        assert!(groups.add_member("dev", "alice").unwrap());
+        assert!(groups.add_member("dev", "bob").unwrap());
+        groups.set_roles("ops", vec!["admin".to_string()]).unwrap();
+        assert!(groups.remove_member("dev", "bob").unwrap());
+        assert!(matches!(
+            groups.add_member("qa", "bob"),
+            Err(GroupError::NoSuchGroup(_))
+        ));
+
+        let groups = Groups::open(&path).unwrap();
+        let names = |user| {
+            groups
+                .of(user)
+                .into_iter()
+                .map(|group| group.name)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names("alice"), ["dev", "ops"]);
+        assert!(names("bob").is_empty());
+        assert_eq!(groups.get("ops").unwrap().roles, ["admin"]);
+        groups.delete("ops").unwrap();
+        assert_eq!(names("alice"), ["dev"]);
+    }
+}
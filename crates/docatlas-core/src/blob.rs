@@ -0,0 +1,278 @@
+//! Blobs are opaque binary payloads stored outside of rows.
+//!
+//! Rows only store a fixed size [reference](BlobRef) into a blob segment, which allows blobs of
+//! any size to be attached to a document. Blobs are never indexed.
+//!
+//! Blobs are [staged](StagedBlobs) while a row is encoded, and only appended to a segment once the
+//! row is accepted, so rows that end up rejected do not leave blobs behind.
+
+use std::fmt::Debug;
+use std::io;
+use std::ops::Range;
+use std::sync::Arc;
+
+use crate::persist::PersistentVec;
+
+/// The number of bytes needed to store a [`BlobRef`](BlobRef) within a row
+pub const BLOB_REF_SIZE: usize = 16;
+
+/// A reference to a blob stored within a blob segment
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlobRef {
+    offset: u64,
+    len: u64,
+}
+
+impl BlobRef {
+    /// Gets the offset of the blob within its segment
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Gets the length of the blob in bytes
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Checks if the referenced blob is empty
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Writes this reference into a cell as a little endian offset followed by a length
+    ///
+    /// # Panic
+    /// Panics if `cell` is not exactly [`BLOB_REF_SIZE`](BLOB_REF_SIZE) bytes long
+    pub fn write(&self, cell: &mut [u8]) {
+        assert_eq!(cell.len(), BLOB_REF_SIZE, "cell has incorrect size");
+        cell[..8].copy_from_slice(&self.offset.to_le_bytes());
+        cell[8..].copy_from_slice(&self.len.to_le_bytes());
+    }
+
+    /// Reads a reference from a cell, returning `None` if the cell references no blob
+    ///
+    /// # Panic
+    /// Panics if `cell` is not exactly [`BLOB_REF_SIZE`](BLOB_REF_SIZE) bytes long
+    pub fn read(cell: &[u8]) -> Option<Self> {
+        assert_eq!(cell.len(), BLOB_REF_SIZE, "cell has incorrect size");
+        let offset = u64::from_le_bytes(cell[..8].try_into().expect("offset is 8 bytes"));
+        let len = u64::from_le_bytes(cell[8..].try_into().expect("length is 8 bytes"));
+        (len != 0).then_some(Self { offset, len })
+    }
+}
+
+/// Reads blobs by their reference
+pub trait Blobs: Debug {
+    /// Gets the bytes of a blob, if the reference is within these blobs
+    fn get(&self, blob: BlobRef) -> Option<&[u8]>;
+}
+
+/// An append only segment of blobs.
+///
+/// Blobs are never moved or removed, so a reference stays valid for the lifetime of the segment.
+/// Blobs replaced by an update are left in place until the segment is rewritten.
+#[derive(Debug)]
+pub struct BlobSegment {
+    data: PersistentVec<u8>,
+}
+
+impl BlobSegment {
+    /// Creates a blob segment backed by the given bytes
+    pub fn new(data: PersistentVec<u8>) -> Self {
+        Self { data }
+    }
+
+    /// Creates a blob segment that is only stored in memory
+    #[cfg(test)]
+    pub fn in_memory() -> Self {
+        Self::new(PersistentVec::in_memory())
+    }
+
+    /// Writes any changes of the segment to the file backing it
+    pub fn flush(&self) -> io::Result<()> {
+        self.data.flush()
+    }
+
+    /// Gets the number of bytes used by the segment
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Checks if the segment contains no blobs
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Makes room for at least `additional` more bytes of blobs, so appending them does not grow
+    /// the segment
+    pub fn reserve(&mut self, additional: usize) {
+        let spare = self.data.capacity().saturating_sub(self.data.len());
+        // appending grows the segment once it is full
+        if additional >= spare {
+            self.data.reserve(additional - spare + 1);
+        }
+    }
+
+    /// Appends a blob to the segment, returning a reference to it
+    pub fn append(&mut self, blob: &[u8]) -> BlobRef {
+        let offset = self.data.len() as u64;
+        self.data.extend_from_slice(blob);
+        BlobRef {
+            offset,
+            len: blob.len() as u64,
+        }
+    }
+
+    /// Gets the bytes of a blob, if the reference is within this segment
+    pub fn get(&self, blob: BlobRef) -> Option<&[u8]> {
+        let start = usize::try_from(blob.offset).ok()?;
+        let end = start.checked_add(usize::try_from(blob.len).ok()?)?;
+        self.data.get(start..end)
+    }
+}
+
+impl Blobs for BlobSegment {
+    fn get(&self, blob: BlobRef) -> Option<&[u8]> {
+        BlobSegment::get(self, blob)
+    }
+}
+
+/// Blobs of an encoded row that are not appended to a blob segment yet. The cells referencing
+/// them are left empty until they are.
+#[derive(Debug, Clone, Default)]
+pub struct StagedBlobs {
+    blobs: Vec<(Range<usize>, Arc<[u8]>)>,
+}
+
+impl StagedBlobs {
+    /// Creates an empty set of staged blobs
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stages a blob for the cell at the given range of the row
+    pub fn stage(&mut self, cell: Range<usize>, blob: Arc<[u8]>) {
+        self.blobs.push((cell, blob));
+    }
+
+    /// Gets the number of staged blobs
+    pub fn len(&self) -> usize {
+        self.blobs.len()
+    }
+
+    /// Checks if no blobs are staged
+    pub fn is_empty(&self) -> bool {
+        self.blobs.is_empty()
+    }
+
+    /// Keeps only the blobs whose cells match a predicate
+    pub(crate) fn retain(&mut self, mut keep: impl FnMut(&Range<usize>) -> bool) {
+        self.blobs.retain(|(cell, _)| keep(cell));
+    }
+
+    /// Stages every blob of another set
+    pub(crate) fn extend(&mut self, other: StagedBlobs) {
+        self.blobs.extend(other.blobs);
+    }
+
+    /// Appends the blobs to a segment, writing the references to them into their cells
+    ///
+    /// # Panic
+    /// Panics if the cells are not within `row`
+    pub fn append_to(self, segment: &mut BlobSegment, row: &mut [u8]) {
+        for (cell, blob) in self.blobs {
+            segment.append(&blob).write(&mut row[cell]);
+        }
+    }
+}
+
+/// The blobs of a segment up to some point, which stay readable while more blobs are appended
+/// to the segment.
+///
+/// The blobs are kept in the chunks appended between snapshots, which are shared by every later
+/// snapshot, so taking a snapshot only copies the blobs appended since the last one.
+#[derive(Debug, Clone, Default)]
+pub struct BlobSnapshot {
+    /// Chunks of blobs along with the offset of their first byte, in ascending order
+    chunks: Vec<(u64, Arc<[u8]>)>,
+    len: u64,
+}
+
+impl BlobSnapshot {
+    /// Takes a snapshot of every blob of a segment, starting from this snapshot if it was taken
+    /// of the same segment
+    pub fn update(&self, segment: &BlobSegment) -> Self {
+        let mut snapshot = self.clone();
+        let len = segment.len() as u64;
+        if len > snapshot.len {
+            let appended = segment
+                .data
+                .get(snapshot.len as usize..)
+                .expect("segments only grow");
+            snapshot.chunks.push((snapshot.len, Arc::from(appended)));
+            snapshot.len = len;
+        }
+        snapshot
+    }
+
+    /// Gets the number of bytes of blobs in the snapshot
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    /// Checks if the snapshot contains no blobs
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Blobs for BlobSnapshot {
+    fn get(&self, blob: BlobRef) -> Option<&[u8]> {
+        // a blob is appended at once, so it never spans chunks
+        let chunk = self
+            .chunks
+            .partition_point(|(offset, _)| *offset <= blob.offset);
+        let (offset, bytes) = self.chunks.get(chunk.checked_sub(1)?)?;
+        let start = usize::try_from(blob.offset - offset).ok()?;
+        let end = start.checked_add(usize::try_from(blob.len).ok()?)?;
+        bytes.get(start..end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn append_and_get() {
+        let mut segment = BlobSegment::in_memory();
+        let first = segment.append(b"hello");
+        let second = segment.append(b"world!");
+
+        assert_eq!(segment.get(first), Some(&b"hello"[..]));
+        assert_eq!(segment.get(second), Some(&b"world!"[..]));
+        assert_eq!(segment.len(), 11);
+
+        let mut cell = [0_u8; BLOB_REF_SIZE];
+        second.write(&mut cell);
+        assert_eq!(BlobRef::read(&cell), Some(second));
+        assert_eq!(BlobRef::read(&[0; BLOB_REF_SIZE]), None);
+    }
+
+    #[test]
+    fn snapshots_keep_their_blobs() {
+        let mut segment = BlobSegment::in_memory();
+        let first = segment.append(b"hello");
+        let snapshot = BlobSnapshot::default().update(&segment);
+        let second = segment.append(b"world!");
+        assert_eq!(Blobs::get(&snapshot, first), Some(&b"hello"[..]));
+        assert_eq!(Blobs::get(&snapshot, second), None);
+
+        let updated = snapshot.update(&segment);
+        let third = segment.append(b"!");
+        assert_eq!(updated.len(), 11);
+        assert_eq!(Blobs::get(&updated, first), Some(&b"hello"[..]));
+        assert_eq!(Blobs::get(&updated, second), Some(&b"world!"[..]));
+        assert_eq!(Blobs::get(&updated, third), None);
+    }
+}
@@ -0,0 +1,69 @@
+//! Cooperative cancellation of long running operations.
+//!
+//! Operations that can take a while, such as searches, periodically check a
+//! [`CancelToken`](CancelToken) and stop early once it has been cancelled, or once its deadline
+//! has passed.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use thiserror::Error;
+
+/// How many items an operation may process between checks of its cancel token
+pub const CHECK_INTERVAL: usize = 1024;
+
+/// A flag shared between an operation and whoever may want to cancel it
+#[derive(Debug, Default, Clone)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
+
+impl CancelToken {
+    /// Creates a token that is not cancelled
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Cancels this token once the deadline passes
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+
+    /// Gets when this token is cancelled, if it has a deadline
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Checks if the deadline of this token has passed
+    pub fn is_expired(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// Cancels every operation checking this token
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Checks if this token has been cancelled, or its deadline has passed
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed) || self.is_expired()
+    }
+
+    /// Returns an error if this token has been cancelled
+    pub fn check(&self) -> Result<(), Cancelled> {
+        if self.is_cancelled() {
+            Err(Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// An operation stopped because it was cancelled
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[error("The operation was cancelled")]
+pub struct Cancelled;
@@ -0,0 +1,591 @@
+//! The canonical binary encoding of rows.
+//!
+//! Every row of a schema is [`Schema::row_size`](Schema::row_size) bytes long, made of one cell
+//! per schema field in schema order. The layout of cells is defined by the codec version.
+//!
+//! # Version 1
+//! All multi-byte values are little endian, regardless of the platform.
+//! - `Keyword(n)` and `Text(n)`: up to `n` bytes, padded with trailing zeroes. An all zero cell
+//!   has no data.
+//! - `Number(4)` and `Number(8)`: an `f32` or `f64` respectively.
+//! - `I64`, `U64` and `F64`: an `i64`, `u64` or `f64` respectively.
+//! - `Number(n)` where `n >= BIG_FLOAT_SIZE`: the raw parts of a big float, as 10 `i16` mantissa
+//!   parts, an `i16` mantissa length, an `i8` sign and an `i8` exponent.
+//! - `Blob`: a [`BlobRef`](BlobRef) into the blob segment of the index, as a `u64` offset and a
+//!   `u64` length. An all zero cell has no data.
+
+use std::cmp::Ordering;
+use std::sync::Arc;
+
+use num_bigfloat::BigFloat;
+use thiserror::Error;
+
+use crate::blob::{BlobRef, BlobSegment, Blobs, StagedBlobs};
+use crate::document::Document;
+use crate::fields::{Field, FieldData, FieldKind, Fields, StoredValue};
+use crate::schema::Schema;
+
+/// The current version of the row codec
+pub const ROW_CODEC_VERSION: u16 = 1;
+
+/// The number of bytes needed to store a [`BigFloat`](BigFloat) without losing precision
+pub const BIG_FLOAT_SIZE: usize = 24;
+
+const MANTISSA_PARTS: usize = 10;
+
+/// Encodes and decodes the rows of a schema
+#[derive(Debug, Clone, Copy)]
+pub struct RowCodec<'s> {
+    schema: &'s Schema,
+}
+
+impl<'s> RowCodec<'s> {
+    /// Creates a codec for the current version
+    pub fn new(schema: &'s Schema) -> Self {
+        Self { schema }
+    }
+
+    /// Creates a codec for rows written with a given codec version
+    pub fn for_version(schema: &'s Schema, version: u16) -> Result<Self, RowDecodeError> {
+        if version != ROW_CODEC_VERSION {
+            return Err(RowDecodeError::UnsupportedVersion(version));
+        }
+        Ok(Self::new(schema))
+    }
+
+    /// Gets the version of this codec
+    pub fn version(&self) -> u16 {
+        ROW_CODEC_VERSION
+    }
+
+    /// Gets the schema of the rows
+    pub fn schema(&self) -> &'s Schema {
+        self.schema
+    }
+
+    /// Encodes a document into a row. Fields of the schema missing from the document are left
+    /// untouched, which allows for partial updates of existing rows.
+    ///
+    /// Documents containing blobs can only be encoded with
+    /// [`encode_with_blobs`](Self::encode_with_blobs).
+    ///
+    /// # Panic
+    /// Panics if `row` is not exactly [`row_size`](Schema::row_size) bytes long
+    pub fn encode(&self, document: &Document, row: &mut [u8]) -> Result<(), RowEncodeError> {
+        self.encode_fields(document, row, None)
+    }
+
+    /// Encodes a document into a row, appending any blobs of the document to a blob segment.
+    /// Blobs are only appended once the whole document is encoded.
+    ///
+    /// # Panic
+    /// Panics if `row` is not exactly [`row_size`](Schema::row_size) bytes long
+    pub fn encode_with_blobs(
+        &self,
+        document: &Document,
+        row: &mut [u8],
+        blobs: &mut BlobSegment,
+    ) -> Result<(), RowEncodeError> {
+        let mut staged = StagedBlobs::new();
+        self.encode_fields(document, row, Some(&mut staged))?;
+        staged.append_to(blobs, row);
+        Ok(())
+    }
+
+    /// Encodes a document into a row, staging any blobs of the document rather than appending
+    /// them, so they can be [appended](StagedBlobs::append_to) once the row is accepted.
+    ///
+    /// # Panic
+    /// Panics if `row` is not exactly [`row_size`](Schema::row_size) bytes long
+    pub fn encode_with_staged_blobs(
+        &self,
+        document: &Document,
+        row: &mut [u8],
+        blobs: &mut StagedBlobs,
+    ) -> Result<(), RowEncodeError> {
+        self.encode_fields(document, row, Some(blobs))
+    }
+
+    fn encode_fields(
+        &self,
+        document: &Document,
+        row: &mut [u8],
+        mut blobs: Option<&mut StagedBlobs>,
+    ) -> Result<(), RowEncodeError> {
+        assert_eq!(row.len(), self.schema.row_size(), "row has incorrect size");
+        if let Some((name, _)) = document
+            .fields()
+            .iter()
+            .find(|(name, _)| self.schema.get(name).is_none())
+        {
+            return Err(RowEncodeError::UnknownField(name.to_string()));
+        }
+
+        let mut offset = 0;
+        for schema_field in self.schema {
+            let size = schema_field.kind.size();
+            if let Some(field) = document.get(&schema_field.name) {
+                if field.kind() != &schema_field.kind {
+                    return Err(RowEncodeError::KindMismatch {
+                        field: schema_field.name.clone(),
+                        expected: schema_field.kind.clone(),
+                        found: field.kind().clone(),
+                    });
+                }
+                let cell = &mut row[offset..offset + size];
+                cell.fill(0);
+                match field.data() {
+                    [] => {}
+                    [FieldData::Blob(blob)] if schema_field.kind == FieldKind::Blob => {
+                        let blobs = blobs.as_deref_mut().ok_or_else(|| {
+                            RowEncodeError::NoBlobSegment(schema_field.name.clone())
+                        })?;
+                        blobs.stage(offset..offset + size, blob.clone());
+                    }
+                    [data] => encode_cell(&schema_field.kind, data, cell)
+                        .map_err(|e| e.for_field(&schema_field.name))?,
+                    _ => return Err(RowEncodeError::MultiValued(schema_field.name.clone())),
+                }
+            }
+            offset += size;
+        }
+        Ok(())
+    }
+
+    /// Decodes a row into a document.
+    ///
+    /// Keyword and text fields are read up to their padding, with empty cells producing a field with
+    /// no data. Number fields always produce a number. Rows referencing blobs can only be decoded
+    /// with [`decode_with_blobs`](Self::decode_with_blobs).
+    pub fn decode(&self, row: &[u8]) -> Result<Document, RowDecodeError> {
+        self.decode_fields(row, None)
+    }
+
+    /// Decodes a row into a document, reading any referenced blobs from a blob segment, or a
+    /// snapshot of one
+    pub fn decode_with_blobs(
+        &self,
+        row: &[u8],
+        blobs: &dyn Blobs,
+    ) -> Result<Document, RowDecodeError> {
+        self.decode_fields(row, Some(blobs))
+    }
+
+    fn decode_fields(
+        &self,
+        row: &[u8],
+        blobs: Option<&dyn Blobs>,
+    ) -> Result<Document, RowDecodeError> {
+        if self.schema.row_size() != row.len() {
+            return Err(RowDecodeError::IncorrectSize {
+                expected: self.schema.row_size(),
+                found: row.len(),
+            });
+        }
+
+        let mut fields = Fields::new();
+        let mut offset = 0;
+        for schema_field in self.schema {
+            let size = schema_field.kind.size();
+            let cell = &row[offset..offset + size];
+            let data = match (&schema_field.kind, blobs) {
+                (FieldKind::Blob, Some(blobs)) => decode_blob(cell, blobs)?,
+                (kind, _) => decode_cell(kind, cell)?,
+            };
+            fields.insert(
+                &schema_field.name,
+                Field::new(schema_field.kind.clone(), data),
+            );
+            offset += size;
+        }
+        Ok(Document::from(fields))
+    }
+
+    /// Gets the cell of a field within a row, if the field is part of the schema
+    pub fn cell<'r>(&self, row: &'r [u8], name: impl AsRef<str>) -> Option<&'r [u8]> {
+        self.schema
+            .field_range(name)
+            .and_then(|range| row.get(range))
+    }
+}
+
+/// Encodes a single value into a cell sized for the given field kind
+pub fn encode_cell(
+    kind: &FieldKind,
+    data: &FieldData,
+    cell: &mut [u8],
+) -> Result<(), RowEncodeError> {
+    match (kind, data) {
+        (FieldKind::Keyword(_) | FieldKind::Text(_), FieldData::Bytes(bytes)) => {
+            if bytes.len() > cell.len() {
+                return Err(RowEncodeError::TooLarge {
+                    field: String::new(),
+                    size: cell.len(),
+                    found: bytes.len(),
+                });
+            }
+            cell[..bytes.len()].copy_from_slice(bytes);
+            Ok(())
+        }
+        (FieldKind::Keyword(_) | FieldKind::Text(_), FieldData::Analyzed(analyzed)) => {
+            let bytes = FieldData::Bytes(Arc::from(analyzed.text().as_bytes()));
+            encode_cell(kind, &bytes, cell)
+        }
+        (FieldKind::I64, FieldData::I64(i)) => write_fixed(cell, i.to_le_bytes()),
+        (FieldKind::I64, FieldData::U64(u)) => {
+            write_fixed(cell, in_range::<i64, _>(*u)?.to_le_bytes())
+        }
+        (FieldKind::I64, FieldData::SizeT(size)) => {
+            write_fixed(cell, in_range::<i64, _>(*size)?.to_le_bytes())
+        }
+        (FieldKind::U64, FieldData::U64(u)) => write_fixed(cell, u.to_le_bytes()),
+        (FieldKind::U64, FieldData::I64(i)) => {
+            write_fixed(cell, in_range::<u64, _>(*i)?.to_le_bytes())
+        }
+        (FieldKind::U64, FieldData::SizeT(size)) => {
+            write_fixed(cell, in_range::<u64, _>(*size)?.to_le_bytes())
+        }
+        (FieldKind::F64, FieldData::F64(f)) => write_fixed(cell, f.to_le_bytes()),
+        (FieldKind::F64, FieldData::I64(i)) => write_fixed(cell, (*i as f64).to_le_bytes()),
+        (FieldKind::F64, FieldData::U64(u)) => write_fixed(cell, (*u as f64).to_le_bytes()),
+        (FieldKind::Number(_), data) if data.to_big_float().is_some() => {
+            encode_number(&data.to_big_float().expect("data is a number"), cell)
+        }
+        _ => Err(RowEncodeError::UnsupportedData {
+            field: String::new(),
+            kind: kind.clone(),
+        }),
+    }
+}
+
+/// Decodes the data stored within a single cell. Cells referencing a blob can not be decoded
+/// without the blob segment, see [`decode_blob`](decode_blob).
+pub fn decode_cell(kind: &FieldKind, cell: &[u8]) -> Result<Option<FieldData>, RowDecodeError> {
+    Ok(decode_value(kind, cell, None)?.map(|value| value.to_field_data()))
+}
+
+/// Decodes a blob cell, reading the referenced blob from a blob segment
+pub fn decode_blob(cell: &[u8], blobs: &dyn Blobs) -> Result<Option<FieldData>, RowDecodeError> {
+    Ok(decode_value(&FieldKind::Blob, cell, Some(blobs))?.map(|value| value.to_field_data()))
+}
+
+/// Decodes the value stored within a single cell without copying it, borrowing the bytes of
+/// keyword and text fields from the cell and the bytes of blobs from the given blobs. Cells
+/// referencing a blob can not be decoded without blobs.
+pub fn decode_value<'a>(
+    kind: &FieldKind,
+    cell: &'a [u8],
+    blobs: Option<&'a dyn Blobs>,
+) -> Result<Option<StoredValue<'a>>, RowDecodeError> {
+    match kind {
+        FieldKind::Keyword(_) | FieldKind::Text(_) => {
+            let bytes = unpad(cell);
+            Ok((!bytes.is_empty()).then_some(StoredValue::Bytes(bytes)))
+        }
+        FieldKind::Number(_) => decode_number(cell).map(|number| Some(StoredValue::Number(number))),
+        FieldKind::I64 => Ok(Some(StoredValue::I64(i64::from_le_bytes(fixed(cell)?)))),
+        FieldKind::U64 => Ok(Some(StoredValue::U64(u64::from_le_bytes(fixed(cell)?)))),
+        FieldKind::F64 => Ok(Some(StoredValue::F64(f64::from_le_bytes(fixed(cell)?)))),
+        FieldKind::Blob => {
+            let Some(blob) = BlobRef::read(cell) else {
+                return Ok(None);
+            };
+            let blobs = blobs.ok_or(RowDecodeError::NoBlobSegment)?;
+            blobs
+                .get(blob)
+                .map(|bytes| Some(StoredValue::Blob(bytes)))
+                .ok_or(RowDecodeError::MissingBlob(blob))
+        }
+    }
+}
+
+/// Compares two cells of the same kind without decoding them into field data. Returns `None` if
+/// the values can not be ordered, such as blobs or `NaN`.
+pub fn compare_cells(kind: &FieldKind, a: &[u8], b: &[u8]) -> Option<Ordering> {
+    match kind {
+        FieldKind::Keyword(_) | FieldKind::Text(_) => Some(unpad(a).cmp(unpad(b))),
+        FieldKind::I64 => {
+            Some(i64::from_le_bytes(fixed(a).ok()?).cmp(&i64::from_le_bytes(fixed(b).ok()?)))
+        }
+        FieldKind::U64 => {
+            Some(u64::from_le_bytes(fixed(a).ok()?).cmp(&u64::from_le_bytes(fixed(b).ok()?)))
+        }
+        FieldKind::F64 => {
+            f64::from_le_bytes(fixed(a).ok()?).partial_cmp(&f64::from_le_bytes(fixed(b).ok()?))
+        }
+        FieldKind::Number(_) => decode_number(a).ok()?.partial_cmp(&decode_number(b).ok()?),
+        FieldKind::Blob => None,
+    }
+}
+
+fn write_fixed(cell: &mut [u8], bytes: [u8; 8]) -> Result<(), RowEncodeError> {
+    cell.copy_from_slice(&bytes);
+    Ok(())
+}
+
+fn in_range<T: TryFrom<F>, F>(value: F) -> Result<T, RowEncodeError> {
+    T::try_from(value).map_err(|_| RowEncodeError::OutOfRange(String::new()))
+}
+
+/// Reads a fixed width cell
+fn fixed(cell: &[u8]) -> Result<[u8; 8], RowDecodeError> {
+    cell.try_into().map_err(|_| RowDecodeError::IncorrectSize {
+        expected: 8,
+        found: cell.len(),
+    })
+}
+
+/// Removes the trailing zeroes used to pad keyword and text cells
+pub fn unpad(cell: &[u8]) -> &[u8] {
+    let end = cell.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+    &cell[..end]
+}
+
+fn encode_number(number: &BigFloat, cell: &mut [u8]) -> Result<(), RowEncodeError> {
+    match cell.len() {
+        4 => cell.copy_from_slice(&(number.to_f64() as f32).to_le_bytes()),
+        8 => cell.copy_from_slice(&number.to_f64().to_le_bytes()),
+        size if size >= BIG_FLOAT_SIZE => {
+            let (mantissa, mantissa_len, sign, exponent) = number
+                .to_raw_parts()
+                .ok_or(RowEncodeError::NotFinite(String::new()))?;
+            let (digits, rest) = cell.split_at_mut(MANTISSA_PARTS * 2);
+            for (dest, part) in digits.chunks_exact_mut(2).zip(mantissa) {
+                dest.copy_from_slice(&part.to_le_bytes());
+            }
+            rest[..2].copy_from_slice(&mantissa_len.to_le_bytes());
+            rest[2] = sign as u8;
+            rest[3] = exponent as u8;
+        }
+        size => return Err(RowEncodeError::UnsupportedNumberSize(size)),
+    }
+    Ok(())
+}
+
+fn decode_number(cell: &[u8]) -> Result<BigFloat, RowDecodeError> {
+    match cell.len() {
+        4 => Ok(BigFloat::from_f32(f32::from_le_bytes(
+            cell.try_into().expect("cell is 4 bytes"),
+        ))),
+        8 => Ok(BigFloat::from_f64(f64::from_le_bytes(
+            cell.try_into().expect("cell is 8 bytes"),
+        ))),
+        size if size >= BIG_FLOAT_SIZE => {
+            let mut mantissa = [0_i16; MANTISSA_PARTS];
+            for (part, bytes) in mantissa.iter_mut().zip(cell.chunks_exact(2)) {
+                *part = i16::from_le_bytes([bytes[0], bytes[1]]);
+            }
+            let rest = &cell[MANTISSA_PARTS * 2..];
+            let mantissa_len = i16::from_le_bytes([rest[0], rest[1]]);
+            Ok(BigFloat::from_raw_parts(
+                mantissa,
+                mantissa_len,
+                rest[2] as i8,
+                rest[3] as i8,
+            ))
+        }
+        size => Err(RowDecodeError::UnsupportedNumberSize(size)),
+    }
+}
+
+/// An error occurred encoding a document into a row
+#[derive(Debug, Error)]
+pub enum RowEncodeError {
+    #[error("Field {0:?} is not part of the schema")]
+    UnknownField(String),
+    #[error("Field {field:?} has kind {found:?}, but the schema expects {expected:?}")]
+    KindMismatch {
+        field: String,
+        expected: FieldKind,
+        found: FieldKind,
+    },
+    #[error("Field {0:?} has multiple values, but rows can only store one value per field")]
+    MultiValued(String),
+    #[error("Field {field:?} can only store {size} bytes (found: {found})")]
+    TooLarge {
+        field: String,
+        size: usize,
+        found: usize,
+    },
+    #[error("Field {field:?} of kind {kind:?} can not store the given data")]
+    UnsupportedData { field: String, kind: FieldKind },
+    #[error("Field {0:?} is not a finite number")]
+    NotFinite(String),
+    #[error("Numbers can not be stored in {0} bytes")]
+    UnsupportedNumberSize(usize),
+    #[error("Field {0:?} contains a blob, but no blob segment was given")]
+    NoBlobSegment(String),
+    #[error("Field {0:?} can not represent the given number")]
+    OutOfRange(String),
+}
+
+impl RowEncodeError {
+    /// Attaches the name of the field being encoded to the error
+    fn for_field(self, name: &str) -> Self {
+        match self {
+            RowEncodeError::TooLarge { size, found, .. } => RowEncodeError::TooLarge {
+                field: name.to_string(),
+                size,
+                found,
+            },
+            RowEncodeError::UnsupportedData { kind, .. } => RowEncodeError::UnsupportedData {
+                field: name.to_string(),
+                kind,
+            },
+            RowEncodeError::NotFinite(_) => RowEncodeError::NotFinite(name.to_string()),
+            RowEncodeError::OutOfRange(_) => RowEncodeError::OutOfRange(name.to_string()),
+            other => other,
+        }
+    }
+}
+
+/// An error occurred decoding a row into a document
+#[derive(Debug, Error)]
+pub enum RowDecodeError {
+    #[error("Incorrect row size (expected: {expected}, found: {found})")]
+    IncorrectSize { expected: usize, found: usize },
+    #[error("Numbers can not be stored in {0} bytes")]
+    UnsupportedNumberSize(usize),
+    #[error("Unsupported row codec version {0} (supported: {ROW_CODEC_VERSION})")]
+    UnsupportedVersion(u16),
+    #[error("Row references a blob, but no blob segment was given")]
+    NoBlobSegment,
+    #[error("Blob {0:?} is not within the blob segment")]
+    MissingBlob(BlobRef),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob::BLOB_REF_SIZE;
+    use crate::schema::SchemaField;
+
+    fn schema() -> Schema {
+        Schema::from_iter([
+            SchemaField {
+                name: "name".to_string(),
+                kind: FieldKind::Keyword(4),
+            },
+            SchemaField {
+                name: "value".to_string(),
+                kind: FieldKind::Number(8),
+            },
+        ])
+    }
+
+    #[test]
+    fn layout_is_little_endian() {
+        let schema = schema();
+        let document = Document::from(Fields::from_iter([
+            (
+                "name",
+                Field::new(
+                    FieldKind::Keyword(4),
+                    [FieldData::Bytes(Arc::from(&b"ab"[..]))],
+                ),
+            ),
+            (
+                "value",
+                Field::new(
+                    FieldKind::Number(8),
+                    [FieldData::Number(BigFloat::from_f64(1.5))],
+                ),
+            ),
+        ]));
+
+        let mut row = [0_u8; 12];
+        RowCodec::new(&schema).encode(&document, &mut row).unwrap();
+        assert_eq!(&row[..4], b"ab\0\0");
+        assert_eq!(&row[4..], &[0, 0, 0, 0, 0, 0, 0xf8, 0x3f]);
+        assert_eq!(RowCodec::new(&schema).cell(&row, "value"), Some(&row[4..]));
+    }
+
+    #[test]
+    fn blobs_are_stored_outside_the_row() {
+        let schema = Schema::from_iter([SchemaField {
+            name: "attachment".to_string(),
+            kind: FieldKind::Blob,
+        }]);
+        let document = Document::from(Fields::from_iter([(
+            "attachment",
+            Field::new(
+                FieldKind::Blob,
+                [FieldData::Blob(Arc::from(&b"a large attachment"[..]))],
+            ),
+        )]));
+        let codec = RowCodec::new(&schema);
+
+        let mut row = [0_u8; BLOB_REF_SIZE];
+        assert!(matches!(
+            codec.encode(&document, &mut row),
+            Err(RowEncodeError::NoBlobSegment(_))
+        ));
+
+        let mut blobs = BlobSegment::in_memory();
+        codec
+            .encode_with_blobs(&document, &mut row, &mut blobs)
+            .unwrap();
+        assert_eq!(blobs.len(), 18);
+        assert!(matches!(
+            codec.decode(&row),
+            Err(RowDecodeError::NoBlobSegment)
+        ));
+
+        let decoded = codec.decode_with_blobs(&row, &blobs).unwrap();
+        assert_eq!(
+            decoded.get("attachment").unwrap().data(),
+            document.get("attachment").unwrap().data()
+        );
+    }
+
+    #[test]
+    fn fixed_width_numbers() {
+        let mut cell = [0_u8; 8];
+        encode_cell(&FieldKind::I64, &FieldData::I64(-5), &mut cell).unwrap();
+        assert_eq!(
+            decode_cell(&FieldKind::I64, &cell).unwrap(),
+            Some(FieldData::I64(-5))
+        );
+
+        let mut other = [0_u8; 8];
+        encode_cell(&FieldKind::I64, &FieldData::U64(3), &mut other).unwrap();
+        assert_eq!(
+            compare_cells(&FieldKind::I64, &cell, &other),
+            Some(Ordering::Less)
+        );
+
+        assert!(matches!(
+            encode_cell(&FieldKind::U64, &FieldData::I64(-1), &mut cell),
+            Err(RowEncodeError::OutOfRange(_))
+        ));
+        encode_cell(&FieldKind::U64, &FieldData::U64(u64::MAX), &mut cell).unwrap();
+        assert_eq!(
+            decode_cell(&FieldKind::U64, &cell).unwrap(),
+            Some(FieldData::U64(u64::MAX))
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_versions() {
+        let schema = schema();
+        assert!(RowCodec::for_version(&schema, ROW_CODEC_VERSION).is_ok());
+        assert!(matches!(
+            RowCodec::for_version(&schema, ROW_CODEC_VERSION + 1),
+            Err(RowDecodeError::UnsupportedVersion(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        let schema = schema();
+        let document = Document::from(Fields::from_iter([(
+            "other",
+            Field::new(FieldKind::Keyword(4), []),
+        )]));
+        let mut row = [0_u8; 12];
+        assert!(matches!(
+            RowCodec::new(&schema).encode(&document, &mut row),
+            Err(RowEncodeError::UnknownField(_))
+        ));
+    }
+}
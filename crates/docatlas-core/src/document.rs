@@ -1,17 +1,41 @@
 //! A document is a non-normalized tuple of data that allows for nested types
 
-use serde::Serialize;
-use thiserror::Error;
+use serde::{Deserialize, Serialize};
 
-use crate::fields::Fields;
+use crate::blob::Blobs;
+use crate::codec::{self, RowDecodeError};
+use crate::fields::{Field, Fields, StoredValue};
 use crate::schema::Schema;
 
 /// A document is made of fields
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct Document {
     fields: Fields,
 }
 
+impl Document {
+    /// Gets the fields of this document
+    pub fn fields(&self) -> &Fields {
+        &self.fields
+    }
+
+    /// Gets a field of this document by name, if present
+    pub fn get(&self, name: impl AsRef<str>) -> Option<&Field> {
+        self.fields.get(name)
+    }
+
+    /// Gets a mutable reference to the fields of this document
+    pub fn fields_mut(&mut self) -> &mut Fields {
+        &mut self.fields
+    }
+
+    /// Unwraps the fields of this document
+    pub fn into_fields(self) -> Fields {
+        self.fields
+    }
+}
+
 impl From<Fields> for Document {
     fn from(value: Fields) -> Self {
         Self { fields: value }
@@ -24,23 +48,209 @@ pub struct DocumentData<'a> {
     data: &'a [u8],
 }
 
-impl DocumentData<'_> {
-    /// Tries to convert raw data into a human friendly document
-    pub fn try_into_document(&self, schema: &Schema) -> Result<Document, DocumentDataError> {
-        if schema.row_size() != self.data.len() {
-            return Err(DocumentDataError::IncorrectSize {
-                expected: schema.row_size(),
-                found: self.data.len(),
-            });
-        }
+impl<'a> DocumentData<'a> {
+    /// Wraps the raw data of a row
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
 
-        todo!()
+    /// Gets the raw data
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.data
     }
+
+    /// Tries to convert raw data into a human friendly document, using the
+    /// [row codec](crate::codec::RowCodec) of the schema.
+    pub fn try_into_document(&self, schema: &Schema) -> Result<Document, RowDecodeError> {
+        schema.codec().decode(self.data)
+    }
+}
+
+impl<'a> From<&'a [u8]> for DocumentData<'a> {
+    fn from(value: &'a [u8]) -> Self {
+        Self::new(value)
+    }
+}
+
+/// The fields of a document as they are stored in its row. Values are decoded as they are read,
+/// borrowing their bytes from the row and its blobs, so reading a few fields of many documents
+/// copies nothing.
+#[derive(Debug, Clone, Copy)]
+pub struct StoredDocument<'a> {
+    schema: &'a Schema,
+    row: &'a [u8],
+    blobs: Option<&'a dyn Blobs>,
 }
 
-/// An error trying to read document data into a document
-#[derive(Debug, Error)]
-pub enum DocumentDataError {
-    #[error("Incorrect row size (expected: {expected}, found: {found})")]
-    IncorrectSize { expected: usize, found: usize },
+impl<'a> StoredDocument<'a> {
+    /// Wraps a row of the schema, whose blobs are read from the given blobs
+    pub fn new(schema: &'a Schema, row: &'a [u8], blobs: Option<&'a dyn Blobs>) -> Self {
+        Self { schema, row, blobs }
+    }
+
+    /// Gets the raw bytes of the row
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.row
+    }
+
+    /// Gets the value of a field, or `None` if the field is empty or not part of the schema
+    pub fn get(&self, name: &str) -> Result<Option<StoredValue<'a>>, RowDecodeError> {
+        let Some(field) = self.schema.get(name) else {
+            return Ok(None);
+        };
+        let range = self
+            .schema
+            .field_range(name)
+            .expect("fields of the schema have a range");
+        let cell = self.row.get(range).ok_or(RowDecodeError::IncorrectSize {
+            expected: self.schema.row_size(),
+            found: self.row.len(),
+        })?;
+        codec::decode_value(&field.kind, cell, self.blobs)
+    }
+
+    /// Gets the value of every field, in the order of the schema
+    pub fn values(
+        &self,
+    ) -> impl Iterator<Item = Result<(&'a str, Option<StoredValue<'a>>), RowDecodeError>> + 'a {
+        let document = *self;
+        self.schema.iter().map(move |field| {
+            let value = document.get(&field.name)?;
+            Ok((field.name.as_str(), value))
+        })
+    }
+
+    /// Copies every field into a document
+    pub fn to_document(&self) -> Result<Document, RowDecodeError> {
+        let codec = self.schema.codec();
+        match self.blobs {
+            Some(blobs) => codec.decode_with_blobs(self.row, blobs),
+            None => codec.decode(self.row),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use num_bigfloat::BigFloat;
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::codec::BIG_FLOAT_SIZE;
+    use crate::fields::{FieldData, FieldKind};
+    use crate::schema::SchemaField;
+
+    fn schema() -> Schema {
+        Schema::from_iter([
+            SchemaField {
+                name: "keyword".to_string(),
+                kind: FieldKind::Keyword(8),
+            },
+            SchemaField {
+                name: "text".to_string(),
+                kind: FieldKind::Text(32),
+            },
+            SchemaField {
+                name: "double".to_string(),
+                kind: FieldKind::Number(8),
+            },
+            SchemaField {
+                name: "big".to_string(),
+                kind: FieldKind::Number(BIG_FLOAT_SIZE),
+            },
+        ])
+    }
+
+    fn bytes(max: usize) -> impl Strategy<Value = Vec<u8>> {
+        prop::collection::vec(1_u8..=u8::MAX, 0..=max)
+    }
+
+    fn field(kind: FieldKind, data: Option<FieldData>) -> Field {
+        Field::new(kind, data)
+    }
+
+    #[test]
+    fn rejects_incorrect_size() {
+        let error = DocumentData::new(&[0; 4])
+            .try_into_document(&schema())
+            .unwrap_err();
+        assert!(matches!(
+            error,
+            RowDecodeError::IncorrectSize {
+                expected: 72,
+                found: 4
+            }
+        ));
+    }
+
+    #[test]
+    fn read_stored_fields() {
+        let schema = schema();
+        let document = Document::from(Fields::from_iter([
+            (
+                "keyword",
+                field(
+                    FieldKind::Keyword(8),
+                    Some(FieldData::Bytes(Arc::from(&b"dune"[..]))),
+                ),
+            ),
+            ("text", field(FieldKind::Text(32), None)),
+            (
+                "double",
+                field(
+                    FieldKind::Number(8),
+                    Some(FieldData::Number(BigFloat::from_f64(1.5))),
+                ),
+            ),
+            ("big", field(FieldKind::Number(BIG_FLOAT_SIZE), None)),
+        ]));
+        let mut row = vec![0_u8; schema.row_size()];
+        schema.codec().encode(&document, &mut row).unwrap();
+
+        let stored = StoredDocument::new(&schema, &row, None);
+        let keyword = stored.get("keyword").unwrap().unwrap();
+        // the bytes are read from the row itself
+        assert!(row
+            .as_ptr_range()
+            .contains(&keyword.as_bytes().unwrap().as_ptr()));
+        assert_eq!(stored.get("text").unwrap(), None);
+        assert_eq!(stored.get("missing").unwrap(), None);
+        assert_eq!(stored.values().count(), 4);
+        assert_eq!(
+            stored.to_document().unwrap().get("keyword").unwrap().data(),
+            document.get("keyword").unwrap().data()
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip(
+            keyword in bytes(8),
+            text in bytes(32),
+            double in any::<f64>().prop_filter("finite", |f| f.is_finite()),
+            big in any::<i32>(),
+        ) {
+            let schema = schema();
+            let bytes_data = |bytes: &Vec<u8>| (!bytes.is_empty()).then(|| FieldData::Bytes(Arc::from(bytes.as_slice())));
+            let document = Document::from(Fields::from_iter([
+                ("keyword", field(FieldKind::Keyword(8), bytes_data(&keyword))),
+                ("text", field(FieldKind::Text(32), bytes_data(&text))),
+                ("double", field(FieldKind::Number(8), Some(FieldData::Number(BigFloat::from_f64(double))))),
+                ("big", field(FieldKind::Number(BIG_FLOAT_SIZE), Some(FieldData::Number(BigFloat::from_i64(big as i64))))),
+            ]));
+
+            let mut row = vec![0_u8; schema.row_size()];
+            schema.codec().encode(&document, &mut row).unwrap();
+            let decoded = DocumentData::new(&row).try_into_document(&schema).unwrap();
+
+            prop_assert_eq!(decoded.fields().len(), document.fields().len());
+            for (name, field) in document.fields().iter() {
+                let decoded_field = decoded.get(name).unwrap();
+                prop_assert_eq!(decoded_field.kind(), field.kind());
+                prop_assert_eq!(decoded_field.data(), field.data());
+            }
+        }
+    }
 }
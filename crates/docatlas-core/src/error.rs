@@ -0,0 +1,257 @@
+//! The errors sent to clients. Every error has a stable numeric code, which tells clients what
+//! went wrong without parsing its message, and which is all that is sent along with the message.
+//! Codes below 2000 are the fault of the client, and the others the fault of the server.
+//!
+//! Codes are never reused or changed once released. Clients given a code newer than they know of
+//! get an [`DocatlasError::Unknown`] error, still in the right category.
+//!
+//! Errors inside of docatlas keep what caused them with [`ResultExt::context`], rather than being
+//! converted into other errors that discard their cause.
+
+use std::io;
+
+use crate::auth::authentication::AuthenticationError;
+use crate::auth::authorization::AuthorizationError;
+use crate::auth::users::GroupError;
+use crate::cancel::Cancelled;
+use crate::codec::RowDecodeError;
+use crate::index::IndexWriterError;
+use crate::query::QueryError;
+use serde::{Deserialize, Serialize};
+
+mod context;
+
+pub use context::{Error, ResultExt};
+
+/// The first code of errors that are the fault of the server
+const FIRST_SERVER_CODE: u16 = 2000;
+
+/// Whose fault an error is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCategory {
+    /// The request was invalid, and should not be retried as is
+    Client,
+    /// The request could not be handled, and may be retried later on
+    Server,
+}
+
+/// An error sent to clients
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, thiserror::Error)]
+#[serde(into = "RawError", from = "RawError")]
+pub enum DocatlasError {
+    #[error("{0}")]
+    InvalidRequest(String),
+    #[error("{0}")]
+    NotFound(String),
+    #[error("{0}")]
+    AlreadyExists(String),
+    /// The request conflicts with the state of the daemon, such as features it does not enable
+    #[error("{0}")]
+    Conflict(String),
+    #[error("{0}")]
+    Unauthenticated(String),
+    #[error("{0}")]
+    PermissionDenied(String),
+    #[error("{0}")]
+    Cancelled(String),
+    #[error("{0}")]
+    Internal(String),
+    /// An error with a code this version of docatlas does not know of
+    #[error("{message}")]
+    Unknown { code: u16, message: String },
+}
+
+impl DocatlasError {
+    /// Gets the stable code of the error
+    pub fn code(&self) -> u16 {
+        match self {
+            DocatlasError::InvalidRequest(_) => 1000,
+            DocatlasError::NotFound(_) => 1001,
+            DocatlasError::AlreadyExists(_) => 1002,
+            DocatlasError::Conflict(_) => 1003,
+            DocatlasError::Unauthenticated(_) => 1004,
+            DocatlasError::PermissionDenied(_) => 1005,
+            DocatlasError::Cancelled(_) => 1006,
+            DocatlasError::Internal(_) => 2000,
+            DocatlasError::Unknown { code, .. } => *code,
+        }
+    }
+
+    /// Gets whose fault the error is
+    pub fn category(&self) -> ErrorCategory {
+        if self.code() < FIRST_SERVER_CODE {
+            ErrorCategory::Client
+        } else {
+            ErrorCategory::Server
+        }
+    }
+
+    /// Gets the message of the error
+    pub fn message(&self) -> &str {
+        match self {
+            DocatlasError::InvalidRequest(message)
+            | DocatlasError::NotFound(message)
+            | DocatlasError::AlreadyExists(message)
+            | DocatlasError::Conflict(message)
+            | DocatlasError::Unauthenticated(message)
+            | DocatlasError::PermissionDenied(message)
+            | DocatlasError::Cancelled(message)
+            | DocatlasError::Internal(message)
+            | DocatlasError::Unknown { message, .. } => message,
+        }
+    }
+}
+
+/// How errors are sent to clients
+#[derive(Serialize, Deserialize)]
+struct RawError {
+    code: u16,
+    message: String,
+}
+
+impl From<DocatlasError> for RawError {
+    fn from(value: DocatlasError) -> Self {
+        RawError {
+            code: value.code(),
+            message: value.message().to_string(),
+        }
+    }
+}
+
+impl From<RawError> for DocatlasError {
+    fn from(RawError { code, message }: RawError) -> Self {
+        match code {
+            1000 => DocatlasError::InvalidRequest(message),
+            1001 => DocatlasError::NotFound(message),
+            1002 => DocatlasError::AlreadyExists(message),
+            1003 => DocatlasError::Conflict(message),
+            1004 => DocatlasError::Unauthenticated(message),
+            1005 => DocatlasError::PermissionDenied(message),
+            1006 => DocatlasError::Cancelled(message),
+            2000 => DocatlasError::Internal(message),
+            code => DocatlasError::Unknown { code, message },
+        }
+    }
+}
+
+impl From<io::Error> for DocatlasError {
+    fn from(value: io::Error) -> Self {
+        DocatlasError::Internal(value.to_string())
+    }
+}
+
+impl From<Cancelled> for DocatlasError {
+    fn from(value: Cancelled) -> Self {
+        DocatlasError::Cancelled(value.to_string())
+    }
+}
+
+impl From<AuthorizationError> for DocatlasError {
+    fn from(value: AuthorizationError) -> Self {
+        DocatlasError::PermissionDenied(value.to_string())
+    }
+}
+
+impl From<AuthenticationError> for DocatlasError {
+    fn from(value: AuthenticationError) -> Self {
+        let message = value.to_string();
+        match value {
+            AuthenticationError::UnknownIdentifier => DocatlasError::NotFound(message),
+            AuthenticationError::UserExists => DocatlasError::AlreadyExists(message),
+            AuthenticationError::StoreError(_) => DocatlasError::Internal(message),
+            _ => DocatlasError::InvalidRequest(message),
+        }
+    }
+}
+
+impl From<GroupError> for DocatlasError {
+    fn from(value: GroupError) -> Self {
+        let message = value.to_string();
+        match value {
+            GroupError::NoSuchGroup(_) => DocatlasError::NotFound(message),
+            GroupError::GroupExists(_) => DocatlasError::AlreadyExists(message),
+            GroupError::StoreError(_) => DocatlasError::Internal(message),
+        }
+    }
+}
+
+impl From<IndexWriterError> for DocatlasError {
+    fn from(value: IndexWriterError) -> Self {
+        let message = value.to_string();
+        match value {
+            IndexWriterError::DuplicatePrimaryKey(_) | IndexWriterError::Duplicate(_) => {
+                DocatlasError::AlreadyExists(message)
+            }
+            // a failed transaction fails the way its failed write did
+            IndexWriterError::Transaction { source, .. } => match DocatlasError::from(*source) {
+                DocatlasError::AlreadyExists(_) => DocatlasError::AlreadyExists(message),
+                _ => DocatlasError::InvalidRequest(message),
+            },
+            IndexWriterError::Stale => DocatlasError::Conflict(message),
+            _ => DocatlasError::InvalidRequest(message),
+        }
+    }
+}
+
+/// Rows are only ever decoded from what the daemon stored, so rows failing to decode is never the
+/// fault of the client
+impl From<RowDecodeError> for DocatlasError {
+    fn from(value: RowDecodeError) -> Self {
+        DocatlasError::Internal(value.to_string())
+    }
+}
+
+impl From<QueryError> for DocatlasError {
+    fn from(value: QueryError) -> Self {
+        match value {
+            QueryError::InvalidValue(e) => DocatlasError::InvalidRequest(e.to_string()),
+            QueryError::InvalidPattern(e) => DocatlasError::InvalidRequest(e.to_string()),
+            QueryError::Cancelled(e) => e.into(),
+            QueryError::Decode(e) => e.into(),
+        }
+    }
+}
+
+/// Clients are told every cause of the error, since they can not see the logs of the daemon
+impl From<Error> for DocatlasError {
+    fn from(value: Error) -> Self {
+        DocatlasError::Internal(format!("{value:#}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn send_codes() {
+        let error = DocatlasError::from(GroupError::NoSuchGroup("ops".to_string()));
+        assert_eq!(error.code(), 1001);
+        assert_eq!(error.category(), ErrorCategory::Client);
+        let json = serde_json::to_value(&error).unwrap();
+        assert_eq!(json["code"], 1001);
+        assert_eq!(json["message"], "No group named \"ops\"");
+        let bytes = postcard::to_allocvec(&error).unwrap();
+        assert_eq!(
+            postcard::from_bytes::<DocatlasError>(&bytes).unwrap(),
+            error
+        );
+
+        let internal = DocatlasError::from(io::Error::other("disk full"));
+        assert_eq!(internal.category(), ErrorCategory::Server);
+        assert_eq!(internal.to_string(), "disk full");
+
+        // errors added by later versions keep their code and category
+        let newer: DocatlasError =
+            serde_json::from_str(r#"{"code": 2042, "message": "node is draining"}"#).unwrap();
+        assert_eq!(
+            newer,
+            DocatlasError::Unknown {
+                code: 2042,
+                message: "node is draining".to_string()
+            }
+        );
+        assert_eq!(newer.category(), ErrorCategory::Server);
+        assert_eq!(serde_json::to_value(&newer).unwrap()["code"], 2042);
+    }
+}
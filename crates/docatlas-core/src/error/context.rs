@@ -0,0 +1,166 @@
+//! Errors describing what was being done when they occurred, such as which file was being read,
+//! while keeping the error that caused them as their source.
+
+use std::backtrace::{Backtrace, BacktraceStatus};
+use std::error::Error as StdError;
+use std::fmt::{Debug, Display, Formatter};
+use std::io;
+
+/// An error with a description of what was being done when it occurred. Its cause is kept as its
+/// [source](StdError::source), and a backtrace is captured where the first context was given if
+/// `RUST_BACKTRACE` or `RUST_LIB_BACKTRACE` are set.
+///
+/// Only the context is displayed, unless displayed with `{:#}`, which also displays every cause.
+pub struct Error {
+    context: String,
+    source: Box<dyn StdError + Send + Sync>,
+    backtrace: Backtrace,
+}
+
+impl Error {
+    /// Creates an error caused by `source`, which occurred while doing what `context` describes
+    pub fn new<E>(context: impl Display, source: E) -> Self
+    where
+        E: StdError + Send + Sync + 'static,
+    {
+        let source: Box<dyn StdError + Send + Sync> = Box::new(source);
+        // errors given more context keep the backtrace of where they first got some
+        let backtrace = if source.is::<Error>() {
+            Backtrace::disabled()
+        } else {
+            Backtrace::capture()
+        };
+        Self {
+            context: context.to_string(),
+            source,
+            backtrace,
+        }
+    }
+
+    /// Gets what was being done when the error occurred
+    pub fn context(&self) -> &str {
+        &self.context
+    }
+
+    /// Gets the backtrace captured where the first context was given
+    pub fn backtrace(&self) -> &Backtrace {
+        match self.source.downcast_ref::<Error>() {
+            Some(inner) => inner.backtrace(),
+            None => &self.backtrace,
+        }
+    }
+
+    /// Gets this error, followed by every error that caused it
+    pub fn chain(&self) -> impl Iterator<Item = &(dyn StdError + 'static)> {
+        std::iter::successors(Some(self as &(dyn StdError + 'static)), |&error| {
+            error.source()
+        })
+    }
+
+    /// Gets the error that ultimately caused this one
+    pub fn root_cause(&self) -> &(dyn StdError + 'static) {
+        self.chain()
+            .last()
+            .expect("the chain holds at least this error")
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.context)?;
+        if f.alternate() {
+            for cause in self.chain().skip(1) {
+                write!(f, ": {cause}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Debug for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.context)?;
+        for (i, cause) in self.chain().skip(1).enumerate() {
+            if i == 0 {
+                write!(f, "\n\nCaused by:")?;
+            }
+            write!(f, "\n    {i}: {cause}")?;
+        }
+        if self.backtrace().status() == BacktraceStatus::Captured {
+            write!(f, "\n\nBacktrace:\n{}", self.backtrace())?;
+        }
+        Ok(())
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        Some(&*self.source)
+    }
+}
+
+/// Keeps the kind of the io error that caused the error, if any, so callers can still tell what
+/// went wrong
+impl From<Error> for io::Error {
+    fn from(value: Error) -> Self {
+        let kind = value
+            .chain()
+            .find_map(|error| error.downcast_ref::<io::Error>())
+            .map_or(io::ErrorKind::Other, io::Error::kind);
+        io::Error::new(kind, value)
+    }
+}
+
+/// Adds context to the errors of results
+pub trait ResultExt<T> {
+    /// Describes what was being done if the result is an error
+    fn context(self, context: impl Display) -> Result<T, Error>;
+
+    /// Describes what was being done if the result is an error. The description is only made if
+    /// it is.
+    fn with_context<C, F>(self, context: F) -> Result<T, Error>
+    where
+        C: Display,
+        F: FnOnce() -> C;
+}
+
+impl<T, E> ResultExt<T> for Result<T, E>
+where
+    E: StdError + Send + Sync + 'static,
+{
+    fn context(self, context: impl Display) -> Result<T, Error> {
+        self.map_err(|error| Error::new(context, error))
+    }
+
+    fn with_context<C, F>(self, context: F) -> Result<T, Error>
+    where
+        C: Display,
+        F: FnOnce() -> C,
+    {
+        self.map_err(|error| Error::new(context(), error))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keep_causes() {
+        let missing = std::fs::read("/does/not/exist")
+            .context("could not read the mapping")
+            .with_context(|| format!("could not load index {:?}", "books"))
+            .unwrap_err();
+        assert_eq!(missing.to_string(), "could not load index \"books\"");
+        assert!(format!("{missing:#}")
+            .starts_with("could not load index \"books\": could not read the mapping: "));
+        assert_eq!(missing.chain().count(), 3);
+        let root = missing.root_cause().downcast_ref::<io::Error>().unwrap();
+        assert_eq!(root.kind(), io::ErrorKind::NotFound);
+
+        let error = io::Error::from(missing);
+        assert_eq!(error.kind(), io::ErrorKind::NotFound);
+        assert_eq!(error.to_string(), "could not load index \"books\"");
+        assert!(error.get_ref().unwrap().is::<Error>());
+    }
+}
@@ -0,0 +1,101 @@
+//! Exports documents into files read by other programs, such as [json lines](json_lines) and
+//! [csv] files, or parquet files if the `parquet` feature is enabled.
+//!
+//! Every writer is a [`DocumentWriter`], so [`export_index`] can stream the documents of an
+//! index, or those matched by a search, into any of them a page at a time.
+
+use std::io;
+
+use base64::Engine;
+use thiserror::Error;
+
+use crate::codec::RowDecodeError;
+use crate::document::Document;
+use crate::fields::FieldData;
+use crate::index::IndexWriter;
+
+pub mod csv;
+pub mod json_lines;
+#[cfg(feature = "parquet")]
+pub mod parquet;
+
+/// The number of rows read from an index at a time while exporting it
+pub const PAGE_SIZE: usize = 1024;
+
+/// A document could not be exported
+#[derive(Debug, Error)]
+pub enum ExportError {
+    #[error("field {field:?} holds {values} values, but only one can be exported")]
+    MultipleValues { field: String, values: usize },
+    #[error("field {field:?} can not be exported as {kind}")]
+    InvalidValue { field: String, kind: String },
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+    #[cfg(feature = "parquet")]
+    #[error("could not write parquet: {0}")]
+    Parquet(#[from] ::parquet::errors::ParquetError),
+    #[error("could not write json: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error(transparent)]
+    Decode(#[from] RowDecodeError),
+}
+
+/// Writes documents into a file
+pub trait DocumentWriter {
+    /// Writes a document
+    fn write(&mut self, document: &Document) -> Result<(), ExportError>;
+
+    /// Writes anything not written yet, returning how many documents were written. Nothing can
+    /// be written afterwards.
+    fn finish(&mut self) -> Result<usize, ExportError>;
+}
+
+/// Writes the documents of an index in the order of their rows, or only those matched by a
+/// search given as its field and query, then finishes the writer. Rows are read [`PAGE_SIZE`]
+/// at a time, so the documents never have to be held in memory all at once.
+pub fn export_index<W: DocumentWriter + ?Sized>(
+    index: &IndexWriter,
+    search: Option<(&str, &str)>,
+    writer: &mut W,
+) -> Result<usize, ExportError> {
+    let mut from = Some(0);
+    while let Some(row) = from {
+        let (rows, next) = index.scan(search, row, PAGE_SIZE);
+        for row in rows {
+            let document = index.read(row).expect("scanned rows exist")?;
+            writer.write(&document)?;
+        }
+        from = next;
+    }
+    writer.finish()
+}
+
+/// Converts a value into json. Blobs are written in base64 and text that is not valid utf-8
+/// has its invalid bytes replaced.
+pub fn json_value(data: &FieldData) -> serde_json::Value {
+    use serde_json::Value;
+    match data {
+        FieldData::SizeT(n) => Value::from(*n),
+        FieldData::I64(i) => Value::from(*i),
+        FieldData::U64(u) => Value::from(*u),
+        FieldData::F64(f) => Value::from(*f),
+        FieldData::Number(number) => Value::from(number.to_f64()),
+        FieldData::Bytes(_) | FieldData::Blob(_) | FieldData::Analyzed(_) => {
+            Value::from(text_value(data))
+        }
+    }
+}
+
+/// Converts a value into text, the same way [`json_value`] would write it
+pub fn text_value(data: &FieldData) -> String {
+    match data {
+        FieldData::SizeT(n) => n.to_string(),
+        FieldData::I64(i) => i.to_string(),
+        FieldData::U64(u) => u.to_string(),
+        FieldData::F64(f) => f.to_string(),
+        FieldData::Number(number) => number.to_f64().to_string(),
+        FieldData::Bytes(bytes) => String::from_utf8_lossy(bytes).into_owned(),
+        FieldData::Blob(bytes) => base64::engine::general_purpose::STANDARD.encode(bytes),
+        FieldData::Analyzed(text) => text.text().to_string(),
+    }
+}
@@ -0,0 +1,181 @@
+//! Exports documents into csv files, or tsv files with a tab as their delimiter. Every column
+//! is a field, named by the header row, and cells are quoted when they hold the delimiter, a
+//! quote or a line break. Fields that are not set are written as empty cells, and fields
+//! holding more than one value can not be written.
+
+use std::io::Write;
+
+use crate::document::Document;
+use crate::export::{text_value, DocumentWriter, ExportError};
+
+/// Writes documents into a csv file
+#[derive(Debug)]
+pub struct CsvWriter<W: Write> {
+    writer: W,
+    columns: Vec<String>,
+    delimiter: char,
+    headers: bool,
+    /// Whether anything was written into the file yet
+    started: bool,
+    written: usize,
+}
+
+impl<W: Write> CsvWriter<W> {
+    /// Writes the given fields as the columns of a comma separated file with a header row
+    pub fn new<I: IntoIterator<Item = S>, S: Into<String>>(writer: W, columns: I) -> Self {
+        Self {
+            writer,
+            columns: columns.into_iter().map(Into::into).collect(),
+            delimiter: ',',
+            headers: true,
+            started: false,
+            written: 0,
+        }
+    }
+
+    /// Sets the character separating cells
+    pub fn with_delimiter(mut self, delimiter: char) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// Sets whether the first row names the columns
+    pub fn with_headers(mut self, headers: bool) -> Self {
+        self.headers = headers;
+        self
+    }
+
+    /// Gets the fields written as columns
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+
+    /// Gets the character separating cells
+    pub fn delimiter(&self) -> char {
+        self.delimiter
+    }
+
+    /// Gets whether the first row names the columns
+    pub fn headers(&self) -> bool {
+        self.headers
+    }
+
+    /// Writes the header row if it was not written yet
+    fn start(&mut self) -> Result<(), ExportError> {
+        if !self.started && self.headers {
+            let columns = self.columns.clone();
+            self.write_row(columns.iter().map(String::as_str))?;
+        }
+        self.started = true;
+        Ok(())
+    }
+
+    fn write_row<'a>(&mut self, cells: impl Iterator<Item = &'a str>) -> Result<(), ExportError> {
+        let mut line = String::new();
+        for (i, cell) in cells.enumerate() {
+            if i > 0 {
+                line.push(self.delimiter);
+            }
+            if cell.contains([self.delimiter, '"', '\n', '\r']) {
+                line.push('"');
+                line.push_str(&cell.replace('"', "\"\""));
+                line.push('"');
+            } else {
+                line.push_str(cell);
+            }
+        }
+        line.push('\n');
+        self.writer.write_all(line.as_bytes())?;
+        Ok(())
+    }
+}
+
+impl<W: Write> DocumentWriter for CsvWriter<W> {
+    fn write(&mut self, document: &Document) -> Result<(), ExportError> {
+        self.start()?;
+        let cells = self
+            .columns
+            .iter()
+            .map(|name| match document.get(name).map(|field| field.data()) {
+                None | Some([]) => Ok(String::new()),
+                Some([data]) => Ok(text_value(data)),
+                Some(data) => Err(ExportError::MultipleValues {
+                    field: name.clone(),
+                    values: data.len(),
+                }),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        self.write_row(cells.iter().map(String::as_str))?;
+        self.written += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<usize, ExportError> {
+        // files without any document still name their columns
+        self.start()?;
+        self.writer.flush()?;
+        Ok(self.written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fields::{Field, FieldData, FieldKind, Fields};
+    use crate::import::csv::{Csv, CsvOptions};
+    use crate::schema::{Schema, SchemaField};
+
+    fn book(title: &str, year: Option<i64>) -> Document {
+        let mut fields = Fields::new();
+        let data = FieldData::Bytes(title.as_bytes().into());
+        fields.insert("title", Field::new(FieldKind::Text(32), [data]));
+        if let Some(year) = year {
+            fields.insert("year", Field::new(FieldKind::I64, [FieldData::I64(year)]));
+        }
+        Document::from(fields)
+    }
+
+    #[test]
+    fn write_rows() {
+        let mut writer = CsvWriter::new(vec![], ["title", "year"]);
+        writer.write(&book("Dune", Some(1965))).unwrap();
+        writer.write(&book("Emma, \"a novel\"", None)).unwrap();
+        assert_eq!(writer.finish().unwrap(), 2);
+        let written = String::from_utf8(writer.writer).unwrap();
+        assert_eq!(
+            written,
+            "title,year\nDune,1965\n\"Emma, \"\"a novel\"\"\",\n"
+        );
+
+        // the rows can be imported again
+        let schema = Schema::from_iter(
+            [("title", FieldKind::Text(32)), ("year", FieldKind::I64)].map(|(name, kind)| {
+                SchemaField {
+                    name: name.to_string(),
+                    kind,
+                }
+            }),
+        );
+        let records = Csv::new(written.as_bytes(), schema, CsvOptions::new())
+            .unwrap()
+            .collect::<Vec<_>>();
+        let emma = records[1].document.as_ref().unwrap();
+        assert_eq!(
+            emma.get("title").unwrap().data(),
+            [FieldData::Bytes(b"Emma, \"a novel\"".as_slice().into())]
+        );
+        assert!(emma.get("year").is_none());
+
+        let mut writer = CsvWriter::new(vec![], ["title"])
+            .with_delimiter('\t')
+            .with_headers(false);
+        let mut tags = book("Dune", None);
+        let data = ["a", "b"].map(|tag| FieldData::Bytes(tag.as_bytes().into()));
+        tags.fields_mut()
+            .insert("title", Field::new(FieldKind::Text(32), data));
+        assert!(matches!(
+            writer.write(&tags),
+            Err(ExportError::MultipleValues { values: 2, .. })
+        ));
+    }
+}
@@ -0,0 +1,116 @@
+//! Exports documents into json lines files, writing a json object per line. Nested fields are
+//! written flat under their dotted names, so the `author.name` field is written as
+//! `{"author.name": "Frank Herbert"}`. Fields holding a single value are written as that value,
+//! and fields holding more than one as an array of them.
+
+use std::io::Write;
+
+use serde_json::{Map, Value};
+
+use crate::document::Document;
+use crate::export::{json_value, DocumentWriter, ExportError};
+
+/// Writes documents into a json lines file
+#[derive(Debug)]
+pub struct JsonLinesWriter<W: Write> {
+    writer: W,
+    fields: Option<Vec<String>>,
+    written: usize,
+}
+
+impl<W: Write> JsonLinesWriter<W> {
+    /// Writes every field of the documents
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            fields: None,
+            written: 0,
+        }
+    }
+
+    /// Only writes the given fields, in the given order
+    pub fn with_fields<I: IntoIterator<Item = S>, S: Into<String>>(mut self, fields: I) -> Self {
+        self.fields = Some(fields.into_iter().map(Into::into).collect());
+        self
+    }
+
+    /// Gets the fields written, if not every field is
+    pub fn fields(&self) -> Option<&[String]> {
+        self.fields.as_deref()
+    }
+}
+
+impl<W: Write> DocumentWriter for JsonLinesWriter<W> {
+    fn write(&mut self, document: &Document) -> Result<(), ExportError> {
+        let object = document_to_json(document, self.fields.as_deref());
+        serde_json::to_writer(&mut self.writer, &object)?;
+        self.writer.write_all(b"\n")?;
+        self.written += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<usize, ExportError> {
+        self.writer.flush()?;
+        Ok(self.written)
+    }
+}
+
+/// Converts a document into a flat json object, holding only the given fields if there are any
+pub fn document_to_json(document: &Document, fields: Option<&[String]>) -> Map<String, Value> {
+    let mut object = Map::new();
+    let mut insert = |name: &str| {
+        let Some(field) = document.get(name) else {
+            return;
+        };
+        let value = match field.data() {
+            [] => return,
+            [data] => json_value(data),
+            data => Value::Array(data.iter().map(json_value).collect()),
+        };
+        object.insert(name.to_string(), value);
+    };
+    match fields {
+        Some(fields) => fields.iter().for_each(|name| insert(name)),
+        None => document.fields().iter().for_each(|(name, _)| insert(name)),
+    }
+    object
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fields::{Field, FieldData, FieldKind, Fields};
+
+    #[test]
+    fn write_lines() {
+        let mut fields = Fields::new();
+        let data = FieldData::Bytes(b"Frank Herbert".as_slice().into());
+        fields.insert("author.name", Field::new(FieldKind::Text(32), [data]));
+        fields.insert("year", Field::new(FieldKind::I64, [FieldData::I64(1965)]));
+        let tags = ["classic", "sci-fi"].map(|tag| FieldData::Bytes(tag.as_bytes().into()));
+        fields.insert("tags", Field::new(FieldKind::Keyword(16), tags));
+        let dune = Document::from(fields);
+
+        let mut writer = JsonLinesWriter::new(vec![]);
+        writer.write(&dune).unwrap();
+        assert_eq!(writer.finish().unwrap(), 1);
+        let line: Value = serde_json::from_slice(&writer.writer).unwrap();
+        assert_eq!(
+            line,
+            serde_json::json!({
+                "author.name": "Frank Herbert",
+                "year": 1965,
+                "tags": ["classic", "sci-fi"],
+            })
+        );
+
+        let mut writer = JsonLinesWriter::new(vec![]).with_fields(["year", "pages"]);
+        writer.write(&dune).unwrap();
+        writer.write(&Document::from(Fields::new())).unwrap();
+        assert_eq!(writer.finish().unwrap(), 2);
+        assert_eq!(
+            String::from_utf8(writer.writer).unwrap(),
+            "{\"year\":1965}\n{}\n"
+        );
+    }
+}
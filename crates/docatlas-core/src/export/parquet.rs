@@ -0,0 +1,321 @@
+//! Exports documents into parquet files. Every field of the schema is written as an optional
+//! column of the same name: keyword and text fields as strings, `i64` fields as integers, `u64`
+//! fields as unsigned integers, `f64` and number fields as doubles, and blobs as binary data.
+//!
+//! Documents are buffered until there are enough of them to write a row group, which is
+//! compressed with snappy.
+
+use std::fmt::{Debug, Formatter};
+use std::io::Write;
+use std::sync::Arc;
+
+use parquet::basic::{Compression, LogicalType, Repetition, Type as PhysicalType};
+use parquet::column::writer::ColumnWriter;
+use parquet::data_type::ByteArray;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::types::Type;
+
+use crate::document::Document;
+use crate::export::{DocumentWriter, ExportError};
+use crate::fields::{FieldData, FieldKind};
+use crate::schema::{Schema, SchemaField};
+
+/// The number of documents written in a row group unless set otherwise
+pub const DEFAULT_ROW_GROUP_SIZE: usize = 8192;
+
+/// Writes documents of a schema into a parquet file
+pub struct ParquetWriter<W: Write + Send> {
+    writer: SerializedFileWriter<W>,
+    schema: Schema,
+    row_group_size: usize,
+    /// The cells of the documents not written yet, in the order of the fields of the schema
+    buffered: Vec<Vec<Option<Cell>>>,
+    written: usize,
+}
+
+/// A value of a column
+#[derive(Debug)]
+enum Cell {
+    Bytes(ByteArray),
+    I64(i64),
+    F64(f64),
+}
+
+impl<W: Write + Send> ParquetWriter<W> {
+    /// Writes a parquet file with a column for every field of the schema
+    pub fn new(writer: W, schema: Schema) -> Result<Self, ExportError> {
+        let columns = schema
+            .iter()
+            .map(|field| column_type(field).map(Arc::new))
+            .collect::<Result<Vec<_>, _>>()?;
+        let root = Type::group_type_builder("schema")
+            .with_fields(columns)
+            .build()?;
+        let properties = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+        Ok(Self {
+            writer: SerializedFileWriter::new(writer, Arc::new(root), Arc::new(properties))?,
+            schema,
+            row_group_size: DEFAULT_ROW_GROUP_SIZE,
+            buffered: vec![],
+            written: 0,
+        })
+    }
+
+    /// Sets the number of documents written in a row group
+    pub fn with_row_group_size(mut self, row_group_size: usize) -> Self {
+        self.row_group_size = row_group_size.max(1);
+        self
+    }
+
+    /// Gets the number of documents written in a row group
+    pub fn row_group_size(&self) -> usize {
+        self.row_group_size
+    }
+
+    /// Writes the buffered documents as a row group
+    fn write_row_group(&mut self) -> Result<(), ExportError> {
+        if self.buffered.is_empty() {
+            return Ok(());
+        }
+        let mut row_group = self.writer.next_row_group()?;
+        let mut index = 0;
+        while let Some(mut column) = row_group.next_column()? {
+            let cells = self.buffered.iter_mut().map(|row| row[index].take());
+            let levels = cells
+                .map(|cell| (i16::from(cell.is_some()), cell))
+                .collect::<Vec<_>>();
+            let definitions = levels.iter().map(|(level, _)| *level).collect::<Vec<_>>();
+            let values = levels.into_iter().filter_map(|(_, cell)| cell);
+            match column.untyped() {
+                ColumnWriter::ByteArrayColumnWriter(writer) => {
+                    let values = values
+                        .map(|cell| match cell {
+                            Cell::Bytes(bytes) => bytes,
+                            cell => unreachable!("{cell:?} in a byte array column"),
+                        })
+                        .collect::<Vec<_>>();
+                    writer.write_batch(&values, Some(&definitions), None)?;
+                }
+                ColumnWriter::Int64ColumnWriter(writer) => {
+                    let values = values
+                        .map(|cell| match cell {
+                            Cell::I64(value) => value,
+                            cell => unreachable!("{cell:?} in an integer column"),
+                        })
+                        .collect::<Vec<_>>();
+                    writer.write_batch(&values, Some(&definitions), None)?;
+                }
+                ColumnWriter::DoubleColumnWriter(writer) => {
+                    let values = values
+                        .map(|cell| match cell {
+                            Cell::F64(value) => value,
+                            cell => unreachable!("{cell:?} in a double column"),
+                        })
+                        .collect::<Vec<_>>();
+                    writer.write_batch(&values, Some(&definitions), None)?;
+                }
+                _ => unreachable!("only byte array, integer and double columns are written"),
+            }
+            column.close()?;
+            index += 1;
+        }
+        row_group.close()?;
+        self.written += self.buffered.len();
+        self.buffered.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write + Send> DocumentWriter for ParquetWriter<W> {
+    /// Writes a document. Fields that are not in the schema are left out, and fields holding
+    /// more than one value can not be written.
+    fn write(&mut self, document: &Document) -> Result<(), ExportError> {
+        let cells = self
+            .schema
+            .iter()
+            .map(|field| match document.get(&field.name).map(|f| f.data()) {
+                None | Some([]) => Ok(None),
+                Some([data]) => cell(field, data).map(Some),
+                Some(data) => Err(ExportError::MultipleValues {
+                    field: field.name.clone(),
+                    values: data.len(),
+                }),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        self.buffered.push(cells);
+        if self.buffered.len() >= self.row_group_size {
+            self.write_row_group()?;
+        }
+        Ok(())
+    }
+
+    /// Writes the documents not written yet along with the footer of the file
+    fn finish(&mut self) -> Result<usize, ExportError> {
+        self.write_row_group()?;
+        self.writer.finish()?;
+        Ok(self.written)
+    }
+}
+
+impl<W: Write + Send> Debug for ParquetWriter<W> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ParquetWriter")
+            .field("schema", &self.schema)
+            .field("row_group_size", &self.row_group_size)
+            .field("buffered", &self.buffered.len())
+            .field("written", &self.written)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Gets the type of the column a field is written as
+fn column_type(field: &SchemaField) -> Result<Type, ExportError> {
+    let (physical, logical) = match field.kind {
+        FieldKind::Keyword(_) | FieldKind::Text(_) => {
+            (PhysicalType::BYTE_ARRAY, Some(LogicalType::String))
+        }
+        FieldKind::I64 => (PhysicalType::INT64, None),
+        FieldKind::U64 => (
+            PhysicalType::INT64,
+            Some(LogicalType::Integer {
+                bit_width: 64,
+                is_signed: false,
+            }),
+        ),
+        FieldKind::F64 | FieldKind::Number(_) => (PhysicalType::DOUBLE, None),
+        FieldKind::Blob => (PhysicalType::BYTE_ARRAY, None),
+    };
+    Ok(Type::primitive_type_builder(&field.name, physical)
+        .with_repetition(Repetition::OPTIONAL)
+        .with_logical_type(logical)
+        .build()?)
+}
+
+/// Converts the value of a field into a value of its column
+fn cell(field: &SchemaField, data: &FieldData) -> Result<Cell, ExportError> {
+    let invalid = || ExportError::InvalidValue {
+        field: field.name.clone(),
+        kind: format!("{:?}", field.kind),
+    };
+    Ok(match (&field.kind, data) {
+        (FieldKind::Keyword(_) | FieldKind::Text(_), FieldData::Bytes(bytes))
+        | (FieldKind::Blob, FieldData::Blob(bytes) | FieldData::Bytes(bytes)) => {
+            Cell::Bytes(ByteArray::from(bytes.to_vec()))
+        }
+        (FieldKind::Keyword(_) | FieldKind::Text(_), FieldData::Analyzed(text)) => {
+            Cell::Bytes(ByteArray::from(text.text()))
+        }
+        (FieldKind::I64, FieldData::I64(i)) => Cell::I64(*i),
+        (FieldKind::I64, FieldData::U64(u)) => Cell::I64(i64::try_from(*u).map_err(|_| invalid())?),
+        (FieldKind::I64, FieldData::SizeT(n)) => {
+            Cell::I64(i64::try_from(*n).map_err(|_| invalid())?)
+        }
+        // unsigned integers are stored with the bits of signed ones
+        (FieldKind::U64, FieldData::U64(u)) => Cell::I64(*u as i64),
+        (FieldKind::U64, FieldData::I64(i)) if *i >= 0 => Cell::I64(*i),
+        (FieldKind::U64, FieldData::SizeT(n)) => Cell::I64(*n as u64 as i64),
+        (FieldKind::F64 | FieldKind::Number(_), FieldData::F64(f)) => Cell::F64(*f),
+        (FieldKind::F64 | FieldKind::Number(_), FieldData::Number(number)) => {
+            Cell::F64(number.to_f64())
+        }
+        (FieldKind::F64 | FieldKind::Number(_), FieldData::I64(i)) => Cell::F64(*i as f64),
+        (FieldKind::F64 | FieldKind::Number(_), FieldData::U64(u)) => Cell::F64(*u as f64),
+        _ => return Err(invalid()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Seek, SeekFrom};
+
+    use super::*;
+    use crate::fields::{Field, Fields};
+    use crate::import::parquet::{infer_schema, Parquet};
+
+    fn book(id: &str, year: i64, copies: u64, rating: Option<f64>) -> Document {
+        let mut fields = Fields::new();
+        let data = FieldData::Bytes(id.as_bytes().into());
+        fields.insert("id", Field::new(FieldKind::Keyword(16), [data]));
+        fields.insert("year", Field::new(FieldKind::I64, [FieldData::I64(year)]));
+        fields.insert(
+            "copies",
+            Field::new(FieldKind::U64, [FieldData::U64(copies)]),
+        );
+        if let Some(rating) = rating {
+            fields.insert(
+                "rating",
+                Field::new(FieldKind::F64, [FieldData::F64(rating)]),
+            );
+        }
+        Document::from(fields)
+    }
+
+    #[test]
+    fn export_and_import() {
+        let schema = Schema::from_iter(
+            [
+                ("id", FieldKind::Keyword(16)),
+                ("year", FieldKind::I64),
+                ("copies", FieldKind::U64),
+                ("rating", FieldKind::F64),
+                ("cover", FieldKind::Blob),
+            ]
+            .map(|(name, kind)| SchemaField {
+                name: name.to_string(),
+                kind,
+            }),
+        );
+        let mut file = tempfile::tempfile().unwrap();
+        let mut writer = ParquetWriter::new(file.try_clone().unwrap(), schema.clone())
+            .unwrap()
+            .with_row_group_size(2);
+        writer
+            .write(&book("dune", 1965, u64::MAX, Some(4.5)))
+            .unwrap();
+        writer.write(&book("emma", 1815, 3, None)).unwrap();
+        writer.write(&book("ubik", 1969, 0, Some(4.0))).unwrap();
+        let mut tags = book("many", 2000, 1, None);
+        let data = ["a", "b"].map(|tag| FieldData::Bytes(tag.as_bytes().into()));
+        tags.fields_mut()
+            .insert("id", Field::new(FieldKind::Keyword(16), data));
+        assert!(matches!(
+            writer.write(&tags),
+            Err(ExportError::MultipleValues { values: 2, .. })
+        ));
+        assert_eq!(writer.finish().unwrap(), 3);
+
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let inferred = infer_schema(file.try_clone().unwrap(), 10).unwrap();
+        let kinds = inferred
+            .iter()
+            .map(|field| (field.name.as_str(), field.kind.clone()))
+            .collect::<Vec<_>>();
+        // columns without any value are not inferred
+        assert_eq!(
+            kinds,
+            [
+                ("id", FieldKind::Keyword(16)),
+                ("year", FieldKind::I64),
+                ("copies", FieldKind::U64),
+                ("rating", FieldKind::F64),
+            ]
+        );
+
+        let records = Parquet::new(file, schema).unwrap().collect::<Vec<_>>();
+        let lines = records.iter().map(|record| record.line).collect::<Vec<_>>();
+        assert_eq!(lines, [1, 2, 3]);
+        let dune = records[0].document.as_ref().unwrap();
+        assert_eq!(
+            dune.get("copies").unwrap().data(),
+            [FieldData::U64(u64::MAX)]
+        );
+        assert_eq!(dune.get("rating").unwrap().data(), [FieldData::F64(4.5)]);
+        let emma = records[1].document.as_ref().unwrap();
+        assert_eq!(emma.get("year").unwrap().data(), [FieldData::I64(1815)]);
+        assert!(emma.get("rating").is_none());
+        assert!(emma.get("cover").is_none());
+    }
+}
@@ -1,17 +1,67 @@
 //! The fields that make up a document.
 
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::fmt::Display;
+use std::str::FromStr;
 use std::sync::Arc;
 
 use num_bigfloat::BigFloat;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::analysis::AnalyzedText;
+use crate::blob::BLOB_REF_SIZE;
 
 /// A view of a set of fields.
-#[derive(Debug)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct Fields {
     map: HashMap<String, Field>,
 }
 
+impl Fields {
+    /// Creates a new, empty set of fields
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Gets a field by name, if present
+    pub fn get(&self, name: impl AsRef<str>) -> Option<&Field> {
+        self.map.get(name.as_ref())
+    }
+
+    /// Gets a mutable reference to a field by name, if present
+    pub fn get_mut(&mut self, name: impl AsRef<str>) -> Option<&mut Field> {
+        self.map.get_mut(name.as_ref())
+    }
+
+    /// Inserts a field, returning the field previously stored under the same name
+    pub fn insert(&mut self, name: impl AsRef<str>, field: Field) -> Option<Field> {
+        self.map.insert(name.as_ref().to_string(), field)
+    }
+
+    /// Removes a field by name, if present
+    pub fn remove(&mut self, name: impl AsRef<str>) -> Option<Field> {
+        self.map.remove(name.as_ref())
+    }
+
+    /// Gets an iterator over the fields and their names
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Field)> {
+        self.map.iter().map(|(k, v)| (&**k, v))
+    }
+
+    /// Gets the number of fields
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    /// Checks whether there are no fields
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
 impl<S: AsRef<str>> FromIterator<(S, Field)> for Fields {
     fn from_iter<T: IntoIterator<Item = (S, Field)>>(iter: T) -> Self {
         let map = iter
@@ -25,21 +75,54 @@ impl<S: AsRef<str>> FromIterator<(S, Field)> for Fields {
 /// A field contains a kind and related data.
 ///
 /// Data is stored non-normally.
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Field {
     kind: FieldKind,
     data: Vec<FieldData>,
 }
 
+impl Field {
+    /// Creates a new field of a given kind
+    pub fn new<I: IntoIterator<Item = FieldData>>(kind: FieldKind, data: I) -> Self {
+        Self {
+            kind,
+            data: data.into_iter().collect(),
+        }
+    }
+
+    /// Gets the kind of the field
+    pub fn kind(&self) -> &FieldKind {
+        &self.kind
+    }
+
+    /// Gets the data stored in this field
+    pub fn data(&self) -> &[FieldData] {
+        &self.data
+    }
+
+    /// Gets a mutable reference to the data stored in this field
+    pub fn data_mut(&mut self) -> &mut Vec<FieldData> {
+        &mut self.data
+    }
+}
+
 /// The kind of the field
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum FieldKind {
     /// Keywords are non-tokenized
     Keyword(usize),
     /// Text fields are tokenized
     Text(usize),
-    /// Just a number
+    /// Just a number, stored with as much precision as its size allows
     Number(usize),
+    /// A signed 64-bit integer
+    I64,
+    /// An unsigned 64-bit integer
+    U64,
+    /// A 64-bit floating point number
+    F64,
+    /// Opaque binary data stored outside of the row. Blobs are never indexed.
+    Blob,
 }
 
 impl FieldKind {
@@ -49,40 +132,251 @@ impl FieldKind {
             FieldKind::Keyword(u) => *u,
             FieldKind::Text(u) => *u,
             FieldKind::Number(u) => *u,
+            FieldKind::I64 | FieldKind::U64 | FieldKind::F64 => 8,
+            FieldKind::Blob => BLOB_REF_SIZE,
         }
     }
 
     /// A field kind that is directly indexable.
     pub fn indexable(&self) -> bool {
-        todo!()
+        matches!(self, FieldKind::Keyword(_))
     }
 
     /// A field that can be searched, but is not indexed by itself.
     pub fn searchable(&self) -> bool {
-        todo!()
+        matches!(self, FieldKind::Text(_))
     }
 
     /// A field that can be aggregated against. These are mostly just numbers, aka have
     /// closure over mathematical operations.
     pub fn aggregateable(&self) -> bool {
-        todo!()
+        matches!(
+            self,
+            FieldKind::Number(_) | FieldKind::I64 | FieldKind::U64 | FieldKind::F64
+        )
+    }
+}
+
+/// Parses kinds written like `keyword:32`, `text:256`, `number:16`, `i64`, `u64`, `f64` or
+/// `blob`, where sized kinds give their size after the colon
+impl FromStr for FieldKind {
+    type Err = UnknownFieldKindError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let unknown = || UnknownFieldKindError(s.to_string());
+        let (name, size) = match s.split_once(':') {
+            Some((name, size)) => (name, Some(size.parse().map_err(|_| unknown())?)),
+            None => (s, None),
+        };
+        match (name.to_ascii_lowercase().as_str(), size) {
+            ("keyword", Some(size)) => Ok(FieldKind::Keyword(size)),
+            ("text", Some(size)) => Ok(FieldKind::Text(size)),
+            ("number", Some(size)) => Ok(FieldKind::Number(size)),
+            ("i64", None) => Ok(FieldKind::I64),
+            ("u64", None) => Ok(FieldKind::U64),
+            ("f64", None) => Ok(FieldKind::F64),
+            ("blob", None) => Ok(FieldKind::Blob),
+            _ => Err(unknown()),
+        }
     }
 }
 
+/// A field kind could not be parsed
+#[derive(Debug, Error)]
+#[error("Unknown field kind {0:?}, expected keyword:<size>, text:<size>, number:<size>, i64, u64, f64 or blob")]
+pub struct UnknownFieldKindError(String);
+
 /// A field within a document
 ///
 /// These values are the "raw" values, and are what indexes are built on top of. The Field Kind is used to interpret
 /// how the data is actually viewed.
 ///
-/// Field values should be optimized for multiple reading.
-#[derive(Debug, PartialEq)]
+/// Field values should be optimized for multiple reading. When serialized, bytes are written as
+/// byte strings and numbers by their exact parts, so no precision is lost.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum FieldData {
     /// The sizet data type, used mainly for identifiers
     SizeT(usize),
     /// Bytes of a set size
-    Bytes(Arc<[u8]>),
-    /// A floating point number of a dynamic size
+    Bytes(#[serde(with = "arc_bytes")] Arc<[u8]>),
+    /// A floating point number of a dynamic size, used for arbitrary precision
+    Number(#[serde(with = "big_float")] BigFloat),
+    /// A signed 64-bit integer
+    I64(i64),
+    /// An unsigned 64-bit integer
+    U64(u64),
+    /// A 64-bit floating point number
+    F64(f64),
+    /// Binary data of any size
+    Blob(#[serde(with = "arc_bytes")] Arc<[u8]>),
+    /// Text along with its tokens, stored in rows as just the text
+    Analyzed(Arc<AnalyzedText>),
+}
+
+impl FieldData {
+    /// Gets this data as a big float, if it is a number
+    pub fn to_big_float(&self) -> Option<BigFloat> {
+        match self {
+            FieldData::SizeT(size) => Some(BigFloat::from_u64(*size as u64)),
+            FieldData::Number(number) => Some(*number),
+            FieldData::I64(i) => Some(BigFloat::from_i64(*i)),
+            FieldData::U64(u) => Some(BigFloat::from_u64(*u)),
+            FieldData::F64(f) => Some(BigFloat::from_f64(*f)),
+            _ => None,
+        }
+    }
+
+    /// Compares two values. Fixed width numbers are compared directly, only falling back to big
+    /// floats when mixed with arbitrary precision numbers. Bytes compare lexicographically, and
+    /// values that can not be compared return `None`.
+    pub fn compare(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (FieldData::I64(a), FieldData::I64(b)) => Some(a.cmp(b)),
+            (FieldData::U64(a), FieldData::U64(b)) => Some(a.cmp(b)),
+            (FieldData::SizeT(a), FieldData::SizeT(b)) => Some(a.cmp(b)),
+            (FieldData::F64(a), FieldData::F64(b)) => a.partial_cmp(b),
+            (FieldData::I64(a), FieldData::U64(b)) => Some(i128::from(*a).cmp(&i128::from(*b))),
+            (FieldData::U64(a), FieldData::I64(b)) => Some(i128::from(*a).cmp(&i128::from(*b))),
+            (FieldData::Bytes(a), FieldData::Bytes(b)) => Some(a.cmp(b)),
+            (a, b) => a.to_big_float()?.partial_cmp(&b.to_big_float()?),
+        }
+    }
+
+    /// Turns bytes into a blob, leaving any other data as it is
+    pub fn into_blob(self) -> Self {
+        match self {
+            FieldData::Bytes(bytes) => FieldData::Blob(bytes),
+            data => data,
+        }
+    }
+}
+
+/// A value stored in a row, borrowed from the row or the blob it references instead of being
+/// copied into [field data](FieldData)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StoredValue<'a> {
+    /// The bytes of a keyword or text field, without their padding
+    Bytes(&'a [u8]),
     Number(BigFloat),
+    I64(i64),
+    U64(u64),
+    F64(f64),
+    Blob(&'a [u8]),
+}
+
+impl<'a> StoredValue<'a> {
+    /// Copies the value into field data
+    pub fn to_field_data(&self) -> FieldData {
+        match *self {
+            StoredValue::Bytes(bytes) => FieldData::Bytes(Arc::from(bytes)),
+            StoredValue::Number(number) => FieldData::Number(number),
+            StoredValue::I64(i) => FieldData::I64(i),
+            StoredValue::U64(u) => FieldData::U64(u),
+            StoredValue::F64(f) => FieldData::F64(f),
+            StoredValue::Blob(bytes) => FieldData::Blob(Arc::from(bytes)),
+        }
+    }
+
+    /// Gets the bytes of the value, if it is bytes or a blob
+    pub fn as_bytes(&self) -> Option<&'a [u8]> {
+        match *self {
+            StoredValue::Bytes(bytes) | StoredValue::Blob(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    /// Compares the value with field data like [`FieldData::compare`], with analyzed text
+    /// comparing as its bytes
+    pub fn compare(&self, other: &FieldData) -> Option<Ordering> {
+        match (self, other) {
+            (StoredValue::Bytes(a), FieldData::Bytes(b)) => Some((*a).cmp(&**b)),
+            (StoredValue::Bytes(a), FieldData::Analyzed(b)) => Some((*a).cmp(b.text().as_bytes())),
+            (StoredValue::Bytes(_) | StoredValue::Blob(_), _) => None,
+            // numbers are copied without allocating
+            (number, other) => number.to_field_data().compare(other),
+        }
+    }
+}
+
+impl<'a> From<&'a FieldData> for StoredValue<'a> {
+    fn from(data: &'a FieldData) -> Self {
+        match data {
+            FieldData::SizeT(size) => StoredValue::U64(*size as u64),
+            FieldData::Bytes(bytes) => StoredValue::Bytes(bytes),
+            FieldData::Number(number) => StoredValue::Number(*number),
+            FieldData::I64(i) => StoredValue::I64(*i),
+            FieldData::U64(u) => StoredValue::U64(*u),
+            FieldData::F64(f) => StoredValue::F64(*f),
+            FieldData::Blob(bytes) => StoredValue::Blob(bytes),
+            FieldData::Analyzed(text) => StoredValue::Bytes(text.text().as_bytes()),
+        }
+    }
+}
+
+/// Serializes shared bytes as a byte string
+mod arc_bytes {
+    use std::sync::Arc;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+    use serde_bytes::{ByteBuf, Bytes};
+
+    pub fn serialize<S: Serializer>(bytes: &Arc<[u8]>, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(Bytes::new(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Arc<[u8]>, D::Error> {
+        ByteBuf::deserialize(deserializer).map(|buf| Arc::from(buf.into_vec()))
+    }
+}
+
+/// Serializes big floats by their raw parts, which is stable across platforms and versions
+mod big_float {
+    use num_bigfloat::{BigFloat, INF_NEG, INF_POS, NAN};
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    enum Repr {
+        Finite {
+            mantissa: [i16; 10],
+            mantissa_len: i16,
+            sign: i8,
+            exponent: i8,
+        },
+        Infinite {
+            negative: bool,
+        },
+        NaN,
+    }
+
+    pub fn serialize<S: Serializer>(number: &BigFloat, serializer: S) -> Result<S::Ok, S::Error> {
+        let repr = match number.to_raw_parts() {
+            Some((mantissa, mantissa_len, sign, exponent)) => Repr::Finite {
+                mantissa,
+                mantissa_len,
+                sign,
+                exponent,
+            },
+            None if number.is_nan() => Repr::NaN,
+            None => Repr::Infinite {
+                negative: number.is_negative(),
+            },
+        };
+        repr.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BigFloat, D::Error> {
+        Ok(match Repr::deserialize(deserializer)? {
+            Repr::Finite {
+                mantissa,
+                mantissa_len,
+                sign,
+                exponent,
+            } => BigFloat::from_raw_parts(mantissa, mantissa_len, sign, exponent),
+            Repr::Infinite { negative: false } => INF_POS,
+            Repr::Infinite { negative: true } => INF_NEG,
+            Repr::NaN => NAN,
+        })
+    }
 }
 
 /// A type that can be represent fields
@@ -102,3 +396,192 @@ impl AsFields for Fields {
         self.map.iter().map(|(k, v)| (&**k, v)).collect()
     }
 }
+
+/// A type that can be turned into fields.
+///
+/// With the `derive` feature, this can be derived for structs whose members implement
+/// [`ToFieldData`](ToFieldData). Every member needs a `#[field(...)]` attribute giving its `kind`
+/// (`keyword`, `text`, `number`, `i64`, `u64`, `f64` or `blob`). Keywords and text need a `size`,
+/// while `number` sizes default to 8 bytes. Members can be renamed with `name = "..."` or left
+/// out with `skip`. The bytes of blob members are stored as [blobs](FieldData::Blob).
+///
+/// ```ignore
+/// #[derive(ToFields)]
+/// struct Article {
+///     #[field(kind = "keyword", size = 16)]
+///     id: String,
+///     #[field(kind = "text", size = 256)]
+///     body: String,
+///     #[field(kind = "u64", name = "view_count")]
+///     views: u64,
+///     #[field(kind = "blob")]
+///     cover: Vec<u8>,
+///     #[field(skip)]
+///     cached: bool,
+/// }
+/// ```
+pub trait ToFields {
+    /// Builds the fields of some value
+    fn to_fields(&self) -> Fields;
+}
+
+impl ToFields for Fields {
+    fn to_fields(&self) -> Fields {
+        self.clone()
+    }
+}
+
+/// A value that can be stored as the data of a field
+pub trait ToFieldData {
+    /// Gets the field data of this value, or `None` if there is no data
+    fn to_field_data(&self) -> Option<FieldData>;
+}
+
+impl ToFieldData for str {
+    fn to_field_data(&self) -> Option<FieldData> {
+        Some(FieldData::Bytes(Arc::from(self.as_bytes())))
+    }
+}
+
+impl ToFieldData for String {
+    fn to_field_data(&self) -> Option<FieldData> {
+        self.as_str().to_field_data()
+    }
+}
+
+impl ToFieldData for [u8] {
+    fn to_field_data(&self) -> Option<FieldData> {
+        Some(FieldData::Bytes(Arc::from(self)))
+    }
+}
+
+impl ToFieldData for Vec<u8> {
+    fn to_field_data(&self) -> Option<FieldData> {
+        self.as_slice().to_field_data()
+    }
+}
+
+impl ToFieldData for Arc<[u8]> {
+    fn to_field_data(&self) -> Option<FieldData> {
+        Some(FieldData::Bytes(self.clone()))
+    }
+}
+
+impl ToFieldData for BigFloat {
+    fn to_field_data(&self) -> Option<FieldData> {
+        Some(FieldData::Number(*self))
+    }
+}
+
+impl ToFieldData for usize {
+    fn to_field_data(&self) -> Option<FieldData> {
+        Some(FieldData::SizeT(*self))
+    }
+}
+
+macro_rules! number_field_data {
+    ($($ty:ty => $variant:ident),+ $(,)?) => {
+        $(
+        impl ToFieldData for $ty {
+            fn to_field_data(&self) -> Option<FieldData> {
+                Some(FieldData::$variant((*self).into()))
+            }
+        }
+        )*
+    };
+}
+
+number_field_data!(
+    i8 => I64,
+    i16 => I64,
+    i32 => I64,
+    i64 => I64,
+    u8 => U64,
+    u16 => U64,
+    u32 => U64,
+    u64 => U64,
+    f32 => F64,
+    f64 => F64,
+);
+
+impl<T: ToFieldData + ?Sized> ToFieldData for &T {
+    fn to_field_data(&self) -> Option<FieldData> {
+        (**self).to_field_data()
+    }
+}
+
+impl<T: ToFieldData> ToFieldData for Option<T> {
+    fn to_field_data(&self) -> Option<FieldData> {
+        self.as_ref().and_then(ToFieldData::to_field_data)
+    }
+}
+
+#[cfg(feature = "derive")]
+pub use docatlas_derive::ToFields;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serde_round_trip() {
+        let fields = Fields::from_iter([
+            (
+                "name",
+                Field::new(
+                    FieldKind::Keyword(8),
+                    [FieldData::Bytes(Arc::from(&b"docatlas"[..]))],
+                ),
+            ),
+            (
+                "size",
+                Field::new(
+                    FieldKind::Number(8),
+                    [
+                        FieldData::Number(BigFloat::from_f64(-12.5)),
+                        FieldData::I64(-3),
+                        FieldData::SizeT(3),
+                    ],
+                ),
+            ),
+        ]);
+
+        let serialized = ron::to_string(&fields).unwrap();
+        let deserialized: Fields = ron::from_str(&serialized).unwrap();
+        assert_eq!(deserialized.len(), 2);
+        for (name, field) in fields.iter() {
+            let other = deserialized.get(name).unwrap();
+            assert_eq!(field.kind(), other.kind());
+            assert_eq!(field.data(), other.data());
+        }
+    }
+
+    #[test]
+    fn compare_numbers() {
+        assert_eq!(
+            FieldData::I64(-1).compare(&FieldData::U64(u64::MAX)),
+            Some(Ordering::Less)
+        );
+        assert_eq!(
+            FieldData::F64(2.5).compare(&FieldData::Number(BigFloat::from_f64(2.0))),
+            Some(Ordering::Greater)
+        );
+        assert_eq!(FieldData::F64(f64::NAN).compare(&FieldData::F64(1.0)), None);
+        assert_eq!(
+            FieldData::I64(1).compare(&FieldData::Bytes(Arc::from(&b"1"[..]))),
+            None
+        );
+    }
+
+    #[test]
+    fn parse_kinds() {
+        assert_eq!(
+            "keyword:32".parse::<FieldKind>().unwrap(),
+            FieldKind::Keyword(32)
+        );
+        assert_eq!("F64".parse::<FieldKind>().unwrap(), FieldKind::F64);
+        assert!("keyword".parse::<FieldKind>().is_err());
+        assert!("i64:8".parse::<FieldKind>().is_err());
+        assert!("date".parse::<FieldKind>().is_err());
+    }
+}
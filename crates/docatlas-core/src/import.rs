@@ -0,0 +1,233 @@
+//! Imports documents from files written by other programs, such as [json lines](json_lines) and
+//! [csv] files, or parquet files if the `parquet` feature is enabled.
+//!
+//! Values are converted into the kind of their field in the schema of the target index. Indexes
+//! that do not exist yet can be given a schema inferred from the first records of the file
+//! instead. Records that can not be converted are reported by their line, without stopping the
+//! import.
+
+use std::io;
+
+use base64::Engine;
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::document::Document;
+use crate::fields::{FieldData, FieldKind};
+use crate::schema::{Schema, SchemaField};
+
+pub mod csv;
+pub mod json_lines;
+#[cfg(feature = "parquet")]
+pub mod parquet;
+
+/// The smallest size inferred for text and keyword fields
+const MIN_INFERRED_SIZE: usize = 16;
+
+/// A record read from a file, converted into a document if it could be
+#[derive(Debug)]
+pub struct Record {
+    /// The line the record starts on, or its row in files without lines, counting from 1
+    pub line: usize,
+    pub document: Result<Document, ImportError>,
+}
+
+/// How many records of a file were imported, and why the others were not
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ImportSummary {
+    /// The number of records read
+    pub records: usize,
+    pub added: usize,
+    /// Documents dropped by the ingest pipeline of the index
+    pub dropped: usize,
+    /// Why every record that could not be imported failed
+    pub failed: Vec<LineError>,
+}
+
+impl ImportSummary {
+    /// Records that the record on a line could not be imported
+    pub fn fail(&mut self, line: usize, message: impl ToString) {
+        self.failed.push(LineError {
+            line,
+            message: message.to_string(),
+        });
+    }
+}
+
+/// Why the record on a line could not be imported
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct LineError {
+    pub line: usize,
+    pub message: String,
+}
+
+/// A record could not be converted into a document
+#[derive(Debug, Error)]
+pub enum ImportError {
+    #[error("invalid json: {0}")]
+    InvalidJson(#[from] serde_json::Error),
+    #[error("expected a json object")]
+    NotAnObject,
+    #[error("malformed row: {0}")]
+    Malformed(String),
+    #[error("unknown field {0:?}")]
+    UnknownField(String),
+    #[error("field {field:?} can not hold {value}")]
+    InvalidValue { field: String, value: String },
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+    #[cfg(feature = "parquet")]
+    #[error("invalid parquet: {0}")]
+    InvalidParquet(#[from] ::parquet::errors::ParquetError),
+}
+
+/// Parses a value given as text into the kind of its field. Blobs are given in base64.
+pub fn parse_text(field: &str, kind: &FieldKind, text: &str) -> Result<FieldData, ImportError> {
+    let invalid = || ImportError::InvalidValue {
+        field: field.to_string(),
+        value: format!("{text:?}"),
+    };
+    Ok(match kind {
+        FieldKind::Keyword(_) | FieldKind::Text(_) => FieldData::Bytes(text.as_bytes().into()),
+        FieldKind::I64 => FieldData::I64(text.trim().parse().map_err(|_| invalid())?),
+        FieldKind::U64 => FieldData::U64(text.trim().parse().map_err(|_| invalid())?),
+        FieldKind::F64 | FieldKind::Number(_) => {
+            FieldData::F64(text.trim().parse().map_err(|_| invalid())?)
+        }
+        FieldKind::Blob => FieldData::Blob(
+            base64::engine::general_purpose::STANDARD
+                .decode(text.trim())
+                .map_err(|_| invalid())?
+                .into(),
+        ),
+    })
+}
+
+/// Infers the schema of the records of a file from their values. Fields are kept in the order
+/// they were first seen in.
+#[derive(Debug, Default)]
+pub struct SchemaInference {
+    fields: Vec<InferredField>,
+}
+
+/// A field seen in the records of a file
+#[derive(Debug)]
+struct InferredField {
+    name: String,
+    kind: Inferred,
+    /// The length of its longest value, written as text
+    text_len: usize,
+}
+
+impl SchemaInference {
+    /// Creates an inference that has not seen any record yet
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that a field holds a value of the given kind
+    pub(crate) fn observe(&mut self, name: &str, kind: Inferred, text_len: usize) {
+        match self.fields.iter_mut().find(|field| field.name == name) {
+            Some(field) => {
+                field.text_len = field.text_len.max(text_len);
+                field.kind = field.kind.merge(kind, field.text_len);
+            }
+            None => self.fields.push(InferredField {
+                name: name.to_string(),
+                kind,
+                text_len,
+            }),
+        }
+    }
+
+    /// Gets the schema holding every field seen so far
+    pub fn schema(&self) -> Schema {
+        Schema::from_iter(self.fields.iter().map(|field| SchemaField {
+            name: field.name.clone(),
+            kind: field.kind.into_kind(),
+        }))
+    }
+}
+
+/// The kind of a field inferred from the values of some records
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Inferred {
+    I64,
+    U64,
+    F64,
+    /// Text without whitespace, holding up to the given number of bytes
+    Keyword(usize),
+    /// Text with whitespace, holding up to the given number of bytes
+    Text(usize),
+    /// Binary data, only inferred from files that tell text and binary data apart
+    Blob,
+}
+
+impl Inferred {
+    /// Infers the kind of a value given as text
+    pub(crate) fn from_text(text: &str) -> Self {
+        if text.contains(char::is_whitespace) {
+            Inferred::Text(text.len())
+        } else {
+            Inferred::Keyword(text.len())
+        }
+    }
+
+    /// Gets the kind of a field holding values of both kinds, the longest of which is `text_len`
+    /// bytes long when written as text. Numbers of different kinds widen to floats, and anything
+    /// else is held as text.
+    fn merge(self, other: Self, text_len: usize) -> Self {
+        use Inferred::*;
+        match (self, other) {
+            (a, b) if a == b => a,
+            (Blob, _) | (_, Blob) => Blob,
+            (I64 | U64 | F64, I64 | U64 | F64) => F64,
+            (Text(a), Text(b) | Keyword(b)) | (Keyword(b), Text(a)) => Text(a.max(b)),
+            (Keyword(a), Keyword(b)) => Keyword(a.max(b)),
+            (Text(len), _) | (_, Text(len)) => Text(len.max(text_len)),
+            (Keyword(len), _) | (_, Keyword(len)) => Keyword(len.max(text_len)),
+        }
+    }
+
+    fn into_kind(self) -> FieldKind {
+        let size = |len: usize| len.max(MIN_INFERRED_SIZE).next_power_of_two();
+        match self {
+            Inferred::I64 => FieldKind::I64,
+            Inferred::U64 => FieldKind::U64,
+            Inferred::F64 => FieldKind::F64,
+            Inferred::Keyword(len) => FieldKind::Keyword(size(len)),
+            Inferred::Text(len) => FieldKind::Text(size(len)),
+            Inferred::Blob => FieldKind::Blob,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_values() {
+        let parsed = parse_text("count", &FieldKind::I64, " -3 ").unwrap();
+        assert_eq!(parsed, FieldData::I64(-3));
+        let parsed = parse_text("blob", &FieldKind::Blob, "aGk=").unwrap();
+        assert_eq!(parsed, FieldData::Blob(b"hi".as_slice().into()));
+        let error = parse_text("count", &FieldKind::U64, "-3").unwrap_err();
+        assert_eq!(error.to_string(), "field \"count\" can not hold \"-3\"");
+    }
+
+    #[test]
+    fn widen_inferred_kinds() {
+        assert_eq!(Inferred::I64.merge(Inferred::F64, 0), Inferred::F64);
+        assert_eq!(Inferred::I64.merge(Inferred::U64, 0), Inferred::F64);
+        assert_eq!(
+            Inferred::Keyword(4).merge(Inferred::Text(9), 0),
+            Inferred::Text(9)
+        );
+        assert_eq!(
+            Inferred::I64.merge(Inferred::Keyword(3), 5),
+            Inferred::Keyword(5)
+        );
+        assert_eq!(Inferred::Keyword(40).into_kind(), FieldKind::Keyword(64));
+    }
+}
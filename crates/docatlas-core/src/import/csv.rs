@@ -0,0 +1,472 @@
+//! Imports csv files, and other files of delimited values such as tsv. Cells may be quoted with
+//! `"`, in which case they can hold delimiters, newlines and quotes written twice (`""`). Empty
+//! cells leave their field unset.
+//!
+//! Rows are malformed if they do not have as many cells as the header, or end within a quoted
+//! cell. What is done with them is set by their [policy](MalformedRows).
+
+use std::io::{self, BufRead};
+use std::mem;
+
+use crate::document::Document;
+use crate::fields::{Field, Fields};
+use crate::import::{parse_text, ImportError, Inferred, Record, SchemaInference};
+use crate::schema::Schema;
+
+/// What is done with malformed rows
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MalformedRows {
+    /// Reports the row as a failed record, and carries on
+    #[default]
+    Report,
+    /// Skips the row without reporting it
+    Skip,
+    /// Reports the row as a failed record, and reads no further
+    Abort,
+}
+
+/// How a file of delimited values is read
+#[derive(Debug, Clone)]
+pub struct CsvOptions {
+    delimiter: char,
+    has_headers: bool,
+    malformed: MalformedRows,
+}
+
+impl Default for CsvOptions {
+    fn default() -> Self {
+        Self {
+            delimiter: ',',
+            has_headers: true,
+            malformed: MalformedRows::default(),
+        }
+    }
+}
+
+impl CsvOptions {
+    /// Reads comma separated values whose first row names the fields of the cells below it
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads tab separated values whose first row names the fields of the cells below it
+    pub fn tsv() -> Self {
+        Self::default().with_delimiter('\t')
+    }
+
+    /// Sets the character separating cells
+    pub fn with_delimiter(mut self, delimiter: char) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// Sets whether the first row names the fields of the cells below it. Files without headers
+    /// set fields named `column_1`, `column_2` and so on.
+    pub fn with_headers(mut self, has_headers: bool) -> Self {
+        self.has_headers = has_headers;
+        self
+    }
+
+    /// Sets what is done with malformed rows
+    pub fn with_malformed_rows(mut self, malformed: MalformedRows) -> Self {
+        self.malformed = malformed;
+        self
+    }
+
+    /// Gets the character separating cells
+    pub fn delimiter(&self) -> char {
+        self.delimiter
+    }
+
+    /// Gets whether the first row names the fields of the cells below it
+    pub fn has_headers(&self) -> bool {
+        self.has_headers
+    }
+
+    /// Gets what is done with malformed rows
+    pub fn malformed_rows(&self) -> MalformedRows {
+        self.malformed
+    }
+}
+
+/// Reads the records of a file of delimited values one row at a time. Blank lines are skipped.
+#[derive(Debug)]
+pub struct Csv<R> {
+    rows: Rows<R>,
+    schema: Schema,
+    options: CsvOptions,
+    /// The names of the fields of each column, known once the first row is read
+    headers: Option<Vec<String>>,
+    /// The line of the malformed row the import was aborted on
+    aborted: Option<usize>,
+    failed: bool,
+}
+
+impl<R: BufRead> Csv<R> {
+    /// Reads records converted into documents of the given schema. The header is read right
+    /// away, and must only name fields of the schema.
+    pub fn new(reader: R, schema: Schema, options: CsvOptions) -> Result<Self, ImportError> {
+        let mut rows = Rows::new(reader, options.delimiter);
+        let headers = if options.has_headers {
+            match rows.next().transpose()? {
+                Some(row) => {
+                    let headers = row.cells.map_err(ImportError::Malformed)?;
+                    if let Some(unknown) = headers.iter().find(|name| schema.get(name).is_none()) {
+                        return Err(ImportError::UnknownField(unknown.clone()));
+                    }
+                    Some(headers)
+                }
+                None => Some(vec![]),
+            }
+        } else {
+            None
+        };
+        Ok(Self {
+            rows,
+            schema,
+            options,
+            headers,
+            aborted: None,
+            failed: false,
+        })
+    }
+
+    /// Gets the line of the malformed row the import was aborted on, if it was
+    pub fn aborted(&self) -> Option<usize> {
+        self.aborted
+    }
+
+    /// Converts the cells of a row into a document
+    fn document(&mut self, cells: Result<Vec<String>, String>) -> Result<Document, ImportError> {
+        let cells = cells.map_err(ImportError::Malformed)?;
+        let headers = self
+            .headers
+            .get_or_insert_with(|| column_names(cells.len()));
+        if cells.len() != headers.len() {
+            return Err(ImportError::Malformed(format!(
+                "expected {} cells, found {}",
+                headers.len(),
+                cells.len()
+            )));
+        }
+        let mut fields = Fields::new();
+        for (name, cell) in headers.iter().zip(cells) {
+            if cell.is_empty() {
+                continue;
+            }
+            let kind = &self
+                .schema
+                .get(name)
+                .ok_or_else(|| ImportError::UnknownField(name.clone()))?
+                .kind;
+            let data = parse_text(name, kind, &cell)?;
+            fields.insert(name, Field::new(kind.clone(), [data]));
+        }
+        Ok(Document::from(fields))
+    }
+}
+
+impl<R: BufRead> Iterator for Csv<R> {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed || self.aborted.is_some() {
+            return None;
+        }
+        loop {
+            let row = match self.rows.next()? {
+                Ok(row) => row,
+                Err(e) => {
+                    // the rest of the file can not be read either
+                    self.failed = true;
+                    return Some(Record {
+                        line: self.rows.line,
+                        document: Err(e.into()),
+                    });
+                }
+            };
+            let document = self.document(row.cells);
+            if let Err(ImportError::Malformed(_)) = &document {
+                match self.options.malformed {
+                    MalformedRows::Report => {}
+                    MalformedRows::Skip => continue,
+                    MalformedRows::Abort => self.aborted = Some(row.line),
+                }
+            }
+            return Some(Record {
+                line: row.line,
+                document,
+            });
+        }
+    }
+}
+
+/// Infers a schema from up to `limit` rows of a file of delimited values. Malformed rows are
+/// skipped. Cells holding integers are inferred to be `i64` or `u64` fields, other numbers `f64`
+/// fields, and anything else keyword or text fields.
+pub fn infer_schema<R: BufRead>(
+    reader: R,
+    options: &CsvOptions,
+    limit: usize,
+) -> io::Result<Schema> {
+    let mut rows = Rows::new(reader, options.delimiter);
+    let mut headers = None;
+    if options.has_headers {
+        match rows.next().transpose()? {
+            Some(Row {
+                cells: Ok(cells), ..
+            }) => headers = Some(cells),
+            Some(Row { cells: Err(e), .. }) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+            None => {}
+        }
+    }
+    let mut inference = SchemaInference::new();
+    let mut records = 0;
+    for row in rows {
+        if records == limit {
+            break;
+        }
+        let Ok(cells) = row?.cells else {
+            continue;
+        };
+        let headers = headers.get_or_insert_with(|| column_names(cells.len()));
+        if cells.len() != headers.len() {
+            continue;
+        }
+        records += 1;
+        for (name, cell) in headers.iter().zip(&cells) {
+            if !cell.is_empty() {
+                inference.observe(name, infer_cell(cell), cell.len());
+            }
+        }
+    }
+    Ok(inference.schema())
+}
+
+/// Infers the kind of a cell, which holds a number if it can be parsed as one
+fn infer_cell(cell: &str) -> Inferred {
+    let number = cell.trim();
+    if number.parse::<i64>().is_ok() {
+        Inferred::I64
+    } else if number.parse::<u64>().is_ok() {
+        Inferred::U64
+    } else if number.starts_with(|c: char| c.is_ascii_digit() || "+-.".contains(c))
+        && number.parse::<f64>().is_ok_and(f64::is_finite)
+    {
+        // words such as `inf` and `nan` also parse as floats
+        Inferred::F64
+    } else {
+        Inferred::from_text(cell)
+    }
+}
+
+/// The names of the fields of files without headers
+fn column_names(columns: usize) -> Vec<String> {
+    (1..=columns).map(|i| format!("column_{i}")).collect()
+}
+
+/// A row of a file of delimited values
+#[derive(Debug)]
+struct Row {
+    /// The line the row starts on, counting from 1
+    line: usize,
+    /// The cells of the row, or why they could not be read
+    cells: Result<Vec<String>, String>,
+}
+
+/// Splits a file of delimited values into rows
+#[derive(Debug)]
+struct Rows<R> {
+    reader: R,
+    delimiter: char,
+    /// The last line read
+    line: usize,
+}
+
+impl<R: BufRead> Rows<R> {
+    fn new(reader: R, delimiter: char) -> Self {
+        Self {
+            reader,
+            delimiter,
+            line: 0,
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for Rows<R> {
+    type Item = io::Result<Row>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut start = None;
+        let mut cells = vec![];
+        let mut cell = String::new();
+        let mut error = None;
+        // whether the cell being read is quoted, and whether its closing quote was read
+        let mut quoted = false;
+        let mut closed = false;
+        let mut buffer = String::new();
+        loop {
+            buffer.clear();
+            match self.reader.read_line(&mut buffer) {
+                Ok(0) if start.is_none() => return None,
+                Ok(0) => {
+                    error.get_or_insert_with(|| "unterminated quoted cell".to_string());
+                    cells.push(mem::take(&mut cell));
+                    break;
+                }
+                Ok(_) => self.line += 1,
+                Err(e) => return Some(Err(e)),
+            }
+            let text = buffer.strip_suffix('\n').unwrap_or(&buffer);
+            let text = text.strip_suffix('\r').unwrap_or(text);
+            if start.is_none() {
+                if text.trim().is_empty() {
+                    continue;
+                }
+                start = Some(self.line);
+            } else {
+                // the line continues a quoted cell
+                cell.push('\n');
+            }
+
+            let mut chars = text.chars().peekable();
+            while let Some(c) = chars.next() {
+                if quoted {
+                    match c {
+                        '"' if chars.peek() == Some(&'"') => {
+                            chars.next();
+                            cell.push('"');
+                        }
+                        '"' => {
+                            quoted = false;
+                            closed = true;
+                        }
+                        c => cell.push(c),
+                    }
+                } else if c == self.delimiter {
+                    cells.push(mem::take(&mut cell));
+                    closed = false;
+                } else if closed {
+                    error.get_or_insert_with(|| format!("unexpected {c:?} after a quoted cell"));
+                } else if c == '"' && cell.is_empty() {
+                    quoted = true;
+                } else {
+                    cell.push(c);
+                }
+            }
+            if !quoted {
+                cells.push(cell);
+                break;
+            }
+        }
+        Some(Ok(Row {
+            line: start.expect("a row was read"),
+            cells: match error {
+                Some(error) => Err(error),
+                None => Ok(cells),
+            },
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fields::{FieldData, FieldKind};
+
+    const BOOKS: &str = "id,title,year,rating\n\
+        dune,Dune,1965,4.5\n\
+        emma,\"Emma, a novel\",1815,\n\
+        \n\
+        quote,\"\"\"Hi\"\" and\nbye\",2001,3\n\
+        short,Short\n\
+        bad,Bad,long ago,1\n\
+        open,\"never closed,1,1\n";
+
+    #[test]
+    fn split_rows() {
+        let rows = Rows::new(BOOKS.as_bytes(), ',')
+            .map(Result::unwrap)
+            .map(|row| (row.line, row.cells))
+            .collect::<Vec<_>>();
+        assert_eq!(rows.len(), 7);
+        assert_eq!(rows[2].0, 3);
+        assert_eq!(
+            rows[2].1.as_ref().unwrap(),
+            &["emma", "Emma, a novel", "1815", ""]
+        );
+        assert_eq!(rows[3].0, 5);
+        assert_eq!(rows[3].1.as_ref().unwrap()[1], "\"Hi\" and\nbye");
+        assert_eq!(rows[6].1, Err("unterminated quoted cell".to_string()));
+
+        let rows = Rows::new("a\t\"b\"c\n".as_bytes(), '\t')
+            .map(Result::unwrap)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rows[0].cells,
+            Err("unexpected 'c' after a quoted cell".to_string())
+        );
+    }
+
+    #[test]
+    fn import_rows() {
+        let schema = infer_schema(BOOKS.as_bytes(), &CsvOptions::new(), 3).unwrap();
+        let kinds = schema
+            .iter()
+            .map(|field| (field.name.as_str(), field.kind.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                ("id", FieldKind::Keyword(16)),
+                ("title", FieldKind::Text(16)),
+                ("year", FieldKind::I64),
+                ("rating", FieldKind::F64),
+            ]
+        );
+
+        let records = Csv::new(BOOKS.as_bytes(), schema.clone(), CsvOptions::new())
+            .unwrap()
+            .collect::<Vec<_>>();
+        let lines = records.iter().map(|record| record.line).collect::<Vec<_>>();
+        assert_eq!(lines, [2, 3, 5, 7, 8, 9]);
+        let emma = records[1].document.as_ref().unwrap();
+        assert_eq!(emma.get("year").unwrap().data(), [FieldData::I64(1815)]);
+        assert!(emma.get("rating").is_none());
+        let errors = records[3..]
+            .iter()
+            .map(|record| record.document.as_ref().unwrap_err().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(errors[0], "malformed row: expected 4 cells, found 2");
+        assert_eq!(errors[1], "field \"year\" can not hold \"long ago\"");
+        assert_eq!(errors[2], "malformed row: unterminated quoted cell");
+
+        let options = CsvOptions::new().with_malformed_rows(MalformedRows::Skip);
+        let records = Csv::new(BOOKS.as_bytes(), schema.clone(), options).unwrap();
+        assert_eq!(records.count(), 4);
+
+        let options = CsvOptions::new().with_malformed_rows(MalformedRows::Abort);
+        let mut records = Csv::new(BOOKS.as_bytes(), schema.clone(), options).unwrap();
+        assert_eq!(records.by_ref().count(), 4);
+        assert_eq!(records.aborted(), Some(7));
+
+        let error = Csv::new("id,pages\n".as_bytes(), schema, CsvOptions::new()).unwrap_err();
+        assert_eq!(error.to_string(), "unknown field \"pages\"");
+    }
+
+    #[test]
+    fn import_without_headers() {
+        let tsv = "1\tthe quick fox\n-2\tjumps\n";
+        let options = CsvOptions::tsv().with_headers(false);
+        let schema = infer_schema(tsv.as_bytes(), &options, 10).unwrap();
+        assert_eq!(schema["column_1"].kind, FieldKind::I64);
+        assert_eq!(schema["column_2"].kind, FieldKind::Text(16));
+        let records = Csv::new(tsv.as_bytes(), schema, options)
+            .unwrap()
+            .collect::<Vec<_>>();
+        let jumps = records[1].document.as_ref().unwrap();
+        assert_eq!(jumps.get("column_1").unwrap().data(), [FieldData::I64(-2)]);
+    }
+}
@@ -0,0 +1,233 @@
+//! Imports json lines files, also known as ndjson, which hold a json object per line. Nested
+//! objects are flattened, so `{"author": {"name": "Frank Herbert"}}` sets the `author.name`
+//! field. Arrays set every value of a field, and `null` leaves it unset.
+
+use std::io::{self, BufRead};
+
+use serde_json::{Map, Value};
+
+use crate::document::Document;
+use crate::fields::{Field, FieldData, FieldKind, Fields};
+use crate::import::{parse_text, ImportError, Inferred, Record, SchemaInference};
+use crate::schema::Schema;
+
+/// Reads the records of a json lines file one line at a time. Blank lines are skipped.
+#[derive(Debug)]
+pub struct JsonLines<R> {
+    lines: io::Lines<R>,
+    schema: Schema,
+    line: usize,
+    failed: bool,
+}
+
+impl<R: BufRead> JsonLines<R> {
+    /// Reads records converted into documents of the given schema
+    pub fn new(reader: R, schema: Schema) -> Self {
+        Self {
+            lines: reader.lines(),
+            schema,
+            line: 0,
+            failed: false,
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for JsonLines<R> {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        loop {
+            self.line += 1;
+            let text = match self.lines.next()? {
+                Ok(text) => text,
+                Err(e) => {
+                    // the rest of the file can not be read either
+                    self.failed = true;
+                    return Some(Record {
+                        line: self.line,
+                        document: Err(e.into()),
+                    });
+                }
+            };
+            if text.trim().is_empty() {
+                continue;
+            }
+            let document = serde_json::from_str(&text)
+                .map_err(ImportError::from)
+                .and_then(|value| document_from_json(value, &self.schema));
+            return Some(Record {
+                line: self.line,
+                document,
+            });
+        }
+    }
+}
+
+/// Converts a json object into a document of the given schema
+pub fn document_from_json(value: Value, schema: &Schema) -> Result<Document, ImportError> {
+    let Value::Object(object) = value else {
+        return Err(ImportError::NotAnObject);
+    };
+    let mut fields = Fields::new();
+    for (name, value) in flatten(object) {
+        let kind = &schema
+            .get(&name)
+            .ok_or_else(|| ImportError::UnknownField(name.clone()))?
+            .kind;
+        let values = match value {
+            Value::Array(values) => values,
+            value => vec![value],
+        };
+        let data = values
+            .iter()
+            .filter(|value| !value.is_null())
+            .map(|value| field_data(&name, kind, value))
+            .collect::<Result<Vec<_>, _>>()?;
+        if !data.is_empty() {
+            fields.insert(&name, Field::new(kind.clone(), data));
+        }
+    }
+    Ok(Document::from(fields))
+}
+
+/// Infers a schema from up to `limit` records of a json lines file. Lines that are not json
+/// objects are skipped.
+pub fn infer_schema<R: BufRead>(reader: R, limit: usize) -> io::Result<Schema> {
+    let mut inference = SchemaInference::new();
+    let mut records = 0;
+    for line in reader.lines() {
+        if records == limit {
+            break;
+        }
+        let Ok(Value::Object(object)) = serde_json::from_str(&line?) else {
+            continue;
+        };
+        records += 1;
+        for (name, value) in flatten(object) {
+            let values = match value {
+                Value::Array(values) => values,
+                value => vec![value],
+            };
+            for value in values {
+                let text_len = match &value {
+                    Value::String(text) => text.len(),
+                    value => value.to_string().len(),
+                };
+                let kind = match &value {
+                    Value::String(text) => Inferred::from_text(text),
+                    Value::Number(number) if number.is_i64() => Inferred::I64,
+                    Value::Number(number) if number.is_u64() => Inferred::U64,
+                    Value::Number(_) => Inferred::F64,
+                    Value::Bool(_) => Inferred::Keyword(text_len),
+                    Value::Null | Value::Array(_) | Value::Object(_) => continue,
+                };
+                inference.observe(&name, kind, text_len);
+            }
+        }
+    }
+    Ok(inference.schema())
+}
+
+/// Flattens nested objects into fields named by the path to them, joined by dots
+fn flatten(object: Map<String, Value>) -> Vec<(String, Value)> {
+    fn flatten_into(
+        prefix: Option<&str>,
+        object: Map<String, Value>,
+        out: &mut Vec<(String, Value)>,
+    ) {
+        for (key, value) in object {
+            let name = match prefix {
+                Some(prefix) => format!("{prefix}.{key}"),
+                None => key,
+            };
+            match value {
+                Value::Object(object) => flatten_into(Some(&name), object, out),
+                value => out.push((name, value)),
+            }
+        }
+    }
+
+    let mut flattened = vec![];
+    flatten_into(None, object, &mut flattened);
+    flattened
+}
+
+/// Converts a json value into the kind of its field
+fn field_data(field: &str, kind: &FieldKind, value: &Value) -> Result<FieldData, ImportError> {
+    let invalid = || ImportError::InvalidValue {
+        field: field.to_string(),
+        value: value.to_string(),
+    };
+    Ok(match (kind, value) {
+        (kind, Value::String(text)) => parse_text(field, kind, text)?,
+        (FieldKind::Keyword(_) | FieldKind::Text(_), Value::Number(_) | Value::Bool(_)) => {
+            FieldData::Bytes(value.to_string().as_bytes().into())
+        }
+        (FieldKind::I64, Value::Number(number)) => {
+            FieldData::I64(number.as_i64().ok_or_else(invalid)?)
+        }
+        (FieldKind::U64, Value::Number(number)) => {
+            FieldData::U64(number.as_u64().ok_or_else(invalid)?)
+        }
+        (FieldKind::F64 | FieldKind::Number(_), Value::Number(number)) => {
+            FieldData::F64(number.as_f64().ok_or_else(invalid)?)
+        }
+        _ => return Err(invalid()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOOKS: &str = r#"{"id": "dune", "title": "Dune", "year": 1965, "author": {"name": "Frank Herbert"}}
+{"id": "emma", "title": "Emma", "year": 1815, "tags": ["classic", "romance"]}
+
+{"id": "bad", "year": "long ago"}
+not json
+{"id": "lost", "pages": 300}
+"#;
+
+    #[test]
+    fn import_lines() {
+        let schema = infer_schema(BOOKS.as_bytes(), 2).unwrap();
+        let kinds = schema
+            .iter()
+            .map(|field| (field.name.as_str(), field.kind.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            kinds,
+            [
+                ("author.name", FieldKind::Text(16)),
+                ("id", FieldKind::Keyword(16)),
+                ("title", FieldKind::Keyword(16)),
+                ("year", FieldKind::I64),
+                ("tags", FieldKind::Keyword(16)),
+            ]
+        );
+
+        let records = JsonLines::new(BOOKS.as_bytes(), schema).collect::<Vec<_>>();
+        let lines = records.iter().map(|record| record.line).collect::<Vec<_>>();
+        assert_eq!(lines, [1, 2, 4, 5, 6]);
+
+        let dune = records[0].document.as_ref().unwrap();
+        assert_eq!(
+            dune.get("author.name").unwrap().data(),
+            [FieldData::Bytes(b"Frank Herbert".as_slice().into())]
+        );
+        assert_eq!(dune.get("year").unwrap().data(), [FieldData::I64(1965)]);
+        let emma = records[1].document.as_ref().unwrap();
+        assert_eq!(emma.get("tags").unwrap().data().len(), 2);
+
+        let errors = records[2..]
+            .iter()
+            .map(|record| record.document.as_ref().unwrap_err().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(errors[0], "field \"year\" can not hold \"long ago\"");
+        assert!(errors[1].starts_with("invalid json"), "{}", errors[1]);
+        assert_eq!(errors[2], "unknown field \"pages\"");
+    }
+}
@@ -0,0 +1,224 @@
+//! Imports parquet files, one row at a time. Groups are flattened like they are in
+//! [json lines](super::json_lines) files, so the `name` column of an `author` group sets the
+//! `author.name` field. Lists set every value of a field, and nulls leave it unset. Maps can not
+//! be imported.
+//!
+//! Integers are converted into `i64` fields, or `u64` fields if they are unsigned 64-bit
+//! integers, floats and decimals into `f64` fields, strings into keyword and text fields, and
+//! other binary data into blobs. Dates and timestamps are kept as the integer parquet stores
+//! them as, the days or the milli- or microseconds since the unix epoch.
+
+use std::fmt::{Debug, Formatter};
+use std::fs::File;
+
+use parquet::file::reader::SerializedFileReader;
+use parquet::record::reader::RowIter;
+use parquet::record::{Field as Value, Row};
+
+use crate::document::Document;
+use crate::fields::{Field, FieldData, FieldKind, Fields};
+use crate::import::{parse_text, ImportError, Inferred, Record, SchemaInference};
+use crate::schema::Schema;
+
+/// Reads the records of a parquet file one row at a time
+pub struct Parquet {
+    rows: RowIter<'static>,
+    schema: Schema,
+    row: usize,
+    failed: bool,
+}
+
+impl Parquet {
+    /// Reads records converted into documents of the given schema. The footer of the file, which
+    /// describes its columns, is read right away.
+    pub fn new(file: File, schema: Schema) -> Result<Self, ImportError> {
+        let reader = SerializedFileReader::new(file)?;
+        Ok(Self {
+            rows: RowIter::from_file_into(Box::new(reader)),
+            schema,
+            row: 0,
+            failed: false,
+        })
+    }
+}
+
+impl Debug for Parquet {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Parquet")
+            .field("schema", &self.schema)
+            .field("row", &self.row)
+            .field("failed", &self.failed)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Iterator for Parquet {
+    type Item = Record;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        self.row += 1;
+        let document = match self.rows.next()? {
+            Ok(row) => document_from_row(row, &self.schema),
+            Err(e) => {
+                // the rest of the file can not be read either
+                self.failed = true;
+                Err(e.into())
+            }
+        };
+        Some(Record {
+            line: self.row,
+            document,
+        })
+    }
+}
+
+/// Converts a row of a parquet file into a document of the given schema
+pub fn document_from_row(row: Row, schema: &Schema) -> Result<Document, ImportError> {
+    let mut fields = Fields::new();
+    for (name, values) in flatten(row)? {
+        let values = values
+            .into_iter()
+            .filter(|value| !matches!(value, Value::Null))
+            .collect::<Vec<_>>();
+        if values.is_empty() {
+            continue;
+        }
+        let kind = &schema
+            .get(&name)
+            .ok_or_else(|| ImportError::UnknownField(name.clone()))?
+            .kind;
+        let data = values
+            .iter()
+            .map(|value| field_data(&name, kind, value))
+            .collect::<Result<Vec<_>, _>>()?;
+        fields.insert(&name, Field::new(kind.clone(), data));
+    }
+    Ok(Document::from(fields))
+}
+
+/// Infers a schema from up to `limit` rows of a parquet file
+pub fn infer_schema(file: File, limit: usize) -> Result<Schema, ImportError> {
+    let reader = SerializedFileReader::new(file)?;
+    let mut inference = SchemaInference::new();
+    for row in RowIter::from_file_into(Box::new(reader)).take(limit) {
+        for (name, values) in flatten(row?)? {
+            for value in values {
+                let text_len = match &value {
+                    Value::Str(text) => text.len(),
+                    value => value.to_string().len(),
+                };
+                let kind = match &value {
+                    Value::Str(text) => Inferred::from_text(text),
+                    Value::Bool(_) => Inferred::Keyword(text_len),
+                    Value::Byte(_)
+                    | Value::Short(_)
+                    | Value::Int(_)
+                    | Value::Long(_)
+                    | Value::UByte(_)
+                    | Value::UShort(_)
+                    | Value::UInt(_)
+                    | Value::Date(_)
+                    | Value::TimestampMillis(_)
+                    | Value::TimestampMicros(_) => Inferred::I64,
+                    Value::ULong(_) => Inferred::U64,
+                    Value::Float16(_) | Value::Float(_) | Value::Double(_) | Value::Decimal(_) => {
+                        Inferred::F64
+                    }
+                    Value::Bytes(_) => Inferred::Blob,
+                    Value::Null
+                    | Value::Group(_)
+                    | Value::ListInternal(_)
+                    | Value::MapInternal(_) => continue,
+                };
+                inference.observe(&name, kind, text_len);
+            }
+        }
+    }
+    Ok(inference.schema())
+}
+
+/// Flattens the groups of a row into fields named by the path to them, joined by dots, holding
+/// every value of their lists
+fn flatten(row: Row) -> Result<Vec<(String, Vec<Value>)>, ImportError> {
+    fn flatten_into(
+        prefix: Option<&str>,
+        row: Row,
+        out: &mut Vec<(String, Vec<Value>)>,
+    ) -> Result<(), ImportError> {
+        for (column, value) in row.into_columns() {
+            let name = match prefix {
+                Some(prefix) => format!("{prefix}.{column}"),
+                None => column,
+            };
+            match value {
+                Value::Group(group) => flatten_into(Some(&name), group, out)?,
+                Value::ListInternal(list) => {
+                    out.push((name, list.elements().to_vec()));
+                }
+                Value::MapInternal(_) => {
+                    return Err(ImportError::InvalidValue {
+                        field: name,
+                        value: "a map".to_string(),
+                    })
+                }
+                value => out.push((name, vec![value])),
+            }
+        }
+        Ok(())
+    }
+
+    let mut flattened = vec![];
+    flatten_into(None, row, &mut flattened)?;
+    Ok(flattened)
+}
+
+/// Converts a parquet value into the kind of its field
+fn field_data(field: &str, kind: &FieldKind, value: &Value) -> Result<FieldData, ImportError> {
+    let invalid = || ImportError::InvalidValue {
+        field: field.to_string(),
+        value: value.to_string(),
+    };
+    let integer = match value {
+        Value::Byte(i) => Some(i64::from(*i)),
+        Value::Short(i) => Some(i64::from(*i)),
+        Value::Int(i) | Value::Date(i) => Some(i64::from(*i)),
+        Value::Long(i) | Value::TimestampMillis(i) | Value::TimestampMicros(i) => Some(*i),
+        Value::UByte(i) => Some(i64::from(*i)),
+        Value::UShort(i) => Some(i64::from(*i)),
+        Value::UInt(i) => Some(i64::from(*i)),
+        _ => None,
+    };
+    Ok(match (kind, value) {
+        (kind, Value::Str(text)) => parse_text(field, kind, text)?,
+        (FieldKind::Blob, Value::Bytes(bytes)) => FieldData::Blob(bytes.data().into()),
+        (FieldKind::Keyword(_) | FieldKind::Text(_), Value::Bytes(_))
+        | (_, Value::Group(_) | Value::ListInternal(_) | Value::MapInternal(_)) => {
+            return Err(invalid())
+        }
+        (FieldKind::Keyword(_) | FieldKind::Text(_), value) => {
+            FieldData::Bytes(value.to_string().as_bytes().into())
+        }
+        (FieldKind::I64, Value::ULong(i)) => {
+            FieldData::I64(i64::try_from(*i).map_err(|_| invalid())?)
+        }
+        (FieldKind::I64, _) => FieldData::I64(integer.ok_or_else(invalid)?),
+        (FieldKind::U64, Value::ULong(i)) => FieldData::U64(*i),
+        (FieldKind::U64, _) => {
+            let integer = integer.ok_or_else(invalid)?;
+            FieldData::U64(u64::try_from(integer).map_err(|_| invalid())?)
+        }
+        (FieldKind::F64 | FieldKind::Number(_), value) => FieldData::F64(match value {
+            Value::Float16(f) => f64::from(*f),
+            Value::Float(f) => f64::from(*f),
+            Value::Double(f) => *f,
+            Value::ULong(i) => *i as f64,
+            // decimals are displayed exactly
+            Value::Decimal(_) => value.to_string().parse().map_err(|_| invalid())?,
+            _ => integer.ok_or_else(invalid)? as f64,
+        }),
+        _ => return Err(invalid()),
+    })
+}
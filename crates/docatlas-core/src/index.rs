@@ -1,9 +1,1199 @@
+//! An index stores the documents of a schema as rows
+
+use std::collections::HashMap;
+use std::io;
+use std::ops::Range;
 use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::blob::{BlobSegment, Blobs, StagedBlobs};
+use crate::cancel::{CancelToken, Cancelled};
+use crate::codec::{encode_cell, RowDecodeError, RowEncodeError};
+use crate::document::{Document, DocumentData};
+use crate::fields::{FieldData, Fields};
+use crate::ingest::{Pipeline, PipelineError};
+use crate::persist::PersistentVec;
+use crate::query::arena::QueryArena;
+use crate::query::cache::FilterCache;
+use crate::query::regexp::Regexp;
+use crate::schema::Schema;
+
+use flush::WriteBuffer;
+use pinned::PinnedSearchers;
+use searcher::Commits;
 
-use crate::fields::Fields;
+pub use dedup::{DedupMode, Deduplication, FingerprintSlot, Fingerprinted};
+pub use doc_values::{Column, ColumnEncoding, DocValues, DocValuesError};
+pub use flush::{FlushPolicy, IngestStats, DEFAULT_FLUSH_LATENCY, DEFAULT_WRITE_BUFFER_BUDGET};
+pub use pinned::DEFAULT_PIN_TTL;
+pub use postings::{PackedPostings, Postings};
+pub use routing::Router;
+pub use searcher::{IndexReader, Searcher, SEGMENT_ROWS};
+pub use transaction::{Prepared, Transaction, Write, Written};
+
+mod dedup;
+mod doc_values;
+mod flush;
+mod pinned;
+mod postings;
+mod routing;
+mod searcher;
+mod transaction;
+
+/// The number of documents encoded per batch when adding documents in bulk, until the writer has
+/// seen how fast documents arrive
+pub const BULK_BATCH_SIZE: usize = 1024;
 
 #[derive(Debug)]
 pub struct Index {
     path: PathBuf,
     documents: Vec<(Vec<Fields>,)>,
 }
+
+/// Writes documents into the rows of an index.
+///
+/// Rows are keyed by the primary key of the schema, if one is set. Every document is run through
+/// the ingest pipeline of the writer before being written. Documents containing blobs can only be
+/// written once the writer has a blob segment. Every write invalidates the filter cache of the
+/// writer.
+///
+/// Searches made through the writer see every write as soon as it is made, so they can not run
+/// while it writes. A [`Searcher`] acquired from the writer searches the index as it was when it
+/// was acquired instead, and can keep searching while the writer writes.
+#[derive(Debug)]
+pub struct IndexWriter {
+    schema: Schema,
+    rows: PersistentVec<u8>,
+    blobs: Option<BlobSegment>,
+    primary_keys: HashMap<Box<[u8]>, usize>,
+    postings: Postings,
+    pipeline: Pipeline,
+    dedup: Option<Deduplication>,
+    filter_cache: FilterCache,
+    write_buffer: WriteBuffer,
+    /// The number of writes made by the writer
+    generation: u64,
+    commits: Commits,
+    pinned: PinnedSearchers,
+}
+
+impl IndexWriter {
+    /// Creates a new index writer over a set of rows. Rows that are already present are keyed by
+    /// their primary key and added to the postings.
+    pub fn new(schema: Schema, rows: PersistentVec<u8>) -> Self {
+        let mut primary_keys = HashMap::new();
+        if let Some(range) = schema
+            .primary_key()
+            .and_then(|field| schema.field_range(&field.name))
+        {
+            for (index, row) in rows.chunks_exact(schema.row_size()).enumerate() {
+                primary_keys.insert(Box::from(&row[range.clone()]), index);
+            }
+        }
+        let mut postings = Postings::new();
+        postings.insert_rows(&schema, 0, &rows);
+
+        Self {
+            schema,
+            rows,
+            blobs: None,
+            primary_keys,
+            postings,
+            pipeline: Pipeline::default(),
+            dedup: None,
+            filter_cache: FilterCache::default(),
+            write_buffer: WriteBuffer::default(),
+            generation: 0,
+            commits: Commits::default(),
+            pinned: PinnedSearchers::default(),
+        }
+    }
+
+    /// Sets the duplicate detection of the writer. Rows already written are expected to have
+    /// their fingerprints persisted within the given deduplication.
+    pub fn with_deduplication(mut self, dedup: Deduplication) -> Self {
+        self.dedup = Some(dedup);
+        self
+    }
+
+    /// Gets the duplicate detection of the writer, if one is set
+    pub fn deduplication(&self) -> Option<&Deduplication> {
+        self.dedup.as_ref()
+    }
+
+    /// Sets the blob segment that the blobs of the rows are stored in
+    pub fn with_blob_segment(mut self, blobs: BlobSegment) -> Self {
+        self.blobs = Some(blobs);
+        self
+    }
+
+    /// Gets the blob segment of the writer, if one is set
+    pub fn blobs(&self) -> Option<&BlobSegment> {
+        self.blobs.as_ref()
+    }
+
+    /// Sets the ingest pipeline documents are run through before being written
+    pub fn with_pipeline(mut self, pipeline: Pipeline) -> Self {
+        self.pipeline = pipeline;
+        self
+    }
+
+    /// Gets the ingest pipeline of the writer
+    pub fn pipeline(&self) -> &Pipeline {
+        &self.pipeline
+    }
+
+    /// Sets the cache of the rows matched by the filter clauses of queries against the index
+    pub fn with_filter_cache(mut self, filter_cache: FilterCache) -> Self {
+        self.filter_cache = filter_cache;
+        self
+    }
+
+    /// Gets the filter cache of the writer
+    pub fn filter_cache(&self) -> &FilterCache {
+        &self.filter_cache
+    }
+
+    /// Sets how many documents added in bulk are buffered before they are written
+    pub fn with_flush_policy(mut self, policy: FlushPolicy) -> Self {
+        self.write_buffer.policy = policy;
+        self
+    }
+
+    /// Gets the flush policy of the writer
+    pub fn flush_policy(&self) -> FlushPolicy {
+        self.write_buffer.policy
+    }
+
+    /// Gets what the writer observed of the documents added to it in bulk
+    pub fn ingest_stats(&self) -> IngestStats {
+        self.write_buffer.stats
+    }
+
+    /// Gets the schema of the index
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    /// Gets the number of rows in the index
+    pub fn len(&self) -> usize {
+        self.rows.len() / self.schema.row_size()
+    }
+
+    /// Checks if the index contains no rows
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Writes any changes of the rows, blobs and fingerprints of the index to the files backing
+    /// them
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub fn flush(&self) -> io::Result<()> {
+        self.rows.flush()?;
+        if let Some(blobs) = &self.blobs {
+            blobs.flush()?;
+        }
+        if let Some(dedup) = &self.dedup {
+            dedup.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Gets the raw bytes of a row, if present
+    pub fn row(&self, index: usize) -> Option<&[u8]> {
+        let row_size = self.schema.row_size();
+        self.rows.get(index * row_size..(index + 1) * row_size)
+    }
+
+    /// Gets the data of the document stored in a row, if present
+    pub fn document(&self, index: usize) -> Option<DocumentData<'_>> {
+        self.row(index).map(DocumentData::new)
+    }
+
+    /// Decodes the document stored in a row, including any blobs it references
+    pub fn read(&self, index: usize) -> Option<Result<Document, RowDecodeError>> {
+        let codec = self.schema.codec();
+        self.row(index).map(|row| match &self.blobs {
+            Some(blobs) => codec.decode_with_blobs(row, blobs),
+            None => codec.decode(row),
+        })
+    }
+
+    /// Builds the doc values of the keyword and text fields of every row, encoding each field as
+    /// given
+    pub fn doc_values(&self, encodings: &HashMap<String, ColumnEncoding>) -> DocValues {
+        DocValues::build(&self.schema, &self.rows, encodings)
+    }
+
+    /// Gets the postings of the index
+    pub fn postings(&self) -> &Postings {
+        &self.postings
+    }
+
+    /// Adds documents in bulk, returning the row of every added document in the order they were
+    /// given. Documents dropped by the ingest pipeline have no row.
+    ///
+    /// Documents are encoded in batches sized by the [flush policy](FlushPolicy) of the writer,
+    /// with each batch being written to the rows and postings at once. A document that can not be added does not
+    /// prevent the other documents from being added, including documents whose primary key
+    /// already exists.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub fn add_documents<I: IntoIterator<Item = Document>>(
+        &mut self,
+        documents: I,
+    ) -> Vec<Result<Option<usize>, IndexWriterError>> {
+        let row_size = self.schema.row_size();
+        let mut documents = documents.into_iter();
+        let (lower, _) = documents.size_hint();
+        self.reserve(lower);
+
+        let mut results = Vec::with_capacity(lower);
+        let mut batch = vec![];
+        loop {
+            let batch_rows = self.write_buffer.batch_rows(row_size);
+            self.reserve(batch_rows);
+            batch.clear();
+            batch.reserve(batch_rows.min(lower.max(1)) * row_size);
+            let first_row = self.len();
+            let blob_bytes = self.blobs.as_ref().map_or(0, BlobSegment::len);
+            let started = Instant::now();
+            let mut taken = 0;
+            for document in documents.by_ref().take(batch_rows) {
+                taken += 1;
+                let document = match self.pipeline.apply(document) {
+                    Ok(Some(document)) => document,
+                    Ok(None) => {
+                        results.push(Ok(None));
+                        continue;
+                    }
+                    Err(e) => {
+                        results.push(Err(e.into()));
+                        continue;
+                    }
+                };
+                let start = batch.len();
+                batch.resize(start + row_size, 0);
+                let result = encode(
+                    &self.schema,
+                    self.blobs.is_some(),
+                    &document,
+                    &mut batch[start..],
+                )
+                .map_err(IndexWriterError::from)
+                .and_then(|blobs| {
+                    self.check_duplicate(&batch[start..], None)
+                        .map(|fingerprint| (blobs, fingerprint))
+                })
+                .and_then(|(blobs, fingerprint)| {
+                    self.claim_primary_key(&document, &batch[start..], first_row + start / row_size)
+                        .map(|()| (blobs, fingerprint))
+                });
+                match result {
+                    Ok((blobs, fingerprint)) => {
+                        let row = first_row + start / row_size;
+                        self.append_blobs(blobs, &mut batch[start..]);
+                        self.record_fingerprint(fingerprint, row);
+                        results.push(Ok(Some(row)));
+                    }
+                    Err(e) => {
+                        batch.truncate(start);
+                        results.push(Err(e));
+                    }
+                }
+            }
+            if taken == 0 {
+                break;
+            }
+            let blob_bytes = self.blobs.as_ref().map_or(0, BlobSegment::len) - blob_bytes;
+            self.write_buffer
+                .record(taken, batch.len() + blob_bytes, started.elapsed());
+
+            self.rows.extend_from_slice(&batch);
+            self.postings.insert_rows(&self.schema, first_row, &batch);
+            self.touch(first_row..self.len());
+        }
+        results
+    }
+
+    /// Gets a searcher over the documents written so far, which keeps searching them as they are
+    /// now while the writer keeps writing. Searchers acquired between the same writes share their
+    /// commit point.
+    pub fn searcher(&mut self) -> Searcher {
+        let mut commits = std::mem::take(&mut self.commits);
+        let searcher = commits.commit(self, self.generation);
+        self.commits = commits;
+        searcher
+    }
+
+    /// Sets how long a [pinned](Self::pin) searcher stays pinned without being used
+    pub fn with_pin_ttl(mut self, ttl: Duration) -> Self {
+        self.pinned.ttl = ttl;
+        self
+    }
+
+    /// Gets how long a pinned searcher stays pinned without being used
+    pub fn pin_ttl(&self) -> Duration {
+        self.pinned.ttl
+    }
+
+    /// Gets a searcher over the documents written so far, pinned at its sequence number, which is
+    /// its [generation](Searcher::generation). Reads at that sequence number get the same searcher
+    /// from [`pinned`](Self::pinned) until it goes unused for the pin ttl.
+    pub fn pin(&mut self) -> Searcher {
+        let searcher = self.searcher();
+        self.pinned.pin(searcher.clone());
+        searcher
+    }
+
+    /// Gets the searcher pinned at a sequence number, unless it was unpinned
+    pub fn pinned(&mut self, sequence: u64) -> Option<Searcher> {
+        self.pinned.get(sequence)
+    }
+
+    /// Makes room for the rows and blobs of the given number of documents, unless there is enough
+    /// room already
+    fn reserve(&mut self, documents: usize) {
+        let row_size = self.schema.row_size();
+        let spare = self.rows.capacity().saturating_sub(self.rows.len());
+        if documents * row_size >= spare {
+            self.rows.reserve(documents * row_size - spare + 1);
+        }
+        let blob_bytes = self.write_buffer.blob_bytes(documents, row_size);
+        if let Some(blobs) = &mut self.blobs {
+            blobs.reserve(blob_bytes);
+        }
+    }
+
+    /// Records a write to the given rows, which invalidates the filter cache and the rows of the
+    /// last commit point
+    fn touch(&mut self, rows: Range<usize>) {
+        self.generation += 1;
+        self.filter_cache.invalidate();
+        self.commits.touch(rows);
+    }
+
+    /// Checks that an encoded row is not a duplicate, returning its fingerprint if duplicates are
+    /// being detected
+    fn check_duplicate(
+        &self,
+        row: &[u8],
+        updating: Option<usize>,
+    ) -> Result<Option<u64>, IndexWriterError> {
+        self.dedup
+            .as_ref()
+            .map(|dedup| dedup.check(&self.schema, row, updating))
+            .transpose()
+    }
+
+    /// Appends the staged blobs of an accepted row to the blob segment
+    fn append_blobs(&mut self, blobs: StagedBlobs, row: &mut [u8]) {
+        if let Some(segment) = &mut self.blobs {
+            blobs.append_to(segment, row);
+        }
+    }
+
+    fn record_fingerprint(&mut self, fingerprint: Option<u64>, row: usize) {
+        if let (Some(dedup), Some(fingerprint)) = (&mut self.dedup, fingerprint) {
+            dedup.record(fingerprint, row);
+        }
+    }
+
+    /// Registers the primary key of an encoded row, if the schema has a primary key
+    fn claim_primary_key(
+        &mut self,
+        document: &Document,
+        row: &[u8],
+        index: usize,
+    ) -> Result<(), IndexWriterError> {
+        let Some(primary_key) = self.schema.primary_key() else {
+            return Ok(());
+        };
+        if document
+            .get(&primary_key.name)
+            .is_none_or(|field| field.data().is_empty())
+        {
+            return Err(IndexWriterError::MissingPrimaryKey(
+                primary_key.name.clone(),
+            ));
+        }
+        let range = self
+            .schema
+            .field_range(&primary_key.name)
+            .expect("primary key is part of the schema");
+        let key = Box::from(&row[range]);
+        if self.primary_keys.contains_key(&key) {
+            return Err(IndexWriterError::DuplicatePrimaryKey(
+                primary_key.name.clone(),
+            ));
+        }
+        self.primary_keys.insert(key, index);
+        Ok(())
+    }
+
+    /// Inserts the document if no row shares its primary key, otherwise applies the document to
+    /// the existing row according to the given mode.
+    #[tracing::instrument(level = "debug", skip_all, fields(?mode))]
+    pub fn upsert(
+        &mut self,
+        document: Document,
+        mode: UpsertMode,
+    ) -> Result<Upserted, IndexWriterError> {
+        let Some(document) = self.pipeline.apply(document)? else {
+            return Ok(Upserted::Dropped);
+        };
+        let (mut row, key, blobs) = self.encode_keyed(&document)?;
+        let row_size = self.schema.row_size();
+
+        match self.primary_keys.get(&key) {
+            Some(&index) => {
+                if let UpsertMode::Merge = mode {
+                    let stored = self.row(index).expect("keyed rows exist");
+                    row = self.merge_cells(&document, stored, &row);
+                }
+                let fingerprint = self.check_duplicate(&row, Some(index))?;
+                self.append_blobs(blobs, &mut row);
+                let stored = &mut self.rows[index * row_size..(index + 1) * row_size];
+                if let Some(dedup) = &mut self.dedup {
+                    dedup.forget(dedup.fingerprint(&self.schema, stored), index);
+                }
+                self.postings.remove_row(&self.schema, index, stored);
+                stored.copy_from_slice(&row);
+                self.postings.insert_row(&self.schema, index, &row);
+                self.record_fingerprint(fingerprint, index);
+                self.touch(index..index + 1);
+                Ok(Upserted::Updated(index))
+            }
+            None => {
+                let fingerprint = self.check_duplicate(&row, None)?;
+                self.append_blobs(blobs, &mut row);
+                let index = self.len();
+                self.rows.extend_from_slice(&row);
+                self.postings.insert_row(&self.schema, index, &row);
+                self.primary_keys.insert(key, index);
+                self.record_fingerprint(fingerprint, index);
+                self.touch(index..index + 1);
+                Ok(Upserted::Inserted(index))
+            }
+        }
+    }
+}
+
+/// A row, along with the cell of its primary key and the blobs staged for it
+type KeyedRow = (Vec<u8>, Box<[u8]>, StagedBlobs);
+
+impl IndexWriter {
+    /// Encodes a document into a row, returning the row along with its primary key cell and its
+    /// staged blobs. The schema must have a primary key, which the document must have a value for.
+    fn encode_keyed(&self, document: &Document) -> Result<KeyedRow, IndexWriterError> {
+        let primary_key = self
+            .schema
+            .primary_key()
+            .ok_or(IndexWriterError::NoPrimaryKey)?;
+        if document
+            .get(&primary_key.name)
+            .is_none_or(|field| field.data().is_empty())
+        {
+            return Err(IndexWriterError::MissingPrimaryKey(
+                primary_key.name.clone(),
+            ));
+        }
+        let key_range = self
+            .schema
+            .field_range(&primary_key.name)
+            .expect("primary key is part of the schema");
+
+        let mut row = vec![0_u8; self.schema.row_size()];
+        let blobs = encode(&self.schema, self.blobs.is_some(), document, &mut row)?;
+        let key = Box::from(&row[key_range]);
+        Ok((row, key, blobs))
+    }
+
+    /// Takes the cells of the fields present in a document from its encoded row, and every other
+    /// cell from a stored row
+    fn merge_cells(&self, document: &Document, stored: &[u8], row: &[u8]) -> Vec<u8> {
+        // only the cells of the given fields are taken, so only their staged blobs are appended
+        let mut merged = stored.to_vec();
+        for (name, _) in document.fields().iter() {
+            let range = self
+                .schema
+                .field_range(name)
+                .expect("encoded fields are part of the schema");
+            merged[range.clone()].copy_from_slice(&row[range]);
+        }
+        merged
+    }
+
+    /// Finds the row of the document with the given primary key
+    pub fn find(&self, key: &FieldData) -> Result<Option<usize>, IndexWriterError> {
+        let key = self.key_cell(key)?;
+        Ok(self.primary_keys.get(&key).copied())
+    }
+
+    /// Deletes the document with the given primary key, returning the row it was stored in.
+    ///
+    /// The row is cleared rather than removed, so every following row keeps its index.
+    #[tracing::instrument(level = "debug", skip_all)]
+    pub fn delete(&mut self, key: &FieldData) -> Result<Option<usize>, IndexWriterError> {
+        let key = self.key_cell(key)?;
+        let Some(index) = self.primary_keys.remove(&key) else {
+            return Ok(None);
+        };
+        let row_size = self.schema.row_size();
+        let stored = &mut self.rows[index * row_size..(index + 1) * row_size];
+        if let Some(dedup) = &mut self.dedup {
+            dedup.forget(dedup.fingerprint(&self.schema, stored), index);
+        }
+        self.postings.remove_row(&self.schema, index, stored);
+        stored.fill(0);
+        self.touch(index..index + 1);
+        Ok(Some(index))
+    }
+
+    /// Finds the rows whose field contains every term of a query, in ascending order. Keyword
+    /// fields must match the query as a whole, while text fields are matched word by word.
+    pub fn search(&self, field: &str, query: &str) -> Vec<usize> {
+        self.search_until(field, query, &CancelToken::new())
+            .expect("never cancelled")
+    }
+
+    /// Searches like [`search`](Self::search), stopping early once the token is cancelled
+    pub fn search_until(
+        &self,
+        field: &str,
+        query: &str,
+        cancel: &CancelToken,
+    ) -> Result<Vec<usize>, Cancelled> {
+        match self.search_partial(field, query, cancel) {
+            (rows, true) => Ok(rows),
+            (_, false) => Err(Cancelled),
+        }
+    }
+
+    /// Searches like [`search`](Self::search), stopping early once the token is cancelled.
+    /// Returns the rows matched so far, and whether the search finished.
+    #[tracing::instrument(level = "debug", skip(self, cancel))]
+    pub fn search_partial(
+        &self,
+        field: &str,
+        query: &str,
+        cancel: &CancelToken,
+    ) -> (Vec<usize>, bool) {
+        QueryArena::scoped(|arena| self.search_in(field, query, cancel, arena))
+    }
+
+    /// Searches like [`search_partial`](Self::search_partial), keeping the terms and rows it
+    /// looks up in the arena of the query running the search
+    pub fn search_in(
+        &self,
+        field: &str,
+        query: &str,
+        cancel: &CancelToken,
+        arena: &QueryArena,
+    ) -> (Vec<usize>, bool) {
+        let Some(field) = self.schema.get(field) else {
+            return (vec![], true);
+        };
+        let terms = postings::terms_in(&field.kind, query.as_bytes(), arena);
+        self.postings
+            .intersect_in(&field.name, &terms, cancel, arena)
+    }
+
+    /// Gets up to `limit` rows holding documents in ascending order, starting at row `from`,
+    /// along with the row to continue from unless every row was read. Only the rows matched by a
+    /// search, given as its field and query, are read if there is one. The rows of deleted
+    /// documents are skipped.
+    ///
+    /// Reading every row a page at a time this way sees documents added in the meantime, as
+    /// they are stored past the rows read so far.
+    pub fn scan(
+        &self,
+        search: Option<(&str, &str)>,
+        from: usize,
+        limit: usize,
+    ) -> (Vec<usize>, Option<usize>) {
+        IndexReader::scan(self, search, from, limit)
+    }
+
+    /// Encodes a primary key the same way it is stored in a row
+    fn key_cell(&self, key: &FieldData) -> Result<Box<[u8]>, IndexWriterError> {
+        let primary_key = self
+            .schema
+            .primary_key()
+            .ok_or(IndexWriterError::NoPrimaryKey)?;
+        let mut cell = vec![0_u8; primary_key.kind.size()];
+        encode_cell(&primary_key.kind, key, &mut cell)?;
+        Ok(cell.into_boxed_slice())
+    }
+}
+
+impl IndexReader for IndexWriter {
+    fn schema(&self) -> &Schema {
+        IndexWriter::schema(self)
+    }
+
+    fn len(&self) -> usize {
+        IndexWriter::len(self)
+    }
+
+    fn row(&self, index: usize) -> Option<&[u8]> {
+        IndexWriter::row(self, index)
+    }
+
+    fn blobs(&self) -> Option<&dyn Blobs> {
+        self.blobs.as_ref().map(|blobs| blobs as &dyn Blobs)
+    }
+
+    fn read(&self, index: usize) -> Option<Result<Document, RowDecodeError>> {
+        IndexWriter::read(self, index)
+    }
+
+    fn search_in(
+        &self,
+        field: &str,
+        query: &str,
+        cancel: &CancelToken,
+        arena: &QueryArena,
+    ) -> (Vec<usize>, bool) {
+        IndexWriter::search_in(self, field, query, cancel, arena)
+    }
+
+    fn search_regexp(
+        &self,
+        field: &str,
+        regexp: &Regexp,
+        cancel: &CancelToken,
+    ) -> (Vec<usize>, bool) {
+        self.postings.regexp_rows(field, regexp, cancel)
+    }
+
+    fn filter_cache(&self) -> &FilterCache {
+        IndexWriter::filter_cache(self)
+    }
+}
+
+/// A 64-bit FNV-1a hash, used as it is stable across platforms and releases
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const PRIME: u64 = 0x0000_0100_0000_01b3;
+    bytes.iter().fold(OFFSET_BASIS, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(PRIME)
+    })
+}
+
+/// Encodes a document into a row, staging its blobs if the writer has a blob segment. The blobs
+/// are only appended to the segment once the row is accepted.
+fn encode(
+    schema: &Schema,
+    has_blobs: bool,
+    document: &Document,
+    row: &mut [u8],
+) -> Result<StagedBlobs, RowEncodeError> {
+    let mut blobs = StagedBlobs::new();
+    match has_blobs {
+        true => schema
+            .codec()
+            .encode_with_staged_blobs(document, row, &mut blobs)?,
+        false => schema.codec().encode(document, row)?,
+    }
+    Ok(blobs)
+}
+
+/// How an upsert is applied to an already existing row
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum UpsertMode {
+    /// The existing row is replaced by the document, clearing fields missing from the document
+    #[default]
+    Replace,
+    /// Only the fields present in the document are updated, making it a partial update
+    Merge,
+}
+
+/// The outcome of an upsert, containing the index of the affected row
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Upserted {
+    /// No row shared the primary key, so a new row was inserted
+    Inserted(usize),
+    /// An existing row was updated
+    Updated(usize),
+    /// The document was dropped by the ingest pipeline
+    Dropped,
+}
+
+/// An error occurred writing to an index
+#[derive(Debug, Error)]
+pub enum IndexWriterError {
+    #[error("The schema of the index has no primary key")]
+    NoPrimaryKey,
+    #[error("Document is missing a value for primary key {0:?}")]
+    MissingPrimaryKey(String),
+    #[error("A document with the same value for primary key {0:?} already exists")]
+    DuplicatePrimaryKey(String),
+    #[error("Document is a duplicate of row {0}")]
+    Duplicate(usize),
+    #[error(transparent)]
+    RowEncodeError(#[from] RowEncodeError),
+    #[error(transparent)]
+    PipelineError(#[from] PipelineError),
+    #[error("Write {write} of the transaction failed: {source}")]
+    Transaction {
+        write: usize,
+        source: Box<IndexWriterError>,
+    },
+    #[error("The index was written to after the transaction was prepared")]
+    Stale,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use num_bigfloat::BigFloat;
+
+    use super::*;
+    use crate::fields::{Field, FieldKind};
+    use crate::ingest::Processor;
+    use crate::schema::SchemaField;
+
+    fn schema() -> Schema {
+        Schema::from_iter([
+            SchemaField {
+                name: "id".to_string(),
+                kind: FieldKind::Keyword(8),
+            },
+            SchemaField {
+                name: "count".to_string(),
+                kind: FieldKind::Number(8),
+            },
+            SchemaField {
+                name: "name".to_string(),
+                kind: FieldKind::Text(16),
+            },
+        ])
+        .with_primary_key("id")
+    }
+
+    fn document(fields: &[(&str, FieldKind, FieldData)]) -> Document {
+        fields
+            .iter()
+            .map(|(name, kind, data)| (*name, Field::new(kind.clone(), [data.clone()])))
+            .collect::<Fields>()
+            .into()
+    }
+
+    fn id(id: &str) -> (&'static str, FieldKind, FieldData) {
+        (
+            "id",
+            FieldKind::Keyword(8),
+            FieldData::Bytes(Arc::from(id.as_bytes())),
+        )
+    }
+
+    fn count(count: f64) -> (&'static str, FieldKind, FieldData) {
+        (
+            "count",
+            FieldKind::Number(8),
+            FieldData::Number(BigFloat::from_f64(count)),
+        )
+    }
+
+    fn name(name: &str) -> (&'static str, FieldKind, FieldData) {
+        (
+            "name",
+            FieldKind::Text(16),
+            FieldData::Bytes(Arc::from(name.as_bytes())),
+        )
+    }
+
+    #[test]
+    fn upsert_inserts_when_absent() {
+        let mut writer = IndexWriter::new(schema(), PersistentVec::in_memory());
+        let first = writer
+            .upsert(document(&[id("a"), count(1.0)]), UpsertMode::Replace)
+            .unwrap();
+        let second = writer
+            .upsert(document(&[id("b"), count(2.0)]), UpsertMode::Replace)
+            .unwrap();
+        assert_eq!(first, Upserted::Inserted(0));
+        assert_eq!(second, Upserted::Inserted(1));
+        assert_eq!(writer.len(), 2);
+    }
+
+    #[test]
+    fn upsert_replaces_existing() {
+        let mut writer = IndexWriter::new(schema(), PersistentVec::in_memory());
+        writer
+            .upsert(
+                document(&[id("a"), count(1.0), name("first")]),
+                UpsertMode::Replace,
+            )
+            .unwrap();
+        let upserted = writer
+            .upsert(document(&[id("a"), count(2.0)]), UpsertMode::Replace)
+            .unwrap();
+        assert_eq!(upserted, Upserted::Updated(0));
+        assert_eq!(writer.len(), 1);
+
+        let row = writer.row(0).unwrap();
+        assert_eq!(&row[8..16], &2.0_f64.to_le_bytes());
+        assert!(row[16..].iter().all(|&b| b == 0), "name should be cleared");
+    }
+
+    #[test]
+    fn upsert_merges_existing() {
+        let mut writer = IndexWriter::new(schema(), PersistentVec::in_memory());
+        writer
+            .upsert(
+                document(&[id("a"), count(1.0), name("first")]),
+                UpsertMode::Replace,
+            )
+            .unwrap();
+        writer
+            .upsert(document(&[id("a"), count(2.0)]), UpsertMode::Merge)
+            .unwrap();
+
+        let row = writer.row(0).unwrap();
+        assert_eq!(&row[8..16], &2.0_f64.to_le_bytes());
+        assert_eq!(&row[16..21], b"first");
+    }
+
+    #[test]
+    fn upsert_requires_primary_key() {
+        let mut writer = IndexWriter::new(schema(), PersistentVec::in_memory());
+        let error = writer
+            .upsert(document(&[count(1.0)]), UpsertMode::Replace)
+            .unwrap_err();
+        assert!(matches!(error, IndexWriterError::MissingPrimaryKey(_)));
+    }
+
+    #[test]
+    fn upsert_updates_postings() {
+        let mut writer = IndexWriter::new(schema(), PersistentVec::in_memory());
+        writer
+            .upsert(document(&[id("a"), name("red fox")]), UpsertMode::Replace)
+            .unwrap();
+        writer
+            .upsert(document(&[id("a"), name("blue fox")]), UpsertMode::Merge)
+            .unwrap();
+
+        assert!(writer.postings().get("name", "red").is_empty());
+        assert_eq!(writer.postings().get("name", "blue"), &[0]);
+        assert_eq!(writer.postings().get("id", "a"), &[0]);
+    }
+
+    #[test]
+    fn add_documents_in_bulk() {
+        let mut writer = IndexWriter::new(schema(), PersistentVec::in_memory());
+        let documents = (0..BULK_BATCH_SIZE * 2 + 10)
+            .map(|i| document(&[id(&i.to_string()), count(i as f64), name("bulk")]));
+        let results = writer.add_documents(documents);
+
+        assert_eq!(results.len(), BULK_BATCH_SIZE * 2 + 10);
+        assert!(results
+            .iter()
+            .enumerate()
+            .all(|(i, result)| matches!(result, Ok(Some(row)) if *row == i)));
+        assert_eq!(writer.len(), BULK_BATCH_SIZE * 2 + 10);
+        assert_eq!(writer.postings().get("name", "bulk").len(), writer.len());
+        assert_eq!(
+            &writer.row(BULK_BATCH_SIZE + 1).unwrap()[8..16],
+            &((BULK_BATCH_SIZE + 1) as f64).to_le_bytes()
+        );
+        let stats = writer.ingest_stats();
+        assert!(stats.rate > 0.0);
+        assert_eq!(stats.width, schema().row_size() as f64);
+
+        // batches never buffer more rows than fit in the memory budget
+        let policy = FlushPolicy::new(schema().row_size() * 20);
+        let mut writer =
+            IndexWriter::new(schema(), PersistentVec::in_memory()).with_flush_policy(policy);
+        let documents = (0..100).map(|i| document(&[id(&i.to_string()), name("bulk")]));
+        writer.add_documents(documents.collect::<Vec<_>>());
+        assert_eq!(writer.len(), 100);
+        assert_eq!(writer.postings().get("name", "bulk").len(), 100);
+    }
+
+    #[test]
+    fn add_documents_reports_failures() {
+        let mut writer = IndexWriter::new(schema(), PersistentVec::in_memory());
+        let results = writer.add_documents([
+            document(&[id("a")]),
+            document(&[count(1.0)]),
+            document(&[id("a")]),
+            document(&[id("this id is too long")]),
+            document(&[id("b")]),
+        ]);
+
+        assert!(matches!(results[0], Ok(Some(0))));
+        assert!(matches!(
+            results[1],
+            Err(IndexWriterError::MissingPrimaryKey(_))
+        ));
+        assert!(matches!(
+            results[2],
+            Err(IndexWriterError::DuplicatePrimaryKey(_))
+        ));
+        assert!(matches!(
+            results[3],
+            Err(IndexWriterError::RowEncodeError(_))
+        ));
+        assert!(matches!(results[4], Ok(Some(1))));
+        assert_eq!(writer.len(), 2);
+    }
+
+    #[test]
+    fn find_and_delete_by_primary_key() {
+        let mut writer = IndexWriter::new(schema(), PersistentVec::in_memory());
+        writer
+            .upsert(
+                document(&[id("a"), name("hello world")]),
+                UpsertMode::Replace,
+            )
+            .unwrap();
+        writer
+            .upsert(document(&[id("b"), name("hello")]), UpsertMode::Replace)
+            .unwrap();
+
+        let key = FieldData::Bytes(Arc::from(&b"b"[..]));
+        assert_eq!(writer.find(&key).unwrap(), Some(1));
+        assert_eq!(writer.delete(&key).unwrap(), Some(1));
+        assert_eq!(writer.find(&key).unwrap(), None);
+        assert_eq!(writer.delete(&key).unwrap(), None);
+        assert_eq!(writer.postings().get("name", "hello"), &[0]);
+        assert_eq!(writer.len(), 2);
+    }
+
+    #[test]
+    fn search_matches_every_term() {
+        let mut writer = IndexWriter::new(schema(), PersistentVec::in_memory());
+        writer
+            .upsert(
+                document(&[id("a"), name("hello world")]),
+                UpsertMode::Replace,
+            )
+            .unwrap();
+        writer
+            .upsert(document(&[id("b"), name("hello")]), UpsertMode::Replace)
+            .unwrap();
+
+        assert_eq!(writer.search("name", "Hello"), [0, 1]);
+        assert_eq!(writer.search("name", "world hello"), [0]);
+        assert_eq!(writer.search("id", "b"), [1]);
+        assert!(writer.search("name", "").is_empty());
+        assert!(writer.search("missing", "hello").is_empty());
+    }
+
+    #[test]
+    fn scan_pages_of_rows() {
+        let mut writer = IndexWriter::new(schema(), PersistentVec::in_memory());
+        for (key, text) in [
+            ("a", "hello"),
+            ("b", "world"),
+            ("c", "hello"),
+            ("d", "hello"),
+        ] {
+            writer
+                .upsert(document(&[id(key), name(text)]), UpsertMode::Replace)
+                .unwrap();
+        }
+        writer
+            .delete(&FieldData::Bytes(Arc::from(&b"a"[..])))
+            .unwrap();
+
+        assert_eq!(writer.scan(None, 0, 2), (vec![1, 2], Some(3)));
+        assert_eq!(writer.scan(None, 3, 2), (vec![3], None));
+        let hello = Some(("name", "hello"));
+        assert_eq!(writer.scan(hello, 0, 1), (vec![2], Some(3)));
+        assert_eq!(writer.scan(hello, 3, 1), (vec![3], None));
+        assert_eq!(writer.scan(hello, 4, 1), (vec![], None));
+    }
+
+    #[test]
+    fn read_upserted_document() {
+        let mut writer = IndexWriter::new(schema(), PersistentVec::in_memory());
+        writer
+            .upsert(document(&[id("a"), count(4.0)]), UpsertMode::Replace)
+            .unwrap();
+
+        let document = writer
+            .document(0)
+            .unwrap()
+            .try_into_document(writer.schema())
+            .unwrap();
+        assert_eq!(
+            document.get("count").unwrap().data(),
+            &[FieldData::Number(BigFloat::from_f64(4.0))]
+        );
+        assert!(document.get("name").unwrap().data().is_empty());
+    }
+
+    #[test]
+    fn blobs_are_retrievable_but_not_indexed() {
+        let schema = Schema::from_iter([
+            SchemaField {
+                name: "id".to_string(),
+                kind: FieldKind::Keyword(8),
+            },
+            SchemaField {
+                name: "attachment".to_string(),
+                kind: FieldKind::Blob,
+            },
+        ])
+        .with_primary_key("id");
+        let mut writer = IndexWriter::new(schema, PersistentVec::in_memory())
+            .with_blob_segment(BlobSegment::in_memory());
+        let attachment = (
+            "attachment",
+            FieldKind::Blob,
+            FieldData::Blob(Arc::from(&b"some attachment"[..])),
+        );
+        writer
+            .upsert(document(&[id("a"), attachment]), UpsertMode::Replace)
+            .unwrap();
+        writer
+            .upsert(document(&[id("a")]), UpsertMode::Merge)
+            .unwrap();
+
+        assert_eq!(writer.blobs().unwrap().len(), 15);
+        assert_eq!(writer.postings().term_count("attachment"), 0);
+        let document = writer.read(0).unwrap().unwrap();
+        assert_eq!(
+            document.get("attachment").unwrap().data(),
+            &[FieldData::Blob(Arc::from(&b"some attachment"[..]))]
+        );
+    }
+
+    #[test]
+    fn rejected_documents_leave_no_blobs() {
+        let schema = Schema::from_iter([
+            SchemaField {
+                name: "id".to_string(),
+                kind: FieldKind::Keyword(8),
+            },
+            SchemaField {
+                name: "attachment".to_string(),
+                kind: FieldKind::Blob,
+            },
+        ])
+        .with_primary_key("id");
+        let mut writer = IndexWriter::new(schema, PersistentVec::in_memory())
+            .with_blob_segment(BlobSegment::in_memory());
+        let attachment = |blob: &[u8]| {
+            (
+                "attachment",
+                FieldKind::Blob,
+                FieldData::Blob(Arc::from(blob)),
+            )
+        };
+
+        let results = writer.add_documents([
+            document(&[id("a"), attachment(b"kept")]),
+            document(&[id("a"), attachment(b"rejected")]),
+        ]);
+        assert!(matches!(
+            results[1],
+            Err(IndexWriterError::DuplicatePrimaryKey(_))
+        ));
+        assert_eq!(writer.blobs().unwrap().len(), 4);
+        let document = writer.read(0).unwrap().unwrap();
+        assert_eq!(
+            document.get("attachment").unwrap().data(),
+            &[FieldData::Blob(Arc::from(&b"kept"[..]))]
+        );
+    }
+
+    #[test]
+    fn duplicates_are_rejected() {
+        let dedup = Deduplication::new(["name"], DedupMode::Reject, PersistentVec::in_memory());
+        let mut writer =
+            IndexWriter::new(schema(), PersistentVec::in_memory()).with_deduplication(dedup);
+
+        let results = writer.add_documents([
+            document(&[id("a"), name("hello world")]),
+            document(&[id("b"), name("hello world")]),
+            document(&[id("c"), name("goodbye")]),
+        ]);
+        assert!(matches!(results[0], Ok(Some(0))));
+        assert!(matches!(results[1], Err(IndexWriterError::Duplicate(0))));
+        assert!(matches!(results[2], Ok(Some(1))));
+
+        writer
+            .upsert(document(&[id("a"), name("changed")]), UpsertMode::Replace)
+            .unwrap();
+        assert_eq!(
+            writer
+                .upsert(
+                    document(&[id("b"), name("hello world")]),
+                    UpsertMode::Replace
+                )
+                .unwrap(),
+            Upserted::Inserted(2)
+        );
+    }
+
+    #[test]
+    fn duplicates_are_versioned() {
+        let dedup = Deduplication::new(["name"], DedupMode::Version, PersistentVec::in_memory());
+        let mut writer =
+            IndexWriter::new(schema(), PersistentVec::in_memory()).with_deduplication(dedup);
+
+        let results = writer.add_documents([
+            document(&[id("a"), name("hello world")]),
+            document(&[id("b"), name("hello world")]),
+        ]);
+        assert!(results.iter().all(|result| result.is_ok()));
+
+        let dedup = writer.deduplication().unwrap();
+        let fingerprint = dedup.fingerprint(writer.schema(), writer.row(0).unwrap());
+        assert_eq!(
+            dedup.get(fingerprint),
+            Some(Fingerprinted { row: 1, version: 1 })
+        );
+    }
+
+    #[test]
+    fn documents_go_through_pipeline() {
+        let pipeline = Pipeline::new([
+            Processor::Lowercase {
+                field: "name".to_string(),
+            },
+            Processor::Drop {
+                field: "name".to_string(),
+                value: Some("skip".to_string()),
+            },
+        ])
+        .unwrap();
+        let mut writer =
+            IndexWriter::new(schema(), PersistentVec::in_memory()).with_pipeline(pipeline);
+
+        let results = writer.add_documents([
+            document(&[id("a"), name("LOUD")]),
+            document(&[id("b"), name("Skip")]),
+        ]);
+        assert!(matches!(results[0], Ok(Some(0))));
+        assert!(matches!(results[1], Ok(None)));
+        assert_eq!(writer.postings().get("name", "loud"), &[0]);
+
+        let upserted = writer
+            .upsert(document(&[id("c"), name("skip")]), UpsertMode::Replace)
+            .unwrap();
+        assert_eq!(upserted, Upserted::Dropped);
+        assert_eq!(writer.len(), 1);
+    }
+
+    #[test]
+    fn existing_rows_are_keyed() {
+        let mut rows = PersistentVec::in_memory();
+        schema()
+            .insert(&mut rows, [document(&[id("a"), count(1.0)])])
+            .unwrap();
+
+        let mut writer = IndexWriter::new(schema(), rows);
+        let upserted = writer
+            .upsert(document(&[id("a"), count(3.0)]), UpsertMode::Merge)
+            .unwrap();
+        assert_eq!(upserted, Upserted::Updated(0));
+    }
+}
@@ -0,0 +1,266 @@
+//! Duplicate detection fingerprints documents by the content of selected fields.
+//!
+//! Fingerprints are kept in a hash set backed by a [`PersistentVec`](PersistentVec), so they
+//! survive alongside the rows they were computed from.
+
+use std::io;
+
+use crate::persist::PersistentVec;
+use crate::schema::Schema;
+
+use super::{fnv1a, IndexWriterError};
+
+/// The fewest slots a non-empty fingerprint set has
+const MIN_SLOTS: usize = 16;
+
+/// What happens to a document whose fingerprint already exists
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DedupMode {
+    /// The document is rejected
+    #[default]
+    Reject,
+    /// The document is added as a new version of the existing document
+    Version,
+}
+
+/// The latest row with a given fingerprint
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fingerprinted {
+    /// The row containing the document
+    pub row: usize,
+    /// The number of times the fingerprint was seen before this row, starting at 0
+    pub version: u64,
+}
+
+/// A single slot of a persisted fingerprint set
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FingerprintSlot {
+    fingerprint: u64,
+    row: u64,
+    version: u64,
+}
+
+/// Detects documents whose selected fields match an already written document.
+///
+/// Fingerprints are 64-bit hashes of the encoded cells of the selected fields, so two documents are
+/// duplicates when those cells are equal. Fields that are not part of the schema are ignored.
+#[derive(Debug)]
+pub struct Deduplication {
+    fields: Vec<String>,
+    mode: DedupMode,
+    slots: PersistentVec<FingerprintSlot>,
+    len: usize,
+}
+
+impl Deduplication {
+    /// Creates duplicate detection over the given fields, using previously persisted slots
+    pub fn new<I, S>(fields: I, mode: DedupMode, slots: PersistentVec<FingerprintSlot>) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let len = slots.iter().filter(|slot| slot.fingerprint != 0).count();
+        let mut dedup = Self {
+            fields: fields
+                .into_iter()
+                .map(|field| field.as_ref().to_string())
+                .collect(),
+            mode,
+            slots,
+            len,
+        };
+        if !dedup.slots.is_empty() && !dedup.slots.len().is_power_of_two() {
+            dedup.resize(dedup.slots.len().next_power_of_two());
+        }
+        dedup
+    }
+
+    /// Gets the fields that are fingerprinted
+    pub fn fields(&self) -> &[String] {
+        &self.fields
+    }
+
+    /// Gets what happens to duplicate documents
+    pub fn mode(&self) -> DedupMode {
+        self.mode
+    }
+
+    /// Gets the number of distinct fingerprints
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Checks if no fingerprints are stored
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Writes any changes of the fingerprints to the file backing them
+    pub fn flush(&self) -> io::Result<()> {
+        self.slots.flush()
+    }
+
+    /// Computes the fingerprint of an encoded row
+    pub fn fingerprint(&self, schema: &Schema, row: &[u8]) -> u64 {
+        let mut bytes = vec![];
+        for field in &self.fields {
+            if let Some(range) = schema.field_range(field) {
+                bytes.extend_from_slice(&row[range]);
+            }
+        }
+        // zero marks an empty slot
+        fnv1a(&bytes).max(1)
+    }
+
+    /// Gets the latest row with a fingerprint, if any
+    pub fn get(&self, fingerprint: u64) -> Option<Fingerprinted> {
+        self.find(fingerprint).ok().map(|index| {
+            let slot = self.slots[index];
+            Fingerprinted {
+                row: slot.row as usize,
+                version: slot.version,
+            }
+        })
+    }
+
+    /// Checks whether a row can be written, returning its fingerprint. A row being updated is
+    /// never a duplicate of itself.
+    pub(super) fn check(
+        &self,
+        schema: &Schema,
+        row: &[u8],
+        updating: Option<usize>,
+    ) -> Result<u64, IndexWriterError> {
+        let fingerprint = self.fingerprint(schema, row);
+        match (self.mode, self.get(fingerprint)) {
+            (DedupMode::Reject, Some(existing)) if Some(existing.row) != updating => {
+                Err(IndexWriterError::Duplicate(existing.row))
+            }
+            _ => Ok(fingerprint),
+        }
+    }
+
+    /// Records that a row with a fingerprint was written, making it the latest version
+    pub(super) fn record(&mut self, fingerprint: u64, row: usize) {
+        if (self.len + 1) * 2 > self.slots.len() {
+            self.resize((self.slots.len() * 2).max(MIN_SLOTS));
+        }
+        match self.find(fingerprint) {
+            Ok(index) => {
+                let slot = &mut self.slots[index];
+                if slot.row != row as u64 {
+                    slot.row = row as u64;
+                    slot.version += 1;
+                }
+            }
+            Err(index) => {
+                self.slots[index] = FingerprintSlot {
+                    fingerprint,
+                    row: row as u64,
+                    version: 0,
+                };
+                self.len += 1;
+            }
+        }
+    }
+
+    /// Forgets a fingerprint if its latest version is the given row
+    pub(super) fn forget(&mut self, fingerprint: u64, row: usize) {
+        let Ok(mut hole) = self.find(fingerprint) else {
+            return;
+        };
+        if self.slots[hole].row != row as u64 {
+            return;
+        }
+
+        // shift back any following entries that would no longer be reachable
+        let mask = self.slots.len() - 1;
+        let mut index = (hole + 1) & mask;
+        loop {
+            let slot = self.slots[index];
+            if slot.fingerprint == 0 {
+                break;
+            }
+            let home = slot.fingerprint as usize & mask;
+            let stays = if hole <= index {
+                hole < home && home <= index
+            } else {
+                hole < home || home <= index
+            };
+            if !stays {
+                self.slots[hole] = slot;
+                hole = index;
+            }
+            index = (index + 1) & mask;
+        }
+        self.slots[hole] = FingerprintSlot::default();
+        self.len -= 1;
+    }
+
+    /// Finds the slot of a fingerprint, or the empty slot it would be placed in
+    fn find(&self, fingerprint: u64) -> Result<usize, usize> {
+        if self.slots.is_empty() {
+            return Err(0);
+        }
+        let mask = self.slots.len() - 1;
+        let mut index = fingerprint as usize & mask;
+        loop {
+            match self.slots[index].fingerprint {
+                0 => return Err(index),
+                found if found == fingerprint => return Ok(index),
+                _ => index = (index + 1) & mask,
+            }
+        }
+    }
+
+    fn resize(&mut self, capacity: usize) {
+        let occupied = self
+            .slots
+            .iter()
+            .filter(|slot| slot.fingerprint != 0)
+            .copied()
+            .collect::<Vec<_>>();
+        self.slots.clear();
+        self.slots
+            .extend(std::iter::repeat_n(FingerprintSlot::default(), capacity));
+        let mask = capacity - 1;
+        for slot in occupied {
+            let mut index = slot.fingerprint as usize & mask;
+            while self.slots[index].fingerprint != 0 {
+                index = (index + 1) & mask;
+            }
+            self.slots[index] = slot;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_and_forget() {
+        let mut dedup =
+            Deduplication::new(["body"], DedupMode::Version, PersistentVec::in_memory());
+        for fingerprint in 1..=100 {
+            dedup.record(fingerprint, fingerprint as usize);
+        }
+        dedup.record(50, 1000);
+        assert_eq!(dedup.len(), 100);
+        assert_eq!(
+            dedup.get(50),
+            Some(Fingerprinted {
+                row: 1000,
+                version: 1
+            })
+        );
+
+        for fingerprint in (1..=100).step_by(2) {
+            dedup.forget(fingerprint, fingerprint as usize);
+        }
+        assert_eq!(dedup.len(), 50);
+        assert!(
+            (1..=100).all(|fingerprint| dedup.get(fingerprint).is_some() == (fingerprint % 2 == 0))
+        );
+    }
+}
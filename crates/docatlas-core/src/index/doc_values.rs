@@ -0,0 +1,243 @@
+//! Doc values store the keyword and text fields of an index column by column.
+//!
+//! Every column is encoded on its own, so low cardinality fields can use a dictionary and large
+//! text fields can be compressed with LZ4. All lengths and ordinals are little endian `u32`s.
+//!
+//! - Plain columns store every value with its length prefixed.
+//! - Dictionary columns store the distinct values once, followed by one ordinal per row.
+
+use std::collections::HashMap;
+
+use thiserror::Error;
+
+use crate::codec::unpad;
+use crate::schema::Schema;
+
+/// How a column of keyword or text values is stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ColumnEncoding {
+    /// Values are stored as is
+    #[default]
+    Plain,
+    /// Distinct values are stored once, with each row referencing one
+    Dictionary,
+    /// Values are stored as is, then compressed with LZ4
+    Lz4,
+    /// Values are dictionary encoded, then compressed with LZ4
+    DictionaryLz4,
+}
+
+impl ColumnEncoding {
+    fn dictionary(&self) -> bool {
+        matches!(
+            self,
+            ColumnEncoding::Dictionary | ColumnEncoding::DictionaryLz4
+        )
+    }
+
+    fn lz4(&self) -> bool {
+        matches!(self, ColumnEncoding::Lz4 | ColumnEncoding::DictionaryLz4)
+    }
+}
+
+/// The doc values of the keyword and text fields of an index
+#[derive(Debug, Default)]
+pub struct DocValues {
+    columns: HashMap<String, Column>,
+}
+
+impl DocValues {
+    /// Builds the doc values of a set of rows. Fields without an encoding are stored plainly.
+    pub fn build(
+        schema: &Schema,
+        rows: &[u8],
+        encodings: &HashMap<String, ColumnEncoding>,
+    ) -> Self {
+        let mut columns = HashMap::new();
+        for field in schema {
+            if !(field.kind.indexable() || field.kind.searchable()) {
+                continue;
+            }
+            let range = schema
+                .field_range(&field.name)
+                .expect("field is part of the schema");
+            let values = rows
+                .chunks_exact(schema.row_size())
+                .map(|row| unpad(&row[range.clone()]));
+            let encoding = encodings.get(&field.name).copied().unwrap_or_default();
+            columns.insert(field.name.clone(), Column::encode(values, encoding));
+        }
+        Self { columns }
+    }
+
+    /// Gets the column of a field, if the field is stored
+    pub fn column(&self, name: impl AsRef<str>) -> Option<&Column> {
+        self.columns.get(name.as_ref())
+    }
+
+    /// Gets the total number of bytes used by every column
+    pub fn encoded_len(&self) -> usize {
+        self.columns.values().map(Column::encoded_len).sum()
+    }
+}
+
+/// A single encoded column
+#[derive(Debug)]
+pub struct Column {
+    encoding: ColumnEncoding,
+    len: usize,
+    data: Box<[u8]>,
+}
+
+impl Column {
+    /// Encodes the values of a column
+    pub fn encode<'a, I: IntoIterator<Item = &'a [u8]>>(
+        values: I,
+        encoding: ColumnEncoding,
+    ) -> Self {
+        let mut data = vec![];
+        let mut len = 0;
+        if encoding.dictionary() {
+            let mut ordinals = HashMap::new();
+            let mut dictionary = vec![];
+            let mut rows = vec![];
+            for value in values {
+                let ordinal = *ordinals.entry(value).or_insert_with(|| {
+                    dictionary.push(value);
+                    dictionary.len() as u32 - 1
+                });
+                rows.push(ordinal);
+                len += 1;
+            }
+            data.extend_from_slice(&(dictionary.len() as u32).to_le_bytes());
+            for value in dictionary {
+                write_value(&mut data, value);
+            }
+            for ordinal in rows {
+                data.extend_from_slice(&ordinal.to_le_bytes());
+            }
+        } else {
+            for value in values {
+                write_value(&mut data, value);
+                len += 1;
+            }
+        }
+
+        if encoding.lz4() {
+            data = lz4_flex::compress_prepend_size(&data);
+        }
+        Self {
+            encoding,
+            len,
+            data: data.into_boxed_slice(),
+        }
+    }
+
+    /// Gets the encoding of this column
+    pub fn encoding(&self) -> ColumnEncoding {
+        self.encoding
+    }
+
+    /// Gets the number of values in this column
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Checks if this column has no values
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Gets the number of bytes used by this column
+    pub fn encoded_len(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Decodes every value of this column, in row order. Empty values had no data.
+    pub fn values(&self) -> Result<Vec<Box<[u8]>>, DocValuesError> {
+        let decompressed;
+        let mut data = &self.data[..];
+        if self.encoding.lz4() {
+            decompressed = lz4_flex::decompress_size_prepended(data)?;
+            data = &decompressed;
+        }
+
+        let mut values = Vec::with_capacity(self.len);
+        if self.encoding.dictionary() {
+            let dictionary_len = read_u32(&mut data)? as usize;
+            let dictionary = (0..dictionary_len)
+                .map(|_| read_value(&mut data))
+                .collect::<Result<Vec<_>, _>>()?;
+            for _ in 0..self.len {
+                let ordinal = read_u32(&mut data)? as usize;
+                let value = dictionary.get(ordinal).ok_or(DocValuesError::Corrupt)?;
+                values.push(Box::from(*value));
+            }
+        } else {
+            for _ in 0..self.len {
+                values.push(Box::from(read_value(&mut data)?));
+            }
+        }
+        Ok(values)
+    }
+}
+
+fn write_value(data: &mut Vec<u8>, value: &[u8]) {
+    data.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    data.extend_from_slice(value);
+}
+
+fn read_u32(data: &mut &[u8]) -> Result<u32, DocValuesError> {
+    let (bytes, rest) = data
+        .split_first_chunk::<4>()
+        .ok_or(DocValuesError::Corrupt)?;
+    *data = rest;
+    Ok(u32::from_le_bytes(*bytes))
+}
+
+fn read_value<'a>(data: &mut &'a [u8]) -> Result<&'a [u8], DocValuesError> {
+    let len = read_u32(data)? as usize;
+    if data.len() < len {
+        return Err(DocValuesError::Corrupt);
+    }
+    let (value, rest) = data.split_at(len);
+    *data = rest;
+    Ok(value)
+}
+
+/// An error occurred decoding doc values
+#[derive(Debug, Error)]
+pub enum DocValuesError {
+    #[error("Column data is corrupt")]
+    Corrupt,
+    #[error(transparent)]
+    DecompressError(#[from] lz4_flex::block::DecompressError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_encoding_round_trips() {
+        let values = (0..1000)
+            .map(|i| ["red", "green", "blue", ""][i % 4].as_bytes())
+            .collect::<Vec<_>>();
+
+        let plain = Column::encode(values.iter().copied(), ColumnEncoding::Plain);
+        for encoding in [
+            ColumnEncoding::Plain,
+            ColumnEncoding::Dictionary,
+            ColumnEncoding::Lz4,
+            ColumnEncoding::DictionaryLz4,
+        ] {
+            let column = Column::encode(values.iter().copied(), encoding);
+            let decoded = column.values().unwrap();
+            assert_eq!(decoded.len(), values.len());
+            assert!(decoded.iter().zip(&values).all(|(a, b)| &**a == *b));
+            if encoding != ColumnEncoding::Plain {
+                assert!(column.encoded_len() < plain.encoded_len());
+            }
+        }
+    }
+}
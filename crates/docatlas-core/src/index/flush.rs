@@ -0,0 +1,146 @@
+//! Sizes the batches documents are buffered in before they are written to an index, and the room
+//! reserved for them ahead of time.
+//!
+//! A writer starts out buffering [`BULK_BATCH_SIZE`](super::BULK_BATCH_SIZE) documents per batch.
+//! Once it has seen how fast documents arrive, it buffers as many as arrive within the
+//! [latency](FlushPolicy::latency) of its flush policy instead, so documents streaming in slowly
+//! are written in small batches and bulk loads in large ones. A batch never holds more rows than
+//! fit in the [memory budget](FlushPolicy::memory_budget) of the policy.
+//!
+//! Writers also track how many bytes a document takes up, including its blobs, and reserve room
+//! for a whole batch before writing it. Blob segments grow by many small blobs otherwise, copying
+//! their blobs over every time they do.
+
+use std::time::Duration;
+
+use super::BULK_BATCH_SIZE;
+
+/// The default number of bytes of rows buffered before they are written
+pub const DEFAULT_WRITE_BUFFER_BUDGET: usize = 16 * 1024 * 1024;
+
+/// The default time documents arriving at the observed rate are buffered for
+pub const DEFAULT_FLUSH_LATENCY: Duration = Duration::from_millis(100);
+
+/// The fewest documents buffered before they are written
+const MIN_BATCH_ROWS: usize = 16;
+
+/// The weight of the latest batch in the averages of the ingest stats
+const SMOOTHING: f64 = 0.25;
+
+/// How many documents a writer buffers before writing them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlushPolicy {
+    memory_budget: usize,
+    latency: Duration,
+}
+
+impl FlushPolicy {
+    /// Creates a policy buffering up to `memory_budget` bytes of rows
+    pub fn new(memory_budget: usize) -> Self {
+        Self {
+            memory_budget,
+            latency: DEFAULT_FLUSH_LATENCY,
+        }
+    }
+
+    /// Sets how long documents arriving at the observed rate are buffered for
+    pub fn with_latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Gets the most bytes of rows buffered before they are written
+    pub fn memory_budget(&self) -> usize {
+        self.memory_budget
+    }
+
+    /// Gets how long documents arriving at the observed rate are buffered for
+    pub fn latency(&self) -> Duration {
+        self.latency
+    }
+}
+
+impl Default for FlushPolicy {
+    fn default() -> Self {
+        Self::new(DEFAULT_WRITE_BUFFER_BUDGET)
+    }
+}
+
+/// What a writer observed of the documents written to it, averaged over its recent batches
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct IngestStats {
+    /// The documents arriving per second, or 0 before any batch was written
+    pub rate: f64,
+    /// The bytes a document takes up, counting its row and its blobs
+    pub width: f64,
+}
+
+/// Buffers the documents added to a writer
+#[derive(Debug, Default)]
+pub(super) struct WriteBuffer {
+    pub(super) policy: FlushPolicy,
+    pub(super) stats: IngestStats,
+}
+
+impl WriteBuffer {
+    /// Gets the number of documents to buffer before writing them, given the size of a row
+    pub(super) fn batch_rows(&self, row_size: usize) -> usize {
+        let budget = (self.policy.memory_budget / row_size.max(1)).max(MIN_BATCH_ROWS);
+        let wanted = match self.stats.rate > 0.0 {
+            true => (self.stats.rate * self.policy.latency.as_secs_f64()) as usize,
+            false => BULK_BATCH_SIZE,
+        };
+        wanted.clamp(MIN_BATCH_ROWS, budget)
+    }
+
+    /// Gets the bytes of blobs the given number of documents are expected to take up
+    pub(super) fn blob_bytes(&self, documents: usize, row_size: usize) -> usize {
+        ((self.stats.width - row_size as f64).max(0.0) * documents as f64) as usize
+    }
+
+    /// Records that a batch of documents taking up `bytes` bytes arrived over `elapsed`
+    pub(super) fn record(&mut self, documents: usize, bytes: usize, elapsed: Duration) {
+        if documents == 0 {
+            return;
+        }
+        let rate = documents as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+        let width = bytes as f64 / documents as f64;
+        self.stats = match self.stats.rate > 0.0 {
+            true => IngestStats {
+                rate: self.stats.rate + (rate - self.stats.rate) * SMOOTHING,
+                width: self.stats.width + (width - self.stats.width) * SMOOTHING,
+            },
+            false => IngestStats { rate, width },
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn adapt_batches_to_the_ingest_rate() {
+        let mut buffer = WriteBuffer {
+            policy: FlushPolicy::new(64 * 1024).with_latency(Duration::from_millis(100)),
+            ..WriteBuffer::default()
+        };
+        assert_eq!(buffer.batch_rows(8), BULK_BATCH_SIZE);
+
+        // 5000 documents a second arrive within the latency in batches of 500
+        buffer.record(1000, 1000 * 40, Duration::from_millis(200));
+        assert_eq!(buffer.batch_rows(8), 500);
+        assert_eq!(buffer.blob_bytes(10, 8), 320);
+        // slow streams are written in small batches
+        for _ in 0..50 {
+            buffer.record(1, 40, Duration::from_secs(1));
+        }
+        assert_eq!(buffer.batch_rows(8), MIN_BATCH_ROWS);
+        // fast streams are bounded by the memory budget
+        for _ in 0..50 {
+            buffer.record(100_000, 100_000 * 40, Duration::from_millis(10));
+        }
+        assert_eq!(buffer.batch_rows(8), 8 * 1024);
+        assert_eq!(buffer.batch_rows(1024 * 1024), MIN_BATCH_ROWS);
+    }
+}
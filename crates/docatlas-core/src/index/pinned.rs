@@ -0,0 +1,153 @@
+//! Keeps commit points readable at their sequence number, so reads spread over many requests,
+//! such as scrolling through or exporting an index, see the index as it was when they started.
+//!
+//! Every write to an index bumps its [generation](super::Searcher::generation), which serves as
+//! the sequence number of the commit points taken after it. A writer [pins](super::IndexWriter::pin)
+//! a searcher at its sequence number, and hands the same searcher to every later read at that
+//! sequence number, so those reads never see a write or a deletion made after it. Pinned searchers
+//! are unpinned once they go unused for the pin ttl of the writer, or when too many are pinned.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use super::Searcher;
+
+/// The default time a pinned searcher stays pinned without being used
+pub const DEFAULT_PIN_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// The most searchers a writer keeps pinned at once
+const MAX_PINNED: usize = 64;
+
+/// The searchers pinned by a writer, by sequence number
+#[derive(Debug)]
+pub(super) struct PinnedSearchers {
+    pinned: BTreeMap<u64, Pinned>,
+    pub(super) ttl: Duration,
+}
+
+#[derive(Debug)]
+struct Pinned {
+    searcher: Searcher,
+    used: Instant,
+}
+
+impl Default for PinnedSearchers {
+    fn default() -> Self {
+        Self {
+            pinned: BTreeMap::new(),
+            ttl: DEFAULT_PIN_TTL,
+        }
+    }
+}
+
+impl PinnedSearchers {
+    /// Pins a searcher at its sequence number, unpinning the least recently used searcher if too
+    /// many are pinned
+    pub(super) fn pin(&mut self, searcher: Searcher) {
+        let now = Instant::now();
+        self.expire(now);
+        self.pinned.insert(
+            searcher.generation(),
+            Pinned {
+                searcher,
+                used: now,
+            },
+        );
+        if self.pinned.len() > MAX_PINNED {
+            let oldest = self
+                .pinned
+                .iter()
+                .min_by_key(|(_, pinned)| pinned.used)
+                .map(|(&sequence, _)| sequence);
+            if let Some(oldest) = oldest {
+                self.pinned.remove(&oldest);
+            }
+        }
+    }
+
+    /// Gets the searcher pinned at a sequence number, keeping it pinned for another ttl
+    pub(super) fn get(&mut self, sequence: u64) -> Option<Searcher> {
+        let now = Instant::now();
+        self.expire(now);
+        let pinned = self.pinned.get_mut(&sequence)?;
+        pinned.used = now;
+        Some(pinned.searcher.clone())
+    }
+
+    /// Gets the number of pinned searchers
+    #[cfg(test)]
+    pub(super) fn len(&self) -> usize {
+        self.pinned.len()
+    }
+
+    /// Unpins every searcher unused for longer than the ttl
+    fn expire(&mut self, now: Instant) {
+        let ttl = self.ttl;
+        self.pinned
+            .retain(|_, pinned| now.saturating_duration_since(pinned.used) <= ttl);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fields::{Field, FieldData, FieldKind, Fields};
+    use crate::index::{IndexReader, IndexWriter};
+    use crate::persist::PersistentVec;
+    use crate::schema::{Schema, SchemaField};
+
+    use super::*;
+
+    fn writer() -> IndexWriter {
+        let schema = Schema::from_iter([SchemaField {
+            name: "id".to_string(),
+            kind: FieldKind::Keyword(8),
+        }])
+        .with_primary_key("id");
+        IndexWriter::new(schema, PersistentVec::in_memory())
+    }
+
+    fn add(writer: &mut IndexWriter, id: &str) {
+        let mut fields = Fields::new();
+        let data = FieldData::Bytes(id.as_bytes().into());
+        fields.insert("id", Field::new(FieldKind::Keyword(8), [data]));
+        writer.add_documents([fields.into()]);
+    }
+
+    #[test]
+    fn reads_repeat_at_their_sequence_number() {
+        let mut writer = writer();
+        add(&mut writer, "a");
+        add(&mut writer, "b");
+        let pinned = writer.pin();
+        let sequence = pinned.generation();
+
+        writer
+            .delete(&FieldData::Bytes(b"a".as_slice().into()))
+            .unwrap();
+        add(&mut writer, "c");
+        let at = writer.pinned(sequence).unwrap();
+        assert_eq!(at.len(), 2);
+        assert!(at.is_live(0));
+        assert_eq!(at.scan(None, 0, 10), (vec![0, 1], None));
+        // only pinned sequence numbers can be read at
+        assert!(writer.pinned(sequence + 1).is_none());
+        assert_eq!(writer.pin().scan(None, 0, 10), (vec![1, 2], None));
+    }
+
+    #[test]
+    fn unpin_unused_searchers() {
+        let mut writer = writer().with_pin_ttl(Duration::from_secs(60));
+        let sequence = writer.pin().generation();
+        for id in 0..MAX_PINNED + 1 {
+            add(&mut writer, &id.to_string());
+            writer.pin();
+        }
+        assert_eq!(writer.pinned.len(), MAX_PINNED);
+        assert!(writer.pinned(sequence).is_none());
+
+        writer
+            .pinned
+            .expire(Instant::now() + Duration::from_secs(61));
+        assert_eq!(writer.pinned.len(), 0);
+    }
+}
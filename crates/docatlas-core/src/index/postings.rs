@@ -0,0 +1,469 @@
+//! Postings map the terms found in the fields of an index to the rows containing them
+
+use std::collections::{BTreeMap, HashMap};
+use std::ops::Bound;
+
+pub use packed::PackedPostings;
+
+use crate::analysis;
+use crate::cancel::{CancelToken, Cancelled, CHECK_INTERVAL};
+use crate::codec::unpad;
+use crate::fields::FieldKind;
+use crate::query::arena::{ArenaVec, QueryArena};
+use crate::query::regexp::Regexp;
+use crate::schema::Schema;
+
+mod packed;
+mod simd;
+
+/// The postings of an index, keyed by field name and then by term.
+///
+/// Every posting list is kept in ascending row order, and the terms of every field are kept in
+/// order, so they can be walked by the automaton of a [regexp](Regexp).
+#[derive(Debug, Default)]
+pub struct Postings {
+    fields: HashMap<String, BTreeMap<Box<[u8]>, Vec<usize>>>,
+}
+
+impl Postings {
+    /// Creates a new, empty set of postings
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Gets the rows containing a term within a field, in ascending order
+    pub fn get(&self, field: impl AsRef<str>, term: impl AsRef<[u8]>) -> &[usize] {
+        self.fields
+            .get(field.as_ref())
+            .and_then(|terms| terms.get(term.as_ref()))
+            .map(|rows| rows.as_slice())
+            .unwrap_or(&[])
+    }
+
+    /// Gets the rows containing every one of the given terms within a field, in ascending order.
+    /// No rows match an empty set of terms.
+    pub fn intersect<T: AsRef<[u8]>>(&self, field: impl AsRef<str>, terms: &[T]) -> Vec<usize> {
+        self.intersect_until(field, terms, &CancelToken::new())
+            .expect("never cancelled")
+    }
+
+    /// Intersects the rows of terms like [`intersect`](Self::intersect), stopping early once the
+    /// token is cancelled
+    pub fn intersect_until<T: AsRef<[u8]>>(
+        &self,
+        field: impl AsRef<str>,
+        terms: &[T],
+        cancel: &CancelToken,
+    ) -> Result<Vec<usize>, Cancelled> {
+        match self.intersect_partial(field, terms, cancel) {
+            (rows, true) => Ok(rows),
+            (_, false) => Err(Cancelled),
+        }
+    }
+
+    /// Intersects the rows of terms like [`intersect`](Self::intersect), stopping early once the
+    /// token is cancelled. Returns the rows found so far, and whether every row was checked.
+    pub fn intersect_partial<T: AsRef<[u8]>>(
+        &self,
+        field: impl AsRef<str>,
+        terms: &[T],
+        cancel: &CancelToken,
+    ) -> (Vec<usize>, bool) {
+        QueryArena::scoped(|arena| self.intersect_in(field, terms, cancel, arena))
+    }
+
+    /// Intersects the rows of terms like [`intersect_partial`](Self::intersect_partial), keeping
+    /// the rows of every step in the arena of the query
+    pub fn intersect_in<T: AsRef<[u8]>>(
+        &self,
+        field: impl AsRef<str>,
+        terms: &[T],
+        cancel: &CancelToken,
+        arena: &QueryArena,
+    ) -> (Vec<usize>, bool) {
+        let mut lists = arena.vec_with_capacity(terms.len());
+        lists.extend(terms.iter().map(|term| self.get(field.as_ref(), term)));
+        lists.sort_by_key(|rows| rows.len());
+        let Some((shortest, rest)) = lists.split_first() else {
+            return (vec![], true);
+        };
+        let mut rows = vec![];
+        let (mut matched, mut next) = (arena.vec(), arena.vec());
+        for chunk in shortest.chunks(CHECK_INTERVAL) {
+            if cancel.is_cancelled() {
+                return (rows, false);
+            }
+            matched.clear();
+            matched.extend_from_slice(chunk);
+            for list in rest {
+                // only the rows between the first and last row of the chunk can match
+                let start = list.partition_point(|row| row < &chunk[0]);
+                let end = list.partition_point(|row| row <= &chunk[chunk.len() - 1]);
+                next.clear();
+                simd::intersect(&matched, &list[start..end], &mut next);
+                std::mem::swap(&mut matched, &mut next);
+                if matched.is_empty() {
+                    break;
+                }
+            }
+            rows.extend_from_slice(&matched);
+        }
+        (rows, true)
+    }
+
+    /// Gets the terms of a field matched by a regexp, in order, stopping early once the token is
+    /// cancelled.
+    ///
+    /// The states the automaton of the regexp reached along the last term are kept, so the prefix
+    /// a term shares with the term before it is not fed through the automaton again. Once a
+    /// prefix can not lead to a match, the terms starting with it are skipped.
+    pub fn matching_terms(
+        &self,
+        field: impl AsRef<str>,
+        regexp: &Regexp,
+        cancel: &CancelToken,
+    ) -> Result<Vec<&[u8]>, Cancelled> {
+        let Some(terms) = self.fields.get(field.as_ref()) else {
+            return Ok(vec![]);
+        };
+        let mut matched = vec![];
+        // the states reached after every byte of the last term, starting before its first byte
+        let mut states = vec![regexp.start()];
+        let mut last: &[u8] = &[];
+        let mut cursor = terms.range::<Box<[u8]>, _>(..);
+        let mut walked = 0;
+        while let Some((term, _)) = cursor.next() {
+            walked += 1;
+            if walked % CHECK_INTERVAL == 0 && cancel.is_cancelled() {
+                return Err(Cancelled);
+            }
+            let shared = last.iter().zip(term.iter()).take_while(|(a, b)| a == b);
+            states.truncate(shared.count().min(states.len() - 1) + 1);
+            last = term;
+            while states.len() <= term.len() {
+                let state = *states.last().expect("walks start with a state");
+                match regexp.next(state, term[states.len() - 1]) {
+                    Some(next) => states.push(next),
+                    None => break,
+                }
+            }
+            if states.len() > term.len() {
+                if regexp.accepts(states[term.len()]) {
+                    matched.push(&**term);
+                }
+                continue;
+            }
+            // no term starting with the bytes walked so far can match
+            match successor(&term[..states.len()]) {
+                Some(next) => cursor = terms.range((Bound::Included(next), Bound::Unbounded)),
+                None => break,
+            }
+        }
+        Ok(matched)
+    }
+
+    /// Gets the rows containing any term of a field matched by a regexp, in ascending order,
+    /// stopping early once the token is cancelled. Returns the rows found so far, and whether
+    /// every term was checked.
+    pub fn regexp_rows(
+        &self,
+        field: impl AsRef<str>,
+        regexp: &Regexp,
+        cancel: &CancelToken,
+    ) -> (Vec<usize>, bool) {
+        let Ok(terms) = self.matching_terms(field.as_ref(), regexp, cancel) else {
+            return (vec![], false);
+        };
+        let mut rows = vec![];
+        for term in terms {
+            rows.extend_from_slice(self.get(field.as_ref(), term));
+        }
+        rows.sort_unstable();
+        rows.dedup();
+        (rows, true)
+    }
+
+    /// Gets the number of distinct terms within a field
+    pub fn term_count(&self, field: impl AsRef<str>) -> usize {
+        self.fields
+            .get(field.as_ref())
+            .map(|terms| terms.len())
+            .unwrap_or(0)
+    }
+
+    /// Adds the terms of a single row
+    pub fn insert_row(&mut self, schema: &Schema, row: usize, bytes: &[u8]) {
+        for (field, term) in row_terms(schema, bytes) {
+            let rows = self.posting_list(field, term);
+            match rows.binary_search(&row) {
+                Ok(_) => {}
+                Err(position) => rows.insert(position, row),
+            }
+        }
+    }
+
+    /// Adds the terms of consecutive rows, starting at `first_row`. All rows must come after any
+    /// row already present in the postings.
+    ///
+    /// Terms are grouped before being added, so every posting list is only extended once.
+    pub fn insert_rows(&mut self, schema: &Schema, first_row: usize, bytes: &[u8]) {
+        let mut grouped: HashMap<(&str, Box<[u8]>), Vec<usize>> = HashMap::new();
+        for (offset, row) in bytes.chunks_exact(schema.row_size()).enumerate() {
+            for (field, term) in row_terms(schema, row) {
+                let rows = grouped.entry((field, term)).or_default();
+                if rows.last() != Some(&(first_row + offset)) {
+                    rows.push(first_row + offset);
+                }
+            }
+        }
+
+        for ((field, term), rows) in grouped {
+            let list = self.posting_list(field, term);
+            debug_assert!(list.last() < rows.first(), "rows must be appended in order");
+            list.extend(rows);
+        }
+    }
+
+    /// Removes the terms of a row. The bytes must be the same as when the row was added.
+    pub fn remove_row(&mut self, schema: &Schema, row: usize, bytes: &[u8]) {
+        for (field, term) in row_terms(schema, bytes) {
+            let Some(terms) = self.fields.get_mut(field) else {
+                continue;
+            };
+            let Some(rows) = terms.get_mut(&term) else {
+                continue;
+            };
+            if let Ok(position) = rows.binary_search(&row) {
+                rows.remove(position);
+            }
+            if rows.is_empty() {
+                terms.remove(&term);
+            }
+        }
+    }
+
+    fn posting_list(&mut self, field: &str, term: Box<[u8]>) -> &mut Vec<usize> {
+        if !self.fields.contains_key(field) {
+            self.fields.insert(field.to_string(), BTreeMap::new());
+        }
+        self.fields
+            .get_mut(field)
+            .expect("field was just inserted")
+            .entry(term)
+            .or_default()
+    }
+}
+
+/// Gets the first bytes that come after every term starting with a prefix, or nothing if every
+/// term after the prefix starts with it
+fn successor(prefix: &[u8]) -> Option<Box<[u8]>> {
+    let end = prefix.iter().rposition(|&byte| byte != u8::MAX)?;
+    let mut next = prefix[..=end].to_vec();
+    next[end] += 1;
+    Some(next.into())
+}
+
+/// Gets the terms of every field within a row
+fn row_terms<'s>(schema: &'s Schema, row: &[u8]) -> Vec<(&'s str, Box<[u8]>)> {
+    let mut output = vec![];
+    let mut offset = 0;
+    for field in schema {
+        let size = field.kind.size();
+        for term in terms(&field.kind, &row[offset..offset + size]) {
+            output.push((field.name.as_str(), term));
+        }
+        offset += size;
+    }
+    output
+}
+
+/// Gets the terms of a single cell. Keywords are indexed as a whole, while text is tokenized into
+/// lowercase words. Other kinds of fields produce no terms.
+pub fn terms(kind: &FieldKind, cell: &[u8]) -> Vec<Box<[u8]>> {
+    let cell = unpad(cell);
+    if cell.is_empty() {
+        return vec![];
+    }
+
+    if kind.indexable() {
+        vec![Box::from(cell)]
+    } else if kind.searchable() {
+        tokenize(&String::from_utf8_lossy(cell))
+            .map(|token| Box::from(token.as_bytes()))
+            .collect()
+    } else {
+        vec![]
+    }
+}
+
+/// Gets the terms of a cell like [`terms`], copying them into the arena of a query
+pub fn terms_in<'a>(
+    kind: &FieldKind,
+    cell: &[u8],
+    arena: &'a QueryArena,
+) -> ArenaVec<'a, &'a [u8]> {
+    let cell = unpad(cell);
+    let mut terms = arena.vec();
+    if cell.is_empty() {
+        return terms;
+    }
+
+    if kind.indexable() {
+        terms.push(arena.alloc_bytes(cell));
+    } else if kind.searchable() {
+        terms.extend(
+            tokenize(&String::from_utf8_lossy(cell))
+                .map(|token| arena.alloc_bytes(token.as_bytes())),
+        );
+    }
+    terms
+}
+
+/// Splits text into lowercase terms, see [`analysis`](crate::analysis)
+pub fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    analysis::tokens(text).map(|token| token.term)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use super::*;
+    use crate::schema::SchemaField;
+
+    fn schema() -> Schema {
+        Schema::from_iter([
+            SchemaField {
+                name: "tag".to_string(),
+                kind: FieldKind::Keyword(4),
+            },
+            SchemaField {
+                name: "body".to_string(),
+                kind: FieldKind::Text(12),
+            },
+        ])
+    }
+
+    #[test]
+    fn tokenizes_text() {
+        let tokens = tokenize("Hello, World! hello").collect::<Vec<_>>();
+        assert_eq!(tokens, ["hello", "world", "hello"]);
+    }
+
+    #[test]
+    fn insert_and_remove_rows() {
+        let schema = schema();
+        let mut postings = Postings::new();
+        let rows = b"ab\0\0the cat\0\0\0\0\0ab\0\0a dog\0\0\0\0\0\0\0";
+        postings.insert_rows(&schema, 0, rows);
+
+        assert_eq!(postings.get("tag", b"ab"), &[0, 1]);
+        assert_eq!(postings.get("body", b"cat"), &[0]);
+        assert_eq!(postings.get("body", b"dog"), &[1]);
+        assert_eq!(postings.term_count("body"), 4);
+
+        postings.remove_row(&schema, 0, &rows[..16]);
+        assert_eq!(postings.get("tag", b"ab"), &[1]);
+        assert!(postings.get("body", b"cat").is_empty());
+        assert_eq!(postings.term_count("body"), 2);
+    }
+
+    #[test]
+    fn walk_terms_with_a_regexp() {
+        let schema = schema();
+        let mut postings = Postings::new();
+        for (row, (tag, body)) in [("du", "dune dust"), ("dx", "dusk data"), ("ab", "dune")]
+            .into_iter()
+            .enumerate()
+        {
+            let mut bytes = [0; 16];
+            bytes[..tag.len()].copy_from_slice(tag.as_bytes());
+            bytes[4..4 + body.len()].copy_from_slice(body.as_bytes());
+            postings.insert_row(&schema, row, &bytes);
+        }
+
+        let cancel = CancelToken::new();
+        let regexp = Regexp::new("du[nsx].").unwrap();
+        assert_eq!(
+            postings.matching_terms("body", &regexp, &cancel),
+            Ok(vec![&b"dune"[..], b"dusk", b"dust"])
+        );
+        assert_eq!(
+            postings.regexp_rows("body", &regexp, &cancel),
+            (vec![0, 1, 2], true)
+        );
+        let regexp = Regexp::new("d(u|x)").unwrap();
+        assert_eq!(
+            postings.regexp_rows("tag", &regexp, &cancel),
+            (vec![0, 1], true)
+        );
+        assert_eq!(
+            postings.regexp_rows("missing", &regexp, &cancel),
+            (vec![], true)
+        );
+
+        assert_eq!(successor(b"ab").as_deref(), Some(&b"ac"[..]));
+        assert_eq!(successor(b"a\xff\xff").as_deref(), Some(&b"b"[..]));
+        assert_eq!(successor(b"\xff"), None);
+    }
+
+    #[test]
+    fn intersect_stops_once_cancelled() {
+        let schema = schema();
+        let mut postings = Postings::new();
+        let rows = b"ab\0\0the cat\0\0\0\0\0ab\0\0a cat\0\0\0\0\0\0\0";
+        postings.insert_rows(&schema, 0, rows);
+
+        let cancel = CancelToken::new();
+        let terms = [&b"ab"[..]];
+        assert_eq!(
+            postings.intersect_until("tag", &terms, &cancel),
+            Ok(vec![0, 1])
+        );
+        cancel.cancel();
+        assert_eq!(
+            postings.intersect_until("tag", &terms, &cancel),
+            Err(Cancelled)
+        );
+
+        let expired = CancelToken::new().with_deadline(Instant::now());
+        assert!(expired.is_cancelled());
+        assert_eq!(
+            postings.intersect_partial("tag", &terms, &expired),
+            (vec![], false)
+        );
+    }
+
+    #[test]
+    fn intersect_long_posting_lists() {
+        let schema = Schema::from_iter([SchemaField {
+            name: "body".to_string(),
+            kind: FieldKind::Text(16),
+        }]);
+        let mut postings = Postings::new();
+        let mut rows = vec![];
+        for row in 0..5000 {
+            let mut body = [0; 16];
+            let words = [
+                (row % 2 == 0, "two"),
+                (row % 3 == 0, "three"),
+                (row == 4998, "last"),
+            ];
+            let mut offset = 0;
+            for (_, word) in words.iter().filter(|(contains, _)| *contains) {
+                body[offset..offset + word.len()].copy_from_slice(word.as_bytes());
+                offset += word.len() + 1;
+            }
+            rows.extend_from_slice(&body);
+        }
+        postings.insert_rows(&schema, 0, &rows);
+
+        let every_sixth = (0..5000).step_by(6).collect::<Vec<_>>();
+        assert_eq!(postings.intersect("body", &["two", "three"]), every_sixth);
+        assert_eq!(
+            postings.intersect("body", &["three", "last", "two"]),
+            [4998]
+        );
+        assert!(postings.intersect("body", &["two", "none"]).is_empty());
+    }
+}
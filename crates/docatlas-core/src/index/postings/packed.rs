@@ -0,0 +1,260 @@
+//! Posting lists compressed to a fraction of their size, for keeping many of them around.
+//!
+//! Rows are stored as the difference from the row before them, since the rows of a posting list
+//! are close to each other. Full blocks of [`BLOCK_LEN`] differences are bit-packed with as few
+//! bits as the largest difference of the block needs, and the differences left over after the
+//! last block are written as varints.
+
+use super::simd::{self, BLOCK_LEN};
+
+/// A posting list compressed into bit-packed blocks
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PackedPostings {
+    len: usize,
+    blocks: Vec<Block>,
+    /// The differences of the rows after the last block, as varints
+    tail: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Block {
+    /// The last row of the block, which the next block starts from
+    last: usize,
+    differences: Differences,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Differences {
+    /// Differences packed into `bits` bits each
+    Packed { bits: u32, words: Box<[u32]> },
+    /// Differences too large to pack into 32 bits, as varints
+    Varint(Box<[u8]>),
+}
+
+impl PackedPostings {
+    /// Compresses a posting list, whose rows must be in strictly ascending order
+    pub fn new(rows: &[usize]) -> Self {
+        debug_assert!(
+            rows.windows(2).all(|pair| pair[0] < pair[1]),
+            "rows must be in strictly ascending order"
+        );
+        let mut blocks = vec![];
+        let mut previous = 0;
+        let mut chunks = rows.chunks_exact(BLOCK_LEN);
+        for chunk in chunks.by_ref() {
+            let mut differences = [0; BLOCK_LEN];
+            let mut packable = true;
+            for (difference, &row) in differences.iter_mut().zip(chunk) {
+                match u32::try_from(row - previous) {
+                    Ok(value) => *difference = value,
+                    Err(_) => packable = false,
+                }
+                previous = row;
+            }
+            let differences = match packable {
+                true => {
+                    let bits = simd::bits_needed(&differences);
+                    let words = simd::pack(&differences, bits).into_boxed_slice();
+                    Differences::Packed { bits, words }
+                }
+                false => Differences::Varint(varints(block_base(&blocks), chunk).into()),
+            };
+            blocks.push(Block {
+                last: previous,
+                differences,
+            });
+        }
+        Self {
+            len: rows.len(),
+            tail: varints(previous, chunks.remainder()),
+            blocks,
+        }
+    }
+
+    /// Gets the number of rows in the posting list
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Checks if the posting list holds no rows
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Gets roughly how many bytes the compressed posting list takes up
+    pub fn size_in_bytes(&self) -> usize {
+        let blocks = self.blocks.iter().map(|block| match &block.differences {
+            Differences::Packed { words, .. } => words.len() * 4,
+            Differences::Varint(bytes) => bytes.len(),
+        });
+        std::mem::size_of::<Self>()
+            + self.blocks.len() * std::mem::size_of::<Block>()
+            + blocks.sum::<usize>()
+            + self.tail.len()
+    }
+
+    /// Decompresses every row, in ascending order
+    pub fn rows(&self) -> Vec<usize> {
+        let mut rows = Vec::with_capacity(self.len);
+        let mut scratch = [0; BLOCK_LEN];
+        let mut base = 0;
+        for block in &self.blocks {
+            decode_block(base, &block.differences, &mut scratch, &mut rows);
+            base = block.last;
+        }
+        decode_varints(base, &self.tail, &mut rows);
+        rows
+    }
+
+    /// Gets the rows found both in this posting list and in the given rows, which must be in
+    /// strictly ascending order. Only the blocks that may hold any of the given rows are
+    /// decompressed.
+    pub fn intersect(&self, rows: &[usize]) -> Vec<usize> {
+        let mut output = vec![];
+        let mut decoded = Vec::with_capacity(BLOCK_LEN);
+        let mut scratch = [0; BLOCK_LEN];
+        let mut rows = rows;
+        let mut base = 0;
+        for block in &self.blocks {
+            // the block holds rows after its base, up to and including its last row
+            let start = match base {
+                0 => 0,
+                base => rows.partition_point(|&row| row <= base),
+            };
+            rows = &rows[start..];
+            let end = rows.partition_point(|&row| row <= block.last);
+            if end > 0 {
+                decoded.clear();
+                decode_block(base, &block.differences, &mut scratch, &mut decoded);
+                simd::intersect(&decoded, &rows[..end], &mut output);
+            }
+            rows = &rows[end..];
+            base = block.last;
+            if rows.is_empty() {
+                return output;
+            }
+        }
+        decoded.clear();
+        decode_varints(base, &self.tail, &mut decoded);
+        simd::intersect(&decoded, rows, &mut output);
+        output
+    }
+}
+
+impl From<&[usize]> for PackedPostings {
+    fn from(rows: &[usize]) -> Self {
+        Self::new(rows)
+    }
+}
+
+/// Gets the row the next block starts from
+fn block_base(blocks: &[Block]) -> usize {
+    blocks.last().map_or(0, |block| block.last)
+}
+
+/// Appends the rows of a block, which follow `base`
+fn decode_block(
+    base: usize,
+    differences: &Differences,
+    scratch: &mut [u32; BLOCK_LEN],
+    rows: &mut Vec<usize>,
+) {
+    match differences {
+        Differences::Packed { bits, words } => {
+            simd::unpack(words, *bits, scratch);
+            // the sums of the differences of a block fit in 32 bits when they are this small
+            if *bits <= u32::BITS - BLOCK_LEN.trailing_zeros() {
+                simd::prefix_sum(scratch);
+                rows.extend(scratch.iter().map(|&sum| base + sum as usize));
+            } else {
+                let mut row = base;
+                rows.extend(scratch.iter().map(|&difference| {
+                    row += difference as usize;
+                    row
+                }));
+            }
+        }
+        Differences::Varint(bytes) => decode_varints(base, bytes, rows),
+    }
+}
+
+/// Writes the difference of every row from the row before it as a varint
+fn varints(mut previous: usize, rows: &[usize]) -> Vec<u8> {
+    let mut bytes = vec![];
+    for &row in rows {
+        let mut difference = row - previous;
+        while difference >= 0x80 {
+            bytes.push(difference as u8 | 0x80);
+            difference >>= 7;
+        }
+        bytes.push(difference as u8);
+        previous = row;
+    }
+    bytes
+}
+
+/// Appends the rows whose differences are written as varints, following `previous`
+fn decode_varints(mut previous: usize, bytes: &[u8], rows: &mut Vec<usize>) {
+    let (mut difference, mut shift) = (0, 0);
+    for &byte in bytes {
+        difference |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            previous += difference;
+            rows.push(previous);
+            (difference, shift) = (0, 0);
+        } else {
+            shift += 7;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn compress_posting_lists() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(5);
+        let mut dense = (0..1000).filter(|row| row % 3 != 0).collect::<Vec<_>>();
+        let sparse = {
+            let mut rows = (0..700)
+                .map(|_| rng.gen_range(0..1 << 40))
+                .collect::<Vec<usize>>();
+            rows.sort_unstable();
+            rows.dedup();
+            rows
+        };
+        for rows in [vec![], vec![0], dense.clone(), sparse] {
+            let packed = PackedPostings::new(&rows);
+            assert_eq!(packed.len(), rows.len());
+            assert_eq!(packed.rows(), rows);
+        }
+
+        // the differences of every row are 1 or 2 bits wide
+        let packed = PackedPostings::new(&dense);
+        assert!(packed.size_in_bytes() < dense.len());
+        dense.push(usize::MAX);
+        assert_eq!(PackedPostings::new(&dense).rows(), dense);
+    }
+
+    #[test]
+    fn intersect_compressed_lists() {
+        let rows = (0..5000).step_by(3).collect::<Vec<_>>();
+        let packed = PackedPostings::new(&rows);
+        let other = (2000..2600)
+            .step_by(2)
+            .chain([4998, 7000])
+            .collect::<Vec<_>>();
+        let expected = other
+            .iter()
+            .copied()
+            .filter(|row| row % 3 == 0 && *row < 5000)
+            .collect::<Vec<_>>();
+        assert_eq!(packed.intersect(&other), expected);
+        assert_eq!(packed.intersect(&[0, 1, 3]), [0, 3]);
+        assert!(packed.intersect(&[]).is_empty());
+        assert!(PackedPostings::default().intersect(&other).is_empty());
+    }
+}
@@ -0,0 +1,345 @@
+//! The kernels decoding and intersecting posting lists, which dominate the time spent searching
+//! large indexes.
+//!
+//! With the `simd` feature, x86_64 processors unpack blocks and sum their differences with SSE2
+//! instructions, which they always have, and intersect posting lists with AVX2 instructions if
+//! they have them. Every other processor runs scalar code giving the same results.
+//!
+//! Blocks are packed the way SIMD registers read them: value `i` of a block is packed into lane
+//! `i % 4`, and the words of the four lanes are interleaved, so a single 128-bit load reads the
+//! next word of every lane.
+
+/// The number of values packed into a block
+pub const BLOCK_LEN: usize = 128;
+
+/// The number of 32-bit lanes values are packed side by side in
+const LANES: usize = 4;
+
+/// Skewed lists are intersected by searching the longer list for every row of the shorter one,
+/// once it is this many times longer
+const GALLOP_RATIO: usize = 32;
+
+/// Gets the fewest bits every value fits in
+pub fn bits_needed(values: &[u32]) -> u32 {
+    let max = values.iter().fold(0, |max, value| max | value);
+    u32::BITS - max.leading_zeros()
+}
+
+/// Packs a block of values, which must fit in `bits` bits, into `bits * 4` words
+pub fn pack(values: &[u32; BLOCK_LEN], bits: u32) -> Vec<u32> {
+    let mut words = vec![0; bits as usize * LANES];
+    if bits == 0 {
+        return words;
+    }
+    for lane in 0..LANES {
+        let (mut word, mut shift) = (0, 0);
+        for value in values.iter().skip(lane).step_by(LANES) {
+            words[word * LANES + lane] |= value << shift;
+            if shift + bits >= u32::BITS {
+                word += 1;
+                // the value is split between two words
+                if shift + bits > u32::BITS {
+                    words[word * LANES + lane] |= value >> (u32::BITS - shift);
+                }
+                shift = shift + bits - u32::BITS;
+            } else {
+                shift += bits;
+            }
+        }
+    }
+    words
+}
+
+/// Unpacks a block of values packed into `bits * 4` words by [`pack`]
+pub fn unpack(words: &[u32], bits: u32, values: &mut [u32; BLOCK_LEN]) {
+    assert_eq!(words.len(), bits as usize * LANES, "not a packed block");
+    if bits == 0 {
+        values.fill(0);
+        return;
+    }
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    {
+        x86::unpack(words, bits, values)
+    }
+    #[cfg(not(all(feature = "simd", target_arch = "x86_64")))]
+    {
+        scalar::unpack(words, bits, values)
+    }
+}
+
+/// Replaces every value of a block by the sum of the values up to and including it. The sums
+/// wrap around if they do not fit in 32 bits.
+pub fn prefix_sum(values: &mut [u32; BLOCK_LEN]) {
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    {
+        x86::prefix_sum(values)
+    }
+    #[cfg(not(all(feature = "simd", target_arch = "x86_64")))]
+    {
+        scalar::prefix_sum(values)
+    }
+}
+
+/// Appends the rows found in both lists to `output`. Both lists must be in strictly ascending
+/// order, and so are the rows appended.
+pub fn intersect(left: &[usize], right: &[usize], output: &mut impl Extend<usize>) {
+    let (short, long) = match left.len() <= right.len() {
+        true => (left, right),
+        false => (right, left),
+    };
+    if short.is_empty() {
+        return;
+    }
+    if long.len() / short.len() >= GALLOP_RATIO {
+        return scalar::gallop(short, long, output);
+    }
+    #[cfg(all(feature = "simd", target_arch = "x86_64"))]
+    if is_x86_feature_detected!("avx2") {
+        // SAFETY: the processor was just checked to support AVX2
+        return unsafe { x86::intersect_avx2(short, long, output) };
+    }
+    scalar::intersect(short, long, output)
+}
+
+/// The kernels used when SIMD instructions are not, which also check the SIMD kernels
+mod scalar {
+    use super::{BLOCK_LEN, LANES};
+
+    #[cfg_attr(all(feature = "simd", target_arch = "x86_64"), allow(dead_code))]
+    pub fn unpack(words: &[u32], bits: u32, values: &mut [u32; BLOCK_LEN]) {
+        let mask = u32::MAX >> (u32::BITS - bits);
+        for lane in 0..LANES {
+            let (mut word, mut shift) = (0, 0);
+            let mut current = words[lane];
+            for value in values.iter_mut().skip(lane).step_by(LANES) {
+                let mut unpacked = current >> shift;
+                if shift + bits >= u32::BITS {
+                    word += 1;
+                    if word < bits as usize {
+                        let next = words[word * LANES + lane];
+                        if shift + bits > u32::BITS {
+                            unpacked |= next << (u32::BITS - shift);
+                        }
+                        current = next;
+                    }
+                    shift = shift + bits - u32::BITS;
+                } else {
+                    shift += bits;
+                }
+                *value = unpacked & mask;
+            }
+        }
+    }
+
+    #[cfg_attr(all(feature = "simd", target_arch = "x86_64"), allow(dead_code))]
+    pub fn prefix_sum(values: &mut [u32; BLOCK_LEN]) {
+        let mut sum = 0u32;
+        for value in values {
+            sum = sum.wrapping_add(*value);
+            *value = sum;
+        }
+    }
+
+    /// Merges both lists, stepping through whichever list has the lower row
+    pub fn intersect(short: &[usize], long: &[usize], output: &mut impl Extend<usize>) {
+        let (mut i, mut j) = (0, 0);
+        while i < short.len() && j < long.len() {
+            match short[i].cmp(&long[j]) {
+                std::cmp::Ordering::Less => i += 1,
+                std::cmp::Ordering::Greater => j += 1,
+                std::cmp::Ordering::Equal => {
+                    output.extend([short[i]]);
+                    i += 1;
+                    j += 1;
+                }
+            }
+        }
+    }
+
+    /// Searches the long list for every row of the short list, in steps doubling in size from
+    /// where the last row was found
+    pub fn gallop(short: &[usize], mut long: &[usize], output: &mut impl Extend<usize>) {
+        for &row in short {
+            let mut step = 1;
+            while step < long.len() && long[step] < row {
+                step *= 2;
+            }
+            let end = (step + 1).min(long.len());
+            match long[..end].binary_search(&row) {
+                Ok(found) => {
+                    output.extend([row]);
+                    long = &long[found + 1..];
+                }
+                Err(next) => long = &long[next..],
+            }
+            if long.is_empty() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+mod x86 {
+    use std::arch::x86_64::*;
+
+    use super::{BLOCK_LEN, LANES};
+
+    pub fn unpack(words: &[u32], bits: u32, values: &mut [u32; BLOCK_LEN]) {
+        let load = |word: usize| {
+            debug_assert!(word < bits as usize);
+            // SAFETY: the block holds `bits` words for every lane
+            unsafe { _mm_loadu_si128(words.as_ptr().add(word * LANES).cast()) }
+        };
+        // SAFETY: every x86_64 processor has SSE2
+        unsafe {
+            let mask = _mm_set1_epi32((u32::MAX >> (u32::BITS - bits)) as i32);
+            let (mut word, mut shift) = (0, 0);
+            let mut current = load(0);
+            for chunk in values.chunks_exact_mut(LANES) {
+                let mut unpacked = _mm_srl_epi32(current, _mm_cvtsi32_si128(shift as i32));
+                if shift + bits >= u32::BITS {
+                    word += 1;
+                    if word < bits as usize {
+                        let next = load(word);
+                        if shift + bits > u32::BITS {
+                            let count = _mm_cvtsi32_si128((u32::BITS - shift) as i32);
+                            unpacked = _mm_or_si128(unpacked, _mm_sll_epi32(next, count));
+                        }
+                        current = next;
+                    }
+                    shift = shift + bits - u32::BITS;
+                } else {
+                    shift += bits;
+                }
+                _mm_storeu_si128(chunk.as_mut_ptr().cast(), _mm_and_si128(unpacked, mask));
+            }
+        }
+    }
+
+    pub fn prefix_sum(values: &mut [u32; BLOCK_LEN]) {
+        // SAFETY: every x86_64 processor has SSE2
+        unsafe {
+            let mut carry = _mm_setzero_si128();
+            for chunk in values.chunks_exact_mut(LANES) {
+                let mut sums = _mm_loadu_si128(chunk.as_ptr().cast());
+                sums = _mm_add_epi32(sums, _mm_slli_si128::<4>(sums));
+                sums = _mm_add_epi32(sums, _mm_slli_si128::<8>(sums));
+                sums = _mm_add_epi32(sums, carry);
+                carry = _mm_shuffle_epi32::<0xFF>(sums);
+                _mm_storeu_si128(chunk.as_mut_ptr().cast(), sums);
+            }
+        }
+    }
+
+    /// Compares every row of the short list with four rows of the long list at a time, skipping
+    /// the rows of the long list that are lower
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn intersect_avx2(short: &[usize], long: &[usize], output: &mut impl Extend<usize>) {
+        const WIDTH: usize = 4;
+        let mut j = 0;
+        for &row in short {
+            while j + WIDTH <= long.len() && long[j + WIDTH - 1] < row {
+                j += WIDTH;
+            }
+            if j + WIDTH > long.len() {
+                while j < long.len() && long[j] < row {
+                    j += 1;
+                }
+                if j == long.len() {
+                    return;
+                }
+                if long[j] == row {
+                    output.extend([row]);
+                }
+                continue;
+            }
+            // SAFETY: the four rows from `j` are within the long list
+            let rows = unsafe { _mm256_loadu_si256(long.as_ptr().add(j).cast()) };
+            let found = _mm256_cmpeq_epi64(rows, _mm256_set1_epi64x(row as i64));
+            if _mm256_movemask_epi8(found) != 0 {
+                output.extend([row]);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+
+    fn block(bits: u32, rng: &mut impl Rng) -> [u32; BLOCK_LEN] {
+        let max = u32::MAX >> (u32::BITS - bits.max(1));
+        std::array::from_fn(|_| match bits {
+            0 => 0,
+            _ => rng.gen_range(0..=max),
+        })
+    }
+
+    fn rows(len: usize, spread: usize, rng: &mut impl Rng) -> Vec<usize> {
+        let mut rows = (0..len)
+            .map(|_| rng.gen_range(0..len * spread))
+            .collect::<Vec<_>>();
+        rows.sort_unstable();
+        rows.dedup();
+        rows
+    }
+
+    #[test]
+    fn pack_every_width() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+        for bits in 0..=u32::BITS {
+            let values = block(bits, &mut rng);
+            assert!(bits_needed(&values) <= bits);
+            let words = pack(&values, bits);
+            assert_eq!(words.len(), bits as usize * LANES);
+
+            let mut unpacked = [u32::MAX; BLOCK_LEN];
+            unpack(&words, bits, &mut unpacked);
+            assert_eq!(unpacked, values, "{bits} bits");
+            if bits > 0 {
+                let mut scalar = [u32::MAX; BLOCK_LEN];
+                scalar::unpack(&words, bits, &mut scalar);
+                assert_eq!(scalar, values, "{bits} bits");
+            }
+        }
+    }
+
+    #[test]
+    fn sum_blocks() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(11);
+        let values = block(28, &mut rng);
+        let mut sums = values;
+        prefix_sum(&mut sums);
+        let mut expected = values;
+        scalar::prefix_sum(&mut expected);
+        assert_eq!(sums, expected);
+        assert_eq!(sums[1], values[0].wrapping_add(values[1]));
+    }
+
+    #[test]
+    fn intersect_sorted_lists() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(3);
+        for (left, right) in [(0, 100), (100, 100), (1000, 900), (10, 5000), (3, 2)] {
+            let left = rows(left, 4, &mut rng);
+            let right = rows(right, 4, &mut rng);
+            let expected = left
+                .iter()
+                .copied()
+                .filter(|row| right.binary_search(row).is_ok())
+                .collect::<Vec<_>>();
+
+            let mut output = vec![];
+            intersect(&left, &right, &mut output);
+            assert_eq!(output, expected);
+            output.clear();
+            intersect(&right, &left, &mut output);
+            assert_eq!(output, expected);
+            output.clear();
+            scalar::gallop(&left, &right, &mut output);
+            assert_eq!(output, expected);
+        }
+    }
+}
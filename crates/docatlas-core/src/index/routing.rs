@@ -0,0 +1,148 @@
+//! Routing places documents into shards based on the value of a field.
+//!
+//! Documents are routed by the routing key of their schema, falling back to the primary key. Both
+//! are hashed in their encoded form, so a document and a query for the same value are always
+//! routed to the same shard.
+
+use std::num::NonZeroUsize;
+
+use crate::codec::{encode_cell, RowEncodeError};
+use crate::document::Document;
+use crate::fields::FieldData;
+use crate::schema::{Schema, SchemaField};
+
+use super::fnv1a;
+
+/// Routes documents to one of a fixed number of shards
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Router {
+    shards: NonZeroUsize,
+}
+
+impl Router {
+    /// Creates a router over a number of shards
+    pub fn new(shards: NonZeroUsize) -> Self {
+        Self { shards }
+    }
+
+    /// Gets the number of shards routed to
+    pub fn shards(&self) -> usize {
+        self.shards.get()
+    }
+
+    /// Gets the shard of an encoded row. Rows of a schema with neither a routing key nor a primary
+    /// key are routed by all of their bytes.
+    pub fn route_row(&self, schema: &Schema, row: &[u8]) -> usize {
+        match routing_field(schema).and_then(|field| schema.field_range(&field.name)) {
+            Some(range) => self.shard(&row[range]),
+            None => self.shard(row),
+        }
+    }
+
+    /// Gets the shard of a document. A document missing its routing field is routed as if the
+    /// field had no data.
+    pub fn route(&self, schema: &Schema, document: &Document) -> Result<usize, RowEncodeError> {
+        let Some(field) = routing_field(schema) else {
+            let mut row = vec![0_u8; schema.row_size()];
+            schema.codec().encode(document, &mut row)?;
+            return Ok(self.shard(&row));
+        };
+        let mut cell = vec![0_u8; field.kind.size()];
+        if let Some([data, ..]) = document.get(&field.name).map(|field| field.data()) {
+            encode_cell(&field.kind, data, &mut cell)?;
+        }
+        Ok(self.shard(&cell))
+    }
+
+    /// Gets the only shard that can contain documents with the given value for their routing
+    /// field, allowing queries on that value to skip every other shard. Returns `None` if the
+    /// schema has no routing field, in which case every shard must be searched.
+    pub fn shard_for_value(
+        &self,
+        schema: &Schema,
+        data: &FieldData,
+    ) -> Result<Option<usize>, RowEncodeError> {
+        let Some(field) = routing_field(schema) else {
+            return Ok(None);
+        };
+        let mut cell = vec![0_u8; field.kind.size()];
+        encode_cell(&field.kind, data, &mut cell)?;
+        Ok(Some(self.shard(&cell)))
+    }
+
+    fn shard(&self, bytes: &[u8]) -> usize {
+        (fnv1a(bytes) % self.shards.get() as u64) as usize
+    }
+}
+
+/// Gets the field documents of a schema are routed by
+fn routing_field(schema: &Schema) -> Option<&SchemaField> {
+    schema.routing_key().or_else(|| schema.primary_key())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::fields::{Field, FieldKind, Fields};
+
+    fn schema() -> Schema {
+        Schema::from_iter([
+            SchemaField {
+                name: "id".to_string(),
+                kind: FieldKind::Keyword(8),
+            },
+            SchemaField {
+                name: "tenant".to_string(),
+                kind: FieldKind::Keyword(8),
+            },
+        ])
+        .with_primary_key("id")
+        .with_routing_key("tenant")
+    }
+
+    fn document(id: &str, tenant: &str) -> Document {
+        let keyword = |value: &str| {
+            Field::new(
+                FieldKind::Keyword(8),
+                [FieldData::Bytes(Arc::from(value.as_bytes()))],
+            )
+        };
+        Document::from(Fields::from_iter([
+            ("id", keyword(id)),
+            ("tenant", keyword(tenant)),
+        ]))
+    }
+
+    #[test]
+    fn documents_with_the_same_routing_value_co_locate() {
+        let schema = schema();
+        let router = Router::new(NonZeroUsize::new(16).unwrap());
+        let tenant = FieldData::Bytes(Arc::from(&b"acme"[..]));
+        let shard = router
+            .shard_for_value(&schema, &tenant)
+            .unwrap()
+            .expect("schema has a routing key");
+
+        for id in ["a", "b", "c", "d"] {
+            let document = document(id, "acme");
+            assert_eq!(router.route(&schema, &document).unwrap(), shard);
+
+            let mut row = vec![0_u8; schema.row_size()];
+            schema.codec().encode(&document, &mut row).unwrap();
+            assert_eq!(router.route_row(&schema, &row), shard);
+        }
+    }
+
+    #[test]
+    fn schemas_without_routing_fields_search_every_shard() {
+        let schema = Schema::from_iter([SchemaField {
+            name: "id".to_string(),
+            kind: FieldKind::Keyword(8),
+        }]);
+        let router = Router::new(NonZeroUsize::new(4).unwrap());
+        let data = FieldData::Bytes(Arc::from(&b"a"[..]));
+        assert_eq!(router.shard_for_value(&schema, &data).unwrap(), None);
+    }
+}
@@ -0,0 +1,465 @@
+//! Searchers read an index as it was at a commit point, while its writer keeps writing.
+//!
+//! The rows of a commit point are split into segments of [`SEGMENT_ROWS`] rows, each with its own
+//! postings and the set of its rows holding no document. A writer remembers which segments it
+//! changed since its last commit point, so the next one only copies those, and shares every other
+//! segment with the commit points before it. Blobs are append only, so a commit point only copies
+//! the blobs appended since the last one.
+//!
+//! A searcher pins its commit point for as long as it lives, so its searches never see a write
+//! that was only partly applied, nor any write made after the searcher was acquired.
+
+use std::collections::BTreeSet;
+use std::ops::Range;
+use std::sync::Arc;
+
+use crate::blob::{BlobSnapshot, Blobs};
+use crate::cancel::{CancelToken, Cancelled};
+use crate::codec::RowDecodeError;
+use crate::document::{Document, StoredDocument};
+use crate::query::arena::QueryArena;
+use crate::query::cache::FilterCache;
+use crate::query::regexp::Regexp;
+use crate::schema::Schema;
+
+use super::postings::{self, Postings};
+use super::IndexWriter;
+
+/// The number of rows in every segment of a commit point
+pub const SEGMENT_ROWS: usize = 4096;
+
+/// Reads the documents of an index, through either its [writer](IndexWriter) or a [`Searcher`]
+pub trait IndexReader {
+    /// Gets the schema of the index
+    fn schema(&self) -> &Schema;
+
+    /// Gets the number of rows in the index
+    fn len(&self) -> usize;
+
+    /// Checks if the index contains no rows
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Gets the raw bytes of a row, if present
+    fn row(&self, index: usize) -> Option<&[u8]>;
+
+    /// Gets the blobs referenced by the rows, if the index stores blobs
+    fn blobs(&self) -> Option<&dyn Blobs>;
+
+    /// Gets the fields of the document stored in a row without copying them, which borrow from
+    /// the reader
+    fn stored(&self, index: usize) -> Option<StoredDocument<'_>> {
+        let row = self.row(index)?;
+        Some(StoredDocument::new(self.schema(), row, self.blobs()))
+    }
+
+    /// Decodes the document stored in a row, including any blobs it references
+    fn read(&self, index: usize) -> Option<Result<Document, RowDecodeError>> {
+        self.stored(index).map(|document| document.to_document())
+    }
+
+    /// Searches like [`search_partial`](Self::search_partial), keeping the terms and rows it looks
+    /// up in the arena of the query running the search
+    fn search_in(
+        &self,
+        field: &str,
+        query: &str,
+        cancel: &CancelToken,
+        arena: &QueryArena,
+    ) -> (Vec<usize>, bool);
+
+    /// Searches like [`IndexWriter::search`], stopping early once the token is cancelled.
+    /// Returns the rows matched so far, and whether the search finished.
+    fn search_partial(&self, field: &str, query: &str, cancel: &CancelToken) -> (Vec<usize>, bool) {
+        QueryArena::scoped(|arena| self.search_in(field, query, cancel, arena))
+    }
+
+    /// Finds the rows whose field holds a term matched by a regexp in ascending order, stopping
+    /// early once the token is cancelled. Returns the rows matched so far, and whether the search
+    /// finished.
+    fn search_regexp(
+        &self,
+        field: &str,
+        regexp: &Regexp,
+        cancel: &CancelToken,
+    ) -> (Vec<usize>, bool);
+
+    /// Gets the cache of the rows matched by the filter clauses of queries
+    fn filter_cache(&self) -> &FilterCache;
+
+    /// Checks if a row holds a document, rather than one that was deleted
+    fn is_live(&self, index: usize) -> bool {
+        // deleting a document clears its row
+        self.row(index)
+            .is_some_and(|cells| cells.iter().any(|&byte| byte != 0))
+    }
+
+    /// Finds the rows whose field contains every term of a query, in ascending order
+    fn search(&self, field: &str, query: &str) -> Vec<usize> {
+        self.search_until(field, query, &CancelToken::new())
+            .expect("never cancelled")
+    }
+
+    /// Searches like [`search`](Self::search), stopping early once the token is cancelled
+    fn search_until(
+        &self,
+        field: &str,
+        query: &str,
+        cancel: &CancelToken,
+    ) -> Result<Vec<usize>, Cancelled> {
+        match self.search_partial(field, query, cancel) {
+            (rows, true) => Ok(rows),
+            (_, false) => Err(Cancelled),
+        }
+    }
+
+    /// Pages through the rows holding documents like [`IndexWriter::scan`]
+    fn scan(
+        &self,
+        search: Option<(&str, &str)>,
+        from: usize,
+        limit: usize,
+    ) -> (Vec<usize>, Option<usize>) {
+        if let Some((field, query)) = search {
+            let matched = self.search(field, query);
+            let start = matched.partition_point(|&row| row < from);
+            let rows = matched[start..].iter().copied().take(limit).collect();
+            return (rows, matched.get(start.saturating_add(limit)).copied());
+        }
+        let mut rows = vec![];
+        let mut row = from;
+        while row < self.len() && rows.len() < limit {
+            if self.is_live(row) {
+                rows.push(row);
+            }
+            row += 1;
+        }
+        (rows, (row < self.len()).then_some(row))
+    }
+}
+
+/// Searches an index as it was at a commit point.
+///
+/// Searchers are cheap to clone, and every clone shares the same commit point.
+#[derive(Debug, Clone)]
+pub struct Searcher {
+    commit: Arc<Commit>,
+}
+
+#[derive(Debug)]
+struct Commit {
+    /// The number of writes made to the index before the commit point
+    generation: u64,
+    schema: Schema,
+    len: usize,
+    segments: Vec<Arc<Segment>>,
+    blobs: Option<BlobSnapshot>,
+    /// The filter clauses used by the searchers of this commit point, whose rows never change
+    filter_cache: FilterCache,
+}
+
+/// Consecutive rows of a commit point, along with their postings
+#[derive(Debug)]
+struct Segment {
+    first_row: usize,
+    rows: Box<[u8]>,
+    postings: Postings,
+    /// A bit for every row that holds no document
+    deleted: Box<[u64]>,
+}
+
+/// What a writer keeps of its last commit point to take the next one
+#[derive(Debug, Default)]
+pub(super) struct Commits {
+    last: Option<Searcher>,
+    /// The segments changed since the last commit point
+    dirty: BTreeSet<usize>,
+}
+
+impl Commits {
+    /// Marks the segments holding the given rows as changed
+    pub(super) fn touch(&mut self, rows: Range<usize>) {
+        if rows.is_empty() {
+            return;
+        }
+        self.dirty
+            .extend(rows.start / SEGMENT_ROWS..=(rows.end - 1) / SEGMENT_ROWS);
+    }
+
+    /// Gets a searcher over the rows of a writer as they are now, sharing the segments that did
+    /// not change since the last commit point
+    pub(super) fn commit(&mut self, writer: &IndexWriter, generation: u64) -> Searcher {
+        if let Some(last) = &self.last {
+            if last.commit.generation == generation {
+                return last.clone();
+            }
+        }
+        let previous = self.last.take();
+        let previous = previous.as_ref().map(|last| &*last.commit);
+        let len = writer.len();
+        let segments = (0..len.div_ceil(SEGMENT_ROWS))
+            .map(|segment| {
+                let kept = previous
+                    .and_then(|previous| previous.segments.get(segment))
+                    .filter(|_| !self.dirty.contains(&segment));
+                match kept {
+                    Some(kept) => Arc::clone(kept),
+                    None => {
+                        let rows = segment * SEGMENT_ROWS..((segment + 1) * SEGMENT_ROWS).min(len);
+                        Arc::new(Segment::new(writer, rows))
+                    }
+                }
+            })
+            .collect();
+        let blobs = writer.blobs().map(|blobs| {
+            previous
+                .and_then(|previous| previous.blobs.clone())
+                .unwrap_or_default()
+                .update(blobs)
+        });
+        let cache = writer.filter_cache();
+        let searcher = Searcher {
+            commit: Arc::new(Commit {
+                generation,
+                schema: writer.schema().clone(),
+                len,
+                segments,
+                blobs,
+                filter_cache: FilterCache::new(cache.max_bytes()).with_min_uses(cache.min_uses()),
+            }),
+        };
+        self.dirty.clear();
+        self.last = Some(searcher.clone());
+        searcher
+    }
+}
+
+impl Segment {
+    fn new(writer: &IndexWriter, rows: Range<usize>) -> Self {
+        let row_size = writer.schema().row_size();
+        let bytes = writer
+            .rows
+            .get(rows.start * row_size..rows.end * row_size)
+            .expect("segments are within the rows");
+        let mut postings = Postings::new();
+        postings.insert_rows(writer.schema(), rows.start, bytes);
+        let mut deleted = vec![0_u64; rows.len().div_ceil(64)];
+        for (offset, row) in bytes.chunks_exact(row_size).enumerate() {
+            if row.iter().all(|&byte| byte == 0) {
+                deleted[offset / 64] |= 1 << (offset % 64);
+            }
+        }
+        Self {
+            first_row: rows.start,
+            rows: bytes.into(),
+            postings,
+            deleted: deleted.into(),
+        }
+    }
+}
+
+impl Searcher {
+    /// Gets the number of writes made to the index before the commit point of the searcher
+    pub fn generation(&self) -> u64 {
+        self.commit.generation
+    }
+
+    fn segment(&self, row: usize) -> Option<&Segment> {
+        self.commit
+            .segments
+            .get(row / SEGMENT_ROWS)
+            .map(|segment| &**segment)
+    }
+}
+
+impl IndexReader for Searcher {
+    fn schema(&self) -> &Schema {
+        &self.commit.schema
+    }
+
+    fn len(&self) -> usize {
+        self.commit.len
+    }
+
+    fn row(&self, index: usize) -> Option<&[u8]> {
+        let row_size = self.commit.schema.row_size();
+        let segment = self.segment(index)?;
+        let offset = index - segment.first_row;
+        segment.rows.get(offset * row_size..(offset + 1) * row_size)
+    }
+
+    fn blobs(&self) -> Option<&dyn Blobs> {
+        self.commit.blobs.as_ref().map(|blobs| blobs as &dyn Blobs)
+    }
+
+    fn search_in(
+        &self,
+        field: &str,
+        query: &str,
+        cancel: &CancelToken,
+        arena: &QueryArena,
+    ) -> (Vec<usize>, bool) {
+        let Some(field) = self.commit.schema.get(field) else {
+            return (vec![], true);
+        };
+        let terms = postings::terms_in(&field.kind, query.as_bytes(), arena);
+        let mut rows = vec![];
+        for segment in &self.commit.segments {
+            let (matched, complete) =
+                segment
+                    .postings
+                    .intersect_in(&field.name, &terms, cancel, arena);
+            rows.extend(matched);
+            if !complete {
+                return (rows, false);
+            }
+        }
+        (rows, true)
+    }
+
+    fn search_regexp(
+        &self,
+        field: &str,
+        regexp: &Regexp,
+        cancel: &CancelToken,
+    ) -> (Vec<usize>, bool) {
+        let mut rows = vec![];
+        // every segment walks terms of its own, and holds rows after those of the segments before
+        for segment in &self.commit.segments {
+            let (matched, complete) = segment.postings.regexp_rows(field, regexp, cancel);
+            rows.extend(matched);
+            if !complete {
+                return (rows, false);
+            }
+        }
+        (rows, true)
+    }
+
+    fn filter_cache(&self) -> &FilterCache {
+        &self.commit.filter_cache
+    }
+
+    fn is_live(&self, index: usize) -> bool {
+        self.segment(index).is_some_and(|segment| {
+            let offset = index - segment.first_row;
+            offset < segment.rows.len() / self.commit.schema.row_size()
+                && segment.deleted[offset / 64] & (1 << (offset % 64)) == 0
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blob::BlobSegment;
+    use crate::fields::{Field, FieldData, FieldKind, Fields, StoredValue};
+    use crate::index::UpsertMode;
+    use crate::persist::PersistentVec;
+    use crate::query::Query;
+    use crate::schema::SchemaField;
+
+    fn writer() -> IndexWriter {
+        let schema = Schema::from_iter(
+            [
+                ("id", FieldKind::Keyword(16)),
+                ("status", FieldKind::Keyword(16)),
+                ("cover", FieldKind::Blob),
+            ]
+            .map(|(name, kind)| SchemaField {
+                name: name.to_string(),
+                kind,
+            }),
+        )
+        .with_primary_key("id");
+        IndexWriter::new(schema, PersistentVec::in_memory())
+            .with_blob_segment(BlobSegment::new(PersistentVec::in_memory()))
+    }
+
+    fn document(id: &str, status: &str) -> Document {
+        let text = |text: &str| FieldData::Bytes(text.as_bytes().into());
+        let mut fields = Fields::new();
+        fields.insert("id", Field::new(FieldKind::Keyword(16), [text(id)]));
+        fields.insert("status", Field::new(FieldKind::Keyword(16), [text(status)]));
+        fields.insert(
+            "cover",
+            Field::new(FieldKind::Blob, [FieldData::Blob(id.as_bytes().into())]),
+        );
+        Document::from(fields)
+    }
+
+    #[test]
+    fn searchers_see_their_commit_point() {
+        let mut writer = writer();
+        let documents = (0..SEGMENT_ROWS + 10).map(|row| {
+            let status = match row % 2 {
+                0 => "draft",
+                _ => "published",
+            };
+            document(&format!("b{row}"), status)
+        });
+        writer.add_documents(documents);
+        let searcher = writer.searcher();
+        assert_eq!(searcher.generation(), writer.searcher().generation());
+        assert_eq!(
+            searcher.search("status", "published").len(),
+            SEGMENT_ROWS / 2 + 5
+        );
+
+        // writes made after the searcher was acquired are not seen
+        writer
+            .upsert(document("b1", "draft"), UpsertMode::Replace)
+            .unwrap();
+        writer
+            .delete(&FieldData::Bytes(b"b3".as_slice().into()))
+            .unwrap();
+        writer.add_documents([document("new", "published")]);
+        assert_eq!(searcher.len(), SEGMENT_ROWS + 10);
+        assert_eq!(searcher.search("status", "published")[..2], [1, 3]);
+        assert!(searcher.is_live(3));
+        let stored = searcher.stored(1).unwrap();
+        assert_eq!(
+            stored.get("status").unwrap(),
+            Some(StoredValue::Bytes(b"published"))
+        );
+        assert_eq!(stored.get("cover").unwrap(), Some(StoredValue::Blob(b"b1")));
+        let cover = searcher.read(1).unwrap().unwrap();
+        assert_eq!(
+            cover.get("cover").unwrap().data(),
+            [FieldData::Blob(b"b1".as_slice().into())]
+        );
+
+        let latest = writer.searcher();
+        assert!(latest.generation() > searcher.generation());
+        assert_eq!(latest.len(), SEGMENT_ROWS + 11);
+        assert_eq!(latest.search("status", "published")[..2], [5, 7]);
+        assert!(!latest.is_live(3));
+        assert_eq!(latest.scan(None, 2, 2), (vec![2, 4], Some(5)));
+        assert!(latest.read(SEGMENT_ROWS + 10).unwrap().is_ok());
+        // segments that were not written to are shared
+        writer.add_documents([document("newer", "draft")]);
+        let next = writer.searcher();
+        assert!(Arc::ptr_eq(
+            &latest.commit.segments[0],
+            &next.commit.segments[0]
+        ));
+        assert!(!Arc::ptr_eq(
+            &latest.commit.segments[1],
+            &next.commit.segments[1]
+        ));
+
+        let published = Query::Term {
+            field: "status".to_string(),
+            value: "published".to_string(),
+        };
+        let cancel = CancelToken::new();
+        assert_eq!(
+            published.rows(&searcher, &cancel).unwrap().len(),
+            SEGMENT_ROWS / 2 + 5
+        );
+        assert_eq!(
+            published.rows(&latest, &cancel).unwrap().len(),
+            SEGMENT_ROWS / 2 + 4
+        );
+    }
+}
@@ -0,0 +1,602 @@
+//! Transactions group writes to an index, so they are applied all at once or not at all.
+//!
+//! A document is spread over several structures of a writer: its row, the postings of its
+//! fields, the doc values built from its row and the primary key lookup. Committing a transaction
+//! first works out what every write does to them without changing anything, running documents
+//! through the ingest pipeline, encoding them and looking up their primary keys. Later writes see
+//! the earlier writes of the transaction, so a document can be inserted and then updated or
+//! deleted by the same transaction, and documents are checked for duplicates against the
+//! documents as they are after the earlier writes. Only once every write is known to succeed are
+//! the rows, postings, fingerprints and primary keys changed, which can not fail.
+//!
+//! Blobs of the documents are staged along with their rows, and only appended to the blob segment
+//! of the writer once the transaction is applied, so a failed transaction leaves no blobs behind.
+//!
+//! A transaction can also be [prepared](IndexWriter::prepare) and [applied](IndexWriter::apply)
+//! separately, so something that may fail, such as logging it, can be done in between. What it
+//! does is only known for the writer as it was when it was prepared, so applying it fails if
+//! anything was written to the writer since.
+//!
+//! Transactions can be serialized, so the daemon stores a whole transaction in a single frame of
+//! the write-ahead log of the index, making it just as atomic after a crash.
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::blob::StagedBlobs;
+use crate::document::Document;
+use crate::fields::FieldData;
+
+use super::{DedupMode, IndexWriter, IndexWriterError, UpsertMode, Upserted};
+
+/// Writes to an index that are committed together
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Transaction {
+    writes: Vec<Write>,
+}
+
+/// A single write of a transaction
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Write {
+    /// Inserts the document, or applies it to the document sharing its primary key
+    Upsert {
+        document: Document,
+        mode: UpsertMode,
+    },
+    /// Deletes the document with the given primary key
+    Delete { key: FieldData },
+}
+
+/// What a write of a committed transaction did
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Written {
+    Upserted(Upserted),
+    /// The row of the deleted document, if there was one
+    Deleted(Option<usize>),
+}
+
+impl Transaction {
+    /// Creates a transaction without any writes
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an upsert of a document
+    pub fn with_upsert(mut self, document: Document, mode: UpsertMode) -> Self {
+        self.writes.push(Write::Upsert { document, mode });
+        self
+    }
+
+    /// Adds a deletion of the document with the given primary key
+    pub fn with_delete(mut self, key: FieldData) -> Self {
+        self.writes.push(Write::Delete { key });
+        self
+    }
+
+    /// Gets the writes of the transaction, in the order they are applied
+    pub fn writes(&self) -> &[Write] {
+        &self.writes
+    }
+
+    /// Gets the number of writes in the transaction
+    pub fn len(&self) -> usize {
+        self.writes.len()
+    }
+
+    /// Checks whether the transaction has no writes
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+}
+
+impl FromIterator<Write> for Transaction {
+    fn from_iter<I: IntoIterator<Item = Write>>(iter: I) -> Self {
+        Self {
+            writes: iter.into_iter().collect(),
+        }
+    }
+}
+
+impl IntoIterator for Transaction {
+    type Item = Write;
+    type IntoIter = std::vec::IntoIter<Write>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.writes.into_iter()
+    }
+}
+
+/// A transaction whose writes are all known to succeed, which is applied to the writer it was
+/// prepared by as long as nothing else was written to it since
+#[derive(Debug)]
+#[must_use = "a prepared transaction does nothing until applied"]
+pub struct Prepared {
+    staged: Staged,
+    written: Vec<Written>,
+    /// The generation of the writer it was prepared by
+    generation: u64,
+}
+
+impl Prepared {
+    /// Gets what every write of the transaction does once applied, in the order they were given
+    pub fn written(&self) -> &[Written] {
+        &self.written
+    }
+}
+
+/// The changes a transaction makes, before they are applied
+#[derive(Debug, Default)]
+struct Staged {
+    /// The final contents of every row the transaction writes, along with its fingerprint. Rows
+    /// past the end of the writer are appended, and cleared rows are `None`.
+    rows: BTreeMap<usize, Option<StagedRow>>,
+    /// The rows of the primary keys the transaction writes, or `None` if they were deleted
+    keys: HashMap<Box<[u8]>, Option<usize>>,
+    /// The latest row of the fingerprints the transaction writes or overwrites, or `None` if no
+    /// row holds them anymore
+    fingerprints: HashMap<u64, Option<usize>>,
+    /// The row the next inserted document is stored in
+    next_row: usize,
+}
+
+/// A row as it is written by a transaction
+#[derive(Debug)]
+struct StagedRow {
+    row: Vec<u8>,
+    fingerprint: Option<u64>,
+    /// The blobs of the row, which its cells reference once they are appended
+    blobs: StagedBlobs,
+}
+
+impl IndexWriter {
+    /// Applies every write of a transaction, or none of them if any fails. Returns what every
+    /// write did, in the order they were given.
+    pub fn commit(&mut self, transaction: Transaction) -> Result<Vec<Written>, IndexWriterError> {
+        let prepared = self.prepare(&transaction)?;
+        self.apply(prepared)
+    }
+
+    /// Works out what every write of a transaction does without changing anything, failing if
+    /// any of them would
+    #[tracing::instrument(level = "debug", skip_all, fields(writes = transaction.len()))]
+    pub fn prepare(&self, transaction: &Transaction) -> Result<Prepared, IndexWriterError> {
+        let mut staged = Staged {
+            next_row: self.len(),
+            ..Staged::default()
+        };
+        let mut written = Vec::with_capacity(transaction.len());
+        for (position, write) in transaction.writes().iter().enumerate() {
+            let result = match write {
+                Write::Upsert { document, mode } => self
+                    .stage_upsert(&mut staged, document, *mode)
+                    .map(Written::Upserted),
+                Write::Delete { key } => self.stage_delete(&mut staged, key).map(Written::Deleted),
+            };
+            written.push(result.map_err(|e| IndexWriterError::Transaction {
+                write: position,
+                source: Box::new(e),
+            })?);
+        }
+        Ok(Prepared {
+            staged,
+            written,
+            generation: self.generation,
+        })
+    }
+
+    /// Applies a transaction prepared by this writer, which only fails if something was written
+    /// to the writer since. Returns what every write did, in the order they were given.
+    pub fn apply(&mut self, prepared: Prepared) -> Result<Vec<Written>, IndexWriterError> {
+        if prepared.generation != self.generation {
+            return Err(IndexWriterError::Stale);
+        }
+        self.apply_staged(prepared.staged);
+        Ok(prepared.written)
+    }
+
+    /// Gets the row of a primary key as it is after the writes staged so far
+    fn staged_row(&self, staged: &Staged, key: &[u8]) -> Option<usize> {
+        match staged.keys.get(key) {
+            Some(row) => *row,
+            None => self.primary_keys.get(key).copied(),
+        }
+    }
+
+    /// Gets the latest row holding a fingerprint as it is after the writes staged so far
+    fn staged_fingerprint(&self, staged: &Staged, fingerprint: u64) -> Option<usize> {
+        match staged.fingerprints.get(&fingerprint) {
+            Some(row) => *row,
+            None => self.dedup.as_ref()?.get(fingerprint).map(|found| found.row),
+        }
+    }
+
+    /// Checks that an encoded row is not a duplicate of any row as it is after the writes staged
+    /// so far, returning its fingerprint if duplicates are being detected
+    fn check_staged_duplicate(
+        &self,
+        staged: &Staged,
+        row: &[u8],
+        updating: Option<usize>,
+    ) -> Result<Option<u64>, IndexWriterError> {
+        let Some(dedup) = &self.dedup else {
+            return Ok(None);
+        };
+        let fingerprint = dedup.fingerprint(&self.schema, row);
+        match self.staged_fingerprint(staged, fingerprint) {
+            Some(existing) if dedup.mode() == DedupMode::Reject && Some(existing) != updating => {
+                Err(IndexWriterError::Duplicate(existing))
+            }
+            _ => Ok(Some(fingerprint)),
+        }
+    }
+
+    /// Stages the contents of a row, forgetting the fingerprint it held before
+    fn stage_row(&self, staged: &mut Staged, index: usize, row: Option<StagedRow>) {
+        if let Some(dedup) = &self.dedup {
+            let previous = match staged.rows.get(&index) {
+                Some(written) => written.as_ref().and_then(|written| written.fingerprint),
+                None => self
+                    .row(index)
+                    .map(|stored| dedup.fingerprint(&self.schema, stored)),
+            };
+            if let Some(previous) = previous {
+                if self.staged_fingerprint(staged, previous) == Some(index) {
+                    staged.fingerprints.insert(previous, None);
+                }
+            }
+            if let Some(fingerprint) = row.as_ref().and_then(|row| row.fingerprint) {
+                staged.fingerprints.insert(fingerprint, Some(index));
+            }
+        }
+        staged.rows.insert(index, row);
+    }
+
+    fn stage_upsert(
+        &self,
+        staged: &mut Staged,
+        document: &Document,
+        mode: UpsertMode,
+    ) -> Result<Upserted, IndexWriterError> {
+        // only documents that are processed need a copy
+        let document = match self.pipeline.is_empty() {
+            true => Cow::Borrowed(document),
+            false => match self.pipeline.apply(document.clone())? {
+                Some(document) => Cow::Owned(document),
+                None => return Ok(Upserted::Dropped),
+            },
+        };
+        let (mut row, key, mut blobs) = self.encode_keyed(&document)?;
+        match self.staged_row(staged, &key) {
+            Some(index) => {
+                if let UpsertMode::Merge = mode {
+                    let current = match staged.rows.get(&index) {
+                        Some(Some(staged)) => {
+                            // the staged blobs of the cells that are kept are still to be appended
+                            let mut kept = staged.blobs.clone();
+                            kept.retain(|cell| {
+                                document.fields().iter().all(|(name, _)| {
+                                    self.schema.field_range(name).as_ref() != Some(cell)
+                                })
+                            });
+                            blobs.extend(kept);
+                            staged.row.as_slice()
+                        }
+                        _ => self.row(index).expect("keyed rows exist"),
+                    };
+                    row = self.merge_cells(&document, current, &row);
+                }
+                let fingerprint = self.check_staged_duplicate(staged, &row, Some(index))?;
+                let row = StagedRow {
+                    row,
+                    fingerprint,
+                    blobs,
+                };
+                self.stage_row(staged, index, Some(row));
+                Ok(Upserted::Updated(index))
+            }
+            None => {
+                let fingerprint = self.check_staged_duplicate(staged, &row, None)?;
+                let index = staged.next_row;
+                staged.next_row += 1;
+                let row = StagedRow {
+                    row,
+                    fingerprint,
+                    blobs,
+                };
+                self.stage_row(staged, index, Some(row));
+                staged.keys.insert(key, Some(index));
+                Ok(Upserted::Inserted(index))
+            }
+        }
+    }
+
+    fn stage_delete(
+        &self,
+        staged: &mut Staged,
+        key: &FieldData,
+    ) -> Result<Option<usize>, IndexWriterError> {
+        let key = self.key_cell(key)?;
+        let Some(index) = self.staged_row(staged, &key) else {
+            return Ok(None);
+        };
+        self.stage_row(staged, index, None);
+        staged.keys.insert(key, None);
+        Ok(Some(index))
+    }
+
+    /// Writes the staged rows into the rows, postings and fingerprints of the writer, appending
+    /// their blobs, and keys them
+    fn apply_staged(&mut self, staged: Staged) {
+        let (Some(&first), Some(&last)) = (staged.rows.keys().next(), staged.rows.keys().last())
+        else {
+            return;
+        };
+        let row_size = self.schema.row_size();
+        let len = self.len();
+        self.reserve(staged.next_row - len);
+        for (index, mut row) in staged.rows {
+            if let Some(staged) = &mut row {
+                self.append_blobs(std::mem::take(&mut staged.blobs), &mut staged.row);
+            }
+            if index < len {
+                let stored = &mut self.rows[index * row_size..(index + 1) * row_size];
+                if let Some(dedup) = &mut self.dedup {
+                    dedup.forget(dedup.fingerprint(&self.schema, stored), index);
+                }
+                self.postings.remove_row(&self.schema, index, stored);
+                match &row {
+                    Some(staged) => stored.copy_from_slice(&staged.row),
+                    // deleted rows are cleared rather than removed, like by `delete`
+                    None => stored.fill(0),
+                }
+            } else {
+                match &row {
+                    Some(staged) => self.rows.extend_from_slice(&staged.row),
+                    None => self.rows.extend_from_slice(&vec![0; row_size]),
+                }
+            }
+            if let Some(staged) = row {
+                self.postings.insert_row(&self.schema, index, &staged.row);
+                self.record_fingerprint(staged.fingerprint, index);
+            }
+        }
+        for (key, row) in staged.keys {
+            match row {
+                Some(row) => self.primary_keys.insert(key, row),
+                None => self.primary_keys.remove(&key),
+            };
+        }
+        self.touch(first..last + 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::blob::BlobSegment;
+    use crate::fields::{Field, FieldKind, Fields};
+    use crate::index::Deduplication;
+    use crate::persist::PersistentVec;
+    use crate::schema::{Schema, SchemaField};
+
+    use super::*;
+
+    fn writer() -> IndexWriter {
+        let schema = Schema::from_iter([
+            SchemaField {
+                name: "id".to_string(),
+                kind: FieldKind::Keyword(8),
+            },
+            SchemaField {
+                name: "name".to_string(),
+                kind: FieldKind::Text(16),
+            },
+        ])
+        .with_primary_key("id");
+        IndexWriter::new(schema, PersistentVec::in_memory())
+    }
+
+    fn document(id: &str, name: Option<&str>) -> Document {
+        let mut fields = vec![("id", Field::new(FieldKind::Keyword(8), [key(id)]))];
+        if let Some(name) = name {
+            fields.push(("name", Field::new(FieldKind::Text(16), [key(name)])));
+        }
+        fields.into_iter().collect::<Fields>().into()
+    }
+
+    fn key(id: &str) -> FieldData {
+        FieldData::Bytes(id.as_bytes().into())
+    }
+
+    #[test]
+    fn writes_see_earlier_writes() {
+        let mut writer = writer();
+        writer
+            .upsert(document("a", Some("first")), UpsertMode::Replace)
+            .unwrap();
+        let transaction = Transaction::new()
+            .with_upsert(document("b", Some("second")), UpsertMode::Replace)
+            .with_upsert(document("a", None), UpsertMode::Merge)
+            .with_delete(key("a"))
+            .with_upsert(document("a", Some("again")), UpsertMode::Replace)
+            .with_upsert(document("c", Some("gone")), UpsertMode::Replace)
+            .with_delete(key("c"))
+            .with_delete(key("d"));
+        let written = writer.commit(transaction).unwrap();
+        assert_eq!(
+            written,
+            [
+                Written::Upserted(Upserted::Inserted(1)),
+                Written::Upserted(Upserted::Updated(0)),
+                Written::Deleted(Some(0)),
+                Written::Upserted(Upserted::Inserted(2)),
+                Written::Upserted(Upserted::Inserted(3)),
+                Written::Deleted(Some(3)),
+                Written::Deleted(None),
+            ]
+        );
+        assert_eq!(writer.len(), 4);
+        assert_eq!(writer.find(&key("a")).unwrap(), Some(2));
+        assert_eq!(writer.find(&key("c")).unwrap(), None);
+        assert!(writer.row(0).unwrap().iter().all(|&byte| byte == 0));
+        assert!(writer.row(3).unwrap().iter().all(|&byte| byte == 0));
+        assert_eq!(writer.search("name", "first"), Vec::<usize>::new());
+        assert_eq!(writer.search("name", "again"), [2]);
+        assert_eq!(writer.search("name", "second"), [1]);
+        assert_eq!(writer.search("name", "gone"), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn failed_transactions_change_nothing() {
+        let mut writer = writer();
+        writer
+            .upsert(document("a", Some("first")), UpsertMode::Replace)
+            .unwrap();
+        let transaction = Transaction::new()
+            .with_delete(key("a"))
+            .with_upsert(document("b", Some("second")), UpsertMode::Replace)
+            .with_upsert(Fields::new().into(), UpsertMode::Replace);
+        let error = writer.commit(transaction).unwrap_err();
+        assert!(matches!(
+            error,
+            IndexWriterError::Transaction { write: 2, ref source }
+                if matches!(**source, IndexWriterError::MissingPrimaryKey(_))
+        ));
+        assert_eq!(writer.len(), 1);
+        assert_eq!(writer.find(&key("a")).unwrap(), Some(0));
+        assert_eq!(writer.find(&key("b")).unwrap(), None);
+        assert_eq!(writer.search("name", "first"), [0]);
+    }
+
+    #[test]
+    fn prepared_transactions_change_nothing_until_applied() {
+        let mut writer = writer();
+        let transaction = Transaction::new()
+            .with_upsert(document("a", Some("first")), UpsertMode::Replace)
+            .with_delete(key("b"));
+        let prepared = writer.prepare(&transaction).unwrap();
+        assert_eq!(
+            prepared.written(),
+            [
+                Written::Upserted(Upserted::Inserted(0)),
+                Written::Deleted(None),
+            ]
+        );
+        assert_eq!(writer.len(), 0);
+        assert_eq!(writer.find(&key("a")).unwrap(), None);
+        assert_eq!(writer.apply(prepared).unwrap().len(), 2);
+        assert_eq!(writer.find(&key("a")).unwrap(), Some(0));
+        assert_eq!(writer.search("name", "first"), [0]);
+    }
+
+    #[test]
+    fn refuse_stale_prepared_transactions() {
+        let mut writer = writer();
+        let transaction =
+            Transaction::new().with_upsert(document("a", Some("first")), UpsertMode::Replace);
+        let prepared = writer.prepare(&transaction).unwrap();
+        // the prepared insert would share its row with this one
+        writer
+            .upsert(document("b", Some("second")), UpsertMode::Replace)
+            .unwrap();
+        assert!(matches!(
+            writer.apply(prepared),
+            Err(IndexWriterError::Stale)
+        ));
+        assert_eq!(writer.len(), 1);
+        assert_eq!(writer.find(&key("a")).unwrap(), None);
+        assert_eq!(writer.find(&key("b")).unwrap(), Some(0));
+
+        let prepared = writer.prepare(&transaction).unwrap();
+        assert_eq!(
+            writer.apply(prepared).unwrap(),
+            [Written::Upserted(Upserted::Inserted(1))]
+        );
+    }
+
+    #[test]
+    fn detect_duplicates_among_staged_writes() {
+        let mut writer = writer().with_deduplication(Deduplication::new(
+            ["name"],
+            DedupMode::Reject,
+            PersistentVec::in_memory(),
+        ));
+        writer
+            .upsert(document("a", Some("first")), UpsertMode::Replace)
+            .unwrap();
+
+        // documents written by the same transaction are duplicates of each other
+        let transaction = Transaction::new()
+            .with_upsert(document("b", Some("second")), UpsertMode::Replace)
+            .with_upsert(document("c", Some("second")), UpsertMode::Replace);
+        let error = writer.commit(transaction).unwrap_err();
+        assert!(matches!(
+            error,
+            IndexWriterError::Transaction { write: 1, ref source }
+                if matches!(**source, IndexWriterError::Duplicate(1))
+        ));
+        assert_eq!(writer.len(), 1);
+
+        // documents deleted or overwritten earlier no longer hold their fingerprint
+        let transaction = Transaction::new()
+            .with_delete(key("a"))
+            .with_upsert(document("b", Some("first")), UpsertMode::Replace)
+            .with_upsert(document("b", Some("second")), UpsertMode::Replace)
+            .with_upsert(document("c", Some("first")), UpsertMode::Replace);
+        let written = writer.commit(transaction).unwrap();
+        assert_eq!(written[3], Written::Upserted(Upserted::Inserted(2)));
+        let fingerprints = writer.deduplication().unwrap();
+        assert_eq!(fingerprints.len(), 2);
+        let error = writer
+            .upsert(document("d", Some("second")), UpsertMode::Replace)
+            .unwrap_err();
+        assert!(matches!(error, IndexWriterError::Duplicate(1)));
+    }
+
+    #[test]
+    fn blobs_are_appended_once_applied() {
+        let schema = Schema::from_iter([
+            SchemaField {
+                name: "id".to_string(),
+                kind: FieldKind::Keyword(8),
+            },
+            SchemaField {
+                name: "attachment".to_string(),
+                kind: FieldKind::Blob,
+            },
+        ])
+        .with_primary_key("id");
+        let mut writer = IndexWriter::new(schema, PersistentVec::in_memory())
+            .with_blob_segment(BlobSegment::in_memory());
+        let attached = |id: &str, blob: Option<&[u8]>| -> Document {
+            let mut fields = vec![("id", Field::new(FieldKind::Keyword(8), [key(id)]))];
+            if let Some(blob) = blob {
+                let blob = FieldData::Blob(Arc::from(blob));
+                fields.push(("attachment", Field::new(FieldKind::Blob, [blob])));
+            }
+            fields.into_iter().collect::<Fields>().into()
+        };
+
+        let transaction = Transaction::new()
+            .with_upsert(attached("a", Some(b"lost")), UpsertMode::Replace)
+            .with_upsert(Fields::new().into(), UpsertMode::Replace);
+        writer.commit(transaction).unwrap_err();
+        assert_eq!(writer.blobs().unwrap().len(), 0);
+
+        // only the blobs of the rows as they are at the end of the transaction are appended
+        let transaction = Transaction::new()
+            .with_upsert(attached("a", Some(b"replaced")), UpsertMode::Replace)
+            .with_upsert(attached("a", Some(b"cover")), UpsertMode::Replace)
+            .with_upsert(attached("a", None), UpsertMode::Merge);
+        writer.commit(transaction).unwrap();
+        assert_eq!(writer.blobs().unwrap().len(), 5);
+        let document = writer.read(0).unwrap().unwrap();
+        assert_eq!(
+            document.get("attachment").unwrap().data(),
+            &[FieldData::Blob(Arc::from(&b"cover"[..]))]
+        );
+    }
+}
@@ -0,0 +1,337 @@
+//! Ingest pipelines transform documents before they are indexed.
+//!
+//! A pipeline is a chain of [processors](Processor) applied in order, configured per index. Any
+//! processor can fail the document, and the [`Drop`](Processor::Drop) processor can discard it
+//! entirely.
+
+use std::sync::Arc;
+
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::analysis::AnalyzedText;
+use crate::document::Document;
+use crate::fields::{Field, FieldData, FieldKind, Fields};
+
+/// A single step of an ingest pipeline. Processors that target a field the document does not have
+/// leave the document unchanged.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Processor {
+    /// Removes leading and trailing whitespace from the values of a field
+    Trim { field: String },
+    /// Lowercases the values of a field
+    Lowercase { field: String },
+    /// Renames a field, replacing any field already using the new name
+    Rename { from: String, to: String },
+    /// Parses the value of a field as a date using a [`chrono`](chrono::format::strftime) format
+    /// string, storing the milliseconds since the unix epoch into an [`I64`](FieldKind::I64) field. Dates
+    /// without an offset are assumed to be UTC, and dates without a time are at midnight.
+    DateParse {
+        field: String,
+        target: String,
+        format: String,
+    },
+    /// Matches a field against a regex, creating a field for every named capture group. Created
+    /// fields have the same kind as the matched field.
+    Extract { field: String, pattern: String },
+    /// Drops the document if a field has the given value, or if the field is present when no value
+    /// is given
+    Drop {
+        field: String,
+        #[serde(default)]
+        value: Option<String>,
+    },
+}
+
+/// A chain of processors applied to documents before they are indexed
+#[derive(Debug, Default)]
+pub struct Pipeline {
+    processors: Vec<(Processor, Option<Regex>)>,
+}
+
+impl Pipeline {
+    /// Creates a new pipeline, compiling the patterns of any processors that need them
+    pub fn new<I: IntoIterator<Item = Processor>>(processors: I) -> Result<Self, PipelineError> {
+        let processors = processors
+            .into_iter()
+            .map(|processor| {
+                let regex = match &processor {
+                    Processor::Extract { pattern, .. } => Some(Regex::new(pattern)?),
+                    _ => None,
+                };
+                Ok((processor, regex))
+            })
+            .collect::<Result<Vec<_>, PipelineError>>()?;
+        Ok(Self { processors })
+    }
+
+    /// Gets the processors of this pipeline, in the order they are applied
+    pub fn processors(&self) -> impl Iterator<Item = &Processor> {
+        self.processors.iter().map(|(processor, _)| processor)
+    }
+
+    /// Checks if this pipeline has no processors
+    pub fn is_empty(&self) -> bool {
+        self.processors.is_empty()
+    }
+
+    /// Applies every processor to a document, returning `None` if the document was dropped
+    pub fn apply(&self, mut document: Document) -> Result<Option<Document>, PipelineError> {
+        for (processor, regex) in &self.processors {
+            let fields = document.fields_mut();
+            match processor {
+                Processor::Trim { field } => {
+                    map_text(fields, field, |text| text.trim().to_string())
+                }
+                Processor::Lowercase { field } => {
+                    map_text(fields, field, |text| text.to_lowercase())
+                }
+                Processor::Rename { from, to } => {
+                    if let Some(removed) = fields.remove(from) {
+                        fields.insert(to, removed);
+                    }
+                }
+                Processor::DateParse {
+                    field,
+                    target,
+                    format,
+                } => {
+                    let Some(text) = fields.get(field).and_then(first_text) else {
+                        continue;
+                    };
+                    let millis =
+                        parse_date(&text, format).ok_or_else(|| PipelineError::InvalidDate {
+                            field: field.clone(),
+                            value: text.clone(),
+                        })?;
+                    fields.insert(target, Field::new(FieldKind::I64, [FieldData::I64(millis)]));
+                }
+                Processor::Extract { field, .. } => {
+                    let regex = regex.as_ref().expect("extract processors have a regex");
+                    let Some(source) = fields.get(field) else {
+                        continue;
+                    };
+                    let kind = source.kind().clone();
+                    let Some(text) = first_text(source) else {
+                        continue;
+                    };
+                    let captures = regex
+                        .captures(&text)
+                        .ok_or_else(|| PipelineError::NoMatch(field.clone()))?;
+                    for name in regex.capture_names().flatten() {
+                        if let Some(capture) = captures.name(name) {
+                            let bytes = Arc::from(capture.as_str().as_bytes());
+                            fields
+                                .insert(name, Field::new(kind.clone(), [FieldData::Bytes(bytes)]));
+                        }
+                    }
+                }
+                Processor::Drop { field, value } => {
+                    let Some(present) = fields.get(field) else {
+                        continue;
+                    };
+                    let dropped = match value {
+                        None => true,
+                        Some(value) => first_text(present).is_some_and(|text| &text == value),
+                    };
+                    if dropped {
+                        return Ok(None);
+                    }
+                }
+            }
+        }
+        Ok(Some(document))
+    }
+}
+
+/// Maps every text value of a field. Analyzed text is analyzed again once mapped.
+fn map_text<F: Fn(&str) -> String>(fields: &mut Fields, name: &str, func: F) {
+    let Some(field) = fields.get_mut(name) else {
+        return;
+    };
+    for data in field.data_mut() {
+        match data {
+            FieldData::Bytes(bytes) => {
+                let mapped = func(&String::from_utf8_lossy(bytes));
+                *bytes = Arc::from(mapped.as_bytes());
+            }
+            FieldData::Analyzed(analyzed) => {
+                *analyzed = Arc::new(AnalyzedText::new(func(analyzed.text())));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Gets the first text value of a field
+fn first_text(field: &Field) -> Option<String> {
+    field.data().iter().find_map(|data| match data {
+        FieldData::Bytes(bytes) => Some(String::from_utf8_lossy(bytes).into_owned()),
+        FieldData::Analyzed(analyzed) => Some(analyzed.text().to_string()),
+        _ => None,
+    })
+}
+
+/// Parses a date into the milliseconds since the unix epoch
+fn parse_date(text: &str, format: &str) -> Option<i64> {
+    if let Ok(date_time) = DateTime::parse_from_str(text, format) {
+        return Some(date_time.timestamp_millis());
+    }
+    if let Ok(date_time) = NaiveDateTime::parse_from_str(text, format) {
+        return Some(date_time.and_utc().timestamp_millis());
+    }
+    NaiveDate::parse_from_str(text, format)
+        .ok()
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .map(|date_time| date_time.and_utc().timestamp_millis())
+}
+
+/// An error occurred creating or applying a pipeline
+#[derive(Debug, Error)]
+pub enum PipelineError {
+    #[error(transparent)]
+    InvalidPattern(#[from] regex::Error),
+    #[error("Field {field:?} does not contain a valid date (found: {value:?})")]
+    InvalidDate { field: String, value: String },
+    #[error("Field {0:?} does not match the extract pattern")]
+    NoMatch(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(value: &str) -> Field {
+        Field::new(
+            FieldKind::Text(64),
+            [FieldData::Bytes(Arc::from(value.as_bytes()))],
+        )
+    }
+
+    fn document(fields: &[(&str, &str)]) -> Document {
+        fields
+            .iter()
+            .map(|(name, value)| (*name, text(value)))
+            .collect::<Fields>()
+            .into()
+    }
+
+    fn bytes<'a>(document: &'a Document, name: &str) -> &'a [u8] {
+        match document.get(name).unwrap().data() {
+            [FieldData::Bytes(bytes)] => bytes,
+            other => panic!("unexpected data {other:?}"),
+        }
+    }
+
+    #[test]
+    fn trim_lowercase_and_rename() {
+        let pipeline = Pipeline::new([
+            Processor::Trim {
+                field: "name".to_string(),
+            },
+            Processor::Lowercase {
+                field: "name".to_string(),
+            },
+            Processor::Rename {
+                from: "name".to_string(),
+                to: "user".to_string(),
+            },
+        ])
+        .unwrap();
+
+        let document = pipeline
+            .apply(document(&[("name", "  Josh ")]))
+            .unwrap()
+            .unwrap();
+        assert!(document.get("name").is_none());
+        assert_eq!(bytes(&document, "user"), b"josh");
+
+        // analyzed text is analyzed again, so its terms follow the mapped text
+        let analyzed = AnalyzedText::new("  Dune Messiah ");
+        let fields = Fields::from_iter([(
+            "name",
+            Field::new(
+                FieldKind::Text(64),
+                [FieldData::Analyzed(Arc::new(analyzed))],
+            ),
+        )]);
+        let document = pipeline.apply(fields.into()).unwrap().unwrap();
+        let [FieldData::Analyzed(analyzed)] = document.get("user").unwrap().data() else {
+            panic!("expected analyzed text");
+        };
+        assert_eq!(analyzed.text(), "dune messiah");
+        assert_eq!(analyzed.tokens()[0].offsets(), 0..4);
+    }
+
+    #[test]
+    fn parse_dates() {
+        let pipeline = Pipeline::new([Processor::DateParse {
+            field: "date".to_string(),
+            target: "timestamp".to_string(),
+            format: "%Y-%m-%d".to_string(),
+        }])
+        .unwrap();
+
+        let parsed = pipeline
+            .apply(document(&[("date", "1970-01-02")]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            parsed.get("timestamp").unwrap().data(),
+            &[FieldData::I64(86_400_000)]
+        );
+
+        let error = pipeline
+            .apply(document(&[("date", "yesterday")]))
+            .unwrap_err();
+        assert!(matches!(error, PipelineError::InvalidDate { .. }));
+    }
+
+    #[test]
+    fn extract_named_groups() {
+        let pipeline = Pipeline::new([Processor::Extract {
+            field: "line".to_string(),
+            pattern: r"^(?P<level>\w+): (?P<message>.*)$".to_string(),
+        }])
+        .unwrap();
+
+        let document = pipeline
+            .apply(document(&[("line", "WARN: disk almost full")]))
+            .unwrap()
+            .unwrap();
+        assert_eq!(bytes(&document, "level"), b"WARN");
+        assert_eq!(bytes(&document, "message"), b"disk almost full");
+        assert_eq!(document.get("level").unwrap().kind(), &FieldKind::Text(64));
+    }
+
+    #[test]
+    fn drop_documents() {
+        let pipeline = Pipeline::new([Processor::Drop {
+            field: "status".to_string(),
+            value: Some("draft".to_string()),
+        }])
+        .unwrap();
+
+        assert!(pipeline
+            .apply(document(&[("status", "draft")]))
+            .unwrap()
+            .is_none());
+        assert!(pipeline
+            .apply(document(&[("status", "published")]))
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn invalid_patterns_are_rejected() {
+        let error = Pipeline::new([Processor::Extract {
+            field: "line".to_string(),
+            pattern: "(".to_string(),
+        }])
+        .unwrap_err();
+        assert!(matches!(error, PipelineError::InvalidPattern(_)));
+    }
+}
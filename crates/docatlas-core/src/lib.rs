@@ -2,13 +2,24 @@
 //!
 //!
 
+pub mod aggregation;
+pub mod analysis;
 pub mod auth;
+pub mod blob;
+pub mod cancel;
+pub mod codec;
 pub mod document;
+pub mod error;
+pub mod export;
 pub mod fields;
+pub mod import;
 pub mod index;
+pub mod ingest;
 pub mod persist;
+pub mod query;
 pub mod schema;
 pub mod shared;
+pub mod sql;
 pub mod transport;
 
 pub mod prelude {
@@ -3,15 +3,22 @@
 use std::ops::{Deref, DerefMut};
 
 pub use {
+    block::{Block, BlockBuilder, BlockError, BlockRef, Blocks, SharedBlock},
+    check::{check, CheckReport, Checker, Fix, Fixed, Issue, Problem},
     persisted_cell::PersistedCell,
+    persisted_lru::PersistedLru,
+    persisted_trie::{PersistedTrie, Prefix},
     persisted_unsafe_cell::PersistedUnsafeCell,
-    persisted_vec::{Drain, PersistentVec, Split, SplitMut},
+    persisted_vec::{Drain, IntoChunks, IntoIter, PersistentVec, Split, SplitMut},
 };
 
 mod block;
+mod check;
 mod persisted_box;
 mod persisted_cell;
+mod persisted_lru;
 mod persisted_raw_array;
+mod persisted_trie;
 mod persisted_unsafe_cell;
 mod persisted_vec;
 
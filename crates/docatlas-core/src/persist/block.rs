@@ -1,6 +1,18 @@
 //! A segments is a piece of memory where stuff is stored.
 //!
 //! Segments store actual data, and can be flushed to/read from disk.
+//!
+//! Blocks [opened read-only](BlockBuilder::open_read_only) map their file without write access, so
+//! any number of them may map the same file at once, such as replicas and backup tools reading the
+//! files of a running daemon, and a snapshot can not be changed by accident.
+//!
+//! Growing a block remaps it, which leaves every pointer and slice taken from it before dangling.
+//! Data that is referred to across growth is referred to by a [`BlockRef`], which only holds the
+//! offset of the data and is resolved against the block every time it is used. Every remap starts
+//! a new [generation](Block::generation) of the block, which raw pointers are only valid within.
+//!
+//! A [`SharedBlock`] lets several structures share a single file, each of them leasing a region
+//! of it as a block of its own.
 
 use std::collections::HashSet;
 use std::fmt::{Debug, Display, Formatter, Pointer};
@@ -10,20 +22,30 @@ use std::marker::PhantomData;
 use std::mem::transmute;
 use std::ops::{Deref, DerefMut};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::OnceLock;
 use std::{io, ptr, slice};
 
-use memmap::MmapMut;
+use memmap::{Mmap, MmapMut};
 use parking_lot::Mutex;
 use serde::de::DeserializeOwned;
 use serde::ser::Error as SerError;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use thiserror::Error;
 
+use crate::error::{self, ResultExt};
 use crate::persist::Persist;
 
+use shared::Lease;
+pub use shared::SharedBlock;
+
+mod shared;
+
 static OPEN_PATHS: OnceLock<Mutex<HashSet<PathBuf>>> = OnceLock::new();
 
+/// The id of the next block created
+static NEXT_BLOCK_ID: AtomicU64 = AtomicU64::new(0);
+
 /// A segment key
 type BlockKey = u64;
 
@@ -48,51 +70,79 @@ impl BlockBuilder {
     /// Creates the file at the given path with a set size if the file does not already exist.
     pub fn open<P: AsRef<Path>>(self, path: P) -> Result<Block, BlockError> {
         let path = path.as_ref();
-
-        let mut guard = OPEN_PATHS.get_or_init(Default::default).lock();
-        if guard.contains(path) {
-            return Err(BlockError::PathAlreadyOpened(path.to_path_buf()));
-        } else {
-            guard.insert(path.to_path_buf());
-        }
+        claim_path(path)?;
 
         let file = match path.exists() {
-            true => File::options().write(true).read(true).open(path)?,
+            true => File::options()
+                .write(true)
+                .read(true)
+                .open(path)
+                .with_context(|| format!("could not open block {path:?}"))?,
             false => {
                 let mut file = File::options()
                     .write(true)
                     .read(true)
                     .create(true)
-                    .open(path)?;
+                    .open(path)
+                    .with_context(|| format!("could not create block {path:?}"))?;
                 let Some(size) = self.size else {
                     return Err(BlockError::MissingSize { is_anon: false });
                 };
-                file.set_len(size as u64)?;
+                file.set_len(size as u64)
+                    .with_context(|| format!("could not size block {path:?}"))?;
                 file
             }
         };
 
         unsafe {
-            let map = MmapMut::map_mut(&file)?;
-            Ok(Block {
-                disk_path: Some(path.to_path_buf()),
-                mem_map: map,
-            })
+            let map = MmapMut::map_mut(&file).with_context(|| format!("could not map {path:?}"))?;
+            Ok(Block::new(Some(path.to_path_buf()), Map::Writable(map)))
+        }
+    }
+
+    /// Opens the block stored in the file at a given path without write access. Unlike writable
+    /// blocks, any number of read-only blocks may open the same file at once.
+    ///
+    /// Writing to a read-only block fails, or panics where writes can not fail.
+    pub fn open_read_only<P: AsRef<Path>>(self, path: P) -> Result<Block, BlockError> {
+        let path = path.as_ref();
+        let file = File::open(path).with_context(|| format!("could not open block {path:?}"))?;
+        unsafe {
+            let map = Mmap::map(&file).with_context(|| format!("could not map {path:?}"))?;
+            Ok(Block::new(Some(path.to_path_buf()), Map::ReadOnly(map)))
         }
     }
 
+    /// Opens the file at a given path as a block shared by several structures, creating it if it
+    /// does not exist. New leases of the shared block are created with the set size.
+    pub fn open_shared<P: AsRef<Path>>(self, path: P) -> Result<SharedBlock, BlockError> {
+        SharedBlock::open(path.as_ref(), self.size)
+    }
+
     /// Creates a block that's stored anonymously
     pub fn create(self) -> Result<Block, BlockError> {
         match self.size {
             None => Err(BlockError::MissingSize { is_anon: true }),
-            Some(size) => Ok(Block {
-                disk_path: None,
-                mem_map: MmapMut::map_anon(size)?,
-            }),
+            Some(size) => Ok(Block::new(None, Map::Writable(MmapMut::map_anon(size)?))),
         }
     }
 }
 
+/// Marks a path as opened by a writable block, unless another block opened it already
+fn claim_path(path: &Path) -> Result<(), BlockError> {
+    let mut guard = OPEN_PATHS.get_or_init(Default::default).lock();
+    if !guard.insert(path.to_path_buf()) {
+        return Err(BlockError::PathAlreadyOpened(path.to_path_buf()));
+    }
+    Ok(())
+}
+
+/// Releases a path claimed by a writable block
+fn release_path(path: &Path) {
+    let open_paths = OPEN_PATHS.get().expect("will exist by now if path is set");
+    open_paths.lock().remove(path);
+}
+
 /// Gets the number of bytes needed to store `capacity` number of values `T`.
 pub fn size_of<T: Sized>(count: usize) -> usize {
     std::mem::size_of::<T>() * count
@@ -103,13 +153,16 @@ fn create_mmap(space_req: usize, path: &Path) -> Result<MmapMut, BlockError> {
         .write(true)
         .read(true)
         .create(true)
-        .open(path)?;
+        .open(path)
+        .with_context(|| format!("could not open block {path:?}"))?;
 
     if file.metadata()?.len() != space_req as u64 {
-        file.set_len(space_req as u64)?;
+        file.set_len(space_req as u64)
+            .with_context(|| format!("could not resize block {path:?} to {space_req} bytes"))?;
     }
 
-    let mmap = unsafe { MmapMut::map_mut(&file)? };
+    let mmap =
+        unsafe { MmapMut::map_mut(&file).with_context(|| format!("could not map {path:?}"))? };
     Ok(mmap)
 }
 
@@ -141,80 +194,273 @@ pub enum BlockError {
     MissingSize { is_anon: bool },
     #[error("Path {0} already open, only one block can open a file at a time")]
     PathAlreadyOpened(PathBuf),
+    #[error("The reference points into another block")]
+    ForeignRef,
+    #[error("{len} bytes at offset {offset} do not fit in a block of {size} bytes")]
+    OutOfBounds {
+        offset: usize,
+        len: usize,
+        size: usize,
+    },
+    #[error("Offset {offset} is not aligned to {align} bytes")]
+    Misaligned { offset: usize, align: usize },
+    #[error("The block is read-only")]
+    ReadOnly,
+    #[error("{0:?} does not hold a shared block")]
+    NotShared(PathBuf),
+    #[error("Lease {0:?} is already leased")]
+    AlreadyLeased(String),
+    #[error("{0:?} is not a valid lease name")]
+    InvalidLeaseName(String),
+    #[error("The shared block holds too many leases")]
+    TooManyLeases,
     #[error(transparent)]
     IoError(#[from] io::Error),
+    #[error(transparent)]
+    Context(#[from] error::Error),
 }
 
 /// A segment stores data in memory and with a file backing
 pub struct Block {
+    id: u64,
+    generation: u64,
     disk_path: Option<PathBuf>,
-    mem_map: MmapMut,
+    mem_map: Map,
+    /// The region of a shared block this block is leased from
+    lease: Option<Lease>,
+}
+
+/// The memory a block is mapped to
+enum Map {
+    Writable(MmapMut),
+    ReadOnly(Mmap),
+}
+
+impl Map {
+    fn as_slice(&self) -> &[u8] {
+        match self {
+            Map::Writable(map) => map,
+            Map::ReadOnly(map) => map,
+        }
+    }
+
+    /// Gets the writable map
+    ///
+    /// # Panic
+    /// Panics if the map is read-only
+    fn writable(&mut self) -> &mut MmapMut {
+        match self {
+            Map::Writable(map) => map,
+            Map::ReadOnly(_) => panic!("the block is read-only"),
+        }
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        match self {
+            Map::Writable(map) => map.flush(),
+            Map::ReadOnly(_) => Ok(()),
+        }
+    }
+}
+
+/// Refers to `len` values of type `T` stored at an offset of a block.
+///
+/// Unlike a pointer, a reference stays valid while the block grows, as it is resolved against the
+/// block every time it is [read](Block::get) or [written](Block::get_mut). Resolving a reference
+/// against a block other than the one it was made for fails.
+pub struct BlockRef<T: Persist> {
+    block: u64,
+    offset: usize,
+    len: usize,
+    _kind: PhantomData<fn() -> T>,
 }
 
 impl Debug for Block {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("Segment")
             .field("disk_path", &self.disk_path)
-            .field("size", &self.mem_map.as_ref().len())
+            .field("size", &self.size())
+            .field("read_only", &self.is_read_only())
+            .field("lease", &self.lease.as_ref().map(Lease::name))
             .finish_non_exhaustive()
     }
 }
 
 impl Block {
+    fn new(disk_path: Option<PathBuf>, mem_map: Map) -> Self {
+        Self {
+            id: NEXT_BLOCK_ID.fetch_add(1, Ordering::Relaxed),
+            generation: 0,
+            disk_path,
+            mem_map,
+            lease: None,
+        }
+    }
+
+    /// Checks whether the block was [opened read-only](BlockBuilder::open_read_only)
+    pub fn is_read_only(&self) -> bool {
+        matches!(self.mem_map, Map::ReadOnly(_))
+    }
+
+    /// Gets the number of times the block was remapped to grow it. Raw pointers into the block are
+    /// only valid within the generation they were taken in.
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Refers to `len` values of type `T` stored at `offset` bytes into the block
+    pub fn make_ref<T: Persist>(
+        &self,
+        offset: usize,
+        len: usize,
+    ) -> Result<BlockRef<T>, BlockError> {
+        let reference = BlockRef {
+            block: self.id,
+            offset,
+            len,
+            _kind: PhantomData,
+        };
+        self.check(&reference)?;
+        Ok(reference)
+    }
+
+    /// Reads the values a reference refers to
+    pub fn get<T: Persist>(&self, reference: &BlockRef<T>) -> Result<&[T], BlockError> {
+        self.check(reference)?;
+        unsafe {
+            let ptr = self.as_ptr().add(reference.offset) as *const T;
+            Ok(slice::from_raw_parts(ptr, reference.len))
+        }
+    }
+
+    /// Gets mutable access to the values a reference refers to
+    pub fn get_mut<T: Persist>(&mut self, reference: &BlockRef<T>) -> Result<&mut [T], BlockError> {
+        self.check(reference)?;
+        if self.is_read_only() {
+            return Err(BlockError::ReadOnly);
+        }
+        unsafe {
+            let ptr = self.as_ptr_mut().add(reference.offset) as *mut T;
+            Ok(slice::from_raw_parts_mut(ptr, reference.len))
+        }
+    }
+
+    /// Checks that a reference was made for this block, and that what it refers to fits in it
+    fn check<T: Persist>(&self, reference: &BlockRef<T>) -> Result<(), BlockError> {
+        if reference.block != self.id {
+            return Err(BlockError::ForeignRef);
+        }
+        let len = reference.len * std::mem::size_of::<T>();
+        match reference.offset.checked_add(len) {
+            Some(end) if end <= self.size() => {}
+            _ => {
+                return Err(BlockError::OutOfBounds {
+                    offset: reference.offset,
+                    len,
+                    size: self.size(),
+                })
+            }
+        }
+        let align = std::mem::align_of::<T>();
+        if (self.mem_map.as_slice().as_ptr() as usize + reference.offset) & (align - 1) != 0 {
+            return Err(BlockError::Misaligned {
+                offset: reference.offset,
+                align,
+            });
+        }
+        Ok(())
+    }
+
     /// Hex dumps the contents of this segment, one page (4096 kb) at time
     pub fn hexdump(&self, page: usize) {
         let count = 1028 * 4 * 8;
         let start = (count * page).clamp(0, self.size());
         let end = (count * (page + 1)).clamp(0, self.size());
-        hexdump::hexdump(&self.mem_map.as_ref()[start..end])
+        hexdump::hexdump(&self.mem_map.as_slice()[start..end])
     }
 
     /// Gets the size of the segment
     pub fn size(&self) -> usize {
-        self.mem_map.as_ref().len()
+        self.mem_map.as_slice().len()
+    }
+
+    /// Writes any changes of this block to the file backing it. Anonymous and read-only blocks have
+    /// nothing to write.
+    #[tracing::instrument(level = "trace", skip_all, fields(path = ?self.disk_path))]
+    pub fn flush(&self) -> io::Result<()> {
+        match self.disk_path {
+            Some(_) => self.mem_map.flush(),
+            None => Ok(()),
+        }
     }
 
     /// Gets a pointer to mmap
+    ///
+    /// # Safety
+    /// The pointer dangles once the block grows, so it must only be used within the current
+    /// [generation](Self::generation) of the block.
     pub unsafe fn as_ptr(&self) -> *const u8 {
-        self.mem_map.as_ptr()
+        self.mem_map.as_slice().as_ptr()
     }
 
     /// Gets a mutable pointer to mmap
+    ///
+    /// # Safety
+    /// See [`as_ptr`](Self::as_ptr)
+    ///
+    /// # Panic
+    /// Panics if the block is read-only
     pub unsafe fn as_ptr_mut(&mut self) -> *mut u8 {
-        self.mem_map.as_mut_ptr()
+        self.mem_map.writable().as_mut_ptr()
     }
 
     /// Gets an aligned pointer to a type within this block
+    ///
+    /// # Safety
+    /// See [`as_ptr`](Self::as_ptr). The type must also fit at the start of the block, see
+    /// [`assert_can_contain`](Self::assert_can_contain).
     pub unsafe fn as_typed_ptr<T: Persist>(&self) -> *const T {
         let ptr = self.as_ptr();
         ptr as *const T
     }
 
     /// Gets an aligned mutable pointer to a type within this block
+    ///
+    /// # Safety
+    /// See [`as_ptr`](Self::as_ptr). The type must also fit at the start of the block, see
+    /// [`assert_can_contain`](Self::assert_can_contain).
     pub unsafe fn as_typed_mut_ptr<T: Persist>(&mut self) -> *mut T {
         let ptr = self.as_ptr_mut();
         ptr as *mut T
     }
 
-    /// reserves an additional amount of bytes of space in of disk space.
+    /// reserves an additional amount of bytes of space in of disk space, starting a new
+    /// [generation](Self::generation) of the block.
+    ///
+    /// # Safety
+    /// Every pointer previously taken from the block dangles once it returns. Data referred to
+    /// across growth must be referred to by a [`BlockRef`] instead.
     ///
     /// # Panic
-    /// panics if the additional amount of space could not be overwritten
+    /// panics if the additional amount of space could not be overwritten, or if the block is
+    /// read-only
     pub unsafe fn reserve(&mut self, additional: usize) {
         let old_size = self.size();
         let new = additional + self.size();
-        match &self.disk_path {
-            None => {
-                let mut mmap = MmapMut::map_anon(new).expect("could not create new");
-                mmap[..old_size].clone_from_slice(self.mem_map.as_ref());
-                self.mem_map = mmap;
-            }
-            Some(path) => {
-                let mut mmap = create_mmap(new, path).expect("could create new map");
-                mmap[..old_size].clone_from_slice(self.mem_map.as_ref());
-                self.mem_map = mmap;
-            }
+        let old = self.mem_map.writable();
+        if let Some(lease) = &self.lease {
+            let mmap = lease.grow(old, new).expect("could not grow lease");
+            self.mem_map = Map::Writable(mmap);
+            self.generation += 1;
+            return;
         }
+        let mut mmap = match &self.disk_path {
+            None => MmapMut::map_anon(new).expect("could not create new"),
+            Some(path) => create_mmap(new, path).expect("could create new map"),
+        };
+        mmap[..old_size].clone_from_slice(old);
+        self.mem_map = Map::Writable(mmap);
+        self.generation += 1;
     }
 
     /// Asserts that this block can store a given type
@@ -235,12 +481,58 @@ impl Block {
     }
 }
 
+impl<T: Persist> BlockRef<T> {
+    /// Gets the offset in bytes of the values from the start of the block
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Gets the number of values referred to
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Checks whether no values are referred to
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<T: Persist> Clone for BlockRef<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: Persist> Copy for BlockRef<T> {}
+
+impl<T: Persist> Debug for BlockRef<T> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BlockRef")
+            .field("block", &self.block)
+            .field("offset", &self.offset)
+            .field("len", &self.len)
+            .finish()
+    }
+}
+
+impl<T: Persist> PartialEq for BlockRef<T> {
+    fn eq(&self, other: &Self) -> bool {
+        (self.block, self.offset, self.len) == (other.block, other.offset, other.len)
+    }
+}
+
+impl<T: Persist> Eq for BlockRef<T> {}
+
 impl Drop for Block {
     fn drop(&mut self) {
-        if let Some(ref path) = &self.disk_path {
-            let open_paths = OPEN_PATHS.get().expect("will exist by now if path is set");
-            let mut guard = open_paths.lock();
-            guard.remove(path);
+        if self.is_read_only() {
+            return;
+        }
+        if let Some(lease) = &self.lease {
+            lease.release();
+        } else if let Some(path) = &self.disk_path {
+            release_path(path);
         }
         drop(self.mem_map.flush());
     }
@@ -320,6 +612,68 @@ mod tests {
         assert_eq!(block.size(), 8);
     }
 
+    #[test]
+    fn references_survive_growth() {
+        let mut block = Blocks.builder().with_size(64).create().unwrap();
+        let reference = block.make_ref::<u32>(8, 4).unwrap();
+        block
+            .get_mut(&reference)
+            .unwrap()
+            .copy_from_slice(&[1, 2, 3, 4]);
+        unsafe {
+            block.reserve(4096);
+        }
+        assert_eq!(block.generation(), 1);
+        assert_eq!(block.get(&reference).unwrap(), [1, 2, 3, 4]);
+
+        let other = Blocks.builder().with_size(64).create().unwrap();
+        assert!(matches!(other.get(&reference), Err(BlockError::ForeignRef)));
+        assert!(matches!(
+            block.make_ref::<u32>(4096, 32),
+            Err(BlockError::OutOfBounds { .. })
+        ));
+        assert!(matches!(
+            block.make_ref::<u32>(2, 1),
+            Err(BlockError::Misaligned {
+                offset: 2,
+                align: 4
+            })
+        ));
+    }
+
+    #[test]
+    fn open_read_only() {
+        let temp_dir = tempdir().unwrap();
+        let file = temp_dir.path().join("temp#1");
+        let mut block = Blocks.builder().with_size(64).open(&file).unwrap();
+        unsafe {
+            *block.as_ptr_mut() = 15;
+        }
+        block.flush().unwrap();
+
+        // readers share the file with each other and with the writer
+        let reader = Blocks.builder().open_read_only(&file).unwrap();
+        let mut other = Blocks.builder().open_read_only(&file).unwrap();
+        assert!(reader.is_read_only());
+        unsafe {
+            assert_eq!(*reader.as_ptr(), 15);
+        }
+        let reference = other.make_ref::<u8>(0, 1).unwrap();
+        assert_eq!(other.get(&reference).unwrap(), [15]);
+        assert!(matches!(
+            other.get_mut(&reference),
+            Err(BlockError::ReadOnly)
+        ));
+        drop(other);
+        drop(block);
+        // closing a reader leaves the file to the writer
+        Blocks.builder().open(&file).unwrap();
+        assert!(Blocks
+            .builder()
+            .open_read_only(temp_dir.path().join("missing"))
+            .is_err());
+    }
+
     #[test]
     fn reserve_file() {
         let temp_dir = tempdir().unwrap();
@@ -0,0 +1,458 @@
+//! Blocks sharing a single file.
+//!
+//! A shared block starts with a header listing its leases, which are named regions of the file.
+//! Every lease is mapped on its own, so growing one lease remaps only the block it was leased as
+//! and leaves the pointers into every other lease valid. A lease that can not grow in place is
+//! moved to the first gap of the file it fits in, and the space it leaves is reused by later
+//! leases.
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::{Debug, Formatter};
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use memmap::{MmapMut, MmapOptions};
+use parking_lot::Mutex;
+
+use crate::error::ResultExt;
+
+use super::{claim_path, release_path, Block, BlockError, Map};
+
+/// Marks a file as a shared block
+const MAGIC: &[u8; 8] = b"DAShared";
+
+/// Leases start at multiples of this many bytes, which is a multiple of the page size of every
+/// platform files can be mapped at an offset on
+const REGION_ALIGN: u64 = 16 * 1024;
+
+/// The most bytes of a lease name
+const NAME_LEN: usize = 48;
+
+/// The bytes of a lease in the header: its offset, its length and its name
+const ENTRY_LEN: usize = 16 + NAME_LEN;
+
+/// The magic and number of leases that start the header
+const HEADER_PREFIX: usize = 16;
+
+/// The most leases a shared block holds
+const MAX_LEASES: usize = (REGION_ALIGN as usize - HEADER_PREFIX) / ENTRY_LEN;
+
+/// A file shared by several structures, each of them storing its data in a region of the file it
+/// [leases](Self::lease) as a block.
+///
+/// Handles to a shared block are cheap to clone, and the file stays open until every handle and
+/// every block leased from it is dropped. Like other writable blocks, only one shared block can
+/// open a file at a time, and each of its leases can only be leased once at a time.
+#[derive(Clone)]
+pub struct SharedBlock {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    path: PathBuf,
+    file: File,
+    /// The size new leases are created with
+    size: Option<usize>,
+    state: Mutex<State>,
+}
+
+struct State {
+    /// The first region of the file, holding the header
+    header: MmapMut,
+    regions: BTreeMap<String, Region>,
+    /// The leases currently leased as blocks
+    leased: HashSet<String>,
+    file_len: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Region {
+    offset: u64,
+    len: u64,
+}
+
+impl Region {
+    fn end(&self) -> u64 {
+        self.offset + self.len
+    }
+
+    fn overlaps(&self, other: &Region) -> bool {
+        self.offset < other.end() && other.offset < self.end()
+    }
+}
+
+/// The lease a block was leased as
+pub(super) struct Lease {
+    shared: SharedBlock,
+    name: String,
+}
+
+impl SharedBlock {
+    pub(super) fn open(path: &Path, size: Option<usize>) -> Result<Self, BlockError> {
+        claim_path(path)?;
+        match Self::open_claimed(path, size) {
+            Ok(shared) => Ok(shared),
+            Err(e) => {
+                release_path(path);
+                Err(e)
+            }
+        }
+    }
+
+    fn open_claimed(path: &Path, size: Option<usize>) -> Result<Self, BlockError> {
+        let file = File::options()
+            .write(true)
+            .read(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .with_context(|| format!("could not open shared block {path:?}"))?;
+        let mut file_len = file.metadata()?.len();
+        let created = file_len == 0;
+        if created {
+            file_len = REGION_ALIGN;
+            file.set_len(file_len)
+                .with_context(|| format!("could not size shared block {path:?}"))?;
+        }
+        if file_len < REGION_ALIGN {
+            return Err(BlockError::NotShared(path.to_path_buf()));
+        }
+        let header = map(&file, 0, REGION_ALIGN)?;
+        let mut state = State {
+            header,
+            regions: BTreeMap::new(),
+            leased: HashSet::new(),
+            file_len,
+        };
+        match created {
+            true => state.store(),
+            false => {
+                state.regions = state
+                    .load()
+                    .ok_or_else(|| BlockError::NotShared(path.to_path_buf()))?
+            }
+        }
+        Ok(Self {
+            inner: Arc::new(Inner {
+                path: path.to_path_buf(),
+                file,
+                size,
+                state: Mutex::new(state),
+            }),
+        })
+    }
+
+    /// Gets the path of the file the block is stored in
+    pub fn path(&self) -> &Path {
+        &self.inner.path
+    }
+
+    /// Gets the names of every lease of the block, whether it is leased right now or not
+    pub fn leases(&self) -> Vec<String> {
+        self.inner.state.lock().regions.keys().cloned().collect()
+    }
+
+    /// Leases the region of the file with the given name as a block, creating it with the size
+    /// the shared block was opened with if it does not exist. The region stays leased until the
+    /// block is dropped.
+    pub fn lease(&self, name: &str) -> Result<Block, BlockError> {
+        if name.is_empty() || name.len() > NAME_LEN || name.contains('\0') {
+            return Err(BlockError::InvalidLeaseName(name.to_string()));
+        }
+        let mut state = self.inner.state.lock();
+        if state.leased.contains(name) {
+            return Err(BlockError::AlreadyLeased(name.to_string()));
+        }
+        let (region, created) = match state.regions.get(name) {
+            Some(&region) => (region, false),
+            None => {
+                let size = self
+                    .inner
+                    .size
+                    .ok_or(BlockError::MissingSize { is_anon: false })?;
+                if state.regions.len() >= MAX_LEASES {
+                    return Err(BlockError::TooManyLeases);
+                }
+                let len = align(size as u64);
+                let region = Region {
+                    offset: state.allocate(len, None),
+                    len,
+                };
+                (region, true)
+            }
+        };
+        state.fit(&self.inner.file, &region)?;
+        let mut mmap = map(&self.inner.file, region.offset, region.len)?;
+        if created {
+            // the region may have belonged to a lease that was removed
+            mmap.fill(0);
+            state.regions.insert(name.to_string(), region);
+            state.store();
+        }
+        state.leased.insert(name.to_string());
+
+        let mut block = Block::new(Some(self.inner.path.clone()), Map::Writable(mmap));
+        block.lease = Some(Lease {
+            shared: self.clone(),
+            name: name.to_string(),
+        });
+        Ok(block)
+    }
+
+    /// Removes a lease along with its data, so its region can be reused by other leases. Returns
+    /// whether the lease existed.
+    pub fn remove(&self, name: &str) -> Result<bool, BlockError> {
+        let mut state = self.inner.state.lock();
+        if state.leased.contains(name) {
+            return Err(BlockError::AlreadyLeased(name.to_string()));
+        }
+        let removed = state.regions.remove(name).is_some();
+        if removed {
+            state.store();
+        }
+        Ok(removed)
+    }
+
+    /// Writes the header listing the leases to the file. Leased blocks are flushed on their own.
+    pub fn flush(&self) -> io::Result<()> {
+        self.inner.state.lock().header.flush()
+    }
+}
+
+impl Debug for SharedBlock {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let state = self.inner.state.lock();
+        f.debug_struct("SharedBlock")
+            .field("path", &self.inner.path)
+            .field("leases", &state.regions)
+            .field("leased", &state.leased)
+            .finish()
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        drop(self.state.get_mut().header.flush());
+        release_path(&self.path);
+    }
+}
+
+impl Lease {
+    pub(super) fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Grows the region of the lease to at least `size` bytes, returning the new map of it. If the
+    /// region is moved, the data of the old map is copied into it.
+    pub(super) fn grow(&self, old: &[u8], size: usize) -> Result<MmapMut, BlockError> {
+        let inner = &self.shared.inner;
+        let mut state = inner.state.lock();
+        let current = state.regions[&self.name];
+        let len = align(size as u64);
+        let grown = Region {
+            offset: current.offset,
+            len,
+        };
+        let region = match state.is_free(&grown, &self.name) {
+            true => grown,
+            false => Region {
+                offset: state.allocate(len, Some(&self.name)),
+                len,
+            },
+        };
+        state.fit(&inner.file, &region)?;
+        let mut mmap = map(&inner.file, region.offset, region.len)?;
+        if region.offset != current.offset {
+            mmap[..old.len()].copy_from_slice(old);
+        }
+        mmap[current.len as usize..].fill(0);
+        state.regions.insert(self.name.clone(), region);
+        state.store();
+        Ok(mmap)
+    }
+
+    /// Returns the lease to the shared block, so it can be leased again
+    pub(super) fn release(&self) {
+        self.shared.inner.state.lock().leased.remove(&self.name);
+    }
+}
+
+impl State {
+    /// Reads the leases listed in the header, unless it is not the header of a shared block
+    fn load(&self) -> Option<BTreeMap<String, Region>> {
+        let header = &self.header[..];
+        if &header[..8] != MAGIC {
+            return None;
+        }
+        let count = read_u64(header, 8) as usize;
+        if count > MAX_LEASES {
+            return None;
+        }
+        (0..count)
+            .map(|lease| {
+                let entry = &header[HEADER_PREFIX + lease * ENTRY_LEN..][..ENTRY_LEN];
+                let region = Region {
+                    offset: read_u64(entry, 0),
+                    len: read_u64(entry, 8),
+                };
+                let name = &entry[16..];
+                let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(NAME_LEN)];
+                let name = String::from_utf8(name.to_vec()).ok()?;
+                (region.end() <= self.file_len).then_some((name, region))
+            })
+            .collect()
+    }
+
+    /// Writes the leases into the header
+    fn store(&mut self) {
+        let header = &mut self.header[..];
+        header.fill(0);
+        header[..8].copy_from_slice(MAGIC);
+        header[8..16].copy_from_slice(&(self.regions.len() as u64).to_le_bytes());
+        for (lease, (name, region)) in self.regions.iter().enumerate() {
+            let entry = &mut header[HEADER_PREFIX + lease * ENTRY_LEN..][..ENTRY_LEN];
+            entry[..8].copy_from_slice(&region.offset.to_le_bytes());
+            entry[8..16].copy_from_slice(&region.len.to_le_bytes());
+            entry[16..16 + name.len()].copy_from_slice(name.as_bytes());
+        }
+    }
+
+    /// Checks whether a region overlaps neither the header nor any lease but the given one
+    fn is_free(&self, region: &Region, except: &str) -> bool {
+        region.offset >= REGION_ALIGN
+            && self
+                .regions
+                .iter()
+                .all(|(name, other)| name == except || !region.overlaps(other))
+    }
+
+    /// Finds the first gap of the file that fits `len` bytes, ignoring the region of a lease
+    fn allocate(&self, len: u64, except: Option<&str>) -> u64 {
+        let mut taken = self
+            .regions
+            .iter()
+            .filter(|(name, _)| Some(name.as_str()) != except)
+            .map(|(_, region)| *region)
+            .collect::<Vec<_>>();
+        taken.sort_by_key(|region| region.offset);
+        let mut start = REGION_ALIGN;
+        for region in taken {
+            if region.offset >= start + len {
+                break;
+            }
+            start = start.max(region.end());
+        }
+        start
+    }
+
+    /// Grows the file until the region fits in it
+    fn fit(&mut self, file: &File, region: &Region) -> io::Result<()> {
+        if region.end() > self.file_len {
+            file.set_len(region.end())?;
+            self.file_len = region.end();
+        }
+        Ok(())
+    }
+}
+
+fn map(file: &File, offset: u64, len: u64) -> io::Result<MmapMut> {
+    unsafe {
+        MmapOptions::new()
+            .offset(offset)
+            .len(len as usize)
+            .map_mut(file)
+    }
+}
+
+/// Rounds a length up to the alignment of regions
+fn align(len: u64) -> u64 {
+    len.max(1).div_ceil(REGION_ALIGN) * REGION_ALIGN
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().expect("8 bytes"))
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use crate::persist::block::Blocks;
+    use crate::persist::PersistentVec;
+
+    use super::*;
+
+    #[test]
+    fn leases_share_a_file() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("shared");
+        {
+            let shared = Blocks.builder().with_size(64).open_shared(&path).unwrap();
+            let mut first = PersistentVec::<u64>::new(shared.lease("first").unwrap());
+            let mut second = PersistentVec::<u64>::new(shared.lease("second").unwrap());
+            // growing the first lease moves it past the second
+            for value in 0..10_000 {
+                first.push(value);
+                second.push(value * 2);
+            }
+            assert!(matches!(
+                shared.lease("first"),
+                Err(BlockError::AlreadyLeased(_))
+            ));
+            assert!(matches!(
+                Blocks.builder().open(&path),
+                Err(BlockError::PathAlreadyOpened(_))
+            ));
+            assert!(matches!(
+                shared.lease(&"x".repeat(NAME_LEN + 1)),
+                Err(BlockError::InvalidLeaseName(_))
+            ));
+            first.flush().unwrap();
+            second.flush().unwrap();
+            shared.flush().unwrap();
+        }
+
+        let shared = Blocks.builder().open_shared(&path).unwrap();
+        assert_eq!(shared.leases(), ["first", "second"]);
+        let first = PersistentVec::<u64>::new(shared.lease("first").unwrap());
+        let second = PersistentVec::<u64>::new(shared.lease("second").unwrap());
+        assert_eq!(first.len(), 10_000);
+        assert_eq!(first[9_999], 9_999);
+        assert_eq!(second[9_999], 19_998);
+        // leases are only created with a size
+        assert!(matches!(
+            shared.lease("third"),
+            Err(BlockError::MissingSize { .. })
+        ));
+    }
+
+    #[test]
+    fn reuse_removed_leases() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("shared");
+        let shared = Blocks.builder().with_size(64).open_shared(&path).unwrap();
+        let mut removed = PersistentVec::<u64>::new(shared.lease("removed").unwrap());
+        removed.extend([1, 2, 3]);
+        let kept = shared.lease("kept").unwrap();
+        assert!(matches!(
+            shared.remove("removed"),
+            Err(BlockError::AlreadyLeased(_))
+        ));
+        drop(removed);
+
+        assert!(shared.remove("removed").unwrap());
+        assert!(!shared.remove("removed").unwrap());
+        let reused = PersistentVec::<u64>::new(shared.lease("reused").unwrap());
+        assert!(reused.is_empty());
+        assert_eq!(shared.inner.state.lock().file_len, 3 * REGION_ALIGN);
+        drop((reused, kept, shared));
+
+        std::fs::write(&path, b"not shared").unwrap();
+        assert!(matches!(
+            Blocks.builder().open_shared(&path),
+            Err(BlockError::NotShared(_))
+        ));
+    }
+}
@@ -0,0 +1,611 @@
+//! Checks the integrity of a data directory, the way `fsck` checks a file system.
+//!
+//! A data directory holds the indexes of the daemon in `indexes`, and the snapshot repositories
+//! stored next to them in `snapshots`:
+//!
+//! ```text
+//! <path>/
+//!     indexes/
+//!         books/
+//!             mapping.json
+//!             wal.log
+//!     snapshots/
+//!         nightly/
+//!             segments/<blake2s hash of the segment>
+//!             snapshots/<snapshot>.json
+//! ```
+//!
+//! Checking a data directory finds
+//! - mappings that are missing or are not a [schema](Schema)
+//! - frames of write-ahead logs whose header claims more bytes than are left in the log, or whose
+//!   payload does not match the crc32 in their header
+//! - snapshot manifests that can not be read or reference segments the repository does not hold,
+//!   and segments that do not match the hash they are stored by
+//! - files left behind by writes that never finished, such as `mapping.json.tmp` and
+//!   `wal.log.merging`, and segments no manifest references
+//!
+//! Nothing is changed unless a [fix](Fix) is chosen. [Repairing](Fix::Repair) only throws away
+//! what the daemon would never read: logs are cut before their first broken frame, which the
+//! daemon rolls back when it starts anyway, and orphaned files are removed.
+//! [Quarantining](Fix::Quarantine) moves orphaned files and corrupt mappings, manifests and
+//! segments into `quarantine` within the data directory instead, at the path they had, and keeps a
+//! copy of every log before cutting it. The daemon then starts without them, while they are kept
+//! around to be looked into.
+//!
+//! Fixes must never run while a daemon uses the data directory, as it may be writing to the very
+//! files being cut or moved. `docatlas check` refuses to fix a directory while a running daemon
+//! holds its pid file.
+
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, ErrorKind, Read};
+use std::path::{Path, PathBuf};
+
+use blake2::{Blake2s256, Digest};
+use serde::{Deserialize, Serialize};
+
+use crate::schema::Schema;
+
+/// The directory indexes are stored in within a data directory
+const INDEXES_DIR: &str = "indexes";
+/// The directory snapshot repositories are stored in within a data directory
+const SNAPSHOTS_DIR: &str = "snapshots";
+/// The directory corrupt and orphaned files are moved into
+const QUARANTINE_DIR: &str = "quarantine";
+/// The file storing the mapping of an index
+const MAPPING_FILE: &str = "mapping.json";
+/// The write-ahead log of an index
+const WAL_FILE: &str = "wal.log";
+/// The write-ahead log of an index while it is merged
+const MERGING_FILE: &str = "wal.log.merging";
+/// The extension of files written before they replace another
+const TEMP_EXTENSION: &str = ".tmp";
+/// The size of the header preceding every frame of a write-ahead log
+const HEADER_LEN: u64 = 8;
+
+/// Checks a data directory without changing anything in it
+pub fn check(path: impl AsRef<Path>) -> io::Result<CheckReport> {
+    Checker::new().check(path)
+}
+
+/// What is done with the problems found by a [check](Checker)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Fix {
+    /// Cuts logs before their first broken frame and removes orphaned files
+    Repair,
+    /// Repairs logs after copying them into the quarantine, and moves orphaned and corrupt files
+    /// into it
+    Quarantine,
+}
+
+/// Checks data directories, fixing the problems found if a fix is chosen
+#[derive(Debug, Clone, Default)]
+pub struct Checker {
+    fix: Option<Fix>,
+}
+
+impl Checker {
+    /// Creates a checker that only reports problems
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets what is done with the problems found
+    pub fn with_fix(mut self, fix: Fix) -> Self {
+        self.fix = Some(fix);
+        self
+    }
+
+    /// Gets what is done with the problems found, if anything
+    pub fn fix(&self) -> Option<Fix> {
+        self.fix
+    }
+
+    /// Checks the data directory at the given path. Problems with the files within it are
+    /// reported, while errors are only returned if the directory can not be walked or a problem
+    /// can not be fixed.
+    pub fn check(&self, path: impl AsRef<Path>) -> io::Result<CheckReport> {
+        let path = path.as_ref();
+        let mut check = Check {
+            root: path,
+            fix: self.fix,
+            report: CheckReport::default(),
+        };
+        for index in entries(&path.join(INDEXES_DIR))? {
+            if index.is_dir() {
+                check.index(&index)?;
+            }
+        }
+        for repository in entries(&path.join(SNAPSHOTS_DIR))? {
+            if repository.is_dir() {
+                check.repository(&repository)?;
+            }
+        }
+        Ok(check.report)
+    }
+}
+
+/// The problems found by a check
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CheckReport {
+    /// The number of files checked
+    pub files: usize,
+    /// Every problem found, in the order the files were checked
+    pub issues: Vec<Issue>,
+}
+
+impl CheckReport {
+    /// Checks whether no problem was found
+    pub fn is_clean(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    /// Iterates over the problems that were not fixed
+    pub fn unfixed(&self) -> impl Iterator<Item = &Issue> {
+        self.issues.iter().filter(|issue| issue.fixed.is_none())
+    }
+}
+
+/// A problem with a file of a data directory
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Issue {
+    pub path: PathBuf,
+    pub problem: Problem,
+    /// What was done about the problem, if it was fixed
+    pub fixed: Option<Fixed>,
+}
+
+/// What is wrong with a file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Problem {
+    /// The file could not be read
+    Unreadable { reason: String },
+    /// The directory of an index holds no mapping
+    MissingMapping,
+    /// The mapping of an index is not a schema
+    InvalidMapping { reason: String },
+    /// The header of the frame at `offset` claims more bytes than are left in the log
+    TornFrame { offset: u64 },
+    /// The payload of the frame at `offset` does not match its checksum
+    ChecksumMismatch { offset: u64 },
+    /// The manifest of a snapshot could not be parsed
+    InvalidManifest { reason: String },
+    /// The manifest of a snapshot references a segment the repository does not hold
+    MissingSegment { segment: String },
+    /// The contents of a segment do not match the hash it is stored by
+    CorruptSegment,
+    /// The file is left over from a write that never finished, or is no longer referenced
+    Orphaned,
+}
+
+/// How a problem was fixed
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Fixed {
+    /// The log was cut to `len` bytes, after being copied to `copy` if it was quarantined
+    Truncated { len: u64, copy: Option<PathBuf> },
+    /// The file was removed
+    Removed,
+    /// The file, or the directory holding it, was moved to `to`
+    Quarantined { to: PathBuf },
+}
+
+/// The segments referenced by the manifest of a snapshot
+#[derive(Deserialize)]
+struct ManifestSegments {
+    indexes: Vec<IndexSegments>,
+}
+
+#[derive(Deserialize)]
+struct IndexSegments {
+    segments: Vec<String>,
+}
+
+/// A check of a single data directory
+struct Check<'a> {
+    root: &'a Path,
+    fix: Option<Fix>,
+    report: CheckReport,
+}
+
+impl Check<'_> {
+    /// Checks the directory of an index. Indexes without a readable mapping are moved into the
+    /// quarantine as a whole, as the daemon can not load them.
+    fn index(&mut self, dir: &Path) -> io::Result<()> {
+        let mapping = dir.join(MAPPING_FILE);
+        let problem = match fs::read(&mapping) {
+            Ok(json) => {
+                self.report.files += 1;
+                serde_json::from_slice::<Schema>(&json)
+                    .err()
+                    .map(|e| Problem::InvalidMapping {
+                        reason: e.to_string(),
+                    })
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => Some(Problem::MissingMapping),
+            Err(e) => Some(Problem::Unreadable {
+                reason: e.to_string(),
+            }),
+        };
+        if let Some(problem) = problem {
+            let fixed = match self.fix {
+                Some(Fix::Quarantine) => Some(self.quarantine(dir)?),
+                _ => None,
+            };
+            let moved = fixed.is_some();
+            self.issue(&mapping, problem, fixed);
+            if moved {
+                return Ok(());
+            }
+        }
+        for file in entries(dir)? {
+            match file.file_name().and_then(|name| name.to_str()) {
+                Some(WAL_FILE) => self.log(&file)?,
+                Some(MERGING_FILE) => self.orphan(&file)?,
+                Some(name) if name.ends_with(TEMP_EXTENSION) => self.orphan(&file)?,
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks every frame of a write-ahead log
+    fn log(&mut self, path: &Path) -> io::Result<()> {
+        self.report.files += 1;
+        let (offset, problem) = match broken_frame(path) {
+            Ok(Some(broken)) => broken,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                let reason = e.to_string();
+                self.issue(path, Problem::Unreadable { reason }, None);
+                return Ok(());
+            }
+        };
+        let fixed = match self.fix {
+            None => None,
+            Some(fix) => {
+                let copy = match fix {
+                    Fix::Repair => None,
+                    Fix::Quarantine => {
+                        let to = self.quarantine_path(path);
+                        fs::create_dir_all(to.parent().unwrap_or(self.root))?;
+                        fs::copy(path, &to)?;
+                        Some(to)
+                    }
+                };
+                let log = OpenOptions::new().write(true).open(path)?;
+                log.set_len(offset)?;
+                log.sync_all()?;
+                Some(Fixed::Truncated { len: offset, copy })
+            }
+        };
+        self.issue(path, problem, fixed);
+        Ok(())
+    }
+
+    /// Checks the manifests and segments of a snapshot repository
+    fn repository(&mut self, dir: &Path) -> io::Result<()> {
+        let mut referenced = HashSet::new();
+        let mut manifests = vec![];
+        // a manifest that can not be read may reference any segment, so none are orphaned
+        let mut complete = true;
+        for path in entries(&dir.join("snapshots"))? {
+            let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            if name.ends_with(TEMP_EXTENSION) {
+                self.orphan(&path)?;
+                continue;
+            }
+            if !name.ends_with(".json") {
+                continue;
+            }
+            self.report.files += 1;
+            let parsed = fs::read(&path).map_err(|e| e.to_string()).and_then(|json| {
+                serde_json::from_slice::<ManifestSegments>(&json).map_err(|e| e.to_string())
+            });
+            match parsed {
+                Ok(manifest) => {
+                    let segments = manifest
+                        .indexes
+                        .into_iter()
+                        .flat_map(|index| index.segments)
+                        .collect::<Vec<_>>();
+                    referenced.extend(segments.iter().cloned());
+                    manifests.push((path, segments));
+                }
+                Err(reason) => {
+                    complete = false;
+                    let fixed = match self.fix {
+                        Some(Fix::Quarantine) => Some(self.quarantine(&path)?),
+                        _ => None,
+                    };
+                    self.issue(&path, Problem::InvalidManifest { reason }, fixed);
+                }
+            }
+        }
+
+        let segments = dir.join("segments");
+        for path in entries(&segments)? {
+            let Some(id) = path.file_name().and_then(|name| name.to_str()) else {
+                continue;
+            };
+            if id.ends_with(TEMP_EXTENSION) || (complete && !referenced.contains(id)) {
+                self.orphan(&path)?;
+                continue;
+            }
+            self.report.files += 1;
+            match fs::read(&path) {
+                Ok(segment) if segment_id(&segment) == id => {}
+                Ok(_) => {
+                    let fixed = match self.fix {
+                        Some(Fix::Quarantine) => Some(self.quarantine(&path)?),
+                        _ => None,
+                    };
+                    self.issue(&path, Problem::CorruptSegment, fixed);
+                }
+                Err(e) => {
+                    let reason = e.to_string();
+                    self.issue(&path, Problem::Unreadable { reason }, None);
+                }
+            }
+        }
+
+        // checked last, so quarantined segments are reported as missing too
+        for (path, ids) in manifests {
+            for segment in ids {
+                if !segments.join(&segment).exists() {
+                    self.issue(&path, Problem::MissingSegment { segment }, None);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reports a file that is left over, removing or quarantining it if a fix is chosen
+    fn orphan(&mut self, path: &Path) -> io::Result<()> {
+        self.report.files += 1;
+        let fixed = match self.fix {
+            None => None,
+            Some(Fix::Repair) => {
+                fs::remove_file(path)?;
+                Some(Fixed::Removed)
+            }
+            Some(Fix::Quarantine) => Some(self.quarantine(path)?),
+        };
+        self.issue(path, Problem::Orphaned, fixed);
+        Ok(())
+    }
+
+    /// Moves a file or directory into the quarantine
+    fn quarantine(&self, path: &Path) -> io::Result<Fixed> {
+        let to = self.quarantine_path(path);
+        fs::create_dir_all(to.parent().unwrap_or(self.root))?;
+        fs::rename(path, &to)?;
+        Ok(Fixed::Quarantined { to })
+    }
+
+    /// Gets where a file is kept in the quarantine, which is the path it had within the data
+    /// directory unless that is taken by a file quarantined before
+    fn quarantine_path(&self, path: &Path) -> PathBuf {
+        let relative = path
+            .strip_prefix(self.root)
+            .unwrap_or_else(|_| Path::new(path.file_name().unwrap_or(path.as_os_str())));
+        let to = self.root.join(QUARANTINE_DIR).join(relative);
+        let mut taken = to.clone();
+        let mut attempt = 1;
+        while taken.exists() {
+            let mut name = to.as_os_str().to_owned();
+            name.push(format!(".{attempt}"));
+            taken = PathBuf::from(name);
+            attempt += 1;
+        }
+        taken
+    }
+
+    fn issue(&mut self, path: &Path, problem: Problem, fixed: Option<Fixed>) {
+        self.report.issues.push(Issue {
+            path: path.to_path_buf(),
+            problem,
+            fixed,
+        });
+    }
+}
+
+/// Finds the first frame of a write-ahead log that is cut short or does not match its checksum,
+/// along with its offset
+fn broken_frame(path: &Path) -> io::Result<Option<(u64, Problem)>> {
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let mut header = [0; HEADER_LEN as usize];
+    let mut payload = vec![];
+    let mut offset = 0;
+    while offset < len {
+        // the length is checked against the log before reading, so a garbled header is never
+        // taken as a huge frame
+        if len - offset < HEADER_LEN {
+            return Ok(Some((offset, Problem::TornFrame { offset })));
+        }
+        reader.read_exact(&mut header)?;
+        let frame_len = u32::from_le_bytes(header[..4].try_into().unwrap()) as u64;
+        let checksum = u32::from_le_bytes(header[4..].try_into().unwrap());
+        if len - offset - HEADER_LEN < frame_len {
+            return Ok(Some((offset, Problem::TornFrame { offset })));
+        }
+        payload.resize(frame_len as usize, 0);
+        reader.read_exact(&mut payload)?;
+        if crc32fast::hash(&payload) != checksum {
+            return Ok(Some((offset, Problem::ChecksumMismatch { offset })));
+        }
+        offset += HEADER_LEN + frame_len;
+    }
+    Ok(None)
+}
+
+/// Gets the id a segment is stored by, which is the hash of its contents
+fn segment_id(segment: &[u8]) -> String {
+    Blake2s256::digest(segment)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Gets the paths of the entries of a directory in order, or nothing if it does not exist
+fn entries(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e),
+    };
+    let mut paths = entries
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    paths.sort();
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn frame(payload: &[u8]) -> Vec<u8> {
+        let mut frame = (payload.len() as u32).to_le_bytes().to_vec();
+        frame.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    /// Creates a data directory with one healthy index, one index with a broken mapping and log,
+    /// and a repository with a corrupt, a missing and an orphaned segment
+    fn data_dir(root: &Path) -> (Vec<u8>, String) {
+        let mapping = serde_json::to_vec(&Schema::new()).unwrap();
+        let books = root.join(INDEXES_DIR).join("books");
+        fs::create_dir_all(&books).unwrap();
+        fs::write(books.join(MAPPING_FILE), &mapping).unwrap();
+        let mut log = [frame(b"first"), frame(b"second")].concat();
+        let committed = log.clone();
+        log.extend_from_slice(&frame(b"third")[..6]);
+        fs::write(books.join(WAL_FILE), &log).unwrap();
+        fs::write(books.join(MERGING_FILE), b"half a merge").unwrap();
+
+        let broken = root.join(INDEXES_DIR).join("broken");
+        fs::create_dir_all(&broken).unwrap();
+        fs::write(broken.join(MAPPING_FILE), b"{ not json").unwrap();
+
+        let repository = root.join(SNAPSHOTS_DIR).join("nightly");
+        let segments = repository.join("segments");
+        fs::create_dir_all(&segments).unwrap();
+        fs::create_dir_all(repository.join("snapshots")).unwrap();
+        let good = segment_id(b"good");
+        fs::write(segments.join(&good), b"good").unwrap();
+        let corrupt = segment_id(b"corrupt");
+        fs::write(segments.join(&corrupt), b"c0rrupt").unwrap();
+        fs::write(segments.join(segment_id(b"orphan")), b"orphan").unwrap();
+        let manifest = serde_json::json!({
+            "name": "first",
+            "created": "2023-01-01T00:00:00Z",
+            "indexes": [{
+                "name": "books",
+                "schema": Schema::new(),
+                "rows": 3,
+                "segments": [good, corrupt, "missing"],
+            }],
+        });
+        fs::write(
+            repository.join("snapshots").join("first.json"),
+            serde_json::to_vec(&manifest).unwrap(),
+        )
+        .unwrap();
+        (committed, corrupt)
+    }
+
+    fn problems(report: &CheckReport) -> Vec<(&str, &Problem)> {
+        report
+            .issues
+            .iter()
+            .map(|issue| {
+                let name = issue.path.file_name().unwrap().to_str().unwrap();
+                (name, &issue.problem)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn find_problems() {
+        let dir = tempdir().unwrap();
+        let (committed, corrupt) = data_dir(dir.path());
+        let report = check(dir.path()).unwrap();
+        assert_eq!(report.files, 8);
+        let found = problems(&report);
+        let torn = Problem::TornFrame {
+            offset: committed.len() as u64,
+        };
+        assert_eq!(found[0], (WAL_FILE, &torn));
+        assert_eq!(found[1], (MERGING_FILE, &Problem::Orphaned));
+        assert!(matches!(
+            found[2],
+            (MAPPING_FILE, Problem::InvalidMapping { .. })
+        ));
+        // segments are checked in the order of their hashes
+        assert!(found.contains(&(corrupt.as_str(), &Problem::CorruptSegment)));
+        assert!(found.contains(&(segment_id(b"orphan").as_str(), &Problem::Orphaned)));
+        let missing = Problem::MissingSegment {
+            segment: "missing".to_string(),
+        };
+        assert_eq!(found[5], ("first.json", &missing));
+        assert_eq!(found.len(), 6);
+        assert_eq!(report.unfixed().count(), 6);
+
+        // checking changes nothing
+        assert_eq!(check(dir.path()).unwrap().issues, report.issues);
+    }
+
+    #[test]
+    fn repair_and_quarantine() {
+        let dir = tempdir().unwrap();
+        let (committed, corrupt) = data_dir(dir.path());
+        let books = dir.path().join(INDEXES_DIR).join("books");
+        let report = Checker::new()
+            .with_fix(Fix::Repair)
+            .check(dir.path())
+            .unwrap();
+        assert_eq!(fs::read(books.join(WAL_FILE)).unwrap(), committed);
+        assert!(!books.join(MERGING_FILE).exists());
+        // corrupt files are left alone, and nothing is missing that was not before
+        assert_eq!(report.unfixed().count(), 3);
+        assert_eq!(check(dir.path()).unwrap().issues.len(), 3);
+
+        let report = Checker::new()
+            .with_fix(Fix::Quarantine)
+            .check(dir.path())
+            .unwrap();
+        let quarantine = dir.path().join(QUARANTINE_DIR);
+        assert_eq!(
+            report.issues[0].fixed,
+            Some(Fixed::Quarantined {
+                to: quarantine.join(INDEXES_DIR).join("broken"),
+            })
+        );
+        assert!(quarantine
+            .join(SNAPSHOTS_DIR)
+            .join("nightly/segments")
+            .join(&corrupt)
+            .exists());
+        // the quarantined segment is now missing as well
+        let report = check(dir.path()).unwrap();
+        let missing = report
+            .issues
+            .iter()
+            .map(|issue| match &issue.problem {
+                Problem::MissingSegment { segment } => segment.as_str(),
+                problem => panic!("unexpected {problem:?}"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(missing, [corrupt.as_str(), "missing"]);
+    }
+}
@@ -0,0 +1,303 @@
+//! A persisted least recently used cache
+
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::hash::Hash;
+use std::io;
+use std::path::Path;
+
+use crate::persist::block::{BlockError, Blocks, DEFAULT_SEGMENT_SIZE};
+use crate::persist::PersistentVec;
+
+/// Marks the end of the recency list
+const NIL: usize = usize::MAX;
+
+/// A cache holding up to a fixed number of entries, evicting the least recently used entry to make
+/// room for new ones.
+///
+/// Entries are stored in a block along with the order they were used in, so a cache opened on a
+/// file starts out as warm as it was when it was last flushed. Only the key lookup lives in
+/// memory, and is rebuilt when the cache is opened.
+pub struct PersistedLru<K: Copy + Eq + Hash, V: Copy> {
+    entries: PersistentVec<Entry<K, V>>,
+    keys: HashMap<K, usize>,
+    capacity: usize,
+    /// The most recently used entry
+    head: usize,
+    /// The least recently used entry
+    tail: usize,
+}
+
+/// An entry of the cache, linked to the entries used right before and after it
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct Entry<K, V> {
+    key: K,
+    value: V,
+    /// The entry used more recently than this one
+    newer: usize,
+    /// The entry used less recently than this one
+    older: usize,
+}
+
+impl<K: Copy + Eq + Hash, V: Copy> PersistedLru<K, V> {
+    /// Creates an empty cache in memory, holding up to `capacity` entries
+    pub fn in_memory(capacity: usize) -> Self {
+        Self::new(PersistentVec::in_memory(), capacity)
+    }
+
+    /// Opens the cache stored in the file at the given path, creating it if it does not exist.
+    /// If the file holds more than `capacity` entries, the least recently used ones are evicted.
+    pub fn open(path: impl AsRef<Path>, capacity: usize) -> Result<Self, BlockError> {
+        let block = Blocks
+            .builder()
+            .with_size(DEFAULT_SEGMENT_SIZE)
+            .open(path)?;
+        Ok(Self::new(PersistentVec::new(block), capacity))
+    }
+
+    fn new(entries: PersistentVec<Entry<K, V>>, capacity: usize) -> Self {
+        let mut keys = HashMap::with_capacity(entries.len());
+        let (mut head, mut tail) = (NIL, NIL);
+        for (index, entry) in entries.iter().enumerate() {
+            keys.insert(entry.key, index);
+            if entry.newer == NIL {
+                head = index;
+            }
+            if entry.older == NIL {
+                tail = index;
+            }
+        }
+        let mut lru = Self {
+            entries,
+            keys,
+            capacity,
+            head,
+            tail,
+        };
+        while lru.len() > capacity {
+            lru.evict();
+        }
+        lru
+    }
+
+    /// Gets the most entries the cache holds
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Gets the number of entries in the cache
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Checks whether the cache holds no entries
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Checks whether the cache holds an entry for a key, without counting it as used
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.keys.contains_key(key)
+    }
+
+    /// Gets the value of a key, marking it as the most recently used entry
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        let index = *self.keys.get(key)?;
+        self.touch(index);
+        Some(&self.entries[index].value)
+    }
+
+    /// Gets the value of a key, without counting it as used
+    pub fn peek(&self, key: &K) -> Option<&V> {
+        let index = *self.keys.get(key)?;
+        Some(&self.entries[index].value)
+    }
+
+    /// Inserts the value of a key as the most recently used entry. Returns the entry replaced by
+    /// it, or the least recently used entry if it was evicted to make room.
+    pub fn insert(&mut self, key: K, value: V) -> Option<(K, V)> {
+        if let Some(&index) = self.keys.get(&key) {
+            let old = std::mem::replace(&mut self.entries[index].value, value);
+            self.touch(index);
+            return Some((key, old));
+        }
+        if self.capacity == 0 {
+            return Some((key, value));
+        }
+        let evicted = match self.len() >= self.capacity {
+            true => self.evict(),
+            false => None,
+        };
+        let index = self.len();
+        self.entries.extend_from_slice(&[Entry {
+            key,
+            value,
+            newer: NIL,
+            older: NIL,
+        }]);
+        self.keys.insert(key, index);
+        self.link_front(index);
+        evicted
+    }
+
+    /// Removes the entry of a key, returning its value
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let index = self.keys.remove(key)?;
+        Some(self.remove_at(index).value)
+    }
+
+    /// Removes the least recently used entry
+    pub fn evict(&mut self) -> Option<(K, V)> {
+        if self.tail == NIL {
+            return None;
+        }
+        let entry = self.remove_at(self.tail);
+        self.keys.remove(&entry.key);
+        Some((entry.key, entry.value))
+    }
+
+    /// Removes every entry
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.keys.clear();
+        self.head = NIL;
+        self.tail = NIL;
+    }
+
+    /// Iterates over the entries, from the most to the least recently used, without counting them
+    /// as used
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        let mut next = self.head;
+        std::iter::from_fn(move || {
+            let entry = self.entries.get(next)?;
+            next = entry.older;
+            Some((&entry.key, &entry.value))
+        })
+    }
+
+    /// Writes the entries and the order they were used in to the file backing the cache
+    pub fn flush(&self) -> io::Result<()> {
+        self.entries.flush()
+    }
+
+    /// Marks an entry as the most recently used
+    fn touch(&mut self, index: usize) {
+        if self.head != index {
+            self.unlink(index);
+            self.link_front(index);
+        }
+    }
+
+    /// Removes the entry at an index, moving the last entry into its place
+    fn remove_at(&mut self, index: usize) -> Entry<K, V> {
+        self.unlink(index);
+        let removed = self.entries[index];
+        let last = self.len() - 1;
+        if index != last {
+            let moved = self.entries[last];
+            self.entries[index] = moved;
+            self.keys.insert(moved.key, index);
+            // the neighbours of the moved entry still point at where it was
+            match moved.newer {
+                NIL => self.head = index,
+                newer => self.entries[newer].older = index,
+            }
+            match moved.older {
+                NIL => self.tail = index,
+                older => self.entries[older].newer = index,
+            }
+        }
+        self.entries.pop();
+        removed
+    }
+
+    /// Takes an entry out of the recency list
+    fn unlink(&mut self, index: usize) {
+        let Entry { newer, older, .. } = self.entries[index];
+        match newer {
+            NIL => self.head = older,
+            newer => self.entries[newer].older = older,
+        }
+        match older {
+            NIL => self.tail = newer,
+            older => self.entries[older].newer = newer,
+        }
+    }
+
+    /// Puts an entry at the front of the recency list
+    fn link_front(&mut self, index: usize) {
+        let old_head = self.head;
+        self.entries[index].newer = NIL;
+        self.entries[index].older = old_head;
+        match old_head {
+            NIL => self.tail = index,
+            old_head => self.entries[old_head].newer = index,
+        }
+        self.head = index;
+    }
+}
+
+impl<K: Copy + Eq + Hash + Debug, V: Copy + Debug> Debug for PersistedLru<K, V> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn keys(lru: &PersistedLru<u32, u64>) -> Vec<u32> {
+        lru.iter().map(|(key, _)| *key).collect()
+    }
+
+    #[test]
+    fn evict_least_recently_used() {
+        let mut lru = PersistedLru::<u32, u64>::in_memory(3);
+        for key in 1..=3 {
+            assert_eq!(lru.insert(key, key as u64 * 10), None);
+        }
+        assert_eq!(lru.get(&1), Some(&10));
+        assert_eq!(keys(&lru), [1, 3, 2]);
+        assert_eq!(lru.insert(4, 40), Some((2, 20)));
+        assert_eq!(lru.insert(3, 33), Some((3, 30)));
+        assert_eq!(keys(&lru), [3, 4, 1]);
+        // peeking leaves the order alone
+        assert_eq!(lru.peek(&1), Some(&10));
+        assert_eq!(lru.remove(&4), Some(40));
+        assert_eq!(keys(&lru), [3, 1]);
+        assert_eq!(lru.evict(), Some((1, 10)));
+        assert_eq!(lru.len(), 1);
+        lru.clear();
+        assert!(lru.is_empty());
+        assert_eq!(lru.evict(), None);
+    }
+
+    #[test]
+    fn survive_reopening_warm() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("cache");
+        {
+            let mut lru = PersistedLru::<u32, u64>::open(&path, 100).unwrap();
+            for key in 0..50 {
+                lru.insert(key, key as u64);
+            }
+            lru.get(&0);
+            lru.remove(&25);
+            lru.flush().unwrap();
+        }
+        let mut lru = PersistedLru::<u32, u64>::open(&path, 100).unwrap();
+        assert_eq!(lru.len(), 49);
+        assert_eq!(keys(&lru)[..3], [0, 49, 48]);
+        assert_eq!(lru.get(&24), Some(&24));
+        assert!(!lru.contains_key(&25));
+        drop(lru);
+
+        // reopening with less room evicts the least recently used entries
+        let lru = PersistedLru::<u32, u64>::open(&path, 2).unwrap();
+        assert_eq!(keys(&lru), [24, 0]);
+    }
+}
@@ -0,0 +1,517 @@
+//! A persisted radix trie
+
+use std::fmt::{Debug, Formatter};
+use std::io;
+use std::path::Path;
+
+use crate::persist::block::{BlockError, Blocks, DEFAULT_SEGMENT_SIZE};
+use crate::persist::PersistentVec;
+
+/// Marks a missing child or sibling
+const NIL: usize = usize::MAX;
+
+/// The node every key starts at, whose label is empty
+const ROOT: usize = 0;
+
+/// The most bytes of a key held by a single node. Longer runs of bytes without a branch are
+/// spread over a chain of nodes.
+const LABEL_LEN: usize = 16;
+
+/// A map from byte strings to values, sharing the storage of common prefixes between keys.
+///
+/// Every node of the trie is labelled with the bytes that lead to it from its parent, so a key
+/// without branches below some prefix is stored in as few nodes as its length allows. Children
+/// are kept in the order of their first byte, so keys are iterated in lexicographic order, and
+/// every key starting with a prefix can be iterated without looking at any other key.
+///
+/// Nodes are stored in a block, so a trie opened on a file holds what it held when it was last
+/// flushed. Nodes freed by removals are reused by later insertions.
+pub struct PersistedTrie<V: Copy> {
+    nodes: PersistentVec<Node<V>>,
+    /// Nodes that are not part of the trie and can be reused
+    free: Vec<usize>,
+    len: usize,
+}
+
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct Node<V> {
+    value: Option<V>,
+    first_child: usize,
+    next_sibling: usize,
+    label_len: u8,
+    label: [u8; LABEL_LEN],
+}
+
+impl<V> Node<V> {
+    fn new(label: &[u8], value: Option<V>) -> Self {
+        let mut node = Self {
+            value,
+            first_child: NIL,
+            next_sibling: NIL,
+            label_len: 0,
+            label: [0; LABEL_LEN],
+        };
+        node.set_label(label);
+        node
+    }
+
+    fn label(&self) -> &[u8] {
+        &self.label[..self.label_len as usize]
+    }
+
+    fn set_label(&mut self, label: &[u8]) {
+        self.label[..label.len()].copy_from_slice(label);
+        self.label_len = label.len() as u8;
+    }
+}
+
+impl<V: Copy> PersistedTrie<V> {
+    /// Creates an empty trie in memory
+    pub fn in_memory() -> Self {
+        Self::new(PersistentVec::in_memory())
+    }
+
+    /// Opens the trie stored in the file at the given path, creating it if it does not exist
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, BlockError> {
+        let block = Blocks
+            .builder()
+            .with_size(DEFAULT_SEGMENT_SIZE)
+            .open(path)?;
+        Ok(Self::new(PersistentVec::new(block)))
+    }
+
+    fn new(mut nodes: PersistentVec<Node<V>>) -> Self {
+        if nodes.is_empty() {
+            nodes.push(Node::new(&[], None));
+        }
+        // nodes freed before the trie was flushed are the ones no longer reachable from the root
+        let mut reachable = vec![false; nodes.len()];
+        let mut len = 0;
+        let mut stack = vec![ROOT];
+        while let Some(node) = stack.pop() {
+            reachable[node] = true;
+            len += nodes[node].value.is_some() as usize;
+            let mut child = nodes[node].first_child;
+            while child != NIL {
+                stack.push(child);
+                child = nodes[child].next_sibling;
+            }
+        }
+        let free = (0..nodes.len()).filter(|&node| !reachable[node]).collect();
+        Self { nodes, free, len }
+    }
+
+    /// Gets the number of keys in the trie
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Checks whether the trie holds no keys
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Checks whether the trie holds a value for a key
+    pub fn contains_key(&self, key: impl AsRef<[u8]>) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Gets the value of a key
+    pub fn get(&self, key: impl AsRef<[u8]>) -> Option<&V> {
+        let node = *self.path(key.as_ref())?.last()?;
+        self.nodes[node].value.as_ref()
+    }
+
+    /// Gets the longest prefix of a key that is itself a key of the trie, along with its value
+    pub fn longest_prefix<'k>(&self, key: &'k [u8]) -> Option<(&'k [u8], &V)> {
+        let mut longest = self.nodes[ROOT].value.as_ref().map(|value| (0, value));
+        let (mut node, mut matched) = (ROOT, 0);
+        while let Some(child) = key.get(matched).and_then(|&byte| self.child(node, byte)) {
+            let label = self.nodes[child].label();
+            if !key[matched..].starts_with(label) {
+                break;
+            }
+            matched += label.len();
+            node = child;
+            if let Some(value) = &self.nodes[node].value {
+                longest = Some((matched, value));
+            }
+        }
+        longest.map(|(len, value)| (&key[..len], value))
+    }
+
+    /// Inserts the value of a key, returning the value it replaced
+    pub fn insert(&mut self, key: impl AsRef<[u8]>, value: V) -> Option<V> {
+        let mut rest = key.as_ref();
+        let mut node = ROOT;
+        loop {
+            let Some(&first) = rest.first() else {
+                let old = self.nodes[node].value.replace(value);
+                self.len += old.is_none() as usize;
+                return old;
+            };
+            let Some(child) = self.child(node, first) else {
+                let chain = self.chain(rest, value);
+                self.link_child(node, chain);
+                self.len += 1;
+                return None;
+            };
+            let label = self.nodes[child].label();
+            let common = label.iter().zip(rest).take_while(|(a, b)| a == b).count();
+            if common < label.len() {
+                self.split(child, common);
+            }
+            rest = &rest[common..];
+            node = child;
+        }
+    }
+
+    /// Removes a key, returning its value
+    pub fn remove(&mut self, key: impl AsRef<[u8]>) -> Option<V> {
+        let path = self.path(key.as_ref())?;
+        let value = self.nodes[*path.last()?].value.take()?;
+        self.len -= 1;
+        // prune the nodes left without a value, up to the first one still needed
+        for depth in (1..path.len()).rev() {
+            let (parent, node) = (path[depth - 1], path[depth]);
+            if self.nodes[node].value.is_some() {
+                break;
+            }
+            if self.nodes[node].first_child != NIL {
+                self.merge(node);
+                break;
+            }
+            self.unlink_child(parent, node);
+            self.release(node);
+        }
+        Some(value)
+    }
+
+    /// Removes every key
+    pub fn clear(&mut self) {
+        self.nodes.clear();
+        self.nodes.push(Node::new(&[], None));
+        self.free.clear();
+        self.len = 0;
+    }
+
+    /// Iterates over every key starting with a prefix and its value, in the order of the keys
+    pub fn prefix(&self, prefix: impl AsRef<[u8]>) -> Prefix<'_, V> {
+        let mut rest = prefix.as_ref();
+        let mut key = Vec::new();
+        let mut node = ROOT;
+        while let Some(&first) = rest.first() {
+            let Some(child) = self.child(node, first) else {
+                return Prefix::empty(self);
+            };
+            let label = self.nodes[child].label();
+            // the prefix may end inside the label of a node, whose keys all start with it
+            let matched = label.len().min(rest.len());
+            if label[..matched] != rest[..matched] {
+                return Prefix::empty(self);
+            }
+            key.extend_from_slice(label);
+            rest = &rest[matched..];
+            node = child;
+        }
+        let base = key.len() - self.nodes[node].label().len();
+        Prefix {
+            trie: self,
+            key,
+            stack: vec![(node, base)],
+        }
+    }
+
+    /// Iterates over every key and its value, in the order of the keys
+    pub fn iter(&self) -> Prefix<'_, V> {
+        self.prefix([])
+    }
+
+    /// Writes the nodes of the trie to the file backing it
+    pub fn flush(&self) -> io::Result<()> {
+        self.nodes.flush()
+    }
+
+    /// Gets the nodes leading to a key, starting at the root, if the key ends at a node
+    fn path(&self, key: &[u8]) -> Option<Vec<usize>> {
+        let mut path = vec![ROOT];
+        let mut rest = key;
+        while let Some(&first) = rest.first() {
+            let child = self.child(*path.last()?, first)?;
+            rest = rest.strip_prefix(self.nodes[child].label())?;
+            path.push(child);
+        }
+        Some(path)
+    }
+
+    /// Gets the child of a node whose label starts with a byte
+    fn child(&self, node: usize, byte: u8) -> Option<usize> {
+        let mut child = self.nodes[node].first_child;
+        while child != NIL {
+            let label = self.nodes[child].label();
+            if label[0] == byte {
+                return Some(child);
+            }
+            if label[0] > byte {
+                return None;
+            }
+            child = self.nodes[child].next_sibling;
+        }
+        None
+    }
+
+    /// Links a node in among the children of a parent, keeping them ordered by their first byte
+    fn link_child(&mut self, parent: usize, node: usize) {
+        let byte = self.nodes[node].label[0];
+        let mut previous = NIL;
+        let mut next = self.nodes[parent].first_child;
+        while next != NIL && self.nodes[next].label[0] < byte {
+            previous = next;
+            next = self.nodes[next].next_sibling;
+        }
+        self.nodes[node].next_sibling = next;
+        match previous {
+            NIL => self.nodes[parent].first_child = node,
+            previous => self.nodes[previous].next_sibling = node,
+        }
+    }
+
+    /// Takes a node out of the children of its parent
+    fn unlink_child(&mut self, parent: usize, node: usize) {
+        let next = self.nodes[node].next_sibling;
+        let mut previous = NIL;
+        let mut current = self.nodes[parent].first_child;
+        while current != node {
+            previous = current;
+            current = self.nodes[current].next_sibling;
+        }
+        match previous {
+            NIL => self.nodes[parent].first_child = next,
+            previous => self.nodes[previous].next_sibling = next,
+        }
+    }
+
+    /// Creates the chain of nodes holding the rest of a key, returning the first node of it
+    fn chain(&mut self, rest: &[u8], value: V) -> usize {
+        let mut chunks = rest.chunks(LABEL_LEN).rev();
+        let last = chunks.next().expect("chained keys are not empty");
+        let mut node = self.allocate(Node::new(last, Some(value)));
+        for chunk in chunks {
+            let mut parent = Node::new(chunk, None);
+            parent.first_child = node;
+            node = self.allocate(parent);
+        }
+        node
+    }
+
+    /// Splits the label of a node after `at` bytes, moving its value and children into a new
+    /// child holding the rest of the label
+    fn split(&mut self, node: usize, at: usize) {
+        let current = self.nodes[node];
+        let mut tail = Node::new(&current.label()[at..], current.value);
+        tail.first_child = current.first_child;
+        let tail = self.allocate(tail);
+        let node = &mut self.nodes[node];
+        node.label_len = at as u8;
+        node.value = None;
+        node.first_child = tail;
+    }
+
+    /// Merges a node without a value into its only child, if their labels fit into a single node
+    fn merge(&mut self, node: usize) {
+        let current = self.nodes[node];
+        let child = self.nodes[current.first_child];
+        if current.value.is_some()
+            || child.next_sibling != NIL
+            || current.label().len() + child.label().len() > LABEL_LEN
+        {
+            return;
+        }
+        let merged = &mut self.nodes[node];
+        merged.set_label(&[current.label(), child.label()].concat());
+        merged.value = child.value;
+        merged.first_child = child.first_child;
+        self.release(current.first_child);
+    }
+
+    fn allocate(&mut self, node: Node<V>) -> usize {
+        match self.free.pop() {
+            Some(index) => {
+                self.nodes[index] = node;
+                index
+            }
+            None => {
+                self.nodes.push(node);
+                self.nodes.len() - 1
+            }
+        }
+    }
+
+    fn release(&mut self, node: usize) {
+        self.nodes[node] = Node::new(&[], None);
+        self.free.push(node);
+    }
+}
+
+impl<V: Copy + Debug> Debug for PersistedTrie<V> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_map()
+            .entries(
+                self.iter()
+                    .map(|(key, value)| (String::from_utf8_lossy(&key).into_owned(), value)),
+            )
+            .finish()
+    }
+}
+
+/// Iterates over the keys of a [`PersistedTrie`] starting with a prefix, along with their values
+pub struct Prefix<'a, V: Copy> {
+    trie: &'a PersistedTrie<V>,
+    /// The key of the node visited last
+    key: Vec<u8>,
+    /// The nodes left to visit, along with the length of the key of their parent
+    stack: Vec<(usize, usize)>,
+}
+
+impl<'a, V: Copy> Prefix<'a, V> {
+    fn empty(trie: &'a PersistedTrie<V>) -> Self {
+        Self {
+            trie,
+            key: Vec::new(),
+            stack: Vec::new(),
+        }
+    }
+}
+
+impl<'a, V: Copy> Iterator for Prefix<'a, V> {
+    type Item = (Vec<u8>, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let nodes = &self.trie.nodes;
+        while let Some((node, base)) = self.stack.pop() {
+            self.key.truncate(base);
+            self.key.extend_from_slice(nodes[node].label());
+            let first = self.stack.len();
+            let mut child = nodes[node].first_child;
+            while child != NIL {
+                self.stack.push((child, self.key.len()));
+                child = nodes[child].next_sibling;
+            }
+            // the smallest child is visited first
+            self.stack[first..].reverse();
+            if let Some(value) = &nodes[node].value {
+                return Some((self.key.clone(), value));
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::tempdir;
+
+    use super::*;
+
+    fn keys(iter: Prefix<'_, u32>) -> Vec<String> {
+        iter.map(|(key, _)| String::from_utf8(key).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn iterate_keys_by_prefix() {
+        let mut trie = PersistedTrie::<u32>::in_memory();
+        let words = [
+            "romane",
+            "romanus",
+            "romulus",
+            "rubens",
+            "ruber",
+            "rubicon",
+            "rubicundus",
+            "a/path/that/is/longer/than/a/single/label",
+            "a/path/that/is/long",
+        ];
+        for (value, word) in words.iter().enumerate() {
+            assert_eq!(trie.insert(word, value as u32), None);
+        }
+        assert_eq!(trie.len(), words.len());
+        assert_eq!(trie.insert("ruber", 40), Some(4));
+        assert_eq!(trie.get("rubicon"), Some(&5));
+        assert_eq!(trie.get("rubi"), None);
+        assert_eq!(trie.get("rubiconx"), None);
+
+        assert_eq!(
+            keys(trie.prefix("rub")),
+            ["rubens", "ruber", "rubicon", "rubicundus"]
+        );
+        // prefixes can end inside the label of a node
+        assert_eq!(keys(trie.prefix("roma")), ["romane", "romanus"]);
+        assert_eq!(keys(trie.prefix("rx")), Vec::<String>::new());
+        assert_eq!(
+            keys(trie.prefix("a/path/that/is/long")),
+            [
+                "a/path/that/is/long",
+                "a/path/that/is/longer/than/a/single/label"
+            ]
+        );
+        assert_eq!(
+            trie.longest_prefix(b"a/path/that/is/longest"),
+            Some((b"a/path/that/is/long".as_slice(), &8))
+        );
+        assert_eq!(trie.longest_prefix(b"rom"), None);
+
+        assert_eq!(trie.remove("romanus"), Some(1));
+        assert_eq!(trie.remove("romanus"), None);
+        assert_eq!(trie.remove("rub"), None);
+        assert_eq!(trie.remove("a/path/that/is/long"), Some(8));
+        assert_eq!(
+            keys(trie.iter()),
+            [
+                "a/path/that/is/longer/than/a/single/label",
+                "romane",
+                "romulus",
+                "rubens",
+                "ruber",
+                "rubicon",
+                "rubicundus"
+            ]
+        );
+        assert_eq!(trie.len(), 7);
+        trie.insert("", 100);
+        assert_eq!(trie.longest_prefix(b"rom"), Some((b"".as_slice(), &100)));
+        trie.clear();
+        assert!(trie.is_empty());
+        assert_eq!(trie.iter().count(), 0);
+    }
+
+    #[test]
+    fn survive_reopening() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("trie");
+        let nodes = {
+            let mut trie = PersistedTrie::<u32>::open(&path).unwrap();
+            for id in 0..500 {
+                trie.insert(format!("ids/{id}"), id);
+            }
+            for id in 0..250 {
+                trie.remove(format!("ids/{id}"));
+            }
+            trie.flush().unwrap();
+            trie.nodes.len()
+        };
+        let mut trie = PersistedTrie::<u32>::open(&path).unwrap();
+        assert_eq!(trie.len(), 250);
+        assert_eq!(trie.get("ids/499"), Some(&499));
+        assert_eq!(trie.get("ids/10"), None);
+        assert_eq!(trie.prefix("ids/25").count(), 10);
+
+        // the nodes freed before reopening are reused
+        for id in 0..250 {
+            trie.insert(format!("ids/{id}"), id);
+        }
+        assert_eq!(trie.len(), 500);
+        assert!(trie.nodes.len() <= nodes);
+    }
+}
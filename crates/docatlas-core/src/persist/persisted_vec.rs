@@ -1,15 +1,15 @@
 //! A persisted vector
 
 use std::cell::UnsafeCell;
-use std::collections::VecDeque;
 use std::fmt::{Debug, Formatter};
+use std::io;
 use std::marker::PhantomData;
 use std::mem::transmute;
 use std::ops::{Bound, Deref, DerefMut, Not, RangeBounds};
 use std::sync::atomic::{AtomicIsize, Ordering};
 use std::vec;
 
-use crate::persist::block::{Block, Blocks};
+use crate::persist::block::{Block, BlockRef, Blocks};
 use crate::persist::Persist;
 
 /// A persistent vector.
@@ -35,7 +35,6 @@ impl<T: Persist> Persist for RawVec<T> {
 
 impl<T: Persist> PersistentVec<T> {
     /// Creates a persistent vector in-memory
-    #[cfg(test)]
     pub fn in_memory() -> Self {
         Self::new(Blocks.new())
     }
@@ -64,6 +63,11 @@ impl<T: Persist> PersistentVec<T> {
         transmute(std::ptr::slice_from_raw_parts_mut(data_ptr, len))
     }
 
+    /// Writes any changes of this vector to the file backing it
+    pub fn flush(&self) -> io::Result<()> {
+        self.block.flush()
+    }
+
     /// Creates a new persistent vector on a given block.
     pub fn with_iter<I: IntoIterator<Item = T>>(block: Block, iter: I) -> Self {
         let mut out = Self::new(block);
@@ -73,10 +77,11 @@ impl<T: Persist> PersistentVec<T> {
 
     /// Gets the capacity of the persistent vector
     pub fn capacity(&self) -> usize {
-        unsafe {
+        let bytes = unsafe {
             self.block.size()
                 - ((self.as_data_ptr() as *const u8).offset_from(self.block.as_ptr())) as usize
-        }
+        };
+        bytes / T::size().max(1)
     }
 
     /// Reserves an additional amount of capacity to store `n` amount of `T`
@@ -139,6 +144,29 @@ impl<T: Persist> PersistentVec<T> {
         self.set_len(self.len() + 1);
     }
 
+    /// Copies a slice of values to the end of the vector, reserving the required space at most once.
+    pub fn extend_from_slice(&mut self, values: &[T])
+    where
+        T: Copy,
+    {
+        let required = self.len() + values.len();
+        if required >= self.capacity() {
+            unsafe {
+                self.block
+                    .reserve((required - self.capacity() + values.len()) * T::size());
+            }
+        }
+
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                values.as_ptr(),
+                self.as_data_ptr_mut().add(self.len()),
+                values.len(),
+            );
+        }
+        self.set_len(self.len() + values.len());
+    }
+
     /// Pops the last value added to the vector
     pub fn pop(&mut self) -> Option<T> {
         if self.len() > 0 {
@@ -215,6 +243,86 @@ impl<T: Persist> PersistentVec<T> {
         Drain::new(self, range)
     }
 
+    /// Refers to a range of the values of the vector. Unlike a slice, the reference stays valid
+    /// while the vector grows.
+    ///
+    /// # Panic
+    /// Panics if the range is out of the bounds of the vector
+    pub fn make_ref<R: RangeBounds<usize>>(&self, range: R) -> BlockRef<T>
+    where
+        T: Sized,
+    {
+        let start = match range.start_bound() {
+            Bound::Included(&i) => i,
+            Bound::Excluded(&i) => i + 1,
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(&i) => i + 1,
+            Bound::Excluded(&i) => i,
+            Bound::Unbounded => self.len(),
+        };
+        assert!(
+            start <= end && end <= self.len(),
+            "range {start}..{end} out of bounds of a vector of length {}",
+            self.len()
+        );
+        self.block
+            .make_ref(self.data_offset() + start * T::size(), end - start)
+            .expect("the values of the vector are within its block")
+    }
+
+    /// Reads the values a reference refers to, or `None` if they are no longer part of the vector
+    /// or the reference was made for another vector
+    pub fn get_ref(&self, reference: &BlockRef<T>) -> Option<&[T]>
+    where
+        T: Sized,
+    {
+        self.contains_ref(reference)
+            .then(|| self.block.get(reference).ok())
+            .flatten()
+    }
+
+    /// Gets mutable access to the values a reference refers to, or `None` if they are no longer
+    /// part of the vector or the reference was made for another vector
+    pub fn get_ref_mut(&mut self, reference: &BlockRef<T>) -> Option<&mut [T]>
+    where
+        T: Sized,
+    {
+        self.contains_ref(reference)
+            .then(|| self.block.get_mut(reference).ok())
+            .flatten()
+    }
+
+    /// Checks whether the values a reference refers to are values of the vector
+    fn contains_ref(&self, reference: &BlockRef<T>) -> bool
+    where
+        T: Sized,
+    {
+        let Some(offset) = reference.offset().checked_sub(self.data_offset()) else {
+            return false;
+        };
+        offset % T::size().max(1) == 0 && offset / T::size().max(1) + reference.len() <= self.len()
+    }
+
+    /// Gets the offset in bytes of the first value from the start of the block
+    fn data_offset(&self) -> usize {
+        unsafe { (self.as_data_ptr() as *const u8).offset_from(self.block.as_ptr()) as usize }
+    }
+
+    /// Moves the values out of the vector in chunks of up to `size` values, which are read from
+    /// the block as they are iterated over. The vector is left empty once the iterator is dropped.
+    ///
+    /// # Panic
+    /// Panics if `size` is 0
+    pub fn into_chunks(self, size: usize) -> IntoChunks<T> {
+        assert!(size > 0, "chunks must hold at least one value");
+        IntoChunks {
+            values: self.into_iter(),
+            size,
+        }
+    }
+
     /// Splits the data into slices of a given length
     pub fn split(&self, len: usize) -> Split<T> {
         Split {
@@ -268,14 +376,14 @@ impl<T: Persist> DerefMut for PersistentVec<T> {
 
 impl<T: Persist> IntoIterator for PersistentVec<T> {
     type Item = T;
-    type IntoIter = std::collections::vec_deque::IntoIter<T>;
+    type IntoIter = IntoIter<T>;
 
-    fn into_iter(mut self) -> Self::IntoIter {
-        let mut vec = VecDeque::with_capacity(self.len());
-        while let Some(back) = self.pop() {
-            vec.push_front(back);
+    fn into_iter(self) -> Self::IntoIter {
+        IntoIter {
+            front: 0,
+            back: self.len(),
+            vec: self,
         }
-        vec.into_iter()
     }
 }
 
@@ -443,6 +551,75 @@ impl<'a, T: Persist> SplitMut<'a, T> {
     }
 }
 
+/// Moves the values out of a vector, reading them from its block as they are iterated over. The
+/// vector is left empty once the iterator is dropped.
+#[derive(Debug)]
+pub struct IntoIter<T: Persist> {
+    vec: PersistentVec<T>,
+    front: usize,
+    back: usize,
+}
+
+impl<T: Persist> Iterator for IntoIter<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.front == self.back {
+            return None;
+        }
+        let value = unsafe { std::ptr::read(self.vec.as_data_ptr().add(self.front)) };
+        self.front += 1;
+        Some(value)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.back - self.front;
+        (remaining, Some(remaining))
+    }
+}
+
+impl<T: Persist> DoubleEndedIterator for IntoIter<T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.front == self.back {
+            return None;
+        }
+        self.back -= 1;
+        Some(unsafe { std::ptr::read(self.vec.as_data_ptr().add(self.back)) })
+    }
+}
+
+impl<T: Persist> ExactSizeIterator for IntoIter<T> {}
+
+impl<T: Persist> Drop for IntoIter<T> {
+    fn drop(&mut self) {
+        // the values were moved out, so they must not be read from the vector again
+        self.vec.set_len(0);
+    }
+}
+
+/// Moves the values out of a vector in chunks, see [`PersistentVec::into_chunks`]
+#[derive(Debug)]
+pub struct IntoChunks<T: Persist> {
+    values: IntoIter<T>,
+    size: usize,
+}
+
+impl<T: Persist> Iterator for IntoChunks<T> {
+    type Item = Vec<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let len = self.values.len().min(self.size);
+        (len > 0).then(|| self.values.by_ref().take(len).collect())
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let chunks = self.values.len().div_ceil(self.size);
+        (chunks, Some(chunks))
+    }
+}
+
+impl<T: Persist> ExactSizeIterator for IntoChunks<T> {}
+
 #[derive(Debug)]
 pub struct Drain<'a, T: Persist> {
     vec: &'a mut PersistentVec<T>,
@@ -517,6 +694,52 @@ mod test {
         p_vec.block.hexdump(0);
     }
 
+    #[test]
+    fn move_values_out_lazily() {
+        let temp_dir = tempdir().unwrap();
+        let file = temp_dir.path().join("temp#1");
+        let block = Blocks.builder().with_size(64).open(&file).unwrap();
+        let mut p_vec = PersistentVec::new(block);
+        p_vec.extend_from_slice(&(0..100_u32).collect::<Vec<_>>());
+
+        let mut values = p_vec.into_iter();
+        assert_eq!(values.len(), 100);
+        assert_eq!(values.next(), Some(0));
+        assert_eq!(values.next_back(), Some(99));
+        assert_eq!(values.by_ref().take(3).collect::<Vec<_>>(), [1, 2, 3]);
+        assert_eq!(values.len(), 95);
+        drop(values);
+        // the values were moved out of the file
+        let block = Blocks.builder().open(&file).unwrap();
+        assert!(PersistentVec::<u32>::new(block).is_empty());
+
+        let mut p_vec = PersistentVec::in_memory();
+        p_vec.extend_from_slice(&(0..10_u32).collect::<Vec<_>>());
+        let chunks = p_vec.into_chunks(4);
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(
+            chunks.collect::<Vec<_>>(),
+            [vec![0, 1, 2, 3], vec![4, 5, 6, 7], vec![8, 9]]
+        );
+    }
+
+    #[test]
+    fn references_survive_growth() {
+        let mut p_vec = PersistentVec::<u64>::in_memory();
+        p_vec.extend_from_slice(&[1, 2, 3, 4]);
+        let reference = p_vec.make_ref(1..3);
+        p_vec.extend_from_slice(&vec![0; 64 * 1024]);
+        assert_eq!(p_vec.get_ref(&reference), Some(&[2, 3][..]));
+        p_vec.get_ref_mut(&reference).unwrap()[0] = 20;
+        assert_eq!(&p_vec[..3], &[1, 20, 3]);
+
+        // values that were removed can no longer be referred to
+        p_vec.clear();
+        assert_eq!(p_vec.get_ref(&reference), None);
+        let other = PersistentVec::<u64>::in_memory();
+        assert_eq!(other.get_ref(&reference), None);
+    }
+
     #[test]
     fn can_push_file() {
         let temp_dir = tempdir().unwrap();
@@ -534,6 +757,36 @@ mod test {
         p_vec.block.hexdump(0);
     }
 
+    #[test]
+    fn can_extend_from_slice() {
+        let block = Blocks.builder().with_size(64).create().unwrap();
+        let mut p_vec = PersistentVec::<u32>::new(block);
+        p_vec.push(0);
+
+        let values = (1..100).collect::<Vec<u32>>();
+        p_vec.extend_from_slice(&values);
+        assert_eq!(p_vec.len(), 100);
+        assert!(p_vec.iter().copied().eq(0..100));
+    }
+
+    #[test]
+    fn capacity_counts_elements() {
+        let mut p_vec = PersistentVec::<u64>::in_memory();
+        p_vec.reserve(16);
+        assert!(p_vec.capacity() >= 16);
+        assert!(p_vec.capacity() * u64::size() <= p_vec.block.size());
+
+        // extending exactly up to the capacity must not reserve more than is missing
+        let capacity = p_vec.capacity();
+        let values = (0..capacity as u64).collect::<Vec<_>>();
+        p_vec.extend_from_slice(&values);
+        assert_eq!(p_vec.len(), capacity);
+        p_vec.extend_from_slice(&[1, 2, 3]);
+        assert_eq!(p_vec.len(), capacity + 3);
+        assert!(p_vec.capacity() >= capacity + 3);
+        assert_eq!(&p_vec.as_slice()[capacity..], &[1, 2, 3]);
+    }
+
     #[test]
     fn can_pop() {
         let block = Blocks.new();
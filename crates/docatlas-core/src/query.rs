@@ -0,0 +1,726 @@
+//! Queries select documents of an index. Every query language the daemon speaks, such as the
+//! elasticsearch query dsl and sql, is lowered into a [`Query`] before it runs.
+//!
+//! Matches and terms of keyword and text fields are looked up in the postings of the index.
+//! Other fields, and ranges over any field, are compared by reading every document. Values are
+//! given as text, and parsed according to the kind of their field, so `"1965"` matches the `i64`
+//! value `1965`. Fields that are not in the schema match no document.
+//!
+//! [Regexps](Query::Regexp) only match the terms of keyword and text fields, which are found by
+//! walking the terms of the field with the automaton of the [pattern](regexp).
+//!
+//! The rows matched by frequently used filter clauses are kept in the [filter cache](cache) of the
+//! index, so queries sharing them do not look them up again. The terms and posting lists a query
+//! looks up along the way are kept in the [arena](arena) of the query, which is released at once
+//! when the query finishes.
+//!
+//! Queries can be [explained](Query::explain), recording how every clause ran as a
+//! [plan](explain::QueryPlan).
+
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashSet};
+use std::ops::Bound;
+use std::time::Instant;
+
+use thiserror::Error;
+
+use crate::analysis;
+use crate::cancel::{CancelToken, Cancelled};
+use crate::codec::RowDecodeError;
+use crate::document::Document;
+use crate::fields::{FieldData, FieldKind, StoredValue};
+use crate::import::{parse_text, ImportError};
+use crate::index::IndexReader;
+use crate::query::arena::QueryArena;
+use crate::query::explain::{Explanation, QueryPlan};
+use crate::query::regexp::{Regexp, RegexpError};
+use crate::schema::Schema;
+
+pub mod arena;
+pub mod cache;
+pub mod explain;
+pub mod regexp;
+pub mod string;
+
+/// A query selecting documents of an index
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Query {
+    /// Every document
+    All,
+    /// Documents whose text field holds every term of the text, or any of them unless `all` is
+    /// set. Other fields must hold the text as a whole, like a [term](Query::Term).
+    Match {
+        field: String,
+        text: String,
+        all: bool,
+    },
+    /// Documents whose field holds the value
+    Term { field: String, value: String },
+    /// Documents whose field holds a value within both bounds
+    Range {
+        field: String,
+        lower: Bound<String>,
+        upper: Bound<String>,
+    },
+    /// Documents whose field holds any value
+    Exists { field: String },
+    /// Documents whose keyword field holds a value, or whose text field holds a term, matched as
+    /// a whole by a regular expression
+    Regexp { field: String, pattern: String },
+    /// Documents matching every query, or every document if there are none
+    And(Vec<Query>),
+    /// Documents matching any query, or no document if there are none
+    Or(Vec<Query>),
+    /// Documents not matching the query
+    Not(Box<Query>),
+}
+
+/// A query could not run
+#[derive(Debug, Error)]
+pub enum QueryError {
+    #[error(transparent)]
+    InvalidValue(#[from] ImportError),
+    #[error(transparent)]
+    Cancelled(#[from] Cancelled),
+    #[error(transparent)]
+    Decode(#[from] RowDecodeError),
+    #[error(transparent)]
+    InvalidPattern(#[from] RegexpError),
+}
+
+impl Query {
+    /// Gets every field the query looks at, in the order they appear
+    pub fn fields(&self) -> Vec<&str> {
+        let mut fields = vec![];
+        self.visit_fields(&mut fields);
+        fields
+    }
+
+    fn visit_fields<'a>(&'a self, fields: &mut Vec<&'a str>) {
+        match self {
+            Query::All => {}
+            Query::Match { field, .. }
+            | Query::Term { field, .. }
+            | Query::Range { field, .. }
+            | Query::Exists { field }
+            | Query::Regexp { field, .. } => {
+                if !fields.contains(&field.as_str()) {
+                    fields.push(field);
+                }
+            }
+            Query::And(queries) | Query::Or(queries) => {
+                queries.iter().for_each(|query| query.visit_fields(fields))
+            }
+            Query::Not(query) => query.visit_fields(fields),
+        }
+    }
+
+    /// Finds the rows of the documents matching the query in ascending order, stopping early
+    /// once the token is cancelled
+    pub fn rows(
+        &self,
+        index: &dyn IndexReader,
+        cancel: &CancelToken,
+    ) -> Result<Vec<usize>, QueryError> {
+        QueryArena::scoped(|arena| {
+            let mut run = Run::new(index, cancel, arena);
+            Ok(self.row_set(&mut run)?.into_iter().collect())
+        })
+    }
+
+    /// Finds the rows of the documents matching the query like [`rows`](Self::rows), recording
+    /// how every clause of the query ran
+    pub fn explain(
+        &self,
+        index: &dyn IndexReader,
+        cancel: &CancelToken,
+    ) -> Result<Explanation, QueryError> {
+        QueryArena::scoped(|arena| {
+            let mut run = Run::new(index, cancel, arena);
+            run.plan = Some(QueryPlan::default());
+            let rows = self.row_set(&mut run)?.into_iter().collect();
+            let plan = run
+                .plan
+                .and_then(|mut root| root.children.pop())
+                .expect("explained queries plan their clauses");
+            Ok(Explanation { rows, plan })
+        })
+    }
+
+    /// Checks whether a document of an index with the given schema matches the query, like it
+    /// would if it were searched for in the index
+    pub fn matches(&self, document: &Document, schema: &Schema) -> Result<bool, QueryError> {
+        let values = |field: &str| document.get(field).map_or(&[][..], |field| field.data());
+        let kind = |field: &str| schema.get(field).map(|field| &field.kind);
+        Ok(match self {
+            Query::All => true,
+            Query::Match { field, text, all } => match kind(field) {
+                Some(kind) if kind.searchable() => {
+                    let terms = values(field)
+                        .iter()
+                        .flat_map(|data| analysis::tokens(&text_of(data)).collect::<Vec<_>>())
+                        .map(|token| token.term)
+                        .collect::<HashSet<_>>();
+                    let mut wanted = analysis::tokens(text).peekable();
+                    match all {
+                        true => {
+                            wanted.peek().is_some()
+                                && wanted.all(|token| terms.contains(&token.term))
+                        }
+                        false => wanted.any(|token| terms.contains(&token.term)),
+                    }
+                }
+                Some(kind) => {
+                    let value = parse_text(field, kind, text)?;
+                    values(field).iter().any(|data| equal(data.into(), &value))
+                }
+                None => false,
+            },
+            Query::Term { field, value } => match kind(field) {
+                Some(kind) => {
+                    let value = parse_text(field, kind, value)?;
+                    values(field).iter().any(|data| equal(data.into(), &value))
+                }
+                None => false,
+            },
+            Query::Range {
+                field,
+                lower,
+                upper,
+            } => match kind(field) {
+                Some(kind) => {
+                    let lower = parse_bound(field, kind, lower)?;
+                    let upper = parse_bound(field, kind, upper)?;
+                    values(field)
+                        .iter()
+                        .any(|data| within(data.into(), &lower, &upper))
+                }
+                None => false,
+            },
+            Query::Exists { field } => !values(field).is_empty(),
+            Query::Regexp { field, pattern } => match kind(field) {
+                Some(kind) if kind.indexable() || kind.searchable() => {
+                    let regexp = Regexp::new(pattern)?;
+                    values(field).iter().any(|data| {
+                        let text = text_of(data);
+                        match kind.indexable() {
+                            true => regexp.matches(text.as_bytes()),
+                            false => analysis::tokens(&text)
+                                .any(|token| regexp.matches(token.term.as_bytes())),
+                        }
+                    })
+                }
+                _ => false,
+            },
+            Query::And(queries) => {
+                for query in queries {
+                    if !query.matches(document, schema)? {
+                        return Ok(false);
+                    }
+                }
+                true
+            }
+            Query::Or(queries) => {
+                for query in queries {
+                    if query.matches(document, schema)? {
+                        return Ok(true);
+                    }
+                }
+                false
+            }
+            Query::Not(query) => !query.matches(document, schema)?,
+        })
+    }
+
+    /// Finds the rows matching a clause, recording how it ran in a plan of its own if the query
+    /// is explained
+    fn row_set(&self, run: &mut Run) -> Result<BTreeSet<usize>, QueryError> {
+        if run.plan.is_none() {
+            return self.cached_rows(run);
+        }
+        let parent = run.plan.replace(QueryPlan::new(self));
+        let started = Instant::now();
+        let rows = self.cached_rows(run);
+        let mut plan = std::mem::replace(&mut run.plan, parent).expect("clauses are planned");
+        plan.time = started.elapsed();
+        let rows = rows?;
+        plan.matched = rows.len();
+        if let Some(parent) = &mut run.plan {
+            parent.children.push(plan);
+        }
+        Ok(rows)
+    }
+
+    fn cached_rows(&self, run: &mut Run) -> Result<BTreeSet<usize>, QueryError> {
+        let cache = run.index.filter_cache();
+        if let Some(rows) = cache.get(self) {
+            if let Some(plan) = &mut run.plan {
+                plan.cached = true;
+            }
+            return Ok(rows);
+        }
+        let rows = self.find_rows(run)?;
+        cache.insert(self, &rows);
+        Ok(rows)
+    }
+
+    fn find_rows(&self, run: &mut Run) -> Result<BTreeSet<usize>, QueryError> {
+        let index = run.index;
+        let kind = |field: &str| index.schema().get(field).map(|field| field.kind.clone());
+        Ok(match self {
+            Query::All => all_rows(run),
+            Query::Match { field, text, all } => match kind(field) {
+                Some(kind) if kind.searchable() && *all => {
+                    search(run, field, text)?.into_iter().collect()
+                }
+                Some(kind) if kind.searchable() => {
+                    let mut rows = BTreeSet::new();
+                    for token in analysis::tokens(text) {
+                        rows.extend(search(run, field, &token.term)?);
+                    }
+                    rows
+                }
+                Some(kind) => term_rows(run, field, &kind, text)?,
+                None => BTreeSet::new(),
+            },
+            Query::Term { field, value } => match kind(field) {
+                Some(kind) => term_rows(run, field, &kind, value)?,
+                None => BTreeSet::new(),
+            },
+            Query::Range {
+                field,
+                lower,
+                upper,
+            } => {
+                let Some(kind) = kind(field) else {
+                    return Ok(BTreeSet::new());
+                };
+                let lower = parse_bound(field, &kind, lower)?;
+                let upper = parse_bound(field, &kind, upper)?;
+                filter_rows(run, field, |data| within(data, &lower, &upper))?
+            }
+            Query::Exists { field } => filter_rows(run, field, |_| true)?,
+            Query::Regexp { field, pattern } => match kind(field) {
+                Some(kind) if kind.indexable() || kind.searchable() => {
+                    let regexp = Regexp::new(pattern)?;
+                    match index.search_regexp(field, &regexp, run.cancel) {
+                        (rows, true) => {
+                            run.examine(rows.len());
+                            rows.into_iter().collect()
+                        }
+                        (_, false) => return Err(Cancelled.into()),
+                    }
+                }
+                _ => BTreeSet::new(),
+            },
+            Query::And(queries) => {
+                let mut rows = None::<BTreeSet<usize>>;
+                for query in queries {
+                    let matched = query.row_set(run)?;
+                    rows = Some(match rows {
+                        Some(rows) => rows.intersection(&matched).copied().collect(),
+                        None => matched,
+                    });
+                }
+                match rows {
+                    Some(rows) => rows,
+                    None => all_rows(run),
+                }
+            }
+            Query::Or(queries) => {
+                let mut rows = BTreeSet::new();
+                for query in queries {
+                    rows.extend(query.row_set(run)?);
+                }
+                rows
+            }
+            Query::Not(query) => {
+                let matched = query.row_set(run)?;
+                let mut rows = all_rows(run);
+                rows.retain(|row| !matched.contains(row));
+                rows
+            }
+        })
+    }
+}
+
+/// The state of a query while it runs
+struct Run<'a> {
+    index: &'a dyn IndexReader,
+    cancel: &'a CancelToken,
+    arena: &'a QueryArena,
+    /// The plan of the clause running, if the query is explained
+    plan: Option<QueryPlan>,
+}
+
+impl<'a> Run<'a> {
+    fn new(index: &'a dyn IndexReader, cancel: &'a CancelToken, arena: &'a QueryArena) -> Self {
+        Self {
+            index,
+            cancel,
+            arena,
+            plan: None,
+        }
+    }
+
+    /// Records that the clause running looked at some documents
+    fn examine(&mut self, documents: usize) {
+        if let Some(plan) = &mut self.plan {
+            plan.examined += documents;
+        }
+    }
+}
+
+/// Gets the rows of every document of an index
+fn all_rows(run: &mut Run) -> BTreeSet<usize> {
+    let rows = run.index.scan(None, 0, usize::MAX).0;
+    run.examine(rows.len());
+    rows.into_iter().collect()
+}
+
+/// Searches the postings of a field, keeping the terms and rows looked up in the arena
+fn search(run: &mut Run, field: &str, query: &str) -> Result<Vec<usize>, Cancelled> {
+    match run.index.search_in(field, query, run.cancel, run.arena) {
+        (rows, true) => {
+            run.examine(rows.len());
+            Ok(rows)
+        }
+        (_, false) => Err(Cancelled),
+    }
+}
+
+/// Finds the rows whose field holds a value given as text. Keyword fields are looked up in their
+/// postings, and text fields are looked up by their terms before their values are compared.
+fn term_rows(
+    run: &mut Run,
+    field: &str,
+    kind: &FieldKind,
+    text: &str,
+) -> Result<BTreeSet<usize>, QueryError> {
+    if kind.indexable() {
+        return Ok(search(run, field, text)?.into_iter().collect());
+    }
+    let value = parse_text(field, kind, text)?;
+    let equal = |data: StoredValue| equal(data, &value);
+    if !kind.searchable() {
+        return filter_rows(run, field, equal);
+    }
+    let mut rows = BTreeSet::new();
+    for row in search(run, field, text)? {
+        if matches_row(run.index, row, field, equal)? {
+            rows.insert(row);
+        }
+    }
+    Ok(rows)
+}
+
+/// Finds the rows with a value of a field that matches a predicate, by reading every document
+fn filter_rows(
+    run: &mut Run,
+    field: &str,
+    matches: impl Fn(StoredValue) -> bool,
+) -> Result<BTreeSet<usize>, QueryError> {
+    let mut rows = BTreeSet::new();
+    for row in all_rows(run) {
+        if run.cancel.is_cancelled() {
+            return Err(Cancelled.into());
+        }
+        if matches_row(run.index, row, field, &matches)? {
+            rows.insert(row);
+        }
+    }
+    Ok(rows)
+}
+
+/// Checks whether the value of the field of a row matches a predicate, reading the value without
+/// copying it
+fn matches_row(
+    index: &dyn IndexReader,
+    row: usize,
+    field: &str,
+    matches: impl Fn(StoredValue) -> bool,
+) -> Result<bool, QueryError> {
+    let document = index.stored(row).expect("matched rows exist");
+    Ok(document.get(field)?.is_some_and(matches))
+}
+
+/// Parses a bound given as text according to the kind of its field
+fn parse_bound(
+    field: &str,
+    kind: &FieldKind,
+    bound: &Bound<String>,
+) -> Result<Bound<FieldData>, QueryError> {
+    Ok(match bound {
+        Bound::Included(text) => Bound::Included(parse_text(field, kind, text)?),
+        Bound::Excluded(text) => Bound::Excluded(parse_text(field, kind, text)?),
+        Bound::Unbounded => Bound::Unbounded,
+    })
+}
+
+/// Checks whether a value is within both bounds
+fn within(data: StoredValue, lower: &Bound<FieldData>, upper: &Bound<FieldData>) -> bool {
+    let above = match lower {
+        Bound::Included(value) => data.compare(value).is_some_and(Ordering::is_ge),
+        Bound::Excluded(value) => data.compare(value).is_some_and(Ordering::is_gt),
+        Bound::Unbounded => true,
+    };
+    let below = match upper {
+        Bound::Included(value) => data.compare(value).is_some_and(Ordering::is_le),
+        Bound::Excluded(value) => data.compare(value).is_some_and(Ordering::is_lt),
+        Bound::Unbounded => true,
+    };
+    above && below
+}
+
+/// Checks whether a value equals a value parsed from text
+fn equal(data: StoredValue, value: &FieldData) -> bool {
+    data.compare(value) == Some(Ordering::Equal)
+}
+
+/// Gets the text of a value, or nothing if it is not text
+fn text_of(data: &FieldData) -> Cow<'_, str> {
+    match data {
+        FieldData::Bytes(bytes) => String::from_utf8_lossy(bytes),
+        FieldData::Analyzed(text) => Cow::Borrowed(text.text()),
+        _ => Cow::Borrowed(""),
+    }
+}
+
+/// Gets a value that compares with the values parsed from text. Analyzed text compares as its
+/// bytes.
+pub fn comparable(data: &FieldData) -> Cow<'_, FieldData> {
+    match data {
+        FieldData::Analyzed(text) => Cow::Owned(FieldData::Bytes(text.text().as_bytes().into())),
+        data => Cow::Borrowed(data),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fields::{Field, Fields};
+    use crate::index::{IndexWriter, UpsertMode};
+    use crate::persist::PersistentVec;
+    use crate::schema::SchemaField;
+
+    fn books() -> IndexWriter {
+        let schema = Schema::from_iter(
+            [
+                ("id", FieldKind::Keyword(16)),
+                ("title", FieldKind::Text(32)),
+                ("year", FieldKind::I64),
+            ]
+            .map(|(name, kind)| SchemaField {
+                name: name.to_string(),
+                kind,
+            }),
+        )
+        .with_primary_key("id");
+        let mut index = IndexWriter::new(schema, PersistentVec::in_memory());
+        for (id, title, year) in [
+            ("dune", "Dune", 1965),
+            ("messiah", "Dune Messiah", 1969),
+            ("emma", "Emma", 1815),
+        ] {
+            let mut fields = Fields::new();
+            let text = |text: &str| FieldData::Bytes(text.as_bytes().into());
+            fields.insert("id", Field::new(FieldKind::Keyword(16), [text(id)]));
+            fields.insert("title", Field::new(FieldKind::Text(32), [text(title)]));
+            fields.insert("year", Field::new(FieldKind::I64, [FieldData::I64(year)]));
+            index
+                .upsert(Document::from(fields), UpsertMode::Replace)
+                .unwrap();
+        }
+        index
+    }
+
+    fn rows(index: &dyn IndexReader, query: Query) -> Vec<usize> {
+        query.rows(index, &CancelToken::new()).unwrap()
+    }
+
+    #[test]
+    fn run_queries() {
+        let index = books();
+        let term = |field: &str, value: &str| Query::Term {
+            field: field.to_string(),
+            value: value.to_string(),
+        };
+        let matching = |text: &str| Query::Match {
+            field: "title".to_string(),
+            text: text.to_string(),
+            all: true,
+        };
+        assert_eq!(rows(&index, Query::All), [0, 1, 2]);
+        assert_eq!(rows(&index, matching("dune")), [0, 1]);
+        let any = Query::Match {
+            field: "title".to_string(),
+            text: "messiah emma".to_string(),
+            all: false,
+        };
+        assert_eq!(rows(&index, any), [1, 2]);
+        // terms of text fields match the whole value
+        assert_eq!(rows(&index, term("title", "Dune")), [0]);
+        assert_eq!(rows(&index, term("year", "1815")), [2]);
+        assert_eq!(rows(&index, term("id", "emma")), [2]);
+        assert_eq!(rows(&index, term("pages", "300")), [] as [usize; 0]);
+        let range = Query::Range {
+            field: "year".to_string(),
+            lower: Bound::Excluded("1815".to_string()),
+            upper: Bound::Included("1969".to_string()),
+        };
+        assert_eq!(rows(&index, range.clone()), [0, 1]);
+        let query = Query::And(vec![range, Query::Not(Box::new(matching("messiah")))]);
+        assert_eq!(rows(&index, query.clone()), [0]);
+        assert_eq!(query.fields(), ["year", "title"]);
+        assert_eq!(rows(&index, Query::Or(vec![])), [] as [usize; 0]);
+        assert!(matches!(
+            term("year", "long ago").rows(&index, &CancelToken::new()),
+            Err(QueryError::InvalidValue(_))
+        ));
+
+        let regexp = |field: &str, pattern: &str| Query::Regexp {
+            field: field.to_string(),
+            pattern: pattern.to_string(),
+        };
+        // text fields are matched term by term, keywords as a whole
+        assert_eq!(rows(&index, regexp("title", "(dun|emm)[a-z]")), [0, 1, 2]);
+        assert_eq!(
+            rows(&index, regexp("title", "dune messiah")),
+            [] as [usize; 0]
+        );
+        assert_eq!(rows(&index, regexp("id", "d.*|m.*h")), [0, 1]);
+        assert_eq!(rows(&index, regexp("year", "19.*")), [] as [usize; 0]);
+        assert!(matches!(
+            regexp("id", "(dune").rows(&index, &CancelToken::new()),
+            Err(QueryError::InvalidPattern(_))
+        ));
+    }
+
+    #[test]
+    fn match_documents_like_the_index() {
+        let index = books();
+        let queries = [
+            Query::All,
+            Query::Match {
+                field: "title".to_string(),
+                text: "MESSIAH emma".to_string(),
+                all: false,
+            },
+            Query::Match {
+                field: "title".to_string(),
+                text: "dune MESSIAH".to_string(),
+                all: true,
+            },
+            Query::Term {
+                field: "title".to_string(),
+                value: "Dune".to_string(),
+            },
+            Query::Range {
+                field: "year".to_string(),
+                lower: Bound::Unbounded,
+                upper: Bound::Excluded("1969".to_string()),
+            },
+            Query::Not(Box::new(Query::Exists {
+                field: "id".to_string(),
+            })),
+            Query::Regexp {
+                field: "title".to_string(),
+                pattern: "mes+iah|em+a".to_string(),
+            },
+            Query::Regexp {
+                field: "id".to_string(),
+                pattern: "[de].*".to_string(),
+            },
+        ];
+        for query in queries {
+            let matched = (0..index.len())
+                .filter(|&row| {
+                    let document = index.read(row).unwrap().unwrap();
+                    query.matches(&document, index.schema()).unwrap()
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(matched, rows(&index, query.clone()), "{query:?}");
+        }
+    }
+
+    #[test]
+    fn reuse_cached_filters() {
+        let mut index = books();
+        let recent = Query::Range {
+            field: "year".to_string(),
+            lower: Bound::Included("1900".to_string()),
+            upper: Bound::Unbounded,
+        };
+        let query = |title: &str| {
+            Query::And(vec![
+                recent.clone(),
+                Query::Match {
+                    field: "title".to_string(),
+                    text: title.to_string(),
+                    all: true,
+                },
+            ])
+        };
+        assert_eq!(rows(&index, query("dune")), [0, 1]);
+        assert_eq!(rows(&index, query("messiah")), [1]);
+        assert_eq!(index.filter_cache().stats().entries, 1);
+        assert_eq!(rows(&index, query("dune")), [0, 1]);
+        assert_eq!(index.filter_cache().stats().hits, 1);
+
+        // writes invalidate the rows of every clause
+        let mut fields = Fields::new();
+        let text = |text: &str| FieldData::Bytes(text.as_bytes().into());
+        fields.insert("id", Field::new(FieldKind::Keyword(16), [text("children")]));
+        fields.insert(
+            "title",
+            Field::new(FieldKind::Text(32), [text("Children of Dune")]),
+        );
+        fields.insert("year", Field::new(FieldKind::I64, [FieldData::I64(1976)]));
+        index
+            .upsert(Document::from(fields), UpsertMode::Replace)
+            .unwrap();
+        assert_eq!(index.filter_cache().stats().entries, 0);
+        assert_eq!(rows(&index, query("dune")), [0, 1, 3]);
+        assert_eq!(rows(&index, recent.clone()), [0, 1, 3]);
+        assert_eq!(index.filter_cache().stats().hits, 2);
+    }
+
+    #[test]
+    fn explain_queries() {
+        let index = books();
+        let query = Query::And(vec![
+            Query::Match {
+                field: "title".to_string(),
+                text: "dune".to_string(),
+                all: true,
+            },
+            Query::Not(Box::new(Query::Term {
+                field: "year".to_string(),
+                value: "1969".to_string(),
+            })),
+        ]);
+        let explain = || query.explain(&index, &CancelToken::new()).unwrap();
+        let explanation = explain();
+        assert_eq!(explanation.rows, rows(&index, query.clone()));
+        let plan = explanation.plan;
+        assert_eq!(plan.clause, "and of 2");
+        assert_eq!((plan.examined, plan.matched), (0, 1));
+        let [matched, not] = &plan.children[..] else {
+            panic!("expected a plan for both clauses, got {plan:?}");
+        };
+        assert_eq!(matched.clause, "match title all of \"dune\"");
+        assert_eq!((matched.examined, matched.matched), (2, 2));
+        assert!(matched.time <= plan.time);
+        // the years are compared by reading every document
+        assert_eq!((not.examined, not.matched), (3, 2));
+        assert_eq!(not.children[0].clause, "term year = \"1969\"");
+        assert_eq!((not.children[0].examined, not.children[0].matched), (3, 1));
+
+        // the clause was cached once it was used again, and no longer examines any document
+        assert!(!not.children[0].cached);
+        let year = explain().plan.children[1].children.remove(0);
+        assert!(year.cached);
+        assert_eq!((year.examined, year.matched), (0, 1));
+    }
+}
@@ -0,0 +1,117 @@
+//! Arenas hold the temporary buffers of a query, such as the terms it looks up and the rows of
+//! the posting lists it intersects, which are released all at once when the query finishes.
+//!
+//! Buffers are bumped out of chunks the arena already holds, so a query allocates a handful of
+//! chunks instead of a buffer for every term and posting list. Every thread keeps an arena for
+//! the queries it [runs](QueryArena::scoped), whose chunks are reused by the next query, so busy
+//! threads barely allocate at all.
+
+use std::cell::RefCell;
+
+use bumpalo::Bump;
+
+/// A vector whose items are stored in a [`QueryArena`]
+pub type ArenaVec<'a, T> = bumpalo::collections::Vec<'a, T>;
+
+/// The most bytes the arena of a thread keeps between queries. Arenas that grew larger while
+/// running a query are dropped once it finishes, rather than holding on to their memory.
+pub const MAX_RETAINED_BYTES: usize = 4 * 1024 * 1024;
+
+thread_local! {
+    static ARENA: RefCell<QueryArena> = RefCell::new(QueryArena::new());
+}
+
+/// Allocates the temporary buffers of a query
+#[derive(Debug, Default)]
+pub struct QueryArena {
+    bump: Bump,
+}
+
+impl QueryArena {
+    /// Creates an empty arena
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates an arena that holds `bytes` bytes before allocating any more
+    pub fn with_capacity(bytes: usize) -> Self {
+        Self {
+            bump: Bump::with_capacity(bytes),
+        }
+    }
+
+    /// Runs a query with the arena of the current thread, releasing every buffer it allocated
+    /// once it returns. Queries running within another query get an arena of their own.
+    pub fn scoped<R>(query: impl FnOnce(&QueryArena) -> R) -> R {
+        ARENA.with(|arena| match arena.try_borrow_mut() {
+            Ok(mut arena) => {
+                let result = query(&arena);
+                arena.release();
+                result
+            }
+            Err(_) => query(&QueryArena::new()),
+        })
+    }
+
+    /// Copies bytes into the arena
+    pub fn alloc_bytes(&self, bytes: &[u8]) -> &[u8] {
+        self.bump.alloc_slice_copy(bytes)
+    }
+
+    /// Creates an empty vector stored in the arena
+    pub fn vec<T>(&self) -> ArenaVec<'_, T> {
+        ArenaVec::new_in(&self.bump)
+    }
+
+    /// Creates a vector stored in the arena, with room for `capacity` items
+    pub fn vec_with_capacity<T>(&self, capacity: usize) -> ArenaVec<'_, T> {
+        ArenaVec::with_capacity_in(capacity, &self.bump)
+    }
+
+    /// Gets the number of bytes the chunks of the arena hold, whether they are used or not
+    pub fn allocated_bytes(&self) -> usize {
+        self.bump.allocated_bytes()
+    }
+
+    /// Releases every buffer, keeping the chunks of the arena for the next query unless they
+    /// take up more than [`MAX_RETAINED_BYTES`]
+    pub fn release(&mut self) {
+        if self.allocated_bytes() > MAX_RETAINED_BYTES {
+            *self = Self::new();
+        } else {
+            self.bump.reset();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuse_the_arena_of_the_thread() {
+        QueryArena::scoped(|arena| {
+            let mut rows = arena.vec();
+            rows.extend(0..1000_usize);
+            assert_eq!(arena.alloc_bytes(b"dune"), b"dune");
+            // nested queries do not share the buffers of the query running them
+            let nested = QueryArena::scoped(|nested| {
+                nested.vec_with_capacity::<usize>(10);
+                nested.allocated_bytes()
+            });
+            assert!(nested < arena.allocated_bytes());
+        });
+        // the next query bumps its buffers out of the chunks the last one left behind
+        QueryArena::scoped(|arena| {
+            let retained = arena.allocated_bytes();
+            assert!(retained > 0);
+            arena.vec_with_capacity::<usize>(100);
+            assert_eq!(arena.allocated_bytes(), retained);
+        });
+
+        let mut arena = QueryArena::new();
+        arena.vec_with_capacity::<u8>(MAX_RETAINED_BYTES + 1);
+        arena.release();
+        assert_eq!(arena.allocated_bytes(), QueryArena::new().allocated_bytes());
+    }
+}
@@ -0,0 +1,273 @@
+//! Caches the rows matched by the filter clauses of queries, so clauses shared by many queries,
+//! such as `tenant = X` or `status = published`, are only looked up once.
+//!
+//! Only [terms](Query::Term), [ranges](Query::Range) and [exists](Query::Exists) clauses are
+//! cached, since they match documents without scoring them. A clause is cached once it has been
+//! looked up [`DEFAULT_MIN_USES`] times, so clauses only used once do not push out the clauses
+//! that are reused. Rows are kept as [packed postings](PackedPostings), and the least recently
+//! used clauses are dropped once the cache outgrows its size.
+//!
+//! The rows of a clause are only valid for the rows of the index they were found in, so the
+//! writer of the index [invalidates](FilterCache::invalidate) its cache whenever it writes. The
+//! rows a [searcher](crate::index::Searcher) reads never change, so every commit point has a cache
+//! of its own instead.
+
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+
+use crate::index::PackedPostings;
+use crate::query::Query;
+
+/// The default number of bytes the rows of cached clauses take up
+pub const DEFAULT_FILTER_CACHE_SIZE: usize = 32 * 1024 * 1024;
+
+/// The default number of times a clause is looked up before its rows are cached
+pub const DEFAULT_MIN_USES: usize = 2;
+
+/// The most clauses whose uses are counted, after which the counts start over
+const MAX_COUNTED: usize = 1024;
+
+/// The rows of the filter clauses most recently used in queries against an index
+#[derive(Debug)]
+pub struct FilterCache {
+    max_bytes: usize,
+    min_uses: usize,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Counts the times every clause was looked up
+    uses: HashMap<Query, usize>,
+    entries: HashMap<Query, Entry>,
+    bytes: usize,
+    /// Increases with every lookup, ordering the entries by when they were last used
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+#[derive(Debug)]
+struct Entry {
+    rows: PackedPostings,
+    last_used: u64,
+}
+
+/// How well a filter cache is doing
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FilterCacheStats {
+    /// The number of clauses cached
+    pub entries: usize,
+    /// The number of bytes the cached rows take up
+    pub bytes: usize,
+    /// The number of lookups answered from the cache
+    pub hits: u64,
+    /// The number of lookups of cacheable clauses that were not
+    pub misses: u64,
+}
+
+impl FilterCache {
+    /// Creates a cache keeping up to `max_bytes` of rows. A cache of 0 bytes caches nothing.
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            min_uses: DEFAULT_MIN_USES,
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Sets the number of times a clause is looked up before its rows are cached
+    pub fn with_min_uses(mut self, min_uses: usize) -> Self {
+        self.min_uses = min_uses.max(1);
+        self
+    }
+
+    /// Gets the number of bytes of rows the cache keeps
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+
+    /// Gets the number of times a clause is looked up before its rows are cached
+    pub fn min_uses(&self) -> usize {
+        self.min_uses
+    }
+
+    /// Checks if the rows of a clause can be cached
+    pub fn cacheable(clause: &Query) -> bool {
+        matches!(
+            clause,
+            Query::Term { .. } | Query::Range { .. } | Query::Exists { .. }
+        )
+    }
+
+    /// Gets the cached rows of a clause, counting the lookup towards caching it
+    pub fn get(&self, clause: &Query) -> Option<BTreeSet<usize>> {
+        if self.max_bytes == 0 || !Self::cacheable(clause) {
+            return None;
+        }
+        let mut state = self.state.lock().expect("filter cache poisoned");
+        state.clock += 1;
+        let clock = state.clock;
+        match state.entries.get_mut(clause) {
+            Some(entry) => {
+                entry.last_used = clock;
+                let rows = entry.rows.rows().into_iter().collect();
+                state.hits += 1;
+                Some(rows)
+            }
+            None => {
+                state.misses += 1;
+                if state.uses.len() >= MAX_COUNTED && !state.uses.contains_key(clause) {
+                    state.uses.clear();
+                }
+                *state.uses.entry(clause.clone()).or_default() += 1;
+                None
+            }
+        }
+    }
+
+    /// Caches the rows a clause matched if it has been looked up often enough, dropping the least
+    /// recently used clauses until the cache fits its size again
+    pub fn insert(&self, clause: &Query, rows: &BTreeSet<usize>) {
+        if self.max_bytes == 0 || !Self::cacheable(clause) {
+            return;
+        }
+        let mut state = self.state.lock().expect("filter cache poisoned");
+        if state.uses.get(clause).copied().unwrap_or(0) < self.min_uses
+            || state.entries.contains_key(clause)
+        {
+            return;
+        }
+        let rows = PackedPostings::new(&rows.iter().copied().collect::<Vec<_>>());
+        let size = rows.size_in_bytes();
+        if size > self.max_bytes {
+            return;
+        }
+        while state.bytes + size > self.max_bytes {
+            let Some(oldest) = state
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(clause, _)| clause.clone())
+            else {
+                break;
+            };
+            if let Some(evicted) = state.entries.remove(&oldest) {
+                state.bytes -= evicted.rows.size_in_bytes();
+            }
+        }
+        state.uses.remove(clause);
+        state.bytes += size;
+        let last_used = state.clock;
+        state
+            .entries
+            .insert(clause.clone(), Entry { rows, last_used });
+    }
+
+    /// Drops the rows of every clause, which no longer match the rows of the index. How often
+    /// clauses were used is kept, so frequently used clauses are cached again on their next use.
+    pub fn invalidate(&self) {
+        let mut state = self.state.lock().expect("filter cache poisoned");
+        if !state.entries.is_empty() {
+            let cached = std::mem::take(&mut state.entries);
+            let min_uses = self.min_uses;
+            state
+                .uses
+                .extend(cached.into_keys().map(|clause| (clause, min_uses)));
+            state.bytes = 0;
+        }
+    }
+
+    /// Gets how well the cache is doing
+    pub fn stats(&self) -> FilterCacheStats {
+        let state = self.state.lock().expect("filter cache poisoned");
+        FilterCacheStats {
+            entries: state.entries.len(),
+            bytes: state.bytes,
+            hits: state.hits,
+            misses: state.misses,
+        }
+    }
+}
+
+impl Default for FilterCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_FILTER_CACHE_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn term(value: &str) -> Query {
+        Query::Term {
+            field: "status".to_string(),
+            value: value.to_string(),
+        }
+    }
+
+    #[test]
+    fn cache_reused_clauses() {
+        let cache = FilterCache::new(1024);
+        let rows = BTreeSet::from([1, 4, 9]);
+        // clauses are only cached once they are used again
+        assert!(cache.get(&term("published")).is_none());
+        cache.insert(&term("published"), &rows);
+        assert_eq!(cache.stats().entries, 0);
+        assert!(cache.get(&term("published")).is_none());
+        cache.insert(&term("published"), &rows);
+        assert_eq!(cache.get(&term("published")), Some(rows.clone()));
+        assert_eq!(
+            cache.stats(),
+            FilterCacheStats {
+                entries: 1,
+                bytes: PackedPostings::new(&[1, 4, 9]).size_in_bytes(),
+                hits: 1,
+                misses: 2,
+            }
+        );
+        // scoring clauses are never cached
+        let matching = Query::Match {
+            field: "title".to_string(),
+            text: "dune".to_string(),
+            all: true,
+        };
+        for _ in 0..3 {
+            assert!(cache.get(&matching).is_none());
+            cache.insert(&matching, &rows);
+        }
+        assert_eq!(cache.stats().entries, 1);
+
+        // invalidated clauses are cached again on their next use
+        cache.invalidate();
+        assert_eq!(cache.stats().entries, 0);
+        assert!(cache.get(&term("published")).is_none());
+        cache.insert(&term("published"), &BTreeSet::from([2]));
+        assert_eq!(cache.get(&term("published")), Some(BTreeSet::from([2])));
+    }
+
+    #[test]
+    fn evict_least_recently_used() {
+        let size = PackedPostings::new(&[1, 2]).size_in_bytes();
+        let cache = FilterCache::new(size * 2).with_min_uses(1);
+        let rows = BTreeSet::from([1, 2]);
+        for value in ["a", "b"] {
+            cache.get(&term(value));
+            cache.insert(&term(value), &rows);
+        }
+        assert!(cache.get(&term("a")).is_some());
+        cache.get(&term("c"));
+        cache.insert(&term("c"), &rows);
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.bytes), (2, size * 2));
+        assert!(cache.get(&term("a")).is_some());
+        assert!(cache.get(&term("b")).is_none());
+        assert!(cache.get(&term("c")).is_some());
+
+        let disabled = FilterCache::new(0).with_min_uses(1);
+        disabled.get(&term("a"));
+        disabled.insert(&term("a"), &rows);
+        assert_eq!(disabled.stats(), FilterCacheStats::default());
+    }
+}
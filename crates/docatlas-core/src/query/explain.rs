@@ -0,0 +1,86 @@
+//! Explaining a query runs it while recording how every clause ran, so slow queries can be traced
+//! to the clauses that made them slow.
+//!
+//! Every clause of an explained query is a node of its [plan](QueryPlan), whose children are the
+//! clauses it combines. Nodes record the time their clause took, including the time of their
+//! children, the documents they examined themselves and whether their rows came from the
+//! [filter cache](super::cache).
+
+use std::fmt::{self, Display, Formatter};
+use std::ops::Bound;
+use std::time::Duration;
+
+use super::Query;
+
+/// The rows matched by an explained query, along with how it ran
+#[derive(Debug, Clone)]
+pub struct Explanation {
+    /// The rows of the documents matching the query, in ascending order
+    pub rows: Vec<usize>,
+    /// How the clauses of the query ran
+    pub plan: QueryPlan,
+}
+
+/// How a clause of a query ran
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QueryPlan {
+    /// Describes the clause, such as `term status = "published"`
+    pub clause: String,
+    /// The time the clause took, including the time of its children
+    pub time: Duration,
+    /// The documents the clause looked at, either in the postings of the index or by reading them
+    pub examined: usize,
+    /// The documents matching the clause
+    pub matched: usize,
+    /// Whether the rows of the clause were found in the filter cache
+    pub cached: bool,
+    /// The clauses the clause combines, in the order they ran
+    pub children: Vec<QueryPlan>,
+}
+
+impl QueryPlan {
+    /// Creates the plan of a clause before it runs
+    pub fn new(clause: &Query) -> Self {
+        Self {
+            clause: clause.to_string(),
+            ..Self::default()
+        }
+    }
+}
+
+impl Display for Query {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Query::All => write!(f, "all"),
+            Query::Match {
+                field,
+                text,
+                all: true,
+            } => write!(f, "match {field} all of {text:?}"),
+            Query::Match { field, text, .. } => write!(f, "match {field} any of {text:?}"),
+            Query::Term { field, value } => write!(f, "term {field} = {value:?}"),
+            Query::Range {
+                field,
+                lower,
+                upper,
+            } => {
+                write!(f, "range {field} ")?;
+                match lower {
+                    Bound::Included(value) => write!(f, "[{value:?}")?,
+                    Bound::Excluded(value) => write!(f, "({value:?}")?,
+                    Bound::Unbounded => write!(f, "(*")?,
+                }
+                match upper {
+                    Bound::Included(value) => write!(f, ", {value:?}]"),
+                    Bound::Excluded(value) => write!(f, ", {value:?})"),
+                    Bound::Unbounded => write!(f, ", *)"),
+                }
+            }
+            Query::Exists { field } => write!(f, "exists {field}"),
+            Query::Regexp { field, pattern } => write!(f, "regexp {field} ~ /{pattern}/"),
+            Query::And(queries) => write!(f, "and of {}", queries.len()),
+            Query::Or(queries) => write!(f, "or of {}", queries.len()),
+            Query::Not(_) => write!(f, "not"),
+        }
+    }
+}
@@ -0,0 +1,189 @@
+//! Regular expressions matching the terms of keyword and text fields.
+//!
+//! A pattern uses the syntax of the [regex](https://docs.rs/regex) crate, and must match a term as
+//! a whole, as if it were written between `^` and `$`. Keywords are matched as they were written,
+//! while text is matched word by word against its lowercase terms.
+//!
+//! Patterns are compiled into a deterministic automaton, which is walked along the terms of a
+//! field in the order they are kept in the [postings](crate::index::Postings::matching_terms).
+//! Terms sharing a prefix only feed that prefix through the automaton once, and once a prefix can
+//! not lead to a match, every term starting with it is skipped without being looked at.
+//!
+//! Short patterns can describe huge automata, such as `(a|b)*a(a|b){20}`, so patterns are limited
+//! to [`MAX_PATTERN_LENGTH`] bytes, [`MAX_NESTING`] levels of nesting and automata of
+//! [`MAX_AUTOMATON_BYTES`], and are rejected as [too complex](RegexpError::TooComplex) before they
+//! can take up the time or memory of the daemon.
+
+use regex_automata::dfa::{dense, Automaton, StartKind};
+use regex_automata::nfa::thompson::{self, WhichCaptures};
+use regex_automata::util::primitives::StateID;
+use regex_automata::util::start;
+use regex_automata::util::syntax;
+use regex_automata::{Anchored, MatchKind};
+use thiserror::Error;
+
+/// The longest pattern, in bytes
+pub const MAX_PATTERN_LENGTH: usize = 1000;
+
+/// The deepest groups, repetitions and classes can be nested within a pattern
+pub const MAX_NESTING: u32 = 32;
+
+/// The most memory a pattern may take to compile into an automaton, and that its automaton may
+/// take once compiled
+pub const MAX_AUTOMATON_BYTES: usize = 2 << 20;
+
+/// A pattern could not be compiled
+#[derive(Debug, Error)]
+pub enum RegexpError {
+    #[error("pattern is {0} bytes long, longer than the limit of {MAX_PATTERN_LENGTH}")]
+    TooLong(usize),
+    #[error("pattern {pattern:?} is too complex: {reason}")]
+    TooComplex { pattern: String, reason: String },
+    #[error("invalid pattern {pattern:?}: {reason}")]
+    Invalid { pattern: String, reason: String },
+}
+
+/// A compiled pattern, matching whole terms
+#[derive(Debug, Clone)]
+pub struct Regexp {
+    pattern: String,
+    dfa: dense::DFA<Vec<u32>>,
+    start: StateID,
+}
+
+impl Regexp {
+    /// Compiles a pattern, unless it is invalid or over the limits of its complexity
+    pub fn new(pattern: &str) -> Result<Self, RegexpError> {
+        if pattern.len() > MAX_PATTERN_LENGTH {
+            return Err(RegexpError::TooLong(pattern.len()));
+        }
+        let invalid = |reason: String| RegexpError::Invalid {
+            pattern: pattern.to_string(),
+            reason,
+        };
+        let too_complex = |reason: String| RegexpError::TooComplex {
+            pattern: pattern.to_string(),
+            reason,
+        };
+        let syntax = syntax::Config::new().nest_limit(MAX_NESTING);
+        // checked on its own first, so a pattern can not close the group it is anchored in
+        syntax::parse_with(pattern, &syntax).map_err(|e| invalid(e.to_string()))?;
+        let nfa = thompson::Compiler::new()
+            .syntax(syntax)
+            .configure(
+                thompson::Config::new()
+                    .which_captures(WhichCaptures::None)
+                    .nfa_size_limit(Some(MAX_AUTOMATON_BYTES)),
+            )
+            .build(&format!(r"(?:{pattern})\z"))
+            .map_err(|e| match e.size_limit() {
+                Some(_) => too_complex(e.to_string()),
+                None => invalid(e.to_string()),
+            })?;
+        let dfa = dense::Builder::new()
+            .configure(
+                dense::Config::new()
+                    .start_kind(StartKind::Anchored)
+                    // every match ends at the end of the term, so none are preferred
+                    .match_kind(MatchKind::All)
+                    .dfa_size_limit(Some(MAX_AUTOMATON_BYTES))
+                    .determinize_size_limit(Some(MAX_AUTOMATON_BYTES)),
+            )
+            .build_from_nfa(&nfa)
+            .map_err(|e| match e.is_size_limit_exceeded() {
+                true => too_complex(e.to_string()),
+                false => invalid(e.to_string()),
+            })?;
+        let start = dfa
+            .start_state(&start::Config::new().anchored(Anchored::Yes))
+            .map_err(|e| invalid(e.to_string()))?;
+        Ok(Self {
+            pattern: pattern.to_string(),
+            dfa,
+            start,
+        })
+    }
+
+    /// Gets the pattern the regexp was compiled from
+    pub fn pattern(&self) -> &str {
+        &self.pattern
+    }
+
+    /// Gets the memory taken by the automaton of the regexp, in bytes
+    pub fn memory_usage(&self) -> usize {
+        self.dfa.memory_usage()
+    }
+
+    /// Checks whether the pattern matches a term as a whole
+    pub fn matches(&self, term: &[u8]) -> bool {
+        let mut state = self.start();
+        for &byte in term {
+            match self.next(state, byte) {
+                Some(next) => state = next,
+                None => return false,
+            }
+        }
+        self.accepts(state)
+    }
+
+    /// Gets the state of the automaton before any byte of a term was fed through it
+    pub(crate) fn start(&self) -> StateID {
+        self.start
+    }
+
+    /// Feeds the next byte of a term through the automaton, or gets nothing once no term
+    /// continuing the bytes fed so far can match
+    pub(crate) fn next(&self, state: StateID, byte: u8) -> Option<StateID> {
+        let next = self.dfa.next_state(state, byte);
+        (!self.dfa.is_dead_state(next)).then_some(next)
+    }
+
+    /// Checks whether a term ending at a state is matched
+    pub(crate) fn accepts(&self, state: StateID) -> bool {
+        self.dfa.is_match_state(self.dfa.next_eoi_state(state))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn match_whole_terms() {
+        let regexp = Regexp::new("du(ne|st)").unwrap();
+        assert!(regexp.matches(b"dune"));
+        assert!(regexp.matches(b"dust"));
+        assert!(!regexp.matches(b"dunes"));
+        assert!(!regexp.matches(b"sand dune"));
+        assert!(!regexp.matches(b"du"));
+        assert!(Regexp::new("a|ab").unwrap().matches(b"ab"));
+        assert!(Regexp::new(".*").unwrap().matches(b""));
+        assert_eq!(regexp.pattern(), "du(ne|st)");
+
+        // a pattern can not escape the group anchoring it to the whole term
+        assert!(matches!(
+            Regexp::new("a)|(b"),
+            Err(RegexpError::Invalid { .. })
+        ));
+    }
+
+    #[test]
+    fn reject_complex_patterns() {
+        assert!(matches!(
+            Regexp::new(&"a".repeat(MAX_PATTERN_LENGTH + 1)),
+            Err(RegexpError::TooLong(1001))
+        ));
+        assert!(matches!(
+            Regexp::new(&format!("{}a{}", "(".repeat(40), ")".repeat(40))),
+            Err(RegexpError::Invalid { .. })
+        ));
+        assert!(matches!(
+            Regexp::new("(a|b)*a(a|b){24}"),
+            Err(RegexpError::TooComplex { .. })
+        ));
+        assert!(matches!(
+            Regexp::new(r"\w{100}"),
+            Err(RegexpError::TooComplex { .. })
+        ));
+    }
+}
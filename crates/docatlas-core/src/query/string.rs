@@ -0,0 +1,689 @@
+//! Query strings, written like lucene queries, for searching without building a [`Query`].
+//!
+//! ```text
+//! title:"rust search" AND price:[10 TO 100] NOT status:draft
+//! ```
+//!
+//! | syntax                        | matches documents whose field                        |
+//! |-------------------------------|------------------------------------------------------|
+//! | `field:term`                  | holds the term                                       |
+//! | `field:"some words"`          | holds every term of the words                        |
+//! | `field:(a OR b)`              | matches the group, searching `field` by default      |
+//! | `field:[a TO b]`              | holds a value within the bounds, including them      |
+//! | `field:{a TO b}`              | holds a value within the bounds, excluding them      |
+//! | `field:>a`, `field:<=b`, ...  | holds a value above or below a bound                 |
+//! | `field:*`                     | holds any value                                      |
+//! | `field:/pattern/`             | holds a term matched as a whole by the [regexp]      |
+//!
+//! `*:*` matches every document, and bounds of `*` are left open. Terms without a field search
+//! the default field. Clauses are joined with `AND` (or `&&`) and `OR` (or `||`), where `AND`
+//! binds tighter, and clauses written next to each other are joined by the default operator.
+//! `NOT`, `!` and `-` negate a clause, and `+` requires it. Negated and required clauses are
+//! always joined to the clause before them with `AND`, so `a -b` never matches documents holding
+//! `b`.
+//!
+//! Special characters are escaped with a backslash. Within a regexp, only `\/` is unescaped, and
+//! every other escape is left to the pattern. Phrases match like every one of their terms
+//! is required, wherever they appear, as the positions of terms are not indexed.
+//!
+//! [regexp]: crate::query::regexp
+
+use std::ops::Bound;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::query::Query;
+
+/// How clauses written next to each other are joined
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Operator {
+    /// Documents must match any of the clauses
+    #[default]
+    Or,
+    /// Documents must match every clause
+    And,
+}
+
+impl FromStr for Operator {
+    type Err = QueryStringError;
+
+    /// Parses `AND` or `OR`, ignoring their case
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            s if s.eq_ignore_ascii_case("or") => Ok(Operator::Or),
+            s if s.eq_ignore_ascii_case("and") => Ok(Operator::And),
+            s => Err(QueryStringError::UnknownOperator(s.to_string())),
+        }
+    }
+}
+
+/// A query string could not be parsed
+#[derive(Debug, Error)]
+pub enum QueryStringError {
+    #[error("syntax error at {position}: {message}")]
+    Syntax { position: usize, message: String },
+    #[error("{term:?} at {position} has no field, and there is no default field")]
+    NoDefaultField { position: usize, term: String },
+    #[error("unsupported at {position}: {message}")]
+    Unsupported { position: usize, message: String },
+    #[error("unknown operator {0:?}, expected AND or OR")]
+    UnknownOperator(String),
+}
+
+impl QueryStringError {
+    fn syntax(position: usize, message: impl ToString) -> Self {
+        QueryStringError::Syntax {
+            position,
+            message: message.to_string(),
+        }
+    }
+
+    fn unsupported(position: usize, message: impl ToString) -> Self {
+        QueryStringError::Unsupported {
+            position,
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Parses query strings into queries
+#[derive(Debug, Clone, Default)]
+pub struct QueryString {
+    default_field: Option<String>,
+    default_operator: Operator,
+}
+
+impl QueryString {
+    /// Parses query strings without a default field, joining clauses with `OR` by default
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the field searched by terms written without one
+    pub fn with_default_field(mut self, field: impl Into<String>) -> Self {
+        self.default_field = Some(field.into());
+        self
+    }
+
+    /// Sets how clauses written next to each other are joined
+    pub fn with_default_operator(mut self, operator: Operator) -> Self {
+        self.default_operator = operator;
+        self
+    }
+
+    /// Gets the field searched by terms written without one
+    pub fn default_field(&self) -> Option<&str> {
+        self.default_field.as_deref()
+    }
+
+    /// Gets how clauses written next to each other are joined
+    pub fn default_operator(&self) -> Operator {
+        self.default_operator
+    }
+
+    /// Parses a query string. Empty query strings match every document.
+    pub fn parse(&self, text: &str) -> Result<Query, QueryStringError> {
+        let mut parser = Parser {
+            tokens: tokenize(text)?,
+            next: 0,
+            end: text.len(),
+            operator: self.default_operator,
+            depth: 0,
+        };
+        if parser.tokens.is_empty() {
+            return Ok(Query::All);
+        }
+        let query = parser.or(self.default_field.as_deref())?;
+        match parser.tokens.get(parser.next) {
+            Some((_, position)) => Err(QueryStringError::syntax(*position, "unexpected )")),
+            None => Ok(query),
+        }
+    }
+}
+
+/// A token of a query string
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    /// A term, or a keyword such as `AND` unless any of it was escaped
+    Term {
+        text: String,
+        escaped: bool,
+    },
+    /// Words quoted with double quotes
+    Phrase(String),
+    /// A pattern between slashes
+    Regexp(String),
+    /// A `*` on its own
+    Star,
+    Symbol(&'static str),
+}
+
+/// Symbols, with the longer ones first so they are preferred
+const SYMBOLS: [&str; 15] = [
+    "&&", "||", ">=", "<=", ">", "<", "(", ")", "[", "]", "{", "}", ":", "+", "!",
+];
+
+/// Characters ending a term, unless they are escaped
+/// How deeply groups and negations may nest, so deep query strings can not overflow the stack
+const MAX_DEPTH: usize = 64;
+
+const TERM_END: [char; 8] = ['(', ')', '[', ']', '{', '}', ':', '"'];
+
+/// Splits a query string into tokens, along with the byte offset each one starts at
+fn tokenize(text: &str) -> Result<Vec<(Token, usize)>, QueryStringError> {
+    let mut tokens = vec![];
+    let mut chars = text.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        if c == '^' || c == '~' {
+            return Err(QueryStringError::unsupported(
+                start,
+                "boosts, fuzzy terms and proximity",
+            ));
+        }
+        // a minus only negates at the start of a term, so terms like `2020-01-01` keep it, and
+        // never before a digit, so numbers can be negative
+        if c == '-' && !text[start + 1..].starts_with(|c: char| c.is_ascii_digit()) {
+            chars.next();
+            tokens.push((Token::Symbol("!"), start));
+            continue;
+        }
+        let rest = &text[start..];
+        if let Some(symbol) = SYMBOLS.into_iter().find(|symbol| rest.starts_with(symbol)) {
+            for _ in symbol.chars() {
+                chars.next();
+            }
+            tokens.push((Token::Symbol(symbol), start));
+            continue;
+        }
+        if c == '"' {
+            chars.next();
+            let mut phrase = String::new();
+            loop {
+                match chars.next() {
+                    Some((_, '"')) => break,
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, c)) => phrase.push(c),
+                        None => return Err(QueryStringError::syntax(start, "unterminated phrase")),
+                    },
+                    Some((_, c)) => phrase.push(c),
+                    None => return Err(QueryStringError::syntax(start, "unterminated phrase")),
+                }
+            }
+            tokens.push((Token::Phrase(phrase), start));
+            continue;
+        }
+        if c == '/' {
+            chars.next();
+            let mut pattern = String::new();
+            loop {
+                match chars.next() {
+                    Some((_, '/')) => break,
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, '/')) => pattern.push('/'),
+                        Some((_, c)) => pattern.extend(['\\', c]),
+                        None => return Err(QueryStringError::syntax(start, "unterminated regexp")),
+                    },
+                    Some((_, c)) => pattern.push(c),
+                    None => return Err(QueryStringError::syntax(start, "unterminated regexp")),
+                }
+            }
+            tokens.push((Token::Regexp(pattern), start));
+            continue;
+        }
+        let mut term = String::new();
+        let mut escaped = false;
+        while let Some(&(position, c)) = chars.peek() {
+            if c.is_whitespace() || TERM_END.contains(&c) {
+                break;
+            }
+            chars.next();
+            match c {
+                '\\' => match chars.next() {
+                    Some((_, c)) => {
+                        term.push(c);
+                        escaped = true;
+                    }
+                    None => return Err(QueryStringError::syntax(position, "nothing to escape")),
+                },
+                '*' if term.is_empty() && ends_term(chars.peek()) => {
+                    tokens.push((Token::Star, start));
+                    break;
+                }
+                '*' | '?' => {
+                    return Err(QueryStringError::unsupported(position, "wildcard terms"));
+                }
+                '^' | '~' => {
+                    return Err(QueryStringError::unsupported(
+                        position,
+                        "boosts, fuzzy terms and proximity",
+                    ))
+                }
+                c => term.push(c),
+            }
+        }
+        if !term.is_empty() {
+            tokens.push((
+                Token::Term {
+                    text: term,
+                    escaped,
+                },
+                start,
+            ));
+        }
+    }
+    Ok(tokens)
+}
+
+/// Checks whether the character after a `*` ends it, so the `*` stands on its own
+fn ends_term(next: Option<&(usize, char)>) -> bool {
+    next.is_none_or(|&(_, c)| c.is_whitespace() || TERM_END.contains(&c))
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    next: usize,
+    /// The length of the query string, where errors at its end are reported
+    end: usize,
+    operator: Operator,
+    /// How many groups and negations the parser is within
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next).map(|(token, _)| token)
+    }
+
+    fn position(&self) -> usize {
+        self.tokens
+            .get(self.next)
+            .map_or(self.end, |(_, position)| *position)
+    }
+
+    /// Takes the next token if it is the keyword or symbol
+    fn eat(&mut self, keyword: &str) -> bool {
+        let matched = match self.peek() {
+            Some(Token::Term {
+                text,
+                escaped: false,
+            }) => text == keyword,
+            Some(Token::Symbol(symbol)) => *symbol == keyword,
+            _ => false,
+        };
+        if matched {
+            self.next += 1;
+        }
+        matched
+    }
+
+    fn is_keyword(&self, keywords: &[&str]) -> bool {
+        match self.peek() {
+            Some(Token::Term {
+                text,
+                escaped: false,
+            }) => keywords.contains(&text.as_str()),
+            Some(Token::Symbol(symbol)) => keywords.contains(symbol),
+            _ => false,
+        }
+    }
+
+    /// Checks whether another clause follows without an operator before it
+    fn clause_follows(&self) -> bool {
+        self.peek().is_some() && !self.is_keyword(&[")", "AND", "&&", "OR", "||"])
+    }
+
+    fn or(&mut self, field: Option<&str>) -> Result<Query, QueryStringError> {
+        let mut queries = vec![self.and(field)?];
+        while self.eat("OR")
+            || self.eat("||")
+            || (self.operator == Operator::Or && self.clause_follows())
+        {
+            queries.push(self.and(field)?);
+        }
+        Ok(match queries.len() {
+            1 => queries.remove(0),
+            _ => Query::Or(queries),
+        })
+    }
+
+    fn and(&mut self, field: Option<&str>) -> Result<Query, QueryStringError> {
+        let mut queries = vec![self.unary(field)?];
+        while self.eat("AND")
+            || self.eat("&&")
+            || (self.clause_follows()
+                && (self.operator == Operator::And || self.is_keyword(&["NOT", "!", "+"])))
+        {
+            queries.push(self.unary(field)?);
+        }
+        Ok(match queries.len() {
+            1 => queries.remove(0),
+            _ => Query::And(queries),
+        })
+    }
+
+    fn unary(&mut self, field: Option<&str>) -> Result<Query, QueryStringError> {
+        if self.eat("NOT") || self.eat("!") {
+            return Ok(Query::Not(Box::new(
+                self.nested(|parser| parser.unary(field))?,
+            )));
+        }
+        if self.eat("+") {
+            return self.nested(|parser| parser.unary(field));
+        }
+        self.primary(field)
+    }
+
+    /// Parses something nested within a group or negation
+    fn nested<T>(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<T, QueryStringError>,
+    ) -> Result<T, QueryStringError> {
+        if self.depth == MAX_DEPTH {
+            return Err(QueryStringError::syntax(
+                self.position(),
+                "query nested too deeply",
+            ));
+        }
+        self.depth += 1;
+        let parsed = parse(self);
+        self.depth -= 1;
+        parsed
+    }
+
+    fn primary(&mut self, field: Option<&str>) -> Result<Query, QueryStringError> {
+        if self.eat("(") {
+            return self.group(field);
+        }
+        let position = self.position();
+        let named = match self.tokens.get(self.next + 1) {
+            Some((Token::Symbol(":"), _)) => match self.peek() {
+                Some(Token::Term { text, .. }) => Some(text.clone()),
+                Some(Token::Star) => Some("*".to_string()),
+                _ => None,
+            },
+            _ => None,
+        };
+        let Some(named) = named else {
+            return self.value(field, position);
+        };
+        self.next += 2;
+        if named == "*" {
+            return match self.peek() {
+                Some(Token::Star) => {
+                    self.next += 1;
+                    Ok(Query::All)
+                }
+                _ => Err(QueryStringError::unsupported(
+                    position,
+                    "searching every field, other than with *:*",
+                )),
+            };
+        }
+        if self.eat("(") {
+            return self.group(Some(&named));
+        }
+        let position = self.position();
+        self.value(Some(&named), position)
+    }
+
+    /// Parses the rest of a group after its opening parenthesis
+    fn group(&mut self, field: Option<&str>) -> Result<Query, QueryStringError> {
+        let query = self.nested(|parser| parser.or(field))?;
+        match self.eat(")") {
+            true => Ok(query),
+            false => Err(QueryStringError::syntax(self.position(), "expected )")),
+        }
+    }
+
+    /// Parses what a field is searched for, or what the default field is searched for if no
+    /// field was named
+    fn value(&mut self, field: Option<&str>, position: usize) -> Result<Query, QueryStringError> {
+        let Some((token, _)) = self.tokens.get(self.next).cloned() else {
+            return Err(QueryStringError::syntax(position, "expected a term"));
+        };
+        let field = |term: &str| match field {
+            Some(field) => Ok(field.to_string()),
+            None => Err(QueryStringError::NoDefaultField {
+                position,
+                term: term.to_string(),
+            }),
+        };
+        self.next += 1;
+        Ok(match token {
+            Token::Term { text, .. } | Token::Phrase(text) => Query::Match {
+                field: field(&text)?,
+                text,
+                all: true,
+            },
+            Token::Star => Query::Exists { field: field("*")? },
+            Token::Regexp(pattern) => Query::Regexp {
+                field: field(&pattern)?,
+                pattern,
+            },
+            Token::Symbol(open @ ("[" | "{")) => {
+                let field = field(open)?;
+                let lower = self.bound(open == "[")?;
+                if !self.eat("TO") {
+                    return Err(QueryStringError::syntax(self.position(), "expected TO"));
+                }
+                let upper = self.bound(true)?;
+                let inclusive = match self.peek() {
+                    Some(Token::Symbol("]")) => true,
+                    Some(Token::Symbol("}")) => false,
+                    _ => return Err(QueryStringError::syntax(self.position(), "expected ] or }")),
+                };
+                self.next += 1;
+                let upper = match (upper, inclusive) {
+                    (Bound::Included(value), false) => Bound::Excluded(value),
+                    (upper, _) => upper,
+                };
+                Query::Range {
+                    field,
+                    lower,
+                    upper,
+                }
+            }
+            Token::Symbol(comparison @ (">" | ">=" | "<" | "<=")) => {
+                let field = field(comparison)?;
+                let bound = self.bound(comparison.ends_with('='))?;
+                let (lower, upper) = match comparison.starts_with('>') {
+                    true => (bound, Bound::Unbounded),
+                    false => (Bound::Unbounded, bound),
+                };
+                Query::Range {
+                    field,
+                    lower,
+                    upper,
+                }
+            }
+            Token::Symbol(symbol) => {
+                return Err(QueryStringError::syntax(
+                    position,
+                    format!("unexpected {symbol}"),
+                ))
+            }
+        })
+    }
+
+    /// Parses the bound of a range, which is left open if it is `*`
+    fn bound(&mut self, inclusive: bool) -> Result<Bound<String>, QueryStringError> {
+        let position = self.position();
+        let bound = match self.peek() {
+            Some(Token::Star) => Bound::Unbounded,
+            Some(Token::Term { text, .. } | Token::Phrase(text)) if inclusive => {
+                Bound::Included(text.clone())
+            }
+            Some(Token::Term { text, .. } | Token::Phrase(text)) => Bound::Excluded(text.clone()),
+            _ => return Err(QueryStringError::syntax(position, "expected a bound")),
+        };
+        self.next += 1;
+        Ok(bound)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matching(field: &str, text: &str) -> Query {
+        Query::Match {
+            field: field.to_string(),
+            text: text.to_string(),
+            all: true,
+        }
+    }
+
+    #[test]
+    fn parse_query_strings() {
+        let parser = QueryString::new().with_default_field("title");
+        let query = parser
+            .parse(r#"title:"rust search" AND price:[10 TO 100} NOT status:draft"#)
+            .unwrap();
+        assert_eq!(
+            query,
+            Query::And(vec![
+                matching("title", "rust search"),
+                Query::Range {
+                    field: "price".to_string(),
+                    lower: Bound::Included("10".to_string()),
+                    upper: Bound::Excluded("100".to_string()),
+                },
+                Query::Not(Box::new(matching("status", "draft"))),
+            ])
+        );
+        // AND binds tighter than OR, and clauses without an operator are joined by OR
+        assert_eq!(
+            parser.parse("dune emma AND year:>=1800").unwrap(),
+            Query::Or(vec![
+                matching("title", "dune"),
+                Query::And(vec![
+                    matching("title", "emma"),
+                    Query::Range {
+                        field: "year".to_string(),
+                        lower: Bound::Included("1800".to_string()),
+                        upper: Bound::Unbounded,
+                    },
+                ]),
+            ])
+        );
+        assert_eq!(
+            parser.parse("dune -messiah").unwrap(),
+            Query::And(vec![
+                matching("title", "dune"),
+                Query::Not(Box::new(matching("title", "messiah"))),
+            ])
+        );
+        assert_eq!(
+            parser.parse("author:(herbert || austen) year:*").unwrap(),
+            Query::Or(vec![
+                Query::Or(vec![
+                    matching("author", "herbert"),
+                    matching("author", "austen"),
+                ]),
+                Query::Exists {
+                    field: "year".to_string(),
+                },
+            ])
+        );
+        assert_eq!(
+            parser.parse(r"/du(ne|st)/ path:/a\/b\.c/").unwrap(),
+            Query::Or(vec![
+                Query::Regexp {
+                    field: "title".to_string(),
+                    pattern: "du(ne|st)".to_string(),
+                },
+                Query::Regexp {
+                    field: "path".to_string(),
+                    pattern: r"a/b\.c".to_string(),
+                },
+            ])
+        );
+        assert_eq!(parser.parse("*:*").unwrap(), Query::All);
+        assert_eq!(parser.parse("  ").unwrap(), Query::All);
+        assert_eq!(
+            parser.parse(r"date:2020-01-01 id:a\:b").unwrap(),
+            Query::Or(vec![matching("date", "2020-01-01"), matching("id", "a:b")])
+        );
+    }
+
+    #[test]
+    fn join_clauses_with_the_default_operator() {
+        let parser = QueryString::new()
+            .with_default_field("title")
+            .with_default_operator(Operator::And);
+        assert_eq!(
+            parser.parse("dune messiah OR emma").unwrap(),
+            Query::Or(vec![
+                Query::And(vec![
+                    matching("title", "dune"),
+                    matching("title", "messiah")
+                ]),
+                matching("title", "emma"),
+            ])
+        );
+        assert_eq!(
+            parser.parse("year:[* TO 1900]").unwrap(),
+            Query::Range {
+                field: "year".to_string(),
+                lower: Bound::Unbounded,
+                upper: Bound::Included("1900".to_string()),
+            }
+        );
+        assert_eq!(
+            parser.parse("year:{-50 TO *]").unwrap(),
+            Query::Range {
+                field: "year".to_string(),
+                lower: Bound::Excluded("-50".to_string()),
+                upper: Bound::Unbounded,
+            }
+        );
+    }
+
+    #[test]
+    fn report_invalid_query_strings() {
+        let parser = QueryString::new();
+        let error = |text: &str| parser.parse(text).unwrap_err().to_string();
+        assert_eq!(
+            error("dune"),
+            "\"dune\" at 0 has no field, and there is no default field"
+        );
+        assert_eq!(error("title:(dune"), "syntax error at 11: expected )");
+        assert_eq!(error("title:dune)"), "syntax error at 10: unexpected )");
+        assert_eq!(error("year:[1 1900]"), "syntax error at 8: expected TO");
+        assert_eq!(
+            error("title:\"dune"),
+            "syntax error at 6: unterminated phrase"
+        );
+        assert_eq!(error("title:du*"), "unsupported at 8: wildcard terms");
+        assert_eq!(
+            error("title:/du.*"),
+            "syntax error at 6: unterminated regexp"
+        );
+        assert_eq!(
+            error("title:dune^2"),
+            "unsupported at 10: boosts, fuzzy terms and proximity"
+        );
+        assert_eq!(
+            error(&format!("{}title:dune", "(".repeat(65))),
+            "syntax error at 65: query nested too deeply"
+        );
+        assert_eq!(
+            error(&format!("{}title:dune", "NOT ".repeat(100))),
+            "syntax error at 260: query nested too deeply"
+        );
+        assert!(parser
+            .parse(&format!("{}title:dune{}", "(".repeat(64), ")".repeat(64)))
+            .is_ok());
+        assert_eq!("and".parse::<Operator>().unwrap(), Operator::And);
+        assert_eq!(
+            "xor".parse::<Operator>().unwrap_err().to_string(),
+            "unknown operator \"xor\", expected AND or OR"
+        );
+    }
+}
@@ -1,26 +1,53 @@
 //! A schema defines the mapping of an index
 
 use crate::document::Document;
-use std::iter;
+use serde::{Deserialize, Serialize};
 use std::iter::FusedIterator;
 use std::ops::{
     Index, Range, RangeBounds, RangeFrom, RangeFull, RangeInclusive, RangeTo, RangeToInclusive,
 };
 use std::vec::Drain;
 
+use crate::codec::{RowCodec, RowEncodeError};
 use crate::fields::FieldKind;
 use crate::persist::PersistentVec;
 
 /// A schema defines an ordered array of fields
-#[derive(Debug)]
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Schema {
     fields: Vec<SchemaField>,
+    #[serde(default)]
+    primary_key: Option<String>,
+    #[serde(default)]
+    routing_key: Option<String>,
 }
 
 impl Schema {
     /// Creates a new, empty schema
     pub fn new() -> Self {
-        Self { fields: vec![] }
+        Self::default()
+    }
+
+    /// Sets the field used as the primary key of this schema
+    pub fn with_primary_key(mut self, name: impl AsRef<str>) -> Self {
+        self.primary_key = Some(name.as_ref().to_string());
+        self
+    }
+
+    /// Gets the primary key field of this schema, if one is set and present
+    pub fn primary_key(&self) -> Option<&SchemaField> {
+        self.primary_key.as_ref().and_then(|name| self.get(name))
+    }
+
+    /// Sets the field whose value determines which shard a document is placed in
+    pub fn with_routing_key(mut self, name: impl AsRef<str>) -> Self {
+        self.routing_key = Some(name.as_ref().to_string());
+        self
+    }
+
+    /// Gets the routing key field of this schema, if one is set and present
+    pub fn routing_key(&self) -> Option<&SchemaField> {
+        self.routing_key.as_ref().and_then(|name| self.get(name))
     }
 
     /// Gets an iterator over the schema fields
@@ -61,6 +88,24 @@ impl Schema {
         self.iter().map(|field| field.kind.size()).sum()
     }
 
+    /// Gets the byte range of a field within a row, if the field is present
+    pub fn field_range(&self, name: impl AsRef<str>) -> Option<Range<usize>> {
+        let mut offset = 0;
+        for field in self {
+            let size = field.kind.size();
+            if field.name == name.as_ref() {
+                return Some(offset..offset + size);
+            }
+            offset += size;
+        }
+        None
+    }
+
+    /// Gets the codec used to encode and decode rows of this schema
+    pub fn codec(&self) -> RowCodec<'_> {
+        RowCodec::new(self)
+    }
+
     /// Gets a split over a block, where each split is a row
     pub fn row_bytes<'p>(
         &self,
@@ -69,19 +114,25 @@ impl Schema {
         p_vec.split_mut(self.row_size())
     }
 
-    /// Inserts documents into a given schema. This performs no checks onto the data and it's validity
-    /// beyond it being the correct size.
+    /// Inserts documents into a given schema, returning the number of documents inserted. This
+    /// performs no checks onto the data and it's validity beyond it being encodable.
+    ///
+    /// If any document can not be encoded, no documents are inserted.
     pub fn insert<I: IntoIterator<Item = Document>>(
         &self,
         p_vec: &mut PersistentVec<u8>,
         documents: I,
-    ) -> Result<usize, ()> {
+    ) -> Result<usize, RowEncodeError> {
         let documents = documents.into_iter().collect::<Vec<_>>();
-        let byte_req = documents.len() * self.row_size();
-        p_vec.reserve(byte_req);
-        p_vec.extend(iter::repeat(0).take(byte_req));
+        let row_size = self.row_size();
+        let mut rows = vec![0_u8; documents.len() * row_size];
+        let codec = self.codec();
+        for (document, row) in documents.iter().zip(rows.chunks_exact_mut(row_size)) {
+            codec.encode(document, row)?;
+        }
 
-        todo!()
+        p_vec.extend_from_slice(&rows);
+        Ok(documents.len())
     }
 }
 
@@ -152,6 +203,8 @@ impl FromIterator<SchemaField> for Schema {
     fn from_iter<T: IntoIterator<Item = SchemaField>>(iter: T) -> Self {
         Self {
             fields: iter.into_iter().collect(),
+            primary_key: None,
+            routing_key: None,
         }
     }
 }
@@ -176,7 +229,7 @@ impl<'a> Iterator for SchemaIter<'a> {
 impl FusedIterator for SchemaIter<'_> {}
 
 /// A single field in a schema
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct SchemaField {
     pub name: String,
     pub kind: FieldKind,
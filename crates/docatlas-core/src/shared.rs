@@ -1,14 +1,25 @@
 //! Contains shared object defintitions
 
-use parking_lot::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+mod diagnostics;
+
+use parking_lot::{
+    MappedRwLockReadGuard, MappedRwLockWriteGuard, RwLock, RwLockReadGuard,
+    RwLockUpgradableReadGuard, RwLockWriteGuard,
+};
 use std::hash::{Hash, Hasher};
 use std::ops::{Deref, DerefMut};
 use std::sync::Arc;
+use std::time::Duration;
+
+#[cfg(feature = "lock-diagnostics")]
+pub use diagnostics::{lock_threshold, set_lock_threshold, LockHolder, DEFAULT_LOCK_THRESHOLD};
+use diagnostics::{Access, Diagnostics, Held};
 
 /// A shared, thread safe object
 #[derive(Debug, Default)]
 pub struct Shared<T> {
     inner: Arc<RwLock<T>>,
+    diagnostics: Diagnostics,
 }
 
 impl<T> Shared<T> {
@@ -16,29 +27,90 @@ impl<T> Shared<T> {
     pub fn new(v: T) -> Self {
         Self {
             inner: Arc::new(RwLock::new(v)),
+            diagnostics: Default::default(),
         }
     }
 
-    pub fn read(&self) -> SharedReadGuard<T> {
-        SharedReadGuard {
-            guard: self.inner.read(),
-        }
+    #[cfg_attr(feature = "lock-diagnostics", track_caller)]
+    pub fn read(&self) -> SharedReadGuard<'_, T> {
+        let (guard, held) = self.diagnostics.acquire(
+            Access::Read,
+            || self.inner.read(),
+            |timeout| self.inner.try_read_for(timeout),
+        );
+        SharedReadGuard { guard, held }
     }
 
-    pub fn try_read(&self) -> Option<SharedReadGuard<T>> {
-        self.inner.try_read().map(|guard| SharedReadGuard { guard })
+    #[cfg_attr(feature = "lock-diagnostics", track_caller)]
+    pub fn try_read(&self) -> Option<SharedReadGuard<'_, T>> {
+        self.diagnostics
+            .try_acquire(Access::Read, || self.inner.try_read())
+            .map(|(guard, held)| SharedReadGuard { guard, held })
     }
 
-    pub fn write(&self) -> SharedWriteGuard<T> {
-        SharedWriteGuard {
-            guard: self.inner.write(),
-        }
+    /// Tries to read the value, waiting at most for the given duration
+    #[cfg_attr(feature = "lock-diagnostics", track_caller)]
+    pub fn try_read_for(&self, timeout: Duration) -> Option<SharedReadGuard<'_, T>> {
+        self.diagnostics
+            .try_acquire(Access::Read, || self.inner.try_read_for(timeout))
+            .map(|(guard, held)| SharedReadGuard { guard, held })
+    }
+
+    /// Reads the value with the option of writing it later on. Other readers may read the value
+    /// at the same time, but only one upgradeable reader or writer may hold it.
+    #[cfg_attr(feature = "lock-diagnostics", track_caller)]
+    pub fn upgradeable_read(&self) -> SharedUpgradeableReadGuard<'_, T> {
+        let (guard, held) = self.diagnostics.acquire(
+            Access::UpgradeableRead,
+            || self.inner.upgradable_read(),
+            |timeout| self.inner.try_upgradable_read_for(timeout),
+        );
+        SharedUpgradeableReadGuard { guard, held }
+    }
+
+    /// Tries to read the value with the option of writing it later on, waiting at most for the
+    /// given duration
+    #[cfg_attr(feature = "lock-diagnostics", track_caller)]
+    pub fn try_upgradeable_read_for(
+        &self,
+        timeout: Duration,
+    ) -> Option<SharedUpgradeableReadGuard<'_, T>> {
+        self.diagnostics
+            .try_acquire(Access::UpgradeableRead, || {
+                self.inner.try_upgradable_read_for(timeout)
+            })
+            .map(|(guard, held)| SharedUpgradeableReadGuard { guard, held })
+    }
+
+    #[cfg_attr(feature = "lock-diagnostics", track_caller)]
+    pub fn write(&self) -> SharedWriteGuard<'_, T> {
+        let (guard, held) = self.diagnostics.acquire(
+            Access::Write,
+            || self.inner.write(),
+            |timeout| self.inner.try_write_for(timeout),
+        );
+        SharedWriteGuard { guard, held }
+    }
+
+    #[cfg_attr(feature = "lock-diagnostics", track_caller)]
+    pub fn try_write(&self) -> Option<SharedWriteGuard<'_, T>> {
+        self.diagnostics
+            .try_acquire(Access::Write, || self.inner.try_write())
+            .map(|(guard, held)| SharedWriteGuard { guard, held })
     }
 
-    pub fn try_write(&self) -> Option<SharedWriteGuard<T>> {
-        self.inner
-            .try_write()
-            .map(|guard| SharedWriteGuard { guard })
+    /// Tries to write the value, waiting at most for the given duration
+    #[cfg_attr(feature = "lock-diagnostics", track_caller)]
+    pub fn try_write_for(&self, timeout: Duration) -> Option<SharedWriteGuard<'_, T>> {
+        self.diagnostics
+            .try_acquire(Access::Write, || self.inner.try_write_for(timeout))
+            .map(|(guard, held)| SharedWriteGuard { guard, held })
+    }
+
+    /// Gets everyone currently holding the lock of the value
+    #[cfg(feature = "lock-diagnostics")]
+    pub fn holders(&self) -> Vec<LockHolder> {
+        self.diagnostics.holders()
     }
 }
 
@@ -64,6 +136,7 @@ impl<T> Clone for Shared<T> {
     fn clone(&self) -> Self {
         Self {
             inner: self.inner.clone(),
+            diagnostics: self.diagnostics.clone(),
         }
     }
 }
@@ -72,6 +145,21 @@ impl<T> Clone for Shared<T> {
 #[derive(Debug)]
 pub struct SharedReadGuard<'a, T> {
     guard: RwLockReadGuard<'a, T>,
+    held: Held,
+}
+
+impl<'a, T> SharedReadGuard<'a, T> {
+    /// Narrows the guard down to a part of the value. This is an associated function, so that it
+    /// does not hide a method of the value.
+    pub fn map<U, F>(this: Self, f: F) -> MappedSharedReadGuard<'a, U>
+    where
+        F: FnOnce(&T) -> &U,
+    {
+        MappedSharedReadGuard {
+            guard: RwLockReadGuard::map(this.guard, f),
+            held: this.held,
+        }
+    }
 }
 
 impl<T> Deref for SharedReadGuard<'_, T> {
@@ -82,10 +170,101 @@ impl<T> Deref for SharedReadGuard<'_, T> {
     }
 }
 
-/// A read guard of a shared value.
+/// A read guard of a part of a shared value
+#[derive(Debug)]
+pub struct MappedSharedReadGuard<'a, T> {
+    guard: MappedRwLockReadGuard<'a, T>,
+    /// Only kept to be dropped along with the guard
+    #[allow(dead_code)]
+    held: Held,
+}
+
+impl<T> Deref for MappedSharedReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.guard.deref()
+    }
+}
+
+/// A read guard of a shared value that can be upgraded to a write guard, without letting any
+/// other writer in between.
+#[derive(Debug)]
+pub struct SharedUpgradeableReadGuard<'a, T> {
+    guard: RwLockUpgradableReadGuard<'a, T>,
+    held: Held,
+}
+
+impl<'a, T> SharedUpgradeableReadGuard<'a, T> {
+    /// Waits for every other reader to finish, then writes the value
+    pub fn upgrade(this: Self) -> SharedWriteGuard<'a, T> {
+        let guard = RwLockUpgradableReadGuard::upgrade(this.guard);
+        this.held.set_access(Access::Write);
+        SharedWriteGuard {
+            guard,
+            held: this.held,
+        }
+    }
+
+    /// Tries to write the value, waiting at most for the given duration for every other reader to
+    /// finish. The guard is given back if they did not.
+    pub fn try_upgrade_for(this: Self, timeout: Duration) -> Result<SharedWriteGuard<'a, T>, Self> {
+        let held = this.held;
+        match RwLockUpgradableReadGuard::try_upgrade_for(this.guard, timeout) {
+            Ok(guard) => {
+                held.set_access(Access::Write);
+                Ok(SharedWriteGuard { guard, held })
+            }
+            Err(guard) => Err(Self { guard, held }),
+        }
+    }
+
+    /// Gives up the option of writing the value, letting another upgradeable reader in
+    pub fn downgrade(this: Self) -> SharedReadGuard<'a, T> {
+        this.held.set_access(Access::Read);
+        SharedReadGuard {
+            guard: RwLockUpgradableReadGuard::downgrade(this.guard),
+            held: this.held,
+        }
+    }
+}
+
+impl<T> Deref for SharedUpgradeableReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.guard.deref()
+    }
+}
+
+/// A write guard of a shared value.
 #[derive(Debug)]
 pub struct SharedWriteGuard<'a, T> {
     guard: RwLockWriteGuard<'a, T>,
+    held: Held,
+}
+
+impl<'a, T> SharedWriteGuard<'a, T> {
+    /// Narrows the guard down to a part of the value. This is an associated function, so that it
+    /// does not hide a method of the value.
+    pub fn map<U, F>(this: Self, f: F) -> MappedSharedWriteGuard<'a, U>
+    where
+        F: FnOnce(&mut T) -> &mut U,
+    {
+        MappedSharedWriteGuard {
+            guard: RwLockWriteGuard::map(this.guard, f),
+            held: this.held,
+        }
+    }
+
+    /// Stops writing the value, letting other readers in while still reading it
+    pub fn downgrade(this: Self) -> SharedReadGuard<'a, T> {
+        this.held.set_access(Access::Read);
+        SharedReadGuard {
+            guard: RwLockWriteGuard::downgrade(this.guard),
+            held: this.held,
+        }
+    }
 }
 
 impl<T> Deref for SharedWriteGuard<'_, T> {
@@ -101,3 +280,87 @@ impl<T> DerefMut for SharedWriteGuard<'_, T> {
         self.guard.deref_mut()
     }
 }
+
+/// A write guard of a part of a shared value
+#[derive(Debug)]
+pub struct MappedSharedWriteGuard<'a, T> {
+    guard: MappedRwLockWriteGuard<'a, T>,
+    /// Only kept to be dropped along with the guard
+    #[allow(dead_code)]
+    held: Held,
+}
+
+impl<T> Deref for MappedSharedWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        self.guard.deref()
+    }
+}
+
+impl<T> DerefMut for MappedSharedWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.guard.deref_mut()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn upgrade_reads() {
+        let shared = Shared::new(vec![1, 2]);
+        let upgradeable = shared.upgradeable_read();
+        let reader = shared.read();
+        // only one upgradeable reader at a time, and no writers
+        assert!(shared
+            .try_upgradeable_read_for(Duration::from_millis(10))
+            .is_none());
+        assert!(shared.try_write_for(Duration::from_millis(10)).is_none());
+
+        // upgrading waits for the other readers
+        let upgradeable =
+            SharedUpgradeableReadGuard::try_upgrade_for(upgradeable, Duration::from_millis(10))
+                .unwrap_err();
+        drop(reader);
+        let mut writer = SharedUpgradeableReadGuard::upgrade(upgradeable);
+        writer.push(3);
+        let reader = SharedWriteGuard::downgrade(writer);
+        assert_eq!(*reader, [1, 2, 3]);
+        assert!(shared.try_read_for(Duration::from_millis(10)).is_some());
+    }
+
+    #[test]
+    fn map_guards() {
+        let shared = Shared::new((String::from("logs"), 0));
+        {
+            let mut count = SharedWriteGuard::map(shared.write(), |(_, count)| count);
+            *count += 1;
+        }
+        let name = SharedReadGuard::map(shared.read(), |(name, _)| name);
+        assert_eq!(&*name, "logs");
+        assert_eq!(shared.read().1, 1);
+    }
+
+    #[cfg(feature = "lock-diagnostics")]
+    #[test]
+    fn record_lock_holders() {
+        let shared = Shared::new(0);
+        assert!(shared.holders().is_empty());
+        let reader = shared.upgradeable_read();
+        let line = line!() - 1;
+        let holders = shared.holders();
+        assert_eq!(holders.len(), 1);
+        assert_eq!(holders[0].access(), Access::UpgradeableRead);
+        assert_eq!(holders[0].location().file(), file!());
+        assert_eq!(holders[0].location().line(), line);
+
+        let writer = SharedUpgradeableReadGuard::upgrade(reader);
+        assert_eq!(shared.holders()[0].access(), Access::Write);
+        let other = shared.clone();
+        assert!(other.try_read_for(Duration::from_millis(10)).is_none());
+        drop(writer);
+        assert!(shared.holders().is_empty());
+    }
+}
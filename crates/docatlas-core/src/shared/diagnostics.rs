@@ -0,0 +1,259 @@
+//! Records who holds the locks of shared values, to find out why the daemon stalls. This is only
+//! done when the `lock-diagnostics` feature is enabled, otherwise locking costs nothing more.
+//!
+//! With the feature enabled, every lock remembers the thread and the place in the code holding
+//! it. Waiting for a lock, or holding one, for longer than the [threshold](set_lock_threshold)
+//! emits a warning, naming who holds the lock in the former case.
+
+#[cfg(not(feature = "lock-diagnostics"))]
+pub(super) use disabled::*;
+#[cfg(feature = "lock-diagnostics")]
+pub use enabled::*;
+
+/// How a lock is held
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    UpgradeableRead,
+    Write,
+}
+
+#[cfg(feature = "lock-diagnostics")]
+mod enabled {
+    use std::collections::HashMap;
+    use std::panic::Location;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use parking_lot::Mutex;
+    use tracing::warn;
+
+    use super::Access;
+
+    /// How long a lock may be waited for or held before a warning is emitted, in milliseconds
+    static THRESHOLD_MILLIS: AtomicU64 = AtomicU64::new(DEFAULT_LOCK_THRESHOLD.as_millis() as u64);
+    /// Identifies the holders of locks
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+    /// How long a lock may be waited for or held before a warning is emitted unless set otherwise
+    pub const DEFAULT_LOCK_THRESHOLD: Duration = Duration::from_secs(1);
+
+    /// Sets how long a lock may be waited for or held before a warning is emitted
+    pub fn set_lock_threshold(threshold: Duration) {
+        THRESHOLD_MILLIS.store(threshold.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Gets how long a lock may be waited for or held before a warning is emitted
+    pub fn lock_threshold() -> Duration {
+        Duration::from_millis(THRESHOLD_MILLIS.load(Ordering::Relaxed))
+    }
+
+    /// Someone holding a lock
+    #[derive(Debug, Clone)]
+    pub struct LockHolder {
+        thread: String,
+        location: &'static Location<'static>,
+        access: Access,
+        since: Instant,
+    }
+
+    impl LockHolder {
+        /// Gets the name, or the id if it has none, of the thread holding the lock
+        pub fn thread(&self) -> &str {
+            &self.thread
+        }
+
+        /// Gets the place in the code where the lock was taken
+        pub fn location(&self) -> &'static Location<'static> {
+            self.location
+        }
+
+        /// Gets how the lock is held
+        pub fn access(&self) -> Access {
+            self.access
+        }
+
+        /// Gets how long the lock has been held
+        pub fn held_for(&self) -> Duration {
+            self.since.elapsed()
+        }
+    }
+
+    /// The holders of the lock of a shared value
+    #[derive(Debug, Default, Clone)]
+    pub(in crate::shared) struct Diagnostics {
+        holders: Arc<Mutex<HashMap<u64, LockHolder>>>,
+    }
+
+    impl Diagnostics {
+        /// Gets everyone currently holding the lock
+        pub(in crate::shared) fn holders(&self) -> Vec<LockHolder> {
+            self.holders.lock().values().cloned().collect()
+        }
+
+        /// Waits for the lock with `try_for`, warning about its holders every time the threshold
+        /// passes without getting it
+        #[track_caller]
+        pub(in crate::shared) fn acquire<G>(
+            &self,
+            access: Access,
+            _lock: impl FnOnce() -> G,
+            mut try_for: impl FnMut(Duration) -> Option<G>,
+        ) -> (G, Held) {
+            let location = Location::caller();
+            let start = Instant::now();
+            loop {
+                if let Some(guard) = try_for(lock_threshold()) {
+                    return (guard, self.held(location, access, start));
+                }
+                warn!(
+                    "{location} waited {:?} for {access:?} lock held by {}",
+                    start.elapsed(),
+                    self.describe_holders()
+                );
+            }
+        }
+
+        /// Tries to take the lock once with `try_lock`, warning if that took too long
+        #[track_caller]
+        pub(in crate::shared) fn try_acquire<G>(
+            &self,
+            access: Access,
+            try_lock: impl FnOnce() -> Option<G>,
+        ) -> Option<(G, Held)> {
+            let location = Location::caller();
+            let start = Instant::now();
+            let guard = try_lock();
+            if start.elapsed() > lock_threshold() {
+                warn!(
+                    "{location} waited {:?} for {access:?} lock held by {}",
+                    start.elapsed(),
+                    self.describe_holders()
+                );
+            }
+            guard.map(|guard| (guard, self.held(location, access, start)))
+        }
+
+        /// Records that the lock was taken
+        fn held(
+            &self,
+            location: &'static Location<'static>,
+            access: Access,
+            start: Instant,
+        ) -> Held {
+            let wait = start.elapsed();
+            if wait > lock_threshold() {
+                warn!("{location} got {access:?} lock after waiting {wait:?}");
+            }
+            let current = thread::current();
+            let holder = LockHolder {
+                thread: current
+                    .name()
+                    .map(str::to_string)
+                    .unwrap_or_else(|| format!("{:?}", current.id())),
+                location,
+                access,
+                since: Instant::now(),
+            };
+            let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+            self.holders.lock().insert(id, holder);
+            Held {
+                diagnostics: self.clone(),
+                id,
+            }
+        }
+
+        fn describe_holders(&self) -> String {
+            let holders = self.holders();
+            if holders.is_empty() {
+                return "no one".to_string();
+            }
+            holders
+                .iter()
+                .map(|holder| {
+                    format!(
+                        "{} at {} ({:?} for {:?})",
+                        holder.thread,
+                        holder.location,
+                        holder.access,
+                        holder.held_for()
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        }
+    }
+
+    /// Forgets the holder of a lock once it is released, warning if it was held for too long
+    #[derive(Debug)]
+    pub(in crate::shared) struct Held {
+        diagnostics: Diagnostics,
+        id: u64,
+    }
+
+    impl Held {
+        /// Records that the lock is now held differently
+        pub(in crate::shared) fn set_access(&self, access: Access) {
+            if let Some(holder) = self.diagnostics.holders.lock().get_mut(&self.id) {
+                holder.access = access;
+            }
+        }
+    }
+
+    impl Drop for Held {
+        fn drop(&mut self) {
+            let Some(holder) = self.diagnostics.holders.lock().remove(&self.id) else {
+                return;
+            };
+            let held_for = holder.held_for();
+            if held_for > lock_threshold() {
+                warn!(
+                    "{} held {:?} lock for {held_for:?} on thread {}",
+                    holder.location, holder.access, holder.thread
+                );
+            }
+        }
+    }
+}
+
+#[cfg(not(feature = "lock-diagnostics"))]
+mod disabled {
+    use std::time::Duration;
+
+    use super::Access;
+
+    /// Records nothing
+    #[derive(Debug, Default, Clone)]
+    pub struct Diagnostics;
+
+    impl Diagnostics {
+        /// Waits for the lock with `lock`
+        pub fn acquire<G>(
+            &self,
+            _access: Access,
+            lock: impl FnOnce() -> G,
+            _try_for: impl FnMut(Duration) -> Option<G>,
+        ) -> (G, Held) {
+            (lock(), Held)
+        }
+
+        /// Tries to take the lock once with `try_lock`
+        pub fn try_acquire<G>(
+            &self,
+            _access: Access,
+            try_lock: impl FnOnce() -> Option<G>,
+        ) -> Option<(G, Held)> {
+            try_lock().map(|guard| (guard, Held))
+        }
+    }
+
+    /// Records nothing
+    #[derive(Debug)]
+    pub struct Held;
+
+    impl Held {
+        pub fn set_access(&self, _access: Access) {}
+    }
+}
@@ -0,0 +1,226 @@
+//! A sql frontend, reading `SELECT` statements and running them as [queries](crate::query) and
+//! [aggregations](crate::aggregation).
+//!
+//! ```sql
+//! SELECT b.title, a.name AS author
+//! FROM books b
+//! LEFT JOIN authors a ON b.author_id = a.id
+//! WHERE b.year BETWEEN 1960 AND 1970 AND MATCH(b.title, 'dune')
+//! ORDER BY b.year DESC
+//! LIMIT 10 OFFSET 20
+//! ```
+//!
+//! Statements select from a single index, and can join other indexes on fields holding the same
+//! values, such as a primary key held by the documents of another index. `GROUP BY` puts rows
+//! into buckets, summarized by `COUNT`, `SUM`, `AVG`, `MIN` and `MAX`.
+//!
+//! Columns are the fields of the indexes, and are named like `year`, or like `b.year` when
+//! qualified by the name or alias of their index. Nested fields keep their dots, so `author.name`
+//! is the `author.name` field unless `author` names an index of the statement. Only the first
+//! value of fields holding many values is selected, but conditions match any of their values.
+//!
+//! Conditions on a single index are run by that index before any join. Missing values never
+//! equal, or differ from, a value, so `year != 1965` only matches documents with a year.
+
+use std::fmt::{Display, Formatter};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::aggregation::Metric;
+use crate::document::Document;
+use crate::fields::FieldData;
+use crate::query::{Query, QueryError};
+use crate::schema::Schema;
+
+mod execute;
+mod lexer;
+mod parser;
+
+pub use execute::execute;
+pub use parser::parse;
+
+/// A sql statement could not be run
+#[derive(Debug, Error)]
+pub enum SqlError {
+    #[error("syntax error at {position}: {message}")]
+    Syntax { position: usize, message: String },
+    #[error("unknown column {0}")]
+    UnknownColumn(Column),
+    #[error("column {0} is ambiguous, qualify it with the name of its index")]
+    AmbiguousColumn(Column),
+    #[error("the index {0:?} is named more than once, give it an alias")]
+    DuplicateIndex(String),
+    #[error("unsupported: {0}")]
+    Unsupported(String),
+    #[error(transparent)]
+    Query(#[from] QueryError),
+}
+
+impl SqlError {
+    pub(crate) fn syntax(position: usize, message: impl ToString) -> Self {
+        SqlError::Syntax {
+            position,
+            message: message.to_string(),
+        }
+    }
+}
+
+/// A `SELECT` statement
+#[derive(Debug, Clone, PartialEq)]
+pub struct Select {
+    pub items: Vec<SelectItem>,
+    pub from: IndexRef,
+    pub joins: Vec<Join>,
+    pub filter: Option<Expr>,
+    pub group_by: Vec<Column>,
+    pub order_by: Vec<OrderBy>,
+    pub limit: Option<usize>,
+    pub offset: usize,
+}
+
+/// What a statement selects
+#[derive(Debug, Clone, PartialEq)]
+pub enum SelectItem {
+    /// Every field of every index
+    Wildcard,
+    Column {
+        column: Column,
+        alias: Option<String>,
+    },
+    /// A metric of the rows of a group, over a column unless it counts rows
+    Aggregate {
+        metric: Metric,
+        column: Option<Column>,
+        alias: Option<String>,
+    },
+}
+
+/// A column, named by the parts of its name between dots
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Column(pub Vec<String>);
+
+impl Display for Column {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0.join("."))
+    }
+}
+
+/// An index selected from, named by its alias within the statement if it has one
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexRef {
+    pub index: String,
+    pub alias: Option<String>,
+}
+
+impl IndexRef {
+    /// Gets the name of the index within the statement
+    pub fn name(&self) -> &str {
+        self.alias.as_deref().unwrap_or(&self.index)
+    }
+}
+
+/// Joins the rows selected so far with the documents of an index holding equal values
+#[derive(Debug, Clone, PartialEq)]
+pub struct Join {
+    pub index: IndexRef,
+    /// Whether rows without a matching document are kept, without values for the index
+    pub left: bool,
+    pub on: (Column, Column),
+}
+
+/// A condition rows must meet
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    And(Vec<Expr>),
+    Or(Vec<Expr>),
+    Not(Box<Expr>),
+    Compare {
+        column: Column,
+        op: CompareOp,
+        value: String,
+    },
+    In {
+        column: Column,
+        values: Vec<String>,
+        negated: bool,
+    },
+    Between {
+        column: Column,
+        low: String,
+        high: String,
+        negated: bool,
+    },
+    IsNull {
+        column: Column,
+        negated: bool,
+    },
+    /// Matches the terms of a text, like a search
+    Match {
+        column: Column,
+        text: String,
+    },
+}
+
+impl Expr {
+    /// Gets the column the condition is on, if it is not made of other conditions
+    pub fn column(&self) -> Option<&Column> {
+        match self {
+            Expr::And(_) | Expr::Or(_) | Expr::Not(_) => None,
+            Expr::Compare { column, .. }
+            | Expr::In { column, .. }
+            | Expr::Between { column, .. }
+            | Expr::IsNull { column, .. }
+            | Expr::Match { column, .. } => Some(column),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompareOp {
+    Eq,
+    NotEq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+}
+
+/// Orders rows by a column, or by what the statement selects at a position counting from 1
+#[derive(Debug, Clone, PartialEq)]
+pub struct OrderBy {
+    pub key: OrderKey,
+    pub descending: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OrderKey {
+    /// A column, or the alias of something selected
+    Column(Column),
+    Position(usize),
+}
+
+/// The indexes a statement can select from
+pub trait Catalog {
+    type Error: From<SqlError>;
+
+    /// Gets the schema of an index
+    fn schema(&self, index: &str) -> Result<Schema, Self::Error>;
+
+    /// Gets the documents of an index matching a query, or only the first `limit` of them if the
+    /// statement selects no more than that
+    fn documents(
+        &self,
+        index: &str,
+        query: &Query,
+        limit: Option<usize>,
+    ) -> Result<Vec<Document>, Self::Error>;
+}
+
+/// The rows selected by a statement
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Table {
+    pub columns: Vec<String>,
+    /// The value of every column of every row, or nothing where the row has no value
+    pub rows: Vec<Vec<Option<FieldData>>>,
+}
@@ -0,0 +1,928 @@
+//! Runs statements against the indexes of a catalog. The documents of every index are found by
+//! a query made of the conditions on that index alone, then joined with hash joins. Conditions on
+//! several indexes, or on indexes joined with `LEFT JOIN`, are checked once the rows are joined,
+//! since rows without a document for such an index must still meet them.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::ops::Bound;
+
+use crate::aggregation::{compare_values, Bucket, Grouping, Metric};
+use crate::document::Document;
+use crate::fields::{FieldData, Fields};
+use crate::query::{comparable, Query};
+use crate::schema::Schema;
+use crate::sql::{
+    parse, Catalog, Column, CompareOp, Expr, OrderKey, Select, SelectItem, SqlError, Table,
+};
+
+/// Parses a statement and runs it against the indexes of a catalog
+pub fn execute<C: Catalog>(catalog: &C, statement: &str) -> Result<Table, C::Error> {
+    let select = parse(statement)?;
+    let mut sources: Vec<Source> = vec![];
+    let indexes = std::iter::once((&select.from, false))
+        .chain(select.joins.iter().map(|join| (&join.index, join.left)));
+    for (index, left) in indexes {
+        if sources.iter().any(|source| source.name == index.name()) {
+            return Err(SqlError::DuplicateIndex(index.name().to_string()).into());
+        }
+        sources.push(Source {
+            name: index.name().to_string(),
+            index: index.index.clone(),
+            schema: catalog.schema(&index.index)?,
+            left,
+        });
+    }
+    let scope = Scope { sources };
+    let (pushed, residual) = split_filter(&scope, select.filter.as_ref())?;
+    // rows of a single index that are neither filtered, grouped nor ordered once they are found
+    // are selected in the order they are found, so no more than are selected need to be read
+    let limit = match select.joins.is_empty()
+        && residual.is_empty()
+        && select.group_by.is_empty()
+        && select.order_by.is_empty()
+        && select
+            .items
+            .iter()
+            .all(|item| !matches!(item, SelectItem::Aggregate { .. }))
+    {
+        true => select
+            .limit
+            .map(|limit| limit.saturating_add(select.offset)),
+        false => None,
+    };
+    let mut documents = vec![];
+    for (source, queries) in scope.sources.iter().zip(pushed) {
+        let query = match queries.len() {
+            0 => Query::All,
+            1 => queries.into_iter().next().expect("one query"),
+            _ => Query::And(queries),
+        };
+        documents.push(catalog.documents(&source.index, &query, limit)?);
+    }
+    Ok(run(&scope, &select, &documents, &residual)?)
+}
+
+/// An index selected from by a statement
+#[derive(Debug)]
+struct Source {
+    /// The name of the index within the statement
+    name: String,
+    index: String,
+    schema: Schema,
+    /// Whether the index was joined with `LEFT JOIN`
+    left: bool,
+}
+
+/// The indexes of a statement, which columns are looked up in
+#[derive(Debug)]
+struct Scope {
+    sources: Vec<Source>,
+}
+
+/// A field of an index of a statement
+#[derive(Debug, Clone, PartialEq)]
+struct ColumnRef {
+    source: usize,
+    field: String,
+}
+
+impl Scope {
+    /// Finds the index and field a column names
+    fn resolve(&self, column: &Column) -> Result<ColumnRef, SqlError> {
+        let parts = &column.0;
+        if parts.len() > 1 {
+            if let Some(source) = self.sources.iter().position(|s| s.name == parts[0]) {
+                let field = parts[1..].join(".");
+                return match self.sources[source].schema.get(&field) {
+                    Some(_) => Ok(ColumnRef { source, field }),
+                    None => Err(SqlError::UnknownColumn(column.clone())),
+                };
+            }
+        }
+        let field = parts.join(".");
+        let mut found = self
+            .sources
+            .iter()
+            .enumerate()
+            .filter(|(_, source)| source.schema.get(&field).is_some())
+            .map(|(source, _)| source);
+        match (found.next(), found.next()) {
+            (Some(source), None) => Ok(ColumnRef { source, field }),
+            (None, _) => Err(SqlError::UnknownColumn(column.clone())),
+            (Some(_), Some(_)) => Err(SqlError::AmbiguousColumn(column.clone())),
+        }
+    }
+}
+
+/// A condition lowered into queries on the documents of single indexes
+#[derive(Debug)]
+enum Condition {
+    Query { source: usize, query: Query },
+    And(Vec<Condition>),
+    Or(Vec<Condition>),
+    Not(Box<Condition>),
+}
+
+impl Condition {
+    fn lower(expr: &Expr, scope: &Scope) -> Result<Self, SqlError> {
+        let lower_all = |exprs: &[Expr]| -> Result<Vec<_>, SqlError> {
+            exprs.iter().map(|expr| Self::lower(expr, scope)).collect()
+        };
+        let column = match expr {
+            Expr::And(exprs) => return Ok(Condition::And(lower_all(exprs)?)),
+            Expr::Or(exprs) => return Ok(Condition::Or(lower_all(exprs)?)),
+            Expr::Not(expr) => return Ok(Condition::Not(Box::new(Self::lower(expr, scope)?))),
+            expr => scope.resolve(expr.column().expect("not made of other conditions"))?,
+        };
+        let field = column.field;
+        let term = |value: &String| Query::Term {
+            field: field.clone(),
+            value: value.clone(),
+        };
+        let range = |lower, upper| Query::Range {
+            field: field.clone(),
+            lower,
+            upper,
+        };
+        // missing values are never different from a value either
+        let differ = |query: Query| {
+            Query::And(vec![
+                Query::Exists {
+                    field: field.clone(),
+                },
+                Query::Not(Box::new(query)),
+            ])
+        };
+        let query = match expr {
+            Expr::Compare { op, value, .. } => {
+                let value = value.clone();
+                match op {
+                    CompareOp::Eq => term(&value),
+                    CompareOp::NotEq => differ(term(&value)),
+                    CompareOp::Lt => range(Bound::Unbounded, Bound::Excluded(value)),
+                    CompareOp::LtEq => range(Bound::Unbounded, Bound::Included(value)),
+                    CompareOp::Gt => range(Bound::Excluded(value), Bound::Unbounded),
+                    CompareOp::GtEq => range(Bound::Included(value), Bound::Unbounded),
+                }
+            }
+            Expr::In {
+                values, negated, ..
+            } => {
+                let query = Query::Or(values.iter().map(term).collect());
+                match negated {
+                    true => differ(query),
+                    false => query,
+                }
+            }
+            Expr::Between {
+                low, high, negated, ..
+            } => {
+                let query = range(Bound::Included(low.clone()), Bound::Included(high.clone()));
+                match negated {
+                    true => differ(query),
+                    false => query,
+                }
+            }
+            Expr::IsNull { negated, .. } => {
+                let exists = Query::Exists {
+                    field: field.clone(),
+                };
+                match negated {
+                    true => exists,
+                    false => Query::Not(Box::new(exists)),
+                }
+            }
+            Expr::Match { text, .. } => Query::Match {
+                field: field.clone(),
+                text: text.clone(),
+                all: true,
+            },
+            Expr::And(_) | Expr::Or(_) | Expr::Not(_) => unreachable!("lowered above"),
+        };
+        Ok(Condition::Query {
+            source: column.source,
+            query,
+        })
+    }
+
+    /// Gets the indexes the condition is on
+    fn sources(&self) -> Vec<usize> {
+        let mut sources = match self {
+            Condition::Query { source, .. } => vec![*source],
+            Condition::And(conditions) | Condition::Or(conditions) => {
+                conditions.iter().flat_map(Condition::sources).collect()
+            }
+            Condition::Not(condition) => condition.sources(),
+        };
+        sources.sort_unstable();
+        sources.dedup();
+        sources
+    }
+
+    /// Converts a condition on a single index into a query on its documents
+    fn into_query(self) -> Query {
+        match self {
+            Condition::Query { query, .. } => query,
+            Condition::And(conditions) => {
+                Query::And(conditions.into_iter().map(Condition::into_query).collect())
+            }
+            Condition::Or(conditions) => {
+                Query::Or(conditions.into_iter().map(Condition::into_query).collect())
+            }
+            Condition::Not(condition) => Query::Not(Box::new(condition.into_query())),
+        }
+    }
+
+    /// Checks whether a joined row meets the condition. Indexes without a document in the row
+    /// are checked as if they had an empty one.
+    fn holds(&self, scope: &Scope, row: &[Option<&Document>]) -> Result<bool, SqlError> {
+        Ok(match self {
+            Condition::Query { source, query } => {
+                let empty = Document::from(Fields::new());
+                let document = row[*source].unwrap_or(&empty);
+                query.matches(document, &scope.sources[*source].schema)?
+            }
+            Condition::And(conditions) => {
+                for condition in conditions {
+                    if !condition.holds(scope, row)? {
+                        return Ok(false);
+                    }
+                }
+                true
+            }
+            Condition::Or(conditions) => {
+                for condition in conditions {
+                    if condition.holds(scope, row)? {
+                        return Ok(true);
+                    }
+                }
+                false
+            }
+            Condition::Not(condition) => !condition.holds(scope, row)?,
+        })
+    }
+}
+
+/// Splits the conditions of a statement into the queries run by each index, and the conditions
+/// checked once rows are joined
+fn split_filter(
+    scope: &Scope,
+    filter: Option<&Expr>,
+) -> Result<(Vec<Vec<Query>>, Vec<Condition>), SqlError> {
+    let mut pushed = scope.sources.iter().map(|_| vec![]).collect::<Vec<_>>();
+    let mut residual = vec![];
+    let conjuncts = match filter {
+        Some(Expr::And(exprs)) => exprs.iter().collect(),
+        Some(expr) => vec![expr],
+        None => vec![],
+    };
+    for expr in conjuncts {
+        let condition = Condition::lower(expr, scope)?;
+        match condition.sources()[..] {
+            [source] if !scope.sources[source].left => pushed[source].push(condition.into_query()),
+            _ => residual.push(condition),
+        }
+    }
+    Ok((pushed, residual))
+}
+
+/// A value joined on. Integers join with equal floats, like they compare.
+#[derive(Debug, PartialEq, Eq, Hash)]
+enum JoinKey {
+    Integer(i128),
+    Float(u64),
+    Other(Vec<u8>),
+}
+
+impl JoinKey {
+    fn of(data: &FieldData) -> Self {
+        match comparable(data).as_ref() {
+            FieldData::I64(i) => JoinKey::Integer(i128::from(*i)),
+            FieldData::U64(u) => JoinKey::Integer(i128::from(*u)),
+            FieldData::SizeT(u) => JoinKey::Integer(*u as i128),
+            FieldData::F64(f) if f.fract() == 0.0 && f.abs() < 2f64.powi(63) => {
+                JoinKey::Integer(*f as i128)
+            }
+            FieldData::F64(f) => JoinKey::Float(f.to_bits()),
+            data => {
+                JoinKey::Other(postcard::to_stdvec(data).expect("values can always be encoded"))
+            }
+        }
+    }
+}
+
+/// A row of joined documents, by their position among the documents of every index
+type Row = Vec<Option<usize>>;
+
+/// The values of a row, or of what is selected from it
+type Values = Vec<Option<FieldData>>;
+
+/// Joins, groups, orders and selects the documents found for a statement
+fn run(
+    scope: &Scope,
+    select: &Select,
+    documents: &[Vec<Document>],
+    residual: &[Condition],
+) -> Result<Table, SqlError> {
+    let mut rows: Vec<Row> = (0..documents[0].len()).map(|doc| vec![Some(doc)]).collect();
+    for (joined, join) in select.joins.iter().enumerate() {
+        let source = joined + 1;
+        let (a, b) = (scope.resolve(&join.on.0)?, scope.resolve(&join.on.1)?);
+        let (this, other) = match (a.source == source, b.source == source) {
+            (true, false) if b.source < source => (a, b),
+            (false, true) if a.source < source => (b, a),
+            _ => {
+                return Err(SqlError::Unsupported(format!(
+                    "joining {} must compare one of its fields with a field of an index before it",
+                    join.index.name()
+                )))
+            }
+        };
+        let mut keyed = HashMap::<JoinKey, Vec<usize>>::new();
+        for (doc, document) in documents[source].iter().enumerate() {
+            for data in values(document, &this.field) {
+                let docs = keyed.entry(JoinKey::of(data)).or_default();
+                if docs.last() != Some(&doc) {
+                    docs.push(doc);
+                }
+            }
+        }
+        rows = rows
+            .into_iter()
+            .flat_map(|row| {
+                let mut matched = vec![];
+                if let Some(doc) = row[other.source] {
+                    for data in values(&documents[other.source][doc], &other.field) {
+                        for &doc in keyed.get(&JoinKey::of(data)).into_iter().flatten() {
+                            if !matched.contains(&Some(doc)) {
+                                matched.push(Some(doc));
+                            }
+                        }
+                    }
+                }
+                // rows without a match are kept by left joins, without a document
+                if matched.is_empty() && join.left {
+                    matched.push(None);
+                }
+                matched.into_iter().map(move |doc| {
+                    let mut row = row.clone();
+                    row.push(doc);
+                    row
+                })
+            })
+            .collect();
+    }
+    let documents_of = |row: &Row| -> Vec<Option<&Document>> {
+        row.iter()
+            .zip(documents)
+            .map(|(doc, documents)| doc.map(|doc| &documents[doc]))
+            .collect()
+    };
+    if !residual.is_empty() {
+        let mut kept = vec![];
+        for row in rows {
+            let documents = documents_of(&row);
+            let mut holds = true;
+            for condition in residual {
+                if !condition.holds(scope, &documents)? {
+                    holds = false;
+                    break;
+                }
+            }
+            if holds {
+                kept.push(row);
+            }
+        }
+        rows = kept;
+    }
+    let value = |row: &Row, column: &ColumnRef| -> Option<FieldData> {
+        let document = &documents[column.source][row[column.source]?];
+        let data = values(document, &column.field).first()?;
+        Some(comparable(data).into_owned())
+    };
+
+    let grouped = !select.group_by.is_empty()
+        || select
+            .items
+            .iter()
+            .any(|item| matches!(item, SelectItem::Aggregate { .. }));
+    let mut columns = vec![];
+    // every selected row, along with the values it is ordered by
+    let mut selected: Vec<(Values, Values)> = vec![];
+    if grouped {
+        let keys = select
+            .group_by
+            .iter()
+            .map(|column| scope.resolve(column))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut metrics = vec![];
+        let mut outputs = vec![];
+        for item in &select.items {
+            match item {
+                SelectItem::Wildcard => {
+                    return Err(SqlError::Unsupported(
+                        "* can not be selected along with groups".to_string(),
+                    ))
+                }
+                SelectItem::Column { column, alias } => {
+                    let resolved = scope.resolve(column)?;
+                    let key = keys
+                        .iter()
+                        .position(|key| *key == resolved)
+                        .ok_or_else(|| {
+                            SqlError::Unsupported(format!(
+                                "{column} must be grouped by or used in an aggregate"
+                            ))
+                        })?;
+                    columns.push(alias.clone().unwrap_or_else(|| column.to_string()));
+                    outputs.push(Output::Key(key));
+                }
+                SelectItem::Aggregate {
+                    metric,
+                    column,
+                    alias,
+                } => {
+                    let resolved = column.as_ref().map(|c| scope.resolve(c)).transpose()?;
+                    columns.push(alias.clone().unwrap_or_else(|| {
+                        let column = column.as_ref().map_or("*".to_string(), Column::to_string);
+                        format!("{}({column})", metric_name(*metric))
+                    }));
+                    outputs.push(Output::Metric(metrics.len()));
+                    metrics.push((*metric, resolved));
+                }
+            }
+        }
+        let sort_keys = select
+            .order_by
+            .iter()
+            .map(|order| match &order.key {
+                OrderKey::Position(position) => sort_output(&columns, *position),
+                OrderKey::Column(column) => match output_named(&columns, column) {
+                    Some(output) => Ok(SortKey::Output(output)),
+                    None => {
+                        let resolved = scope.resolve(column)?;
+                        keys.iter()
+                            .position(|key| *key == resolved)
+                            .map(SortKey::Value)
+                            .ok_or_else(|| {
+                                SqlError::Unsupported(format!(
+                                    "{column} must be grouped by to order groups by it"
+                                ))
+                            })
+                    }
+                },
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut grouping = Grouping::new(metrics.iter().map(|(metric, _)| *metric).collect());
+        for row in &rows {
+            let key = keys.iter().map(|key| value(row, key)).collect();
+            let values = metrics
+                .iter()
+                .map(|(_, column)| column.as_ref().and_then(|column| value(row, column)))
+                .collect::<Vec<_>>();
+            grouping.add(key, &values);
+        }
+        let mut buckets = grouping.finish();
+        // aggregating without groups always selects a single row
+        if keys.is_empty() && buckets.is_empty() {
+            buckets.push(Bucket {
+                key: vec![],
+                count: 0,
+                values: metrics
+                    .iter()
+                    .map(|(metric, _)| match metric {
+                        Metric::CountRows | Metric::Count | Metric::Cardinality { .. } => {
+                            Some(FieldData::U64(0))
+                        }
+                        _ => None,
+                    })
+                    .collect(),
+            });
+        }
+        for bucket in buckets {
+            let output = outputs
+                .iter()
+                .map(|output| match output {
+                    Output::Key(key) => bucket.key[*key].clone(),
+                    Output::Metric(metric) => bucket.values[*metric].clone(),
+                })
+                .collect::<Vec<_>>();
+            let sort = sort_keys
+                .iter()
+                .map(|key| match key {
+                    SortKey::Output(position) => output[*position].clone(),
+                    SortKey::Value(key) => bucket.key[*key].clone(),
+                    SortKey::Column(_) => unreachable!("groups are ordered by their keys"),
+                })
+                .collect();
+            selected.push((output, sort));
+        }
+    } else {
+        let mut outputs = vec![];
+        for item in &select.items {
+            match item {
+                SelectItem::Wildcard => {
+                    for (source, index) in scope.sources.iter().enumerate() {
+                        for field in index.schema.iter() {
+                            columns.push(match scope.sources.len() {
+                                1 => field.name.clone(),
+                                _ => format!("{}.{}", index.name, field.name),
+                            });
+                            outputs.push(ColumnRef {
+                                source,
+                                field: field.name.clone(),
+                            });
+                        }
+                    }
+                }
+                SelectItem::Column { column, alias } => {
+                    outputs.push(scope.resolve(column)?);
+                    columns.push(alias.clone().unwrap_or_else(|| column.to_string()));
+                }
+                SelectItem::Aggregate { .. } => unreachable!("statements with aggregates group"),
+            }
+        }
+        let sort_keys = select
+            .order_by
+            .iter()
+            .map(|order| match &order.key {
+                OrderKey::Position(position) => sort_output(&columns, *position),
+                OrderKey::Column(column) => match output_named(&columns, column) {
+                    Some(output) => Ok(SortKey::Output(output)),
+                    None => Ok(SortKey::Column(scope.resolve(column)?)),
+                },
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        for row in &rows {
+            let output = outputs
+                .iter()
+                .map(|column| value(row, column))
+                .collect::<Vec<_>>();
+            let sort = sort_keys
+                .iter()
+                .map(|key| match key {
+                    SortKey::Output(position) => output[*position].clone(),
+                    SortKey::Column(column) => value(row, column),
+                    SortKey::Value(_) => unreachable!("only groups are ordered by their keys"),
+                })
+                .collect();
+            selected.push((output, sort));
+        }
+    }
+
+    if !select.order_by.is_empty() {
+        selected.sort_by(|(_, a), (_, b)| {
+            a.iter()
+                .zip(b)
+                .zip(&select.order_by)
+                .map(|((a, b), order)| {
+                    let ordering = compare_values(a.as_ref(), b.as_ref());
+                    match order.descending {
+                        true => ordering.reverse(),
+                        false => ordering,
+                    }
+                })
+                .find(|ordering| *ordering != Ordering::Equal)
+                .unwrap_or(Ordering::Equal)
+        });
+    }
+    Ok(Table {
+        columns,
+        rows: selected
+            .into_iter()
+            .skip(select.offset)
+            .take(select.limit.unwrap_or(usize::MAX))
+            .map(|(output, _)| output)
+            .collect(),
+    })
+}
+
+/// Gets every value of a field of a document
+fn values<'a>(document: &'a Document, field: &str) -> &'a [FieldData] {
+    document.get(field).map_or(&[], |field| field.data())
+}
+
+/// What a statement with groups selects
+enum Output {
+    /// The value of a column grouped by, at its position among them
+    Key(usize),
+    /// A metric, at its position among them
+    Metric(usize),
+}
+
+/// What rows are ordered by
+enum SortKey {
+    /// Something selected, at its position among them
+    Output(usize),
+    /// A column of the joined rows
+    Column(ColumnRef),
+    /// The value of a column grouped by, at its position among them
+    Value(usize),
+}
+
+fn sort_output(columns: &[String], position: usize) -> Result<SortKey, SqlError> {
+    match position <= columns.len() {
+        true => Ok(SortKey::Output(position - 1)),
+        false => Err(SqlError::Unsupported(format!(
+            "ORDER BY {position} is past the {} selected columns",
+            columns.len()
+        ))),
+    }
+}
+
+/// Finds what is selected under the name of a column, such as an alias
+fn output_named(columns: &[String], column: &Column) -> Option<usize> {
+    let name = column.to_string();
+    columns.iter().position(|selected| *selected == name)
+}
+
+fn metric_name(metric: Metric) -> &'static str {
+    match metric {
+        Metric::CountRows | Metric::Count => "count",
+        Metric::Sum => "sum",
+        Metric::Avg => "avg",
+        Metric::Min => "min",
+        Metric::Max => "max",
+        Metric::Cardinality { .. } => "approx_count_distinct",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+    use crate::cancel::CancelToken;
+    use crate::fields::{Field, FieldKind};
+    use crate::index::{IndexWriter, UpsertMode};
+    use crate::persist::PersistentVec;
+    use crate::schema::SchemaField;
+
+    /// Indexes, along with how many documents were read from them
+    struct Indexes(HashMap<String, IndexWriter>, Cell<usize>);
+
+    impl Catalog for Indexes {
+        type Error = SqlError;
+
+        fn schema(&self, index: &str) -> Result<Schema, SqlError> {
+            Ok(self.0[index].schema().clone())
+        }
+
+        fn documents(
+            &self,
+            index: &str,
+            query: &Query,
+            limit: Option<usize>,
+        ) -> Result<Vec<Document>, SqlError> {
+            let index = &self.0[index];
+            let documents = query
+                .rows(index, &CancelToken::new())?
+                .into_iter()
+                .take(limit.unwrap_or(usize::MAX))
+                .map(|row| index.read(row).unwrap().unwrap())
+                .collect::<Vec<_>>();
+            self.1.set(self.1.get() + documents.len());
+            Ok(documents)
+        }
+    }
+
+    fn index(fields: &[(&str, FieldKind)], documents: &[&[(&str, &str)]]) -> IndexWriter {
+        let schema = Schema::from_iter(fields.iter().map(|(name, kind)| SchemaField {
+            name: name.to_string(),
+            kind: kind.clone(),
+        }))
+        .with_primary_key(fields[0].0);
+        let mut index = IndexWriter::new(schema.clone(), PersistentVec::in_memory());
+        for document in documents {
+            let fields = document
+                .iter()
+                .map(|(name, text)| {
+                    let kind = &schema.get(name).unwrap().kind;
+                    let data = crate::import::parse_text(name, kind, text).unwrap();
+                    (*name, Field::new(kind.clone(), [data]))
+                })
+                .collect::<Fields>();
+            index
+                .upsert(Document::from(fields), UpsertMode::Replace)
+                .unwrap();
+        }
+        index
+    }
+
+    fn catalog() -> Indexes {
+        let books = index(
+            &[
+                ("id", FieldKind::Keyword(16)),
+                ("title", FieldKind::Text(32)),
+                ("year", FieldKind::I64),
+                ("author_id", FieldKind::Keyword(16)),
+            ],
+            &[
+                &[
+                    ("id", "1"),
+                    ("title", "Dune"),
+                    ("year", "1965"),
+                    ("author_id", "herbert"),
+                ],
+                &[
+                    ("id", "2"),
+                    ("title", "Dune Messiah"),
+                    ("year", "1969"),
+                    ("author_id", "herbert"),
+                ],
+                &[
+                    ("id", "3"),
+                    ("title", "Emma"),
+                    ("year", "1815"),
+                    ("author_id", "austen"),
+                ],
+                &[("id", "4"), ("title", "Anonymous"), ("year", "1900")],
+            ],
+        );
+        let authors = index(
+            &[
+                ("id", FieldKind::Keyword(16)),
+                ("name", FieldKind::Text(32)),
+            ],
+            &[
+                &[("id", "herbert"), ("name", "Frank Herbert")],
+                &[("id", "austen"), ("name", "Jane Austen")],
+            ],
+        );
+        Indexes(
+            HashMap::from([
+                ("books".to_string(), books),
+                ("authors".to_string(), authors),
+            ]),
+            Cell::default(),
+        )
+    }
+
+    fn text(text: &str) -> Option<FieldData> {
+        Some(FieldData::Bytes(text.as_bytes().into()))
+    }
+
+    fn int(i: i64) -> Option<FieldData> {
+        Some(FieldData::I64(i))
+    }
+
+    #[test]
+    fn select_from_one_index() {
+        let catalog = catalog();
+        let table = execute(
+            &catalog,
+            "SELECT title, year AS published FROM books \
+             WHERE year > 1815 AND NOT MATCH(title, 'messiah') ORDER BY published DESC",
+        )
+        .unwrap();
+        assert_eq!(table.columns, ["title", "published"]);
+        assert_eq!(
+            table.rows,
+            [
+                vec![text("Dune"), int(1965)],
+                vec![text("Anonymous"), int(1900)]
+            ]
+        );
+
+        let table = execute(
+            &catalog,
+            "SELECT id FROM books WHERE author_id != 'herbert' OR year IN (1965) \
+             ORDER BY 1 LIMIT 2 OFFSET 1",
+        )
+        .unwrap();
+        assert_eq!(table.rows, [vec![text("3")]]);
+
+        let table = execute(&catalog, "SELECT * FROM books WHERE author_id IS NULL").unwrap();
+        assert_eq!(table.columns, ["id", "title", "year", "author_id"]);
+        assert_eq!(
+            table.rows,
+            [vec![text("4"), text("Anonymous"), int(1900), None]]
+        );
+    }
+
+    #[test]
+    fn read_no_more_documents_than_are_selected() {
+        let catalog = catalog();
+        let table = execute(&catalog, "SELECT id FROM books LIMIT 1 OFFSET 1").unwrap();
+        assert_eq!(table.rows.len(), 1);
+        assert_eq!(catalog.1.get(), 2);
+
+        catalog.1.set(0);
+        let table = execute(&catalog, "SELECT id FROM books ORDER BY year LIMIT 1").unwrap();
+        assert_eq!(table.rows, [vec![text("3")]]);
+        assert_eq!(catalog.1.get(), 4);
+    }
+
+    #[test]
+    fn join_indexes() {
+        let catalog = catalog();
+        let table = execute(
+            &catalog,
+            "SELECT b.title, a.name FROM books b JOIN authors a ON a.id = b.author_id \
+             WHERE MATCH(a.name, 'frank') ORDER BY year",
+        )
+        .unwrap();
+        assert_eq!(
+            table.rows,
+            [
+                vec![text("Dune"), text("Frank Herbert")],
+                vec![text("Dune Messiah"), text("Frank Herbert")]
+            ]
+        );
+
+        let table = execute(
+            &catalog,
+            "SELECT title, name FROM books LEFT JOIN authors a ON author_id = a.id \
+             WHERE name IS NULL OR year < 1900",
+        )
+        .unwrap();
+        assert_eq!(
+            table.rows,
+            [
+                vec![text("Emma"), text("Jane Austen")],
+                vec![text("Anonymous"), None]
+            ]
+        );
+    }
+
+    #[test]
+    fn group_rows() {
+        let catalog = catalog();
+        let table = execute(
+            &catalog,
+            "SELECT a.name, COUNT(*) AS books, MIN(b.year), AVG(year) FROM books b \
+             JOIN authors a ON b.author_id = a.id GROUP BY a.name ORDER BY books DESC",
+        )
+        .unwrap();
+        assert_eq!(
+            table.columns,
+            ["a.name", "books", "min(b.year)", "avg(year)"]
+        );
+        assert_eq!(
+            table.rows,
+            [
+                vec![
+                    text("Frank Herbert"),
+                    Some(FieldData::U64(2)),
+                    int(1965),
+                    Some(FieldData::F64(1967.0))
+                ],
+                vec![
+                    text("Jane Austen"),
+                    Some(FieldData::U64(1)),
+                    int(1815),
+                    Some(FieldData::F64(1815.0))
+                ],
+            ]
+        );
+
+        let table = execute(
+            &catalog,
+            "SELECT COUNT(author_id), MAX(year) FROM books WHERE year > 2000",
+        )
+        .unwrap();
+        assert_eq!(table.rows, [vec![Some(FieldData::U64(0)), None]]);
+
+        let table = execute(
+            &catalog,
+            "SELECT APPROX_COUNT_DISTINCT(author_id), APPROX_COUNT_DISTINCT(year, 10) FROM books",
+        )
+        .unwrap();
+        assert_eq!(
+            table.columns,
+            [
+                "approx_count_distinct(author_id)",
+                "approx_count_distinct(year)"
+            ]
+        );
+        assert_eq!(
+            table.rows,
+            [vec![Some(FieldData::U64(2)), Some(FieldData::U64(4))]]
+        );
+    }
+
+    #[test]
+    fn reject_invalid_statements() {
+        let catalog = catalog();
+        let error = |statement: &str| execute(&catalog, statement).unwrap_err().to_string();
+        assert_eq!(error("SELECT pages FROM books"), "unknown column pages");
+        assert_eq!(
+            error("SELECT id FROM books JOIN authors ON author_id = authors.id"),
+            "column id is ambiguous, qualify it with the name of its index"
+        );
+        assert_eq!(
+            error("SELECT title, COUNT(*) FROM books"),
+            "unsupported: title must be grouped by or used in an aggregate"
+        );
+        assert_eq!(
+            error("SELECT * FROM books JOIN books ON id = id"),
+            "the index \"books\" is named more than once, give it an alias"
+        );
+        assert_eq!(
+            error("SELECT title FROM books WHERE year = 'long ago'"),
+            "field \"year\" can not hold \"long ago\""
+        );
+    }
+}
@@ -0,0 +1,109 @@
+//! Splits sql statements into tokens
+
+use crate::sql::SqlError;
+
+/// A token of a sql statement
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Token {
+    /// A name or keyword, which are told apart by the parser
+    Word(String),
+    /// A name quoted with double quotes or backticks, which is never a keyword
+    Quoted(String),
+    /// A string quoted with single quotes
+    String(String),
+    Number(String),
+    Symbol(&'static str),
+}
+
+/// Symbols, with the longer ones first so they are preferred
+const SYMBOLS: [&str; 14] = [
+    "<=", ">=", "<>", "!=", "=", "<", ">", "(", ")", ",", ".", "*", "-", ";",
+];
+
+/// Splits a statement into tokens, along with the byte offset each one starts at
+pub(crate) fn tokenize(statement: &str) -> Result<Vec<(Token, usize)>, SqlError> {
+    let mut tokens = vec![];
+    let mut chars = statement.char_indices().peekable();
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+        let token = if c.is_alphabetic() || c == '_' {
+            let mut word = String::new();
+            while let Some((_, c)) = chars.next_if(|(_, c)| c.is_alphanumeric() || *c == '_') {
+                word.push(c);
+            }
+            Token::Word(word)
+        } else if c.is_ascii_digit() {
+            let mut number = String::new();
+            while let Some((_, c)) = chars.next_if(|(_, c)| c.is_ascii_digit() || *c == '.') {
+                number.push(c);
+            }
+            Token::Number(number)
+        } else if matches!(c, '\'' | '"' | '`') {
+            chars.next();
+            let mut text = String::new();
+            loop {
+                match chars.next() {
+                    // quotes are escaped by doubling them
+                    Some((_, quote)) if quote == c => match chars.next_if(|&(_, next)| next == c) {
+                        Some(_) => text.push(c),
+                        None => break,
+                    },
+                    Some((_, c)) => text.push(c),
+                    None => return Err(SqlError::syntax(start, "unterminated quote")),
+                }
+            }
+            match c {
+                '\'' => Token::String(text),
+                _ => Token::Quoted(text),
+            }
+        } else {
+            let symbol = SYMBOLS
+                .into_iter()
+                .find(|symbol| statement[start..].starts_with(symbol))
+                .ok_or_else(|| SqlError::syntax(start, format!("unexpected character {c:?}")))?;
+            for _ in symbol.chars() {
+                chars.next();
+            }
+            Token::Symbol(symbol)
+        };
+        tokens.push((token, start));
+    }
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokenize_statement() {
+        let tokens = tokenize("SELECT \"a b\".c, 'it''s' FROM t WHERE x >= -1.5")
+            .unwrap()
+            .into_iter()
+            .map(|(token, _)| token)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            tokens,
+            [
+                Token::Word("SELECT".to_string()),
+                Token::Quoted("a b".to_string()),
+                Token::Symbol("."),
+                Token::Word("c".to_string()),
+                Token::Symbol(","),
+                Token::String("it's".to_string()),
+                Token::Word("FROM".to_string()),
+                Token::Word("t".to_string()),
+                Token::Word("WHERE".to_string()),
+                Token::Word("x".to_string()),
+                Token::Symbol(">="),
+                Token::Symbol("-"),
+                Token::Number("1.5".to_string()),
+            ]
+        );
+        let error = tokenize("SELECT 'open").unwrap_err();
+        assert_eq!(error.to_string(), "syntax error at 7: unterminated quote");
+    }
+}
@@ -0,0 +1,559 @@
+//! Parses sql statements by recursive descent
+
+use crate::aggregation::{Metric, DEFAULT_PRECISION, MAX_PRECISION, MIN_PRECISION};
+use crate::sql::lexer::{tokenize, Token};
+use crate::sql::{
+    Column, CompareOp, Expr, IndexRef, Join, OrderBy, OrderKey, Select, SelectItem, SqlError,
+};
+
+/// Words that can not be used as names without quoting them
+const KEYWORDS: [&str; 26] = [
+    "SELECT", "FROM", "WHERE", "GROUP", "BY", "ORDER", "ASC", "DESC", "LIMIT", "OFFSET", "JOIN",
+    "INNER", "LEFT", "OUTER", "ON", "AS", "AND", "OR", "NOT", "IN", "BETWEEN", "IS", "NULL",
+    "MATCH", "TRUE", "FALSE",
+];
+
+/// How deeply parentheses and negations may nest, so deep statements can not overflow the stack
+const MAX_DEPTH: usize = 64;
+
+/// Parses a `SELECT` statement
+pub fn parse(statement: &str) -> Result<Select, SqlError> {
+    let mut parser = Parser {
+        tokens: tokenize(statement)?,
+        position: 0,
+        end: statement.len(),
+        depth: 0,
+    };
+    let select = parser.select()?;
+    parser.eat_symbol(";");
+    match parser.peek() {
+        None => Ok(select),
+        Some(_) => Err(parser.error("expected the end of the statement")),
+    }
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    position: usize,
+    /// The length of the statement, where errors at its end are reported
+    end: usize,
+    /// How many parentheses and negations the parser is within
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(token, _)| token)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.peek().cloned();
+        self.position += 1;
+        token
+    }
+
+    fn error(&self, message: impl ToString) -> SqlError {
+        let position = self
+            .tokens
+            .get(self.position)
+            .map_or(self.end, |&(_, position)| position);
+        SqlError::syntax(position, message)
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Word(word)) if word.eq_ignore_ascii_case(keyword))
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        let found = self.is_keyword(keyword);
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn expect_keyword(&mut self, keyword: &str) -> Result<(), SqlError> {
+        match self.eat_keyword(keyword) {
+            true => Ok(()),
+            false => Err(self.error(format!("expected {keyword}"))),
+        }
+    }
+
+    fn eat_symbol(&mut self, symbol: &str) -> bool {
+        let found = matches!(self.peek(), Some(Token::Symbol(found)) if *found == symbol);
+        if found {
+            self.position += 1;
+        }
+        found
+    }
+
+    fn expect_symbol(&mut self, symbol: &str) -> Result<(), SqlError> {
+        match self.eat_symbol(symbol) {
+            true => Ok(()),
+            false => Err(self.error(format!("expected {symbol:?}"))),
+        }
+    }
+
+    /// Parses a name that is not a keyword, unless it is quoted
+    fn name(&mut self) -> Result<String, SqlError> {
+        match self.peek() {
+            Some(Token::Word(word))
+                if !KEYWORDS
+                    .iter()
+                    .any(|keyword| word.eq_ignore_ascii_case(keyword)) =>
+            {
+                let word = word.clone();
+                self.position += 1;
+                Ok(word)
+            }
+            Some(Token::Quoted(name)) => {
+                let name = name.clone();
+                self.position += 1;
+                Ok(name)
+            }
+            _ => Err(self.error("expected a name")),
+        }
+    }
+
+    /// Parses an optional alias, with or without `AS`
+    fn alias(&mut self) -> Result<Option<String>, SqlError> {
+        if self.eat_keyword("AS") {
+            return self.name().map(Some);
+        }
+        Ok(self.name().ok())
+    }
+
+    fn column(&mut self) -> Result<Column, SqlError> {
+        let mut parts = vec![self.name()?];
+        while self.eat_symbol(".") {
+            parts.push(self.name()?);
+        }
+        Ok(Column(parts))
+    }
+
+    fn number(&mut self) -> Result<usize, SqlError> {
+        match self.peek() {
+            Some(Token::Number(number)) => {
+                let number = number
+                    .parse()
+                    .map_err(|_| self.error("expected an integer"))?;
+                self.position += 1;
+                Ok(number)
+            }
+            _ => Err(self.error("expected an integer")),
+        }
+    }
+
+    /// Parses a literal value, which is given to queries as text
+    fn literal(&mut self) -> Result<String, SqlError> {
+        let negative = self.eat_symbol("-");
+        let literal = match self.next() {
+            Some(Token::Number(number)) if negative => format!("-{number}"),
+            Some(Token::Number(number)) => number,
+            Some(Token::String(text)) if !negative => text,
+            Some(Token::Word(word))
+                if !negative
+                    && (word.eq_ignore_ascii_case("TRUE")
+                        || word.eq_ignore_ascii_case("FALSE")) =>
+            {
+                word.to_lowercase()
+            }
+            _ => {
+                self.position -= 1;
+                return Err(self.error("expected a value"));
+            }
+        };
+        Ok(literal)
+    }
+
+    fn select(&mut self) -> Result<Select, SqlError> {
+        self.expect_keyword("SELECT")?;
+        let mut items = vec![self.select_item()?];
+        while self.eat_symbol(",") {
+            items.push(self.select_item()?);
+        }
+        self.expect_keyword("FROM")?;
+        let from = self.index_ref()?;
+        let mut joins = vec![];
+        loop {
+            let left = if self.eat_keyword("LEFT") {
+                self.eat_keyword("OUTER");
+                true
+            } else {
+                self.eat_keyword("INNER");
+                false
+            };
+            if !self.eat_keyword("JOIN") {
+                if left {
+                    return Err(self.error("expected JOIN"));
+                }
+                break;
+            }
+            let index = self.index_ref()?;
+            self.expect_keyword("ON")?;
+            let column = self.column()?;
+            self.expect_symbol("=")?;
+            let other = self.column()?;
+            joins.push(Join {
+                index,
+                left,
+                on: (column, other),
+            });
+        }
+        let filter = match self.eat_keyword("WHERE") {
+            true => Some(self.or()?),
+            false => None,
+        };
+        let mut group_by = vec![];
+        if self.eat_keyword("GROUP") {
+            self.expect_keyword("BY")?;
+            group_by.push(self.column()?);
+            while self.eat_symbol(",") {
+                group_by.push(self.column()?);
+            }
+        }
+        let mut order_by = vec![];
+        if self.eat_keyword("ORDER") {
+            self.expect_keyword("BY")?;
+            loop {
+                let key = match self.peek() {
+                    Some(Token::Number(_)) => match self.number()? {
+                        0 => return Err(self.error("positions count from 1")),
+                        position => OrderKey::Position(position),
+                    },
+                    _ => OrderKey::Column(self.column()?),
+                };
+                let descending = self.eat_keyword("DESC");
+                if !descending {
+                    self.eat_keyword("ASC");
+                }
+                order_by.push(OrderBy { key, descending });
+                if !self.eat_symbol(",") {
+                    break;
+                }
+            }
+        }
+        let mut limit = None;
+        let mut offset = 0;
+        if self.eat_keyword("LIMIT") {
+            limit = Some(self.number()?);
+        }
+        if self.eat_keyword("OFFSET") {
+            offset = self.number()?;
+        }
+        Ok(Select {
+            items,
+            from,
+            joins,
+            filter,
+            group_by,
+            order_by,
+            limit,
+            offset,
+        })
+    }
+
+    fn select_item(&mut self) -> Result<SelectItem, SqlError> {
+        if self.eat_symbol("*") {
+            return Ok(SelectItem::Wildcard);
+        }
+        let metric = match self.peek() {
+            Some(Token::Word(word))
+                if matches!(
+                    self.tokens.get(self.position + 1),
+                    Some((Token::Symbol("("), _))
+                ) =>
+            {
+                match word.to_uppercase().as_str() {
+                    "COUNT" => Some(Metric::Count),
+                    "SUM" => Some(Metric::Sum),
+                    "AVG" => Some(Metric::Avg),
+                    "MIN" => Some(Metric::Min),
+                    "MAX" => Some(Metric::Max),
+                    "APPROX_COUNT_DISTINCT" => Some(Metric::Cardinality {
+                        precision: DEFAULT_PRECISION,
+                    }),
+                    _ => return Err(self.error(format!("unknown function {word}"))),
+                }
+            }
+            _ => None,
+        };
+        let Some(metric) = metric else {
+            let column = self.column()?;
+            return Ok(SelectItem::Column {
+                column,
+                alias: self.alias()?,
+            });
+        };
+        self.position += 2;
+        let (mut metric, column) = match metric == Metric::Count && self.eat_symbol("*") {
+            true => (Metric::CountRows, None),
+            false => (metric, Some(self.column()?)),
+        };
+        if let Metric::Cardinality { precision } = &mut metric {
+            if self.eat_symbol(",") {
+                let number = self.number()?;
+                if !(MIN_PRECISION as usize..=MAX_PRECISION as usize).contains(&number) {
+                    self.position -= 1;
+                    return Err(self.error(format!(
+                        "precision must be between {MIN_PRECISION} and {MAX_PRECISION}"
+                    )));
+                }
+                *precision = number as u8;
+            }
+        }
+        self.expect_symbol(")")?;
+        Ok(SelectItem::Aggregate {
+            metric,
+            column,
+            alias: self.alias()?,
+        })
+    }
+
+    fn index_ref(&mut self) -> Result<IndexRef, SqlError> {
+        let index = self.name()?;
+        Ok(IndexRef {
+            index,
+            alias: self.alias()?,
+        })
+    }
+
+    fn or(&mut self) -> Result<Expr, SqlError> {
+        let mut exprs = vec![self.and()?];
+        while self.eat_keyword("OR") {
+            exprs.push(self.and()?);
+        }
+        Ok(match exprs.len() {
+            1 => exprs.remove(0),
+            _ => Expr::Or(exprs),
+        })
+    }
+
+    fn and(&mut self) -> Result<Expr, SqlError> {
+        let mut exprs = vec![self.not()?];
+        while self.eat_keyword("AND") {
+            exprs.push(self.not()?);
+        }
+        Ok(match exprs.len() {
+            1 => exprs.remove(0),
+            _ => Expr::And(exprs),
+        })
+    }
+
+    fn not(&mut self) -> Result<Expr, SqlError> {
+        if self.eat_keyword("NOT") {
+            return Ok(Expr::Not(Box::new(self.nested(Self::not)?)));
+        }
+        self.predicate()
+    }
+
+    /// Parses an expression nested within parentheses or a negation
+    fn nested(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<Expr, SqlError>,
+    ) -> Result<Expr, SqlError> {
+        if self.depth == MAX_DEPTH {
+            return Err(self.error("expression nested too deeply"));
+        }
+        self.depth += 1;
+        let expr = parse(self);
+        self.depth -= 1;
+        expr
+    }
+
+    fn predicate(&mut self) -> Result<Expr, SqlError> {
+        if self.eat_symbol("(") {
+            let expr = self.nested(Self::or)?;
+            self.expect_symbol(")")?;
+            return Ok(expr);
+        }
+        if self.is_keyword("MATCH") {
+            self.position += 1;
+            self.expect_symbol("(")?;
+            let column = self.column()?;
+            self.expect_symbol(",")?;
+            let text = self.literal()?;
+            self.expect_symbol(")")?;
+            return Ok(Expr::Match { column, text });
+        }
+        let column = self.column()?;
+        if self.eat_keyword("IS") {
+            let negated = self.eat_keyword("NOT");
+            self.expect_keyword("NULL")?;
+            return Ok(Expr::IsNull { column, negated });
+        }
+        let negated = self.eat_keyword("NOT");
+        if self.eat_keyword("IN") {
+            self.expect_symbol("(")?;
+            let mut values = vec![self.literal()?];
+            while self.eat_symbol(",") {
+                values.push(self.literal()?);
+            }
+            self.expect_symbol(")")?;
+            return Ok(Expr::In {
+                column,
+                values,
+                negated,
+            });
+        }
+        if self.eat_keyword("BETWEEN") {
+            let low = self.literal()?;
+            self.expect_keyword("AND")?;
+            let high = self.literal()?;
+            return Ok(Expr::Between {
+                column,
+                low,
+                high,
+                negated,
+            });
+        }
+        if negated {
+            return Err(self.error("expected IN or BETWEEN"));
+        }
+        let op = match self.next() {
+            Some(Token::Symbol("=")) => CompareOp::Eq,
+            Some(Token::Symbol("!=" | "<>")) => CompareOp::NotEq,
+            Some(Token::Symbol("<")) => CompareOp::Lt,
+            Some(Token::Symbol("<=")) => CompareOp::LtEq,
+            Some(Token::Symbol(">")) => CompareOp::Gt,
+            Some(Token::Symbol(">=")) => CompareOp::GtEq,
+            _ => {
+                self.position -= 1;
+                return Err(self.error("expected a comparison"));
+            }
+        };
+        Ok(Expr::Compare {
+            column,
+            op,
+            value: self.literal()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn column(name: &str) -> Column {
+        Column(name.split('.').map(str::to_string).collect())
+    }
+
+    #[test]
+    fn parse_select() {
+        let select = parse(
+            "select b.title, count(*) as n, max(year) from books b \
+             left join authors a on b.author_id = a.id \
+             where year between 1900 and 2000 and not (a.name = 'x' or a.name is null) \
+             group by b.title order by 2 desc, b.title limit 5 offset 10;",
+        )
+        .unwrap();
+        assert_eq!(
+            select.items,
+            [
+                SelectItem::Column {
+                    column: column("b.title"),
+                    alias: None
+                },
+                SelectItem::Aggregate {
+                    metric: Metric::CountRows,
+                    column: None,
+                    alias: Some("n".to_string())
+                },
+                SelectItem::Aggregate {
+                    metric: Metric::Max,
+                    column: Some(column("year")),
+                    alias: None
+                },
+            ]
+        );
+        assert_eq!(select.from.name(), "b");
+        assert_eq!(
+            select.joins,
+            [Join {
+                index: IndexRef {
+                    index: "authors".to_string(),
+                    alias: Some("a".to_string())
+                },
+                left: true,
+                on: (column("b.author_id"), column("a.id")),
+            }]
+        );
+        assert_eq!(
+            select.filter,
+            Some(Expr::And(vec![
+                Expr::Between {
+                    column: column("year"),
+                    low: "1900".to_string(),
+                    high: "2000".to_string(),
+                    negated: false,
+                },
+                Expr::Not(Box::new(Expr::Or(vec![
+                    Expr::Compare {
+                        column: column("a.name"),
+                        op: CompareOp::Eq,
+                        value: "x".to_string(),
+                    },
+                    Expr::IsNull {
+                        column: column("a.name"),
+                        negated: false,
+                    },
+                ]))),
+            ]))
+        );
+        assert_eq!(select.group_by, [column("b.title")]);
+        assert_eq!(
+            select.order_by,
+            [
+                OrderBy {
+                    key: OrderKey::Position(2),
+                    descending: true
+                },
+                OrderBy {
+                    key: OrderKey::Column(column("b.title")),
+                    descending: false
+                },
+            ]
+        );
+        assert_eq!((select.limit, select.offset), (Some(5), 10));
+    }
+
+    #[test]
+    fn report_syntax_errors() {
+        let error = |statement: &str| parse(statement).unwrap_err().to_string();
+        assert_eq!(error("SELECT * books"), "syntax error at 9: expected FROM");
+        assert_eq!(
+            error("SELECT * FROM books WHERE"),
+            "syntax error at 25: expected a name"
+        );
+        assert_eq!(
+            error("SELECT * FROM books WHERE year IN (1, x)"),
+            "syntax error at 38: expected a value"
+        );
+        assert_eq!(
+            error("SELECT median(year) FROM books"),
+            "syntax error at 7: unknown function median"
+        );
+        assert_eq!(
+            error("SELECT APPROX_COUNT_DISTINCT(year, 2) FROM books"),
+            "syntax error at 35: precision must be between 4 and 18"
+        );
+        assert_eq!(
+            error(&format!(
+                "SELECT * FROM books WHERE {}a = 1",
+                "(".repeat(65)
+            )),
+            "syntax error at 91: expression nested too deeply"
+        );
+        assert_eq!(
+            error(&format!(
+                "SELECT * FROM books WHERE {}a = 1",
+                "NOT ".repeat(65)
+            )),
+            "syntax error at 286: expression nested too deeply"
+        );
+        assert_eq!(
+            error("SELECT * FROM books LIMIT 1 2"),
+            "syntax error at 28: expected the end of the statement"
+        );
+    }
+}
@@ -0,0 +1,189 @@
+//! Compression of packet payloads.
+//!
+//! When a connection uses compression, every payload starts with a single byte telling whether
+//! the rest of it is compressed. Payloads smaller than the negotiated threshold are sent as is,
+//! since compressing them costs more than it saves.
+
+use std::borrow::Cow;
+use std::io::Read;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::transport::frame::MAX_PAYLOAD_SIZE;
+use crate::transport::wire::{WireCodec, WireError};
+
+/// The default size, in bytes, below which payloads are sent uncompressed
+pub const DEFAULT_COMPRESSION_THRESHOLD: usize = 1024;
+
+const RAW: u8 = 0;
+const COMPRESSED: u8 = 1;
+
+/// The zstd level used when compressing payloads
+const ZSTD_LEVEL: i32 = 3;
+
+/// How payloads are compressed after being serialized
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl Compression {
+    /// Every supported compression, in order of preference
+    pub const ALL: [Compression; 3] = [Compression::Zstd, Compression::Lz4, Compression::None];
+
+    /// Compresses a payload
+    pub fn compress(&self, payload: &[u8]) -> Result<Vec<u8>, WireError> {
+        match self {
+            Compression::None => Ok(payload.to_vec()),
+            Compression::Lz4 => Ok(lz4_flex::compress_prepend_size(payload)),
+            Compression::Zstd => {
+                zstd::bulk::compress(payload, ZSTD_LEVEL).map_err(WireError::CompressionError)
+            }
+        }
+    }
+
+    /// Decompresses a payload, which may not decompress to more than [`MAX_PAYLOAD_SIZE`] bytes
+    pub fn decompress<'a>(&self, payload: &'a [u8]) -> Result<Cow<'a, [u8]>, WireError> {
+        match self {
+            Compression::None => Ok(payload.into()),
+            Compression::Lz4 => {
+                let (size, compressed) = lz4_flex::block::uncompressed_size(payload)?;
+                if size > MAX_PAYLOAD_SIZE {
+                    return Err(WireError::DecompressedOversized);
+                }
+                Ok(lz4_flex::block::decompress(compressed, size)?.into())
+            }
+            Compression::Zstd => {
+                let decoder = zstd::Decoder::new(payload).map_err(WireError::CompressionError)?;
+                let mut decompressed = vec![];
+                decoder
+                    .take(MAX_PAYLOAD_SIZE as u64 + 1)
+                    .read_to_end(&mut decompressed)
+                    .map_err(WireError::CompressionError)?;
+                if decompressed.len() > MAX_PAYLOAD_SIZE {
+                    return Err(WireError::DecompressedOversized);
+                }
+                Ok(decompressed.into())
+            }
+        }
+    }
+}
+
+/// Compresses the values serialized by another codec
+#[derive(Debug, Clone, Copy)]
+pub struct Compressed<C> {
+    codec: C,
+    compression: Compression,
+    threshold: usize,
+}
+
+impl<C: WireCodec> Compressed<C> {
+    /// Creates a codec compressing the values serialized by another codec
+    pub fn new(codec: C, compression: Compression) -> Self {
+        Self {
+            codec,
+            compression,
+            threshold: DEFAULT_COMPRESSION_THRESHOLD,
+        }
+    }
+
+    /// Sets the size, in bytes, below which payloads are sent uncompressed
+    pub fn with_threshold(mut self, threshold: usize) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Gets the compression used by this codec
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+}
+
+impl<C: WireCodec> WireCodec for Compressed<C> {
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>, WireError> {
+        let payload = self.codec.encode(value)?;
+        if self.compression == Compression::None {
+            return Ok(payload);
+        }
+        if payload.len() < self.threshold {
+            let mut framed = Vec::with_capacity(payload.len() + 1);
+            framed.push(RAW);
+            framed.extend_from_slice(&payload);
+            return Ok(framed);
+        }
+        let mut framed = vec![COMPRESSED];
+        framed.extend_from_slice(&self.compression.compress(&payload)?);
+        Ok(framed)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, WireError> {
+        if self.compression == Compression::None {
+            return self.codec.decode(bytes);
+        }
+        match bytes.split_first() {
+            Some((&RAW, payload)) => self.codec.decode(payload),
+            Some((&COMPRESSED, payload)) => {
+                self.codec.decode(&self.compression.decompress(payload)?)
+            }
+            _ => Err(WireError::InvalidCompressionHeader),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::wire::Postcard;
+
+    #[test]
+    fn compresses_above_threshold() {
+        let small = String::from("hello");
+        let large = "hello ".repeat(1000);
+        for compression in Compression::ALL {
+            let codec = Compressed::new(Postcard, compression).with_threshold(64);
+            for value in [&small, &large] {
+                let bytes = codec.encode(value).unwrap();
+                assert_eq!(&codec.decode::<String>(&bytes).unwrap(), value);
+            }
+            let small_bytes = codec.encode(&small).unwrap();
+            let large_bytes = codec.encode(&large).unwrap();
+            match compression {
+                Compression::None => assert_eq!(large_bytes.len(), large.len() + 2),
+                _ => {
+                    assert_eq!(small_bytes[0], RAW);
+                    assert_eq!(large_bytes[0], COMPRESSED);
+                    assert!(large_bytes.len() < large.len() / 10);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn refuses_to_decompress_bombs() {
+        let bomb = vec![0; MAX_PAYLOAD_SIZE + 1];
+        for compression in [Compression::Lz4, Compression::Zstd] {
+            let compressed = compression.compress(&bomb).unwrap();
+            assert!(compressed.len() < bomb.len() / 100);
+            assert!(matches!(
+                compression.decompress(&compressed),
+                Err(WireError::DecompressedOversized)
+            ));
+            let fits = compression.compress(&bomb[1..]).unwrap();
+            assert_eq!(
+                compression.decompress(&fits).unwrap().len(),
+                MAX_PAYLOAD_SIZE
+            );
+        }
+        // the size lz4 prepends is checked before anything is decompressed
+        let mut lied = u32::MAX.to_le_bytes().to_vec();
+        lied.extend_from_slice(&[0; 16]);
+        assert!(matches!(
+            Compression::Lz4.decompress(&lied),
+            Err(WireError::DecompressedOversized)
+        ));
+    }
+}
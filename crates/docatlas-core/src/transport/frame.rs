@@ -0,0 +1,166 @@
+//! The wire framing shared by every packet transport.
+//!
+//! Every frame is a header followed by its payload. All header values are big endian.
+//!
+//! | bytes | value                          |
+//! |-------|--------------------------------|
+//! | 8     | length of the payload          |
+//! | 2     | protocol version               |
+//! | 4     | crc32 checksum of the payload  |
+
+use std::io;
+
+use thiserror::Error;
+
+/// The version of the protocol written into every frame
+pub const PROTOCOL_VERSION: u16 = 1;
+
+/// The size of a frame header, in bytes
+pub const HEADER_SIZE: usize = 14;
+
+/// The largest payload that can be sent or received, in bytes
+pub const MAX_PAYLOAD_SIZE: usize = 64 * 1024 * 1024;
+
+/// The header of a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    len: usize,
+    version: u16,
+    checksum: u32,
+}
+
+impl FrameHeader {
+    /// Creates the header for a payload
+    pub fn new(payload: &[u8]) -> Result<Self, FrameError> {
+        if payload.len() > MAX_PAYLOAD_SIZE {
+            return Err(FrameError::Oversized(payload.len()));
+        }
+        Ok(Self {
+            len: payload.len(),
+            version: PROTOCOL_VERSION,
+            checksum: crc32fast::hash(payload),
+        })
+    }
+
+    /// Reads a header, checking its version and length
+    pub fn read(header: &[u8; HEADER_SIZE]) -> Result<Self, FrameError> {
+        let len = u64::from_be_bytes(header[..8].try_into().expect("8 bytes"));
+        let version = u16::from_be_bytes([header[8], header[9]]);
+        let checksum = u32::from_be_bytes(header[10..].try_into().expect("4 bytes"));
+        if version != PROTOCOL_VERSION {
+            return Err(FrameError::UnsupportedVersion(version));
+        }
+        let len = usize::try_from(len)
+            .ok()
+            .filter(|&len| len <= MAX_PAYLOAD_SIZE)
+            .ok_or(FrameError::Oversized(len as usize))?;
+        Ok(Self {
+            len,
+            version,
+            checksum,
+        })
+    }
+
+    /// Writes this header
+    pub fn write(&self) -> [u8; HEADER_SIZE] {
+        let mut header = [0_u8; HEADER_SIZE];
+        header[..8].copy_from_slice(&(self.len as u64).to_be_bytes());
+        header[8..10].copy_from_slice(&self.version.to_be_bytes());
+        header[10..].copy_from_slice(&self.checksum.to_be_bytes());
+        header
+    }
+
+    /// Gets the length of the payload
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Checks if the payload is empty
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Gets the protocol version of the frame
+    pub fn version(&self) -> u16 {
+        self.version
+    }
+
+    /// Checks that a payload matches this header
+    pub fn verify(&self, payload: &[u8]) -> Result<(), FrameError> {
+        if payload.len() != self.len {
+            return Err(FrameError::Truncated {
+                expected: HEADER_SIZE + self.len,
+                actual: HEADER_SIZE + payload.len(),
+            });
+        }
+        let actual = crc32fast::hash(payload);
+        if actual != self.checksum {
+            return Err(FrameError::Corrupt {
+                expected: self.checksum,
+                actual,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// A frame could not be read or written
+#[derive(Debug, Error)]
+pub enum FrameError {
+    #[error("Frame was truncated after {actual} of {expected} bytes")]
+    Truncated { expected: usize, actual: usize },
+    #[error("Payload of {0} bytes exceeds the maximum of {MAX_PAYLOAD_SIZE} bytes")]
+    Oversized(usize),
+    #[error("Frame checksum {actual:#010x} does not match {expected:#010x}")]
+    Corrupt { expected: u32, actual: u32 },
+    #[error("Unsupported protocol version {0}, expected {PROTOCOL_VERSION}")]
+    UnsupportedVersion(u16),
+}
+
+impl From<FrameError> for io::Error {
+    fn from(value: FrameError) -> Self {
+        let kind = match value {
+            FrameError::Truncated { .. } => io::ErrorKind::UnexpectedEof,
+            _ => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn header_round_trips() {
+        let header = FrameHeader::new(b"hello").unwrap();
+        let read = FrameHeader::read(&header.write()).unwrap();
+        assert_eq!(read, header);
+        assert!(read.verify(b"hello").is_ok());
+        assert!(matches!(
+            read.verify(b"hellp"),
+            Err(FrameError::Corrupt { .. })
+        ));
+        assert!(matches!(
+            read.verify(b"hel"),
+            Err(FrameError::Truncated { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let mut header = FrameHeader::new(b"hello").unwrap().write();
+        header[9] = 7;
+        assert!(matches!(
+            FrameHeader::read(&header),
+            Err(FrameError::UnsupportedVersion(7))
+        ));
+
+        let mut header = FrameHeader::new(b"hello").unwrap().write();
+        header[..8].copy_from_slice(&u64::MAX.to_be_bytes());
+        assert!(matches!(
+            FrameHeader::read(&header),
+            Err(FrameError::Oversized(_))
+        ));
+    }
+}
@@ -0,0 +1,353 @@
+//! The handshake performed when a client connects to a server.
+//!
+//! The client sends a [`ClientHello`](ClientHello) with its protocol version and the wire formats
+//! and compression it supports, in order of preference. The server either answers with a
+//! [`ServerHello`](ServerHello) containing the negotiated settings, its keepalive settings,
+//! authentication requirements and build info, or rejects the connection. Handshake packets are always
+//! serialized with postcard and never compressed.
+
+use std::fmt::{Display, Formatter};
+use std::io;
+
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::transport::compression::{Compressed, Compression, DEFAULT_COMPRESSION_THRESHOLD};
+use crate::transport::keepalive::KeepaliveConfig;
+use crate::transport::wire::{Postcard, WireCodec, WireError, WireFormat};
+use crate::transport::Transport;
+
+/// A version of the protocol. Versions with different major versions are incompatible.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct ProtocolVersion {
+    pub major: u16,
+    pub minor: u16,
+}
+
+impl ProtocolVersion {
+    /// The protocol version implemented by this crate
+    pub const CURRENT: ProtocolVersion = ProtocolVersion { major: 1, minor: 0 };
+
+    /// Checks if two end points using these versions can talk to each other
+    pub fn is_compatible(&self, other: &ProtocolVersion) -> bool {
+        self.major == other.major
+    }
+}
+
+impl Display for ProtocolVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// A way a client can authenticate itself to a server
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum AuthMethod {
+    /// A username and password
+    Basic,
+    /// A token given to the user ahead of time
+    Token,
+    /// No credentials at all, for clients the server gives anonymous access
+    Anonymous,
+}
+
+/// Information about the build of a server
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerInfo {
+    pub name: String,
+    pub version: String,
+}
+
+impl Default for ServerInfo {
+    fn default() -> Self {
+        Self {
+            name: env!("CARGO_PKG_NAME").to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        }
+    }
+}
+
+/// The first packet sent by a client
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientHello {
+    pub version: ProtocolVersion,
+    pub formats: Vec<WireFormat>,
+    pub compression: Vec<Compression>,
+}
+
+impl Default for ClientHello {
+    fn default() -> Self {
+        Self {
+            version: ProtocolVersion::CURRENT,
+            formats: WireFormat::ALL.to_vec(),
+            compression: Compression::ALL.to_vec(),
+        }
+    }
+}
+
+/// The response of a server accepting a client
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerHello {
+    pub version: ProtocolVersion,
+    pub format: WireFormat,
+    pub compression: Compression,
+    /// Payloads smaller than this many bytes are sent uncompressed
+    pub compression_threshold: usize,
+    /// How often both end points ping each other, and how long they may stay silent
+    pub keepalive: KeepaliveConfig,
+    /// The ways the client may authenticate itself. Empty if no authentication is required.
+    pub auth: Vec<AuthMethod>,
+    pub server: ServerInfo,
+}
+
+impl ServerHello {
+    /// Gets the codec negotiated for every following packet
+    pub fn codec(&self) -> Compressed<WireFormat> {
+        Compressed::new(self.format, self.compression).with_threshold(self.compression_threshold)
+    }
+}
+
+/// Why a server rejected a client
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Error)]
+pub enum Rejection {
+    #[error("Client protocol version {client} is incompatible with server version {server}")]
+    IncompatibleVersion {
+        client: ProtocolVersion,
+        server: ProtocolVersion,
+    },
+    #[error("No common wire format")]
+    NoCommonFormat,
+    #[error("No common compression")]
+    NoCommonCompression,
+    #[error("The server is already serving its limit of {limit} connections")]
+    TooManyConnections { limit: usize },
+    #[error("The server is already serving its limit of {limit} connections from this address")]
+    TooManyConnectionsFromPeer { limit: usize },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum ServerResponse {
+    Accept(ServerHello),
+    Reject(Rejection),
+}
+
+/// The settings and requirements of a server, used to answer client handshakes
+#[derive(Debug, Clone)]
+pub struct ServerCapabilities {
+    formats: Vec<WireFormat>,
+    compression: Vec<Compression>,
+    compression_threshold: usize,
+    keepalive: KeepaliveConfig,
+    auth: Vec<AuthMethod>,
+    server: ServerInfo,
+}
+
+impl Default for ServerCapabilities {
+    fn default() -> Self {
+        Self {
+            formats: WireFormat::ALL.to_vec(),
+            compression: Compression::ALL.to_vec(),
+            compression_threshold: DEFAULT_COMPRESSION_THRESHOLD,
+            keepalive: KeepaliveConfig::default(),
+            auth: vec![],
+            server: ServerInfo::default(),
+        }
+    }
+}
+
+impl ServerCapabilities {
+    /// Sets the supported wire formats
+    pub fn with_formats(mut self, formats: impl IntoIterator<Item = WireFormat>) -> Self {
+        self.formats = formats.into_iter().collect();
+        self
+    }
+
+    /// Sets the supported compression
+    pub fn with_compression(mut self, compression: impl IntoIterator<Item = Compression>) -> Self {
+        self.compression = compression.into_iter().collect();
+        self
+    }
+
+    /// Sets the size, in bytes, below which payloads are sent uncompressed
+    pub fn with_compression_threshold(mut self, threshold: usize) -> Self {
+        self.compression_threshold = threshold;
+        self
+    }
+
+    /// Sets how often connections are pinged, and how long they may stay silent
+    pub fn with_keepalive(mut self, keepalive: KeepaliveConfig) -> Self {
+        self.keepalive = keepalive;
+        self
+    }
+
+    /// Requires clients to authenticate with one of the given methods
+    pub fn with_auth(mut self, auth: impl IntoIterator<Item = AuthMethod>) -> Self {
+        self.auth = auth.into_iter().collect();
+        self
+    }
+
+    /// Sets the build info sent to clients
+    pub fn with_server_info(mut self, server: ServerInfo) -> Self {
+        self.server = server;
+        self
+    }
+
+    /// Answers a client hello, choosing the first offered format and compression that are also
+    /// supported
+    pub fn answer(&self, hello: &ClientHello) -> Result<ServerHello, Rejection> {
+        let version = ProtocolVersion::CURRENT;
+        if !version.is_compatible(&hello.version) {
+            return Err(Rejection::IncompatibleVersion {
+                client: hello.version,
+                server: version,
+            });
+        }
+        let format = hello
+            .formats
+            .iter()
+            .find(|f| self.formats.contains(f))
+            .ok_or(Rejection::NoCommonFormat)?;
+        let compression = hello
+            .compression
+            .iter()
+            .find(|c| self.compression.contains(c))
+            .ok_or(Rejection::NoCommonCompression)?;
+        Ok(ServerHello {
+            version: version.min(hello.version),
+            format: *format,
+            compression: *compression,
+            compression_threshold: self.compression_threshold,
+            keepalive: self.keepalive,
+            auth: self.auth.clone(),
+            server: self.server.clone(),
+        })
+    }
+}
+
+/// Performs the client side of the handshake
+pub async fn connect<T>(
+    transport: &mut T,
+    hello: &ClientHello,
+) -> Result<ServerHello, HandshakeError>
+where
+    T: Transport<Vec<u8>, Vec<u8>, Error = io::Error> + Unpin,
+{
+    transport.send(Postcard.encode(hello)?).await?;
+    let packet = transport
+        .next()
+        .await
+        .ok_or(HandshakeError::Disconnected)??;
+    match Postcard.decode(&packet)? {
+        ServerResponse::Accept(server)
+            if hello.formats.contains(&server.format)
+                && hello.compression.contains(&server.compression) =>
+        {
+            Ok(server)
+        }
+        ServerResponse::Accept(_) => Err(HandshakeError::Unexpected),
+        ServerResponse::Reject(rejection) => Err(HandshakeError::Rejected(rejection)),
+    }
+}
+
+/// Performs the server side of the handshake
+pub async fn accept<T>(
+    transport: &mut T,
+    capabilities: &ServerCapabilities,
+) -> Result<(ClientHello, ServerHello), HandshakeError>
+where
+    T: Transport<Vec<u8>, Vec<u8>, Error = io::Error> + Unpin,
+{
+    let packet = transport
+        .next()
+        .await
+        .ok_or(HandshakeError::Disconnected)??;
+    let hello: ClientHello = Postcard.decode(&packet)?;
+    let response = capabilities.answer(&hello);
+    let packet = match &response {
+        Ok(server) => ServerResponse::Accept(server.clone()),
+        Err(rejection) => ServerResponse::Reject(rejection.clone()),
+    };
+    transport.send(Postcard.encode(&packet)?).await?;
+    match response {
+        Ok(server) => Ok((hello, server)),
+        Err(rejection) => Err(HandshakeError::Rejected(rejection)),
+    }
+}
+
+/// Performs the server side of the handshake, rejecting the client whatever it offers
+pub async fn reject<T>(transport: &mut T, rejection: Rejection) -> Result<(), HandshakeError>
+where
+    T: Transport<Vec<u8>, Vec<u8>, Error = io::Error> + Unpin,
+{
+    let packet = transport
+        .next()
+        .await
+        .ok_or(HandshakeError::Disconnected)??;
+    let _: ClientHello = Postcard.decode(&packet)?;
+    let packet = ServerResponse::Reject(rejection);
+    transport.send(Postcard.encode(&packet)?).await?;
+    Ok(())
+}
+
+/// The handshake failed
+#[derive(Debug, Error)]
+pub enum HandshakeError {
+    #[error("The other end point disconnected during the handshake")]
+    Disconnected,
+    #[error("Unexpected handshake packet")]
+    Unexpected,
+    #[error(transparent)]
+    Rejected(Rejection),
+    #[error(transparent)]
+    WireError(#[from] WireError),
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_incompatible_versions() {
+        let hello = ClientHello {
+            version: ProtocolVersion {
+                major: ProtocolVersion::CURRENT.major + 1,
+                minor: 0,
+            },
+            ..Default::default()
+        };
+        let rejection = ServerCapabilities::default().answer(&hello).unwrap_err();
+        assert!(matches!(rejection, Rejection::IncompatibleVersion { .. }));
+        assert_eq!(
+            rejection.to_string(),
+            format!(
+                "Client protocol version {} is incompatible with server version {}",
+                hello.version,
+                ProtocolVersion::CURRENT
+            )
+        );
+    }
+
+    #[test]
+    fn prefers_client_order() {
+        let capabilities = ServerCapabilities::default()
+            .with_formats([WireFormat::Json, WireFormat::Ron])
+            .with_auth([AuthMethod::Basic]);
+        let server = capabilities.answer(&ClientHello::default()).unwrap();
+        assert_eq!(server.format, WireFormat::Ron);
+        assert_eq!(server.compression, Compression::Zstd);
+        assert_eq!(server.auth, [AuthMethod::Basic]);
+
+        let hello = ClientHello {
+            compression: vec![Compression::Lz4],
+            ..Default::default()
+        };
+        let capabilities = capabilities.with_compression([Compression::None]);
+        assert_eq!(
+            capabilities.answer(&hello),
+            Err(Rejection::NoCommonCompression)
+        );
+    }
+}
@@ -0,0 +1,217 @@
+//! Keepalive pings and idle timeouts for packet transports.
+//!
+//! After the handshake, every packet starts with a single byte telling whether it carries data,
+//! or is a ping or pong. Both end points ping each other periodically, and answer every ping with
+//! a pong. An end point that has not heard anything from the other for longer than the idle timeout
+//! considers the connection dead, so dead sockets are noticed even when no requests are being made.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use futures::{Sink, Stream};
+use serde::{Deserialize, Serialize};
+use tokio::time::{self, Instant, Interval, MissedTickBehavior, Sleep};
+
+use crate::transport::Transport;
+
+/// The default time between pings
+pub const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+/// The default time without hearing from the other end point before the connection is dropped
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+const DATA: u8 = 0;
+const PING: u8 = 1;
+const PONG: u8 = 2;
+
+/// How often a connection is pinged, and how long it may stay silent
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct KeepaliveConfig {
+    interval: Duration,
+    idle_timeout: Option<Duration>,
+}
+
+impl Default for KeepaliveConfig {
+    fn default() -> Self {
+        Self {
+            interval: DEFAULT_KEEPALIVE_INTERVAL,
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+        }
+    }
+}
+
+impl KeepaliveConfig {
+    /// Sets the time between pings
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Sets the time without hearing from the other end point before the connection is dropped,
+    /// or `None` to never drop idle connections
+    pub fn with_idle_timeout(mut self, idle_timeout: impl Into<Option<Duration>>) -> Self {
+        self.idle_timeout = idle_timeout.into();
+        self
+    }
+
+    /// Gets the time between pings
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Gets the idle timeout, if idle connections are dropped
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout
+    }
+}
+
+/// Pings the other end point of a packet transport, and fails once it stays silent for too long.
+///
+/// Pings are sent and answered while the transport is polled for packets, so it should be polled
+/// continuously, as a [`Dispatcher`](crate::transport::mux::Dispatcher) or a server loop does.
+#[derive(Debug)]
+pub struct Keepalive<T> {
+    inner: T,
+    config: KeepaliveConfig,
+    ping: Interval,
+    idle: Option<Pin<Box<Sleep>>>,
+    send_ping: bool,
+    send_pong: bool,
+    unflushed: bool,
+}
+
+impl<T> Keepalive<T>
+where
+    T: Transport<Vec<u8>, Vec<u8>, Error = io::Error> + Unpin,
+{
+    /// Wraps a transport. Must be called from within a tokio runtime.
+    pub fn new(inner: T, config: KeepaliveConfig) -> Self {
+        let mut ping = time::interval_at(Instant::now() + config.interval, config.interval);
+        ping.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Self {
+            inner,
+            config,
+            ping,
+            idle: config
+                .idle_timeout
+                .map(|timeout| Box::pin(time::sleep(timeout))),
+            send_ping: false,
+            send_pong: false,
+            unflushed: false,
+        }
+    }
+
+    /// Gets the keepalive settings of this transport
+    pub fn config(&self) -> &KeepaliveConfig {
+        &self.config
+    }
+
+    /// Gets the wrapped transport
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Sends any pending ping or pong, without flushing it
+    fn poll_control(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.send_ping || self.send_pong {
+            ready!(Pin::new(&mut self.inner).poll_ready(cx))?;
+            let kind = if self.send_pong {
+                self.send_pong = false;
+                PONG
+            } else {
+                self.send_ping = false;
+                PING
+            };
+            Pin::new(&mut self.inner).start_send(vec![kind])?;
+            self.unflushed = true;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Sends and flushes any pending ping or pong
+    fn poll_flush_control(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_control(cx))?;
+        if self.unflushed {
+            ready!(Pin::new(&mut self.inner).poll_flush(cx))?;
+            self.unflushed = false;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T> Sink<Vec<u8>> for Keepalive<T>
+where
+    T: Transport<Vec<u8>, Vec<u8>, Error = io::Error> + Unpin,
+{
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_control(cx))?;
+        Pin::new(&mut self.inner).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Vec<u8>) -> io::Result<()> {
+        let mut packet = Vec::with_capacity(item.len() + 1);
+        packet.push(DATA);
+        packet.extend_from_slice(&item);
+        Pin::new(&mut self.inner).start_send(packet)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_control(cx))?;
+        ready!(Pin::new(&mut self.inner).poll_flush(cx))?;
+        self.unflushed = false;
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+impl<T> Stream for Keepalive<T>
+where
+    T: Transport<Vec<u8>, Vec<u8>, Error = io::Error> + Unpin,
+{
+    type Item = io::Result<Vec<u8>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            if let Some(idle) = &mut this.idle {
+                if idle.as_mut().poll(cx).is_ready() {
+                    return Poll::Ready(Some(Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "the other end point has been silent for too long",
+                    ))));
+                }
+            }
+            if this.ping.poll_tick(cx).is_ready() {
+                this.send_ping = true;
+            }
+            if let Poll::Ready(Err(e)) = this.poll_flush_control(cx) {
+                return Poll::Ready(Some(Err(e)));
+            }
+            let packet = match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+                Some(Ok(packet)) => packet,
+                other => return Poll::Ready(other),
+            };
+            if let (Some(idle), Some(timeout)) = (&mut this.idle, this.config.idle_timeout) {
+                idle.as_mut().reset(Instant::now() + timeout);
+            }
+            match packet.split_first() {
+                Some((&DATA, payload)) => return Poll::Ready(Some(Ok(payload.to_vec()))),
+                Some((&PING, [])) => this.send_pong = true,
+                Some((&PONG, [])) => {}
+                _ => {
+                    return Poll::Ready(Some(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "invalid keepalive packet",
+                    ))))
+                }
+            }
+        }
+    }
+}
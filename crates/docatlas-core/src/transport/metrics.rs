@@ -0,0 +1,288 @@
+//! Metrics of transports.
+//!
+//! A [`Metered`](Metered) transport counts the packets and bytes passing through it, and a
+//! [`Timed`](Timed) codec measures how long values take to serialize and deserialize. Both record
+//! into a shared [`TransportMetrics`](TransportMetrics), which can be rendered in the prometheus
+//! text format.
+
+use std::fmt::Write;
+use std::io;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use futures::{Sink, Stream};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::transport::frame::HEADER_SIZE;
+use crate::transport::wire::{WireCodec, WireError};
+use crate::transport::Transport;
+
+/// The upper bounds, in seconds, of the buckets of latency histograms
+pub const LATENCY_BUCKETS: [f64; 10] = [
+    0.000_01, 0.000_05, 0.000_1, 0.000_5, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5,
+];
+
+/// A histogram of durations, with fixed buckets
+#[derive(Debug, Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    /// Records a single duration
+    pub fn record(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+            if seconds <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Gets the number of recorded durations
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Gets the sum of every recorded duration
+    pub fn sum(&self) -> Duration {
+        Duration::from_nanos(self.sum_nanos.load(Ordering::Relaxed))
+    }
+
+    fn render(&self, name: &str, help: &str, out: &mut String) {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        for (bucket, bound) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{le=\"{bound}\"}} {}",
+                bucket.load(Ordering::Relaxed)
+            );
+        }
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {}", self.count());
+        let _ = writeln!(out, "{name}_sum {}", self.sum().as_secs_f64());
+        let _ = writeln!(out, "{name}_count {}", self.count());
+    }
+}
+
+/// Counters and histograms shared by every transport of a process
+#[derive(Debug, Default)]
+pub struct TransportMetrics {
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    frames_in: AtomicU64,
+    frames_out: AtomicU64,
+    serialize: Histogram,
+    deserialize: Histogram,
+}
+
+impl TransportMetrics {
+    /// Creates metrics with every counter at zero
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Gets the number of bytes received, including frame headers
+    pub fn bytes_in(&self) -> u64 {
+        self.bytes_in.load(Ordering::Relaxed)
+    }
+
+    /// Gets the number of bytes sent, including frame headers
+    pub fn bytes_out(&self) -> u64 {
+        self.bytes_out.load(Ordering::Relaxed)
+    }
+
+    /// Gets the number of frames received
+    pub fn frames_in(&self) -> u64 {
+        self.frames_in.load(Ordering::Relaxed)
+    }
+
+    /// Gets the number of frames sent
+    pub fn frames_out(&self) -> u64 {
+        self.frames_out.load(Ordering::Relaxed)
+    }
+
+    /// Gets the time spent serializing values
+    pub fn serialize(&self) -> &Histogram {
+        &self.serialize
+    }
+
+    /// Gets the time spent deserializing values
+    pub fn deserialize(&self) -> &Histogram {
+        &self.deserialize
+    }
+
+    /// Renders these metrics in the prometheus text format, with every name starting with the
+    /// given prefix
+    pub fn render(&self, prefix: &str, out: &mut String) {
+        let counters = [
+            (
+                "bytes_in",
+                "Bytes received, including frame headers",
+                self.bytes_in(),
+            ),
+            (
+                "bytes_out",
+                "Bytes sent, including frame headers",
+                self.bytes_out(),
+            ),
+            ("frames_in", "Frames received", self.frames_in()),
+            ("frames_out", "Frames sent", self.frames_out()),
+        ];
+        for (name, help, value) in counters {
+            let _ = writeln!(out, "# HELP {prefix}_{name}_total {help}");
+            let _ = writeln!(out, "# TYPE {prefix}_{name}_total counter");
+            let _ = writeln!(out, "{prefix}_{name}_total {value}");
+        }
+        self.serialize.render(
+            &format!("{prefix}_serialize_seconds"),
+            "Time spent serializing values",
+            out,
+        );
+        self.deserialize.render(
+            &format!("{prefix}_deserialize_seconds"),
+            "Time spent deserializing values",
+            out,
+        );
+    }
+
+    fn received(&self, packet: &[u8]) {
+        self.frames_in.fetch_add(1, Ordering::Relaxed);
+        self.bytes_in
+            .fetch_add((packet.len() + HEADER_SIZE) as u64, Ordering::Relaxed);
+    }
+
+    fn sent(&self, packet: &[u8]) {
+        self.frames_out.fetch_add(1, Ordering::Relaxed);
+        self.bytes_out
+            .fetch_add((packet.len() + HEADER_SIZE) as u64, Ordering::Relaxed);
+    }
+}
+
+/// Counts the packets and bytes passing through a packet transport
+#[derive(Debug)]
+pub struct Metered<T> {
+    inner: T,
+    metrics: Arc<TransportMetrics>,
+}
+
+impl<T> Metered<T>
+where
+    T: Transport<Vec<u8>, Vec<u8>, Error = io::Error> + Unpin,
+{
+    /// Wraps a transport, recording into the given metrics
+    pub fn new(inner: T, metrics: Arc<TransportMetrics>) -> Self {
+        Self { inner, metrics }
+    }
+
+    /// Gets the wrapped transport
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T> Sink<Vec<u8>> for Metered<T>
+where
+    T: Transport<Vec<u8>, Vec<u8>, Error = io::Error> + Unpin,
+{
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Vec<u8>) -> io::Result<()> {
+        self.metrics.sent(&item);
+        Pin::new(&mut self.inner).start_send(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+impl<T> Stream for Metered<T>
+where
+    T: Transport<Vec<u8>, Vec<u8>, Error = io::Error> + Unpin,
+{
+    type Item = io::Result<Vec<u8>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let next = Pin::new(&mut self.inner).poll_next(cx);
+        if let Poll::Ready(Some(Ok(packet))) = &next {
+            self.metrics.received(packet);
+        }
+        next
+    }
+}
+
+/// Measures how long another codec takes to serialize and deserialize values
+#[derive(Debug, Clone)]
+pub struct Timed<C> {
+    codec: C,
+    metrics: Arc<TransportMetrics>,
+}
+
+impl<C: WireCodec> Timed<C> {
+    /// Wraps a codec, recording into the given metrics
+    pub fn new(codec: C, metrics: Arc<TransportMetrics>) -> Self {
+        Self { codec, metrics }
+    }
+}
+
+impl<C: WireCodec> WireCodec for Timed<C> {
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>, WireError> {
+        let start = Instant::now();
+        let encoded = self.codec.encode(value);
+        self.metrics.serialize.record(start.elapsed());
+        encoded
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, WireError> {
+        let start = Instant::now();
+        let decoded = self.codec.decode(bytes);
+        self.metrics.deserialize.record(start.elapsed());
+        decoded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::wire::Postcard;
+
+    #[test]
+    fn render_prometheus_text() {
+        let metrics = Arc::new(TransportMetrics::new());
+        let codec = Timed::new(Postcard, metrics.clone());
+        let bytes = codec.encode("hello").unwrap();
+        assert_eq!(codec.decode::<String>(&bytes).unwrap(), "hello");
+        metrics.sent(&bytes);
+        metrics.serialize.record(Duration::from_millis(20));
+
+        let mut out = String::new();
+        metrics.render("test", &mut out);
+        let lines = out.lines().collect::<Vec<_>>();
+        assert!(lines.contains(&"test_frames_out_total 1"));
+        assert!(
+            lines.contains(&format!("test_bytes_out_total {}", bytes.len() + HEADER_SIZE).as_str())
+        );
+        assert!(lines.contains(&"test_serialize_seconds_count 2"));
+        assert!(lines.contains(&"test_serialize_seconds_bucket{le=\"0.01\"} 1"));
+        assert!(lines.contains(&"test_serialize_seconds_bucket{le=\"+Inf\"} 2"));
+        assert!(lines.contains(&"test_deserialize_seconds_count 1"));
+    }
+}
@@ -0,0 +1,224 @@
+//! Multiplexing of requests over a single transport.
+//!
+//! Every request is sent in an [`Envelope`](Envelope) with a correlation id, and every response
+//! chunk is sent back in an envelope with the id of its request. Responses can arrive in any
+//! order, and a single request can be answered with several chunks, the last of which is marked.
+//! Requests are written through a bounded [`SendQueue`](SendQueue), so callers are slowed down
+//! instead of buffering without limit when the connection can not keep up.
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use futures::channel::mpsc;
+use futures::future::{select, Either};
+use futures::{pin_mut, Stream, StreamExt};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::transport::queue::{QueueConfig, QueueError, SendQueue};
+use crate::transport::Transport;
+
+/// A request or response chunk tagged with the id of its request
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Envelope<T> {
+    id: u64,
+    last: bool,
+    body: T,
+}
+
+impl<T> Envelope<T> {
+    /// Creates an envelope that completes its request
+    pub fn new(id: u64, body: T) -> Self {
+        Self {
+            id,
+            last: true,
+            body,
+        }
+    }
+
+    /// Creates an envelope for a chunk of a response, with more chunks following it
+    pub fn chunk(id: u64, body: T) -> Self {
+        Self {
+            id,
+            last: false,
+            body,
+        }
+    }
+
+    /// Gets the id of the request
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Checks if this is the last envelope sent for its request
+    pub fn is_last(&self) -> bool {
+        self.last
+    }
+
+    /// Gets the body of this envelope
+    pub fn body(&self) -> &T {
+        &self.body
+    }
+
+    /// Takes the body of this envelope
+    pub fn into_body(self) -> T {
+        self.body
+    }
+
+    /// Converts the body of this envelope, keeping its id
+    pub fn map<U>(self, func: impl FnOnce(T) -> U) -> Envelope<U> {
+        Envelope {
+            id: self.id,
+            last: self.last,
+            body: func(self.body),
+        }
+    }
+}
+
+type Waiters<Resp> = Arc<Mutex<HashMap<u64, mpsc::UnboundedSender<Resp>>>>;
+
+/// Sends requests over a shared transport and routes every response back to its caller.
+///
+/// A dispatcher is created alongside a driver future, which must be polled for any request to make
+/// progress. The driver finishes once the connection closes or every dispatcher is dropped.
+#[derive(Debug)]
+pub struct Dispatcher<Req, Resp> {
+    next_id: Arc<AtomicU64>,
+    outgoing: SendQueue<Envelope<Req>>,
+    waiters: Waiters<Resp>,
+}
+
+impl<Req, Resp> Clone for Dispatcher<Req, Resp> {
+    fn clone(&self) -> Self {
+        Self {
+            next_id: self.next_id.clone(),
+            outgoing: self.outgoing.clone(),
+            waiters: self.waiters.clone(),
+        }
+    }
+}
+
+impl<Req, Resp> Dispatcher<Req, Resp> {
+    /// Creates a dispatcher over a transport, returning the dispatcher and its driver
+    pub fn new<T>(transport: T) -> (Self, impl Future<Output = Result<(), T::Error>>)
+    where
+        T: Transport<Envelope<Req>, Envelope<Resp>>,
+    {
+        Self::with_queue(transport, QueueConfig::default())
+    }
+
+    /// Creates a dispatcher whose requests are sent through a queue of the given size and policy
+    pub fn with_queue<T>(
+        transport: T,
+        queue: QueueConfig,
+    ) -> (Self, impl Future<Output = Result<(), T::Error>>)
+    where
+        T: Transport<Envelope<Req>, Envelope<Resp>>,
+    {
+        let (sink, mut stream) = transport.split();
+        let (outgoing, send) = SendQueue::new(sink, queue);
+        let waiters: Waiters<Resp> = Default::default();
+        let dispatcher = Self {
+            next_id: Arc::new(AtomicU64::new(0)),
+            outgoing,
+            waiters: waiters.clone(),
+        };
+        let driver = async move {
+            let receive = async {
+                while let Some(envelope) = stream.next().await {
+                    let envelope = envelope?;
+                    let mut waiters = waiters.lock();
+                    let id = envelope.id;
+                    let last = envelope.last;
+                    if let Some(waiter) = waiters.get(&id) {
+                        let _ = waiter.unbounded_send(envelope.body);
+                    }
+                    if last {
+                        waiters.remove(&id);
+                    }
+                }
+                Ok(())
+            };
+            pin_mut!(send, receive);
+            let result = match select(send, receive).await {
+                Either::Left((result, _)) => result,
+                Either::Right((result, _)) => result,
+            };
+            waiters.lock().clear();
+            result
+        };
+        (dispatcher, driver)
+    }
+
+    /// Sends a request, returning the stream of its response chunks. Waits while the send queue is
+    /// full, unless it uses another slow consumer policy.
+    pub async fn request(&self, body: Req) -> Result<Responses<Resp>, DispatchError> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = mpsc::unbounded();
+        self.waiters.lock().insert(id, sender);
+        if let Err(e) = self.outgoing.send(Envelope::new(id, body)).await {
+            self.waiters.lock().remove(&id);
+            return Err(match e {
+                QueueError::Closed => DispatchError::Closed,
+                e => DispatchError::QueueError(e),
+            });
+        }
+        Ok(Responses { id, receiver })
+    }
+
+    /// Sends a request and waits for the first chunk of its response
+    pub async fn call(&self, body: Req) -> Result<Resp, DispatchError> {
+        self.request(body)
+            .await?
+            .next()
+            .await
+            .ok_or(DispatchError::Closed)
+    }
+
+    /// Gets the number of requests still waiting for a response
+    pub fn in_flight(&self) -> usize {
+        self.waiters.lock().len()
+    }
+
+    /// Gets the number of requests waiting to be sent
+    pub fn queued(&self) -> usize {
+        self.outgoing.depth()
+    }
+}
+
+/// The response chunks of a single request. The stream ends after the last chunk, or if the
+/// connection closes first.
+#[derive(Debug)]
+pub struct Responses<Resp> {
+    id: u64,
+    receiver: mpsc::UnboundedReceiver<Resp>,
+}
+
+impl<Resp> Responses<Resp> {
+    /// Gets the id the request was sent with
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+}
+
+impl<Resp> Stream for Responses<Resp> {
+    type Item = Resp;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_next_unpin(cx)
+    }
+}
+
+/// A request could not be dispatched
+#[derive(Debug, Error)]
+pub enum DispatchError {
+    #[error("The connection is closed")]
+    Closed,
+    #[error(transparent)]
+    QueueError(QueueError),
+}
@@ -1,13 +1,15 @@
 //! Async packet reader
 
+use async_stream::stream;
+use futures::{AsyncWrite, Stream};
+use std::future::Future;
 use std::io;
 use std::marker::PhantomData;
-use std::pin::{Pin, pin};
+use std::pin::{pin, Pin};
 use std::task::{Context, Poll};
-use futures::{AsyncWrite, Stream};
-use std::future::Future;
-use async_stream::stream;
 
+use crate::transport::frame::{FrameError, FrameHeader, HEADER_SIZE};
+use crate::transport::wire::{WireCodec, WireError, WireFormat};
 use serde::de::DeserializeOwned;
 use tokio::io::{AsyncRead, AsyncReadExt, BufReader};
 
@@ -19,11 +21,20 @@ pub struct Packet<T> {
 #[derive(Debug)]
 pub struct PacketReader<T: DeserializeOwned, R: AsyncRead + Unpin> {
     reader: BufReader<R>,
+    format: WireFormat,
     _emit: PhantomData<T>,
 }
 
 impl<T: DeserializeOwned, R: AsyncRead + Unpin> PacketReader<T, R> {
-    pub fn stream<'a>(&'a mut self) -> impl Stream<Item=Result<Packet<T>, PacketReadError>> + 'a {
+    pub fn new(reader: R, format: WireFormat) -> Self {
+        Self {
+            reader: BufReader::new(reader),
+            format,
+            _emit: PhantomData,
+        }
+    }
+
+    pub fn stream<'a>(&'a mut self) -> impl Stream<Item = Result<Packet<T>, PacketReadError>> + 'a {
         stream! {
             let last = loop {
                 let r = self.next().await;
@@ -37,23 +48,95 @@ impl<T: DeserializeOwned, R: AsyncRead + Unpin> PacketReader<T, R> {
     }
 }
 
-
 impl<T: DeserializeOwned, R: AsyncRead + Unpin> PacketReader<T, R> {
     async fn next(&mut self) -> Result<Packet<T>, PacketReadError> {
-        let len = self.reader.read_u64().await?;
-        let mut buffer = vec![0_u8; len as usize - 8];
-        self.reader.read_exact(&mut buffer).await?;
-        let read: T = ron::de::from_bytes(&buffer).map_err(|e| e.code)?;
+        let mut header = [0_u8; HEADER_SIZE];
+        let read = read_fully(&mut self.reader, &mut header).await?;
+        if read < HEADER_SIZE {
+            return Err(FrameError::Truncated {
+                expected: HEADER_SIZE,
+                actual: read,
+            }
+            .into());
+        }
+        let header = FrameHeader::read(&header)?;
+        // the buffer grows as the payload arrives, rather than trusting the claimed length up front
+        let mut buffer = vec![];
+        (&mut self.reader)
+            .take(header.len() as u64)
+            .read_to_end(&mut buffer)
+            .await?;
+        header.verify(&buffer)?;
+        let read: T = self.format.decode(&buffer)?;
         Ok(Packet { wrapped: read })
     }
 }
 
-
+/// Reads until the buffer is full or the reader is exhausted, returning the number of bytes read
+async fn read_fully<R: AsyncRead + Unpin>(reader: &mut R, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buffer.len() {
+        match reader.read(&mut buffer[read..]).await? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    Ok(read)
+}
 
 #[derive(Debug, thiserror::Error)]
 pub enum PacketReadError {
     #[error(transparent)]
-    RonError(#[from] ron::Error),
+    WireError(#[from] WireError),
     #[error(transparent)]
     IoError(#[from] io::Error),
+    #[error(transparent)]
+    FrameError(#[from] FrameError),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::frame::{MAX_PAYLOAD_SIZE, PROTOCOL_VERSION};
+
+    fn frame(payload: &[u8]) -> Vec<u8> {
+        let mut frame = FrameHeader::new(payload).unwrap().write().to_vec();
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[tokio::test]
+    async fn validates_frames() {
+        let valid = frame(b"\"hello\"");
+        let mut reader = PacketReader::<String, _>::new(&valid[..], WireFormat::Ron);
+        assert_eq!(reader.next().await.unwrap().wrapped, "hello");
+
+        let mut corrupt = frame(b"\"hello\"");
+        *corrupt.last_mut().unwrap() ^= 1;
+        let mut reader = PacketReader::<String, _>::new(&corrupt[..], WireFormat::Ron);
+        assert!(matches!(
+            reader.next().await,
+            Err(PacketReadError::FrameError(FrameError::Corrupt { .. }))
+        ));
+
+        let truncated = frame(b"\"hello\"");
+        let mut reader =
+            PacketReader::<String, _>::new(&truncated[..truncated.len() - 2], WireFormat::Ron);
+        assert!(matches!(
+            reader.next().await,
+            Err(PacketReadError::FrameError(FrameError::Truncated { .. }))
+        ));
+
+        // a header claiming the largest payload only costs what is actually sent
+        let mut oversold = (MAX_PAYLOAD_SIZE as u64).to_be_bytes().to_vec();
+        oversold.extend_from_slice(&PROTOCOL_VERSION.to_be_bytes());
+        oversold.extend_from_slice(&[0; 4]);
+        oversold.extend_from_slice(b"\"hello\"");
+        let mut reader = PacketReader::<String, _>::new(&oversold[..], WireFormat::Ron);
+        assert!(matches!(
+            reader.next().await,
+            Err(PacketReadError::FrameError(FrameError::Truncated { actual, .. }))
+                if actual == HEADER_SIZE + 7
+        ));
+    }
 }
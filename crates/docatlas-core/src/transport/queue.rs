@@ -0,0 +1,306 @@
+//! Bounded send queues.
+//!
+//! A [`SendQueue`](SendQueue) decouples whatever produces packets from the sink writing them, while
+//! never holding more than a fixed number of them. What happens once the queue is full is decided
+//! by a [`SlowConsumerPolicy`](SlowConsumerPolicy): producers can be made to wait, be told the
+//! packet was rejected, or the consumer can be disconnected altogether.
+
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use futures::future::{select, Either};
+use futures::{pin_mut, Sink, SinkExt};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, Notify};
+
+/// The default number of packets a send queue can hold
+pub const DEFAULT_SEND_QUEUE_CAPACITY: usize = 256;
+
+/// What to do when a consumer can not keep up with its producers
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SlowConsumerPolicy {
+    /// Producers wait until there is room in the queue
+    #[default]
+    Block,
+    /// Packets that do not fit in the queue are rejected with an error
+    Reject,
+    /// The consumer is disconnected once the queue is full
+    Disconnect,
+}
+
+impl Display for SlowConsumerPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            SlowConsumerPolicy::Block => "block",
+            SlowConsumerPolicy::Reject => "reject",
+            SlowConsumerPolicy::Disconnect => "disconnect",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for SlowConsumerPolicy {
+    type Err = UnknownPolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "block" => Ok(SlowConsumerPolicy::Block),
+            "reject" => Ok(SlowConsumerPolicy::Reject),
+            "disconnect" => Ok(SlowConsumerPolicy::Disconnect),
+            _ => Err(UnknownPolicyError(s.to_string())),
+        }
+    }
+}
+
+/// A slow consumer policy could not be parsed
+#[derive(Debug, Error)]
+#[error("Unknown slow consumer policy {0:?}, expected one of block, reject or disconnect")]
+pub struct UnknownPolicyError(String);
+
+/// The size of a send queue, and what happens once it is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct QueueConfig {
+    capacity: usize,
+    policy: SlowConsumerPolicy,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            capacity: DEFAULT_SEND_QUEUE_CAPACITY,
+            policy: SlowConsumerPolicy::default(),
+        }
+    }
+}
+
+impl QueueConfig {
+    /// Sets the number of packets the queue can hold. Must be greater than zero.
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        assert!(
+            capacity > 0,
+            "send queues need room for at least one packet"
+        );
+        self.capacity = capacity;
+        self
+    }
+
+    /// Sets what happens once the queue is full
+    pub fn with_policy(mut self, policy: SlowConsumerPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    /// Gets the number of packets the queue can hold
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Gets what happens once the queue is full
+    pub fn policy(&self) -> SlowConsumerPolicy {
+        self.policy
+    }
+}
+
+/// The sending end of a bounded queue in front of a sink.
+///
+/// A queue is created alongside a driver future, which writes queued packets into the sink and
+/// must be polled for any packet to be sent. The driver finishes once every sender is dropped, or
+/// once the consumer is disconnected for being too slow.
+#[derive(Debug)]
+pub struct SendQueue<T> {
+    sender: mpsc::Sender<T>,
+    config: QueueConfig,
+    depth: Arc<AtomicUsize>,
+    disconnect: Arc<Notify>,
+}
+
+impl<T> Clone for SendQueue<T> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+            config: self.config,
+            depth: self.depth.clone(),
+            disconnect: self.disconnect.clone(),
+        }
+    }
+}
+
+impl<T> SendQueue<T> {
+    /// Creates a queue in front of a sink, returning the queue and its driver
+    pub fn new<S>(
+        sink: S,
+        config: QueueConfig,
+    ) -> (Self, impl Future<Output = Result<(), S::Error>>)
+    where
+        S: Sink<T> + Unpin,
+    {
+        let (sender, mut receiver) = mpsc::channel(config.capacity);
+        let queue = Self {
+            sender,
+            config,
+            depth: Arc::new(AtomicUsize::new(0)),
+            disconnect: Arc::new(Notify::new()),
+        };
+        let depth = queue.depth.clone();
+        let disconnect = queue.disconnect.clone();
+        let driver = async move {
+            let mut sink = sink;
+            loop {
+                let item = {
+                    let disconnected = disconnect.notified();
+                    let received = receiver.recv();
+                    pin_mut!(disconnected, received);
+                    match select(disconnected, received).await {
+                        Either::Left(_) => return Ok(()),
+                        Either::Right((item, _)) => item,
+                    }
+                };
+                let Some(item) = item else {
+                    return Ok(());
+                };
+                depth.fetch_sub(1, Ordering::Relaxed);
+                sink.feed(item).await?;
+                while let Ok(item) = receiver.try_recv() {
+                    depth.fetch_sub(1, Ordering::Relaxed);
+                    sink.feed(item).await?;
+                }
+                sink.flush().await?;
+            }
+        };
+        (queue, driver)
+    }
+
+    /// Queues a packet, following the slow consumer policy if the queue is full
+    pub async fn send(&self, item: T) -> Result<(), QueueError> {
+        // counted before sending, since the driver may take the packet before this returns
+        self.depth.fetch_add(1, Ordering::Relaxed);
+        let result = match self.config.policy {
+            SlowConsumerPolicy::Block => {
+                self.sender.send(item).await.map_err(|_| QueueError::Closed)
+            }
+            policy => match self.sender.try_send(item) {
+                Ok(()) => Ok(()),
+                Err(TrySendError::Closed(_)) => Err(QueueError::Closed),
+                Err(TrySendError::Full(_)) if policy == SlowConsumerPolicy::Reject => {
+                    Err(QueueError::Full)
+                }
+                Err(TrySendError::Full(_)) => {
+                    self.disconnect.notify_one();
+                    Err(QueueError::Disconnected)
+                }
+            },
+        };
+        if result.is_err() {
+            self.depth.fetch_sub(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    /// Gets the number of packets waiting in the queue
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+
+    /// Gets the size of the queue, and what happens once it is full
+    pub fn config(&self) -> &QueueConfig {
+        &self.config
+    }
+
+    /// Checks if the driver of the queue has finished
+    pub fn is_closed(&self) -> bool {
+        self.sender.is_closed()
+    }
+}
+
+/// A packet could not be queued
+#[derive(Debug, Error)]
+pub enum QueueError {
+    #[error("The send queue is full")]
+    Full,
+    #[error("The consumer was disconnected for not keeping up")]
+    Disconnected,
+    #[error("The send queue is closed")]
+    Closed,
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::channel::mpsc as futures_mpsc;
+    use futures::StreamExt;
+
+    use super::*;
+
+    /// A sink that only accepts packets once they are read from its receiver
+    fn rendezvous() -> (
+        impl Sink<u32, Error = futures_mpsc::SendError> + Unpin,
+        futures_mpsc::Receiver<u32>,
+    ) {
+        futures_mpsc::channel(0)
+    }
+
+    #[tokio::test]
+    async fn policies_apply_once_full() {
+        for policy in [SlowConsumerPolicy::Reject, SlowConsumerPolicy::Disconnect] {
+            let (sink, mut receiver) = rendezvous();
+            let config = QueueConfig::default().with_capacity(2).with_policy(policy);
+            let (queue, driver) = SendQueue::new(sink, config);
+            queue.send(1).await.unwrap();
+            queue.send(2).await.unwrap();
+            assert_eq!(queue.depth(), 2);
+            match (policy, queue.send(3).await) {
+                (SlowConsumerPolicy::Reject, Err(QueueError::Full)) => {
+                    drop(queue);
+                    let driver = tokio::spawn(driver);
+                    assert_eq!(receiver.by_ref().take(2).collect::<Vec<_>>().await, [1, 2]);
+                    driver.await.unwrap().unwrap();
+                }
+                (SlowConsumerPolicy::Disconnect, Err(QueueError::Disconnected)) => {
+                    driver.await.unwrap();
+                    assert!(queue.is_closed());
+                    assert!(matches!(queue.send(4).await, Err(QueueError::Closed)));
+                }
+                (policy, result) => panic!("{policy} policy resulted in {result:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn block_waits_for_room() {
+        let (sink, receiver) = rendezvous();
+        let config = QueueConfig::default().with_capacity(1);
+        let (queue, driver) = SendQueue::new(sink, config);
+        let driver = tokio::spawn(driver);
+        let producer = tokio::spawn(async move {
+            for i in 0..16 {
+                queue.send(i).await.unwrap();
+            }
+        });
+        assert_eq!(
+            receiver.collect::<Vec<_>>().await,
+            (0..16).collect::<Vec<_>>()
+        );
+        producer.await.unwrap();
+        driver.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn parse_policy() {
+        for policy in [
+            SlowConsumerPolicy::Block,
+            SlowConsumerPolicy::Reject,
+            SlowConsumerPolicy::Disconnect,
+        ] {
+            assert_eq!(
+                policy.to_string().parse::<SlowConsumerPolicy>().unwrap(),
+                policy
+            );
+        }
+        assert!("drop".parse::<SlowConsumerPolicy>().is_err());
+    }
+}
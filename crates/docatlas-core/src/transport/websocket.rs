@@ -0,0 +1,133 @@
+//! A transport of packets built on a websocket, for browser based clients.
+//!
+//! Every packet is sent as a single binary message holding one frame, exactly as it would be sent
+//! over a tcp stream. Pings and pongs are answered by the websocket itself, and text messages are
+//! rejected.
+
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+
+use bytes::BytesMut;
+use futures::{Sink, Stream};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::{Error as WsError, Message};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::transport::frame::{FrameError, HEADER_SIZE};
+use crate::transport::PacketCodec;
+
+/// A transport of packets built on a websocket
+#[derive(Debug)]
+pub struct WebSocketTransport {
+    peer_addr: Option<SocketAddr>,
+    socket: WebSocketStream<MaybeTlsStream<TcpStream>>,
+}
+
+impl WebSocketTransport {
+    /// Performs the server side of the websocket handshake on a connected tcp stream
+    pub async fn accept(stream: TcpStream) -> io::Result<Self> {
+        let peer_addr = stream.peer_addr().ok();
+        let socket = tokio_tungstenite::accept_async(MaybeTlsStream::Plain(stream))
+            .await
+            .map_err(into_io_error)?;
+        Ok(Self { peer_addr, socket })
+    }
+
+    /// Connects to a websocket server, such as `ws://localhost:3677`
+    pub async fn connect(url: &str) -> io::Result<Self> {
+        let (socket, _) = tokio_tungstenite::connect_async(url)
+            .await
+            .map_err(into_io_error)?;
+        let peer_addr = match socket.get_ref() {
+            MaybeTlsStream::Plain(stream) => stream.peer_addr().ok(),
+            _ => None,
+        };
+        Ok(Self { peer_addr, socket })
+    }
+
+    /// Gets the address of the other end point, if known
+    pub fn peer_addr(&self) -> Option<&SocketAddr> {
+        self.peer_addr.as_ref()
+    }
+}
+
+fn into_io_error(error: WsError) -> io::Error {
+    match error {
+        WsError::Io(e) => e,
+        e => io::Error::other(e),
+    }
+}
+
+impl Sink<Vec<u8>> for WebSocketTransport {
+    type Error = io::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.socket)
+            .poll_ready(cx)
+            .map_err(into_io_error)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Vec<u8>) -> io::Result<()> {
+        let mut frame = BytesMut::new();
+        PacketCodec.encode(item, &mut frame)?;
+        Pin::new(&mut self.socket)
+            .start_send(Message::Binary(frame.to_vec()))
+            .map_err(into_io_error)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.socket)
+            .poll_flush(cx)
+            .map_err(into_io_error)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.socket)
+            .poll_close(cx)
+            .map_err(into_io_error)
+    }
+}
+
+impl Stream for WebSocketTransport {
+    type Item = io::Result<Vec<u8>>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let message = match ready!(Pin::new(&mut self.socket).poll_next(cx)) {
+                None | Some(Err(WsError::ConnectionClosed)) => return Poll::Ready(None),
+                Some(Err(e)) => return Poll::Ready(Some(Err(into_io_error(e)))),
+                Some(Ok(message)) => message,
+            };
+            match message {
+                Message::Binary(data) => {
+                    let mut buffer = BytesMut::from(&data[..]);
+                    let packet = match PacketCodec.decode_eof(&mut buffer) {
+                        Ok(Some(packet)) if buffer.is_empty() => Ok(packet),
+                        Ok(Some(_)) => Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "websocket message holds more than one frame",
+                        )),
+                        Ok(None) => Err(FrameError::Truncated {
+                            expected: HEADER_SIZE,
+                            actual: 0,
+                        }
+                        .into()),
+                        Err(e) => Err(e),
+                    };
+                    return Poll::Ready(Some(packet));
+                }
+                Message::Text(_) => {
+                    return Poll::Ready(Some(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "text websocket messages are not supported",
+                    ))))
+                }
+                Message::Close(_) => return Poll::Ready(None),
+                Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => continue,
+            }
+        }
+    }
+}
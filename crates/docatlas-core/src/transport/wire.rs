@@ -0,0 +1,158 @@
+//! Serialization of the values sent over a transport.
+//!
+//! Both end points agree on a [`WireFormat`](WireFormat) and
+//! [`Compression`](crate::transport::compression::Compression) during the
+//! [handshake](crate::transport::handshake).
+//!
+//! Postcard is the default format. RON and JSON are only meant for debugging, as they are much
+//! larger on the wire.
+
+use std::io;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::transport::frame::MAX_PAYLOAD_SIZE;
+use crate::transport::{Transport, TransportExt};
+
+/// Serializes values sent over a transport
+pub trait WireCodec {
+    /// Serializes a value
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>, WireError>;
+
+    /// Deserializes a value
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, WireError>;
+}
+
+/// The compact binary postcard format
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Postcard;
+
+impl WireCodec for Postcard {
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>, WireError> {
+        Ok(postcard::to_stdvec(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, WireError> {
+        Ok(postcard::from_bytes(bytes)?)
+    }
+}
+
+/// The human readable RON format
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Ron;
+
+impl WireCodec for Ron {
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>, WireError> {
+        Ok(ron::to_string(value)?.into_bytes())
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, WireError> {
+        ron::de::from_bytes(bytes).map_err(|e| WireError::RonError(e.code))
+    }
+}
+
+/// The human readable JSON format
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Json;
+
+impl WireCodec for Json {
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>, WireError> {
+        Ok(serde_json::to_vec(value)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, WireError> {
+        Ok(serde_json::from_slice(bytes)?)
+    }
+}
+
+/// A wire format that can be negotiated at handshake
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum WireFormat {
+    #[default]
+    Postcard,
+    Ron,
+    Json,
+}
+
+impl WireFormat {
+    /// Every supported format, in order of preference
+    pub const ALL: [WireFormat; 3] = [WireFormat::Postcard, WireFormat::Ron, WireFormat::Json];
+}
+
+impl WireCodec for WireFormat {
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>, WireError> {
+        match self {
+            WireFormat::Postcard => Postcard.encode(value),
+            WireFormat::Ron => Ron.encode(value),
+            WireFormat::Json => Json.encode(value),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, WireError> {
+        match self {
+            WireFormat::Postcard => Postcard.decode(bytes),
+            WireFormat::Ron => Ron.decode(bytes),
+            WireFormat::Json => Json.decode(bytes),
+        }
+    }
+}
+
+/// Wraps a packet transport so that it sends values of type `I` and receives values of type `O`,
+/// serialized with a codec. Values that can't be serialized are reported as invalid data.
+pub fn wire_transport<I, O, C, T>(transport: T, codec: C) -> impl Transport<I, O, Error = io::Error>
+where
+    I: Serialize,
+    O: DeserializeOwned,
+    C: WireCodec + Clone,
+    T: Transport<Vec<u8>, Vec<u8>, Error = io::Error>,
+{
+    let encoder = codec.clone();
+    let transport = TransportExt::with(transport, move |value: I| {
+        std::future::ready(encoder.encode(&value).map_err(io::Error::from))
+    });
+    TransportExt::then(transport, move |packet: Vec<u8>| {
+        std::future::ready(codec.decode(&packet).map_err(io::Error::from))
+    })
+}
+
+/// An error occurred serializing a value or negotiating a format
+#[derive(Debug, Error)]
+pub enum WireError {
+    #[error(transparent)]
+    PostcardError(#[from] postcard::Error),
+    #[error(transparent)]
+    RonError(#[from] ron::Error),
+    #[error(transparent)]
+    JsonError(#[from] serde_json::Error),
+    #[error(transparent)]
+    DecompressError(#[from] lz4_flex::block::DecompressError),
+    #[error("Could not compress or decompress payload: {0}")]
+    CompressionError(#[source] io::Error),
+    #[error("Compressed payload has an invalid header")]
+    InvalidCompressionHeader,
+    #[error("Decompressed payload exceeds the maximum of {MAX_PAYLOAD_SIZE} bytes")]
+    DecompressedOversized,
+}
+
+impl From<WireError> for io::Error {
+    fn from(value: WireError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_round_trip() {
+        let value = (String::from("hello"), vec![1_u64, 2, 3], Some(-1.5_f64));
+        for format in WireFormat::ALL {
+            let bytes = format.encode(&value).unwrap();
+            let decoded: (String, Vec<u64>, Option<f64>) = format.decode(&bytes).unwrap();
+            assert_eq!(decoded, value, "{format:?}");
+        }
+    }
+}
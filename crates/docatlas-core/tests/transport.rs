@@ -0,0 +1,207 @@
+use std::future::ready;
+use std::io;
+use std::time::Duration;
+
+use docatlas_core::transport::compression::Compression;
+use docatlas_core::transport::handshake::{self, ClientHello, ServerCapabilities};
+use docatlas_core::transport::keepalive::{Keepalive, KeepaliveConfig};
+use docatlas_core::transport::mux::{Dispatcher, Envelope};
+use docatlas_core::transport::wire::{self, Postcard, WireFormat};
+use docatlas_core::transport::{
+    LocalSocketTransport, TcpTransport, TransportExt, WebSocketTransport,
+};
+use futures::{SinkExt, StreamExt};
+use interprocess::local_socket::tokio::LocalSocketListener;
+use tokio::net::TcpListener;
+
+#[tokio::test]
+async fn tcp_round_trip() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut transport = TcpTransport::new(stream);
+        while let Some(packet) = transport.next().await {
+            let mut packet = packet.unwrap();
+            packet.reverse();
+            transport.send(packet).await.unwrap();
+        }
+    });
+
+    let mut client = TcpTransport::connect(addr).await.unwrap();
+    assert_eq!(client.peer_addr(), Some(&addr));
+    for packet in [vec![1, 2, 3], vec![], vec![7; 1 << 16]] {
+        client.send(packet.clone()).await.unwrap();
+        let mut received = client.next().await.unwrap().unwrap();
+        received.reverse();
+        assert_eq!(received, packet);
+    }
+    drop(client);
+    server.await.unwrap();
+}
+
+#[tokio::test]
+async fn combinators() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut transport = TcpTransport::new(stream);
+        let packet = transport.next().await.unwrap().unwrap();
+        transport.send(packet).await.unwrap();
+    });
+
+    // `SinkExt` and `StreamExt` have combinators of the same names
+    let client = TcpTransport::connect(addr).await.unwrap();
+    let client = TransportExt::with(client, |s: String| ready(Ok(s.into_bytes())));
+    let mut client = TransportExt::then(client, |packet| {
+        ready(String::from_utf8(packet).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)))
+    });
+    client.send("hello, world".to_string()).await.unwrap();
+    assert_eq!(client.next().await.unwrap().unwrap(), "hello, world");
+    server.await.unwrap();
+}
+
+#[tokio::test]
+async fn local_socket_round_trip() {
+    let name = format!("@docatlas-transport-{}", std::process::id());
+    let listener = LocalSocketListener::bind(name.as_str()).unwrap();
+    let server = tokio::spawn(async move {
+        let stream = listener.accept().await.unwrap();
+        let mut transport = LocalSocketTransport::new(stream);
+        let packet = transport.next().await.unwrap().unwrap();
+        transport.send(packet).await.unwrap();
+    });
+
+    let mut client = LocalSocketTransport::connect(&name).await.unwrap();
+    assert_eq!(client.peer_id(), Some(&std::process::id()));
+    client.send(b"ping".to_vec()).await.unwrap();
+    assert_eq!(client.next().await.unwrap().unwrap(), b"ping");
+    server.await.unwrap();
+}
+
+#[tokio::test]
+async fn handshake() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut transport = TcpTransport::new(stream);
+        let capabilities =
+            ServerCapabilities::default().with_formats([WireFormat::Json, WireFormat::Ron]);
+        let (_, hello) = handshake::accept(&mut transport, &capabilities)
+            .await
+            .unwrap();
+        let mut transport =
+            wire::wire_transport::<u64, (String, u64), _, _>(transport, hello.codec());
+        let (_, n) = transport.next().await.unwrap().unwrap();
+        transport.send(n * 2).await.unwrap();
+    });
+
+    let mut client = TcpTransport::connect(addr).await.unwrap();
+    let hello = handshake::connect(&mut client, &ClientHello::default())
+        .await
+        .unwrap();
+    assert_eq!(hello.format, WireFormat::Ron);
+    assert_eq!(hello.compression, Compression::Zstd);
+    let mut client = wire::wire_transport::<(String, u64), u64, _, _>(client, hello.codec());
+    client.send(("double".to_string(), 21)).await.unwrap();
+    assert_eq!(client.next().await.unwrap().unwrap(), 42);
+    server.await.unwrap();
+}
+
+#[tokio::test]
+async fn multiplexed_requests() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut transport = wire::wire_transport::<Envelope<String>, Envelope<u64>, _, _>(
+            TcpTransport::new(stream),
+            Postcard,
+        );
+        let first = transport.next().await.unwrap().unwrap();
+        let second = transport.next().await.unwrap().unwrap();
+        // answer out of order, streaming the first response in chunks
+        transport
+            .send(Envelope::new(second.id(), second.body().to_string()))
+            .await
+            .unwrap();
+        for i in 0..*first.body() {
+            transport
+                .send(Envelope::chunk(first.id(), i.to_string()))
+                .await
+                .unwrap();
+        }
+        transport
+            .send(Envelope::new(first.id(), "done".to_string()))
+            .await
+            .unwrap();
+    });
+
+    let client = wire::wire_transport(TcpTransport::connect(addr).await.unwrap(), Postcard);
+    let (dispatcher, driver) = Dispatcher::<u64, String>::new(client);
+    let driver = tokio::spawn(driver);
+
+    let first = dispatcher.request(3).await.unwrap();
+    let second = dispatcher.call(42).await.unwrap();
+    assert_eq!(second, "42");
+    assert_eq!(first.collect::<Vec<_>>().await, ["0", "1", "2", "done"]);
+    assert_eq!(dispatcher.in_flight(), 0);
+
+    server.await.unwrap();
+    driver.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn websocket_round_trip() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut transport = WebSocketTransport::accept(stream).await.unwrap();
+        while let Some(packet) = transport.next().await {
+            transport.send(packet.unwrap()).await.unwrap();
+        }
+    });
+
+    let mut client = WebSocketTransport::connect(&format!("ws://{addr}"))
+        .await
+        .unwrap();
+    assert_eq!(client.peer_addr(), Some(&addr));
+    for packet in [vec![1, 2, 3], vec![], vec![7; 1 << 16]] {
+        client.send(packet.clone()).await.unwrap();
+        assert_eq!(client.next().await.unwrap().unwrap(), packet);
+    }
+    client.close().await.unwrap();
+    server.await.unwrap();
+}
+
+#[tokio::test]
+async fn keepalive() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let config = KeepaliveConfig::default()
+        .with_interval(Duration::from_millis(20))
+        .with_idle_timeout(Duration::from_millis(100));
+    let server = tokio::spawn(async move {
+        let mut results = vec![];
+        for _ in 0..2 {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut transport = Keepalive::new(TcpTransport::new(stream), config);
+            results.push(transport.next().await.unwrap().map_err(|e| e.kind()));
+        }
+        results
+    });
+
+    let mut pinging = Keepalive::new(TcpTransport::connect(addr).await.unwrap(), config);
+    tokio::time::timeout(Duration::from_millis(300), pinging.next())
+        .await
+        .expect_err("only pings and pongs are exchanged");
+    pinging.send(vec![1, 2, 3]).await.unwrap();
+
+    let silent = TcpTransport::connect(addr).await.unwrap();
+    let results = server.await.unwrap();
+    drop(silent);
+    assert_eq!(results, [Ok(vec![1, 2, 3]), Err(io::ErrorKind::TimedOut)]);
+}
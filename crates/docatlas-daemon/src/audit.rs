@@ -0,0 +1,416 @@
+//! The audit log, recording who changed what and when.
+//!
+//! Every change made through the binary protocol, the http and grpc apis, every login, and every
+//! reload of the config is appended to a file as a line of json. Each entry holds the hash of the entry before it, and its
+//! own hash covers that, so editing, removing or reordering entries breaks the chain from that
+//! point on. [`AuditLog::verify`](AuditLog::verify) walks the chain, reporting the first entry
+//! that does not match.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use blake2::{Blake2s256, Digest};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::client::ClientRequest;
+
+/// The hash the first entry chains from
+const GENESIS: &str = "";
+
+/// Something done to the daemon that is worth auditing
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum AuditAction {
+    /// A client tried to authenticate
+    Login {
+        username: Option<String>,
+    },
+    /// Too many attempts to authenticate as a user, or from an address, failed in a row
+    LockedOut {
+        username: Option<String>,
+        ip: Option<String>,
+    },
+    IndexCreated {
+        index: String,
+    },
+    IndexDropped {
+        index: String,
+    },
+    SchemaChanged {
+        index: String,
+    },
+    DocumentsWritten {
+        index: String,
+        count: usize,
+    },
+    DocumentDeleted {
+        index: String,
+    },
+    SnapshotCreated {
+        repository: String,
+        snapshot: String,
+    },
+    SnapshotRestored {
+        repository: String,
+        snapshot: String,
+    },
+    Promoted,
+    /// A user changed their password
+    PasswordChanged {
+        user: String,
+    },
+    /// Every session of a user was ended
+    SessionsRevoked {
+        user: String,
+    },
+    UserCreated {
+        user: String,
+    },
+    /// An admin replaced the password of a user
+    PasswordReset {
+        user: String,
+    },
+    UserDeleted {
+        user: String,
+    },
+    GroupCreated {
+        group: String,
+    },
+    GroupDeleted {
+        group: String,
+    },
+    GroupMemberAdded {
+        group: String,
+        user: String,
+    },
+    GroupMemberRemoved {
+        group: String,
+        user: String,
+    },
+    /// The roles given to every member of a group were replaced
+    GroupRolesChanged {
+        group: String,
+        roles: Vec<String>,
+    },
+    /// Settings took effect after the config was reloaded
+    SettingsChanged {
+        settings: Vec<String>,
+    },
+}
+
+impl AuditAction {
+    /// Gets the action a request takes, or `None` if it only reads
+    pub fn of(request: &ClientRequest) -> Option<Self> {
+        let action = match request {
+            ClientRequest::CreateIndex { index, .. } => AuditAction::IndexCreated {
+                index: index.clone(),
+            },
+            ClientRequest::DeleteIndex { index } => AuditAction::IndexDropped {
+                index: index.clone(),
+            },
+            ClientRequest::PutSchema { index, .. } | ClientRequest::UpdateMapping { index, .. } => {
+                AuditAction::SchemaChanged {
+                    index: index.clone(),
+                }
+            }
+            ClientRequest::IndexDocument { index, .. } => AuditAction::DocumentsWritten {
+                index: index.clone(),
+                count: 1,
+            },
+            ClientRequest::Bulk { index, documents } => AuditAction::DocumentsWritten {
+                index: index.clone(),
+                count: documents.len(),
+            },
+            ClientRequest::Transaction { index, transaction } => AuditAction::DocumentsWritten {
+                index: index.clone(),
+                count: transaction.len(),
+            },
+            ClientRequest::Delete { index, .. } => AuditAction::DocumentDeleted {
+                index: index.clone(),
+            },
+            ClientRequest::CreateSnapshot {
+                repository,
+                snapshot,
+            } => AuditAction::SnapshotCreated {
+                repository: repository.clone(),
+                snapshot: snapshot.clone(),
+            },
+            ClientRequest::RestoreSnapshot {
+                repository,
+                snapshot,
+            } => AuditAction::SnapshotRestored {
+                repository: repository.clone(),
+                snapshot: snapshot.clone(),
+            },
+            ClientRequest::Promote => AuditAction::Promoted,
+            ClientRequest::RevokeSessions { user } => {
+                AuditAction::SessionsRevoked { user: user.clone() }
+            }
+            ClientRequest::CreateUser { user, .. } => {
+                AuditAction::UserCreated { user: user.clone() }
+            }
+            ClientRequest::ResetPassword { user, .. } => {
+                AuditAction::PasswordReset { user: user.clone() }
+            }
+            ClientRequest::DeleteUser { user } => AuditAction::UserDeleted { user: user.clone() },
+            ClientRequest::CreateGroup { group } => AuditAction::GroupCreated {
+                group: group.clone(),
+            },
+            ClientRequest::DeleteGroup { group } => AuditAction::GroupDeleted {
+                group: group.clone(),
+            },
+            ClientRequest::AddGroupMember { group, user } => AuditAction::GroupMemberAdded {
+                group: group.clone(),
+                user: user.clone(),
+            },
+            ClientRequest::RemoveGroupMember { group, user } => AuditAction::GroupMemberRemoved {
+                group: group.clone(),
+                user: user.clone(),
+            },
+            ClientRequest::SetGroupRoles { group, roles } => AuditAction::GroupRolesChanged {
+                group: group.clone(),
+                roles: roles.clone(),
+            },
+            ClientRequest::Traced { request, .. } | ClientRequest::Timed { request, .. } => {
+                return Self::of(request)
+            }
+            _ => return None,
+        };
+        Some(action)
+    }
+}
+
+/// A single entry of the audit log
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// The position of the entry in the log, starting at 1
+    pub seq: u64,
+    /// When the action was taken, in milliseconds since the unix epoch
+    pub timestamp_ms: u64,
+    /// The user who took the action, if they authenticated
+    pub user: Option<String>,
+    /// The address of the client who took the action, if it was taken by a client
+    pub peer: Option<String>,
+    pub action: AuditAction,
+    /// Why the action failed, if it did
+    pub error: Option<String>,
+    /// The hash of the entry before this one
+    pub previous: String,
+    /// The hash of this entry
+    pub hash: String,
+}
+
+impl AuditEntry {
+    /// Computes the hash of this entry, which covers every field but the hash itself
+    fn digest(&self) -> String {
+        let contents = serde_json::to_vec(&(
+            self.seq,
+            self.timestamp_ms,
+            &self.user,
+            &self.peer,
+            &self.action,
+            &self.error,
+            &self.previous,
+        ))
+        .expect("entries can always be serialized");
+        Blake2s256::digest(contents)
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+}
+
+/// Selects entries of the audit log
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AuditQuery {
+    /// Only entries after this sequence number
+    pub after: Option<u64>,
+    /// Only entries of this user
+    pub user: Option<String>,
+    /// The most entries returned, the oldest first
+    pub limit: Option<usize>,
+}
+
+impl AuditQuery {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        self.after.is_none_or(|after| entry.seq > after)
+            && self
+                .user
+                .as_ref()
+                .is_none_or(|user| entry.user.as_ref() == Some(user))
+    }
+}
+
+/// An append only log of the changes made to the daemon
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+    state: Mutex<AuditState>,
+}
+
+#[derive(Debug)]
+struct AuditState {
+    file: File,
+    seq: u64,
+    last_hash: String,
+}
+
+impl AuditLog {
+    /// Opens the audit log at the given path, creating it if it does not exist. New entries are
+    /// chained from the last entry in the file.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, AuditError> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let last = read_entries(&path)?.pop();
+        Ok(Self {
+            path,
+            state: Mutex::new(AuditState {
+                file,
+                seq: last.as_ref().map_or(0, |entry| entry.seq),
+                last_hash: last.map_or(GENESIS.to_string(), |entry| entry.hash),
+            }),
+        })
+    }
+
+    /// Gets the file the log is stored in
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Appends an entry to the log, flushing it to disk before returning
+    pub fn record(
+        &self,
+        user: Option<&str>,
+        peer: Option<&str>,
+        action: AuditAction,
+        error: Option<String>,
+    ) -> Result<AuditEntry, AuditError> {
+        let mut state = self.state.lock().expect("poisoned");
+        let mut entry = AuditEntry {
+            seq: state.seq + 1,
+            timestamp_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis() as u64),
+            user: user.map(str::to_string),
+            peer: peer.map(str::to_string),
+            action,
+            error,
+            previous: state.last_hash.clone(),
+            hash: String::new(),
+        };
+        entry.hash = entry.digest();
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        state.file.write_all(&line)?;
+        state.file.sync_data()?;
+        state.seq = entry.seq;
+        state.last_hash = entry.hash.clone();
+        Ok(entry)
+    }
+
+    /// Gets the entries matching a query
+    pub fn entries(&self, query: &AuditQuery) -> Result<Vec<AuditEntry>, AuditError> {
+        // holding the lock keeps half written entries from being read
+        let _state = self.state.lock().expect("poisoned");
+        Ok(read_entries(&self.path)?
+            .into_iter()
+            .filter(|entry| query.matches(entry))
+            .take(query.limit.unwrap_or(usize::MAX))
+            .collect())
+    }
+
+    /// Checks that no entry was changed, removed or reordered, returning the number of entries
+    pub fn verify(&self) -> Result<u64, AuditError> {
+        let _state = self.state.lock().expect("poisoned");
+        let mut previous = GENESIS.to_string();
+        let mut count = 0;
+        for entry in read_entries(&self.path)? {
+            count += 1;
+            if entry.seq != count || entry.previous != previous || entry.hash != entry.digest() {
+                return Err(AuditError::Tampered(entry.seq));
+            }
+            previous = entry.hash;
+        }
+        Ok(count)
+    }
+}
+
+/// Reads every entry of an audit log file
+fn read_entries(path: &Path) -> Result<Vec<AuditEntry>, AuditError> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    BufReader::new(file)
+        .lines()
+        .filter(|line| !matches!(line, Ok(line) if line.is_empty()))
+        .map(|line| Ok(serde_json::from_str(&line?)?))
+        .collect()
+}
+
+/// The audit log could not be written or read
+#[derive(Debug, Error)]
+pub enum AuditError {
+    #[error("The audit log was tampered with at entry {0}")]
+    Tampered(u64),
+    #[error("Invalid audit log entry: {0}")]
+    InvalidEntry(#[from] serde_json::Error),
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chains_entries() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.log");
+        let log = AuditLog::open(&path).unwrap();
+        let login = AuditAction::Login {
+            username: Some("alice".to_string()),
+        };
+        log.record(Some("alice"), Some("127.0.0.1:5000"), login, None)
+            .unwrap();
+        let created = AuditAction::IndexCreated {
+            index: "books".to_string(),
+        };
+        log.record(Some("alice"), None, created, None).unwrap();
+        drop(log);
+
+        // reopening chains new entries from the last one
+        let log = AuditLog::open(&path).unwrap();
+        let denied = AuditAction::IndexDropped {
+            index: "books".to_string(),
+        };
+        let entry = log
+            .record(Some("bob"), None, denied, Some("unauthorized".to_string()))
+            .unwrap();
+        assert_eq!(entry.seq, 3);
+        assert_eq!(log.verify().unwrap(), 3);
+
+        let query = AuditQuery {
+            user: Some("alice".to_string()),
+            ..AuditQuery::default()
+        };
+        assert_eq!(log.entries(&query).unwrap().len(), 2);
+        let query = AuditQuery {
+            after: Some(1),
+            limit: Some(1),
+            ..AuditQuery::default()
+        };
+        assert_eq!(log.entries(&query).unwrap()[0].seq, 2);
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, contents.replace("\"bob\"", "\"eve\"")).unwrap();
+        assert!(matches!(log.verify(), Err(AuditError::Tampered(3))));
+    }
+}
@@ -0,0 +1,27 @@
+//! Runs the daemon in the background
+
+use docatlas_daemon::config::DaemonConfig;
+
+/// Forks into the background and detaches from the terminal, writing stdout and stderr to the log
+/// file. Must be called before the async runtime is started, as only the calling thread survives
+/// the fork.
+#[cfg(unix)]
+pub fn daemonize(config: &DaemonConfig) -> Result<(), anyhow::Error> {
+    let log = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(config.log_file())?;
+    daemonize::Daemonize::new()
+        // relative paths in the config stay valid
+        .working_directory(std::env::current_dir()?)
+        .stdout(log.try_clone()?)
+        .stderr(log)
+        .start()?;
+    Ok(())
+}
+
+/// Forking is only supported on unix
+#[cfg(not(unix))]
+pub fn daemonize(_config: &DaemonConfig) -> Result<(), anyhow::Error> {
+    anyhow::bail!("--daemonize is only supported on unix")
+}
@@ -1,13 +1,35 @@
 //! Setups logging
 
+use std::io::Write;
+use std::sync::Mutex;
 use std::time::SystemTime;
 
-use docatlas_daemon::config::DaemonConfig;
+use docatlas_daemon::config::{DaemonConfig, LogFormat};
+use docatlas_daemon::log_rotation::RotatingFile;
+use log::LevelFilter;
+use tracing_subscriber::filter::filter_fn;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::fmt::writer::{BoxMakeWriter, MakeWriterExt};
+use tracing_subscriber::prelude::*;
 
-/// Setups logging using the [`fern`](fern) framework. Panics if fern
-/// could not be initialized correctly.
-pub fn setup_logging(config: &DaemonConfig) {
-    fern::Dispatch::new()
+/// Setups logging in the configured format. Panics if logging could not be
+/// initialized correctly.
+///
+/// Records are only filtered by the global max level, so that reloading the
+/// config can change the log level. Daemonized processes only log to the log
+/// file, as their stdout already is the log file. The log file is rotated as
+/// configured.
+pub fn setup_logging(config: &DaemonConfig, daemonized: bool) {
+    match config.log_format() {
+        LogFormat::Text => setup_text_logging(config, daemonized),
+        LogFormat::Json => setup_json_logging(config, daemonized),
+    }
+    log::set_max_level(*config.log_level());
+}
+
+/// Setups logging using the [`fern`](fern) framework
+fn setup_text_logging(config: &DaemonConfig, daemonized: bool) {
+    let dispatch = fern::Dispatch::new()
         .format(|out, msg, record| {
             out.finish(format_args!(
                 "{} [{}] {} - {}",
@@ -17,9 +39,44 @@ pub fn setup_logging(config: &DaemonConfig) {
                 msg
             ))
         })
-        .level(config.log_level().clone())
-        .chain(std::io::stdout())
-        .chain(fern::log_file(config.path().join("docatlas.log")).unwrap())
-        .apply()
-        .expect("could not initialize logger")
+        .level(LevelFilter::Trace)
+        // tracing also logs every time a span is entered and exited, which is only noise here
+        .filter(|metadata| !metadata.target().starts_with("tracing::span"))
+        .chain(Box::new(log_file(config)) as Box<dyn Write + Send>);
+    let dispatch = match daemonized {
+        true => dispatch,
+        false => dispatch.chain(std::io::stdout()),
+    };
+    dispatch.apply().expect("could not initialize logger");
+}
+
+/// Setups logging a json object per line using [`tracing_subscriber`](tracing_subscriber),
+/// with the fields of events such as the request id, index and duration as properties. Spans
+/// are logged when they close if `log_spans` is set.
+fn setup_json_logging(config: &DaemonConfig, daemonized: bool) {
+    let file = log_file(config);
+    let writer = match daemonized {
+        true => BoxMakeWriter::new(Mutex::new(file)),
+        false => BoxMakeWriter::new(Mutex::new(file).and(std::io::stdout)),
+    };
+    let layer = tracing_subscriber::fmt::layer()
+        .json()
+        .flatten_event(true)
+        .with_span_events(match config.log_spans() {
+            true => FmtSpan::CLOSE,
+            false => FmtSpan::NONE,
+        })
+        .with_writer(writer)
+        .with_filter(filter_fn(|metadata| {
+            tracing_log::AsLog::as_log(metadata.level()) <= log::max_level()
+        }));
+    tracing_subscriber::registry()
+        .with(layer)
+        .try_init()
+        .expect("could not initialize logger");
+}
+
+/// Opens the rotated log file
+fn log_file(config: &DaemonConfig) -> RotatingFile {
+    RotatingFile::open(config.log_file(), config.log_rotation()).expect("could not open log file")
 }
@@ -1,25 +1,28 @@
 use clap::Parser;
-use docatlas_daemon::config::{CliDaemonConfig, DaemonConfig};
+use docatlas_daemon::config::CliDaemonConfig;
 use docatlas_daemon::main_loop::main_loop;
+use docatlas_daemon::pid_file::{self, PidFile};
 use futures::{FutureExt, StreamExt};
 use log::debug;
-use merge::Merge;
-use std::fs::File;
 use tracing::info;
 
+mod daemonize;
 mod logging;
 
-#[tokio::main]
-async fn main() -> Result<(), anyhow::Error> {
-    let mut config = CliDaemonConfig::parse().into_config();
-    if let Ok(file) = File::open(config.path().join("config.yaml")) {
-        let read_config: DaemonConfig =
-            serde_yaml::from_reader(file).expect("could not parse config file");
-        config.merge(read_config);
-    }
+fn main() -> Result<(), anyhow::Error> {
+    let cli = CliDaemonConfig::parse();
+    let daemonized = cli.daemonize();
+    let command_line = cli.into_config();
+    let config = command_line.clone().with_config_file()?;
     std::fs::create_dir_all(config.path())?;
+    // refuse to start while still attached to the terminal, so the error is seen
+    pid_file::check(config.pid_file())?;
+    if daemonized {
+        daemonize::daemonize(&config)?;
+    }
+    let _pid_file = PidFile::create(config.pid_file())?;
 
-    logging::setup_logging(&config);
+    logging::setup_logging(&config, daemonized);
     info!(
         "starting docatlasd instance at {:?} on port {}.",
         config.host(),
@@ -28,6 +31,9 @@ async fn main() -> Result<(), anyhow::Error> {
     info!("docatlasd version: {}", env!("CARGO_PKG_VERSION"));
     debug!("running in dir {:?}", config.path());
 
-    main_loop(&config).await?;
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(main_loop(&config, &command_line))?;
     Ok(())
 }
@@ -0,0 +1,339 @@
+//! Change data capture, streaming the changes made to the documents of an index to the clients
+//! subscribed to it.
+//!
+//! Every change made to the documents of an index is numbered by its position in the
+//! [write-ahead log](crate::wal) of the index, starting at 1, and described by an event for every
+//! document it added, updated or deleted. Documents added by a single bulk request share the
+//! sequence number of the request. The most recent changes are kept in a [`ChangeFeed`], so
+//! subscribers resuming from the last sequence number they saw are usually served from memory.
+//! Older changes are read back from the log.
+//!
+//! Replacing the schema of an index, replacing it with imported rows, [merging](crate::merge) its
+//! log or dropping it starts a new log, numbered from 1 again. Subscriptions to the index end when
+//! that happens, and sequence numbers seen before no longer point into the log.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+
+use docatlas_core::document::Document;
+use docatlas_core::fields::FieldData;
+use docatlas_core::index::{IndexWriter, Upserted};
+use docatlas_core::query::string::{QueryString, QueryStringError};
+use docatlas_core::query::{Query, QueryError};
+use docatlas_core::schema::Schema;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::watch;
+
+use crate::replication;
+
+/// The default number of document changes kept in memory for every index
+pub const DEFAULT_CHANGES_BACKLOG: usize = 10_000;
+/// The most events read back from the write-ahead log of an index at once
+pub const MAX_REPLAYED_EVENTS: usize = 10_000;
+
+/// What happened to a document
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Added,
+    Updated,
+    Deleted,
+}
+
+/// A document that was added, updated or deleted
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChangeEvent {
+    /// The sequence number of the change within the log of the index
+    pub seq: u64,
+    pub kind: ChangeKind,
+    /// The primary key of the document, if the index has one
+    pub key: Option<FieldData>,
+    /// The document once it was added or updated, which deletions do not carry
+    pub document: Option<Document>,
+}
+
+/// Selects the events a subscriber receives
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangeFilter {
+    /// The kinds of changes to receive, or every kind if empty
+    #[serde(default)]
+    pub kinds: Vec<ChangeKind>,
+    /// Only receives the documents matching a [query string](docatlas_core::query::string),
+    /// which must name the field of every term. Deletions carry no document, so they are only
+    /// filtered by kind.
+    #[serde(default)]
+    pub query: Option<String>,
+}
+
+/// Where the log of an index is at
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangesPosition {
+    /// The id of the log, which changes every time the log starts again
+    pub log: u64,
+    /// The sequence number of the latest change
+    pub last: u64,
+}
+
+/// A document changed by a change to an index, which is read from the index to describe it
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Changed {
+    Added(usize),
+    Updated(usize),
+    Deleted(FieldData),
+}
+
+impl Changed {
+    /// Gets the document changed by an upsert, unless the document was dropped
+    pub(crate) fn upserted(upserted: Upserted) -> Option<Self> {
+        match upserted {
+            Upserted::Inserted(row) => Some(Changed::Added(row)),
+            Upserted::Updated(row) => Some(Changed::Updated(row)),
+            Upserted::Dropped => None,
+        }
+    }
+}
+
+/// The most recent changes made to the documents of every index
+#[derive(Debug)]
+pub struct ChangeFeed {
+    backlog: usize,
+    state: Mutex<HashMap<String, IndexChanges>>,
+}
+
+#[derive(Debug)]
+struct IndexChanges {
+    position: ChangesPosition,
+    /// No longer keeps every event of this change, nor any event of the changes before it
+    trimmed: u64,
+    events: VecDeque<ChangeEvent>,
+    /// Counts the changes published to the index, to wake its subscribers. It is kept when the
+    /// log starts again, and dropped along with the index.
+    published: watch::Sender<u64>,
+}
+
+impl ChangeFeed {
+    /// Creates a feed keeping up to `backlog` events of every index
+    pub fn new(backlog: usize) -> Self {
+        Self {
+            backlog,
+            state: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Gets where the log of an index is at, if the index exists
+    pub fn position(&self, index: &str) -> Option<ChangesPosition> {
+        let state = self.state.lock().expect("feed poisoned");
+        state.get(index).map(|changes| changes.position)
+    }
+
+    /// Numbers the next change to an index, keeping its events and dropping the oldest events once
+    /// the backlog is full. Returns the sequence number of the change.
+    pub fn publish(&self, index: &str, events: Vec<ChangeEvent>) -> u64 {
+        let mut state = self.state.lock().expect("feed poisoned");
+        let changes = state
+            .entry(index.to_string())
+            .or_insert_with(|| IndexChanges::new(0, watch::channel(0).0));
+        let seq = changes.position.last + 1;
+        changes.position.last = seq;
+        changes
+            .events
+            .extend(events.into_iter().map(|event| ChangeEvent { seq, ..event }));
+        while changes.events.len() > self.backlog {
+            if let Some(dropped) = changes.events.pop_front() {
+                changes.trimmed = dropped.seq;
+            }
+        }
+        changes.published.send_modify(|published| *published += 1);
+        seq
+    }
+
+    /// Starts a new log for an index, already holding `last` changes that are not kept
+    pub fn restart(&self, index: &str, last: u64) {
+        let mut state = self.state.lock().expect("feed poisoned");
+        let published = match state.remove(index) {
+            Some(changes) => changes.published,
+            None => watch::channel(0).0,
+        };
+        published.send_modify(|published| *published += 1);
+        state.insert(index.to_string(), IndexChanges::new(last, published));
+    }
+
+    /// Forgets the changes to an index that was dropped, which ends the waits of its subscribers
+    pub fn remove(&self, index: &str) {
+        self.state.lock().expect("feed poisoned").remove(index);
+    }
+
+    /// Gets every event of the changes to an index after `after` in the log with the given id, or
+    /// `None` if any of them are no longer kept
+    pub fn since(&self, index: &str, log: u64, after: u64) -> Option<Vec<ChangeEvent>> {
+        let state = self.state.lock().expect("feed poisoned");
+        let changes = state.get(index)?;
+        if changes.position.log != log || after < changes.trimmed {
+            return None;
+        }
+        Some(
+            changes
+                .events
+                .iter()
+                .filter(|event| event.seq > after)
+                .cloned()
+                .collect(),
+        )
+    }
+
+    /// Waits for changes to be published to an index, or for its log to start again, if the
+    /// changes to the index are captured. Waiting fails once the index is dropped.
+    pub fn subscribe(&self, index: &str) -> Option<watch::Receiver<u64>> {
+        let state = self.state.lock().expect("feed poisoned");
+        state
+            .get(index)
+            .map(|changes| changes.published.subscribe())
+    }
+}
+
+impl IndexChanges {
+    fn new(last: u64, published: watch::Sender<u64>) -> Self {
+        Self {
+            position: ChangesPosition {
+                log: replication::new_log_id(),
+                last,
+            },
+            trimmed: last,
+            events: VecDeque::new(),
+            published,
+        }
+    }
+}
+
+impl ChangeFilter {
+    /// Parses the query string of the filter, if it has one
+    pub fn query(&self) -> Result<Option<Query>, QueryStringError> {
+        self.query
+            .as_deref()
+            .map(|query| QueryString::new().parse(query))
+            .transpose()
+    }
+
+    /// Checks if a subscriber receives an event, using the query the filter's query string was
+    /// parsed into
+    pub fn matches(
+        &self,
+        event: &ChangeEvent,
+        query: Option<&Query>,
+        schema: &Schema,
+    ) -> Result<bool, QueryError> {
+        if !self.kinds.is_empty() && !self.kinds.contains(&event.kind) {
+            return Ok(false);
+        }
+        match (query, &event.document) {
+            (Some(query), Some(document)) => query.matches(document, schema),
+            _ => Ok(true),
+        }
+    }
+}
+
+/// Describes the documents changed by a change, reading them from the index the change was just
+/// made to. The events are numbered once they are [published](ChangeFeed::publish).
+pub(crate) fn events(writer: &IndexWriter, changed: Vec<Changed>) -> Vec<ChangeEvent> {
+    let key_of = |document: &Document| {
+        let primary_key = writer.schema().primary_key()?;
+        document.get(&primary_key.name)?.data().first().cloned()
+    };
+    changed
+        .into_iter()
+        .filter_map(|changed| {
+            let (kind, row) = match changed {
+                Changed::Added(row) => (ChangeKind::Added, row),
+                Changed::Updated(row) => (ChangeKind::Updated, row),
+                Changed::Deleted(key) => {
+                    return Some(ChangeEvent {
+                        seq: 0,
+                        kind: ChangeKind::Deleted,
+                        key: Some(key),
+                        document: None,
+                    })
+                }
+            };
+            let document = writer.read(row)?.ok()?;
+            Some(ChangeEvent {
+                seq: 0,
+                kind,
+                key: key_of(&document),
+                document: Some(document),
+            })
+        })
+        .collect()
+}
+
+/// The changes to an index could not be read
+#[derive(Debug, Error)]
+pub enum ChangesError {
+    #[error("Changes to {index:?} are not captured")]
+    NotCaptured { index: String },
+    #[error("Changes to {index:?} after {after} are no longer kept")]
+    Unavailable { index: String, after: u64 },
+    #[error("{index:?} has no change {after}, its latest change is {last}")]
+    Ahead {
+        index: String,
+        after: u64,
+        last: u64,
+    },
+    #[error("The log of {0:?} started again, so its changes are numbered from 1 again")]
+    Restarted(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deleted(key: &str) -> ChangeEvent {
+        ChangeEvent {
+            seq: 0,
+            kind: ChangeKind::Deleted,
+            key: Some(FieldData::Bytes(key.as_bytes().into())),
+            document: None,
+        }
+    }
+
+    #[test]
+    fn keep_recent_changes() {
+        let feed = ChangeFeed::new(3);
+        feed.restart("books", 0);
+        feed.restart("films", 0);
+        let log = feed.position("books").unwrap().log;
+        let published = feed.subscribe("books").unwrap();
+        let mut films = feed.subscribe("films").unwrap();
+        films.borrow_and_update();
+        assert_eq!(feed.publish("books", vec![deleted("b1")]), 1);
+        assert_eq!(feed.publish("books", vec![deleted("b2"), deleted("b3")]), 2);
+        assert_eq!(feed.publish("books", vec![]), 3);
+        assert!(published.has_changed().unwrap());
+        // only the subscribers of the index the changes were published to are woken
+        assert!(!films.has_changed().unwrap());
+        assert_eq!(feed.position("books").unwrap().last, 3);
+
+        let seqs = |events: Vec<ChangeEvent>| events.iter().map(|e| e.seq).collect::<Vec<_>>();
+        assert_eq!(seqs(feed.since("books", log, 0).unwrap()), [1, 2, 2]);
+        assert_eq!(seqs(feed.since("books", log, 2).unwrap()), [] as [u64; 0]);
+        // the first change no longer fits in the backlog
+        feed.publish("books", vec![deleted("b4")]);
+        assert!(feed.since("books", log, 0).is_none());
+        assert_eq!(seqs(feed.since("books", log, 1).unwrap()), [2, 2, 4]);
+        // only part of the second change is kept
+        feed.publish("books", vec![deleted("b5")]);
+        assert!(feed.since("books", log, 1).is_none());
+        assert_eq!(seqs(feed.since("books", log, 2).unwrap()), [4, 5]);
+
+        feed.restart("books", 1);
+        let restarted = feed.position("books").unwrap();
+        assert_ne!(restarted.log, log);
+        assert!(feed.since("books", log, 5).is_none());
+        assert!(feed.since("books", restarted.log, 0).is_none());
+        assert_eq!(feed.since("books", restarted.log, 1).unwrap().len(), 0);
+        feed.remove("books");
+        assert!(feed.position("books").is_none());
+        assert!(published.has_changed().is_err());
+        assert!(feed.subscribe("books").is_none());
+    }
+}
@@ -1,65 +1,626 @@
-use async_stream::stream;
-use futures::stream::BoxStream;
-use futures::{FutureExt, Sink, SinkExt, Stream, StreamExt};
+use std::fmt::{Debug, Formatter};
+use std::future::Future;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+use docatlas_core::auth::authentication::AuthenticationRequest;
+use docatlas_core::auth::authorization::Permission;
+use docatlas_core::auth::users::Group;
+use docatlas_core::document::Document;
+use docatlas_core::error::DocatlasError;
+use docatlas_core::fields::FieldData;
+use docatlas_core::index::{Transaction, UpsertMode, Upserted, Written};
+use docatlas_core::query::string::Operator;
+use docatlas_core::schema::Schema;
+use docatlas_core::sql::Table;
+use docatlas_core::transport::handshake::{
+    self, AuthMethod, ClientHello, HandshakeError, ServerCapabilities, ServerInfo,
+};
+use docatlas_core::transport::keepalive::Keepalive;
+use docatlas_core::transport::metrics::{Timed, TransportMetrics};
+use docatlas_core::transport::mux::Envelope;
+use docatlas_core::transport::queue::{QueueConfig, QueueError, SendQueue};
+use docatlas_core::transport::wire;
+use docatlas_core::transport::Transport;
+use futures::stream::SplitStream;
+use futures::{future, SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
-use serde_pickle::DeOptions;
-use tokio::io::{AsyncRead, AsyncReadExt};
 
-pub struct Client<Si, So>
+use crate::audit::{AuditEntry, AuditQuery};
+use crate::changes::{ChangeEvent, ChangeFilter};
+use crate::cluster::ClusterHealth;
+use crate::config::DaemonConfig;
+use crate::handlers::{Hit, IndexInfo};
+use crate::health::Readiness;
+use crate::index_manager::PathUsage;
+use crate::snapshot::SnapshotInfo;
+use crate::trace::TraceContext;
+
+/// A client connected to the daemon
+pub struct Client<T>
 where
-    Si: Stream<Item = ClientRequest> + Unpin,
-    So: Sink<ClientResponse> + Unpin,
+    T: Transport<Envelope<VersionedResponse>, Envelope<VersionedRequest>> + Unpin,
 {
-    stream: Si,
-    sink: So,
+    requests: SplitStream<T>,
+    responses: SendQueue<Envelope<ClientResponse>>,
 }
 
-impl<Si, So> Client<Si, So>
+impl<T> Client<T>
 where
-    Si: Stream<Item = ClientRequest> + Unpin,
-    So: Sink<ClientResponse> + Unpin,
+    T: Transport<Envelope<VersionedResponse>, Envelope<VersionedRequest>> + Unpin,
 {
-    pub fn new(stream: Si, sink: So) -> Self {
-        Self { stream, sink }
+    /// Creates a client whose responses are sent through a bounded queue, returning the client and
+    /// the driver writing its responses
+    pub fn new(
+        transport: T,
+        queue: QueueConfig,
+    ) -> (Self, impl Future<Output = Result<(), T::Error>>) {
+        let (sink, requests) = transport.split();
+        let sink = sink.with(|envelope: Envelope<ClientResponse>| {
+            future::ready(Ok::<_, T::Error>(envelope.map(VersionedResponse::from)))
+        });
+        let (responses, writer) = SendQueue::new(sink, queue);
+        (
+            Self {
+                requests,
+                responses,
+            },
+            writer,
+        )
+    }
+
+    /// Waits for the next request of the client, converted into the latest version of the
+    /// protocol, returning `None` once the client disconnects
+    pub async fn poll_request(&mut self) -> Option<Result<Envelope<ClientRequest>, T::Error>> {
+        let request = self.requests.next().await?;
+        Some(request.map(|envelope| envelope.map(VersionedRequest::into_latest)))
     }
 
-    pub async fn poll_request(&mut self) -> Option<ClientRequest> {
-        self.stream.next().await
+    /// Queues the complete response to a request
+    pub async fn send_response(&self, id: u64, resp: ClientResponse) -> Result<(), QueueError> {
+        self.responses.send(Envelope::new(id, resp)).await
     }
 
-    pub async fn send_response(&mut self, resp: ClientResponse) -> Result<(), So::Error> {
-        self.sink.send(resp).await
+    /// Queues a chunk of the response to a request, with more chunks following it
+    pub async fn send_chunk(&self, id: u64, resp: ClientResponse) -> Result<(), QueueError> {
+        self.responses.send(Envelope::chunk(id, resp)).await
     }
+
+    /// Gets the queue of responses waiting to be sent to the client
+    pub fn responses(&self) -> &SendQueue<Envelope<ClientResponse>> {
+        &self.responses
+    }
+}
+
+/// Performs the handshake with a newly connected client, then wraps its packet transport so that
+/// it receives client requests and sends client responses. The client is disconnected once it has
+/// been silent for longer than the negotiated idle timeout.
+pub async fn client_transport<T>(
+    mut transport: T,
+    capabilities: &ServerCapabilities,
+    metrics: &Arc<TransportMetrics>,
+) -> Result<
+    (
+        ClientHello,
+        impl Transport<Envelope<VersionedResponse>, Envelope<VersionedRequest>, Error = io::Error>,
+    ),
+    HandshakeError,
+>
+where
+    T: Transport<Vec<u8>, Vec<u8>, Error = io::Error> + Unpin,
+{
+    let (client, server) = handshake::accept(&mut transport, capabilities).await?;
+    let transport = Keepalive::new(transport, server.keepalive);
+    let codec = Timed::new(server.codec(), metrics.clone());
+    Ok((client, wire::wire_transport(transport, codec)))
+}
+
+/// The capabilities advertised by the daemon during handshakes
+pub fn capabilities(config: &DaemonConfig) -> ServerCapabilities {
+    let capabilities = ServerCapabilities::default()
+        .with_keepalive(config.keepalive())
+        .with_server_info(ServerInfo {
+            name: env!("CARGO_PKG_NAME").to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        });
+    if config.require_auth() {
+        let anonymous = config.anonymous_user().map(|_| AuthMethod::Anonymous);
+        capabilities.with_auth(
+            [AuthMethod::Basic, AuthMethod::Token]
+                .into_iter()
+                .chain(anonymous),
+        )
+    } else {
+        capabilities
+    }
+}
+
+/// A request *received* from a client connection, in any version of the client protocol.
+///
+/// Variants are only ever appended to a version. Changing an existing variant requires a new
+/// version, which older requests are converted into.
+#[derive(Debug, Serialize, Deserialize)]
+pub enum VersionedRequest {
+    V1(ClientRequest),
 }
 
-pub fn wrap_async_read<R: AsyncRead + Unpin + Send + 'static>(
-    mut read: R,
-) -> BoxStream<'static, ClientRequest> {
-    (stream! {
-        loop {
-            yield read_packet(&mut read).await;
+impl VersionedRequest {
+    /// Converts this request into the latest version of the protocol
+    pub fn into_latest(self) -> ClientRequest {
+        match self {
+            VersionedRequest::V1(request) => request,
         }
-    })
-    .boxed()
+    }
+}
+
+/// A response *sent* to a client, in any version of the client protocol
+#[derive(Debug, Serialize, Deserialize)]
+pub enum VersionedResponse {
+    V1(ClientResponse),
 }
 
-async fn read_packet<R: AsyncRead + Unpin>(reader: &mut R) -> ClientRequest {
-    let len = reader
-        .read_u64()
-        .await
-        .expect("could not read packet length");
-    let mut buffer = vec![0_u8; len as usize];
-    reader
-        .read_exact(&mut buffer)
-        .await
-        .expect("could not fill buffer");
-    serde_pickle::from_slice(&buffer, DeOptions::new()).expect("could not deserialize packet")
+impl From<ClientResponse> for VersionedResponse {
+    fn from(value: ClientResponse) -> Self {
+        VersionedResponse::V1(value)
+    }
 }
 
 /// A request *received* from a client connection
-#[derive(Debug, Deserialize)]
-pub struct ClientRequest {}
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ClientRequest {
+    /// Creates a new, empty index
+    CreateIndex { index: String, schema: Schema },
+    /// Sets the schema of an index, creating the index if it does not exist
+    PutSchema { index: String, schema: Schema },
+    /// Inserts a document into an index, or updates the document sharing its primary key.
+    IndexDocument {
+        index: String,
+        document: Document,
+        /// Only updates the fields present in the document if the document already exists
+        #[serde(default)]
+        partial: bool,
+    },
+    /// Adds documents to an index in bulk
+    Bulk {
+        index: String,
+        documents: Vec<Document>,
+    },
+    /// Commits writes to an index all at once, or none of them if any fails
+    Transaction {
+        index: String,
+        transaction: Transaction,
+    },
+    /// Finds up to `limit` documents whose field contains every term of the query
+    Search {
+        index: String,
+        field: String,
+        query: String,
+        limit: usize,
+    },
+    /// Gets the document with the given primary key
+    Get { index: String, key: FieldData },
+    /// Deletes the document with the given primary key
+    Delete { index: String, key: FieldData },
+    /// Gets a summary of an index, or of every index if none is given
+    Stats { index: Option<String> },
+    /// Authenticates the connection as a user
+    Auth { credentials: Credentials },
+    /// Cancels the request with the given id, if it is still running
+    Cancel { id: u64 },
+    /// Checks whether the daemon is ready to serve requests
+    Health,
+    /// Deletes an index and every document in it
+    DeleteIndex { index: String },
+    /// Lists the names of every index
+    ListIndexes,
+    /// Gets the schema of an index
+    GetMapping { index: String },
+    /// Sets the schema of an existing index, which must not hold any documents
+    UpdateMapping { index: String, schema: Schema },
+    /// Takes a snapshot of every index, storing it in a repository
+    CreateSnapshot {
+        repository: String,
+        snapshot: String,
+    },
+    /// Restores every index of a snapshot, none of which may exist
+    RestoreSnapshot {
+        repository: String,
+        snapshot: String,
+    },
+    /// Lists the snapshots stored in a repository
+    ListSnapshots { repository: String },
+    /// Stops following the primary, so this replica accepts changes to its indexes
+    Promote,
+    /// Gets the status of the cluster, and of every node in it
+    ClusterHealth,
+    /// Makes a request as part of the trace given by a W3C `traceparent`
+    Traced {
+        traceparent: String,
+        request: Box<ClientRequest>,
+    },
+    /// Makes a request that stops once it has run for `timeout_ms`, capped by the daemon. Searches
+    /// that stop early respond with the hits found so far if `partial` is set.
+    Timed {
+        timeout_ms: u64,
+        partial: bool,
+        request: Box<ClientRequest>,
+    },
+    /// Gets the entries of the audit log matching a query
+    AuditLog { query: AuditQuery },
+    /// Gets how much of every directory indexes are stored in is used
+    PathStats,
+    /// Ends the session the connection authenticated with
+    Logout,
+    /// Ends every session of a user
+    RevokeSessions { user: String },
+    /// Changes the password of the user the connection authenticated as, ending their sessions
+    /// and starting a new one
+    ChangePassword { current: Secret, password: Secret },
+    /// Adds a user authenticated by a password
+    CreateUser { user: String, password: Secret },
+    /// Replaces the password of a user, ending their sessions
+    ResetPassword { user: String, password: Secret },
+    /// Removes a user, ending their sessions
+    DeleteUser { user: String },
+    /// Lists every user whose password is stored
+    ListUsers,
+    /// Creates a group without members or roles
+    CreateGroup { group: String },
+    /// Deletes a group, whose members lose its roles
+    DeleteGroup { group: String },
+    /// Adds a user to a group
+    AddGroupMember { group: String, user: String },
+    /// Removes a user from a group
+    RemoveGroupMember { group: String, user: String },
+    /// Replaces the roles given to every member of a group
+    SetGroupRoles { group: String, roles: Vec<String> },
+    /// Lists every group, with their members and roles
+    ListGroups,
+    /// Reads up to `limit` documents of an index in the order of their rows, starting at row
+    /// `from`. Scanning again from the row given in the response reads the next documents.
+    Scan {
+        index: String,
+        from: usize,
+        limit: usize,
+        /// Only reads the documents matched by a search
+        #[serde(default)]
+        filter: Option<ScanFilter>,
+        /// Reads the index as it was at the sequence number given in the response to an earlier
+        /// scan, so later writes and deletions are not seen
+        #[serde(default)]
+        at: Option<u64>,
+    },
+    /// Runs a sql `SELECT` statement, which may read any index the user may read
+    Sql { query: String },
+    /// Finds up to `limit` documents matching a [query string](docatlas_core::query::string),
+    /// whose terms without a field search `default_field`
+    QueryString {
+        index: String,
+        query: String,
+        default_field: Option<String>,
+        default_operator: Operator,
+        limit: usize,
+    },
+    /// Streams the changes made to the documents of an index that pass a filter, starting after
+    /// the change with sequence number `after`, or with the next change if none is given. The
+    /// stream runs until it is cancelled, or the log of the index starts again.
+    Subscribe {
+        index: String,
+        after: Option<u64>,
+        #[serde(default)]
+        filter: ChangeFilter,
+    },
+}
+
+/// How a request is handled, as given by the requests wrapping it
+#[derive(Debug, Clone, Default)]
+pub struct RequestOptions {
+    /// The trace the request is part of
+    pub trace: TraceContext,
+    /// How long the request may run, if the client set a timeout
+    pub timeout: Option<Duration>,
+    /// Whether a search that timed out responds with the hits found so far
+    pub partial: bool,
+}
+
+impl ClientRequest {
+    /// Unwraps traced and timed requests, in any order, returning how the wrapped request is
+    /// handled. Requests that are not traced, or whose `traceparent` is invalid, start a new trace.
+    pub fn into_options(self) -> (RequestOptions, ClientRequest) {
+        match self {
+            ClientRequest::Traced {
+                traceparent,
+                request,
+            } => {
+                let (mut options, request) = request.into_options();
+                if let Ok(trace) = TraceContext::parse(&traceparent) {
+                    options.trace = trace;
+                }
+                (options, request)
+            }
+            ClientRequest::Timed {
+                timeout_ms,
+                partial,
+                request,
+            } => {
+                let (mut options, request) = request.into_options();
+                options.timeout = Some(Duration::from_millis(timeout_ms));
+                options.partial = partial;
+                (options, request)
+            }
+            request => (RequestOptions::default(), request),
+        }
+    }
+
+    /// Gets the index this request is made on, if it is made on a single index
+    pub fn index(&self) -> Option<&str> {
+        match self {
+            ClientRequest::Traced { request, .. } | ClientRequest::Timed { request, .. } => {
+                request.index()
+            }
+            ClientRequest::CreateIndex { index, .. }
+            | ClientRequest::PutSchema { index, .. }
+            | ClientRequest::IndexDocument { index, .. }
+            | ClientRequest::Bulk { index, .. }
+            | ClientRequest::Transaction { index, .. }
+            | ClientRequest::Search { index, .. }
+            | ClientRequest::QueryString { index, .. }
+            | ClientRequest::Scan { index, .. }
+            | ClientRequest::Get { index, .. }
+            | ClientRequest::Delete { index, .. }
+            | ClientRequest::DeleteIndex { index }
+            | ClientRequest::GetMapping { index }
+            | ClientRequest::UpdateMapping { index, .. }
+            | ClientRequest::Subscribe { index, .. } => Some(index),
+            ClientRequest::Stats { index } => index.as_deref(),
+            ClientRequest::Auth { .. }
+            | ClientRequest::Cancel { .. }
+            | ClientRequest::Health
+            | ClientRequest::AuditLog { .. }
+            | ClientRequest::PathStats
+            | ClientRequest::Logout
+            | ClientRequest::RevokeSessions { .. }
+            | ClientRequest::ChangePassword { .. }
+            | ClientRequest::CreateUser { .. }
+            | ClientRequest::ResetPassword { .. }
+            | ClientRequest::DeleteUser { .. }
+            | ClientRequest::ListUsers
+            | ClientRequest::CreateGroup { .. }
+            | ClientRequest::DeleteGroup { .. }
+            | ClientRequest::AddGroupMember { .. }
+            | ClientRequest::RemoveGroupMember { .. }
+            | ClientRequest::SetGroupRoles { .. }
+            | ClientRequest::ListGroups
+            | ClientRequest::ListIndexes
+            | ClientRequest::CreateSnapshot { .. }
+            | ClientRequest::RestoreSnapshot { .. }
+            | ClientRequest::ListSnapshots { .. }
+            | ClientRequest::Promote
+            | ClientRequest::ClusterHealth
+            | ClientRequest::Sql { .. } => None,
+        }
+    }
+
+    /// Gets the permission a user needs to make this request
+    pub fn permission(&self) -> Permission {
+        match self {
+            ClientRequest::Traced { request, .. } | ClientRequest::Timed { request, .. } => {
+                request.permission()
+            }
+            ClientRequest::CreateIndex { .. }
+            | ClientRequest::PutSchema { .. }
+            | ClientRequest::DeleteIndex { .. }
+            | ClientRequest::UpdateMapping { .. }
+            | ClientRequest::CreateSnapshot { .. }
+            | ClientRequest::RestoreSnapshot { .. }
+            | ClientRequest::Promote
+            | ClientRequest::AuditLog { .. }
+            | ClientRequest::RevokeSessions { .. }
+            | ClientRequest::CreateUser { .. }
+            | ClientRequest::ResetPassword { .. }
+            | ClientRequest::DeleteUser { .. }
+            | ClientRequest::ListUsers
+            | ClientRequest::CreateGroup { .. }
+            | ClientRequest::DeleteGroup { .. }
+            | ClientRequest::AddGroupMember { .. }
+            | ClientRequest::RemoveGroupMember { .. }
+            | ClientRequest::SetGroupRoles { .. }
+            | ClientRequest::ListGroups => Permission::Manage,
+            ClientRequest::IndexDocument { .. }
+            | ClientRequest::Bulk { .. }
+            | ClientRequest::Transaction { .. }
+            | ClientRequest::Delete { .. } => Permission::Write,
+            ClientRequest::Search { .. }
+            | ClientRequest::QueryString { .. }
+            | ClientRequest::Scan { .. }
+            | ClientRequest::Get { .. }
+            | ClientRequest::Stats { .. }
+            | ClientRequest::PathStats
+            | ClientRequest::Auth { .. }
+            | ClientRequest::Logout
+            | ClientRequest::ChangePassword { .. }
+            | ClientRequest::Cancel { .. }
+            | ClientRequest::Health
+            | ClientRequest::ListIndexes
+            | ClientRequest::GetMapping { .. }
+            | ClientRequest::ListSnapshots { .. }
+            | ClientRequest::ClusterHealth
+            | ClientRequest::Sql { .. }
+            | ClientRequest::Subscribe { .. } => Permission::Read,
+        }
+    }
+}
+
+/// Limits a scan to the documents whose field contains every term of a query
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScanFilter {
+    pub field: String,
+    pub query: String,
+}
+
+/// How a client proves who it is
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Credentials {
+    /// A username and password
+    Basic { username: String, password: Secret },
+    /// A token given to the user ahead of time, or the token of a session
+    Token { token: Secret },
+}
+
+impl Credentials {
+    /// Creates the request to authenticate with these credentials
+    pub fn request(&self) -> AuthenticationRequest<'_> {
+        match self {
+            Credentials::Basic { username, password } => {
+                AuthenticationRequest::new().with_basic(username, password.expose())
+            }
+            Credentials::Token { token } => AuthenticationRequest::new().with_token(token.expose()),
+        }
+    }
+}
+
+/// A password or token sent by a client, which is never written to logs
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    /// Wraps a password or token
+    pub fn new(secret: impl Into<String>) -> Self {
+        Self(secret.into())
+    }
+
+    /// Gets the secret
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Debug for Secret {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Secret(****)")
+    }
+}
 
 /// A response *sent* to a client as a response to a request
-#[derive(Debug, Serialize)]
-pub struct ClientResponse {}
+#[derive(Debug, Serialize, Deserialize)]
+pub enum ClientResponse {
+    /// The request succeeded, without anything to return
+    Acknowledged,
+    /// The document was upserted into the given row
+    Upserted { row: usize, inserted: bool },
+    /// The document was dropped by the ingest pipeline of the index
+    Dropped,
+    /// The row of every document added in bulk, or why it could not be added
+    Bulk {
+        items: Vec<Result<Option<usize>, String>>,
+    },
+    /// What every write of a committed transaction did
+    Committed { written: Vec<Written> },
+    /// The documents found by a search
+    Hits { hits: Vec<Hit> },
+    /// The document with the requested primary key, if there is one
+    Document { document: Option<Document> },
+    /// The row the deleted document was stored in, if there was one
+    Deleted { row: Option<usize> },
+    /// A summary of the requested indexes
+    Stats { indexes: Vec<IndexInfo> },
+    /// Whether the request to cancel was still running
+    CancelRequested { running: bool },
+    /// The request was cancelled before it finished
+    Cancelled,
+    /// The request could not be handled. Daemons now respond with [`ClientResponse::Failed`]
+    /// instead, which tells clients why.
+    Error { message: String },
+    /// The client made too many requests, and may retry after the given number of milliseconds
+    RateLimited { retry_after_ms: u64 },
+    /// The connection is authenticated as the given user
+    Authenticated { user: String },
+    /// The connection must authenticate before making the request, or failed to
+    Unauthenticated { message: String },
+    /// Whether the daemon is ready to serve requests
+    Health { readiness: Readiness },
+    /// The names of every index, in alphabetical order
+    Indexes { names: Vec<String> },
+    /// The schema of an index
+    Mapping { schema: Schema },
+    /// The snapshot that was just taken
+    Snapshot { snapshot: SnapshotInfo },
+    /// The names of the indexes restored from a snapshot
+    Restored { indexes: Vec<String> },
+    /// The names of every snapshot in a repository, in alphabetical order
+    Snapshots { names: Vec<String> },
+    /// The index is on another node of the cluster, which the request should be sent to instead
+    Moved {
+        index: String,
+        node: String,
+        address: String,
+    },
+    /// The status of the cluster, and of every node in it
+    ClusterHealth { cluster: ClusterHealth },
+    /// The request ran out of time before it finished
+    TimedOut { after_ms: u64 },
+    /// The request ran out of time, and the response holds what was found before it did
+    Partial { response: Box<ClientResponse> },
+    /// Entries of the audit log, the oldest first
+    AuditEntries { entries: Vec<AuditEntry> },
+    /// How much of every directory indexes are stored in is used
+    PathStats { paths: Vec<PathUsage> },
+    /// The connection is authenticated as the given user, and started a session. Later
+    /// connections can authenticate with the session's token until it expires in `expires_in_ms`,
+    /// or goes unused for too long.
+    SessionStarted {
+        user: String,
+        token: Secret,
+        expires_in_ms: u64,
+    },
+    /// The number of sessions that were ended
+    Revoked { sessions: usize },
+    /// The names of every user whose password is stored
+    Users { names: Vec<String> },
+    /// Every group, ordered by name
+    Groups { groups: Vec<Group> },
+    /// The request could not be handled, with the code telling clients why
+    Failed { error: DocatlasError },
+    /// The documents read by a scan, and the row to continue it from unless every row was read
+    Scanned {
+        hits: Vec<Hit>,
+        next: Option<usize>,
+        /// The sequence number the index was read at, which later scans can read at as well
+        #[serde(default)]
+        at: Option<u64>,
+    },
+    /// The rows selected by a sql statement
+    Table { table: Table },
+    /// The subscription started in the log with the given id, whose latest change is `last`.
+    /// Every change after it is streamed as [`ClientResponse::Changes`].
+    Subscribed { log: u64, last: u64 },
+    /// The events of changes passing the filter of a subscription, in the order of their changes
+    Changes { events: Vec<ChangeEvent> },
+}
+
+impl From<Upserted> for ClientResponse {
+    fn from(value: Upserted) -> Self {
+        match value {
+            Upserted::Inserted(row) => ClientResponse::Upserted {
+                row,
+                inserted: true,
+            },
+            Upserted::Updated(row) => ClientResponse::Upserted {
+                row,
+                inserted: false,
+            },
+            Upserted::Dropped => ClientResponse::Dropped,
+        }
+    }
+}
+
+/// Gets the upsert mode requested by a client
+pub fn upsert_mode(partial: bool) -> UpsertMode {
+    if partial {
+        UpsertMode::Merge
+    } else {
+        UpsertMode::Replace
+    }
+}
@@ -0,0 +1,492 @@
+//! Clusters of daemons, which discover each other by gossip.
+//!
+//! A daemon with a `cluster_port` joins the cluster named by `cluster_name`. Every second it tells
+//! its seeds, and every node it has heard of, about itself and every other node it knows, so nodes
+//! only need to share a single seed to find each other. Every node increases its heartbeat each
+//! round, and a node whose heartbeat has not increased for a while is considered gone.
+//!
+//! Every index lives on a single node, which owns it. A request for an index that is not on the
+//! node it was sent to is answered with the node owning the index, which the client should send the
+//! request to instead.
+//!
+//! The status of a cluster is
+//! - `green` when every node is reachable,
+//! - `yellow` when some node is unreachable, or an index is only available on a read-only replica,
+//! - `red` when some index is not available on any reachable node.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use docatlas_core::schema::Schema;
+use docatlas_core::transport::handshake::{self, ClientHello, HandshakeError, ServerCapabilities};
+use docatlas_core::transport::{wire, TcpTransport, Transport};
+use futures::{SinkExt, StreamExt};
+use log::{debug, info};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::{net, time};
+use tokio_util::sync::CancellationToken;
+
+use crate::client::{ClientRequest, ClientResponse};
+use crate::config::DaemonConfig;
+use crate::handlers::Indexes;
+use crate::replication::Replica;
+
+/// How often nodes gossip
+const GOSSIP_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a node may go without gossiping before it is considered gone
+const FAILURE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a single exchange of gossip may take
+const GOSSIP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A node, as told to the other nodes of its cluster
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeState {
+    pub name: String,
+    /// The address clients connect to
+    pub address: String,
+    /// The address other nodes gossip with, if the node accepts gossip
+    pub cluster_address: Option<String>,
+    /// Increased by the node every time it gossips
+    pub heartbeat: u64,
+    /// Whether the node is a read-only replica
+    pub replica: bool,
+    pub indexes: Vec<IndexMetadata>,
+}
+
+/// The metadata of an index, shared with every node of the cluster
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexMetadata {
+    pub name: String,
+    pub schema: Schema,
+    pub rows: usize,
+}
+
+/// Everything a node knows about its cluster, sent to the nodes it gossips with
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Gossip {
+    pub cluster: String,
+    pub nodes: Vec<NodeState>,
+}
+
+/// The status of a cluster
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClusterStatus {
+    Green,
+    Yellow,
+    Red,
+}
+
+impl ClusterStatus {
+    /// Gets the status of a cluster made of the given nodes
+    pub fn of(nodes: &[NodeHealth]) -> Self {
+        let mut status = match nodes.iter().all(|node| node.alive) {
+            true => ClusterStatus::Green,
+            false => ClusterStatus::Yellow,
+        };
+        let indexes = nodes
+            .iter()
+            .flat_map(|node| &node.indexes)
+            .collect::<BTreeSet<_>>();
+        for index in indexes {
+            let holders = nodes
+                .iter()
+                .filter(|node| node.alive && node.indexes.contains(index))
+                .collect::<Vec<_>>();
+            if holders.is_empty() {
+                return ClusterStatus::Red;
+            }
+            if holders.iter().all(|node| node.replica) {
+                status = ClusterStatus::Yellow;
+            }
+        }
+        status
+    }
+}
+
+/// The health of a cluster, and of every node in it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClusterHealth {
+    pub cluster: String,
+    pub status: ClusterStatus,
+    pub nodes: Vec<NodeHealth>,
+}
+
+/// The health of a single node
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NodeHealth {
+    pub name: String,
+    pub address: String,
+    /// Whether the node was heard from recently
+    pub alive: bool,
+    pub replica: bool,
+    pub indexes: Vec<String>,
+}
+
+/// A node known through gossip
+#[derive(Debug)]
+struct Member {
+    state: NodeState,
+    /// When the heartbeat of the node last increased
+    last_seen: Instant,
+}
+
+impl Member {
+    fn alive(&self, now: Instant) -> bool {
+        now.duration_since(self.last_seen) < FAILURE_TIMEOUT
+    }
+}
+
+/// The cluster this daemon is a node of. A daemon without a `cluster_port` is the only node of
+/// its cluster.
+#[derive(Debug)]
+pub struct Cluster {
+    name: String,
+    node: String,
+    address: String,
+    cluster_address: Option<String>,
+    seeds: Vec<String>,
+    indexes: Arc<Indexes>,
+    replica: Option<Arc<Replica>>,
+    heartbeat: AtomicU64,
+    /// Every other node, by name
+    members: Mutex<BTreeMap<String, Member>>,
+}
+
+impl Cluster {
+    /// Creates the node of this daemon, serving the given indexes
+    pub fn new(
+        config: &DaemonConfig,
+        indexes: Arc<Indexes>,
+        replica: Option<Arc<Replica>>,
+    ) -> Self {
+        Self {
+            name: config.cluster_name().to_string(),
+            node: config.node_name(),
+            address: format!("{}:{}", config.host(), config.port()),
+            cluster_address: config
+                .cluster_port()
+                .map(|port| format!("{}:{port}", config.host())),
+            seeds: config.seeds().to_vec(),
+            indexes,
+            replica,
+            heartbeat: AtomicU64::new(0),
+            members: Mutex::default(),
+        }
+    }
+
+    /// Gets the name of the cluster
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Gets the name of this node
+    pub fn node(&self) -> &str {
+        &self.node
+    }
+
+    /// Gets the replication state of this node, if it is a replica
+    pub fn replica(&self) -> Option<&Replica> {
+        self.replica.as_deref()
+    }
+
+    /// Gets the state of this node
+    pub fn local_state(&self) -> NodeState {
+        NodeState {
+            name: self.node.clone(),
+            address: self.address.clone(),
+            cluster_address: self.cluster_address.clone(),
+            heartbeat: self.heartbeat.load(Ordering::SeqCst),
+            replica: self.replica.as_ref().is_some_and(|r| !r.is_promoted()),
+            indexes: self
+                .indexes
+                .infos()
+                .into_iter()
+                .map(|info| IndexMetadata {
+                    name: info.name,
+                    schema: info.schema,
+                    rows: info.rows,
+                })
+                .collect(),
+        }
+    }
+
+    /// Gets everything this node knows about the cluster
+    pub fn gossip(&self) -> Gossip {
+        let mut nodes = vec![self.local_state()];
+        let members = self.members.lock().expect("poisoned");
+        nodes.extend(members.values().map(|member| member.state.clone()));
+        Gossip {
+            cluster: self.name.clone(),
+            nodes,
+        }
+    }
+
+    /// Learns about the nodes another node knows of, keeping the most recent state of every node
+    pub fn merge(&self, gossip: Gossip) -> Result<(), ClusterError> {
+        if gossip.cluster != self.name {
+            return Err(ClusterError::WrongCluster(gossip.cluster));
+        }
+        let now = Instant::now();
+        let mut members = self.members.lock().expect("poisoned");
+        for state in gossip.nodes {
+            if state.name == self.node {
+                continue;
+            }
+            match members.get_mut(&state.name) {
+                Some(member) if member.state.heartbeat >= state.heartbeat => {}
+                Some(member) => {
+                    member.state = state;
+                    member.last_seen = now;
+                }
+                None => {
+                    info!("node {:?} joined cluster {:?}", state.name, self.name);
+                    members.insert(
+                        state.name.clone(),
+                        Member {
+                            state,
+                            last_seen: now,
+                        },
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Gets the health of the cluster
+    pub fn health(&self) -> ClusterHealth {
+        let now = Instant::now();
+        let local = self.local_state();
+        let mut nodes = vec![node_health(&local, true)];
+        let members = self.members.lock().expect("poisoned");
+        nodes.extend(
+            members
+                .values()
+                .map(|member| node_health(&member.state, member.alive(now))),
+        );
+        ClusterHealth {
+            cluster: self.name.clone(),
+            status: ClusterStatus::of(&nodes),
+            nodes,
+        }
+    }
+
+    /// Gets the name and address of the reachable node owning an index, preferring a primary over
+    /// a replica
+    pub fn owner(&self, index: &str) -> Option<(String, String)> {
+        let now = Instant::now();
+        let members = self.members.lock().expect("poisoned");
+        members
+            .values()
+            .filter(|member| member.alive(now))
+            .filter(|member| member.state.indexes.iter().any(|i| i.name == index))
+            .min_by_key(|member| member.state.replica)
+            .map(|member| (member.state.name.clone(), member.state.address.clone()))
+    }
+
+    /// Answers a request for an index that is not on this node with the node owning it, if another
+    /// node does
+    pub fn route(&self, request: &ClientRequest) -> Option<ClientResponse> {
+        let index = request.index()?;
+        if self.indexes.contains(index) {
+            return None;
+        }
+        let (node, address) = self.owner(index)?;
+        Some(ClientResponse::Moved {
+            index: index.to_string(),
+            node,
+            address,
+        })
+    }
+
+    /// Gossips with every seed and known node once a round, until the daemon shuts down
+    pub async fn run(&self, stop: &CancellationToken) {
+        let mut rounds = time::interval(GOSSIP_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = rounds.tick() => {}
+                _ = stop.cancelled() => return,
+            }
+            self.heartbeat.fetch_add(1, Ordering::SeqCst);
+            for peer in self.peers() {
+                match time::timeout(GOSSIP_TIMEOUT, self.exchange(&peer)).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => debug!("could not gossip with {peer}: {e}"),
+                    Err(_) => debug!("gossip with {peer} timed out"),
+                }
+            }
+        }
+    }
+
+    /// Gets the gossip address of every seed and known node, except this one
+    fn peers(&self) -> BTreeSet<String> {
+        let members = self.members.lock().expect("poisoned");
+        let known = members
+            .values()
+            .filter_map(|member| member.state.cluster_address.clone());
+        self.seeds
+            .iter()
+            .cloned()
+            .chain(known)
+            .filter(|peer| Some(peer) != self.cluster_address.as_ref())
+            .collect()
+    }
+
+    /// Sends this node's gossip to another node, and learns from its answer
+    async fn exchange(&self, peer: &str) -> Result<(), ClusterError> {
+        let address = net::lookup_host(peer)
+            .await?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address for the node"))?;
+        let mut transport = TcpTransport::connect(address).await?;
+        let server = handshake::connect(&mut transport, &ClientHello::default()).await?;
+        let mut transport = wire::wire_transport::<Gossip, Gossip, _, _>(transport, server.codec());
+        transport.send(self.gossip()).await?;
+        let answer = transport
+            .next()
+            .await
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))??;
+        self.merge(answer)
+    }
+
+    /// Answers the gossip of another node with this node's gossip
+    pub async fn serve_gossip<T>(
+        &self,
+        mut transport: T,
+        capabilities: &ServerCapabilities,
+    ) -> Result<(), ClusterError>
+    where
+        T: Transport<Vec<u8>, Vec<u8>, Error = io::Error> + Unpin,
+    {
+        let (_, server) = handshake::accept(&mut transport, capabilities).await?;
+        let mut transport = wire::wire_transport::<Gossip, Gossip, _, _>(transport, server.codec());
+        let gossip = transport
+            .next()
+            .await
+            .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))??;
+        self.merge(gossip)?;
+        transport.send(self.gossip()).await?;
+        Ok(())
+    }
+}
+
+fn node_health(state: &NodeState, alive: bool) -> NodeHealth {
+    NodeHealth {
+        name: state.name.clone(),
+        address: state.address.clone(),
+        alive,
+        replica: state.replica,
+        indexes: state.indexes.iter().map(|i| i.name.clone()).collect(),
+    }
+}
+
+/// An error occurred gossiping with another node
+#[derive(Debug, Error)]
+pub enum ClusterError {
+    #[error("gossip from a node of cluster {0:?}")]
+    WrongCluster(String),
+    #[error(transparent)]
+    HandshakeError(#[from] HandshakeError),
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use docatlas_core::fields::FieldKind;
+    use docatlas_core::schema::SchemaField;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::config::CliDaemonConfig;
+
+    fn node(name: &str, alive: bool, replica: bool, indexes: &[&str]) -> NodeHealth {
+        NodeHealth {
+            name: name.to_string(),
+            address: String::new(),
+            alive,
+            replica,
+            indexes: indexes.iter().map(|i| i.to_string()).collect(),
+        }
+    }
+
+    fn config(args: &[&str]) -> DaemonConfig {
+        let args = ["docatlasd"].iter().chain(args);
+        CliDaemonConfig::parse_from(args).into_config()
+    }
+
+    #[test]
+    fn cluster_status() {
+        let primary = node("a", true, false, &["books"]);
+        let replica = node("b", true, true, &["books"]);
+        assert_eq!(
+            ClusterStatus::of(&[primary.clone(), replica.clone()]),
+            ClusterStatus::Green
+        );
+        let lost_replica = node("b", false, true, &["books"]);
+        assert_eq!(
+            ClusterStatus::of(&[primary, lost_replica]),
+            ClusterStatus::Yellow
+        );
+        let lost_primary = node("a", false, false, &["books"]);
+        assert_eq!(
+            ClusterStatus::of(&[lost_primary.clone(), replica]),
+            ClusterStatus::Yellow
+        );
+        assert_eq!(ClusterStatus::of(&[lost_primary]), ClusterStatus::Red);
+    }
+
+    #[tokio::test]
+    async fn nodes_discover_each_other() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let seed_address = listener.local_addr().unwrap().to_string();
+        let seed_indexes = Arc::new(Indexes::new());
+        let schema = Schema::from_iter([SchemaField {
+            name: "id".to_string(),
+            kind: FieldKind::Keyword(8),
+        }]);
+        seed_indexes.create("books", schema).unwrap();
+        let seed = Arc::new(Cluster::new(
+            &config(&["--node-name", "seed"]),
+            seed_indexes,
+            None,
+        ));
+        let server = {
+            let seed = seed.clone();
+            tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let capabilities = ServerCapabilities::default();
+                seed.serve_gossip(TcpTransport::new(stream), &capabilities)
+                    .await
+            })
+        };
+        let joining = Cluster::new(
+            &config(&["--node-name", "joining", "--seeds", &seed_address]),
+            Arc::new(Indexes::new()),
+            None,
+        );
+        joining.exchange(&seed_address).await.unwrap();
+        server.await.unwrap().unwrap();
+
+        let names = |health: ClusterHealth| {
+            assert_eq!(health.status, ClusterStatus::Green);
+            health.nodes.into_iter().map(|n| n.name).collect::<Vec<_>>()
+        };
+        assert_eq!(names(seed.health()), ["seed", "joining"]);
+        assert_eq!(names(joining.health()), ["joining", "seed"]);
+        let request = ClientRequest::GetMapping {
+            index: "books".to_string(),
+        };
+        assert!(matches!(
+            joining.route(&request),
+            Some(ClientResponse::Moved { node, .. }) if node == "seed"
+        ));
+        assert!(seed.route(&request).is_none());
+    }
+}
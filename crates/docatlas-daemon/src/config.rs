@@ -1,29 +1,239 @@
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io;
+use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::thread;
+
+use std::time::Duration;
 
 use clap::{Args, Parser};
+use docatlas_core::auth::authentication::{
+    AuthenticationCache, LoginThrottle, DEFAULT_BACKOFF, DEFAULT_CACHE_TTL, DEFAULT_GROUPS_CLAIM,
+    DEFAULT_LOCKOUT, DEFAULT_MAX_FAILURES, DEFAULT_ROLES_CLAIM, DEFAULT_SESSION_IDLE_TIMEOUT,
+    DEFAULT_SESSION_TTL, DEFAULT_USER_CLAIM,
+};
+use docatlas_core::auth::authorization::{Authorizer, RoleBindings};
+use docatlas_core::auth::passwords::{PasswordHashing, PasswordPolicy};
+use docatlas_core::auth::users::{User, UserFactory};
+use docatlas_core::transport::keepalive::{
+    KeepaliveConfig, DEFAULT_IDLE_TIMEOUT, DEFAULT_KEEPALIVE_INTERVAL,
+};
+use docatlas_core::transport::queue::{
+    QueueConfig, SlowConsumerPolicy, DEFAULT_SEND_QUEUE_CAPACITY,
+};
 use merge::Merge;
-use serde::Deserialize;
+use secrecy::ExposeSecret;
+use serde::{Deserialize, Deserializer};
+use thiserror::Error;
 use tracing::log::LevelFilter;
 
+use crate::changes::DEFAULT_CHANGES_BACKLOG;
+use crate::limits::RateLimit;
+use crate::log_rotation::{Rotation, DEFAULT_LOG_RETENTION};
+use crate::merge::{DEFAULT_MAX_MERGES, DEFAULT_MERGE_THROTTLE};
+use crate::pid_file::PID_FILE;
+use crate::replication::DEFAULT_REPLICATION_BACKLOG;
+use crate::secrets::SecretSource;
+use crate::snapshot::s3::{S3Config, S3Error};
+use crate::tls::{TlsConfig, TlsError, TlsVersion};
+use crate::webhook::{WebhookAuthorizationService, WebhookError, DEFAULT_WEBHOOK_TIMEOUT};
+
 mod merge_strategies;
 
 const DEFAULT_PATH: &str = "/var/lib/docatlas";
 const DEFAULT_HOST: &str = "localhost";
-const DEFAULT_PORT: u16 = 3676;
+/// The port clients connect to unless configured otherwise
+pub const DEFAULT_PORT: u16 = 3676;
+const DEFAULT_WEBSOCKET_PORT: u16 = 3677;
+const DEFAULT_HTTP_PORT: u16 = 3678;
+const DEFAULT_GRPC_PORT: u16 = 3679;
+const DEFAULT_CLUSTER_NAME: &str = "docatlas";
+const DEFAULT_LOCAL_SOCKET: &str = "docatlas.sock";
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+const DEFAULT_MAX_CONNECTIONS_PER_IP: usize = 64;
+const ADMIN_STORE: &str = "admin";
+const USERS_STORE: &str = "users.json";
+const GROUPS_STORE: &str = "groups.json";
+const INDEXES_DIR: &str = "indexes";
+const SNAPSHOTS_DIR: &str = "snapshots";
+const LOG_FILE: &str = "docatlas.log";
 const DEFAULT_LOG_LEVEL_FILTER: LevelFilter = LevelFilter::Info;
 
+/// The name of the config file within the [daemon path](DaemonConfig::path)
+pub const CONFIG_FILE: &str = "config.yaml";
+
 /// The docatlas-daemon daemon configuration
 #[derive(Debug, Default, Clone, Deserialize, Args, Merge)]
 pub struct DaemonConfig {
     #[clap(long)]
     path: Option<PathBuf>,
     #[clap(long)]
+    data_path: Option<Vec<PathBuf>>,
+    #[clap(long)]
     host: Option<String>,
     #[clap(long)]
     port: Option<u16>,
+    #[clap(long)]
+    websocket_port: Option<u16>,
+    #[clap(long)]
+    http_port: Option<u16>,
+    #[clap(long)]
+    grpc_port: Option<u16>,
+    #[clap(long)]
+    local_socket: Option<PathBuf>,
+    #[clap(long)]
+    #[serde(default, deserialize_with = "human_duration")]
+    keepalive_interval: Option<humantime::Duration>,
+    #[clap(long)]
+    #[serde(default, deserialize_with = "human_duration")]
+    idle_timeout: Option<humantime::Duration>,
+    #[clap(long)]
+    send_queue_capacity: Option<usize>,
+    #[clap(long)]
+    slow_client_policy: Option<SlowConsumerPolicy>,
+    #[clap(long)]
+    #[serde(default, deserialize_with = "human_duration")]
+    shutdown_timeout: Option<humantime::Duration>,
+    #[clap(long)]
+    #[serde(default, deserialize_with = "human_duration")]
+    max_request_timeout: Option<humantime::Duration>,
+    #[clap(long)]
+    max_connections: Option<usize>,
+    #[clap(long)]
+    max_connections_per_ip: Option<usize>,
+    #[clap(long)]
+    search_threads: Option<usize>,
+    #[clap(long)]
+    rate_limit: Option<f64>,
+    #[clap(long)]
+    rate_limit_burst: Option<u32>,
+    #[clap(long)]
+    require_auth: Option<bool>,
+    #[clap(long, value_delimiter = ',')]
+    anonymous_roles: Option<Vec<String>>,
+    #[clap(long)]
+    auth_tokens: Option<PathBuf>,
+    #[clap(long)]
+    password_min_length: Option<usize>,
+    #[clap(long)]
+    password_require_mixed_case: Option<bool>,
+    #[clap(long)]
+    password_require_digit: Option<bool>,
+    #[clap(long)]
+    password_require_symbol: Option<bool>,
+    #[clap(long)]
+    password_hash_memory_kib: Option<u32>,
+    #[clap(long)]
+    password_hash_iterations: Option<u32>,
+    #[clap(long)]
+    password_hash_parallelism: Option<u32>,
+    #[clap(long)]
+    jwt_secret: Option<SecretSource>,
+    #[clap(long)]
+    jwt_secret_file: Option<PathBuf>,
+    #[clap(long)]
+    jwt_jwks: Option<PathBuf>,
+    #[clap(long)]
+    jwt_issuer: Option<String>,
+    #[clap(long)]
+    jwt_audience: Option<String>,
+    #[clap(long)]
+    jwt_user_claim: Option<String>,
+    #[clap(long)]
+    jwt_groups_claim: Option<String>,
+    #[clap(long)]
+    jwt_roles_claim: Option<String>,
+    #[clap(long)]
+    #[serde(default, deserialize_with = "human_duration")]
+    session_ttl: Option<humantime::Duration>,
+    #[clap(long)]
+    #[serde(default, deserialize_with = "human_duration")]
+    session_idle_timeout: Option<humantime::Duration>,
+    #[clap(long)]
+    login_max_failures: Option<u32>,
+    #[clap(long)]
+    #[serde(default, deserialize_with = "human_duration")]
+    login_backoff: Option<humantime::Duration>,
+    #[clap(long)]
+    #[serde(default, deserialize_with = "human_duration")]
+    login_lockout: Option<humantime::Duration>,
+    #[clap(long)]
+    #[serde(default, deserialize_with = "human_duration")]
+    auth_cache_ttl: Option<humantime::Duration>,
+    #[clap(long)]
+    role_bindings: Option<PathBuf>,
+    #[clap(long)]
+    authorization_webhook: Option<String>,
+    #[clap(long)]
+    #[serde(default, deserialize_with = "human_duration")]
+    authorization_webhook_timeout: Option<humantime::Duration>,
+    #[clap(long)]
+    audit_log: Option<PathBuf>,
+    #[clap(long)]
+    snapshot_path: Option<PathBuf>,
+    #[clap(long)]
+    snapshot_s3_endpoint: Option<String>,
+    #[clap(long)]
+    snapshot_s3_bucket: Option<String>,
+    #[clap(long)]
+    snapshot_s3_region: Option<String>,
+    #[clap(long)]
+    snapshot_s3_access_key: Option<String>,
+    #[clap(long)]
+    snapshot_s3_secret_key: Option<SecretSource>,
+    #[clap(long)]
+    tls_cert: Option<PathBuf>,
+    #[clap(long)]
+    tls_key: Option<SecretSource>,
+    #[clap(long)]
+    tls_client_ca: Option<PathBuf>,
+    #[clap(long)]
+    tls_min_version: Option<TlsVersion>,
+    #[clap(long)]
+    replication_port: Option<u16>,
+    #[clap(long)]
+    replica_of: Option<String>,
+    #[clap(long)]
+    replica_user: Option<String>,
+    #[clap(long)]
+    replica_password: Option<SecretSource>,
+    #[clap(long)]
+    replication_backlog: Option<usize>,
+    #[clap(long)]
+    changes_backlog: Option<usize>,
+    #[clap(long)]
+    #[serde(default, deserialize_with = "human_duration")]
+    wal_commit_window: Option<humantime::Duration>,
+    #[clap(long)]
+    max_merges: Option<usize>,
+    #[clap(long)]
+    merge_throttle: Option<u64>,
+    #[clap(long)]
+    cluster_name: Option<String>,
+    #[clap(long)]
+    node_name: Option<String>,
+    #[clap(long)]
+    cluster_port: Option<u16>,
+    #[clap(long, value_delimiter = ',')]
+    seeds: Option<Vec<String>>,
 
     #[clap(long = "log")]
     log_level: Option<LevelFilter>,
+    #[clap(long)]
+    log_format: Option<LogFormat>,
+    #[clap(long)]
+    log_max_bytes: Option<u64>,
+    #[clap(long)]
+    #[serde(default, deserialize_with = "human_duration")]
+    log_rotate_every: Option<humantime::Duration>,
+    #[clap(long)]
+    log_retention: Option<usize>,
+    #[clap(long)]
+    log_spans: Option<bool>,
 }
 
 impl DaemonConfig {
@@ -42,19 +252,807 @@ impl DaemonConfig {
         self.port.unwrap_or(DEFAULT_PORT)
     }
 
+    /// Gets the port to accept websocket clients on. By default this value is `3677`.
+    pub fn websocket_port(&self) -> u16 {
+        self.websocket_port.unwrap_or(DEFAULT_WEBSOCKET_PORT)
+    }
+
+    /// Gets the port to serve the http api on. By default this value is `3678`.
+    pub fn http_port(&self) -> u16 {
+        self.http_port.unwrap_or(DEFAULT_HTTP_PORT)
+    }
+
+    /// Gets the port to serve the grpc services on. By default this value is `3679`.
+    pub fn grpc_port(&self) -> u16 {
+        self.grpc_port.unwrap_or(DEFAULT_GRPC_PORT)
+    }
+
+    /// Gets the path of the local socket local clients can connect to. By default this is
+    /// `docatlas.sock` within the [daemon path](Self::path).
+    pub fn local_socket(&self) -> PathBuf {
+        self.local_socket
+            .clone()
+            .unwrap_or_else(|| self.path().join(DEFAULT_LOCAL_SOCKET))
+    }
+
+    /// Gets the time between keepalive pings sent to clients. By default this value is `15s`.
+    pub fn keepalive_interval(&self) -> Duration {
+        self.keepalive_interval
+            .map(Into::into)
+            .unwrap_or(DEFAULT_KEEPALIVE_INTERVAL)
+    }
+
+    /// Gets how long a client may stay silent before it is disconnected. By default this value is
+    /// `60s`.
+    pub fn idle_timeout(&self) -> Duration {
+        self.idle_timeout
+            .map(Into::into)
+            .unwrap_or(DEFAULT_IDLE_TIMEOUT)
+    }
+
+    /// Gets the keepalive settings of client connections
+    pub fn keepalive(&self) -> KeepaliveConfig {
+        KeepaliveConfig::default()
+            .with_interval(self.keepalive_interval())
+            .with_idle_timeout(self.idle_timeout())
+    }
+
+    /// Gets the number of responses that can be waiting to be sent to a single client. By default
+    /// this value is `256`.
+    pub fn send_queue_capacity(&self) -> usize {
+        self.send_queue_capacity
+            .unwrap_or(DEFAULT_SEND_QUEUE_CAPACITY)
+            .max(1)
+    }
+
+    /// Gets what happens to clients that do not read their responses fast enough. By default
+    /// responses wait for room in the send queue, which stops the client's requests from being read.
+    pub fn slow_client_policy(&self) -> SlowConsumerPolicy {
+        self.slow_client_policy.unwrap_or_default()
+    }
+
+    /// Gets the send queue settings of client connections
+    pub fn send_queue(&self) -> QueueConfig {
+        QueueConfig::default()
+            .with_capacity(self.send_queue_capacity())
+            .with_policy(self.slow_client_policy())
+    }
+
+    /// Gets how long requests in flight may take to finish once the daemon is shutting down. By
+    /// default this value is `30s`.
+    pub fn shutdown_timeout(&self) -> Duration {
+        self.shutdown_timeout
+            .map(Into::into)
+            .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT)
+    }
+
+    /// Gets the longest a request may run before it times out. Requests setting a longer timeout
+    /// are cut short, and requests without one use this value. By default this value is `60s`.
+    pub fn max_request_timeout(&self) -> Duration {
+        self.max_request_timeout
+            .map(Into::into)
+            .unwrap_or(DEFAULT_MAX_REQUEST_TIMEOUT)
+    }
+
+    /// Gets the number of clients that can be connected at once. By default this value is `1024`.
+    pub fn max_connections(&self) -> usize {
+        self.max_connections.unwrap_or(DEFAULT_MAX_CONNECTIONS)
+    }
+
+    /// Gets the number of clients that can be connected at once from the same ip address. By
+    /// default this value is `64`.
+    pub fn max_connections_per_ip(&self) -> usize {
+        self.max_connections_per_ip
+            .unwrap_or(DEFAULT_MAX_CONNECTIONS_PER_IP)
+    }
+
+    /// Gets the number of threads searches and other cpu heavy requests run on, apart from the
+    /// threads serving connections. By default there is a thread per cpu.
+    pub fn search_threads(&self) -> usize {
+        self.search_threads
+            .filter(|threads| *threads > 0)
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, NonZeroUsize::get))
+    }
+
+    /// Gets how many requests every client may make per second, and in a single burst. By default
+    /// requests are not rate limited, and bursts allow one second worth of requests.
+    pub fn rate_limit(&self) -> Option<RateLimit> {
+        let limit = RateLimit::new(self.rate_limit.filter(|rate| *rate > 0.0)?);
+        Some(match self.rate_limit_burst {
+            Some(burst) => limit.with_burst(burst.max(1)),
+            None => limit,
+        })
+    }
+
+    /// Gets whether clients must authenticate before making any other request. By default this
+    /// value is `false`.
+    pub fn require_auth(&self) -> bool {
+        self.require_auth.unwrap_or(false)
+    }
+
+    /// Gets the roles of clients that did not authenticate, when authentication is required. By
+    /// default clients get no roles, and must authenticate before making any request.
+    pub fn anonymous_roles(&self) -> &[String] {
+        self.anonymous_roles.as_deref().unwrap_or_default()
+    }
+
+    /// Gets the user clients act as until they authenticate, if authentication is required and
+    /// clients are given any [anonymous roles](Self::anonymous_roles)
+    pub fn anonymous_user(&self) -> Option<User> {
+        (self.require_auth() && !self.anonymous_roles().is_empty())
+            .then(|| UserFactory.anonymous(self.anonymous_roles()))
+    }
+
+    /// Gets the directories data is stored in. By default this is only the
+    /// [daemon path](Self::path).
+    pub fn data_paths(&self) -> Vec<PathBuf> {
+        match self.data_path.as_deref() {
+            Some(paths) if !paths.is_empty() => paths.to_vec(),
+            _ => vec![self.path().to_path_buf()],
+        }
+    }
+
+    /// Gets the directories indexes are stored in, which are `indexes` within every
+    /// [data path](Self::data_paths).
+    pub fn indexes_paths(&self) -> Vec<PathBuf> {
+        self.data_paths()
+            .iter()
+            .map(|path| path.join(INDEXES_DIR))
+            .collect()
+    }
+
+    /// Gets the directory snapshot repositories are stored in, every repository being a directory
+    /// within it. By default this is `snapshots` within the [daemon path](Self::path).
+    pub fn snapshot_path(&self) -> PathBuf {
+        self.snapshot_path
+            .clone()
+            .unwrap_or_else(|| self.path().join(SNAPSHOTS_DIR))
+    }
+
+    /// Gets the object store snapshot repositories are stored in instead of the
+    /// [snapshot path](Self::snapshot_path), if both `snapshot_s3_endpoint` and
+    /// `snapshot_s3_bucket` are set. Requests are signed for `snapshot_s3_region`, which is
+    /// `us-east-1` by default, with `snapshot_s3_access_key` and `snapshot_s3_secret_key` if they
+    /// are set. See [the s3 module](crate::snapshot::s3).
+    pub fn snapshot_s3(&self) -> Result<Option<S3Config>, S3Error> {
+        let config = match (&self.snapshot_s3_endpoint, &self.snapshot_s3_bucket) {
+            (Some(endpoint), Some(bucket)) => S3Config::new(endpoint, bucket)?,
+            (Some(_), None) => return Err(S3Error::MissingSetting("snapshot_s3_bucket")),
+            (None, Some(_)) => return Err(S3Error::MissingSetting("snapshot_s3_endpoint")),
+            (None, None) => return Ok(None),
+        };
+        let config = match &self.snapshot_s3_region {
+            Some(region) => config.with_region(region),
+            None => config,
+        };
+        Ok(Some(
+            match (&self.snapshot_s3_access_key, &self.snapshot_s3_secret_key) {
+                (Some(access_key), Some(secret_key)) => {
+                    config.with_credentials(access_key, secret_key.read()?)
+                }
+                (Some(_), None) => return Err(S3Error::MissingSetting("snapshot_s3_secret_key")),
+                (None, Some(_)) => return Err(S3Error::MissingSetting("snapshot_s3_access_key")),
+                (None, None) => config,
+            },
+        ))
+    }
+
+    /// Gets the file storing the password of the admin user, which is `admin` within the
+    /// [daemon path](Self::path).
+    pub fn admin_store(&self) -> PathBuf {
+        self.path().join(ADMIN_STORE)
+    }
+
+    /// Gets the file storing the passwords of every user but the admin, which is `users.json`
+    /// within the [daemon path](Self::path).
+    pub fn users_store(&self) -> PathBuf {
+        self.path().join(USERS_STORE)
+    }
+
+    /// Gets the file storing the groups users are members of, which is `groups.json` within the
+    /// [daemon path](Self::path).
+    pub fn groups_store(&self) -> PathBuf {
+        self.path().join(GROUPS_STORE)
+    }
+
+    /// Gets the file of tokens clients can authenticate with, if any. Every line of the file is a
+    /// user name followed by their token.
+    pub fn auth_tokens(&self) -> Option<&Path> {
+        self.auth_tokens.as_deref()
+    }
+
+    /// Gets the rules new passwords must follow. By default passwords must have at least 8
+    /// characters, and nothing else.
+    pub fn password_policy(&self) -> PasswordPolicy {
+        let policy = PasswordPolicy::new()
+            .with_mixed_case(self.password_require_mixed_case.unwrap_or(false))
+            .with_digit(self.password_require_digit.unwrap_or(false))
+            .with_symbol(self.password_require_symbol.unwrap_or(false));
+        match self.password_min_length {
+            Some(min_length) => policy.with_min_length(min_length),
+            None => policy,
+        }
+    }
+
+    /// Gets the cost of hashing new passwords with argon2id. By default this is the cost
+    /// recommended by OWASP, `19 MiB` of memory, 2 iterations and no parallelism.
+    pub fn password_hashing(&self) -> PasswordHashing {
+        let defaults = PasswordHashing::default();
+        let hashing = match self.password_hash_memory_kib {
+            Some(memory_kib) => defaults.with_memory_kib(memory_kib),
+            None => defaults,
+        };
+        let hashing = match self.password_hash_iterations {
+            Some(iterations) => hashing.with_iterations(iterations),
+            None => hashing,
+        };
+        match self.password_hash_parallelism {
+            Some(parallelism) => hashing.with_parallelism(parallelism),
+            None => hashing,
+        }
+    }
+
+    /// Gets where the secret json web tokens are signed with is read from, if clients may
+    /// authenticate with tokens signed with a shared secret. Setting `jwt_secret_file` is the same
+    /// as setting `jwt_secret` to `file:` followed by the path.
+    pub fn jwt_secret(&self) -> Option<SecretSource> {
+        self.jwt_secret
+            .clone()
+            .or_else(|| self.jwt_secret_file.clone().map(SecretSource::File))
+    }
+
+    /// Gets the file holding the JWK set json web tokens are signed with, if clients may
+    /// authenticate with tokens signed by an issuer. The set is published by the issuer, usually at
+    /// `/.well-known/jwks.json`.
+    pub fn jwt_jwks(&self) -> Option<&Path> {
+        self.jwt_jwks.as_deref()
+    }
+
+    /// Gets the only issuer json web tokens are accepted from, if any
+    pub fn jwt_issuer(&self) -> Option<&str> {
+        self.jwt_issuer.as_deref()
+    }
+
+    /// Gets the only audience json web tokens are accepted for, if any
+    pub fn jwt_audience(&self) -> Option<&str> {
+        self.jwt_audience.as_deref()
+    }
+
+    /// Gets the claim of json web tokens naming the user. By default this value is `sub`.
+    pub fn jwt_user_claim(&self) -> &str {
+        self.jwt_user_claim.as_deref().unwrap_or(DEFAULT_USER_CLAIM)
+    }
+
+    /// Gets the claim of json web tokens listing the groups of the user. By default this value is
+    /// `groups`.
+    pub fn jwt_groups_claim(&self) -> &str {
+        self.jwt_groups_claim
+            .as_deref()
+            .unwrap_or(DEFAULT_GROUPS_CLAIM)
+    }
+
+    /// Gets the claim of json web tokens listing the roles of the user. By default this value is
+    /// `roles`.
+    pub fn jwt_roles_claim(&self) -> &str {
+        self.jwt_roles_claim
+            .as_deref()
+            .unwrap_or(DEFAULT_ROLES_CLAIM)
+    }
+
+    /// Gets how long the session a client starts by authenticating lasts. By default this value
+    /// is `12h`.
+    pub fn session_ttl(&self) -> Duration {
+        self.session_ttl
+            .map(Into::into)
+            .unwrap_or(DEFAULT_SESSION_TTL)
+    }
+
+    /// Gets how long a session may go unused before it ends. By default this value is `30m`.
+    pub fn session_idle_timeout(&self) -> Duration {
+        self.session_idle_timeout
+            .map(Into::into)
+            .unwrap_or(DEFAULT_SESSION_IDLE_TIMEOUT)
+    }
+
+    /// Gets how many failed attempts in a row to authenticate as a user, or from an address, lock
+    /// them out. Zero turns off slowing down attempts altogether. By default this value is `5`.
+    pub fn login_max_failures(&self) -> u32 {
+        self.login_max_failures.unwrap_or(DEFAULT_MAX_FAILURES)
+    }
+
+    /// Gets how long to wait after the first failed attempt to authenticate, which doubles with
+    /// every other failed attempt in a row. By default this value is `1s`.
+    pub fn login_backoff(&self) -> Duration {
+        self.login_backoff
+            .map(Into::into)
+            .unwrap_or(DEFAULT_BACKOFF)
+    }
+
+    /// Gets how long users and addresses are locked out once too many attempts to authenticate
+    /// failed. By default this value is `15m`.
+    pub fn login_lockout(&self) -> Duration {
+        self.login_lockout
+            .map(Into::into)
+            .unwrap_or(DEFAULT_LOCKOUT)
+    }
+
+    /// Gets what slows down guessing credentials, unless [turned off](Self::login_max_failures)
+    pub fn login_throttle(&self) -> Option<LoginThrottle> {
+        match self.login_max_failures() {
+            0 => None,
+            max_failures => Some(LoginThrottle::new(
+                max_failures,
+                self.login_backoff(),
+                self.login_lockout(),
+            )),
+        }
+    }
+
+    /// Gets how long users are remembered after authenticating with a password, so that their
+    /// password is not verified again when they authenticate with it again. Zero turns off
+    /// remembering users. By default this value is `1m`.
+    pub fn auth_cache_ttl(&self) -> Duration {
+        self.auth_cache_ttl
+            .map(Into::into)
+            .unwrap_or(DEFAULT_CACHE_TTL)
+    }
+
+    /// Gets what remembers users who authenticated, unless [turned off](Self::auth_cache_ttl)
+    pub fn auth_cache(&self) -> Option<AuthenticationCache> {
+        let ttl = self.auth_cache_ttl();
+        (!ttl.is_zero()).then(|| AuthenticationCache::new(ttl))
+    }
+
+    /// Gets the file binding roles to users and groups, if any
+    pub fn role_bindings(&self) -> Option<&Path> {
+        self.role_bindings.as_deref()
+    }
+
+    /// Creates the authorizer deciding what users may do, from the built in roles and the
+    /// [role bindings](Self::role_bindings). Without any bindings, the admin user may do anything
+    /// while every other user may read and write documents.
+    pub fn authorizer(&self) -> Result<Authorizer, ConfigError> {
+        let authorizer = Authorizer::new();
+        let Some(path) = self.role_bindings() else {
+            return Ok(authorizer);
+        };
+        let file = File::open(path).map_err(|e| ConfigError::IoError(path.to_path_buf(), e))?;
+        let bindings: RoleBindings = serde_yaml::from_reader(file)
+            .map_err(|e| ConfigError::ParseError(path.to_path_buf(), e))?;
+        Ok(authorizer.with_bindings(bindings))
+    }
+
+    /// Gets the http url of the web service asked whether users may do what they request, in
+    /// addition to their roles, if any. See [the webhook module](crate::webhook).
+    pub fn authorization_webhook(&self) -> Option<&str> {
+        self.authorization_webhook.as_deref()
+    }
+
+    /// Gets how long to wait for the [authorization webhook](Self::authorization_webhook) to
+    /// answer before denying the request. By default this value is `5s`.
+    pub fn authorization_webhook_timeout(&self) -> Duration {
+        self.authorization_webhook_timeout
+            .map(Into::into)
+            .unwrap_or(DEFAULT_WEBHOOK_TIMEOUT)
+    }
+
+    /// Creates the service asking the [authorization webhook](Self::authorization_webhook), if
+    /// any
+    pub fn authorization_service(
+        &self,
+    ) -> Result<Option<WebhookAuthorizationService>, WebhookError> {
+        self.authorization_webhook()
+            .map(|url| {
+                WebhookAuthorizationService::new(url)
+                    .map(|service| service.with_timeout(self.authorization_webhook_timeout()))
+            })
+            .transpose()
+    }
+
+    /// Gets the file changes to the daemon are audited in, if auditing is enabled. Auditing is
+    /// disabled by default.
+    pub fn audit_log(&self) -> Option<&Path> {
+        self.audit_log.as_deref()
+    }
+
+    /// Gets the tls settings, if tls is enabled by setting both `tls_cert` and `tls_key`. By
+    /// default clients need at least TLS 1.2, and are not asked for a certificate. Unless `tls_key`
+    /// is the PEM encoded key itself, or starts with `env:` or `file:`, it is the path of the key.
+    /// TLS is not served yet, so the daemon refuses to start with it enabled.
+    pub fn tls(&self) -> Result<Option<TlsConfig>, TlsError> {
+        let (cert, key) = match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => (cert, key),
+            (Some(_), None) => return Err(TlsError::MissingKey),
+            (None, Some(_)) => return Err(TlsError::MissingCert),
+            (None, None) if self.tls_client_ca.is_some() => {
+                return Err(TlsError::TlsDisabled("tls_client_ca"))
+            }
+            (None, None) if self.tls_min_version.is_some() => {
+                return Err(TlsError::TlsDisabled("tls_min_version"))
+            }
+            (None, None) => return Ok(None),
+        };
+        let key = match key {
+            SecretSource::Value(pem) if pem.expose_secret().contains("-----BEGIN ") => {
+                SecretSource::Value(pem.clone())
+            }
+            key => key.clone().or_file(),
+        };
+        let tls =
+            TlsConfig::new(cert, key).with_min_version(self.tls_min_version.unwrap_or_default());
+        Ok(Some(match &self.tls_client_ca {
+            Some(client_ca) => tls.with_client_ca(client_ca),
+            None => tls,
+        }))
+    }
+
+    /// Gets the port replicas connect to, if this daemon accepts replicas. By default no port is
+    /// opened for replicas.
+    pub fn replication_port(&self) -> Option<u16> {
+        self.replication_port
+    }
+
+    /// Gets the address of the replication port of the primary this daemon is a replica of, if it
+    /// is a replica. By default the daemon is not a replica.
+    pub fn replica_of(&self) -> Option<&str> {
+        self.replica_of.as_deref()
+    }
+
+    /// Gets the user a replica authenticates to its primary as, along with where their password
+    /// is read from, if both are set. Primaries requiring clients to authenticate only accept
+    /// replicas whose user may manage the cluster.
+    pub fn replica_user(&self) -> Option<(&str, &SecretSource)> {
+        self.replica_user
+            .as_deref()
+            .zip(self.replica_password.as_ref())
+    }
+
+    /// Gets the number of changes kept for replicas that fall behind, past which a replica is sent
+    /// a copy of every index instead. By default this value is `10000`.
+    pub fn replication_backlog(&self) -> usize {
+        self.replication_backlog
+            .unwrap_or(DEFAULT_REPLICATION_BACKLOG)
+    }
+
+    /// Gets the number of document changes kept in memory for the subscribers of every index.
+    /// Subscribers resuming from an older change read it back from the write-ahead log of the
+    /// index. By default this value is `10000`.
+    pub fn changes_backlog(&self) -> usize {
+        self.changes_backlog.unwrap_or(DEFAULT_CHANGES_BACKLOG)
+    }
+
+    /// Gets how long a change to the documents of an index waits for more changes to the same
+    /// index before syncing its write-ahead log, so they are all committed by a single sync.
+    /// Changes made while the log is synced share the next sync even without waiting. By default
+    /// this value is `0s`.
+    pub fn wal_commit_window(&self) -> Duration {
+        self.wal_commit_window
+            .map(Into::into)
+            .unwrap_or(Duration::ZERO)
+    }
+
+    /// Gets the number of write-ahead logs merged in the background at the same time. Setting
+    /// this to `0` stops logs from being merged. By default this value is `1`.
+    pub fn max_merges(&self) -> usize {
+        self.max_merges.unwrap_or(DEFAULT_MAX_MERGES)
+    }
+
+    /// Gets the bytes per second merged write-ahead logs are written at, or `None` if merges are
+    /// not throttled, which setting this to `0` does. By default this value is `16777216`.
+    pub fn merge_throttle(&self) -> Option<u64> {
+        match self.merge_throttle.unwrap_or(DEFAULT_MERGE_THROTTLE) {
+            0 => None,
+            throttle => Some(throttle),
+        }
+    }
+
+    /// Gets the name of the cluster this daemon is a node of. Nodes only gossip with nodes of the
+    /// same cluster. By default this value is `"docatlas"`.
+    pub fn cluster_name(&self) -> &str {
+        self.cluster_name.as_deref().unwrap_or(DEFAULT_CLUSTER_NAME)
+    }
+
+    /// Gets the name of this node, which must be unique within its cluster. By default this is the
+    /// host and port clients connect to.
+    pub fn node_name(&self) -> String {
+        self.node_name
+            .clone()
+            .unwrap_or_else(|| format!("{}:{}", self.host(), self.port()))
+    }
+
+    /// Gets the port other nodes of the cluster gossip with, if this daemon joins a cluster. By
+    /// default the daemon is the only node of its cluster.
+    pub fn cluster_port(&self) -> Option<u16> {
+        self.cluster_port
+    }
+
+    /// Gets the gossip addresses of the nodes this daemon first gossips with to join its cluster.
+    /// By default there are no seeds, so other nodes must use this daemon as a seed.
+    pub fn seeds(&self) -> &[String] {
+        self.seeds.as_deref().unwrap_or_default()
+    }
+
+    /// Gets the file logs are written to, which is `docatlas.log` within the
+    /// [daemon path](Self::path).
+    pub fn log_file(&self) -> PathBuf {
+        self.path().join(LOG_FILE)
+    }
+
+    /// Gets the file the id of the running daemon is written to, which is `docatlasd.pid` within
+    /// the [daemon path](Self::path).
+    pub fn pid_file(&self) -> PathBuf {
+        self.path().join(PID_FILE)
+    }
+
     /// Gets the log level. By default this value [`LevelFilter::Info`](LevelFilter::Info)
     pub fn log_level(&self) -> &LevelFilter {
         self.log_level.as_ref().unwrap_or(&DEFAULT_LOG_LEVEL_FILTER)
     }
+
+    /// Gets how log records are written. By default logs are written as plain text.
+    pub fn log_format(&self) -> LogFormat {
+        self.log_format.unwrap_or_default()
+    }
+
+    /// Gets whether spans are logged once they close, along with the time spent in them, so slow
+    /// requests can be broken down. Spans are only logged in the json format. By default spans
+    /// are not logged.
+    pub fn log_spans(&self) -> bool {
+        self.log_spans.unwrap_or(false)
+    }
+
+    /// Gets when the log file is rotated, and how many rotated log files are kept. By default the
+    /// log file is rotated once it reaches 64 MiB, and 5 rotated files are kept. Setting
+    /// `log_max_bytes` to 0 stops the log file from being rotated by size.
+    pub fn log_rotation(&self) -> Rotation {
+        let rotation = Rotation::default()
+            .with_interval(self.log_rotate_every.map(Into::into))
+            .with_retention(self.log_retention.unwrap_or(DEFAULT_LOG_RETENTION));
+        match self.log_max_bytes {
+            Some(0) => rotation.with_max_bytes(None),
+            Some(max_bytes) => rotation.with_max_bytes(Some(max_bytes)),
+            None => rotation,
+        }
+    }
+
+    /// Fills in every setting missing from this config from the [config file](CONFIG_FILE) within
+    /// the daemon path, if there is one
+    pub fn with_config_file(mut self) -> Result<Self, ConfigError> {
+        let path = self.path().join(CONFIG_FILE);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(self),
+            Err(e) => return Err(ConfigError::IoError(path, e)),
+        };
+        let read_config: DaemonConfig =
+            serde_yaml::from_reader(file).map_err(|e| ConfigError::ParseError(path, e))?;
+        self.merge(read_config);
+        Ok(self)
+    }
+
+    /// Gets the name of every setting whose value differs in the other config
+    pub fn changed_settings(&self, other: &DaemonConfig) -> Vec<&'static str> {
+        [
+            ("path", self.path() != other.path()),
+            ("data_path", self.data_paths() != other.data_paths()),
+            ("host", self.host() != other.host()),
+            ("port", self.port() != other.port()),
+            (
+                "websocket_port",
+                self.websocket_port() != other.websocket_port(),
+            ),
+            ("http_port", self.http_port() != other.http_port()),
+            ("grpc_port", self.grpc_port() != other.grpc_port()),
+            ("local_socket", self.local_socket() != other.local_socket()),
+            (
+                "keepalive_interval",
+                self.keepalive_interval() != other.keepalive_interval(),
+            ),
+            ("idle_timeout", self.idle_timeout() != other.idle_timeout()),
+            (
+                "send_queue_capacity",
+                self.send_queue_capacity() != other.send_queue_capacity(),
+            ),
+            (
+                "slow_client_policy",
+                self.slow_client_policy() != other.slow_client_policy(),
+            ),
+            (
+                "shutdown_timeout",
+                self.shutdown_timeout() != other.shutdown_timeout(),
+            ),
+            (
+                "max_request_timeout",
+                self.max_request_timeout() != other.max_request_timeout(),
+            ),
+            (
+                "max_connections",
+                self.max_connections() != other.max_connections(),
+            ),
+            (
+                "max_connections_per_ip",
+                self.max_connections_per_ip() != other.max_connections_per_ip(),
+            ),
+            (
+                "search_threads",
+                self.search_threads() != other.search_threads(),
+            ),
+            ("rate_limit", self.rate_limit() != other.rate_limit()),
+            ("require_auth", self.require_auth() != other.require_auth()),
+            (
+                "anonymous_roles",
+                self.anonymous_roles() != other.anonymous_roles(),
+            ),
+            ("auth_tokens", self.auth_tokens() != other.auth_tokens()),
+            (
+                "password_policy",
+                self.password_policy() != other.password_policy(),
+            ),
+            (
+                "password_hashing",
+                self.password_hashing() != other.password_hashing(),
+            ),
+            (
+                "jwt",
+                self.jwt_secret() != other.jwt_secret()
+                    || self.jwt_jwks() != other.jwt_jwks()
+                    || self.jwt_issuer() != other.jwt_issuer()
+                    || self.jwt_audience() != other.jwt_audience()
+                    || self.jwt_user_claim() != other.jwt_user_claim()
+                    || self.jwt_groups_claim() != other.jwt_groups_claim()
+                    || self.jwt_roles_claim() != other.jwt_roles_claim(),
+            ),
+            ("session_ttl", self.session_ttl() != other.session_ttl()),
+            (
+                "session_idle_timeout",
+                self.session_idle_timeout() != other.session_idle_timeout(),
+            ),
+            (
+                "login_throttle",
+                self.login_max_failures() != other.login_max_failures()
+                    || self.login_backoff() != other.login_backoff()
+                    || self.login_lockout() != other.login_lockout(),
+            ),
+            (
+                "auth_cache_ttl",
+                self.auth_cache_ttl() != other.auth_cache_ttl(),
+            ),
+            (
+                "role_bindings",
+                self.role_bindings() != other.role_bindings(),
+            ),
+            (
+                "authorization_webhook",
+                self.authorization_webhook() != other.authorization_webhook()
+                    || self.authorization_webhook_timeout()
+                        != other.authorization_webhook_timeout(),
+            ),
+            ("audit_log", self.audit_log() != other.audit_log()),
+            ("tls", self.tls().ok() != other.tls().ok()),
+            (
+                "snapshot_path",
+                self.snapshot_path() != other.snapshot_path(),
+            ),
+            (
+                "snapshot_s3",
+                self.snapshot_s3_endpoint != other.snapshot_s3_endpoint
+                    || self.snapshot_s3_bucket != other.snapshot_s3_bucket
+                    || self.snapshot_s3_region != other.snapshot_s3_region
+                    || self.snapshot_s3_access_key != other.snapshot_s3_access_key
+                    || self.snapshot_s3_secret_key != other.snapshot_s3_secret_key,
+            ),
+            (
+                "replication_port",
+                self.replication_port() != other.replication_port(),
+            ),
+            (
+                "replica_of",
+                self.replica_of() != other.replica_of()
+                    || self.replica_user() != other.replica_user(),
+            ),
+            (
+                "replication_backlog",
+                self.replication_backlog() != other.replication_backlog(),
+            ),
+            (
+                "changes_backlog",
+                self.changes_backlog() != other.changes_backlog(),
+            ),
+            (
+                "wal_commit_window",
+                self.wal_commit_window() != other.wal_commit_window(),
+            ),
+            ("max_merges", self.max_merges() != other.max_merges()),
+            (
+                "merge_throttle",
+                self.merge_throttle() != other.merge_throttle(),
+            ),
+            ("cluster_name", self.cluster_name() != other.cluster_name()),
+            // the default name follows the port, which is reported on its own
+            ("node_name", self.node_name != other.node_name),
+            ("cluster_port", self.cluster_port() != other.cluster_port()),
+            ("seeds", self.seeds() != other.seeds()),
+            ("log_level", self.log_level() != other.log_level()),
+            ("log_format", self.log_format() != other.log_format()),
+            ("log_rotation", self.log_rotation() != other.log_rotation()),
+            ("log_spans", self.log_spans() != other.log_spans()),
+        ]
+        .into_iter()
+        .filter_map(|(setting, changed)| changed.then_some(setting))
+        .collect()
+    }
+}
+
+/// The config file could not be read
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("could not read config file {0:?}: {1}")]
+    IoError(PathBuf, io::Error),
+    #[error("could not parse config file {0:?}: {1}")]
+    ParseError(PathBuf, serde_yaml::Error),
+}
+
+/// How log records are written
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// A line of text per record
+    #[default]
+    Text,
+    /// A json object per record, with the fields of the record as properties
+    Json,
+}
+
+impl Display for LogFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            LogFormat::Text => "text",
+            LogFormat::Json => "json",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = UnknownLogFormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(UnknownLogFormatError(s.to_string())),
+        }
+    }
+}
+
+/// A log format could not be parsed
+#[derive(Debug, Error)]
+#[error("Unknown log format {0:?}, expected text or json")]
+pub struct UnknownLogFormatError(String);
+
+/// Deserializes a duration written like `30s` or `1m 30s`
+fn human_duration<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<humantime::Duration>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|s| s.parse().map_err(serde::de::Error::custom))
+        .transpose()
 }
 
 #[derive(Debug, Parser)]
 pub struct CliDaemonConfig {
     #[clap(flatten)]
     config: DaemonConfig,
+    /// Runs the daemon in the background, writing its output to the log file
+    #[clap(long)]
+    daemonize: bool,
 }
 
 impl CliDaemonConfig {
+    pub fn daemonize(&self) -> bool {
+        self.daemonize
+    }
+
     pub fn config(&self) -> &DaemonConfig {
         &self.config
     }
@@ -1,8 +1,24 @@
 use std::io;
 
+use crate::audit::AuditError;
+use crate::config::ConfigError;
+use crate::snapshot::s3::S3Error;
+use crate::tls::TlsError;
+use crate::webhook::WebhookError;
+
 /// An error occurred in the daemon
 #[derive(Debug, thiserror::Error)]
 pub enum DaemonError {
     #[error(transparent)]
     IoError(#[from] io::Error),
+    #[error("invalid tls settings: {0}")]
+    TlsError(#[from] TlsError),
+    #[error("could not open the audit log: {0}")]
+    AuditError(#[from] AuditError),
+    #[error(transparent)]
+    ConfigError(#[from] ConfigError),
+    #[error("invalid authorization webhook: {0}")]
+    WebhookError(#[from] WebhookError),
+    #[error("invalid s3 snapshot repository: {0}")]
+    S3Error(#[from] S3Error),
 }
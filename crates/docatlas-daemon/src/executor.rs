@@ -0,0 +1,119 @@
+//! A pool of threads for cpu heavy work, such as searches and adding documents in bulk.
+//!
+//! Connections are served by the tokio runtime, whose threads must never be busy for long.
+//! Work handed to the [`Executor`](Executor) runs on threads of its own instead, and its result
+//! is sent back through a channel the handing task awaits, so big searches never hold up
+//! connection handling. Work runs within the span of the task that handed it over.
+
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use thiserror::Error;
+use tokio::sync::oneshot;
+use tracing::Span;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Runs work on a fixed number of threads, in the order it was handed over
+#[derive(Debug)]
+pub struct Executor {
+    jobs: mpsc::Sender<Job>,
+    threads: usize,
+    queued: Arc<AtomicUsize>,
+}
+
+impl Executor {
+    /// Starts an executor with the given number of threads, at least one. The threads stop once
+    /// the executor is dropped and every queued job has run.
+    pub fn new(threads: usize) -> io::Result<Self> {
+        let threads = threads.max(1);
+        let (jobs, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let queued = Arc::new(AtomicUsize::new(0));
+        for n in 0..threads {
+            let receiver = receiver.clone();
+            let queued = queued.clone();
+            thread::Builder::new()
+                .name(format!("docatlas-search-{n}"))
+                .spawn(move || loop {
+                    let job = match receiver.lock().expect("poisoned").recv() {
+                        Ok(job) => job,
+                        Err(_) => break,
+                    };
+                    queued.fetch_sub(1, Ordering::Relaxed);
+                    job();
+                })?;
+        }
+        Ok(Self {
+            jobs,
+            threads,
+            queued,
+        })
+    }
+
+    /// Gets the number of threads work runs on
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Gets the number of jobs waiting for a thread
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Runs a function on the pool, waiting for its result without blocking the runtime
+    pub async fn run<F, R>(&self, func: F) -> Result<R, ExecutorError>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        let span = Span::current();
+        let job: Job = Box::new(move || {
+            let _span = span.entered();
+            let _ = sender.send(panic::catch_unwind(AssertUnwindSafe(func)));
+        });
+        self.queued.fetch_add(1, Ordering::Relaxed);
+        if self.jobs.send(job).is_err() {
+            self.queued.fetch_sub(1, Ordering::Relaxed);
+            return Err(ExecutorError::Stopped);
+        }
+        match receiver.await {
+            Ok(Ok(result)) => Ok(result),
+            Ok(Err(_)) => Err(ExecutorError::Panicked),
+            Err(_) => Err(ExecutorError::Stopped),
+        }
+    }
+}
+
+/// Work handed to the executor did not finish
+#[derive(Debug, Error)]
+pub enum ExecutorError {
+    #[error("The executor stopped before running the job")]
+    Stopped,
+    #[error("The job panicked")]
+    Panicked,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn runs_off_the_runtime() {
+        let executor = Executor::new(2).unwrap();
+        let name = executor
+            .run(|| thread::current().name().map(str::to_string))
+            .await
+            .unwrap();
+        assert!(name.unwrap().starts_with("docatlas-search-"));
+        let panicked = executor.run(|| panic!("search failed")).await;
+        assert!(matches!(panicked, Err(ExecutorError::Panicked)));
+        // threads survive panics
+        assert_eq!(executor.run(|| 1 + 1).await.unwrap(), 2);
+        assert_eq!(executor.queued(), 0);
+    }
+}
@@ -0,0 +1,691 @@
+//! The grpc interface of the daemon, for clients written in any language.
+//!
+//! The services are generated from `proto/docatlas.proto`, along with clients for each of them.
+//!
+//! Clients authenticate by sending credentials in the `authorization` metadata of every request,
+//! as described in [`access`](crate::access), if the daemon requires them to. Requests that are
+//! not authenticated fail with `UNAUTHENTICATED`, and requests the user lacks the permission for
+//! fail with `PERMISSION_DENIED`, like they do over connections. Documents are returned without
+//! the fields the user may not read. Requests that write are recorded in the audit log, if there
+//! is one.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use docatlas_core::auth::authorization::{Permission, Resource};
+use docatlas_core::cancel::CancelToken;
+use docatlas_core::document::Document;
+use docatlas_core::error::{DocatlasError, ErrorCategory};
+use docatlas_core::fields::{Field, FieldData, FieldKind, Fields};
+use docatlas_core::index::Upserted;
+use docatlas_core::schema::{Schema, SchemaField};
+use tonic::service::Interceptor;
+use tonic::transport::server::Router;
+use tonic::transport::Server;
+use tonic::{Request, Response, Status};
+
+use crate::access::{Access, Authenticated};
+use crate::audit::AuditAction;
+use crate::executor::Executor;
+use crate::handlers::{HandlerError, IndexInfo, Indexes};
+use crate::trace::TraceContext;
+
+/// The types and services generated from the proto definitions
+#[allow(clippy::all)]
+pub mod proto {
+    tonic::include_proto!("docatlas.v1");
+}
+
+pub use proto::admin_client::AdminClient;
+pub use proto::bulk_client::BulkClient;
+pub use proto::index_client::IndexClient;
+pub use proto::search_client::SearchClient;
+
+use proto::admin_server::{Admin, AdminServer};
+use proto::bulk_server::{Bulk, BulkServer};
+use proto::index_server::{Index, IndexServer};
+use proto::search_server::{Search, SearchServer};
+
+/// The number of hits returned by a search without a limit
+const DEFAULT_SEARCH_LIMIT: usize = 10;
+
+/// Creates a grpc server offering every service. Searches are cancelled once they run for longer
+/// than the daemon's maximum request timeout.
+pub fn router(
+    indexes: Arc<Indexes>,
+    executor: Arc<Executor>,
+    max_request_timeout: Duration,
+    access: Arc<Access>,
+) -> Router {
+    let service = GrpcService {
+        indexes,
+        executor,
+        max_request_timeout,
+    };
+    let authenticate = Authenticate(access);
+    Server::builder()
+        .trace_fn(|request| {
+            let span = TraceContext::from_headers(request.headers()).span();
+            span.record("path", request.uri().path());
+            span
+        })
+        .add_service(AdminServer::with_interceptor(
+            service.clone(),
+            authenticate.clone(),
+        ))
+        .add_service(IndexServer::with_interceptor(
+            service.clone(),
+            authenticate.clone(),
+        ))
+        .add_service(BulkServer::with_interceptor(
+            service.clone(),
+            authenticate.clone(),
+        ))
+        .add_service(SearchServer::with_interceptor(service, authenticate))
+}
+
+/// Authenticates requests by their `authorization` metadata, adding who they were authenticated
+/// as to their extensions
+#[derive(Debug, Clone)]
+struct Authenticate(Arc<Access>);
+
+impl Interceptor for Authenticate {
+    fn call(&mut self, mut request: Request<()>) -> Result<Request<()>, Status> {
+        // metadata that is not ascii is malformed, rather than missing
+        let authorization = request
+            .metadata()
+            .get("authorization")
+            .map(|value| value.to_str().unwrap_or_default().to_string());
+        let ip = request.remote_addr().map(|address| address.ip());
+        let authenticated = self.0.authenticate(authorization.as_deref(), ip)?;
+        request.extensions_mut().insert(authenticated);
+        Ok(request)
+    }
+}
+
+/// Gets who a request was authenticated as by the interceptor of its service
+fn authenticated<T>(request: &Request<T>) -> Result<Authenticated, HandlerError> {
+    request
+        .extensions()
+        .get::<Authenticated>()
+        .cloned()
+        .ok_or_else(|| HandlerError::Unauthenticated("the request was not authenticated".into()))
+}
+
+/// Implements every grpc service on top of the shared handlers
+#[derive(Debug, Clone)]
+struct GrpcService {
+    indexes: Arc<Indexes>,
+    /// Runs searches and bulk requests off the threads serving connections
+    executor: Arc<Executor>,
+    max_request_timeout: Duration,
+}
+
+impl GrpcService {
+    fn schema(&self, index: &str) -> Result<Schema, Box<Status>> {
+        match self.indexes.info(index) {
+            Ok(info) => Ok(info.schema),
+            Err(e) => Err(Box::new(e.into())),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl Admin for GrpcService {
+    async fn create_index(
+        &self,
+        request: Request<proto::CreateIndexRequest>,
+    ) -> Result<Response<proto::CreateIndexResponse>, Status> {
+        let mut authenticated = authenticated(&request)?;
+        let request = request.into_inner();
+        let action = AuditAction::IndexCreated {
+            index: request.index.clone(),
+        };
+        let created = async {
+            authenticated
+                .authorize_request(Permission::Manage, &Resource::Index(request.index.clone()))
+                .await?;
+            let schema = schema_from_proto(request.schema.unwrap_or_default()).map_err(|e| *e)?;
+            Ok(self.indexes.create(&request.index, schema)?)
+        }
+        .await;
+        authenticated.audit(action, &created.as_ref().map_err(Status::message));
+        created?;
+        Ok(Response::new(proto::CreateIndexResponse {}))
+    }
+
+    async fn drop_index(
+        &self,
+        request: Request<proto::DropIndexRequest>,
+    ) -> Result<Response<proto::DropIndexResponse>, Status> {
+        let mut authenticated = authenticated(&request)?;
+        let index = request.into_inner().index;
+        let action = AuditAction::IndexDropped {
+            index: index.clone(),
+        };
+        let dropped = async {
+            authenticated
+                .authorize_request(Permission::Manage, &Resource::Index(index.clone()))
+                .await?;
+            self.indexes.drop_index(&index)
+        }
+        .await;
+        authenticated.audit(action, &dropped);
+        dropped?;
+        Ok(Response::new(proto::DropIndexResponse {}))
+    }
+
+    async fn list_indexes(
+        &self,
+        request: Request<proto::ListIndexesRequest>,
+    ) -> Result<Response<proto::ListIndexesResponse>, Status> {
+        let mut authenticated = authenticated(&request)?;
+        authenticated
+            .authorize_request(Permission::Read, &Resource::Cluster)
+            .await?;
+        Ok(Response::new(proto::ListIndexesResponse {
+            // only the indexes the user may read
+            indexes: self
+                .indexes
+                .names()
+                .into_iter()
+                .filter(|name| {
+                    authenticated
+                        .authorize(Permission::Read, &Resource::Index(name.clone()))
+                        .is_ok()
+                })
+                .collect(),
+        }))
+    }
+
+    async fn describe_index(
+        &self,
+        request: Request<proto::DescribeIndexRequest>,
+    ) -> Result<Response<proto::IndexInfo>, Status> {
+        let mut authenticated = authenticated(&request)?;
+        let index = request.into_inner().index;
+        authenticated
+            .authorize_request(Permission::Read, &Resource::Index(index.clone()))
+            .await?;
+        let IndexInfo { name, schema, rows } = self.indexes.info(&index)?;
+        Ok(Response::new(proto::IndexInfo {
+            name,
+            schema: Some(schema_to_proto(&schema)),
+            rows: rows as u64,
+        }))
+    }
+}
+
+#[tonic::async_trait]
+impl Index for GrpcService {
+    async fn upsert(
+        &self,
+        request: Request<proto::UpsertRequest>,
+    ) -> Result<Response<proto::UpsertResponse>, Status> {
+        use proto::upsert_response::Result as Outcome;
+
+        let mut authenticated = authenticated(&request)?;
+        let request = request.into_inner();
+        let action = AuditAction::DocumentsWritten {
+            index: request.index.clone(),
+            count: 1,
+        };
+        let upserted = async {
+            authenticated
+                .authorize_request(Permission::Write, &Resource::Index(request.index.clone()))
+                .await?;
+            let schema = self.schema(&request.index).map_err(|e| *e)?;
+            let document = document_from_proto(request.document.unwrap_or_default(), &schema)
+                .map_err(|e| *e)?;
+            let indexes = self.indexes.clone();
+            // waiting for the change to be committed blocks
+            Ok(self
+                .executor
+                .run(move || indexes.upsert(&request.index, document, request.partial))
+                .await
+                .map_err(HandlerError::from)??)
+        }
+        .await;
+        authenticated.audit(action, &upserted.as_ref().map_err(Status::message));
+        let outcome = match upserted? {
+            Upserted::Inserted(row) => Outcome::Inserted(row as u64),
+            Upserted::Updated(row) => Outcome::Updated(row as u64),
+            Upserted::Dropped => Outcome::Dropped(proto::Dropped {}),
+        };
+        Ok(Response::new(proto::UpsertResponse {
+            result: Some(outcome),
+        }))
+    }
+
+    async fn get(
+        &self,
+        request: Request<proto::GetRequest>,
+    ) -> Result<Response<proto::GetResponse>, Status> {
+        let mut authenticated = authenticated(&request)?;
+        let request = request.into_inner();
+        authenticated
+            .authorize_request(Permission::Read, &Resource::Index(request.index.clone()))
+            .await?;
+        let key = self.indexes.parse_key(&request.index, &request.key)?;
+        let access = authenticated.field_access(&request.index);
+        let document = self.indexes.get(&request.index, &key)?;
+        Ok(Response::new(proto::GetResponse {
+            document: document.map(|mut document| {
+                access.strip(&mut document);
+                document_to_proto(document)
+            }),
+        }))
+    }
+
+    async fn delete(
+        &self,
+        request: Request<proto::DeleteRequest>,
+    ) -> Result<Response<proto::DeleteResponse>, Status> {
+        let mut authenticated = authenticated(&request)?;
+        let request = request.into_inner();
+        let action = AuditAction::DocumentDeleted {
+            index: request.index.clone(),
+        };
+        let deleted = async {
+            authenticated
+                .authorize_request(Permission::Write, &Resource::Index(request.index.clone()))
+                .await?;
+            let key = self.indexes.parse_key(&request.index, &request.key)?;
+            let indexes = self.indexes.clone();
+            self.executor
+                .run(move || indexes.delete(&request.index, &key))
+                .await?
+        }
+        .await;
+        authenticated.audit(action, &deleted);
+        let row = deleted?;
+        Ok(Response::new(proto::DeleteResponse {
+            deleted: row.is_some(),
+            row: row.unwrap_or_default() as u64,
+        }))
+    }
+}
+
+#[tonic::async_trait]
+impl Bulk for GrpcService {
+    async fn bulk(
+        &self,
+        request: Request<proto::BulkRequest>,
+    ) -> Result<Response<proto::BulkResponse>, Status> {
+        use proto::bulk_item::Result as Outcome;
+
+        let mut authenticated = authenticated(&request)?;
+        let request = request.into_inner();
+        let action = AuditAction::DocumentsWritten {
+            index: request.index.clone(),
+            count: request.documents.len(),
+        };
+        let added = async {
+            authenticated
+                .authorize_request(Permission::Write, &Resource::Index(request.index.clone()))
+                .await?;
+            let schema = self.schema(&request.index).map_err(|e| *e)?;
+            let documents = request
+                .documents
+                .into_iter()
+                .map(|document| document_from_proto(document, &schema))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| *e)?;
+            let indexes = self.indexes.clone();
+            Ok(self
+                .executor
+                .run(move || indexes.bulk(&request.index, documents))
+                .await
+                .map_err(HandlerError::from)??)
+        }
+        .await;
+        authenticated.audit(action, &added.as_ref().map_err(Status::message));
+        let items = added?
+            .into_iter()
+            .map(|result| proto::BulkItem {
+                result: Some(match result {
+                    Ok(Some(row)) => Outcome::Added(row as u64),
+                    Ok(None) => Outcome::Dropped(proto::Dropped {}),
+                    Err(error) => Outcome::Error(error),
+                }),
+            })
+            .collect();
+        Ok(Response::new(proto::BulkResponse { items }))
+    }
+}
+
+#[tonic::async_trait]
+impl Search for GrpcService {
+    async fn search(
+        &self,
+        request: Request<proto::SearchRequest>,
+    ) -> Result<Response<proto::SearchResponse>, Status> {
+        let mut authenticated = authenticated(&request)?;
+        let request = request.into_inner();
+        authenticated
+            .authorize_request(Permission::Read, &Resource::Index(request.index.clone()))
+            .await?;
+        // searching by a field would tell what it holds
+        let field = Resource::Field {
+            index: request.index.clone(),
+            field: request.field.clone(),
+        };
+        authenticated.authorize(Permission::Read, &field)?;
+        let limit = match request.limit {
+            0 => DEFAULT_SEARCH_LIMIT,
+            limit => limit as usize,
+        };
+        let access = authenticated.field_access(&request.index);
+        let indexes = self.indexes.clone();
+        let cancel = CancelToken::new().with_deadline(Instant::now() + self.max_request_timeout);
+        let hits = self
+            .executor
+            .run(move || {
+                indexes.search_until(
+                    &request.index,
+                    &request.field,
+                    &request.query,
+                    limit,
+                    &cancel,
+                )
+            })
+            .await
+            .map_err(HandlerError::from)??
+            .into_iter()
+            .map(|mut hit| {
+                access.strip(&mut hit.document);
+                proto::Hit {
+                    row: hit.row as u64,
+                    document: Some(document_to_proto(hit.document)),
+                }
+            })
+            .collect();
+        Ok(Response::new(proto::SearchResponse { hits }))
+    }
+}
+
+impl From<HandlerError> for Status {
+    fn from(value: HandlerError) -> Self {
+        let error = DocatlasError::from(value);
+        let message = error.message().to_string();
+        match &error {
+            DocatlasError::NotFound(_) => Status::not_found(message),
+            DocatlasError::AlreadyExists(_) => Status::already_exists(message),
+            DocatlasError::Conflict(_) => Status::failed_precondition(message),
+            DocatlasError::Unauthenticated(_) => Status::unauthenticated(message),
+            DocatlasError::PermissionDenied(_) => Status::permission_denied(message),
+            // only searches are cancelled, once their timeout passes
+            DocatlasError::Cancelled(_) => Status::deadline_exceeded(message),
+            DocatlasError::Internal(_) => Status::internal(message),
+            DocatlasError::InvalidRequest(_) => Status::invalid_argument(message),
+            DocatlasError::Unknown { .. } => match error.category() {
+                ErrorCategory::Client => Status::invalid_argument(message),
+                ErrorCategory::Server => Status::internal(message),
+            },
+        }
+    }
+}
+
+/// Converts a schema. Errors are boxed, as statuses are large.
+fn schema_from_proto(schema: proto::Schema) -> Result<Schema, Box<Status>> {
+    let fields = schema
+        .fields
+        .iter()
+        .map(|field| {
+            let size = field.size as usize;
+            let kind = match field.kind() {
+                proto::FieldKind::Keyword => FieldKind::Keyword(size),
+                proto::FieldKind::Text => FieldKind::Text(size),
+                proto::FieldKind::Number => FieldKind::Number(size),
+                proto::FieldKind::I64 => FieldKind::I64,
+                proto::FieldKind::U64 => FieldKind::U64,
+                proto::FieldKind::F64 => FieldKind::F64,
+                proto::FieldKind::Blob => FieldKind::Blob,
+            };
+            if size == 0 && matches!(kind, FieldKind::Keyword(_) | FieldKind::Text(_)) {
+                return Err(Box::new(Status::invalid_argument(format!(
+                    "field {:?} needs a size",
+                    field.name
+                ))));
+            }
+            Ok(SchemaField {
+                name: field.name.clone(),
+                kind,
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut converted = Schema::from_iter(fields);
+    if !schema.primary_key.is_empty() {
+        converted = converted.with_primary_key(schema.primary_key);
+    }
+    if !schema.routing_key.is_empty() {
+        converted = converted.with_routing_key(schema.routing_key);
+    }
+    Ok(converted)
+}
+
+fn schema_to_proto(schema: &Schema) -> proto::Schema {
+    let name_of = |field: Option<&SchemaField>| field.map(|f| f.name.clone()).unwrap_or_default();
+    proto::Schema {
+        fields: schema
+            .iter()
+            .map(|field| {
+                let kind = match field.kind {
+                    FieldKind::Keyword(_) => proto::FieldKind::Keyword,
+                    FieldKind::Text(_) => proto::FieldKind::Text,
+                    FieldKind::Number(_) => proto::FieldKind::Number,
+                    FieldKind::I64 => proto::FieldKind::I64,
+                    FieldKind::U64 => proto::FieldKind::U64,
+                    FieldKind::F64 => proto::FieldKind::F64,
+                    FieldKind::Blob => proto::FieldKind::Blob,
+                };
+                let size = match field.kind {
+                    FieldKind::Keyword(size) | FieldKind::Text(size) | FieldKind::Number(size) => {
+                        size as u64
+                    }
+                    _ => 0,
+                };
+                proto::SchemaField {
+                    name: field.name.clone(),
+                    kind: kind.into(),
+                    size,
+                }
+            })
+            .collect(),
+        primary_key: name_of(schema.primary_key()),
+        routing_key: name_of(schema.routing_key()),
+    }
+}
+
+/// Converts a document, taking the kind of every field from the schema of its index
+fn document_from_proto(
+    document: proto::Document,
+    schema: &Schema,
+) -> Result<Document, Box<Status>> {
+    use proto::value::Value;
+
+    let mut fields = Fields::new();
+    for (name, field) in document.fields {
+        let kind = schema
+            .get(&name)
+            .ok_or_else(|| Box::new(Status::invalid_argument(format!("unknown field {name:?}"))))?
+            .kind
+            .clone();
+        let data = field
+            .values
+            .into_iter()
+            .filter_map(|value| value.value)
+            .map(|value| match value {
+                Value::Bytes(bytes) => FieldData::Bytes(bytes.into()),
+                Value::I64(i) => FieldData::I64(i),
+                Value::U64(u) => FieldData::U64(u),
+                Value::F64(f) => FieldData::F64(f),
+                Value::Blob(blob) => FieldData::Blob(blob.into()),
+            });
+        fields.insert(&name, Field::new(kind, data));
+    }
+    Ok(Document::from(fields))
+}
+
+fn document_to_proto(document: Document) -> proto::Document {
+    use proto::value::Value;
+
+    let fields = document
+        .fields()
+        .iter()
+        .map(|(name, field)| {
+            let values = field
+                .data()
+                .iter()
+                .map(|data| {
+                    let value = match data {
+                        FieldData::SizeT(size) => Value::U64(*size as u64),
+                        FieldData::Bytes(bytes) => Value::Bytes(bytes.to_vec()),
+                        FieldData::Number(number) => Value::F64(number.to_f64()),
+                        FieldData::I64(i) => Value::I64(*i),
+                        FieldData::U64(u) => Value::U64(*u),
+                        FieldData::F64(f) => Value::F64(*f),
+                        FieldData::Blob(blob) => Value::Blob(blob.to_vec()),
+                        FieldData::Analyzed(text) => Value::Bytes(text.text().as_bytes().to_vec()),
+                    };
+                    proto::Value { value: Some(value) }
+                })
+                .collect();
+            (name.to_string(), proto::Field { values })
+        })
+        .collect();
+    proto::Document { fields }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use docatlas_core::auth::authentication::SessionService;
+    use docatlas_core::auth::authorization::Authorizer;
+
+    use super::proto::value::Value;
+    use super::*;
+
+    /// Wraps a message in a request, authenticated by a daemon not requiring authentication
+    fn request<T>(message: T) -> Request<T> {
+        let access = Access::new(
+            Arc::new(SessionService::default()),
+            Arc::new(Authorizer::new()),
+        );
+        let mut request = Request::new(message);
+        request
+            .extensions_mut()
+            .insert(access.authenticate(None, None).unwrap());
+        request
+    }
+
+    fn bytes(value: &str) -> proto::Field {
+        proto::Field {
+            values: vec![proto::Value {
+                value: Some(Value::Bytes(value.as_bytes().to_vec())),
+            }],
+        }
+    }
+
+    #[tokio::test]
+    async fn services_share_handlers() {
+        let service = GrpcService {
+            indexes: Arc::new(Indexes::new()),
+            executor: Arc::new(Executor::new(1).unwrap()),
+            max_request_timeout: Duration::from_secs(60),
+        };
+        let schema = proto::Schema {
+            fields: vec![
+                proto::SchemaField {
+                    name: "id".to_string(),
+                    kind: proto::FieldKind::Keyword.into(),
+                    size: 8,
+                },
+                proto::SchemaField {
+                    name: "title".to_string(),
+                    kind: proto::FieldKind::Text.into(),
+                    size: 32,
+                },
+            ],
+            primary_key: "id".to_string(),
+            routing_key: String::new(),
+        };
+        service
+            .create_index(request(proto::CreateIndexRequest {
+                index: "books".to_string(),
+                schema: Some(schema.clone()),
+            }))
+            .await
+            .unwrap();
+        let info = service
+            .describe_index(request(proto::DescribeIndexRequest {
+                index: "books".to_string(),
+            }))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(info.schema, Some(schema));
+
+        let document = proto::Document {
+            fields: HashMap::from([
+                ("id".to_string(), bytes("b1")),
+                ("title".to_string(), bytes("Dune Messiah")),
+            ]),
+        };
+        let upserted = Index::upsert(
+            &service,
+            request(proto::UpsertRequest {
+                index: "books".to_string(),
+                document: Some(document),
+                partial: false,
+            }),
+        )
+        .await
+        .unwrap()
+        .into_inner();
+        assert_eq!(
+            upserted.result,
+            Some(proto::upsert_response::Result::Inserted(0))
+        );
+
+        let hits = Search::search(
+            &service,
+            request(proto::SearchRequest {
+                index: "books".to_string(),
+                field: "title".to_string(),
+                query: "dune".to_string(),
+                limit: 0,
+            }),
+        )
+        .await
+        .unwrap()
+        .into_inner()
+        .hits;
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].document.as_ref().unwrap().fields["id"], bytes("b1"));
+
+        let missing = Index::get(
+            &service,
+            request(proto::GetRequest {
+                index: "films".to_string(),
+                key: "b1".to_string(),
+            }),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(missing.code(), tonic::Code::NotFound);
+
+        // requests that did not pass through the interceptor are not trusted
+        let unauthenticated = Index::get(
+            &service,
+            Request::new(proto::GetRequest {
+                index: "books".to_string(),
+                key: "b1".to_string(),
+            }),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(unauthenticated.code(), tonic::Code::Unauthenticated);
+    }
+}
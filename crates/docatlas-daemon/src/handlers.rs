@@ -0,0 +1,1405 @@
+//! The handlers behind every protocol the daemon speaks.
+//!
+//! The binary protocol and the http api both map their requests onto the methods of
+//! [`Indexes`](Indexes), so they always behave the same.
+//!
+//! Every change made to the documents of an index stored on disk is logged to its
+//! [write-ahead log](Wal) before it is acknowledged, and replayed when the daemon starts again.
+//! The index is unlocked while waiting for the change to be committed to the log, so changes made
+//! to the same index at the same time share their fsyncs. Searches may see a change before it is
+//! committed, but a change is never acknowledged before. If the log can not be synced, the index
+//! is rolled back to the changes committed to its log.
+//! Every committed change is also published to the [replication log](ReplicationLog), if there
+//! is one, and to the [change feed](ChangeFeed) clients subscribe to, in the order the changes
+//! were logged. A replica is read-only, and only changes its indexes by
+//! [applying](Indexes::apply) the changes published by its primary.
+//!
+//! The write-ahead log of an index is [merged](Indexes::merge_log) into a single import of its rows
+//! while changes keep being made to the index, which is only locked to start and finish the merge.
+//!
+//! Searches and queries run against a [`Searcher`] of the index, which only locks the index while
+//! it is acquired, so writes to the index are not held up by long searches.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use docatlas_core::auth::authentication::AuthenticationError;
+use docatlas_core::auth::authorization::{AuthorizationError, FieldAccess, Resource};
+use docatlas_core::auth::users::GroupError;
+use docatlas_core::cancel::{CancelToken, Cancelled};
+use docatlas_core::codec::RowDecodeError;
+use docatlas_core::document::Document;
+use docatlas_core::error::DocatlasError;
+use docatlas_core::fields::{FieldData, FieldKind};
+use docatlas_core::index::{
+    IndexReader, IndexWriter, IndexWriterError, Searcher, Transaction, Upserted, Write, Written,
+};
+use docatlas_core::persist::PersistentVec;
+use docatlas_core::query::string::QueryStringError;
+use docatlas_core::query::{Query, QueryError};
+use docatlas_core::schema::Schema;
+use docatlas_core::sql::{self, Catalog, SqlError, Table};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::audit::AuditError;
+use crate::changes::{
+    self, ChangeEvent, ChangeFeed, Changed, ChangesError, ChangesPosition, MAX_REPLAYED_EVENTS,
+};
+use crate::client::upsert_mode;
+use crate::executor::ExecutorError;
+use crate::index_manager::{self, IndexManager, InvalidIndexName, PathUsage};
+use crate::merge::{LogUsage, MergeProgress, MergeStatus, MERGE_CHUNK_BYTES};
+use crate::replication::{IndexCopy, IndexesCopy, Operation, ReplicationLog};
+use crate::snapshot::SnapshotError;
+use crate::wal::{IndexRecovery, Pending, Wal, WalError};
+
+/// The indexes served by the daemon
+#[derive(Debug, Default)]
+pub struct Indexes {
+    indexes: RwLock<HashMap<String, Arc<Mutex<IndexWriter>>>>,
+    /// Stores the mappings of indexes, unless they only live in memory
+    manager: Option<IndexManager>,
+    /// The write-ahead log of every index stored on disk
+    wals: Mutex<HashMap<String, Wal>>,
+    /// How every index was recovered when it was opened
+    recovery: Vec<IndexRecovery>,
+    /// Publishes every change to replicas, if the daemon accepts replicas
+    log: Option<Arc<ReplicationLog>>,
+    /// Keeps the most recent changes to the documents of every index for their subscribers
+    feed: Option<Arc<ChangeFeed>>,
+    /// Set while the daemon is a replica
+    read_only: AtomicBool,
+    /// Held for reading by every change, and for writing while copying every index, so that a
+    /// copy always matches a position in the replication log
+    changing: RwLock<()>,
+    /// How long changes wait for more changes to share the sync of a write-ahead log
+    commit_window: Duration,
+    /// The progress of every write-ahead log being merged, by index
+    merges: Mutex<BTreeMap<String, Arc<MergeProgress>>>,
+}
+
+/// A summary of an index
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexInfo {
+    pub name: String,
+    pub schema: Schema,
+    pub rows: usize,
+}
+
+/// A document found by a search
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Hit {
+    pub row: usize,
+    pub document: Document,
+}
+
+impl Indexes {
+    /// Creates an empty set of indexes, which only live in memory
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Opens the indexes stored by the manager, which are kept on disk from now on. The
+    /// documents of every index are recovered by replaying its write-ahead log.
+    pub fn open(manager: IndexManager) -> io::Result<Self> {
+        let mut indexes = HashMap::new();
+        let mut wals = HashMap::new();
+        let mut recovery = vec![];
+        for (name, schema) in manager.load()? {
+            let (wal, replay) = Wal::open(manager.wal_path(&name)).map_err(io::Error::other)?;
+            let replayed = replay.operations.len();
+            let (writer, failed) = replay_log(schema, replay.operations);
+            recovery.push(IndexRecovery {
+                index: name.clone(),
+                replayed,
+                failed,
+                rolled_back: replay.rolled_back,
+                rows: writer.len(),
+            });
+            indexes.insert(name.clone(), Arc::new(Mutex::new(writer)));
+            wals.insert(name, wal);
+        }
+        Ok(Self {
+            indexes: RwLock::new(indexes),
+            manager: Some(manager),
+            wals: Mutex::new(wals),
+            recovery,
+            ..Self::default()
+        })
+    }
+
+    /// Gets how every index was recovered when the indexes were opened, sorted by name
+    pub fn recovery(&self) -> &[IndexRecovery] {
+        &self.recovery
+    }
+
+    /// Sets how long a change waits for more changes to the same index before syncing its
+    /// write-ahead log, so they are committed by a single sync
+    pub fn with_commit_window(mut self, window: Duration) -> Self {
+        let wals = self.wals.get_mut().expect("wals poisoned");
+        *wals = std::mem::take(wals)
+            .into_iter()
+            .map(|(name, wal)| (name, wal.with_commit_window(window)))
+            .collect();
+        self.commit_window = window;
+        self
+    }
+
+    /// Publishes every change made from now on to the given replication log
+    pub fn with_replication_log(mut self, log: Arc<ReplicationLog>) -> Self {
+        self.log = Some(log);
+        self
+    }
+
+    /// Gets the log every change is published to, if there is one
+    pub fn replication_log(&self) -> Option<&Arc<ReplicationLog>> {
+        self.log.as_ref()
+    }
+
+    /// Captures every change made from now on to the documents of the indexes in the given feed.
+    /// The changes replayed from the write-ahead log of an index are numbered, but not kept.
+    pub fn with_change_feed(mut self, feed: Arc<ChangeFeed>) -> Self {
+        for name in self.names() {
+            let replayed = self
+                .recovery
+                .iter()
+                .find(|recovery| recovery.index == name)
+                .map_or(0, |recovery| recovery.replayed as u64);
+            feed.restart(&name, replayed);
+        }
+        self.feed = Some(feed);
+        self
+    }
+
+    /// Gets the feed the changes to the documents of the indexes are captured in, if there is one
+    pub fn change_feed(&self) -> Option<&Arc<ChangeFeed>> {
+        self.feed.as_ref()
+    }
+
+    /// Gets where the log of the changes to an index is at
+    pub fn change_position(&self, index: &str) -> Result<ChangesPosition, HandlerError> {
+        let feed = self
+            .feed
+            .as_ref()
+            .ok_or_else(|| ChangesError::NotCaptured {
+                index: index.to_string(),
+            })?;
+        feed.position(index)
+            .ok_or_else(|| HandlerError::NoSuchIndex(index.to_string()))
+    }
+
+    /// Gets the events of every change made to the documents of an index after `after`, along
+    /// with where the log of the index is at. The log must have the given id, if any. Changes that
+    /// are no longer kept by the change feed are read back from the write-ahead log of the index,
+    /// stopping after the change reaching [`MAX_REPLAYED_EVENTS`] events, so the position
+    /// is that of the last change read and the rest are read by asking again.
+    pub fn changes(
+        &self,
+        index: &str,
+        log: Option<u64>,
+        after: u64,
+    ) -> Result<(ChangesPosition, Vec<ChangeEvent>), HandlerError> {
+        let position = self.change_position(index)?;
+        let feed = self
+            .feed
+            .as_ref()
+            .expect("the position is read from the feed");
+        if log.is_some_and(|log| log != position.log) {
+            return Err(ChangesError::Restarted(index.to_string()).into());
+        }
+        if after > position.last {
+            return Err(ChangesError::Ahead {
+                index: index.to_string(),
+                after,
+                last: position.last,
+            }
+            .into());
+        }
+        if let Some(events) = feed.since(index, position.log, after) {
+            return Ok((position, events));
+        }
+        let Some(manager) = &self.manager else {
+            return Err(ChangesError::Unavailable {
+                index: index.to_string(),
+                after,
+            }
+            .into());
+        };
+        // changes are only published once committed to the log, so it holds every change up to
+        // the position
+        let operations = Wal::read(manager.wal_path(index))?;
+        let mut writer = IndexWriter::new(self.mapping(index)?, PersistentVec::in_memory());
+        let mut events = vec![];
+        let mut last = after;
+        for (seq, operation) in (1..=position.last).zip(operations) {
+            let changed = replay_change(&mut writer, operation).unwrap_or_default();
+            if seq > after {
+                let changed = changes::events(&writer, changed);
+                events.extend(
+                    changed
+                        .into_iter()
+                        .map(|event| ChangeEvent { seq, ..event }),
+                );
+                last = seq;
+                if events.len() >= MAX_REPLAYED_EVENTS {
+                    break;
+                }
+            }
+        }
+        // the log read may have been started again since the position was taken
+        if feed.position(index).map(|position| position.log) != Some(position.log) {
+            return Err(ChangesError::Restarted(index.to_string()).into());
+        }
+        Ok((ChangesPosition { last, ..position }, events))
+    }
+
+    /// Sets whether the indexes are read-only, refusing every request that would change them
+    pub fn set_read_only(&self, read_only: bool) {
+        self.read_only.store(read_only, Ordering::SeqCst);
+    }
+
+    /// Checks whether the indexes are read-only
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::SeqCst)
+    }
+
+    /// Creates a new, empty index
+    pub fn create(&self, name: &str, schema: Schema) -> Result<(), HandlerError> {
+        self.change(|| self.create_index(name, schema))
+    }
+
+    fn create_index(&self, name: &str, schema: Schema) -> Result<(), HandlerError> {
+        index_manager::validate_name(name)?;
+        let mut indexes = self.indexes.write().expect("indexes poisoned");
+        if indexes.contains_key(name) {
+            return Err(HandlerError::IndexExists(name.to_string()));
+        }
+        if let Some(manager) = &self.manager {
+            manager.create(name, &schema)?;
+        }
+        self.record(
+            || Operation::CreateIndex {
+                index: name.to_string(),
+                schema: schema.clone(),
+            },
+            Vec::new,
+        )?;
+        let writer = IndexWriter::new(schema, PersistentVec::in_memory());
+        indexes.insert(name.to_string(), Arc::new(Mutex::new(writer)));
+        Ok(())
+    }
+
+    /// Sets the schema of an index, creating the index if it does not exist. The schema of an
+    /// index already holding documents can not change.
+    pub fn put_schema(&self, name: &str, schema: Schema) -> Result<(), HandlerError> {
+        self.change(|| self.replace_schema(name, schema, true))
+    }
+
+    /// Sets the schema of an existing index, which must not hold any documents
+    pub fn update_mapping(&self, name: &str, schema: Schema) -> Result<(), HandlerError> {
+        self.change(|| self.replace_schema(name, schema, false))
+    }
+
+    /// Gets the schema of an index
+    pub fn mapping(&self, name: &str) -> Result<Schema, HandlerError> {
+        self.with_index(name, |writer| Ok(writer.schema().clone()))
+    }
+
+    fn replace_schema(&self, name: &str, schema: Schema, create: bool) -> Result<(), HandlerError> {
+        index_manager::validate_name(name)?;
+        let mut indexes = self.indexes.write().expect("indexes poisoned");
+        match indexes.get(name) {
+            Some(writer) if !writer.lock().expect("index poisoned").is_empty() => {
+                return Err(HandlerError::IndexNotEmpty(name.to_string()))
+            }
+            None if !create => return Err(HandlerError::NoSuchIndex(name.to_string())),
+            _ => {}
+        }
+        if let Some(manager) = &self.manager {
+            manager.create(name, &schema)?;
+        }
+        self.record(
+            || Operation::ReplaceSchema {
+                index: name.to_string(),
+                schema: schema.clone(),
+            },
+            Vec::new,
+        )?;
+        let writer = IndexWriter::new(schema, PersistentVec::in_memory());
+        indexes.insert(name.to_string(), Arc::new(Mutex::new(writer)));
+        Ok(())
+    }
+
+    /// Copies the schema and raw rows of an index, all taken while no request is writing to it
+    pub fn export(&self, name: &str) -> Result<(Schema, Vec<u8>), HandlerError> {
+        self.with_index(name, |writer| {
+            let rows = (0..writer.len())
+                .filter_map(|row| writer.row(row))
+                .flatten()
+                .copied()
+                .collect();
+            Ok((writer.schema().clone(), rows))
+        })
+    }
+
+    /// Creates an index holding the given raw rows, as exported from another index with the same
+    /// schema
+    pub fn import(&self, name: &str, schema: Schema, rows: &[u8]) -> Result<(), HandlerError> {
+        self.change(|| self.import_rows(name, schema, rows))
+    }
+
+    fn import_rows(&self, name: &str, schema: Schema, rows: &[u8]) -> Result<(), HandlerError> {
+        index_manager::validate_name(name)?;
+        if !rows.len().is_multiple_of(schema.row_size().max(1)) {
+            return Err(HandlerError::IoError(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("the rows of {name:?} do not match its schema"),
+            )));
+        }
+        let mut indexes = self.indexes.write().expect("indexes poisoned");
+        if indexes.contains_key(name) {
+            return Err(HandlerError::IndexExists(name.to_string()));
+        }
+        if let Some(manager) = &self.manager {
+            manager.create(name, &schema)?;
+        }
+        self.record(
+            || Operation::Import {
+                index: name.to_string(),
+                schema: schema.clone(),
+                rows: rows.to_vec(),
+            },
+            Vec::new,
+        )?;
+        let mut stored = PersistentVec::in_memory();
+        stored.extend_from_slice(rows);
+        let writer = IndexWriter::new(schema, stored);
+        indexes.insert(name.to_string(), Arc::new(Mutex::new(writer)));
+        Ok(())
+    }
+
+    /// Drops an index and every document in it
+    pub fn drop_index(&self, name: &str) -> Result<(), HandlerError> {
+        self.change(|| self.remove_index(name))
+    }
+
+    fn remove_index(&self, name: &str) -> Result<(), HandlerError> {
+        let mut indexes = self.indexes.write().expect("indexes poisoned");
+        if !indexes.contains_key(name) {
+            return Err(HandlerError::NoSuchIndex(name.to_string()));
+        }
+        if let Some(manager) = &self.manager {
+            manager.delete(name)?;
+        }
+        self.record(
+            || Operation::DropIndex {
+                index: name.to_string(),
+            },
+            Vec::new,
+        )?;
+        indexes.remove(name);
+        Ok(())
+    }
+
+    /// Copies every index, along with the position in the replication log the copy was taken at.
+    /// Nothing can change while the copy is taken.
+    pub fn copy(&self) -> Result<IndexesCopy, HandlerError> {
+        let _changing = self.changing.write().expect("indexes poisoned");
+        let (log, seq) = match &self.log {
+            Some(log) => (log.id(), log.last()),
+            None => (0, 0),
+        };
+        let mut indexes = vec![];
+        for name in self.names() {
+            let (schema, rows) = self.export(&name)?;
+            indexes.push(IndexCopy { name, schema, rows });
+        }
+        Ok(IndexesCopy { log, seq, indexes })
+    }
+
+    /// Replaces every index with a copy of the indexes of a primary. Replicas of these indexes
+    /// can not follow the replication log past the replacement, so the log is reset.
+    pub fn replace_all(&self, copies: Vec<IndexCopy>) -> Result<(), HandlerError> {
+        let _changing = self.changing.write().expect("indexes poisoned");
+        for name in self.names() {
+            self.remove_index(&name)?;
+        }
+        for copy in copies {
+            self.import_rows(&copy.name, copy.schema, &copy.rows)?;
+        }
+        if let Some(log) = &self.log {
+            log.reset();
+        }
+        Ok(())
+    }
+
+    /// Applies a change published by the primary, even though a replica is read-only
+    pub fn apply(&self, operation: Operation) -> Result<(), HandlerError> {
+        let _changing = self.changing.read().expect("indexes poisoned");
+        match operation {
+            Operation::CreateIndex { index, schema } => self.create_index(&index, schema),
+            Operation::ReplaceSchema { index, schema } => self.replace_schema(&index, schema, true),
+            Operation::Import {
+                index,
+                schema,
+                rows,
+            } => self.import_rows(&index, schema, &rows),
+            Operation::DropIndex { index } => self.remove_index(&index),
+            Operation::Upsert {
+                index,
+                document,
+                partial,
+            } => self.upsert_document(&index, document, partial).map(|_| ()),
+            Operation::Bulk { index, documents } => {
+                self.add_documents(&index, documents).map(|_| ())
+            }
+            Operation::Delete { index, key } => self.delete_document(&index, &key).map(|_| ()),
+            Operation::Transaction { index, transaction } => {
+                self.commit_transaction(&index, transaction).map(|_| ())
+            }
+        }
+    }
+
+    /// Gets the names of every index, in alphabetical order
+    pub fn names(&self) -> Vec<String> {
+        let mut names = self
+            .indexes
+            .read()
+            .expect("indexes poisoned")
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    /// Checks whether an index exists
+    pub fn contains(&self, name: &str) -> bool {
+        self.indexes
+            .read()
+            .expect("indexes poisoned")
+            .contains_key(name)
+    }
+
+    /// Gets a summary of an index
+    pub fn info(&self, name: &str) -> Result<IndexInfo, HandlerError> {
+        self.with_index(name, |writer| {
+            Ok(IndexInfo {
+                name: name.to_string(),
+                schema: writer.schema().clone(),
+                rows: writer.len(),
+            })
+        })
+    }
+
+    /// Gets how much of every directory indexes are stored in is used, which is nothing if the
+    /// indexes only live in memory
+    pub fn path_usage(&self) -> Vec<PathUsage> {
+        self.manager
+            .as_ref()
+            .map_or_else(Vec::new, IndexManager::usage)
+    }
+
+    /// Gets how large the write-ahead log of every index stored on disk is, compared to its rows,
+    /// sorted by index
+    pub fn log_usage(&self) -> Vec<LogUsage> {
+        let logs = self
+            .wals
+            .lock()
+            .expect("wals poisoned")
+            .iter()
+            .filter_map(|(index, wal)| Some((index.clone(), wal.len().ok()?)))
+            .collect::<BTreeMap<_, _>>();
+        // indexes are locked before their logs, so the logs are measured first
+        logs.into_iter()
+            .filter_map(|(index, log_bytes)| {
+                let rows_bytes = self
+                    .with_index(&index, |writer| {
+                        Ok(writer.len() as u64 * writer.schema().row_size() as u64)
+                    })
+                    .ok()?;
+                Some(LogUsage {
+                    index,
+                    log_bytes,
+                    rows_bytes,
+                })
+            })
+            .collect()
+    }
+
+    /// Gets the progress of every write-ahead log being merged, sorted by index
+    pub fn merges(&self) -> Vec<MergeStatus> {
+        self.merges
+            .lock()
+            .expect("merges poisoned")
+            .values()
+            .map(|progress| progress.status())
+            .collect()
+    }
+
+    /// Merges the write-ahead log of an index into a single import of its rows, followed by the
+    /// changes made while merging. The merged log is written a chunk at a time, calling `pace`
+    /// after every chunk so the caller can throttle the merge, or abandon it by failing. Returns
+    /// the bytes of the merged log, or `None` if the index only lives in memory or its log is
+    /// already being merged.
+    pub fn merge_log(
+        &self,
+        index: &str,
+        mut pace: impl FnMut(&MergeProgress) -> Result<(), Cancelled>,
+    ) -> Result<Option<u64>, HandlerError> {
+        let writer = self.writer(index)?;
+        let progress = Arc::new(MergeProgress::new(index));
+        {
+            let mut merges = self.merges.lock().expect("merges poisoned");
+            if merges.contains_key(index) {
+                return Ok(None);
+            }
+            merges.insert(index.to_string(), progress.clone());
+        }
+        let merged = self.write_merged_log(index, &writer, &progress, &mut pace);
+        self.merges.lock().expect("merges poisoned").remove(index);
+        merged
+    }
+
+    fn write_merged_log(
+        &self,
+        index: &str,
+        writer: &Mutex<IndexWriter>,
+        progress: &MergeProgress,
+        pace: &mut dyn FnMut(&MergeProgress) -> Result<(), Cancelled>,
+    ) -> Result<Option<u64>, HandlerError> {
+        let (searcher, mut merge) = {
+            let mut writer = writer.lock().expect("index poisoned");
+            let wals = self.wals.lock().expect("wals poisoned");
+            let Some(wal) = wals.get(index) else {
+                return Ok(None);
+            };
+            // the index may hold changes that are rolled back once their sync fails
+            wal.settle()?;
+            (writer.searcher(), wal.start_merge()?)
+        };
+        let rows = (0..searcher.len())
+            .filter_map(|row| searcher.row(row))
+            .flatten()
+            .copied()
+            .collect();
+        merge.set_merged(&Operation::Import {
+            index: index.to_string(),
+            schema: searcher.schema().clone(),
+            rows,
+        })?;
+        drop(searcher);
+        let bytes = merge.len() as u64;
+        while merge.write_some(MERGE_CHUNK_BYTES)? > 0 {
+            progress.set_written(merge.written() as u64, bytes);
+            pace(progress)?;
+        }
+
+        let _writer = writer.lock().expect("index poisoned");
+        let mut wals = self.wals.lock().expect("wals poisoned");
+        let Some(wal) = wals.get_mut(index) else {
+            return Ok(None);
+        };
+        let (merged, changes) = merge.finish(wal)?;
+        let bytes = merged.len()?;
+        *wal = merged;
+        if let Some(feed) = &self.feed {
+            feed.restart(index, changes);
+        }
+        Ok(Some(bytes))
+    }
+
+    /// Gets a summary of every index, sorted by name
+    pub fn infos(&self) -> Vec<IndexInfo> {
+        self.names()
+            .iter()
+            .filter_map(|name| self.info(name).ok())
+            .collect()
+    }
+
+    /// Inserts a document, or updates the document sharing its primary key
+    pub fn upsert(
+        &self,
+        index: &str,
+        document: Document,
+        partial: bool,
+    ) -> Result<Upserted, HandlerError> {
+        self.change(|| self.upsert_document(index, document, partial))
+    }
+
+    fn upsert_document(
+        &self,
+        index: &str,
+        document: Document,
+        partial: bool,
+    ) -> Result<Upserted, HandlerError> {
+        let (upserted, pending) = self.with_index(index, |writer| {
+            let recorded = self.records_changes().then(|| document.clone());
+            let upserted = writer.upsert(document, upsert_mode(partial))?;
+            let mut pending = None;
+            if let Some(document) = recorded {
+                pending = self.record_change(
+                    index,
+                    writer,
+                    Operation::Upsert {
+                        index: index.to_string(),
+                        document,
+                        partial,
+                    },
+                    |writer| {
+                        changes::events(writer, Changed::upserted(upserted).into_iter().collect())
+                    },
+                )?;
+            }
+            Ok((upserted, pending))
+        })?;
+        self.commit(index, pending)?;
+        Ok(upserted)
+    }
+
+    /// Adds documents in bulk, returning the row of every document or why it could not be added
+    pub fn bulk(
+        &self,
+        index: &str,
+        documents: Vec<Document>,
+    ) -> Result<Vec<Result<Option<usize>, String>>, HandlerError> {
+        self.change(|| self.add_documents(index, documents))
+    }
+
+    fn add_documents(
+        &self,
+        index: &str,
+        documents: Vec<Document>,
+    ) -> Result<Vec<Result<Option<usize>, String>>, HandlerError> {
+        let (items, pending) = self.with_index(index, |writer| {
+            let recorded = self.records_changes().then(|| documents.clone());
+            let items: Vec<_> = writer
+                .add_documents(documents)
+                .into_iter()
+                .map(|result| result.map_err(|e| e.to_string()))
+                .collect();
+            let mut pending = None;
+            if let Some(documents) = recorded {
+                let added = items.iter().filter_map(|item| item.clone().ok().flatten());
+                pending = self.record_change(
+                    index,
+                    writer,
+                    Operation::Bulk {
+                        index: index.to_string(),
+                        documents,
+                    },
+                    |writer| changes::events(writer, added.map(Changed::Added).collect()),
+                )?;
+            }
+            Ok((items, pending))
+        })?;
+        self.commit(index, pending)?;
+        Ok(items)
+    }
+
+    /// Commits the writes of a transaction to an index, applying all of them or none. The
+    /// transaction is recorded as a single change, so it is replayed as a whole or not at all.
+    pub fn transaction(
+        &self,
+        index: &str,
+        transaction: Transaction,
+    ) -> Result<Vec<Written>, HandlerError> {
+        self.change(|| self.commit_transaction(index, transaction))
+    }
+
+    fn commit_transaction(
+        &self,
+        index: &str,
+        transaction: Transaction,
+    ) -> Result<Vec<Written>, HandlerError> {
+        let (written, pending) = self.with_index(index, |writer| {
+            let prepared = writer.prepare(&transaction)?;
+            if !self.records_changes() {
+                return Ok((writer.apply(prepared)?, None));
+            }
+            let keys = deleted_keys(&transaction);
+            let operation = Operation::Transaction {
+                index: index.to_string(),
+                transaction,
+            };
+            // the transaction is logged before it is applied, so one that can not be logged
+            // leaves nothing to roll back
+            let pending = self.log(&operation)?;
+            let written = writer
+                .apply(prepared)
+                .expect("nothing is written to a locked index");
+            self.publish_committed(pending.as_ref(), operation, || {
+                changes::events(writer, transaction_changes(&written, keys))
+            });
+            Ok((written, pending))
+        })?;
+        self.commit(index, pending)?;
+        Ok(written)
+    }
+
+    /// Gets the document with the given primary key
+    pub fn get(&self, index: &str, key: &FieldData) -> Result<Option<Document>, HandlerError> {
+        self.with_index(index, |writer| match writer.find(key)? {
+            Some(row) => Ok(Some(writer.read(row).expect("keyed rows exist")?)),
+            None => Ok(None),
+        })
+    }
+
+    /// Deletes the document with the given primary key, returning the row it was stored in
+    pub fn delete(&self, index: &str, key: &FieldData) -> Result<Option<usize>, HandlerError> {
+        self.change(|| self.delete_document(index, key))
+    }
+
+    fn delete_document(&self, index: &str, key: &FieldData) -> Result<Option<usize>, HandlerError> {
+        let (deleted, pending) = self.with_index(index, |writer| {
+            let deleted = writer.delete(key)?;
+            let mut pending = None;
+            if deleted.is_some() {
+                pending = self.record_change(
+                    index,
+                    writer,
+                    Operation::Delete {
+                        index: index.to_string(),
+                        key: key.clone(),
+                    },
+                    |writer| changes::events(writer, vec![Changed::Deleted(key.clone())]),
+                )?;
+            }
+            Ok((deleted, pending))
+        })?;
+        self.commit(index, pending)?;
+        Ok(deleted)
+    }
+
+    /// Finds up to `limit` documents whose field contains every term of the query
+    pub fn search(
+        &self,
+        index: &str,
+        field: &str,
+        query: &str,
+        limit: usize,
+    ) -> Result<Vec<Hit>, HandlerError> {
+        self.search_until(index, field, query, limit, &CancelToken::new())
+    }
+
+    /// Searches like [`search`](Self::search), stopping early once the token is cancelled
+    pub fn search_until(
+        &self,
+        index: &str,
+        field: &str,
+        query: &str,
+        limit: usize,
+        cancel: &CancelToken,
+    ) -> Result<Vec<Hit>, HandlerError> {
+        match self.search_partial(index, field, query, limit, cancel)? {
+            (hits, true) => Ok(hits),
+            (_, false) => Err(Cancelled.into()),
+        }
+    }
+
+    /// Searches like [`search`](Self::search), stopping early once the token is cancelled.
+    /// Returns the hits found so far, and whether the search finished.
+    pub fn search_partial(
+        &self,
+        index: &str,
+        field: &str,
+        query: &str,
+        limit: usize,
+        cancel: &CancelToken,
+    ) -> Result<(Vec<Hit>, bool), HandlerError> {
+        self.inspect(index, |searcher| {
+            let (rows, complete) = searcher.search_partial(field, query, cancel);
+            // rows are matched in order, so the first `limit` rows are already final
+            let mut complete = complete || rows.len() >= limit;
+            let mut hits = vec![];
+            for row in rows.into_iter().take(limit) {
+                if cancel.is_cancelled() {
+                    complete = false;
+                    break;
+                }
+                let document = searcher.read(row).expect("matched rows exist")?;
+                hits.push(Hit { row, document });
+            }
+            Ok((hits, complete))
+        })
+    }
+
+    /// Finds up to `limit` documents of an index matching a query, in the order of their rows,
+    /// stopping early once the token is cancelled
+    pub fn query(
+        &self,
+        index: &str,
+        query: &Query,
+        limit: usize,
+        cancel: &CancelToken,
+    ) -> Result<Vec<Hit>, HandlerError> {
+        self.inspect(index, |searcher| {
+            let rows = query.rows(searcher, cancel)?;
+            rows.into_iter()
+                .take(limit)
+                .map(|row| {
+                    let document = searcher.read(row).expect("matched rows exist")?;
+                    Ok(Hit { row, document })
+                })
+                .collect()
+        })
+    }
+
+    /// Reads up to `limit` documents of an index in the order of their rows, starting at row
+    /// `from`, only reading those matched by a search if one is given as its field and query.
+    /// Returns the row to continue from along with the documents, unless every row was read.
+    ///
+    /// Documents are read at the sequence number `at` if it is given, which must be one returned
+    /// by an earlier scan, so every page of a scan sees the index as it was when the scan started.
+    /// Otherwise the index is read as it is now, and its sequence number is returned along with
+    /// the documents.
+    pub fn scan(
+        &self,
+        index: &str,
+        search: Option<(&str, &str)>,
+        from: usize,
+        limit: usize,
+        at: Option<u64>,
+    ) -> Result<(Vec<Hit>, Option<usize>, u64), HandlerError> {
+        let searcher = self.with_index(index, |writer| match at {
+            Some(sequence) => writer
+                .pinned(sequence)
+                .ok_or_else(|| HandlerError::Unpinned {
+                    index: index.to_string(),
+                    sequence,
+                }),
+            None => Ok(writer.pin()),
+        })?;
+        let (rows, next) = searcher.scan(search, from, limit);
+        let hits = rows
+            .into_iter()
+            .map(|row| {
+                let document = searcher.read(row).expect("scanned rows exist")?;
+                Ok(Hit { row, document })
+            })
+            .collect::<Result<_, HandlerError>>()?;
+        Ok((hits, next, searcher.generation()))
+    }
+
+    /// Runs a sql statement, stopping early once the token is cancelled. Reading every index the
+    /// statement selects from, and every field it queries, must be allowed by `authorize`. Only
+    /// the fields of an index allowed by `access` can be selected, or used in any other way.
+    pub fn sql(
+        &self,
+        statement: &str,
+        cancel: &CancelToken,
+        authorize: &dyn Fn(&Resource) -> Result<(), HandlerError>,
+        access: &dyn Fn(&str) -> FieldAccess,
+    ) -> Result<Table, HandlerError> {
+        let catalog = SqlCatalog {
+            indexes: self,
+            cancel,
+            authorize,
+            access,
+        };
+        sql::execute(&catalog, statement)
+    }
+
+    /// Writes every index to the files backing it
+    pub fn flush(&self) -> io::Result<()> {
+        let writers = self
+            .indexes
+            .read()
+            .expect("indexes poisoned")
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        for writer in writers {
+            writer.lock().expect("index poisoned").flush()?;
+        }
+        Ok(())
+    }
+
+    /// Parses a primary key given as text, such as in an url, according to the schema of an index
+    pub fn parse_key(&self, index: &str, key: &str) -> Result<FieldData, HandlerError> {
+        self.with_index(index, |writer| parse_key(writer.schema(), key))
+    }
+
+    /// Makes a change requested by a client, unless the indexes are read-only
+    fn change<R>(&self, func: impl FnOnce() -> Result<R, HandlerError>) -> Result<R, HandlerError> {
+        if self.is_read_only() {
+            return Err(HandlerError::ReadOnly);
+        }
+        let _changing = self.changing.read().expect("indexes poisoned");
+        func()
+    }
+
+    /// Checks whether changes are logged, captured or published anywhere
+    fn records_changes(&self) -> bool {
+        self.manager.is_some() || self.log.is_some() || self.feed.is_some()
+    }
+
+    /// Records a change that was just made to the documents of an index, while it is still
+    /// locked, rolling the index back if the change can not be logged
+    fn record_change(
+        &self,
+        index: &str,
+        writer: &mut IndexWriter,
+        operation: Operation,
+        events: impl FnOnce(&IndexWriter) -> Vec<ChangeEvent>,
+    ) -> Result<Option<Pending>, HandlerError> {
+        let recorded = self.record(|| operation, || events(writer));
+        if recorded.is_err() {
+            self.roll_back(index, writer)?;
+        }
+        recorded
+    }
+
+    /// Logs a change that was just made to the write-ahead log of its index and captures the
+    /// events describing it, while the index it was made to is still locked. Changes to documents
+    /// are only written to the log, and committed and then published once the returned change is
+    /// waited for, while changes to the indexes themselves are committed and published before
+    /// returning.
+    fn record(
+        &self,
+        operation: impl FnOnce() -> Operation,
+        events: impl FnOnce() -> Vec<ChangeEvent>,
+    ) -> Result<Option<Pending>, HandlerError> {
+        if !self.records_changes() {
+            return Ok(None);
+        }
+        let operation = operation();
+        let pending = self.log(&operation)?;
+        self.publish_committed(pending.as_ref(), operation, events);
+        Ok(pending)
+    }
+
+    /// Writes a change to the write-ahead log of its index, if it has one. Changes to documents
+    /// are committed once the returned change is waited for, while changes to the indexes
+    /// themselves start a new log right away.
+    fn log(&self, operation: &Operation) -> Result<Option<Pending>, HandlerError> {
+        let Some(manager) = &self.manager else {
+            return Ok(None);
+        };
+        let mut wals = self.wals.lock().expect("wals poisoned");
+        let create = |index: &str| {
+            Wal::create(manager.wal_path(index))
+                .map(|wal| wal.with_commit_window(self.commit_window))
+        };
+        // the changes written to a log that is replaced are published before the replacement
+        let settle = |wals: &HashMap<String, Wal>, index: &str| {
+            if let Some(wal) = wals.get(index) {
+                // changes that can not be committed are rolled back, which settles them just as well
+                let _ = wal.settle();
+            }
+        };
+        match operation {
+            Operation::CreateIndex { index, .. } | Operation::ReplaceSchema { index, .. } => {
+                settle(&wals, index);
+                wals.insert(index.clone(), create(index)?);
+            }
+            Operation::Import { index, .. } => {
+                settle(&wals, index);
+                let mut wal = create(index)?;
+                wal.append(operation)?;
+                wals.insert(index.clone(), wal);
+            }
+            Operation::DropIndex { index } => {
+                settle(&wals, index);
+                wals.remove(index);
+            }
+            Operation::Upsert { index, .. }
+            | Operation::Bulk { index, .. }
+            | Operation::Delete { index, .. }
+            | Operation::Transaction { index, .. } => {
+                if let Some(wal) = wals.get_mut(index) {
+                    return Ok(Some(wal.write(operation)?));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Publishes a change made to an index once it is committed to the log it was written to, or
+    /// right away if it was not written to one. The events describing changes to documents are
+    /// captured now, while the index is still locked.
+    fn publish_committed(
+        &self,
+        pending: Option<&Pending>,
+        operation: Operation,
+        events: impl FnOnce() -> Vec<ChangeEvent>,
+    ) {
+        let events = match (&self.feed, &operation) {
+            (
+                Some(_),
+                Operation::Upsert { .. }
+                | Operation::Bulk { .. }
+                | Operation::Delete { .. }
+                | Operation::Transaction { .. },
+            ) => events(),
+            _ => vec![],
+        };
+        let (feed, log) = (self.feed.clone(), self.log.clone());
+        match pending {
+            Some(pending) => {
+                pending.then(move || publish(feed.as_deref(), log.as_deref(), operation, events))
+            }
+            None => publish(feed.as_deref(), log.as_deref(), operation, events),
+        }
+    }
+
+    /// Waits for a change written to the write-ahead log of an index to be committed, once the
+    /// index is unlocked. The index is rolled back if it can not be.
+    fn commit(&self, index: &str, pending: Option<Pending>) -> Result<(), HandlerError> {
+        let Some(pending) = pending else {
+            return Ok(());
+        };
+        let committed = pending.commit();
+        if committed.is_err() {
+            self.with_index(index, |writer| self.roll_back(index, writer))?;
+        }
+        Ok(committed?)
+    }
+
+    /// Rolls an index back to the changes in its write-ahead log, undoing the changes that could
+    /// not be logged or committed. The index must be locked, so every change in the log was made
+    /// to it.
+    fn roll_back(&self, index: &str, writer: &mut IndexWriter) -> Result<(), HandlerError> {
+        // changes rolled back from the log may still be in the file until the next is written
+        let operations = match self.wals.lock().expect("wals poisoned").get(index) {
+            Some(wal) => wal.read_written()?,
+            None => return Ok(()),
+        };
+        let (rolled_back, _) = replay_log(writer.schema().clone(), operations);
+        *writer = rolled_back;
+        Ok(())
+    }
+
+    /// Gets a searcher over the documents of an index as they are now, which keeps searching
+    /// them while the index is written to
+    pub fn searcher(&self, name: &str) -> Result<Searcher, HandlerError> {
+        self.with_index(name, |writer| Ok(writer.searcher()))
+    }
+
+    /// Reads an index through a searcher, such as to run queries the other handlers do not. The
+    /// index is only locked while the searcher is acquired.
+    pub(crate) fn inspect<R, E: From<HandlerError>>(
+        &self,
+        name: &str,
+        func: impl FnOnce(&Searcher) -> Result<R, E>,
+    ) -> Result<R, E> {
+        let searcher = self.searcher(name)?;
+        func(&searcher)
+    }
+
+    fn with_index<R>(
+        &self,
+        name: &str,
+        func: impl FnOnce(&mut IndexWriter) -> Result<R, HandlerError>,
+    ) -> Result<R, HandlerError> {
+        let writer = self.writer(name)?;
+        let mut writer = writer.lock().expect("index poisoned");
+        func(&mut writer)
+    }
+
+    fn writer(&self, name: &str) -> Result<Arc<Mutex<IndexWriter>>, HandlerError> {
+        self.indexes
+            .read()
+            .expect("indexes poisoned")
+            .get(name)
+            .cloned()
+            .ok_or_else(|| HandlerError::NoSuchIndex(name.to_string()))
+    }
+}
+
+/// The indexes a sql statement is run against, on behalf of a user
+struct SqlCatalog<'a> {
+    indexes: &'a Indexes,
+    cancel: &'a CancelToken,
+    authorize: &'a dyn Fn(&Resource) -> Result<(), HandlerError>,
+    access: &'a dyn Fn(&str) -> FieldAccess,
+}
+
+impl Catalog for SqlCatalog<'_> {
+    type Error = HandlerError;
+
+    fn schema(&self, index: &str) -> Result<Schema, HandlerError> {
+        (self.authorize)(&Resource::Index(index.to_string()))?;
+        let access = (self.access)(index);
+        let schema = self.indexes.mapping(index)?;
+        // fields that may not be read are left out, as if they did not exist
+        Ok(schema
+            .iter()
+            .filter(|field| access.allows(&field.name))
+            .cloned()
+            .collect())
+    }
+
+    fn documents(
+        &self,
+        index: &str,
+        query: &Query,
+        limit: Option<usize>,
+    ) -> Result<Vec<Document>, HandlerError> {
+        for field in query.fields() {
+            (self.authorize)(&Resource::Field {
+                index: index.to_string(),
+                field: field.to_string(),
+            })?;
+        }
+        let access = (self.access)(index);
+        self.indexes.inspect(index, |searcher| {
+            let mut documents = vec![];
+            let rows = query.rows(searcher, self.cancel)?;
+            for row in rows.into_iter().take(limit.unwrap_or(usize::MAX)) {
+                if self.cancel.is_cancelled() {
+                    return Err(Cancelled.into());
+                }
+                let mut document = searcher.read(row).expect("matched rows exist")?;
+                access.strip(&mut document);
+                documents.push(document);
+            }
+            Ok(documents)
+        })
+    }
+}
+
+/// Applies a change read from the write-ahead log of an index to its writer, returning the
+/// documents it changed
+fn replay_change(
+    writer: &mut IndexWriter,
+    operation: Operation,
+) -> Result<Vec<Changed>, HandlerError> {
+    Ok(match operation {
+        Operation::Import { schema, rows, .. } => {
+            let mut stored = PersistentVec::in_memory();
+            stored.extend_from_slice(&rows);
+            *writer = IndexWriter::new(schema, stored);
+            vec![]
+        }
+        Operation::Upsert {
+            document, partial, ..
+        } => Changed::upserted(writer.upsert(document, upsert_mode(partial))?)
+            .into_iter()
+            .collect(),
+        Operation::Bulk { documents, .. } => writer
+            .add_documents(documents)
+            .into_iter()
+            .filter_map(|result| result.ok().flatten())
+            .map(Changed::Added)
+            .collect(),
+        Operation::Delete { key, .. } => match writer.delete(&key)? {
+            Some(_) => vec![Changed::Deleted(key)],
+            None => vec![],
+        },
+        Operation::Transaction { transaction, .. } => {
+            let keys = deleted_keys(&transaction);
+            transaction_changes(&writer.commit(transaction)?, keys)
+        }
+        // changes to the indexes themselves start a new log instead
+        Operation::CreateIndex { .. }
+        | Operation::ReplaceSchema { .. }
+        | Operation::DropIndex { .. } => vec![],
+    })
+}
+
+/// Gets the key of every deletion of a transaction, in the order of its writes
+fn deleted_keys(transaction: &Transaction) -> Vec<Option<FieldData>> {
+    transaction
+        .writes()
+        .iter()
+        .map(|write| match write {
+            Write::Delete { key } => Some(key.clone()),
+            Write::Upsert { .. } => None,
+        })
+        .collect()
+}
+
+/// Gets the documents changed by a committed transaction, given the key of every deletion in it.
+/// Documents written and then deleted by the same transaction are only reported as deleted.
+fn transaction_changes(written: &[Written], keys: Vec<Option<FieldData>>) -> Vec<Changed> {
+    let mut changed = vec![];
+    for (written, key) in written.iter().zip(keys) {
+        match (written, key) {
+            (Written::Upserted(upserted), _) => changed.extend(Changed::upserted(*upserted)),
+            (Written::Deleted(Some(row)), Some(key)) => {
+                changed.retain(|change| match change {
+                    Changed::Added(other) | Changed::Updated(other) => other != row,
+                    Changed::Deleted(_) => true,
+                });
+                changed.push(Changed::Deleted(key));
+            }
+            (Written::Deleted(_), _) => {}
+        }
+    }
+    changed
+}
+
+/// Publishes a change once it is committed, along with the events describing it, to the change
+/// feed and replication log, if there are any
+fn publish(
+    feed: Option<&ChangeFeed>,
+    log: Option<&ReplicationLog>,
+    operation: Operation,
+    events: Vec<ChangeEvent>,
+) {
+    if let Some(feed) = feed {
+        match &operation {
+            Operation::CreateIndex { index, .. } | Operation::ReplaceSchema { index, .. } => {
+                feed.restart(index, 0)
+            }
+            // the imported rows are the first change of the new log
+            Operation::Import { index, .. } => feed.restart(index, 1),
+            Operation::DropIndex { index } => feed.remove(index),
+            Operation::Upsert { index, .. }
+            | Operation::Bulk { index, .. }
+            | Operation::Delete { index, .. }
+            | Operation::Transaction { index, .. } => {
+                feed.publish(index, events);
+            }
+        }
+    }
+    if let Some(log) = log {
+        log.publish(operation);
+    }
+}
+
+/// Replays the changes read from the write-ahead log of an index into a new writer, returning it
+/// along with the number of changes that could not be applied again
+fn replay_log(schema: Schema, operations: Vec<Operation>) -> (IndexWriter, usize) {
+    let mut writer = IndexWriter::new(schema, PersistentVec::in_memory());
+    let failed = operations
+        .into_iter()
+        .filter(|operation| replay_change(&mut writer, operation.clone()).is_err())
+        .count();
+    (writer, failed)
+}
+
+/// Parses a primary key given as text according to a schema
+pub fn parse_key(schema: &Schema, key: &str) -> Result<FieldData, HandlerError> {
+    let primary_key = schema.primary_key().ok_or(IndexWriterError::NoPrimaryKey)?;
+    let invalid = || HandlerError::InvalidKey(key.to_string());
+    Ok(match primary_key.kind {
+        FieldKind::Keyword(_) | FieldKind::Text(_) => FieldData::Bytes(key.as_bytes().into()),
+        FieldKind::I64 => FieldData::I64(key.parse().map_err(|_| invalid())?),
+        FieldKind::U64 => FieldData::U64(key.parse().map_err(|_| invalid())?),
+        FieldKind::F64 | FieldKind::Number(_) => {
+            FieldData::F64(key.parse().map_err(|_| invalid())?)
+        }
+        FieldKind::Blob => return Err(invalid()),
+    })
+}
+
+/// An error occurred handling a request
+#[derive(Debug, Error)]
+pub enum HandlerError {
+    #[error("No index named {0:?}")]
+    NoSuchIndex(String),
+    #[error("An index named {0:?} already exists")]
+    IndexExists(String),
+    #[error("The schema of {0:?} can not change while it holds documents")]
+    IndexNotEmpty(String),
+    #[error("{0:?} is not a valid primary key")]
+    InvalidKey(String),
+    #[error("{index:?} is no longer readable at sequence number {sequence}")]
+    Unpinned { index: String, sequence: u64 },
+    #[error(transparent)]
+    IndexWriterError(#[from] IndexWriterError),
+    #[error(transparent)]
+    RowDecodeError(#[from] RowDecodeError),
+    #[error(transparent)]
+    Cancelled(#[from] Cancelled),
+    #[error(transparent)]
+    Unauthorized(#[from] AuthorizationError),
+    #[error("{0}")]
+    Unauthenticated(String),
+    #[error("The password of {0:?} must be changed before making any other request")]
+    PasswordExpired(String),
+    #[error(transparent)]
+    AuthenticationError(#[from] AuthenticationError),
+    #[error(transparent)]
+    GroupError(#[from] GroupError),
+    #[error(transparent)]
+    InvalidIndexName(#[from] InvalidIndexName),
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+    #[error(transparent)]
+    SnapshotError(#[from] SnapshotError),
+    #[error(transparent)]
+    ChangesError(#[from] ChangesError),
+    #[error(transparent)]
+    ExecutorError(#[from] ExecutorError),
+    #[error(transparent)]
+    AuditError(#[from] AuditError),
+    #[error(transparent)]
+    WalError(#[from] WalError),
+    #[error(transparent)]
+    Sql(#[from] SqlError),
+    #[error(transparent)]
+    Query(#[from] QueryError),
+    #[error(transparent)]
+    QueryString(#[from] QueryStringError),
+    #[error("This daemon is a read-only replica, changes must be made on its primary")]
+    ReadOnly,
+    #[error("This daemon is not a replica")]
+    NotAReplica,
+    #[error("This daemon is not part of a cluster")]
+    NotClustered,
+    #[error("This daemon does not audit changes")]
+    NotAudited,
+    #[error("This daemon does not authenticate clients")]
+    NotAuthenticating,
+}
+
+impl From<HandlerError> for DocatlasError {
+    fn from(value: HandlerError) -> Self {
+        let message = value.to_string();
+        match value {
+            HandlerError::NoSuchIndex(_)
+            | HandlerError::Unpinned { .. }
+            | HandlerError::SnapshotError(SnapshotError::NoSuchSnapshot(_)) => {
+                DocatlasError::NotFound(message)
+            }
+            HandlerError::IndexExists(_)
+            | HandlerError::SnapshotError(SnapshotError::SnapshotExists(_)) => {
+                DocatlasError::AlreadyExists(message)
+            }
+            HandlerError::IndexNotEmpty(_)
+            | HandlerError::ChangesError(
+                ChangesError::NotCaptured { .. }
+                | ChangesError::Unavailable { .. }
+                | ChangesError::Restarted(_),
+            )
+            | HandlerError::NotAReplica
+            | HandlerError::NotClustered
+            | HandlerError::NotAudited
+            | HandlerError::NotAuthenticating => DocatlasError::Conflict(message),
+            HandlerError::ReadOnly | HandlerError::PasswordExpired(_) => {
+                DocatlasError::PermissionDenied(message)
+            }
+            HandlerError::Unauthenticated(_) => DocatlasError::Unauthenticated(message),
+            HandlerError::IndexWriterError(e) => e.into(),
+            HandlerError::RowDecodeError(e) => e.into(),
+            HandlerError::Cancelled(e) => e.into(),
+            HandlerError::Unauthorized(e) => e.into(),
+            HandlerError::AuthenticationError(e) => e.into(),
+            HandlerError::GroupError(e) => e.into(),
+            HandlerError::IoError(e) => e.into(),
+            HandlerError::Query(e) | HandlerError::Sql(SqlError::Query(e)) => e.into(),
+            HandlerError::Sql(_) | HandlerError::QueryString(_) => {
+                DocatlasError::InvalidRequest(message)
+            }
+            HandlerError::ExecutorError(_)
+            | HandlerError::AuditError(_)
+            | HandlerError::WalError(_)
+            | HandlerError::SnapshotError(
+                SnapshotError::CorruptSegment(_) | SnapshotError::MissingSegment(_),
+            ) => DocatlasError::Internal(message),
+            HandlerError::InvalidKey(_)
+            | HandlerError::InvalidIndexName(_)
+            | HandlerError::SnapshotError(SnapshotError::InvalidName(_))
+            | HandlerError::ChangesError(ChangesError::Ahead { .. }) => {
+                DocatlasError::InvalidRequest(message)
+            }
+        }
+    }
+}
@@ -0,0 +1,107 @@
+//! Liveness and readiness of the daemon, for orchestrators and load balancers.
+//!
+//! The daemon is live for as long as it can answer at all. It is only ready once its indexes are
+//! recovered and its data directory is writable, and stops being ready as soon as it starts
+//! shutting down, so no new clients are routed to it.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+use serde::{Deserialize, Serialize};
+
+/// The file written to check that the data directory is writable
+const PROBE_FILE: &str = ".ready-probe";
+
+/// Tracks whether the daemon is ready to serve requests
+#[derive(Debug)]
+pub struct Health {
+    path: PathBuf,
+    started: Instant,
+    recovered: AtomicBool,
+    shutting_down: AtomicBool,
+}
+
+/// The liveness of the daemon
+#[derive(Debug, Clone, Serialize)]
+pub struct Liveness {
+    pub uptime_secs: u64,
+}
+
+/// The readiness of the daemon, along with every check it is made of
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Readiness {
+    pub ready: bool,
+    /// Every index was recovered from disk
+    pub recovered: bool,
+    /// The data directory can be written to
+    pub disk_writable: bool,
+    /// The daemon is not shutting down
+    pub accepting: bool,
+}
+
+impl Health {
+    /// Creates the health of a daemon storing its data in the given directory, which is not ready
+    /// until its indexes are recovered
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            started: Instant::now(),
+            recovered: AtomicBool::new(false),
+            shutting_down: AtomicBool::new(false),
+        }
+    }
+
+    /// Marks every index as recovered
+    pub fn mark_recovered(&self) {
+        self.recovered.store(true, Ordering::Release);
+    }
+
+    /// Marks the daemon as shutting down
+    pub fn mark_shutting_down(&self) {
+        self.shutting_down.store(true, Ordering::Release);
+    }
+
+    /// Gets the liveness of the daemon
+    pub fn liveness(&self) -> Liveness {
+        Liveness {
+            uptime_secs: self.started.elapsed().as_secs(),
+        }
+    }
+
+    /// Checks the readiness of the daemon, writing a small file to the data directory
+    pub fn readiness(&self) -> Readiness {
+        let recovered = self.recovered.load(Ordering::Acquire);
+        let accepting = !self.shutting_down.load(Ordering::Acquire);
+        let probe = self.path.join(PROBE_FILE);
+        let disk_writable = fs::write(&probe, b"ok").and_then(|()| fs::remove_file(&probe));
+        Readiness {
+            ready: recovered && accepting && disk_writable.is_ok(),
+            recovered,
+            disk_writable: disk_writable.is_ok(),
+            accepting,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ready_between_recovery_and_shutdown() {
+        let dir = tempfile::tempdir().unwrap();
+        let health = Health::new(dir.path());
+        assert!(!health.readiness().ready);
+        health.mark_recovered();
+        let readiness = health.readiness();
+        assert!(readiness.ready && readiness.disk_writable);
+        health.mark_shutting_down();
+        assert!(!health.readiness().ready);
+
+        let missing = Health::new(dir.path().join("missing"));
+        missing.mark_recovered();
+        assert!(!missing.readiness().disk_writable);
+    }
+}
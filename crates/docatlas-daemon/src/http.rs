@@ -0,0 +1,1186 @@
+//! The json http api of the daemon.
+//!
+//! | method   | path                               | action                         |
+//! |----------|------------------------------------|--------------------------------|
+//! | `GET`    | `/indexes`                         | lists every index              |
+//! | `PUT`    | `/indexes/:index`                  | creates an index from a schema |
+//! | `GET`    | `/indexes/:index`                  | describes an index             |
+//! | `DELETE` | `/indexes/:index`                  | drops an index                 |
+//! | `PUT`    | `/indexes/:index/documents`        | upserts a document             |
+//! | `POST`   | `/indexes/:index/_bulk`            | adds documents in bulk         |
+//! | `POST`   | `/indexes/:index/_transaction`     | commits writes all at once     |
+//! | `GET`    | `/indexes/:index/documents/:key`   | gets a document by primary key |
+//! | `DELETE` | `/indexes/:index/documents/:key`   | deletes a document             |
+//! | `GET`    | `/indexes/:index/_search`          | searches a field, or queries   |
+//! | `POST`   | `/sql`                             | runs a sql `SELECT` statement  |
+//! | `GET`    | `/stats/paths`                     | gets the usage of data paths   |
+//! | `GET`    | `/stats/merges`                    | gets the progress of merges    |
+//! | `GET`    | `/metrics`                         | renders the daemon's metrics   |
+//! | `GET`    | `/health/live`                     | checks the daemon is up        |
+//! | `GET`    | `/health/ready`                    | checks the daemon can serve    |
+//! | `GET`    | `/cluster/health`                  | gets the status of the cluster |
+//!
+//! A subset of the elasticsearch api is served under `/es`, see [`elastic`].
+//!
+//! Searches given a `field` find the documents whose field holds every term of `q`. Otherwise `q`
+//! is a [query string](docatlas_core::query::string), whose terms without a field search the
+//! field given by `df`, joined by the `default_operator`, which is `OR` unless given.
+//!
+//! Every route but `/metrics` and `/health` is only served to clients that authenticate, if the
+//! daemon requires them to, by sending credentials in the `authorization` header as described in
+//! [`access`](crate::access). Clients that do not are responded to with `401 Unauthorized`, and
+//! clients lacking the permission a request needs with `403 Forbidden`, like connections are.
+//! Documents are returned without the fields the client may not read. Requests that write are
+//! recorded in the audit log, if there is one.
+//!
+//! Requests passing a `traceparent` header are handled within the trace it names. Searches may
+//! pass a `timeout_ms` parameter, capped by the daemon's maximum, and respond with
+//! `408 Request Timeout` once it passes.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::extract::{ConnectInfo, Path, Query, State};
+use axum::http::{header, Request, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{delete, get, post, put};
+use axum::{Extension, Json, Router};
+use docatlas_core::auth::authorization::{Permission, Resource};
+use docatlas_core::cancel::CancelToken;
+use docatlas_core::document::Document;
+use docatlas_core::error::{DocatlasError, ErrorCategory};
+use docatlas_core::export::json_value;
+use docatlas_core::index::{Transaction, Written};
+use docatlas_core::query::string::QueryString;
+use docatlas_core::schema::Schema;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::Instrument;
+
+use crate::access::{Access, Authenticated};
+use crate::audit::AuditAction;
+use crate::client::ClientResponse;
+use crate::cluster::{Cluster, ClusterHealth};
+use crate::executor::Executor;
+use crate::handlers::{HandlerError, Hit, IndexInfo, Indexes};
+use crate::health::{Health, Liveness, Readiness};
+use crate::index_manager::PathUsage;
+use crate::merge::MergeStatus;
+use crate::metrics::DaemonMetrics;
+use crate::trace::TraceContext;
+
+pub mod elastic;
+
+/// The number of hits returned by a search without a limit
+const DEFAULT_SEARCH_LIMIT: usize = 10;
+
+/// Creates the router of the http api
+#[allow(clippy::too_many_arguments)]
+pub fn router(
+    indexes: Arc<Indexes>,
+    executor: Arc<Executor>,
+    max_request_timeout: Duration,
+    metrics: Arc<DaemonMetrics>,
+    health: Arc<Health>,
+    cluster: Arc<Cluster>,
+    access: Arc<Access>,
+) -> Router {
+    let metrics = Router::new()
+        .route("/metrics", get(render_metrics))
+        .with_state(metrics);
+    let health = Router::new()
+        .route("/health/live", get(live))
+        .route("/health/ready", get(ready))
+        .with_state(health);
+    let cluster = Router::new()
+        .route("/cluster/health", get(cluster_health))
+        .with_state(cluster);
+    // cpu heavy requests, and writes waiting for the log to be synced, run on the executor
+    let searches = Router::new()
+        .route("/indexes/:index/documents", put(upsert))
+        .route("/indexes/:index/documents/:key", delete(delete_document))
+        .route("/indexes/:index/_bulk", post(bulk))
+        .route("/indexes/:index/_transaction", post(transaction))
+        .route("/indexes/:index/_search", get(search))
+        .route("/sql", post(sql))
+        .with_state((indexes.clone(), executor.clone(), max_request_timeout));
+    let authenticated =
+        middleware::from_fn_with_state((access.clone(), executor.clone()), authenticate);
+    Router::new()
+        .route("/indexes", get(list_indexes))
+        .route("/stats/paths", get(path_stats))
+        .route("/stats/merges", get(merge_stats))
+        .route(
+            "/indexes/:index",
+            put(create_index).get(describe_index).delete(drop_index),
+        )
+        .route("/indexes/:index/documents/:key", get(get_document))
+        .with_state(indexes.clone())
+        .merge(searches)
+        .merge(cluster)
+        .route_layer(authenticated)
+        .merge(metrics)
+        .merge(health)
+        .nest(
+            "/es",
+            elastic::router(indexes, executor, max_request_timeout, access),
+        )
+        .layer(middleware::from_fn(trace_request))
+}
+
+/// Authenticates a request before it is handled, responding with `401 Unauthorized` if it can
+/// not be
+async fn authenticate<B>(
+    State((access, executor)): State<(Arc<Access>, Arc<Executor>)>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    match authenticate_request(&access, &executor, request).await {
+        Ok(request) => next.run(request).await,
+        Err(e) => e.into_response(),
+    }
+}
+
+/// Authenticates a request by its `authorization` header, adding who it was authenticated as to
+/// its extensions. Passwords are verified on the executor, as hashing them is slow.
+pub(crate) async fn authenticate_request<B>(
+    access: &Arc<Access>,
+    executor: &Executor,
+    mut request: Request<B>,
+) -> Result<Request<B>, HandlerError> {
+    // a header that is not utf-8 is malformed, rather than missing
+    let authorization = request
+        .headers()
+        .get(header::AUTHORIZATION)
+        .map(|value| value.to_str().unwrap_or_default().to_string());
+    let ip = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(address)| address.ip());
+    let access = access.clone();
+    let authenticated = executor
+        .run(move || access.authenticate(authorization.as_deref(), ip))
+        .await??;
+    request.extensions_mut().insert(authenticated);
+    Ok(request)
+}
+
+/// Handles a request within the span of the trace it is part of
+async fn trace_request<B>(request: Request<B>, next: Next<B>) -> Response {
+    let span = TraceContext::from_headers(request.headers()).span();
+    span.record("method", request.method().as_str());
+    span.record("path", request.uri().path());
+    next.run(request).instrument(span).await
+}
+
+impl IntoResponse for HandlerError {
+    fn into_response(self) -> Response {
+        let error = DocatlasError::from(self);
+        let body = json!({
+            "error": error.message(),
+            "code": error.code(),
+            "category": error.category(),
+        });
+        (status(&error), Json(body)).into_response()
+    }
+}
+
+/// Gets the status responded with when a request fails with an error
+fn status(error: &DocatlasError) -> StatusCode {
+    match error {
+        DocatlasError::NotFound(_) => StatusCode::NOT_FOUND,
+        DocatlasError::AlreadyExists(_) | DocatlasError::Conflict(_) => StatusCode::CONFLICT,
+        DocatlasError::Unauthenticated(_) => StatusCode::UNAUTHORIZED,
+        DocatlasError::PermissionDenied(_) => StatusCode::FORBIDDEN,
+        // only searches are cancelled, once their timeout passes
+        DocatlasError::Cancelled(_) => StatusCode::REQUEST_TIMEOUT,
+        DocatlasError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        DocatlasError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+        DocatlasError::Unknown { .. } => match error.category() {
+            ErrorCategory::Client => StatusCode::BAD_REQUEST,
+            ErrorCategory::Server => StatusCode::INTERNAL_SERVER_ERROR,
+        },
+    }
+}
+
+async fn path_stats(
+    State(indexes): State<Arc<Indexes>>,
+    Extension(mut authenticated): Extension<Authenticated>,
+) -> Result<Json<Vec<PathUsage>>, HandlerError> {
+    authenticated
+        .authorize_request(Permission::Read, &Resource::Cluster)
+        .await?;
+    Ok(Json(indexes.path_usage()))
+}
+
+async fn merge_stats(
+    State(indexes): State<Arc<Indexes>>,
+    Extension(mut authenticated): Extension<Authenticated>,
+) -> Result<Json<Vec<MergeStatus>>, HandlerError> {
+    authenticated
+        .authorize_request(Permission::Read, &Resource::Cluster)
+        .await?;
+    Ok(Json(indexes.merges()))
+}
+
+async fn live(State(health): State<Arc<Health>>) -> Json<Liveness> {
+    Json(health.liveness())
+}
+
+async fn ready(State(health): State<Arc<Health>>) -> (StatusCode, Json<Readiness>) {
+    let readiness = health.readiness();
+    let status = match readiness.ready {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, Json(readiness))
+}
+
+async fn cluster_health(
+    State(cluster): State<Arc<Cluster>>,
+    Extension(mut authenticated): Extension<Authenticated>,
+) -> Result<Json<ClusterHealth>, HandlerError> {
+    authenticated
+        .authorize_request(Permission::Read, &Resource::Cluster)
+        .await?;
+    Ok(Json(cluster.health()))
+}
+
+async fn render_metrics(State(metrics): State<Arc<DaemonMetrics>>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics.render(),
+    )
+}
+
+async fn list_indexes(
+    State(indexes): State<Arc<Indexes>>,
+    Extension(mut authenticated): Extension<Authenticated>,
+) -> Result<Json<Vec<String>>, HandlerError> {
+    authenticated
+        .authorize_request(Permission::Read, &Resource::Cluster)
+        .await?;
+    // only the indexes the user may read
+    let names = indexes
+        .names()
+        .into_iter()
+        .filter(|name| {
+            authenticated
+                .authorize(Permission::Read, &Resource::Index(name.clone()))
+                .is_ok()
+        })
+        .collect();
+    Ok(Json(names))
+}
+
+async fn create_index(
+    State(indexes): State<Arc<Indexes>>,
+    Extension(mut authenticated): Extension<Authenticated>,
+    Path(index): Path<String>,
+    Json(schema): Json<Schema>,
+) -> Result<StatusCode, HandlerError> {
+    let action = AuditAction::IndexCreated {
+        index: index.clone(),
+    };
+    let created = async {
+        authenticated
+            .authorize_request(Permission::Manage, &Resource::Index(index.clone()))
+            .await?;
+        indexes.create(&index, schema)
+    }
+    .await;
+    authenticated.audit(action, &created);
+    created?;
+    Ok(StatusCode::CREATED)
+}
+
+async fn describe_index(
+    State(indexes): State<Arc<Indexes>>,
+    Extension(mut authenticated): Extension<Authenticated>,
+    Path(index): Path<String>,
+) -> Result<Json<IndexInfo>, HandlerError> {
+    authenticated
+        .authorize_request(Permission::Read, &Resource::Index(index.clone()))
+        .await?;
+    indexes.info(&index).map(Json)
+}
+
+async fn drop_index(
+    State(indexes): State<Arc<Indexes>>,
+    Extension(mut authenticated): Extension<Authenticated>,
+    Path(index): Path<String>,
+) -> Result<StatusCode, HandlerError> {
+    let action = AuditAction::IndexDropped {
+        index: index.clone(),
+    };
+    let dropped = async {
+        authenticated
+            .authorize_request(Permission::Manage, &Resource::Index(index.clone()))
+            .await?;
+        indexes.drop_index(&index)
+    }
+    .await;
+    authenticated.audit(action, &dropped);
+    dropped?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Debug, Deserialize)]
+struct UpsertParams {
+    #[serde(default)]
+    partial: bool,
+}
+
+async fn upsert(
+    State((indexes, executor, _)): State<(Arc<Indexes>, Arc<Executor>, Duration)>,
+    Extension(mut authenticated): Extension<Authenticated>,
+    Path(index): Path<String>,
+    Query(params): Query<UpsertParams>,
+    Json(document): Json<Document>,
+) -> Result<Json<ClientResponse>, HandlerError> {
+    let action = AuditAction::DocumentsWritten {
+        index: index.clone(),
+        count: 1,
+    };
+    let upserted = async {
+        authenticated
+            .authorize_request(Permission::Write, &Resource::Index(index.clone()))
+            .await?;
+        // waiting for the change to be committed blocks
+        executor
+            .run(move || indexes.upsert(&index, document, params.partial))
+            .await?
+    }
+    .await;
+    authenticated.audit(action, &upserted);
+    Ok(Json(upserted?.into()))
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum BulkItem {
+    Added { row: usize },
+    Dropped,
+    Failed { error: String },
+}
+
+async fn bulk(
+    State((indexes, executor, _)): State<(Arc<Indexes>, Arc<Executor>, Duration)>,
+    Extension(mut authenticated): Extension<Authenticated>,
+    Path(index): Path<String>,
+    Json(documents): Json<Vec<Document>>,
+) -> Result<Json<Vec<BulkItem>>, HandlerError> {
+    let action = AuditAction::DocumentsWritten {
+        index: index.clone(),
+        count: documents.len(),
+    };
+    let added = async {
+        authenticated
+            .authorize_request(Permission::Write, &Resource::Index(index.clone()))
+            .await?;
+        executor
+            .run(move || indexes.bulk(&index, documents))
+            .await?
+    }
+    .await;
+    authenticated.audit(action, &added);
+    let items = added?
+        .into_iter()
+        .map(|result| match result {
+            Ok(Some(row)) => BulkItem::Added { row },
+            Ok(None) => BulkItem::Dropped,
+            Err(error) => BulkItem::Failed { error },
+        })
+        .collect();
+    Ok(Json(items))
+}
+
+async fn transaction(
+    State((indexes, executor, _)): State<(Arc<Indexes>, Arc<Executor>, Duration)>,
+    Extension(mut authenticated): Extension<Authenticated>,
+    Path(index): Path<String>,
+    Json(transaction): Json<Transaction>,
+) -> Result<Json<Vec<Written>>, HandlerError> {
+    let action = AuditAction::DocumentsWritten {
+        index: index.clone(),
+        count: transaction.len(),
+    };
+    let written = async {
+        authenticated
+            .authorize_request(Permission::Write, &Resource::Index(index.clone()))
+            .await?;
+        executor
+            .run(move || indexes.transaction(&index, transaction))
+            .await?
+    }
+    .await;
+    authenticated.audit(action, &written);
+    Ok(Json(written?))
+}
+
+async fn get_document(
+    State(indexes): State<Arc<Indexes>>,
+    Extension(mut authenticated): Extension<Authenticated>,
+    Path((index, key)): Path<(String, String)>,
+) -> Result<Response, HandlerError> {
+    authenticated
+        .authorize_request(Permission::Read, &Resource::Index(index.clone()))
+        .await?;
+    let key = indexes.parse_key(&index, &key)?;
+    Ok(match indexes.get(&index, &key)? {
+        Some(mut document) => {
+            authenticated.field_access(&index).strip(&mut document);
+            Json(document).into_response()
+        }
+        None => StatusCode::NOT_FOUND.into_response(),
+    })
+}
+
+async fn delete_document(
+    State((indexes, executor, _)): State<(Arc<Indexes>, Arc<Executor>, Duration)>,
+    Extension(mut authenticated): Extension<Authenticated>,
+    Path((index, key)): Path<(String, String)>,
+) -> Result<StatusCode, HandlerError> {
+    let action = AuditAction::DocumentDeleted {
+        index: index.clone(),
+    };
+    let deleted = async {
+        authenticated
+            .authorize_request(Permission::Write, &Resource::Index(index.clone()))
+            .await?;
+        let key = indexes.parse_key(&index, &key)?;
+        executor.run(move || indexes.delete(&index, &key)).await?
+    }
+    .await;
+    authenticated.audit(action, &deleted);
+    Ok(match deleted? {
+        Some(_) => StatusCode::NO_CONTENT,
+        None => StatusCode::NOT_FOUND,
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchParams {
+    field: Option<String>,
+    q: String,
+    df: Option<String>,
+    default_operator: Option<String>,
+    limit: Option<usize>,
+    timeout_ms: Option<u64>,
+}
+
+async fn search(
+    State((indexes, executor, max_timeout)): State<(Arc<Indexes>, Arc<Executor>, Duration)>,
+    Extension(mut authenticated): Extension<Authenticated>,
+    Path(index): Path<String>,
+    Query(params): Query<SearchParams>,
+) -> Result<Json<Vec<Hit>>, HandlerError> {
+    authenticated
+        .authorize_request(Permission::Read, &Resource::Index(index.clone()))
+        .await?;
+    let limit = params.limit.unwrap_or(DEFAULT_SEARCH_LIMIT);
+    let timeout = params
+        .timeout_ms
+        .map_or(max_timeout, |ms| Duration::from_millis(ms).min(max_timeout));
+    let cancel = CancelToken::new().with_deadline(Instant::now() + timeout);
+    let access = authenticated.field_access(&index);
+    let strip = |mut hits: Vec<Hit>| {
+        for hit in &mut hits {
+            access.strip(&mut hit.document);
+        }
+        Json(hits)
+    };
+    if let Some(field) = params.field {
+        // searching by a field would tell what it holds
+        let resource = Resource::Field {
+            index: index.clone(),
+            field: field.clone(),
+        };
+        authenticated.authorize(Permission::Read, &resource)?;
+        return executor
+            .run(move || indexes.search_until(&index, &field, &params.q, limit, &cancel))
+            .await?
+            .map(strip);
+    }
+    let mut parser = QueryString::new();
+    if let Some(operator) = &params.default_operator {
+        parser = parser.with_default_operator(operator.parse()?);
+    }
+    if let Some(field) = params.df {
+        parser = parser.with_default_field(field);
+    }
+    let query = parser.parse(&params.q)?;
+    authenticated.authorize_query(&index, &query)?;
+    executor
+        .run(move || indexes.query(&index, &query, limit, &cancel))
+        .await?
+        .map(strip)
+}
+
+/// A sql statement, like the body of elasticsearch's sql api
+#[derive(Debug, Deserialize)]
+struct SqlBody {
+    query: String,
+    timeout_ms: Option<u64>,
+}
+
+/// The rows selected by a sql statement, with their values written as json
+#[derive(Debug, Serialize)]
+struct SqlRows {
+    columns: Vec<String>,
+    rows: Vec<Vec<serde_json::Value>>,
+}
+
+async fn sql(
+    State((indexes, executor, max_timeout)): State<(Arc<Indexes>, Arc<Executor>, Duration)>,
+    Extension(mut authenticated): Extension<Authenticated>,
+    Json(body): Json<SqlBody>,
+) -> Result<Json<SqlRows>, HandlerError> {
+    let timeout = body
+        .timeout_ms
+        .map_or(max_timeout, |ms| Duration::from_millis(ms).min(max_timeout));
+    let cancel = CancelToken::new().with_deadline(Instant::now() + timeout);
+    // like sql requests made over connections, which name no index
+    authenticated
+        .ask_service(Permission::Read, &Resource::Cluster)
+        .await;
+    // statements are authorized for every index they read, as they are run
+    let table = executor
+        .run(move || {
+            indexes.sql(
+                &body.query,
+                &cancel,
+                &|resource| authenticated.authorize(Permission::Read, resource),
+                &|index| authenticated.field_access(index),
+            )
+        })
+        .await??;
+    Ok(Json(SqlRows {
+        columns: table.columns,
+        rows: table
+            .rows
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|value| value.as_ref().map_or(serde_json::Value::Null, json_value))
+                    .collect()
+            })
+            .collect(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::http::{Method, Request};
+    use base64::Engine;
+    use docatlas_core::auth::authentication::{
+        AuthenticationRequest, AuthenticationToolchain, SessionService, DEFAULT_USER,
+    };
+    use docatlas_core::auth::authorization::{Authorizer, Role};
+    use docatlas_core::auth::users::UserFactory;
+    use serde_json::Value;
+    use tower::ServiceExt;
+
+    use super::*;
+    use crate::audit::{AuditLog, AuditQuery};
+    use crate::config::DaemonConfig;
+    use crate::webhook::WebhookAuthorizationService;
+
+    /// The password the admin is given in place of the default one
+    const ADMIN_PASSWORD: &str = "not-the-default";
+
+    /// Opens an authentication toolchain whose admin no longer has the default password
+    fn admin_auth(dir: &std::path::Path) -> AuthenticationToolchain {
+        let auth = AuthenticationToolchain::open(&dir.join("admin")).unwrap();
+        let current = AuthenticationRequest::new().with_basic(DEFAULT_USER, "admin");
+        auth.change_password(current, ADMIN_PASSWORD).unwrap();
+        auth
+    }
+
+    /// Gets the `authorization` header of basic credentials
+    fn basic(username: &str, password: &str) -> String {
+        let credentials = format!("{username}:{password}");
+        format!(
+            "Basic {}",
+            base64::engine::general_purpose::STANDARD.encode(credentials)
+        )
+    }
+
+    fn test_router(dir: &std::path::Path, access: Access) -> (Router, Arc<Health>) {
+        let health = Arc::new(Health::new(dir));
+        let indexes = Arc::new(Indexes::new());
+        let cluster = Cluster::new(&DaemonConfig::default(), indexes.clone(), None);
+        let router = router(
+            indexes,
+            Arc::new(Executor::new(1).unwrap()),
+            Duration::from_secs(60),
+            Arc::new(DaemonMetrics::new()),
+            health.clone(),
+            Arc::new(cluster),
+            Arc::new(access),
+        );
+        (router, health)
+    }
+
+    async fn send(router: &Router, method: Method, uri: &str, body: Value) -> (StatusCode, Value) {
+        send_as(router, None, method, uri, body).await
+    }
+
+    async fn send_as(
+        router: &Router,
+        authorization: Option<&str>,
+        method: Method,
+        uri: &str,
+        body: impl ToString,
+    ) -> (StatusCode, Value) {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json");
+        if let Some(authorization) = authorization {
+            request = request.header("authorization", authorization);
+        }
+        let request = request.body(Body::from(body.to_string())).unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = serde_json::from_slice(&bytes).unwrap_or(Value::Null);
+        (status, body)
+    }
+
+    #[tokio::test]
+    async fn index_and_document_crud() {
+        let dir = tempfile::tempdir().unwrap();
+        let access = Access::new(
+            Arc::new(SessionService::default()),
+            Arc::new(Authorizer::new()),
+        );
+        let (router, health) = test_router(dir.path(), access);
+        let (status, body) = send(&router, Method::GET, "/cluster/health", Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["status"], "green");
+        let (status, _) = send(&router, Method::GET, "/health/ready", Value::Null).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        health.mark_recovered();
+        let (status, body) = send(&router, Method::GET, "/health/ready", Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["disk_writable"], true);
+
+        let schema = json!({
+            "fields": [
+                { "name": "id", "kind": { "Keyword": 8 } },
+                { "name": "title", "kind": { "Text": 32 } },
+            ],
+            "primary_key": "id",
+        });
+        let (status, _) = send(&router, Method::PUT, "/indexes/books", schema.clone()).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, _) = send(&router, Method::PUT, "/indexes/books", schema).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+
+        let document = json!({
+            "id": { "kind": { "Keyword": 8 }, "data": [{ "Bytes": b"b1" }] },
+            "title": { "kind": { "Text": 32 }, "data": [{ "Bytes": b"Dune Messiah" }] },
+        });
+        let (status, body) = send(&router, Method::PUT, "/indexes/books/documents", document).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "Upserted": { "row": 0, "inserted": true } }));
+
+        let (status, body) = send(
+            &router,
+            Method::GET,
+            "/indexes/books/_search?field=title&q=dune",
+            Value::Null,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body[0]["row"], 0);
+        let (status, _) = send(
+            &router,
+            Method::GET,
+            "/indexes/books/_search?field=title&q=dune&timeout_ms=0",
+            Value::Null,
+        )
+        .await;
+        assert_eq!(status, StatusCode::REQUEST_TIMEOUT);
+        let (status, body) = send(
+            &router,
+            Method::GET,
+            "/indexes/books/_search?q=dune%20AND%20NOT%20id:b2&df=title&default_operator=and",
+            Value::Null,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body[0]["row"], 0);
+        let (status, _) = send(
+            &router,
+            Method::GET,
+            "/indexes/books/_search?q=dune",
+            Value::Null,
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let statement =
+            json!({ "query": "SELECT id, title FROM books WHERE MATCH(title, 'dune')" });
+        let (status, body) = send(&router, Method::POST, "/sql", statement).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            json!({ "columns": ["id", "title"], "rows": [["b1", "Dune Messiah"]] })
+        );
+        let (status, _) = send(&router, Method::POST, "/sql", json!({ "query": "SELECT" })).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, _) = send(
+            &router,
+            Method::GET,
+            "/indexes/books/documents/b1",
+            Value::Null,
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = send(
+            &router,
+            Method::DELETE,
+            "/indexes/books/documents/b1",
+            Value::Null,
+        )
+        .await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+        let (status, _) = send(
+            &router,
+            Method::GET,
+            "/indexes/books/documents/b1",
+            Value::Null,
+        )
+        .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        let (status, body) = send(&router, Method::GET, "/indexes/films", Value::Null).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"], "No index named \"films\"");
+
+        // the elasticsearch api shares the indexes
+        let (status, body) = send(&router, Method::GET, "/es/books/_search", Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["hits"]["total"]["value"], 0);
+    }
+
+    #[tokio::test]
+    async fn refuse_unauthenticated_requests() {
+        let dir = tempfile::tempdir().unwrap();
+        let auth = AuthenticationToolchain::open(&dir.path().join("admin")).unwrap();
+        let access = Access::new(
+            Arc::new(SessionService::default()),
+            Arc::new(Authorizer::new()),
+        )
+        .with_auth(Arc::new(auth));
+        let (router, health) = test_router(dir.path(), access);
+        health.mark_recovered();
+        let search = "/indexes/books/_search?field=title&q=dune";
+
+        let (status, body) = send(&router, Method::GET, search, Value::Null).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            body["error"],
+            "send credentials in the authorization header"
+        );
+        // "admin:guess"
+        let wrong = Some("Basic YWRtaW46Z3Vlc3M=");
+        let (status, body) = send_as(&router, wrong, Method::GET, search, Value::Null).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["error"], "invalid credentials");
+        let (status, _) = send_as(&router, Some("Digest"), Method::GET, search, Value::Null).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let (status, body) = send(&router, Method::GET, "/es/books/_search", Value::Null).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        assert_eq!(body["error"]["type"], "security_exception");
+
+        // "admin:admin", the default credentials of the admin, until the password is changed
+        let default = Some("Basic YWRtaW46YWRtaW4=");
+        let (status, body) = send_as(&router, default, Method::GET, search, Value::Null).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(
+            body["error"],
+            "The password of \"admin\" must be changed before making any other request"
+        );
+        let (status, _) = send_as(&router, default, Method::GET, "/es/books/_search", "").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let auth = admin_auth(dir.path());
+        let access = Access::new(
+            Arc::new(SessionService::default()),
+            Arc::new(Authorizer::new()),
+        )
+        .with_auth(Arc::new(auth));
+        let (router, health) = test_router(dir.path(), access);
+        health.mark_recovered();
+        let (status, _) = send_as(&router, default, Method::GET, search, Value::Null).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let admin = basic(DEFAULT_USER, ADMIN_PASSWORD);
+        let admin = Some(admin.as_str());
+        let (status, _) = send_as(&router, admin, Method::GET, search, Value::Null).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        let (status, _) = send(&router, Method::GET, "/health/ready", Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn authorize_requests() {
+        let dir = tempfile::tempdir().unwrap();
+        let auth = admin_auth(dir.path());
+        let sessions = Arc::new(SessionService::default());
+        let authorizer = Authorizer::new()
+            .with_role(
+                Role::new("librarian")
+                    .with_cluster(Permission::Read)
+                    .with_indexes("books", Permission::Write),
+            )
+            .with_user_roles("librarian", vec!["librarian".to_string()]);
+        let access = Access::new(sessions.clone(), Arc::new(authorizer)).with_auth(Arc::new(auth));
+        let (router, _) = test_router(dir.path(), access);
+        let admin = basic(DEFAULT_USER, ADMIN_PASSWORD);
+        let admin = Some(admin.as_str());
+        let token = sessions.issue(&UserFactory.create("librarian")).token;
+        let librarian = Some(format!("Bearer {token}"));
+        let librarian = librarian.as_deref();
+
+        let schema = json!({
+            "fields": [
+                { "name": "id", "kind": { "Keyword": 8 } },
+                { "name": "title", "kind": { "Text": 32 } },
+            ],
+            "primary_key": "id",
+        });
+        for index in ["/indexes/books", "/indexes/films"] {
+            let (status, _) = send_as(&router, admin, Method::PUT, index, &schema).await;
+            assert_eq!(status, StatusCode::CREATED);
+        }
+        let (status, _) = send_as(&router, librarian, Method::PUT, "/indexes/maps", &schema).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, body) = send_as(&router, librarian, Method::GET, "/indexes", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!(["books"]));
+
+        let document = json!({
+            "id": { "kind": { "Keyword": 8 }, "data": [{ "Bytes": b"b1" }] },
+            "title": { "kind": { "Text": 32 }, "data": [{ "Bytes": b"Dune Messiah" }] },
+        });
+        let books = "/indexes/books/documents";
+        let (status, _) = send_as(&router, librarian, Method::PUT, books, &document).await;
+        assert_eq!(status, StatusCode::OK);
+        let films = "/indexes/films/documents";
+        let (status, _) = send_as(&router, librarian, Method::PUT, films, &document).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let search = "/indexes/films/_search?field=title&q=dune";
+        let (status, _) = send_as(&router, librarian, Method::GET, search, "").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        // the elasticsearch api checks the same permissions
+        let source = json!({ "title": "Children of Dune" });
+        let (status, _) = send_as(
+            &router,
+            librarian,
+            Method::PUT,
+            "/es/books/_doc/b2",
+            &source,
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, body) = send_as(
+            &router,
+            librarian,
+            Method::PUT,
+            "/es/films/_doc/f1",
+            &source,
+        )
+        .await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["error"]["type"], "security_exception");
+        // writing into a missing index would create it
+        let (status, _) = send_as(&router, librarian, Method::POST, "/es/maps/_doc", &source).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let bulk = [
+            json!({ "index": { "_index": "books", "_id": "b3" } }),
+            source.clone(),
+            json!({ "index": { "_index": "films", "_id": "f1" } }),
+            source,
+        ]
+        .map(|line| line.to_string() + "\n")
+        .concat();
+        let (status, body) = send_as(&router, librarian, Method::POST, "/es/_bulk", bulk).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["errors"], true);
+        assert_eq!(body["items"][0]["index"]["status"], 201);
+        assert_eq!(body["items"][1]["index"]["status"], 403);
+    }
+
+    #[tokio::test]
+    async fn anonymous_access() {
+        let dir = tempfile::tempdir().unwrap();
+        let auth = AuthenticationToolchain::open(&dir.path().join("admin")).unwrap();
+        let sessions = Arc::new(SessionService::default());
+        let authorizer = Authorizer::new()
+            .with_role(Role::new("curator").with_indexes("*", Permission::Manage))
+            .with_role(Role::new("public").with_indexes("books", Permission::Read))
+            .with_user_roles("curator", vec!["curator".to_string()]);
+        let access = Access::new(sessions.clone(), Arc::new(authorizer))
+            .with_auth(Arc::new(auth))
+            .with_anonymous(Arc::new(UserFactory.anonymous(["public"])));
+        let (router, _) = test_router(dir.path(), access);
+        let token = sessions.issue(&UserFactory.create("curator")).token;
+        let curator = Some(format!("Bearer {token}"));
+        let curator = curator.as_deref();
+
+        let schema = json!({
+            "fields": [
+                { "name": "id", "kind": { "Keyword": 8 } },
+                { "name": "title", "kind": { "Text": 32 } },
+            ],
+            "primary_key": "id",
+        });
+        for index in ["/indexes/books", "/indexes/films"] {
+            let (status, _) = send_as(&router, curator, Method::PUT, index, &schema).await;
+            assert_eq!(status, StatusCode::CREATED);
+        }
+
+        // clients sending no credentials only get the roles given to anonymous clients
+        let search = "/indexes/books/_search?field=title&q=dune";
+        let (status, _) = send(&router, Method::GET, search, Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        let search = "/indexes/films/_search?field=title&q=dune";
+        let (status, _) = send(&router, Method::GET, search, Value::Null).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let document = json!({
+            "id": { "kind": { "Keyword": 8 }, "data": [{ "Bytes": b"b1" }] },
+            "title": { "kind": { "Text": 32 }, "data": [{ "Bytes": b"Dune Messiah" }] },
+        });
+        let books = "/indexes/books/documents";
+        let (status, _) = send(&router, Method::PUT, books, document.clone()).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let (status, _) = send_as(&router, curator, Method::PUT, books, &document).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // wrong credentials are refused rather than treated as anonymous
+        let wrong = Some("Basic YWRtaW46d3Jvbmc=");
+        let (status, _) = send_as(&router, wrong, Method::GET, search, Value::Null).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn ask_authorization_service() {
+        // allows everything but writing into films
+        let policy = Router::new().route(
+            "/allow",
+            post(|Json(body): Json<serde_json::Value>| async move {
+                let input = &body["input"];
+                let allow = input["index"] != "films" || input["permission"] == "read";
+                Json(json!({ "result": allow }))
+            }),
+        );
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(policy.into_make_service());
+        tokio::spawn(server);
+
+        let dir = tempfile::tempdir().unwrap();
+        let auth = AuthenticationToolchain::open(&dir.path().join("admin")).unwrap();
+        let sessions = Arc::new(SessionService::default());
+        let authorizer = Authorizer::new()
+            .with_role(Role::new("curator").with_indexes("*", Permission::Manage))
+            .with_user_roles("curator", vec!["curator".to_string()]);
+        let service = WebhookAuthorizationService::new(&format!("http://{address}/allow")).unwrap();
+        let access = Access::new(sessions.clone(), Arc::new(authorizer))
+            .with_auth(Arc::new(auth))
+            .with_authorization_service(Arc::new(service));
+        let (router, _) = test_router(dir.path(), access);
+        let token = sessions.issue(&UserFactory.create("curator")).token;
+        let curator = Some(format!("Bearer {token}"));
+        let curator = curator.as_deref();
+
+        let schema = json!({
+            "fields": [
+                { "name": "id", "kind": { "Keyword": 8 } },
+                { "name": "title", "kind": { "Text": 32 } },
+            ],
+            "primary_key": "id",
+        });
+        let (status, _) = send_as(&router, curator, Method::PUT, "/indexes/books", &schema).await;
+        assert_eq!(status, StatusCode::CREATED);
+        // the service decides whatever the roles of the user
+        let (status, _) = send_as(&router, curator, Method::PUT, "/indexes/films", &schema).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let document = json!({
+            "id": { "kind": { "Keyword": 8 }, "data": [{ "Bytes": b"b1" }] },
+            "title": { "kind": { "Text": 32 }, "data": [{ "Bytes": b"Dune Messiah" }] },
+        });
+        let books = "/indexes/books/documents";
+        let (status, _) = send_as(&router, curator, Method::PUT, books, &document).await;
+        assert_eq!(status, StatusCode::OK);
+
+        // and for the elasticsearch api as well
+        let source = json!({ "title": "Children of Dune" });
+        let (status, _) =
+            send_as(&router, curator, Method::PUT, "/es/books/_doc/b2", &source).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (status, body) =
+            send_as(&router, curator, Method::PUT, "/es/films/_doc/f1", &source).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(body["error"]["type"], "security_exception");
+        let bulk = [json!({ "index": { "_id": "f1" } }), source]
+            .map(|line| line.to_string() + "\n")
+            .concat();
+        let (status, body) = send_as(&router, curator, Method::POST, "/es/films/_bulk", bulk).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["items"][0]["index"]["status"], 403);
+    }
+
+    #[tokio::test]
+    async fn audit_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let auth = AuthenticationToolchain::open(&dir.path().join("admin")).unwrap();
+        let sessions = Arc::new(SessionService::default());
+        let authorizer = Authorizer::new()
+            .with_role(Role::new("curator").with_indexes("*", Permission::Manage))
+            .with_role(Role::new("reader").with_indexes("*", Permission::Read))
+            .with_user_roles("curator", vec!["curator".to_string()])
+            .with_user_roles("reader", vec!["reader".to_string()]);
+        let audit = Arc::new(AuditLog::open(dir.path().join("audit.log")).unwrap());
+        let access = Access::new(sessions.clone(), Arc::new(authorizer))
+            .with_auth(Arc::new(auth))
+            .with_audit(audit.clone());
+        let (router, _) = test_router(dir.path(), access);
+        let token = sessions.issue(&UserFactory.create("curator")).token;
+        let curator = Some(format!("Bearer {token}"));
+        let curator = curator.as_deref();
+        let token = sessions.issue(&UserFactory.create("reader")).token;
+        let reader = Some(format!("Bearer {token}"));
+        let reader = reader.as_deref();
+
+        let schema = json!({
+            "fields": [
+                { "name": "id", "kind": { "Keyword": 8 } },
+                { "name": "title", "kind": { "Text": 32 } },
+            ],
+            "primary_key": "id",
+        });
+        let (status, _) = send_as(&router, curator, Method::PUT, "/indexes/books", &schema).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let document = json!({
+            "id": { "kind": { "Keyword": 8 }, "data": [{ "Bytes": b"b1" }] },
+            "title": { "kind": { "Text": 32 }, "data": [{ "Bytes": b"Dune Messiah" }] },
+        });
+        let books = "/indexes/books/documents";
+        let (status, _) = send_as(&router, curator, Method::PUT, books, &document).await;
+        assert_eq!(status, StatusCode::OK);
+        // reads are not audited, but writes that are refused are
+        let search = "/indexes/books/_search?field=title&q=dune";
+        let (status, _) = send_as(&router, reader, Method::GET, search, Value::Null).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = send_as(&router, reader, Method::PUT, books, &document).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let source = json!({ "title": "Children of Dune" });
+        let (status, _) =
+            send_as(&router, curator, Method::PUT, "/es/books/_doc/b2", &source).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let entries = audit.entries(&AuditQuery::default()).unwrap();
+        let entries = entries
+            .into_iter()
+            .map(|entry| (entry.user.unwrap(), entry.action, entry.error.is_some()))
+            .collect::<Vec<_>>();
+        let written = AuditAction::DocumentsWritten {
+            index: "books".to_string(),
+            count: 1,
+        };
+        assert_eq!(
+            entries,
+            [
+                (
+                    "curator".to_string(),
+                    AuditAction::IndexCreated {
+                        index: "books".to_string()
+                    },
+                    false
+                ),
+                ("curator".to_string(), written.clone(), false),
+                ("reader".to_string(), written.clone(), true),
+                ("curator".to_string(), written, false),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn strip_restricted_fields() {
+        let dir = tempfile::tempdir().unwrap();
+        let auth = admin_auth(dir.path());
+        let sessions = Arc::new(SessionService::default());
+        let authorizer = Authorizer::new()
+            .with_role(
+                Role::new("indexer")
+                    .with_cluster(Permission::Read)
+                    .with_index_fields("books", Permission::Read, ["id"]),
+            )
+            .with_user_roles("indexer", vec!["indexer".to_string()]);
+        let access = Access::new(sessions.clone(), Arc::new(authorizer)).with_auth(Arc::new(auth));
+        let (router, _) = test_router(dir.path(), access);
+        let admin = basic(DEFAULT_USER, ADMIN_PASSWORD);
+        let admin = Some(admin.as_str());
+        let token = sessions.issue(&UserFactory.create("indexer")).token;
+        let indexer = Some(format!("Bearer {token}"));
+        let indexer = indexer.as_deref();
+
+        let schema = json!({
+            "fields": [
+                { "name": "id", "kind": { "Keyword": 8 } },
+                { "name": "title", "kind": { "Text": 32 } },
+            ],
+            "primary_key": "id",
+        });
+        let (status, _) = send_as(&router, admin, Method::PUT, "/indexes/books", schema).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let document = json!({
+            "id": { "kind": { "Keyword": 8 }, "data": [{ "Bytes": b"b1" }] },
+            "title": { "kind": { "Text": 32 }, "data": [{ "Bytes": b"Dune Messiah" }] },
+        });
+        let books = "/indexes/books/documents";
+        let (status, _) = send_as(&router, admin, Method::PUT, books, document).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let get = "/indexes/books/documents/b1";
+        let (status, body) = send_as(&router, indexer, Method::GET, get, "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.get("id").is_some());
+        assert!(body.get("title").is_none());
+        let search = "/indexes/books/_search?field=id&q=b1";
+        let (status, body) = send_as(&router, indexer, Method::GET, search, "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body[0]["document"].get("id").is_some());
+        assert!(body[0]["document"].get("title").is_none());
+        let search = "/indexes/books/_search?field=title&q=dune";
+        let (status, _) = send_as(&router, indexer, Method::GET, search, "").await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let statement = json!({ "query": "SELECT * FROM books" });
+        let (status, body) = send_as(&router, indexer, Method::POST, "/sql", &statement).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "columns": ["id"], "rows": [["b1"]] }));
+        let (status, _) = send_as(&router, admin, Method::POST, "/sql", &statement).await;
+        assert_eq!(status, StatusCode::OK);
+        let statement = json!({ "query": "SELECT id FROM films" });
+        let (status, _) = send_as(&router, indexer, Method::POST, "/sql", statement).await;
+        assert_eq!(status, StatusCode::FORBIDDEN);
+
+        let (status, body) = send_as(&router, indexer, Method::GET, "/es/books/_doc/b1", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["_source"], json!({ "id": "b1" }));
+        let (status, body) = send_as(&router, indexer, Method::GET, "/es/books/_search", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["hits"]["hits"][0]["_source"], json!({ "id": "b1" }));
+    }
+}
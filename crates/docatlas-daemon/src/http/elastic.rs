@@ -0,0 +1,1346 @@
+//! A subset of the elasticsearch rest api, served under `/es`, so clients and dashboards written
+//! for elasticsearch can be pointed at docatlas by adding `/es` to the url of the daemon.
+//!
+//! | method         | path                   | action                                   |
+//! |----------------|------------------------|------------------------------------------|
+//! | `GET`          | `/es`                  | describes the daemon as a cluster        |
+//! | `PUT`          | `/es/:index`           | creates an index from its mappings       |
+//! | `POST`         | `/es/:index/_doc`      | adds a document, generating its id       |
+//! | `PUT`          | `/es/:index/_doc/:id`  | upserts a document by its id             |
+//! | `GET`          | `/es/:index/_doc/:id`  | gets a document by its id                |
+//! | `DELETE`       | `/es/:index/_doc/:id`  | deletes a document by its id             |
+//! | `POST`         | `/es/_bulk`            | runs index, create, update and deletes   |
+//! | `POST`         | `/es/:index/_bulk`     | runs bulk actions on a default index     |
+//! | `GET`, `POST`  | `/es/:index/_search`   | searches with the query dsl              |
+//!
+//! The id of a document is its primary key. Indexes created through this api have a keyword
+//! `_id` field as their primary key, which is left out of the `_source` of their documents.
+//! Writing a document into an index that does not exist creates it with a schema inferred from
+//! the document, much like dynamic mappings, but fields can not be added to an index later.
+//!
+//! Mappings may use the `text`, `keyword`, `long`, `integer`, `short`, `byte`, `unsigned_long`,
+//! `double`, `float`, `half_float`, `scaled_float`, `boolean`, `date` and `binary` types, and
+//! nest fields within `properties`. Dates are stored as keywords, so dates written in iso 8601
+//! compare in order.
+//!
+//! Searches understand the `match_all`, `match`, `term`, `terms`, `range`, `regexp`, `bool` and
+//! `query_string` queries, along with `from` and `size`. A query string may also be given by the
+//! `q` parameter, with the `df` and `default_operator` parameters. Hits are returned in the order
+//! documents were stored, and all score 1.
+//!
+//! Clients authenticate and are authorized like they are by the rest of the [http api](super),
+//! and are responded to with a `security_exception` if they do not or may not. Writing into an
+//! index that does not exist needs the permission to manage it, as it creates the index. The
+//! `_source` of documents leaves out the fields the client may not read. Requests that write are
+//! recorded in the audit log, if there is one, along with the indexes created by writing into them.
+//!
+//! Searches with `explain` set, in their body or as a parameter, also return how every clause of
+//! their query ran under `explain`, and the time spent parsing the query, running it and fetching
+//! its hits under `profile`.
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::ops::Bound;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::body::Bytes;
+use axum::extract::{Path, Query as QueryParams, State};
+use axum::http::{Request, StatusCode};
+use axum::middleware::{self, Next};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post, put};
+use axum::{Extension, Json, Router};
+use docatlas_core::auth::authorization::{Permission, Resource};
+use docatlas_core::cancel::{CancelToken, Cancelled};
+use docatlas_core::document::Document;
+use docatlas_core::error::DocatlasError;
+use docatlas_core::export::json_lines::document_to_json;
+use docatlas_core::fields::{Field, FieldKind};
+use docatlas_core::import::json_lines::{document_from_json, infer_schema};
+use docatlas_core::import::parse_text;
+use docatlas_core::index::{IndexReader, Upserted};
+use docatlas_core::query::explain::QueryPlan;
+use docatlas_core::query::string::{QueryString, QueryStringError};
+use docatlas_core::query::{Query, QueryError};
+use docatlas_core::schema::{Schema, SchemaField};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+use crate::access::{Access, Authenticated};
+use crate::audit::AuditAction;
+use crate::executor::Executor;
+use crate::handlers::{HandlerError, Indexes};
+
+/// The version of elasticsearch the api is a subset of
+const VERSION: &str = "7.10.2";
+/// The primary key of indexes created through this api
+const ID_FIELD: &str = "_id";
+/// The size of the ids of indexes created through this api
+const ID_SIZE: usize = 128;
+/// The size of keyword fields mapped without `ignore_above`, and the least size inferred
+const DEFAULT_KEYWORD_SIZE: usize = 256;
+/// The size of text fields, and the least size inferred
+const DEFAULT_TEXT_SIZE: usize = 1024;
+/// The length of generated ids
+const GENERATED_ID_LEN: usize = 20;
+/// The number of hits returned by a search without a size
+const DEFAULT_SIZE: usize = 10;
+
+type ElasticState = (Arc<Indexes>, Arc<Executor>, Duration);
+
+/// Creates the router of the elasticsearch api
+pub fn router(
+    indexes: Arc<Indexes>,
+    executor: Arc<Executor>,
+    max_request_timeout: Duration,
+    access: Arc<Access>,
+) -> Router {
+    let authenticated = middleware::from_fn_with_state((access, executor.clone()), authenticate);
+    Router::new()
+        .route("/", get(describe))
+        .route("/_bulk", post(bulk))
+        .route("/:index", put(create_index))
+        .route("/:index/_bulk", post(bulk_into))
+        .route("/:index/_doc", post(add_document))
+        .route(
+            "/:index/_doc/:id",
+            put(put_document).get(get_document).delete(delete_document),
+        )
+        .route("/:index/_search", get(search).post(search))
+        .route_layer(authenticated)
+        .with_state((indexes, executor, max_request_timeout))
+}
+
+/// Authenticates a request before it is handled, responding with a `security_exception` if it
+/// can not be
+async fn authenticate<B>(
+    State((access, executor)): State<(Arc<Access>, Arc<Executor>)>,
+    request: Request<B>,
+    next: Next<B>,
+) -> Response {
+    match super::authenticate_request(&access, &executor, request).await {
+        Ok(request) => next.run(request).await,
+        Err(e) => ElasticError::from(e).into_response(),
+    }
+}
+
+/// A request failed, responded with like elasticsearch does
+#[derive(Debug)]
+pub struct ElasticError {
+    status: StatusCode,
+    kind: &'static str,
+    reason: String,
+}
+
+impl ElasticError {
+    fn new(status: StatusCode, kind: &'static str, reason: impl ToString) -> Self {
+        Self {
+            status,
+            kind,
+            reason: reason.to_string(),
+        }
+    }
+
+    /// A request could not be parsed
+    fn parsing(reason: impl ToString) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "parsing_exception", reason)
+    }
+
+    /// A document or mapping could not be converted
+    fn mapping(reason: impl ToString) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "mapper_parsing_exception", reason)
+    }
+
+    fn json(&self) -> Value {
+        json!({ "type": self.kind, "reason": self.reason })
+    }
+}
+
+impl From<HandlerError> for ElasticError {
+    fn from(value: HandlerError) -> Self {
+        let error = DocatlasError::from(value);
+        let (status, kind) = match &error {
+            DocatlasError::NotFound(_) => (StatusCode::NOT_FOUND, "index_not_found_exception"),
+            DocatlasError::AlreadyExists(_) => {
+                (StatusCode::BAD_REQUEST, "resource_already_exists_exception")
+            }
+            DocatlasError::Cancelled(_) => (super::status(&error), "timeout_exception"),
+            DocatlasError::Unauthenticated(_) | DocatlasError::PermissionDenied(_) => {
+                (super::status(&error), "security_exception")
+            }
+            DocatlasError::InvalidRequest(_) | DocatlasError::Conflict(_) => {
+                (super::status(&error), "illegal_argument_exception")
+            }
+            _ => (super::status(&error), "exception"),
+        };
+        Self::new(status, kind, error.message())
+    }
+}
+
+impl From<QueryError> for ElasticError {
+    fn from(value: QueryError) -> Self {
+        match value {
+            QueryError::InvalidValue(e) => {
+                ElasticError::new(StatusCode::BAD_REQUEST, "query_shard_exception", e)
+            }
+            QueryError::InvalidPattern(e) => {
+                ElasticError::new(StatusCode::BAD_REQUEST, "query_shard_exception", e)
+            }
+            e => HandlerError::from(e).into(),
+        }
+    }
+}
+
+impl From<QueryStringError> for ElasticError {
+    fn from(value: QueryStringError) -> Self {
+        ElasticError::new(StatusCode::BAD_REQUEST, "query_shard_exception", value)
+    }
+}
+
+impl From<Cancelled> for ElasticError {
+    fn from(value: Cancelled) -> Self {
+        HandlerError::from(value).into()
+    }
+}
+
+impl Display for ElasticError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.kind, self.reason)
+    }
+}
+
+impl IntoResponse for ElasticError {
+    fn into_response(self) -> Response {
+        let body = json!({
+            "error": {
+                "root_cause": [self.json()],
+                "type": self.kind,
+                "reason": self.reason,
+            },
+            "status": self.status.as_u16(),
+        });
+        (self.status, Json(body)).into_response()
+    }
+}
+
+async fn describe(
+    Extension(mut authenticated): Extension<Authenticated>,
+) -> Result<Json<Value>, ElasticError> {
+    authenticated
+        .authorize_request(Permission::Read, &Resource::Cluster)
+        .await?;
+    Ok(Json(json!({
+        "name": "docatlas",
+        "cluster_name": "docatlas",
+        "version": {
+            "number": VERSION,
+            "build_flavor": "default",
+        },
+        "tagline": "You Know, for Search",
+    })))
+}
+
+async fn create_index(
+    State((indexes, _, _)): State<ElasticState>,
+    Extension(mut authenticated): Extension<Authenticated>,
+    Path(index): Path<String>,
+    body: Bytes,
+) -> Result<Json<Value>, ElasticError> {
+    let action = AuditAction::IndexCreated {
+        index: index.clone(),
+    };
+    let created: Result<(), ElasticError> = async {
+        authenticated
+            .authorize_request(Permission::Manage, &Resource::Index(index.clone()))
+            .await?;
+        let body = match body.is_empty() {
+            true => Value::Null,
+            false => serde_json::from_slice(&body).map_err(ElasticError::parsing)?,
+        };
+        Ok(indexes.create(&index, schema_from_mappings(&body)?)?)
+    }
+    .await;
+    authenticated.audit(action, &created);
+    created?;
+    Ok(Json(json!({
+        "acknowledged": true,
+        "shards_acknowledged": true,
+        "index": index,
+    })))
+}
+
+async fn add_document(
+    State((indexes, executor, _)): State<ElasticState>,
+    Extension(mut authenticated): Extension<Authenticated>,
+    Path(index): Path<String>,
+    Json(source): Json<Value>,
+) -> Result<Response, ElasticError> {
+    let action = AuditAction::DocumentsWritten {
+        index: index.clone(),
+        count: 1,
+    };
+    let written = async {
+        authenticated
+            .authorize_request(Permission::Write, &Resource::Index(index.clone()))
+            .await?;
+        let authenticated = authenticated.clone();
+        executor
+            .run(move || {
+                write_document(&indexes, &authenticated, &index, None, source, false)
+                    .map(|written| written.into_response(&index))
+            })
+            .await
+            .map_err(HandlerError::from)?
+    }
+    .await;
+    authenticated.audit(action, &written);
+    written
+}
+
+async fn put_document(
+    State((indexes, executor, _)): State<ElasticState>,
+    Extension(mut authenticated): Extension<Authenticated>,
+    Path((index, id)): Path<(String, String)>,
+    Json(source): Json<Value>,
+) -> Result<Response, ElasticError> {
+    let action = AuditAction::DocumentsWritten {
+        index: index.clone(),
+        count: 1,
+    };
+    let written = async {
+        authenticated
+            .authorize_request(Permission::Write, &Resource::Index(index.clone()))
+            .await?;
+        let authenticated = authenticated.clone();
+        executor
+            .run(move || {
+                write_document(&indexes, &authenticated, &index, Some(&id), source, false)
+                    .map(|written| written.into_response(&index))
+            })
+            .await
+            .map_err(HandlerError::from)?
+    }
+    .await;
+    authenticated.audit(action, &written);
+    written
+}
+
+async fn get_document(
+    State((indexes, _, _)): State<ElasticState>,
+    Extension(mut authenticated): Extension<Authenticated>,
+    Path((index, id)): Path<(String, String)>,
+) -> Result<Response, ElasticError> {
+    authenticated
+        .authorize_request(Permission::Read, &Resource::Index(index.clone()))
+        .await?;
+    let key = indexes.parse_key(&index, &id)?;
+    let schema = indexes.mapping(&index)?;
+    Ok(match indexes.get(&index, &key)? {
+        Some(mut document) => {
+            authenticated.field_access(&index).strip(&mut document);
+            Json(json!({
+                "_index": index,
+                "_id": id,
+                "_version": 1,
+                "found": true,
+                "_source": source(&document, &schema),
+            }))
+            .into_response()
+        }
+        None => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "_index": index, "_id": id, "found": false })),
+        )
+            .into_response(),
+    })
+}
+
+async fn delete_document(
+    State((indexes, executor, _)): State<ElasticState>,
+    Extension(mut authenticated): Extension<Authenticated>,
+    Path((index, id)): Path<(String, String)>,
+) -> Result<Response, ElasticError> {
+    let action = AuditAction::DocumentDeleted {
+        index: index.clone(),
+    };
+    let deleted = async {
+        authenticated
+            .authorize_request(Permission::Write, &Resource::Index(index.clone()))
+            .await?;
+        let key = indexes.parse_key(&index, &id)?;
+        let index = index.clone();
+        executor.run(move || indexes.delete(&index, &key)).await?
+    }
+    .await;
+    authenticated.audit(action, &deleted);
+    let (status, result) = match deleted? {
+        Some(_) => (StatusCode::OK, "deleted"),
+        None => (StatusCode::NOT_FOUND, "not_found"),
+    };
+    let body = json!({ "_index": index, "_id": id, "_version": 1, "result": result });
+    Ok((status, Json(body)).into_response())
+}
+
+/// A document that was written
+#[derive(Debug)]
+struct Written {
+    id: String,
+    upserted: Upserted,
+}
+
+impl Written {
+    /// Gets the status and result of the write
+    fn result(&self) -> (StatusCode, &'static str) {
+        match self.upserted {
+            Upserted::Inserted(_) => (StatusCode::CREATED, "created"),
+            Upserted::Updated(_) => (StatusCode::OK, "updated"),
+            Upserted::Dropped => (StatusCode::OK, "noop"),
+        }
+    }
+
+    fn into_response(self, index: &str) -> Response {
+        let (status, result) = self.result();
+        let body = json!({
+            "_index": index,
+            "_id": self.id,
+            "_version": 1,
+            "result": result,
+            "_shards": { "total": 1, "successful": 1, "failed": 0 },
+        });
+        (status, Json(body)).into_response()
+    }
+}
+
+/// Writes the source of a document into an index, creating the index if it does not exist and
+/// the user may manage it. Documents are given the id if there is one, and otherwise a generated
+/// id unless their source sets their primary key.
+fn write_document(
+    indexes: &Indexes,
+    authenticated: &Authenticated,
+    index: &str,
+    id: Option<&str>,
+    source: Value,
+    partial: bool,
+) -> Result<Written, ElasticError> {
+    if !source.is_object() {
+        return Err(ElasticError::mapping(
+            "the source of a document must be an object",
+        ));
+    }
+    if !indexes.contains(index) {
+        let action = AuditAction::IndexCreated {
+            index: index.to_string(),
+        };
+        let created = authenticated
+            .authorize(Permission::Manage, &Resource::Index(index.to_string()))
+            .map_err(ElasticError::from)
+            .and_then(|()| create_dynamic(indexes, index, &source));
+        authenticated.audit(action, &created);
+        created?;
+    }
+    let schema = indexes.mapping(index)?;
+    let mut document = document_from_json(source, &schema).map_err(ElasticError::mapping)?;
+    match schema.primary_key() {
+        Some(key) if id.is_some() || document.get(&key.name).is_none() => {
+            let id = id.map_or_else(generate_id, str::to_string);
+            let data = parse_text(&key.name, &key.kind, &id).map_err(ElasticError::mapping)?;
+            let field = Field::new(key.kind.clone(), [data]);
+            document.fields_mut().insert(&key.name, field);
+        }
+        None if id.is_some() => {
+            return Err(ElasticError::new(
+                StatusCode::BAD_REQUEST,
+                "illegal_argument_exception",
+                format!("{index:?} has no primary key, so its documents can not be given ids"),
+            ))
+        }
+        _ => {}
+    }
+    let key = document_id(&document, &schema);
+    let upserted = indexes.upsert(index, document, partial)?;
+    let id = match (key, &upserted) {
+        (Some(id), _) => id,
+        // documents of indexes without a primary key are known by their row
+        (None, Upserted::Inserted(row) | Upserted::Updated(row)) => row.to_string(),
+        (None, Upserted::Dropped) => String::new(),
+    };
+    Ok(Written { id, upserted })
+}
+
+/// Creates an index with a schema inferred from the source of its first document
+fn create_dynamic(indexes: &Indexes, index: &str, source: &Value) -> Result<(), ElasticError> {
+    let line = serde_json::to_vec(source).map_err(ElasticError::mapping)?;
+    let inferred = infer_schema(line.as_slice(), 1).map_err(ElasticError::mapping)?;
+    // later documents may hold longer values
+    let fields = inferred.iter().map(|field| SchemaField {
+        name: field.name.clone(),
+        kind: match field.kind {
+            FieldKind::Keyword(size) => FieldKind::Keyword(size.max(DEFAULT_KEYWORD_SIZE)),
+            FieldKind::Text(size) => FieldKind::Text(size.max(DEFAULT_TEXT_SIZE)),
+            ref kind => kind.clone(),
+        },
+    });
+    let schema = Schema::from_iter(std::iter::once(id_field()).chain(fields));
+    match indexes.create(index, schema.with_primary_key(ID_FIELD)) {
+        // another request created it first
+        Ok(()) | Err(HandlerError::IndexExists(_)) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn id_field() -> SchemaField {
+    SchemaField {
+        name: ID_FIELD.to_string(),
+        kind: FieldKind::Keyword(ID_SIZE),
+    }
+}
+
+fn generate_id() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(GENERATED_ID_LEN)
+        .map(char::from)
+        .collect()
+}
+
+/// Gets the id of a document as text, unless its index has no primary key
+fn document_id(document: &Document, schema: &Schema) -> Option<String> {
+    let key = [schema.primary_key()?.name.clone()];
+    match document_to_json(document, Some(&key)).remove(&key[0])? {
+        Value::String(id) => Some(id),
+        id => Some(id.to_string()),
+    }
+}
+
+/// Gets the source of a document, with its nested fields written as nested objects
+fn source(document: &Document, schema: &Schema) -> Value {
+    let mut flat = document_to_json(document, None);
+    if schema.primary_key().is_some_and(|key| key.name == ID_FIELD) {
+        flat.remove(ID_FIELD);
+    }
+    let mut nested = Map::new();
+    for (name, value) in flat {
+        insert_nested(&mut nested, &name, value);
+    }
+    Value::Object(nested)
+}
+
+fn insert_nested(object: &mut Map<String, Value>, name: &str, value: Value) {
+    if let Some((parent, rest)) = name.split_once('.') {
+        let child = object
+            .entry(parent)
+            .or_insert_with(|| Value::Object(Map::new()));
+        if let Value::Object(child) = child {
+            return insert_nested(child, rest, value);
+        }
+    }
+    // fields named like the parent of other fields keep their dotted names
+    object.insert(name.to_string(), value);
+}
+
+/// Converts the mappings of a create index request into a schema with an `_id` primary key
+fn schema_from_mappings(body: &Value) -> Result<Schema, ElasticError> {
+    let mut fields = vec![id_field()];
+    if let Some(properties) = body.pointer("/mappings/properties") {
+        add_properties(None, properties, &mut fields)?;
+    }
+    Ok(Schema::from_iter(fields).with_primary_key(ID_FIELD))
+}
+
+fn add_properties(
+    prefix: Option<&str>,
+    properties: &Value,
+    fields: &mut Vec<SchemaField>,
+) -> Result<(), ElasticError> {
+    let Value::Object(properties) = properties else {
+        return Err(ElasticError::mapping("properties must be an object"));
+    };
+    for (name, mapping) in properties {
+        let name = match prefix {
+            Some(prefix) => format!("{prefix}.{name}"),
+            None => name.clone(),
+        };
+        if let Some(properties) = mapping.get("properties") {
+            add_properties(Some(&name), properties, fields)?;
+            continue;
+        }
+        let kind = match mapping.get("type").and_then(Value::as_str) {
+            Some("text") => FieldKind::Text(DEFAULT_TEXT_SIZE),
+            Some("keyword") => {
+                let size = mapping.get("ignore_above").and_then(Value::as_u64);
+                FieldKind::Keyword(size.map_or(DEFAULT_KEYWORD_SIZE, |size| size as usize))
+            }
+            Some("long" | "integer" | "short" | "byte") => FieldKind::I64,
+            Some("unsigned_long") => FieldKind::U64,
+            Some("double" | "float" | "half_float" | "scaled_float") => FieldKind::F64,
+            Some("boolean") => FieldKind::Keyword(8),
+            Some("date") => FieldKind::Keyword(64),
+            Some("binary") => FieldKind::Blob,
+            Some(other) => {
+                return Err(ElasticError::mapping(format!(
+                    "no handler for type [{other}] declared on field [{name}]"
+                )))
+            }
+            None => {
+                return Err(ElasticError::mapping(format!(
+                    "no type specified for field [{name}]"
+                )))
+            }
+        };
+        fields.push(SchemaField { name, kind });
+    }
+    Ok(())
+}
+
+async fn bulk(
+    state: State<ElasticState>,
+    authenticated: Extension<Authenticated>,
+    body: String,
+) -> Result<Json<Value>, ElasticError> {
+    run_bulk(state, authenticated, None, body).await
+}
+
+async fn bulk_into(
+    state: State<ElasticState>,
+    authenticated: Extension<Authenticated>,
+    Path(index): Path<String>,
+    body: String,
+) -> Result<Json<Value>, ElasticError> {
+    run_bulk(state, authenticated, Some(index), body).await
+}
+
+/// An action of a bulk request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    Index,
+    Create,
+    Update,
+    Delete,
+}
+
+impl Action {
+    fn name(self) -> &'static str {
+        match self {
+            Action::Index => "index",
+            Action::Create => "create",
+            Action::Update => "update",
+            Action::Delete => "delete",
+        }
+    }
+}
+
+/// An action of a bulk request, along with the document it is run on
+#[derive(Debug)]
+struct BulkOperation {
+    action: Action,
+    index: String,
+    id: Option<String>,
+    /// The line following the action, unless it is a delete
+    source: Option<Value>,
+}
+
+async fn run_bulk(
+    State((indexes, executor, _)): State<ElasticState>,
+    Extension(mut authenticated): Extension<Authenticated>,
+    index: Option<String>,
+    body: String,
+) -> Result<Json<Value>, ElasticError> {
+    let started = Instant::now();
+    // every operation is authorized on its own, but the service is only asked about the request
+    authenticated
+        .ask_service(Permission::Write, &Resource::of(index.as_deref()))
+        .await;
+    let operations = parse_bulk(&body, index.as_deref())?;
+    // every index written into is audited once, with the first action on it that failed
+    let mut audited = BTreeMap::new();
+    for operation in &operations {
+        audited
+            .entry(operation.index.clone())
+            .or_insert((0, None))
+            .0 += 1;
+    }
+    let caller = authenticated.clone();
+    let items = executor
+        .run(move || {
+            operations
+                .into_iter()
+                .map(|operation| run_operation(&indexes, &caller, operation))
+                .collect::<Vec<_>>()
+        })
+        .await
+        .map_err(HandlerError::from)?;
+    let mut errors = false;
+    for result in items
+        .iter()
+        .filter_map(|item| item.as_object()?.values().next())
+    {
+        if let Some(error) = result.get("error") {
+            errors = true;
+            let index = result["_index"].as_str().unwrap_or_default();
+            if let Some((_, failed @ None)) = audited.get_mut(index) {
+                *failed = Some(error["reason"].as_str().unwrap_or_default().to_string());
+            }
+        }
+    }
+    for (index, (count, failed)) in audited {
+        let result = failed.map_or(Ok(()), Err);
+        authenticated.audit(AuditAction::DocumentsWritten { index, count }, &result);
+    }
+    Ok(Json(json!({
+        "took": started.elapsed().as_millis() as u64,
+        "errors": errors,
+        "items": items,
+    })))
+}
+
+/// Parses the action and source lines of a bulk request
+fn parse_bulk(body: &str, index: Option<&str>) -> Result<Vec<BulkOperation>, ElasticError> {
+    let mut lines = body.lines().filter(|line| !line.trim().is_empty());
+    let mut operations = vec![];
+    while let Some(line) = lines.next() {
+        let Ok(Value::Object(action)) = serde_json::from_str::<Value>(line) else {
+            return Err(ElasticError::parsing(format!(
+                "malformed action line {line:?}"
+            )));
+        };
+        let (name, meta) = match action.into_iter().next() {
+            Some((name, Value::Object(meta))) => (name, meta),
+            _ => return Err(ElasticError::parsing("an action must hold its metadata")),
+        };
+        let action = match name.as_str() {
+            "index" => Action::Index,
+            "create" => Action::Create,
+            "update" => Action::Update,
+            "delete" => Action::Delete,
+            _ => {
+                return Err(ElasticError::parsing(format!(
+                    "unknown action [{name}], expected one of [create, delete, index, update]"
+                )))
+            }
+        };
+        let index = match (meta.get("_index").and_then(Value::as_str), index) {
+            (Some(index), _) | (None, Some(index)) => index.to_string(),
+            (None, None) => return Err(ElasticError::parsing("an action is missing its index")),
+        };
+        let id = meta.get("_id").map(|id| match id {
+            Value::String(id) => id.clone(),
+            id => id.to_string(),
+        });
+        let source = match action {
+            Action::Delete => None,
+            _ => {
+                let line = lines
+                    .next()
+                    .ok_or_else(|| ElasticError::parsing("an action is missing its source"))?;
+                Some(serde_json::from_str(line).map_err(ElasticError::parsing)?)
+            }
+        };
+        operations.push(BulkOperation {
+            action,
+            index,
+            id,
+            source,
+        });
+    }
+    Ok(operations)
+}
+
+/// Runs an action of a bulk request, returning its item of the response. Every action is
+/// authorized on its own, so actions on indexes the user may not write into fail on their own.
+fn run_operation(
+    indexes: &Indexes,
+    authenticated: &Authenticated,
+    operation: BulkOperation,
+) -> Value {
+    let BulkOperation {
+        action,
+        index,
+        id,
+        source,
+    } = operation;
+    let authorized = authenticated
+        .authorize(Permission::Write, &Resource::Index(index.clone()))
+        .map_err(ElasticError::from);
+    let result = authorized.and_then(|()| match action {
+        Action::Index => write_document(
+            indexes,
+            authenticated,
+            &index,
+            id.as_deref(),
+            source.unwrap_or_default(),
+            false,
+        )
+        .map(|written| (written.result(), written.id)),
+        Action::Create => match &id {
+            Some(id) if exists(indexes, &index, id) => Err(ElasticError::new(
+                StatusCode::CONFLICT,
+                "version_conflict_engine_exception",
+                format!("[{id}]: version conflict, document already exists"),
+            )),
+            _ => write_document(
+                indexes,
+                authenticated,
+                &index,
+                id.as_deref(),
+                source.unwrap_or_default(),
+                false,
+            )
+            .map(|written| (written.result(), written.id)),
+        },
+        Action::Update => update(
+            indexes,
+            authenticated,
+            &index,
+            id.as_deref(),
+            source.unwrap_or_default(),
+        ),
+        Action::Delete => delete(indexes, &index, id.as_deref()),
+    });
+    let item = match result {
+        Ok(((status, result), id)) => json!({
+            "_index": index,
+            "_id": id,
+            "status": status.as_u16(),
+            "result": result,
+        }),
+        Err(error) => json!({
+            "_index": index,
+            "_id": id,
+            "status": error.status.as_u16(),
+            "error": error.json(),
+        }),
+    };
+    json!({ action.name(): item })
+}
+
+type BulkResult = Result<((StatusCode, &'static str), String), ElasticError>;
+
+/// Updates the fields given by the `doc` of an update action
+fn update(
+    indexes: &Indexes,
+    authenticated: &Authenticated,
+    index: &str,
+    id: Option<&str>,
+    source: Value,
+) -> BulkResult {
+    let id = id.ok_or_else(|| ElasticError::parsing("an update is missing its id"))?;
+    let doc = source.get("doc").cloned().unwrap_or_default();
+    let upsert = source.get("doc_as_upsert").and_then(Value::as_bool) == Some(true);
+    let existed = exists(indexes, index, id);
+    if !existed && !upsert {
+        return Err(ElasticError::new(
+            StatusCode::NOT_FOUND,
+            "document_missing_exception",
+            format!("[{id}]: document missing"),
+        ));
+    }
+    let written = write_document(indexes, authenticated, index, Some(id), doc, existed)?;
+    Ok((written.result(), written.id))
+}
+
+fn delete(indexes: &Indexes, index: &str, id: Option<&str>) -> BulkResult {
+    let id = id.ok_or_else(|| ElasticError::parsing("a delete is missing its id"))?;
+    let key = indexes.parse_key(index, id)?;
+    let result = match indexes.delete(index, &key)? {
+        Some(_) => (StatusCode::OK, "deleted"),
+        None => (StatusCode::NOT_FOUND, "not_found"),
+    };
+    Ok((result, id.to_string()))
+}
+
+/// Checks whether an index holds the document with the given id
+fn exists(indexes: &Indexes, index: &str, id: &str) -> bool {
+    indexes
+        .parse_key(index, id)
+        .and_then(|key| indexes.get(index, &key))
+        .is_ok_and(|document| document.is_some())
+}
+
+/// Parses a query of the query dsl
+fn parse_query(value: &Value) -> Result<Query, ElasticError> {
+    let Some((name, body)) = value.as_object().and_then(single_entry) else {
+        return Err(ElasticError::parsing(format!(
+            "a query must be an object with a single key, not {value}"
+        )));
+    };
+    let field_query = || {
+        body.as_object()
+            .and_then(single_entry)
+            .ok_or_else(|| ElasticError::parsing(format!("[{name}] query malformed")))
+    };
+    Ok(match name {
+        "match_all" => Query::All,
+        "match" => {
+            let (field, body) = field_query()?;
+            let (text, operator) = match body {
+                Value::Object(options) => (
+                    options.get("query").and_then(scalar),
+                    options.get("operator").and_then(Value::as_str),
+                ),
+                value => (scalar(value), None),
+            };
+            Query::Match {
+                field: field.to_string(),
+                text: text
+                    .ok_or_else(|| ElasticError::parsing("[match] query is missing its text"))?,
+                all: operator.is_some_and(|operator| operator.eq_ignore_ascii_case("and")),
+            }
+        }
+        "term" => {
+            let (field, body) = field_query()?;
+            let value = match body {
+                Value::Object(options) => options.get("value").and_then(scalar),
+                value => scalar(value),
+            };
+            term(
+                field,
+                value.ok_or_else(|| ElasticError::parsing("[term] query is missing its value"))?,
+            )
+        }
+        "terms" => {
+            let (field, body) = field_query()?;
+            let values = body
+                .as_array()
+                .ok_or_else(|| ElasticError::parsing("[terms] query requires an array"))?;
+            Query::Or(
+                values
+                    .iter()
+                    .filter_map(scalar)
+                    .map(|value| term(field, value))
+                    .collect(),
+            )
+        }
+        "range" => {
+            let (field, body) = field_query()?;
+            let options = body
+                .as_object()
+                .ok_or_else(|| ElasticError::parsing("[range] query requires an object"))?;
+            let bound = |name: &str| options.get(name).and_then(scalar);
+            let range = |lower, upper| Query::Range {
+                field: field.to_string(),
+                lower,
+                upper,
+            };
+            let bounds = [
+                bound("gt").map(|value| range(Bound::Excluded(value), Bound::Unbounded)),
+                bound("gte").map(|value| range(Bound::Included(value), Bound::Unbounded)),
+                bound("lt").map(|value| range(Bound::Unbounded, Bound::Excluded(value))),
+                bound("lte").map(|value| range(Bound::Unbounded, Bound::Included(value))),
+            ];
+            match bounds.into_iter().flatten().collect::<Vec<_>>() {
+                bounds if bounds.is_empty() => Query::Exists {
+                    field: field.to_string(),
+                },
+                bounds => Query::And(bounds),
+            }
+        }
+        "regexp" => {
+            let (field, body) = field_query()?;
+            let pattern = match body {
+                Value::Object(options) => options.get("value").and_then(Value::as_str),
+                value => value.as_str(),
+            };
+            Query::Regexp {
+                field: field.to_string(),
+                pattern: pattern
+                    .ok_or_else(|| ElasticError::parsing("[regexp] query is missing its value"))?
+                    .to_string(),
+            }
+        }
+        "bool" => {
+            let clauses = |name: &str| -> Result<Vec<Query>, ElasticError> {
+                match body.get(name) {
+                    None => Ok(vec![]),
+                    Some(Value::Array(queries)) => queries.iter().map(parse_query).collect(),
+                    Some(query) => Ok(vec![parse_query(query)?]),
+                }
+            };
+            // filters match like musts, as every hit scores the same
+            let mut queries = clauses("must")?;
+            queries.extend(clauses("filter")?);
+            // should clauses only narrow the hits of queries without must clauses
+            let should = clauses("should")?;
+            if queries.is_empty() && !should.is_empty() {
+                queries.push(Query::Or(should));
+            }
+            for query in clauses("must_not")? {
+                queries.push(Query::Not(Box::new(query)));
+            }
+            Query::And(queries)
+        }
+        "query_string" => {
+            let option = |name: &str| body.get(name).and_then(Value::as_str);
+            let query = option("query")
+                .ok_or_else(|| ElasticError::parsing("[query_string] requires [query]"))?;
+            query_string(query, option("default_field"), option("default_operator"))?
+        }
+        _ => {
+            return Err(ElasticError::parsing(format!(
+                "unknown query [{name}], expected one of [bool, match, match_all, query_string, \
+                 range, regexp, term, terms]"
+            )))
+        }
+    })
+}
+
+/// Parses a query string, whose terms without a field search the default field
+fn query_string(
+    query: &str,
+    default_field: Option<&str>,
+    default_operator: Option<&str>,
+) -> Result<Query, ElasticError> {
+    let mut parser = QueryString::new();
+    if let Some(field) = default_field {
+        parser = parser.with_default_field(field);
+    }
+    if let Some(operator) = default_operator {
+        parser = parser.with_default_operator(operator.parse()?);
+    }
+    Ok(parser.parse(query)?)
+}
+
+fn single_entry(object: &Map<String, Value>) -> Option<(&str, &Value)> {
+    match object.len() {
+        1 => object
+            .iter()
+            .next()
+            .map(|(key, value)| (key.as_str(), value)),
+        _ => None,
+    }
+}
+
+/// Gets a value given to a query as text
+fn scalar(value: &Value) -> Option<String> {
+    match value {
+        Value::String(text) => Some(text.clone()),
+        Value::Number(_) | Value::Bool(_) => Some(value.to_string()),
+        _ => None,
+    }
+}
+
+/// Matches fields holding a value like a term query. Text fields hold it if they hold every
+/// one of its terms.
+fn term(field: &str, value: String) -> Query {
+    Query::Match {
+        field: field.to_string(),
+        text: value,
+        all: true,
+    }
+}
+
+/// The body of a search request
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct SearchBody {
+    query: Option<Value>,
+    from: Option<usize>,
+    size: Option<usize>,
+    explain: bool,
+}
+
+/// The parameters of a search request, which take precedence over its body
+#[derive(Debug, Deserialize)]
+struct SearchParams {
+    from: Option<usize>,
+    size: Option<usize>,
+    q: Option<String>,
+    df: Option<String>,
+    default_operator: Option<String>,
+    explain: Option<bool>,
+}
+
+async fn search(
+    State((indexes, executor, max_timeout)): State<ElasticState>,
+    Extension(mut authenticated): Extension<Authenticated>,
+    Path(index): Path<String>,
+    QueryParams(params): QueryParams<SearchParams>,
+    body: Bytes,
+) -> Result<Json<Value>, ElasticError> {
+    let started = Instant::now();
+    authenticated
+        .authorize_request(Permission::Read, &Resource::Index(index.clone()))
+        .await?;
+    let body = match body.is_empty() {
+        true => SearchBody::default(),
+        false => serde_json::from_slice::<Option<SearchBody>>(&body)
+            .map_err(ElasticError::parsing)?
+            .unwrap_or_default(),
+    };
+    let query = match (&params.q, &body.query) {
+        (Some(q), _) => query_string(q, params.df.as_deref(), params.default_operator.as_deref())?,
+        (None, Some(query)) => parse_query(query)?,
+        (None, None) => Query::All,
+    };
+    authenticated.authorize_query(&index, &query)?;
+    let from = params.from.or(body.from).unwrap_or(0);
+    let size = params.size.or(body.size).unwrap_or(DEFAULT_SIZE);
+    let explain = params.explain.unwrap_or(body.explain);
+    let parsed = started.elapsed();
+    let cancel = CancelToken::new().with_deadline(Instant::now() + max_timeout);
+    let access = authenticated.field_access(&index);
+    let searched = index.clone();
+    let (total, hits, profile) = executor
+        .run(move || {
+            indexes.inspect(&searched, |searcher| {
+                let queried = Instant::now();
+                let (rows, plan) = match explain {
+                    true => {
+                        let explanation = query.explain(searcher, &cancel)?;
+                        (explanation.rows, Some(explanation.plan))
+                    }
+                    false => (query.rows(searcher, &cancel)?, None),
+                };
+                let fetched = Instant::now();
+                let hits = rows
+                    .iter()
+                    .skip(from)
+                    .take(size)
+                    .map(|&row| {
+                        let mut document = searcher
+                            .read(row)
+                            .expect("matched rows exist")
+                            .map_err(HandlerError::from)?;
+                        access.strip(&mut document);
+                        Ok(json!({
+                            "_index": searched,
+                            "_id": document_id(&document, searcher.schema())
+                                .unwrap_or_else(|| row.to_string()),
+                            "_score": 1.0,
+                            "_source": source(&document, searcher.schema()),
+                        }))
+                    })
+                    .collect::<Result<Vec<_>, ElasticError>>()?;
+                let profile = plan.map(|plan| {
+                    let phases = [
+                        ("parse", parsed),
+                        ("query", fetched - queried),
+                        ("fetch", fetched.elapsed()),
+                    ];
+                    (plan, phases)
+                });
+                Ok::<_, ElasticError>((rows.len(), hits, profile))
+            })
+        })
+        .await
+        .map_err(HandlerError::from)??;
+    let mut response = json!({
+        "took": started.elapsed().as_millis() as u64,
+        "timed_out": false,
+        "_shards": { "total": 1, "successful": 1, "skipped": 0, "failed": 0 },
+        "hits": {
+            "total": { "value": total, "relation": "eq" },
+            "max_score": if hits.is_empty() { Value::Null } else { json!(1.0) },
+            "hits": hits,
+        },
+    });
+    if let Some((plan, phases)) = profile {
+        response["explain"] = plan_json(&plan);
+        response["profile"] = phases
+            .into_iter()
+            .map(|(phase, time)| (phase.to_string(), json!({ "time_in_nanos": nanos(time) })))
+            .collect();
+    }
+    Ok(Json(response))
+}
+
+/// Describes how a clause of a query ran, and how its children ran
+fn plan_json(plan: &QueryPlan) -> Value {
+    json!({
+        "clause": plan.clause,
+        "time_in_nanos": nanos(plan.time),
+        "examined": plan.examined,
+        "matched": plan.matched,
+        "cached": plan.cached,
+        "children": plan.children.iter().map(plan_json).collect::<Vec<_>>(),
+    })
+}
+
+fn nanos(time: Duration) -> u64 {
+    time.as_nanos().try_into().unwrap_or(u64::MAX)
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::body::Body;
+    use axum::http::Method;
+    use docatlas_core::auth::authentication::SessionService;
+    use docatlas_core::auth::authorization::Authorizer;
+    use tower::ServiceExt;
+
+    use super::*;
+
+    async fn send(router: &Router, method: Method, uri: &str, body: String) -> (StatusCode, Value) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("content-type", "application/json")
+            .body(Body::from(body))
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body = serde_json::from_slice(&bytes).unwrap_or(Value::Null);
+        (status, body)
+    }
+
+    fn ids(body: &Value) -> Vec<&str> {
+        body["hits"]["hits"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|hit| hit["_id"].as_str().unwrap())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn index_and_search_documents() {
+        let router = router(
+            Arc::new(Indexes::new()),
+            Arc::new(Executor::new(1).unwrap()),
+            Duration::from_secs(60),
+            Arc::new(Access::new(
+                Arc::new(SessionService::default()),
+                Arc::new(Authorizer::new()),
+            )),
+        );
+        let (status, body) = send(&router, Method::GET, "/", String::new()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["version"]["number"], VERSION);
+
+        let mappings = json!({
+            "mappings": {
+                "properties": {
+                    "title": { "type": "text" },
+                    "genre": { "type": "keyword" },
+                    "year": { "type": "integer" },
+                    "author": { "properties": { "name": { "type": "text" } } },
+                }
+            }
+        });
+        let (status, _) = send(&router, Method::PUT, "/books", mappings.to_string()).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, body) = send(&router, Method::PUT, "/books", String::new()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["type"], "resource_already_exists_exception");
+
+        let dune = json!({
+            "title": "Dune",
+            "genre": "sci-fi",
+            "year": 1965,
+            "author": { "name": "Frank Herbert" },
+        });
+        let (status, body) = send(&router, Method::PUT, "/books/_doc/dune", dune.to_string()).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body["result"], "created");
+        let (status, body) = send(&router, Method::PUT, "/books/_doc/dune", dune.to_string()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["result"], "updated");
+        let (status, body) = send(&router, Method::GET, "/books/_doc/dune", String::new()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["_source"], dune);
+        let emma = json!({ "title": "Emma", "genre": "romance", "year": 1815 });
+        let (status, body) = send(&router, Method::POST, "/books/_doc", emma.to_string()).await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body["_id"].as_str().unwrap().len(), GENERATED_ID_LEN);
+
+        let bulk = [
+            json!({ "index": { "_id": "ubik" } }),
+            json!({ "title": "Ubik", "genre": "sci-fi", "year": 1969 }),
+            json!({ "create": { "_id": "dune" } }),
+            json!({ "title": "Dune" }),
+            json!({ "update": { "_id": "ubik" } }),
+            json!({ "doc": { "title": "Ubik, a novel" } }),
+            json!({ "delete": { "_id": "missing" } }),
+        ]
+        .map(|line| line.to_string() + "\n")
+        .concat();
+        let (status, body) = send(&router, Method::POST, "/books/_bulk", bulk).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["errors"], true);
+        let statuses = body["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| item.as_object().unwrap().values().next().unwrap()["status"].clone())
+            .collect::<Vec<_>>();
+        assert_eq!(statuses, [201, 409, 200, 404]);
+        let (_, body) = send(&router, Method::GET, "/books/_doc/ubik", String::new()).await;
+        assert_eq!(body["_source"]["title"], "Ubik, a novel");
+        assert_eq!(body["_source"]["year"], 1969);
+
+        let search = |query: Value| {
+            let router = router.clone();
+            async move {
+                let body = json!({ "query": query }).to_string();
+                send(&router, Method::POST, "/books/_search", body).await
+            }
+        };
+        let (status, body) = search(json!({ "match": { "title": "dune ubik" } })).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(ids(&body), ["dune", "ubik"]);
+        assert_eq!(body["hits"]["total"]["value"], 2);
+        let (_, body) = search(json!({
+            "bool": {
+                "filter": { "term": { "genre": "sci-fi" } },
+                "must_not": [{ "range": { "year": { "gte": 1966 } } }],
+            }
+        }))
+        .await;
+        assert_eq!(ids(&body), ["dune"]);
+        let (_, body) = search(json!({ "terms": { "year": [1815, 1969] } })).await;
+        assert_eq!(body["hits"]["total"]["value"], 2);
+        let (_, body) = search(json!({ "match": { "author.name": "herbert" } })).await;
+        assert_eq!(ids(&body), ["dune"]);
+        let (status, body) = search(json!({ "fuzzy": { "title": "dnue" } })).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["type"], "parsing_exception");
+        let (_, body) = search(json!({ "regexp": { "title": "dun.|ubi[a-z]" } })).await;
+        assert_eq!(ids(&body), ["dune", "ubik"]);
+        let (_, body) = search(json!({ "regexp": { "genre": { "value": "sci-.*" } } })).await;
+        assert_eq!(body["hits"]["total"]["value"], 2);
+        let (status, body) = search(json!({ "regexp": { "title": "(a|b)*a(a|b){24}" } })).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["type"], "query_shard_exception");
+        let (_, body) = send(&router, Method::GET, "/books/_search?size=1", String::new()).await;
+        assert_eq!(body["hits"]["total"]["value"], 3);
+        assert_eq!(ids(&body), ["dune"]);
+        let (_, body) = search(json!({
+            "query_string": {
+                "query": "genre:sci-fi AND year:[1960 TO 1966}",
+                "default_operator": "AND",
+            }
+        }))
+        .await;
+        assert_eq!(ids(&body), ["dune"]);
+        let uri = "/books/_search?q=dune%20ubik%20-year:1969&df=title";
+        let (_, body) = send(&router, Method::GET, uri, String::new()).await;
+        assert_eq!(ids(&body), ["dune"]);
+        let (status, body) =
+            send(&router, Method::GET, "/books/_search?q=dune", String::new()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["type"], "query_shard_exception");
+        let body = json!({ "query": { "term": { "genre": "sci-fi" } }, "explain": true });
+        let (_, body) = send(&router, Method::POST, "/books/_search", body.to_string()).await;
+        assert_eq!(body["explain"]["clause"], "match genre all of \"sci-fi\"");
+        assert_eq!(body["explain"]["matched"], 2);
+        assert!(body["profile"]["query"]["time_in_nanos"].is_u64());
+        let (_, body) = send(&router, Method::GET, "/books/_search", String::new()).await;
+        assert!(body.get("explain").is_none());
+
+        // indexes are created with an inferred schema when first written to
+        let film = json!({ "title": "Alien", "year": 1979 });
+        let (status, _) = send(&router, Method::PUT, "/films/_doc/alien", film.to_string()).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (_, body) = search(json!({ "match_all": {} })).await;
+        assert_eq!(body["hits"]["total"]["value"], 3);
+        let (status, body) = send(&router, Method::GET, "/films/_doc/alien", String::new()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["_source"], film);
+        let (status, body) = send(&router, Method::GET, "/songs/_search", String::new()).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body["error"]["type"], "index_not_found_exception");
+    }
+}
@@ -0,0 +1,370 @@
+//! The on-disk layout of the indexes served by the daemon.
+//!
+//! Every index is a directory named after it within the indexes directory, holding the mapping
+//! of the index as `mapping.json` and its [write-ahead log](crate::wal) as `wal.log`:
+//!
+//! ```text
+//! <path>/indexes/
+//!     books/
+//!         mapping.json
+//!         wal.log
+//! ```
+//!
+//! Indexes may be spread over several directories, such as one per disk. Every index lives in
+//! exactly one of them, chosen when the index is created by going round the directories in turn,
+//! skipping any with less than 5% of its disk free unless they all are.
+//!
+//! Index names are used as directory names, so they are restricted to lowercase ascii letters,
+//! digits, `-`, `_` and `.`, may not start with `-`, `_` or `.`, and are at most 255 bytes long.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use docatlas_core::error::ResultExt;
+use docatlas_core::schema::Schema;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// The file storing the mapping of an index
+const MAPPING_FILE: &str = "mapping.json";
+/// The write-ahead log of an index
+const WAL_FILE: &str = "wal.log";
+/// The longest an index name can be, in bytes
+pub const MAX_INDEX_NAME_LEN: usize = 255;
+/// The share of a disk that must be free for new indexes to be placed on it
+const MIN_FREE_SHARE: f64 = 0.05;
+
+/// Manages the directories and mappings of indexes
+#[derive(Debug, Clone)]
+pub struct IndexManager {
+    roots: Vec<PathBuf>,
+    /// The root every index is stored in
+    placements: Arc<Mutex<HashMap<String, usize>>>,
+    /// The root the next index is placed in, if it has room
+    next: Arc<AtomicUsize>,
+}
+
+/// How much of a directory indexes are stored in is used
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PathUsage {
+    pub path: PathBuf,
+    /// The number of indexes stored in the directory
+    pub indexes: usize,
+    /// The bytes used by the indexes stored in the directory
+    pub used_bytes: u64,
+    /// The bytes free on the disk of the directory, if known
+    pub available_bytes: Option<u64>,
+    /// The size of the disk of the directory, if known
+    pub total_bytes: Option<u64>,
+}
+
+impl IndexManager {
+    /// Manages the indexes stored within the given directory
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        Self {
+            roots: vec![root.as_ref().to_path_buf()],
+            placements: Arc::default(),
+            next: Arc::default(),
+        }
+    }
+
+    /// Stores indexes within another directory as well
+    pub fn with_root(mut self, root: impl AsRef<Path>) -> Self {
+        let root = root.as_ref().to_path_buf();
+        if !self.roots.contains(&root) {
+            self.roots.push(root);
+        }
+        self
+    }
+
+    /// Gets the first directory indexes are stored in
+    pub fn root(&self) -> &Path {
+        &self.roots[0]
+    }
+
+    /// Gets every directory indexes are stored in
+    pub fn roots(&self) -> &[PathBuf] {
+        &self.roots
+    }
+
+    /// Gets the directory of an index. Indexes that do not exist yet are in the first directory.
+    pub fn index_dir(&self, name: &str) -> PathBuf {
+        let root = self
+            .placements
+            .lock()
+            .expect("placements poisoned")
+            .get(name)
+            .copied()
+            .unwrap_or(0);
+        self.roots[root].join(name)
+    }
+
+    /// Gets the write-ahead log of an index
+    pub fn wal_path(&self, name: &str) -> PathBuf {
+        self.index_dir(name).join(WAL_FILE)
+    }
+
+    /// Reads the name and mapping of every stored index. Directories that are not named like an
+    /// index are skipped.
+    pub fn load(&self) -> io::Result<Vec<(String, Schema)>> {
+        let mut placements = self.placements.lock().expect("placements poisoned");
+        placements.clear();
+        let mut indexes = vec![];
+        for (root, path) in self.roots.iter().enumerate() {
+            fs::create_dir_all(path)?;
+            for entry in fs::read_dir(path)? {
+                let entry = entry?;
+                if !entry.file_type()?.is_dir() {
+                    continue;
+                }
+                let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+                    continue;
+                };
+                if let Err(e) = validate_name(&name) {
+                    warn!("skipping {:?}: {e}", entry.path());
+                    continue;
+                }
+                if let Some(other) = placements.insert(name.clone(), root) {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "index {name:?} is stored in both {:?} and {path:?}",
+                            self.roots[other]
+                        ),
+                    ));
+                }
+                let mapping = entry.path().join(MAPPING_FILE);
+                let file = fs::File::open(&mapping)
+                    .with_context(|| format!("could not open the mapping of {name:?}"))?;
+                let schema = serde_json::from_reader(io::BufReader::new(file))
+                    .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+                    .with_context(|| format!("invalid mapping {mapping:?}"))?;
+                indexes.push((name, schema));
+            }
+        }
+        indexes.sort_by(|(left, _), (right, _)| left.cmp(right));
+        Ok(indexes)
+    }
+
+    /// Creates the directory of an index, and stores its mapping. Indexes that are already stored
+    /// keep their directory.
+    pub fn create(&self, name: &str, schema: &Schema) -> io::Result<()> {
+        {
+            let mut placements = self.placements.lock().expect("placements poisoned");
+            if !placements.contains_key(name) {
+                placements.insert(name.to_string(), self.place());
+            }
+        }
+        fs::create_dir_all(self.index_dir(name))?;
+        self.write_mapping(name, schema)
+    }
+
+    /// Chooses the directory of a new index
+    fn place(&self) -> usize {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let spaces = self
+            .roots
+            .iter()
+            .map(|root| disk_space(root))
+            .collect::<Vec<_>>();
+        let roomy = (0..self.roots.len())
+            .map(|offset| (start + offset) % self.roots.len())
+            .find(|&root| match spaces[root] {
+                Some((available, total)) => available as f64 >= total as f64 * MIN_FREE_SHARE,
+                None => true,
+            });
+        roomy.unwrap_or_else(|| {
+            // every disk is low on space, so the one with the most left is used
+            (0..self.roots.len())
+                .max_by_key(|&root| spaces[root].map_or(0, |(available, _)| available))
+                .unwrap_or(0)
+        })
+    }
+
+    /// Gets how much of every directory indexes are stored in is used
+    pub fn usage(&self) -> Vec<PathUsage> {
+        let placements = self.placements.lock().expect("placements poisoned").clone();
+        self.roots
+            .iter()
+            .enumerate()
+            .map(|(root, path)| {
+                let names = placements
+                    .iter()
+                    .filter(|(_, placed)| **placed == root)
+                    .map(|(name, _)| name)
+                    .collect::<Vec<_>>();
+                let space = disk_space(path);
+                PathUsage {
+                    path: path.clone(),
+                    indexes: names.len(),
+                    used_bytes: names.iter().map(|name| dir_size(&path.join(name))).sum(),
+                    available_bytes: space.map(|(available, _)| available),
+                    total_bytes: space.map(|(_, total)| total),
+                }
+            })
+            .collect()
+    }
+
+    /// Replaces the stored mapping of an index. The mapping is written to a temporary file
+    /// first, so the stored mapping is never partially written.
+    pub fn write_mapping(&self, name: &str, schema: &Schema) -> io::Result<()> {
+        let dir = self.index_dir(name);
+        let temp = dir.join(format!("{MAPPING_FILE}.tmp"));
+        let json = serde_json::to_vec_pretty(schema).map_err(io::Error::other)?;
+        fs::write(&temp, json).with_context(|| format!("could not write {temp:?}"))?;
+        fs::rename(&temp, dir.join(MAPPING_FILE))
+            .with_context(|| format!("could not replace the mapping of {name:?}"))?;
+        Ok(())
+    }
+
+    /// Deletes the directory of an index, along with everything in it
+    pub fn delete(&self, name: &str) -> io::Result<()> {
+        let dir = self.index_dir(name);
+        self.placements
+            .lock()
+            .expect("placements poisoned")
+            .remove(name);
+        match fs::remove_dir_all(dir) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Gets the bytes used by the files within a directory
+fn dir_size(dir: &Path) -> u64 {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return 0;
+    };
+    entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            Some(match metadata.is_dir() {
+                true => dir_size(&entry.path()),
+                false => metadata.len(),
+            })
+        })
+        .sum()
+}
+
+/// Gets the bytes free on the disk of a directory, and the size of the disk
+#[cfg(unix)]
+fn disk_space(path: &Path) -> Option<(u64, u64)> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stats = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // statvfs only writes to the given struct, which is only read once it succeeded
+    if unsafe { libc::statvfs(path.as_ptr(), stats.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    let stats = unsafe { stats.assume_init() };
+    let block = stats.f_frsize;
+    Some((stats.f_bavail * block, stats.f_blocks * block))
+}
+
+/// Gets the bytes free on the disk of a directory, which can not be known on this platform
+#[cfg(not(unix))]
+fn disk_space(_path: &Path) -> Option<(u64, u64)> {
+    None
+}
+
+/// Checks that a name can be used for an index
+pub fn validate_name(name: &str) -> Result<(), InvalidIndexName> {
+    let invalid = |reason| {
+        Err(InvalidIndexName {
+            name: name.to_string(),
+            reason,
+        })
+    };
+    if name.is_empty() {
+        return invalid("it is empty");
+    }
+    if name.len() > MAX_INDEX_NAME_LEN {
+        return invalid("it is longer than 255 bytes");
+    }
+    if name.starts_with(['-', '_', '.']) {
+        return invalid("it starts with '-', '_' or '.'");
+    }
+    if !name
+        .chars()
+        .all(|c| matches!(c, 'a'..='z' | '0'..='9' | '-' | '_' | '.'))
+    {
+        return invalid("it may only contain lowercase letters, digits, '-', '_' and '.'");
+    }
+    Ok(())
+}
+
+/// A name can not be used for an index
+#[derive(Debug, Error)]
+#[error("{name:?} is not a valid index name, {reason}")]
+pub struct InvalidIndexName {
+    name: String,
+    reason: &'static str,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn naming_rules() {
+        for name in ["books", "books-2023.01", "a_b"] {
+            validate_name(name).unwrap();
+        }
+        for name in ["", "Books", "_books", ".", "..", "books/1", "böoks"] {
+            validate_name(name).unwrap_err();
+        }
+        validate_name(&"a".repeat(MAX_INDEX_NAME_LEN + 1)).unwrap_err();
+    }
+
+    #[test]
+    fn mappings_are_stored() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = IndexManager::new(dir.path().join("indexes"));
+        let schema = Schema::new().with_primary_key("id");
+        manager.create("books", &schema).unwrap();
+        manager.create("authors", &Schema::new()).unwrap();
+        fs::create_dir(manager.root().join("Not An Index")).unwrap();
+
+        let loaded = manager.load().unwrap();
+        let names = loaded.iter().map(|(name, _)| name).collect::<Vec<_>>();
+        assert_eq!(names, ["authors", "books"]);
+
+        manager.delete("books").unwrap();
+        assert_eq!(manager.load().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn indexes_are_spread_over_roots() {
+        let dir = tempfile::tempdir().unwrap();
+        let (first, second) = (dir.path().join("a"), dir.path().join("b"));
+        let manager = IndexManager::new(&first).with_root(&second);
+        manager.load().unwrap();
+        for name in ["w", "x", "y", "z"] {
+            manager.create(name, &Schema::new()).unwrap();
+        }
+        let usage = manager.usage();
+        assert_eq!(usage.iter().map(|u| u.indexes).collect::<Vec<_>>(), [2, 2]);
+        assert!(usage.iter().all(|u| u.used_bytes > 0));
+
+        let reopened = IndexManager::new(&first).with_root(&second);
+        assert_eq!(reopened.load().unwrap().len(), 4);
+        for name in ["w", "x", "y", "z"] {
+            assert_eq!(reopened.index_dir(name), manager.index_dir(name));
+            assert!(reopened.index_dir(name).exists());
+        }
+
+        fs::create_dir_all(first.join("w")).unwrap();
+        fs::create_dir_all(second.join("w")).unwrap();
+        reopened.load().unwrap_err();
+    }
+}
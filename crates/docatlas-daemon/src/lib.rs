@@ -1,4 +1,29 @@
+pub mod access;
+pub mod audit;
+pub mod changes;
 pub mod client;
+pub mod cluster;
 pub mod config;
 pub mod error;
+pub mod executor;
+pub mod grpc;
+pub mod handlers;
+pub mod health;
+pub mod http;
+pub mod index_manager;
+pub mod limits;
+pub mod log_rotation;
 pub mod main_loop;
+pub mod merge;
+pub mod metrics;
+pub mod pid_file;
+pub mod reload;
+pub mod replication;
+pub mod secrets;
+pub mod shutdown;
+pub mod snapshot;
+pub mod systemd;
+pub mod tls;
+pub mod trace;
+pub mod wal;
+pub mod webhook;
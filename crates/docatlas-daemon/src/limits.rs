@@ -0,0 +1,202 @@
+//! Limits on the connections and requests of clients.
+//!
+//! The daemon serves a bounded number of connections, both in total and from any single address.
+//! Requests of every connection are rate limited by a token bucket, which refills at a fixed rate
+//! and allows short bursts of requests.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use docatlas_core::transport::handshake::Rejection;
+
+/// The connections currently open, and how many may be open at once
+#[derive(Debug)]
+pub struct ConnectionLimits {
+    max: usize,
+    per_peer: usize,
+    open: Mutex<OpenConnections>,
+}
+
+#[derive(Debug, Default)]
+struct OpenConnections {
+    total: usize,
+    by_peer: HashMap<IpAddr, usize>,
+}
+
+impl ConnectionLimits {
+    /// Allows up to `max` connections, with at most `per_peer` of them from the same address
+    pub fn new(max: usize, per_peer: usize) -> Self {
+        Self {
+            max,
+            per_peer,
+            open: Mutex::default(),
+        }
+    }
+
+    /// Opens a connection from the given address, or from a local process if there is none. The
+    /// connection stays open until the returned slot is dropped.
+    pub fn try_open(self: &Arc<Self>, peer: Option<IpAddr>) -> Result<ConnectionSlot, Rejection> {
+        let mut open = self.open.lock().expect("connections poisoned");
+        if open.total >= self.max {
+            return Err(Rejection::TooManyConnections { limit: self.max });
+        }
+        if let Some(peer) = peer {
+            let from_peer = open.by_peer.entry(peer).or_default();
+            if *from_peer >= self.per_peer {
+                return Err(Rejection::TooManyConnectionsFromPeer {
+                    limit: self.per_peer,
+                });
+            }
+            *from_peer += 1;
+        }
+        open.total += 1;
+        Ok(ConnectionSlot {
+            limits: self.clone(),
+            peer,
+        })
+    }
+
+    /// Gets the number of open connections
+    pub fn open(&self) -> usize {
+        self.open.lock().expect("connections poisoned").total
+    }
+}
+
+/// An open connection, counted against the connection limits until dropped
+#[derive(Debug)]
+pub struct ConnectionSlot {
+    limits: Arc<ConnectionLimits>,
+    peer: Option<IpAddr>,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        let mut open = self.limits.open.lock().expect("connections poisoned");
+        open.total -= 1;
+        if let Some(peer) = self.peer {
+            if let Some(from_peer) = open.by_peer.get_mut(&peer) {
+                *from_peer -= 1;
+                if *from_peer == 0 {
+                    open.by_peer.remove(&peer);
+                }
+            }
+        }
+    }
+}
+
+/// How many requests a client may make per second, and in a single burst
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    per_second: f64,
+    burst: f64,
+}
+
+impl RateLimit {
+    /// Allows `per_second` requests every second, on average. Must be greater than zero.
+    pub fn new(per_second: f64) -> Self {
+        assert!(per_second > 0.0, "rate limits must allow some requests");
+        Self {
+            per_second,
+            burst: per_second.max(1.0),
+        }
+    }
+
+    /// Sets the number of requests that can be made at once after being idle. Must be at least 1.
+    pub fn with_burst(mut self, burst: u32) -> Self {
+        assert!(burst >= 1, "bursts must allow at least one request");
+        self.burst = burst as f64;
+        self
+    }
+
+    /// Gets the number of requests allowed every second
+    pub fn per_second(&self) -> f64 {
+        self.per_second
+    }
+
+    /// Gets the number of requests that can be made at once
+    pub fn burst(&self) -> u32 {
+        self.burst as u32
+    }
+}
+
+/// Rate limits the requests of a single client
+#[derive(Debug)]
+pub struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket
+    pub fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Gets the rate limit this bucket enforces
+    pub fn limit(&self) -> RateLimit {
+        self.limit
+    }
+
+    /// Takes a token for a request made at the given time, or returns how long until one is
+    /// available
+    pub fn try_take(&mut self, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.per_second).min(self.limit.burst);
+        self.refilled = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64(
+                (1.0 - self.tokens) / self.limit.per_second,
+            ))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn connections_are_capped() {
+        let limits = Arc::new(ConnectionLimits::new(3, 2));
+        let peer = Some(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        let first = limits.try_open(peer).unwrap();
+        let _second = limits.try_open(peer).unwrap();
+        assert_eq!(
+            limits.try_open(peer).unwrap_err(),
+            Rejection::TooManyConnectionsFromPeer { limit: 2 }
+        );
+        let _local = limits.try_open(None).unwrap();
+        assert_eq!(
+            limits.try_open(None).unwrap_err(),
+            Rejection::TooManyConnections { limit: 3 }
+        );
+        drop(first);
+        assert_eq!(limits.open(), 2);
+        let _third = limits.try_open(peer).unwrap();
+    }
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let mut bucket = TokenBucket::new(RateLimit::new(2.0).with_burst(3));
+        let start = Instant::now();
+        for _ in 0..3 {
+            bucket.try_take(start).unwrap();
+        }
+        let retry_after = bucket.try_take(start).unwrap_err();
+        assert_eq!(retry_after, Duration::from_millis(500));
+        assert!(bucket.try_take(start + Duration::from_millis(250)).is_err());
+        bucket.try_take(start + Duration::from_millis(500)).unwrap();
+    }
+}
@@ -0,0 +1,180 @@
+//! Rotates log files once they grow too large or too old.
+//!
+//! A rotated log file is renamed with the suffix `.1`, and older files are shifted up to the
+//! suffix of the retention count, past which they are deleted.
+
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+/// The default size log files are rotated at
+pub const DEFAULT_LOG_MAX_BYTES: u64 = 64 * 1024 * 1024;
+/// The default number of rotated log files to keep
+pub const DEFAULT_LOG_RETENTION: usize = 5;
+
+/// When log files are rotated, and how many rotated files are kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rotation {
+    max_bytes: Option<u64>,
+    interval: Option<Duration>,
+    retention: usize,
+}
+
+impl Default for Rotation {
+    fn default() -> Self {
+        Self {
+            max_bytes: Some(DEFAULT_LOG_MAX_BYTES),
+            interval: None,
+            retention: DEFAULT_LOG_RETENTION,
+        }
+    }
+}
+
+impl Rotation {
+    /// Rotates files once they hold the given number of bytes, or never if `None`
+    pub fn with_max_bytes(mut self, max_bytes: Option<u64>) -> Self {
+        self.max_bytes = max_bytes;
+        self
+    }
+
+    /// Rotates files once they have been written to for the given amount of time, or never if
+    /// `None`
+    pub fn with_interval(mut self, interval: Option<Duration>) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Sets the number of rotated files to keep
+    pub fn with_retention(mut self, retention: usize) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Gets the size files are rotated at, if any
+    pub fn max_bytes(&self) -> Option<u64> {
+        self.max_bytes
+    }
+
+    /// Gets how long files are written to before being rotated, if they are rotated over time
+    pub fn interval(&self) -> Option<Duration> {
+        self.interval
+    }
+
+    /// Gets the number of rotated files to keep
+    pub fn retention(&self) -> usize {
+        self.retention
+    }
+}
+
+/// A log file that is appended to and rotated. Records are never split between two files, as
+/// files are only rotated before a write.
+#[derive(Debug)]
+pub struct RotatingFile {
+    path: PathBuf,
+    rotation: Rotation,
+    file: File,
+    written: u64,
+    opened: SystemTime,
+}
+
+impl RotatingFile {
+    /// Opens the log file at the given path for appending, creating it if needed
+    pub fn open(path: impl AsRef<Path>, rotation: Rotation) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let metadata = file.metadata()?;
+        Ok(Self {
+            path,
+            rotation,
+            file,
+            written: metadata.len(),
+            opened: metadata.created().unwrap_or_else(|_| SystemTime::now()),
+        })
+    }
+
+    /// Gets the path of a rotated log file, where `1` is the most recently rotated
+    pub fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut name = self.path.as_os_str().to_owned();
+        name.push(format!(".{index}"));
+        PathBuf::from(name)
+    }
+
+    /// Checks if the file is due to be rotated
+    fn due(&self, now: SystemTime) -> bool {
+        let too_large = self
+            .rotation
+            .max_bytes
+            .is_some_and(|max| self.written > 0 && self.written >= max);
+        let too_old = self.rotation.interval.is_some_and(|interval| {
+            now.duration_since(self.opened)
+                .is_ok_and(|age| age >= interval)
+        });
+        too_large || too_old
+    }
+
+    /// Shifts every rotated file up by one, dropping the oldest, and starts a new file
+    fn rotate(&mut self, now: SystemTime) -> io::Result<()> {
+        self.file.flush()?;
+        let retention = self.rotation.retention;
+        if retention == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..retention).rev() {
+                match fs::rename(self.rotated_path(index), self.rotated_path(index + 1)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => {}
+                }
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.written = 0;
+        self.opened = now;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let now = SystemTime::now();
+        if self.due(now) {
+            self.rotate(now)?;
+        }
+        let written = self.file.write(buf)?;
+        self.written += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotate_by_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("docatlas.log");
+        let rotation = Rotation::default()
+            .with_max_bytes(Some(4))
+            .with_retention(2);
+        let mut file = RotatingFile::open(&path, rotation).unwrap();
+        for line in ["one\n", "two\n", "three\n", "four\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        file.flush().unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "four\n");
+        assert_eq!(fs::read_to_string(file.rotated_path(1)).unwrap(), "three\n");
+        assert_eq!(fs::read_to_string(file.rotated_path(2)).unwrap(), "two\n");
+        assert!(!file.rotated_path(3).exists());
+    }
+}
@@ -1,25 +1,2542 @@
 //! Contains the main loop
 
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::access::{self, Access, Caller};
+use crate::audit::{AuditAction, AuditLog, AuditQuery};
+use crate::changes::{ChangeFeed, ChangeFilter, ChangesError};
 use crate::client;
-use crate::client::Client;
-use futures::StreamExt;
-use log::info;
+use crate::client::{Client, ClientRequest, ClientResponse, Credentials, ScanFilter, Secret};
+use crate::cluster::Cluster;
+use crate::executor::Executor;
+use crate::handlers::{HandlerError, Indexes};
+use crate::health::Health;
+use crate::index_manager::IndexManager;
+use crate::limits::{ConnectionLimits, TokenBucket};
+use crate::merge::MergeScheduler;
+use crate::metrics::DaemonMetrics;
+use crate::reload::{self, DynamicSettings};
+use crate::replication::{self, Replica, ReplicationLog};
+use crate::shutdown::{self, LastShutdown};
+use crate::snapshot::s3::S3Client;
+use crate::snapshot::{self, Snapshots};
+use crate::systemd::{self, ActivatedSockets, LISTENERS};
+use crate::{grpc, http};
+use docatlas_core::auth::authentication::{
+    AuthenticationError, AuthenticationService, AuthenticationToolchain, JwtAuthenticationService,
+    LoginKey, PasswordAuthenticationService, SessionService, SessionToken,
+    TokenAuthenticationService,
+};
+use docatlas_core::auth::authorization::{
+    AuthorizationService, Authorizer, FieldAccess, Permission, Resource,
+};
+use docatlas_core::auth::users::{Groups, User};
+use docatlas_core::cancel::{CancelToken, Cancelled};
+use docatlas_core::error::DocatlasError;
+use docatlas_core::query::string::{Operator, QueryString, QueryStringError};
+use docatlas_core::query::Query;
+use docatlas_core::transport::handshake::{self, ServerCapabilities};
+use docatlas_core::transport::metrics::Metered;
+use docatlas_core::transport::mux::Envelope;
+use docatlas_core::transport::queue::{QueueConfig, QueueError, SendQueue};
+use docatlas_core::transport::{LocalSocketTransport, TcpTransport, Transport, WebSocketTransport};
+use futures::future::{select, Either};
+use futures::pin_mut;
+use interprocess::local_socket::tokio::LocalSocketListener;
+use log::{info, warn};
+use secrecy::ExposeSecret;
 use tokio::net::TcpListener;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task;
+use tokio_util::sync::CancellationToken;
+use tonic::transport::server::TcpIncoming;
+use tracing::Instrument;
 
 use crate::config::DaemonConfig;
 use crate::error::DaemonError;
+use crate::tls::TlsError;
 
-pub async fn main_loop(config: &DaemonConfig) -> Result<(), DaemonError> {
-    let listener = TcpListener::bind((config.host(), config.port())).await?;
+/// Runs the daemon until it is shut down. Settings given on the command line are kept when the
+/// config file is reloaded.
+pub async fn main_loop(
+    config: &DaemonConfig,
+    command_line: &DaemonConfig,
+) -> Result<(), DaemonError> {
+    if let Some(tls) = config.tls()? {
+        tls.validate()?;
+        // no listener serves tls, and clients expecting it must not be served in plaintext
+        return Err(TlsError::NotServed.into());
+    }
+    if shutdown::mark_running(config.path())? == LastShutdown::Unclean {
+        warn!("the daemon was not shut down cleanly the last time it ran");
+    }
+    let mut activated = ActivatedSockets::from_env()?;
+    let listener = TcpListener::from_std(bind_tcp(&mut activated, "tcp", config.port(), config)?)?;
+    let ws_listener = TcpListener::from_std(bind_tcp(
+        &mut activated,
+        "websocket",
+        config.websocket_port(),
+        config,
+    )?)?;
+    let local_listener = bind_local_socket(config)?;
+    let http_listener = bind_tcp(&mut activated, "http", config.http_port(), config)?;
+    let grpc_listener = TcpListener::from_std(bind_tcp(
+        &mut activated,
+        "grpc",
+        config.grpc_port(),
+        config,
+    )?)?;
+    let replication_listener = match config.replication_port() {
+        Some(port) => Some(TcpListener::from_std(bind_tcp(
+            &mut activated,
+            "replication",
+            port,
+            config,
+        )?)?),
+        None => None,
+    };
+    let cluster_listener = match config.cluster_port() {
+        Some(port) => Some(TcpListener::from_std(bind_tcp(
+            &mut activated,
+            "cluster",
+            port,
+            config,
+        )?)?),
+        None => None,
+    };
+    for name in activated.unused() {
+        warn!("ignoring socket {name:?} passed by systemd, expected one of {LISTENERS:?}");
+    }
 
-    while let Ok((mut stream, socket)) = listener.accept().await {
-        tokio::spawn(async move {
-            info!("new client connected at {socket}");
-            let (stream, sink) = stream.into_split();
-            let mut stream = client::wrap_async_read(stream);
-            let x = stream.next().await;
-            // let client = Client::new(stream, sink);
-        });
+    let stop = CancellationToken::new();
+    let deadline = config.shutdown_timeout();
+    // every connection holds a sender, so the receiver closes once they are all done
+    let (open, mut closed) = mpsc::channel::<()>(1);
+    let auth = match config.require_auth() {
+        true => Some(Arc::new(auth_toolchain(config)?)),
+        false => None,
+    };
+    let anonymous = config.anonymous_user().map(Arc::new);
+    if let Some(anonymous) = &anonymous {
+        info!(
+            "clients that did not authenticate have the roles {:?}",
+            anonymous.roles()
+        );
+    }
+    let authorization_service = match config.authorization_service()? {
+        Some(service) => {
+            info!(
+                "asking {} whether users may do what they request",
+                service.url()
+            );
+            Some(Arc::new(service) as Arc<dyn AuthorizationService>)
+        }
+        None => None,
+    };
+    let mut snapshots = Snapshots::new(config.snapshot_path());
+    if let Some(s3) = config.snapshot_s3()? {
+        info!(
+            "storing snapshots in bucket {:?} of {}",
+            s3.bucket(),
+            s3.endpoint()
+        );
+        snapshots = snapshots.with_s3(S3Client::new(s3, tokio::runtime::Handle::current()));
+    }
+    let groups = Arc::new(Groups::open(config.groups_store())?);
+    let authorizer = Arc::new(config.authorizer()?.with_group_store(groups.clone()));
+    let sessions = Arc::new(SessionService::new(
+        config.session_ttl(),
+        config.session_idle_timeout(),
+    ));
+    let audit = match config.audit_log() {
+        Some(path) => {
+            let audit = AuditLog::open(path)?;
+            match audit.verify() {
+                Ok(entries) => info!("auditing changes in {path:?}, after {entries} entries"),
+                Err(e) => warn!("auditing changes in {path:?}, which can not be trusted: {e}"),
+            }
+            Some(Arc::new(audit))
+        }
+        None => None,
+    };
+    // the http and grpc apis authenticate their clients like connections do
+    let mut access = Access::new(sessions.clone(), authorizer.clone());
+    if let Some(auth) = &auth {
+        access = access.with_auth(auth.clone());
+    }
+    if let Some(anonymous) = &anonymous {
+        access = access.with_anonymous(anonymous.clone());
     }
+    if let Some(service) = &authorization_service {
+        access = access.with_authorization_service(service.clone());
+    }
+    if let Some(audit) = &audit {
+        access = access.with_audit(audit.clone());
+    }
+    let access = Arc::new(access);
+    let mut paths = config.indexes_paths().into_iter();
+    let first = paths.next().expect("there is always a data path");
+    let manager = paths.fold(IndexManager::new(first), IndexManager::with_root);
+    let mut indexes = Indexes::open(manager)?.with_commit_window(config.wal_commit_window());
+    for recovery in indexes.recovery() {
+        match recovery.failed > 0 || recovery.rolled_back > 0 {
+            true => warn!("recovered {recovery}"),
+            false => info!("recovered {recovery}"),
+        }
+    }
+    if replication_listener.is_some() {
+        let log = ReplicationLog::new(config.replication_backlog());
+        indexes = indexes.with_replication_log(Arc::new(log));
+    }
+    let indexes = indexes.with_change_feed(Arc::new(ChangeFeed::new(config.changes_backlog())));
+    let indexes = Arc::new(indexes);
+    let replica = match config.replica_of() {
+        Some(primary) => {
+            let replica = Replica::new(primary);
+            Some(Arc::new(match config.replica_user() {
+                Some((username, password)) => {
+                    let password = password.read().map_err(|e| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            format!("invalid replica_password: {e}"),
+                        )
+                    })?;
+                    replica.with_credentials(Credentials::Basic {
+                        username: username.to_string(),
+                        password: Secret::new(password.expose_secret()),
+                    })
+                }
+                None => replica,
+            }))
+        }
+        None => None,
+    };
+    let executor = Arc::new(Executor::new(config.search_threads())?);
+    let merges = MergeScheduler::new(config.max_merges())
+        .with_throttle(config.merge_throttle())
+        .with_load(executor.clone());
+    let mut metrics = DaemonMetrics::new().with_executor(executor.clone());
+    if let Some(replica) = &replica {
+        info!("replicating the indexes of {}", replica.primary());
+        indexes.set_read_only(true);
+        metrics = metrics.with_replica(replica.clone());
+    }
+    let metrics = Arc::new(metrics);
+    let cluster = Arc::new(Cluster::new(config, indexes.clone(), replica.clone()));
+    let health = Arc::new(Health::new(config.path()));
+    let settings = Arc::new(DynamicSettings::new(config));
+    let shared = Shared {
+        capabilities: client::capabilities(config),
+        queue: config.send_queue(),
+        max_request_timeout: config.max_request_timeout(),
+        indexes: indexes.clone(),
+        executor: executor.clone(),
+        metrics: metrics.clone(),
+        limits: Arc::new(ConnectionLimits::new(
+            config.max_connections(),
+            config.max_connections_per_ip(),
+        )),
+        settings: settings.clone(),
+        auth,
+        anonymous,
+        sessions,
+        authorizer,
+        authorization_service,
+        groups,
+        audit: audit.clone(),
+        health: health.clone(),
+        snapshots: Arc::new(snapshots),
+        cluster: cluster.clone(),
+        shutdown: stop.clone(),
+        _open: open,
+    };
+    let signal = async {
+        match shutdown::signal().await {
+            Ok(()) => info!("shutting down, giving requests in flight {deadline:?} to finish"),
+            Err(e) => warn!("shutting down, could not listen for signals: {e}"),
+        }
+        health.mark_shutting_down();
+        systemd::notify_stopping();
+        stop.cancel();
+    };
+    let reload = reload::reload_on_hangup(config, command_line, &settings, audit.as_deref(), &stop);
+    let tcp = {
+        let shared = shared.clone();
+        async move {
+            while let Some(Ok((stream, socket))) =
+                shutdown::until_shutdown(&shared.shutdown, listener.accept()).await
+            {
+                let transport = TcpTransport::new(stream);
+                tokio::spawn(serve(transport, socket, Some(socket.ip()), shared.clone()));
+            }
+        }
+    };
+    let ws = {
+        let shared = shared.clone();
+        async move {
+            while let Some(Ok((stream, socket))) =
+                shutdown::until_shutdown(&shared.shutdown, ws_listener.accept()).await
+            {
+                let shared = shared.clone();
+                tokio::spawn(async move {
+                    match WebSocketTransport::accept(stream).await {
+                        Ok(transport) => serve(transport, socket, Some(socket.ip()), shared).await,
+                        Err(e) => warn!("websocket handshake with {socket} failed: {e}"),
+                    }
+                });
+            }
+        }
+    };
+    let local = {
+        let shared = shared.clone();
+        async move {
+            while let Some(Ok(stream)) =
+                shutdown::until_shutdown(&shared.shutdown, local_listener.accept()).await
+            {
+                let transport = LocalSocketTransport::new(stream);
+                let peer = match transport.peer_id() {
+                    Some(pid) => format!("local process {pid}"),
+                    None => "unknown local process".to_string(),
+                };
+                tokio::spawn(serve(transport, peer, None, shared.clone()));
+            }
+        }
+    };
+    let replicas = {
+        let capabilities = replication::capabilities(config);
+        let shared = shared.clone();
+        let access = access.clone();
+        async move {
+            let Some(listener) = replication_listener else {
+                return;
+            };
+            if let Ok(address) = listener.local_addr() {
+                info!("accepting replicas at {address}");
+            }
+            while let Some(Ok((stream, socket))) =
+                shutdown::until_shutdown(&shared.shutdown, listener.accept()).await
+            {
+                let capabilities = capabilities.clone();
+                let shared = shared.clone();
+                let access = access.clone();
+                tokio::spawn(async move {
+                    info!("replica connected at {socket}");
+                    let transport = TcpTransport::new(stream);
+                    let indexes = shared.indexes.clone();
+                    match replication::serve_replica(
+                        transport,
+                        &capabilities,
+                        indexes,
+                        &access,
+                        Some(socket.ip()),
+                        &shared.shutdown,
+                    )
+                    .await
+                    {
+                        Ok(()) => info!("replica at {socket} disconnected"),
+                        Err(e) => warn!("dropping replica at {socket}: {e}"),
+                    }
+                });
+            }
+        }
+    };
+    let gossip = {
+        let capabilities = replication::capabilities(config);
+        let shared = shared.clone();
+        async move {
+            let Some(listener) = cluster_listener else {
+                return;
+            };
+            if let Ok(address) = listener.local_addr() {
+                info!(
+                    "node {:?} joining cluster {:?}, gossiping at {address}",
+                    shared.cluster.node(),
+                    shared.cluster.name()
+                );
+            }
+            let accept = async {
+                while let Some(Ok((stream, socket))) =
+                    shutdown::until_shutdown(&shared.shutdown, listener.accept()).await
+                {
+                    let capabilities = capabilities.clone();
+                    let cluster = shared.cluster.clone();
+                    tokio::spawn(async move {
+                        let transport = TcpTransport::new(stream);
+                        if let Err(e) = cluster.serve_gossip(transport, &capabilities).await {
+                            warn!("could not gossip with node at {socket}: {e}");
+                        }
+                    });
+                }
+            };
+            tokio::join!(accept, shared.cluster.run(&shared.shutdown));
+        }
+    };
+    let following = async {
+        if let Some(replica) = replica {
+            replication::follow(replica, indexes.clone(), &stop).await;
+        }
+    };
+    let merging = merges.run(indexes.clone(), &stop);
+    drop(shared);
+    // only the mappings of indexes are stored, and they were loaded when opening the indexes
+    health.mark_recovered();
+    systemd::notify_ready();
+    let connections = shutdown::until_deadline(&stop, deadline, closed.recv());
+    let http = async {
+        info!("serving the http api at {}", http_listener.local_addr()?);
+        let server = axum::Server::from_tcp(http_listener)
+            .map_err(io::Error::other)?
+            .serve(
+                http::router(
+                    indexes.clone(),
+                    executor.clone(),
+                    config.max_request_timeout(),
+                    metrics.clone(),
+                    health.clone(),
+                    cluster.clone(),
+                    access.clone(),
+                )
+                .into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .with_graceful_shutdown(stop.cancelled());
+        match shutdown::until_deadline(&stop, deadline, server).await {
+            Some(served) => served.map_err(io::Error::other),
+            None => {
+                warn!("http requests in flight did not finish in time");
+                Ok(())
+            }
+        }
+    };
+    let grpc = async {
+        info!("serving grpc at {}", grpc_listener.local_addr()?);
+        let incoming =
+            TcpIncoming::from_listener(grpc_listener, true, None).map_err(io::Error::other)?;
+        let server = grpc::router(
+            indexes.clone(),
+            executor.clone(),
+            config.max_request_timeout(),
+            access.clone(),
+        )
+        .serve_with_incoming_shutdown(incoming, stop.cancelled());
+        match shutdown::until_deadline(&stop, deadline, server).await {
+            Some(served) => served.map_err(io::Error::other),
+            None => {
+                warn!("grpc requests in flight did not finish in time");
+                Ok(())
+            }
+        }
+    };
+    let (_, _, _, _, _, _, _, _, _, connections, http, grpc) = tokio::join!(
+        signal,
+        reload,
+        tcp,
+        ws,
+        local,
+        replicas,
+        gossip,
+        following,
+        merging,
+        connections,
+        http,
+        grpc
+    );
+    if connections.is_none() {
+        warn!("client requests in flight did not finish in time");
+    }
+    http?;
+    grpc?;
+
+    indexes.flush()?;
+    shutdown::mark_clean(config.path())?;
+    info!("shut down cleanly");
     Ok(())
 }
+
+/// Creates the toolchain authenticating clients, with the admin user and any configured tokens
+fn auth_toolchain(config: &DaemonConfig) -> io::Result<AuthenticationToolchain> {
+    let hashing = config.password_hashing();
+    if let Err(e) = hashing.hasher() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid password hashing: {e}"),
+        ));
+    }
+    let mut toolchain = AuthenticationToolchain::open_with(&config.admin_store(), hashing)?
+        .with_policy(config.password_policy());
+    toolchain
+        .push(PasswordAuthenticationService::open(config.users_store())?.with_hashing(hashing));
+    if let Some(throttle) = config.login_throttle() {
+        toolchain = toolchain.with_throttle(throttle);
+    }
+    if let Some(cache) = config.auth_cache() {
+        toolchain = toolchain.with_cache(cache);
+    }
+    if let Some(path) = config.auth_tokens() {
+        let tokens = TokenAuthenticationService::read(BufReader::new(File::open(path)?))?;
+        toolchain.push(tokens);
+    }
+    let jwt = match (config.jwt_jwks(), config.jwt_secret()) {
+        (Some(path), _) => Some(JwtAuthenticationService::read_jwks(BufReader::new(
+            File::open(path)?,
+        ))?),
+        (None, Some(source)) => {
+            let secret = source.read().map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("invalid jwt_secret: {e}"),
+                )
+            })?;
+            Some(JwtAuthenticationService::with_secret(
+                secret.expose_secret().trim().as_bytes(),
+            ))
+        }
+        (None, None) => None,
+    };
+    if let Some(mut jwt) = jwt {
+        if let Some(issuer) = config.jwt_issuer() {
+            jwt = jwt.with_issuer(issuer);
+        }
+        if let Some(audience) = config.jwt_audience() {
+            jwt = jwt.with_audience(audience);
+        }
+        toolchain.push(
+            jwt.with_user_claim(config.jwt_user_claim())
+                .with_groups_claim(config.jwt_groups_claim())
+                .with_roles_claim(config.jwt_roles_claim()),
+        );
+    }
+    Ok(toolchain)
+}
+
+/// Takes the listener systemd passed to the daemon under the given name, or binds a new one on the
+/// given port
+fn bind_tcp(
+    activated: &mut ActivatedSockets,
+    name: &str,
+    port: u16,
+    config: &DaemonConfig,
+) -> io::Result<std::net::TcpListener> {
+    let listener = match activated.take(name) {
+        Some(listener) => {
+            info!("using the {name} socket passed by systemd");
+            listener
+        }
+        None => std::net::TcpListener::bind((config.host(), port))?,
+    };
+    listener.set_nonblocking(true)?;
+    Ok(listener)
+}
+
+/// Binds the local socket, replacing any socket file left behind by a previous daemon
+fn bind_local_socket(config: &DaemonConfig) -> io::Result<LocalSocketListener> {
+    let path = config.local_socket();
+    match std::fs::remove_file(&path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let listener = LocalSocketListener::bind(path.as_path())?;
+    info!("listening for local clients at {path:?}");
+    Ok(listener)
+}
+
+/// The state shared by every client connection
+#[derive(Clone)]
+struct Shared {
+    capabilities: ServerCapabilities,
+    queue: QueueConfig,
+    /// The longest any request may run
+    max_request_timeout: Duration,
+    indexes: Arc<Indexes>,
+    /// Runs requests off the threads serving connections
+    executor: Arc<Executor>,
+    metrics: Arc<DaemonMetrics>,
+    limits: Arc<ConnectionLimits>,
+    settings: Arc<DynamicSettings>,
+    /// Authenticates clients, if they are required to
+    auth: Option<Arc<AuthenticationToolchain>>,
+    /// The user clients act as until they authenticate, if they may make requests without
+    /// authenticating
+    anonymous: Option<Arc<User>>,
+    /// The sessions started by authenticated clients
+    sessions: Arc<SessionService>,
+    /// Decides what authenticated users may do
+    authorizer: Arc<Authorizer>,
+    /// Asked whether authenticated users may do what they request, in addition to their roles
+    authorization_service: Option<Arc<dyn AuthorizationService>>,
+    /// The groups users are members of, which the authorizer gives roles through
+    groups: Arc<Groups>,
+    /// Records changes made by clients, if auditing is enabled
+    audit: Option<Arc<AuditLog>>,
+    health: Arc<Health>,
+    snapshots: Arc<Snapshots>,
+    cluster: Arc<Cluster>,
+    shutdown: CancellationToken,
+    /// Held for as long as the connection is open
+    _open: mpsc::Sender<()>,
+}
+
+/// Serves a single client until it disconnects. Clients without an ip address are local processes.
+async fn serve<T, P>(mut transport: T, peer: P, ip: Option<IpAddr>, shared: Shared)
+where
+    T: Transport<Vec<u8>, Vec<u8>, Error = io::Error> + Unpin,
+    P: Display,
+{
+    let Shared {
+        capabilities,
+        queue,
+        max_request_timeout,
+        indexes,
+        executor,
+        metrics,
+        limits,
+        settings,
+        auth,
+        anonymous,
+        sessions,
+        authorizer,
+        authorization_service,
+        groups,
+        audit,
+        health,
+        snapshots,
+        cluster,
+        shutdown,
+        _open,
+    } = shared;
+    info!("new client connected at {peer}");
+    let _slot = match limits.try_open(ip) {
+        Ok(slot) => slot,
+        Err(rejection) => {
+            warn!("rejecting client at {peer}: {rejection}");
+            if let Err(e) = handshake::reject(&mut transport, rejection).await {
+                warn!("could not tell client at {peer} why it was rejected: {e}");
+            }
+            return;
+        }
+    };
+    let transport = Metered::new(transport, metrics.transport().clone());
+    let transport =
+        match client::client_transport(transport, &capabilities, metrics.transport()).await {
+            Ok((hello, transport)) => {
+                info!("client at {peer} speaks protocol {}", hello.version);
+                transport
+            }
+            Err(e) => {
+                warn!("handshake with client at {peer} failed: {e}");
+                return;
+            }
+        };
+    let (mut client, writer) = Client::new(Box::pin(transport), queue);
+    let peer = peer.to_string();
+    let connection = metrics.connected(&peer, client.responses().clone());
+    let running = Running::default();
+    // a request holds a permit until its response is queued, bounding the requests in flight
+    let permits = Arc::new(Semaphore::new(queue.capacity()));
+    // owns the client, so that its responses are flushed once it finishes while shutting down
+    let reader = {
+        let running = running.clone();
+        let peer = peer.clone();
+        let mut bucket = settings.rate_limit().map(TokenBucket::new);
+        let mut user: Option<Arc<User>> = None;
+        // the token of the session the connection is authenticated by
+        let mut session: Option<String> = None;
+        async move {
+            loop {
+                let request = match shutdown::until_shutdown(&shutdown, client.poll_request()).await
+                {
+                    Some(Some(Ok(request))) => request,
+                    Some(Some(Err(e))) => {
+                        warn!("dropping client at {peer}: {e}");
+                        return false;
+                    }
+                    Some(None) => return false,
+                    None => break,
+                };
+                let id = request.id();
+                let (options, body) = request.into_body().into_options();
+                let trace = options.trace;
+                // the body may hold documents and passwords, so only what the request needs and its
+                // size in the default wire format are logged
+                let resource = Resource::of(body.index());
+                let size = postcard::experimental::serialized_size(&body).unwrap_or_default();
+                info!(
+                    "received request {id} from {peer} in trace {trace}: {} on {resource:?}, \
+                     {size} bytes",
+                    body.permission()
+                );
+                if let ClientRequest::Cancel { id: target } = &body {
+                    let cancel = running.lock().expect("poisoned").get(target).cloned();
+                    if let Some(cancel) = &cancel {
+                        cancel.cancel();
+                    }
+                    let response = ClientResponse::CancelRequested {
+                        running: cancel.is_some(),
+                    };
+                    if !respond(client.responses(), id, response, &peer).await {
+                        return false;
+                    }
+                    continue;
+                }
+                if let ClientRequest::Health = &body {
+                    let response = ClientResponse::Health {
+                        readiness: health.readiness(),
+                    };
+                    if !respond(client.responses(), id, response, &peer).await {
+                        return false;
+                    }
+                    continue;
+                }
+                // the rate limit may have been changed by reloading the config
+                let rate_limit = settings.rate_limit();
+                if bucket.as_ref().map(TokenBucket::limit) != rate_limit {
+                    bucket = rate_limit.map(TokenBucket::new);
+                }
+                if let Some(Err(retry_after)) = bucket.as_mut().map(|b| b.try_take(Instant::now()))
+                {
+                    let response = ClientResponse::RateLimited {
+                        retry_after_ms: retry_after.as_millis() as u64,
+                    };
+                    if !respond(client.responses(), id, response, &peer).await {
+                        return false;
+                    }
+                    continue;
+                }
+                if let ClientRequest::Auth { credentials } = &body {
+                    // resuming a session does not start another one
+                    let resumed = match credentials {
+                        Credentials::Token { token } => sessions
+                            .authenticate(&credentials.request())
+                            .ok()
+                            .map(|user| (user, token.expose().to_string())),
+                        Credentials::Basic { .. } => None,
+                    };
+                    let response = match (&auth, resumed) {
+                        (Some(_), Some((resumed, token))) => {
+                            let name = resumed.name().to_string();
+                            info!("client at {peer} resumed a session of {name:?}");
+                            user = Some(Arc::new(resumed));
+                            session = Some(token);
+                            ClientResponse::Authenticated { user: name }
+                        }
+                        (Some(auth), None) => {
+                            let audit = audit.as_deref();
+                            match authenticate(auth, credentials, ip, audit, &peer).await {
+                                Ok(authenticated) => {
+                                    let name = authenticated.name().to_string();
+                                    info!("client at {peer} authenticated as {name:?}");
+                                    if authenticated.must_change_password() {
+                                        warn!("{name:?} must change their password");
+                                    }
+                                    let started = sessions.issue(&authenticated);
+                                    user = Some(Arc::new(authenticated));
+                                    session = Some(started.token.clone());
+                                    session_started(name, started)
+                                }
+                                Err(message) => ClientResponse::Unauthenticated { message },
+                            }
+                        }
+                        (None, _) => ClientResponse::Failed {
+                            error: DocatlasError::Conflict(
+                                "authentication is not enabled on this daemon".to_string(),
+                            ),
+                        },
+                    };
+                    if !respond(client.responses(), id, response, &peer).await {
+                        return false;
+                    }
+                    continue;
+                }
+                if let Some(token) = &session {
+                    if sessions.validate(token).is_none() {
+                        info!("the session of client at {peer} expired or was revoked");
+                        session = None;
+                        user = None;
+                    }
+                }
+                if auth.is_some() && user.is_none() && anonymous.is_none() {
+                    let response = ClientResponse::Unauthenticated {
+                        message: "authenticate before making any other request".to_string(),
+                    };
+                    if !respond(client.responses(), id, response, &peer).await {
+                        return false;
+                    }
+                    continue;
+                }
+
+                if let ClientRequest::Logout = &body {
+                    let response = match session.take() {
+                        Some(token) => {
+                            sessions.revoke(&token);
+                            user = None;
+                            ClientResponse::Acknowledged
+                        }
+                        None => ClientResponse::Failed {
+                            error: DocatlasError::Unauthenticated(
+                                "the connection is not authenticated by a session".to_string(),
+                            ),
+                        },
+                    };
+                    if !respond(client.responses(), id, response, &peer).await {
+                        return false;
+                    }
+                    continue;
+                }
+
+                if let ClientRequest::ChangePassword { current, password } = &body {
+                    let response = match (&auth, &user) {
+                        (Some(auth), Some(authenticated)) => {
+                            let name = authenticated.name().to_string();
+                            let changed = change_password(auth, &name, current, password).await;
+                            if let Some(audit) = &audit {
+                                let action = AuditAction::PasswordChanged { user: name.clone() };
+                                let error = changed.as_ref().err().map(ToString::to_string);
+                                if let Err(e) =
+                                    audit.record(Some(&name), Some(&peer), action, error)
+                                {
+                                    warn!("could not audit password change of {name:?}: {e}");
+                                }
+                            }
+                            match changed {
+                                Ok(changed) => {
+                                    info!("{name:?} changed their password");
+                                    // sessions started with the old password end
+                                    sessions.revoke_user(&name);
+                                    let started = sessions.issue(&changed);
+                                    user = Some(Arc::new(changed));
+                                    session = Some(started.token.clone());
+                                    session_started(name, started)
+                                }
+                                Err(error) => ClientResponse::Failed { error },
+                            }
+                        }
+                        _ => ClientResponse::Failed {
+                            error: DocatlasError::Conflict(
+                                "authentication is not enabled on this daemon".to_string(),
+                            ),
+                        },
+                    };
+                    if !respond(client.responses(), id, response, &peer).await {
+                        return false;
+                    }
+                    continue;
+                }
+                if let Some(expired) = user.as_ref().filter(|user| user.must_change_password()) {
+                    let response = ClientResponse::Failed {
+                        error: HandlerError::PasswordExpired(expired.name().to_string()).into(),
+                    };
+                    if !respond(client.responses(), id, response, &peer).await {
+                        return false;
+                    }
+                    continue;
+                }
+
+                // indexes on other nodes are not served here
+                if let Some(response) = cluster.route(&body) {
+                    if !respond(client.responses(), id, response, &peer).await {
+                        return false;
+                    }
+                    continue;
+                }
+
+                let permit = permits
+                    .clone()
+                    .acquire_owned()
+                    .await
+                    .expect("permits are never closed");
+                let timeout = options.timeout.map_or(max_request_timeout, |timeout| {
+                    timeout.min(max_request_timeout)
+                });
+                // subscriptions run until they are cancelled
+                let subscription = match &body {
+                    ClientRequest::Subscribe {
+                        index,
+                        after,
+                        filter,
+                    } => Some((index.clone(), *after, filter.clone())),
+                    _ => None,
+                };
+                let cancel = match subscription {
+                    Some(_) => CancelToken::new(),
+                    None => CancelToken::new().with_deadline(Instant::now() + timeout),
+                };
+                let partial = options.partial;
+                running.lock().expect("poisoned").insert(id, cancel.clone());
+                let indexes = indexes.clone();
+                let executor = executor.clone();
+                let snapshots = snapshots.clone();
+                let cluster = cluster.clone();
+                let responses = client.responses().clone();
+                let running = running.clone();
+                let peer = peer.clone();
+                let user = user.clone().or_else(|| anonymous.clone());
+                let authorizer = authorizer.clone();
+                let subscriber = Subscriber {
+                    user: user.clone(),
+                    authorizer: authorizer.clone(),
+                    authorization_service: authorization_service.clone(),
+                    session: session.clone().map(|token| (sessions.clone(), token)),
+                };
+                let authorization_service = authorization_service.clone();
+                let sessions = sessions.clone();
+                let shutdown = shutdown.clone();
+                let auth = auth.clone();
+                let groups = groups.clone();
+                let audit = audit.clone();
+                let span = trace.span();
+                span.record("request_id", id);
+                span.record("index", body.index());
+                tokio::spawn(
+                    async move {
+                        let started = Instant::now();
+                        let index = body.index().map(str::to_string);
+                        let token = cancel.clone();
+                        let client = peer.clone();
+                        let stream_indexes = indexes.clone();
+                        // the service is asked before the request runs, as it answers over the
+                        // network
+                        let denied = match (&authorization_service, &user) {
+                            (Some(service), Some(user)) => {
+                                let resource = Resource::of(body.index());
+                                access::authorize_externally(
+                                    service.as_ref(),
+                                    user,
+                                    body.permission(),
+                                    resource,
+                                )
+                                .await
+                                .err()
+                            }
+                            _ => None,
+                        };
+                        let response = executor
+                            .run(move || {
+                                let cluster = Some(cluster.as_ref());
+                                let user = user.as_deref();
+                                let caller = user.map(|user| Caller {
+                                    user,
+                                    authorizer: &authorizer,
+                                    denied: denied.as_ref(),
+                                });
+                                if let ClientRequest::AuditLog { query } = &body {
+                                    return audit_entries(audit.as_deref(), query, caller);
+                                }
+                                let action = AuditAction::of(&body);
+                                let response = match &body {
+                                    ClientRequest::RevokeSessions { user } => {
+                                        revoke_sessions(&sessions, user, caller)
+                                    }
+                                    ClientRequest::CreateUser { .. }
+                                    | ClientRequest::ResetPassword { .. }
+                                    | ClientRequest::DeleteUser { .. }
+                                    | ClientRequest::ListUsers => {
+                                        manage_users(auth.as_deref(), &sessions, &body, caller)
+                                    }
+                                    ClientRequest::CreateGroup { .. }
+                                    | ClientRequest::DeleteGroup { .. }
+                                    | ClientRequest::AddGroupMember { .. }
+                                    | ClientRequest::RemoveGroupMember { .. }
+                                    | ClientRequest::SetGroupRoles { .. }
+                                    | ClientRequest::ListGroups => {
+                                        manage_groups(&groups, &body, caller)
+                                    }
+                                    _ => handle(
+                                        &indexes, &snapshots, cluster, body, &token, partial,
+                                        caller,
+                                    ),
+                                };
+                                if let (Some(audit), Some(action)) = (&audit, action) {
+                                    let error = match &response {
+                                        ClientResponse::Error { message } => Some(message.clone()),
+                                        ClientResponse::Failed { error } => Some(error.to_string()),
+                                        ClientResponse::Cancelled
+                                        | ClientResponse::TimedOut { .. } => {
+                                            Some("cancelled".to_string())
+                                        }
+                                        _ => None,
+                                    };
+                                    access::audit(audit, user, Some(&client), action, error);
+                                }
+                                response
+                            })
+                            .await
+                            .unwrap_or_else(|e| ClientResponse::Failed {
+                                error: DocatlasError::Internal(e.to_string()),
+                            });
+                        // requests stopped by their deadline rather than the client time out
+                        let response = match response {
+                            ClientResponse::Cancelled if cancel.is_expired() => {
+                                ClientResponse::TimedOut {
+                                    after_ms: timeout.as_millis() as u64,
+                                }
+                            }
+                            response => response,
+                        };
+                        let response = match (subscription, response) {
+                            (
+                                Some((index, after, filter)),
+                                ClientResponse::Subscribed { log, last },
+                            ) => {
+                                // streaming does not hold up the requests read after it
+                                drop(permit);
+                                let subscribed = ClientResponse::Subscribed { log, last };
+                                if !respond_chunk(&responses, id, subscribed, &peer).await {
+                                    running.lock().expect("poisoned").remove(&id);
+                                    return;
+                                }
+                                let subscription = Subscription {
+                                    index,
+                                    log,
+                                    after: after.unwrap_or(last),
+                                    filter,
+                                };
+                                let stream = stream_changes(
+                                    &stream_indexes,
+                                    subscription,
+                                    &subscriber,
+                                    &responses,
+                                    id,
+                                    &cancel,
+                                    &peer,
+                                );
+                                let response = shutdown::until_shutdown(&shutdown, stream)
+                                    .await
+                                    .unwrap_or(Some(ClientResponse::Cancelled));
+                                running.lock().expect("poisoned").remove(&id);
+                                if let Some(response) = response {
+                                    respond(&responses, id, response, &peer).await;
+                                }
+                                return;
+                            }
+                            (_, response) => response,
+                        };
+                        running.lock().expect("poisoned").remove(&id);
+                        tracing::info!(
+                            request_id = id,
+                            trace_id = %trace,
+                            index = index.as_deref(),
+                            duration_ms = started.elapsed().as_millis() as u64,
+                            "handled request from {peer}"
+                        );
+                        respond(&responses, id, response, &peer).await;
+                        drop(permit);
+                    }
+                    .instrument(span),
+                );
+            }
+            // the daemon is shutting down, so the requests in flight are finished first
+            let _ = permits.acquire_many(queue.capacity() as u32).await;
+            true
+        }
+    };
+    pin_mut!(reader, writer);
+    match select(reader, writer).await {
+        Either::Left((true, writer)) => {
+            // the metrics hold on to the response queue, which would keep the writer waiting
+            drop(connection);
+            if let Err(e) = writer.await {
+                warn!("dropping client at {peer}: {e}");
+            }
+        }
+        Either::Right((Err(e), _)) => warn!("dropping client at {peer}: {e}"),
+        _ => {}
+    }
+    // nobody is left to read the responses of requests still running
+    for cancel in running.lock().expect("poisoned").values() {
+        cancel.cancel();
+    }
+    info!("client at {peer} disconnected");
+}
+
+/// The cancel tokens of the requests of a client that are still running
+type Running = Arc<Mutex<HashMap<u64, CancelToken>>>;
+
+/// A subscription to the changes made to an index
+struct Subscription {
+    index: String,
+    /// The id of the log the subscription started in
+    log: u64,
+    /// The sequence number of the last change streamed to the client
+    after: u64,
+    filter: ChangeFilter,
+}
+
+/// Who the changes to an index are streamed to. They are authorized again before every batch of
+/// changes, as their session may have ended or what they may do changed since they subscribed.
+struct Subscriber {
+    /// The user, or `None` if clients are not required to authenticate
+    user: Option<Arc<User>>,
+    authorizer: Arc<Authorizer>,
+    authorization_service: Option<Arc<dyn AuthorizationService>>,
+    /// The token of the session the client subscribed in, if any, along with the sessions
+    session: Option<(Arc<SessionService>, String)>,
+}
+
+impl Subscriber {
+    /// Checks that the subscriber may still read an index and every field the query of their
+    /// filter looks at, returning the fields of the index they may read
+    async fn authorize(
+        &self,
+        index: &str,
+        query: Option<&Query>,
+    ) -> Result<FieldAccess, HandlerError> {
+        if let Some((sessions, token)) = &self.session {
+            if sessions.validate(token).is_none() {
+                return Err(HandlerError::Unauthenticated(
+                    "the session the subscription was made in ended".to_string(),
+                ));
+            }
+        }
+        let Some(user) = &self.user else {
+            return Ok(FieldAccess::All);
+        };
+        let resource = Resource::Index(index.to_string());
+        if let Some(service) = &self.authorization_service {
+            access::authorize_externally(
+                service.as_ref(),
+                user,
+                Permission::Read,
+                resource.clone(),
+            )
+            .await?;
+        }
+        let caller = Caller {
+            user,
+            authorizer: &self.authorizer,
+            denied: None,
+        };
+        caller.authorize(Permission::Read, &resource)?;
+        if let Some(query) = query {
+            caller.authorize_query(index, query)?;
+        }
+        Ok(caller.field_access(index))
+    }
+}
+
+/// How often streams check whether they were cancelled while no changes are made
+const SUBSCRIPTION_POLL: Duration = Duration::from_millis(200);
+
+/// Streams the changes made to an index that pass the filter of a subscription, stripping every
+/// field the subscriber may not read, until the subscription is cancelled, the log of the index
+/// starts again or the subscriber may no longer read the index. Returns the response ending the
+/// stream, or `None` if the client should be dropped.
+async fn stream_changes(
+    indexes: &Arc<Indexes>,
+    mut subscription: Subscription,
+    subscriber: &Subscriber,
+    responses: &SendQueue<Envelope<ClientResponse>>,
+    id: u64,
+    cancel: &CancelToken,
+    peer: &str,
+) -> Option<ClientResponse> {
+    let failed = |error: HandlerError| {
+        Some(ClientResponse::Failed {
+            error: error.into(),
+        })
+    };
+    let Some(feed) = indexes.change_feed() else {
+        return failed(
+            ChangesError::NotCaptured {
+                index: subscription.index,
+            }
+            .into(),
+        );
+    };
+    let Some(mut published) = feed.subscribe(&subscription.index) else {
+        return failed(HandlerError::NoSuchIndex(subscription.index));
+    };
+    let query = match subscription.filter.query() {
+        Ok(query) => query,
+        Err(e) => return failed(e.into()),
+    };
+    loop {
+        // changes published while reading are read on the next pass
+        published.borrow_and_update();
+        let changes = {
+            let indexes = indexes.clone();
+            let index = subscription.index.clone();
+            let (log, after) = (subscription.log, subscription.after);
+            task::spawn_blocking(move || {
+                let (position, events) = indexes.changes(&index, Some(log), after)?;
+                Ok::<_, HandlerError>((position, events, indexes.mapping(&index)?))
+            })
+            .await
+        };
+        let (position, events, schema) = match changes {
+            Ok(Ok(changes)) => changes,
+            Ok(Err(e)) => return failed(e),
+            Err(e) => {
+                return Some(ClientResponse::Failed {
+                    error: DocatlasError::Internal(e.to_string()),
+                })
+            }
+        };
+        subscription.after = position.last;
+        let mut passed = vec![];
+        for event in events {
+            match subscription.filter.matches(&event, query.as_ref(), &schema) {
+                Ok(true) => passed.push(event),
+                Ok(false) => {}
+                Err(e) => return failed(e.into()),
+            }
+        }
+        if !passed.is_empty() {
+            let access = match subscriber
+                .authorize(&subscription.index, query.as_ref())
+                .await
+            {
+                Ok(access) => access,
+                Err(e) => return failed(e),
+            };
+            // the key of a document would tell what its primary key holds
+            let key_hidden = schema
+                .primary_key()
+                .is_some_and(|key| !access.allows(&key.name));
+            for event in &mut passed {
+                if key_hidden {
+                    event.key = None;
+                }
+                if let Some(document) = &mut event.document {
+                    access.strip(document);
+                }
+            }
+            let response = ClientResponse::Changes { events: passed };
+            if !respond_chunk(responses, id, response, peer).await {
+                return None;
+            }
+        }
+        // changes read back from the write-ahead log stop short of the log, and the rest are
+        // already published
+        let behind = feed.position(&subscription.index).is_some_and(|position| {
+            position.log == subscription.log && position.last > subscription.after
+        });
+        if behind {
+            if cancel.is_cancelled() {
+                return Some(ClientResponse::Cancelled);
+            }
+            continue;
+        }
+        while tokio::time::timeout(SUBSCRIPTION_POLL, published.changed())
+            .await
+            .is_err()
+        {
+            if cancel.is_cancelled() {
+                return Some(ClientResponse::Cancelled);
+            }
+        }
+        if cancel.is_cancelled() {
+            return Some(ClientResponse::Cancelled);
+        }
+    }
+}
+
+/// Queues a response, returning `false` if the client should be dropped
+async fn respond(
+    responses: &SendQueue<Envelope<ClientResponse>>,
+    id: u64,
+    response: ClientResponse,
+    peer: &str,
+) -> bool {
+    queue_response(responses, Envelope::new(id, response), peer).await
+}
+
+/// Queues a chunk of a response, with more chunks following it, returning `false` if the client
+/// should be dropped
+async fn respond_chunk(
+    responses: &SendQueue<Envelope<ClientResponse>>,
+    id: u64,
+    response: ClientResponse,
+    peer: &str,
+) -> bool {
+    queue_response(responses, Envelope::chunk(id, response), peer).await
+}
+
+/// Queues an envelope holding a response, returning `false` if the client should be dropped
+async fn queue_response(
+    responses: &SendQueue<Envelope<ClientResponse>>,
+    envelope: Envelope<ClientResponse>,
+    peer: &str,
+) -> bool {
+    let id = envelope.id();
+    match responses.send(envelope).await {
+        Ok(()) => true,
+        Err(QueueError::Full) => {
+            warn!(
+                "dropped response to request {id} from {peer}: {}",
+                QueueError::Full
+            );
+            true
+        }
+        Err(e) => {
+            warn!("dropping client at {peer}: {e}");
+            false
+        }
+    }
+}
+
+/// Authenticates a client off the runtime, since verifying passwords is slow. Why credentials
+/// are rejected is only logged, so clients can not tell which usernames exist.
+async fn authenticate(
+    auth: &Arc<AuthenticationToolchain>,
+    credentials: &Credentials,
+    ip: Option<IpAddr>,
+    audit: Option<&AuditLog>,
+    peer: &str,
+) -> Result<User, String> {
+    let auth = auth.clone();
+    let username = match credentials {
+        Credentials::Basic { username, .. } => Some(username.clone()),
+        Credentials::Token { .. } => None,
+    };
+    let credentials = credentials.clone();
+    let authenticated =
+        task::spawn_blocking(move || auth.authenticate_from(credentials.request(), ip)).await;
+    // clients are only told why they failed if they must wait before trying again
+    let message = match &authenticated {
+        Ok(Err(errors)) => match errors.first() {
+            Some(throttled @ AuthenticationError::Throttled { .. }) => throttled.to_string(),
+            _ => "invalid credentials".to_string(),
+        },
+        _ => "invalid credentials".to_string(),
+    };
+    if let Some(audit) = audit {
+        let (user, error) = match &authenticated {
+            Ok(Ok(user)) => (Some(user.name()), None),
+            _ => (None, Some(message.clone())),
+        };
+        let action = AuditAction::Login {
+            username: username.clone(),
+        };
+        if let Err(e) = audit.record(user, Some(peer), action, error) {
+            warn!("could not audit login of client at {peer}: {e}");
+        }
+        let locked_out = match &authenticated {
+            Ok(Err(errors)) => errors
+                .iter()
+                .filter_map(|error| match error {
+                    AuthenticationError::LockedOut(keys) => Some(keys),
+                    _ => None,
+                })
+                .flatten()
+                .collect(),
+            _ => vec![],
+        };
+        for key in locked_out {
+            let action = match key {
+                LoginKey::User(user) => AuditAction::LockedOut {
+                    username: Some(user.clone()),
+                    ip: None,
+                },
+                LoginKey::Ip(ip) => AuditAction::LockedOut {
+                    username: None,
+                    ip: Some(ip.to_string()),
+                },
+            };
+            if let Err(e) = audit.record(None, Some(peer), action, None) {
+                warn!("could not audit lockout of {key}: {e}");
+            }
+        }
+    }
+    match authenticated {
+        Ok(Ok(user)) => Ok(user),
+        Ok(Err(errors)) => {
+            let errors = errors.iter().map(ToString::to_string).collect::<Vec<_>>();
+            warn!(
+                "client at {peer} failed to authenticate: {}",
+                errors.join(" ")
+            );
+            Err(message)
+        }
+        Err(e) => {
+            warn!("authenticating client at {peer} failed: {e}");
+            Err(message)
+        }
+    }
+}
+
+/// Changes the password of a user off the runtime, since hashing passwords is slow. Returns the
+/// user, or why their password could not be changed.
+async fn change_password(
+    auth: &Arc<AuthenticationToolchain>,
+    user: &str,
+    current: &Secret,
+    password: &Secret,
+) -> Result<User, DocatlasError> {
+    let auth = auth.clone();
+    let credentials = Credentials::Basic {
+        username: user.to_string(),
+        password: current.clone(),
+    };
+    let password = password.clone();
+    task::spawn_blocking(move || auth.change_password(credentials.request(), password.expose()))
+        .await
+        .map_err(|e| DocatlasError::Internal(e.to_string()))?
+        .map_err(DocatlasError::from)
+}
+
+/// Creates the response to a client that started a session
+fn session_started(user: String, started: SessionToken) -> ClientResponse {
+    ClientResponse::SessionStarted {
+        user,
+        token: Secret::new(started.token),
+        expires_in_ms: started.expires_in.as_millis() as u64,
+    }
+}
+
+/// Strips every field the user may not read from the documents of a response
+fn strip_fields(response: &mut ClientResponse, access: &FieldAccess) {
+    match response {
+        ClientResponse::Hits { hits } | ClientResponse::Scanned { hits, .. } => {
+            for hit in hits {
+                access.strip(&mut hit.document);
+            }
+        }
+        ClientResponse::Document {
+            document: Some(document),
+        } => access.strip(document),
+        ClientResponse::Partial { response } => strip_fields(response, access),
+        _ => {}
+    }
+}
+
+/// Gets the entries of the audit log matching a query, on behalf of the client
+fn audit_entries(
+    audit: Option<&AuditLog>,
+    query: &AuditQuery,
+    caller: Option<Caller>,
+) -> ClientResponse {
+    let entries = match caller {
+        Some(caller) => caller.authorize(Permission::Manage, &Resource::Cluster),
+        None => Ok(()),
+    }
+    .and_then(|()| {
+        let audit = audit.ok_or(HandlerError::NotAudited)?;
+        Ok(audit.entries(query)?)
+    });
+    match entries {
+        Ok(entries) => ClientResponse::AuditEntries { entries },
+        Err(e) => ClientResponse::Failed { error: e.into() },
+    }
+}
+
+/// Ends every session of a user, on behalf of the client
+fn revoke_sessions(
+    sessions: &SessionService,
+    user: &str,
+    caller: Option<Caller>,
+) -> ClientResponse {
+    let authorized = match caller {
+        Some(caller) => caller.authorize(Permission::Manage, &Resource::Cluster),
+        None => Ok(()),
+    };
+    match authorized {
+        Ok(()) => ClientResponse::Revoked {
+            sessions: sessions.revoke_user(user),
+        },
+        Err(e) => ClientResponse::Failed { error: e.into() },
+    }
+}
+
+/// Adds, removes and lists users, or resets their passwords, on behalf of the client. Users whose
+/// password was reset or who were removed have every session ended.
+fn manage_users(
+    auth: Option<&AuthenticationToolchain>,
+    sessions: &SessionService,
+    request: &ClientRequest,
+    caller: Option<Caller>,
+) -> ClientResponse {
+    let managed = (|| {
+        if let Some(caller) = caller {
+            caller.authorize(Permission::Manage, &Resource::Cluster)?;
+        }
+        let auth = auth.ok_or(HandlerError::NotAuthenticating)?;
+        let response = match request {
+            ClientRequest::CreateUser { user, password } => {
+                auth.add_user(user, password.expose())?;
+                ClientResponse::Acknowledged
+            }
+            ClientRequest::ResetPassword { user, password } => {
+                auth.reset_password(user, password.expose())?;
+                ClientResponse::Revoked {
+                    sessions: sessions.revoke_user(user),
+                }
+            }
+            ClientRequest::DeleteUser { user } => {
+                auth.remove_user(user)?;
+                ClientResponse::Revoked {
+                    sessions: sessions.revoke_user(user),
+                }
+            }
+            ClientRequest::ListUsers => ClientResponse::Users {
+                names: auth.users(),
+            },
+            request => unreachable!("{request:?} does not manage users"),
+        };
+        Ok::<_, HandlerError>(response)
+    })();
+    managed.unwrap_or_else(|e| ClientResponse::Failed { error: e.into() })
+}
+
+/// Creates, changes and lists the groups users are members of, on behalf of the client. Members
+/// are given or lose the roles of a group right away, even in sessions they already started.
+fn manage_groups(
+    groups: &Groups,
+    request: &ClientRequest,
+    caller: Option<Caller>,
+) -> ClientResponse {
+    let managed = (|| {
+        if let Some(caller) = caller {
+            caller.authorize(Permission::Manage, &Resource::Cluster)?;
+        }
+        match request {
+            ClientRequest::CreateGroup { group } => groups.create(group)?,
+            ClientRequest::DeleteGroup { group } => groups.delete(group)?,
+            ClientRequest::AddGroupMember { group, user } => {
+                groups.add_member(group, user)?;
+            }
+            ClientRequest::RemoveGroupMember { group, user } => {
+                groups.remove_member(group, user)?;
+            }
+            ClientRequest::SetGroupRoles { group, roles } => {
+                groups.set_roles(group, roles.clone())?
+            }
+            ClientRequest::ListGroups => {
+                return Ok(ClientResponse::Groups {
+                    groups: groups.list(),
+                })
+            }
+            request => unreachable!("{request:?} does not manage groups"),
+        }
+        Ok::<_, HandlerError>(ClientResponse::Acknowledged)
+    })();
+    managed.unwrap_or_else(|e| ClientResponse::Failed { error: e.into() })
+}
+
+/// Handles a single request of a client, on behalf of the user the client authenticated as, if
+/// any. Requests on a single index need a permission on that index, sql statements need to be
+/// allowed to read every index they select from, and every other request needs it on the
+/// cluster. Searches and queries also need to be allowed to read every field they look at.
+/// Searches stopped by the cancel token's deadline respond with the hits found so far if
+/// `partial` is set.
+fn handle(
+    indexes: &Indexes,
+    snapshots: &Snapshots,
+    cluster: Option<&Cluster>,
+    request: ClientRequest,
+    cancel: &CancelToken,
+    partial: bool,
+    caller: Option<Caller>,
+) -> ClientResponse {
+    let authorized = match caller {
+        // sql statements are authorized for every index they read, as they are run
+        Some(_) if matches!(request, ClientRequest::Sql { .. }) => Ok(()),
+        Some(caller) => caller
+            .authorize(request.permission(), &Resource::of(request.index()))
+            .and_then(|()| match &request {
+                // searching by a field would tell what it holds
+                ClientRequest::Search { index, field, .. }
+                | ClientRequest::Scan {
+                    index,
+                    filter: Some(ScanFilter { field, .. }),
+                    ..
+                } => caller.authorize(
+                    Permission::Read,
+                    &Resource::Field {
+                        index: index.clone(),
+                        field: field.clone(),
+                    },
+                ),
+                ClientRequest::QueryString {
+                    index,
+                    query,
+                    default_field,
+                    default_operator,
+                    ..
+                } => {
+                    let query = parse_query_string(query, default_field, *default_operator)?;
+                    caller.authorize_query(index, &query)
+                }
+                ClientRequest::Subscribe { index, filter, .. } => match filter.query()? {
+                    Some(query) => caller.authorize_query(index, &query),
+                    None => Ok(()),
+                },
+                _ => Ok(()),
+            }),
+        None => Ok(()),
+    };
+    let field_access = caller
+        .zip(request.index())
+        .map(|(caller, index)| caller.field_access(index));
+    let result = authorized.and_then(|()| match request {
+        ClientRequest::CreateIndex { index, schema } => indexes
+            .create(&index, schema)
+            .map(|()| ClientResponse::Acknowledged),
+        ClientRequest::PutSchema { index, schema } => indexes
+            .put_schema(&index, schema)
+            .map(|()| ClientResponse::Acknowledged),
+        ClientRequest::IndexDocument {
+            index,
+            document,
+            partial,
+        } => indexes.upsert(&index, document, partial).map(Into::into),
+        ClientRequest::Bulk { index, documents } => indexes
+            .bulk(&index, documents)
+            .map(|items| ClientResponse::Bulk { items }),
+        ClientRequest::Transaction { index, transaction } => indexes
+            .transaction(&index, transaction)
+            .map(|written| ClientResponse::Committed { written }),
+        ClientRequest::Search {
+            index,
+            field,
+            query,
+            limit,
+        } if partial => indexes
+            .search_partial(&index, &field, &query, limit, cancel)
+            .and_then(|(hits, complete)| match complete {
+                true => Ok(ClientResponse::Hits { hits }),
+                false if cancel.is_expired() => Ok(ClientResponse::Partial {
+                    response: Box::new(ClientResponse::Hits { hits }),
+                }),
+                false => Err(Cancelled.into()),
+            }),
+        ClientRequest::Search {
+            index,
+            field,
+            query,
+            limit,
+        } => indexes
+            .search_until(&index, &field, &query, limit, cancel)
+            .map(|hits| ClientResponse::Hits { hits }),
+        ClientRequest::Scan {
+            index,
+            from,
+            limit,
+            filter,
+            at,
+        } => indexes
+            .scan(
+                &index,
+                filter
+                    .as_ref()
+                    .map(|filter| (filter.field.as_str(), filter.query.as_str())),
+                from,
+                limit,
+                at,
+            )
+            .map(|(hits, next, at)| ClientResponse::Scanned {
+                hits,
+                next,
+                at: Some(at),
+            }),
+        ClientRequest::Sql { query } => indexes
+            .sql(
+                &query,
+                cancel,
+                &|resource| match caller {
+                    Some(caller) => caller.authorize(Permission::Read, resource),
+                    None => Ok(()),
+                },
+                &|index| caller.map_or(FieldAccess::All, |caller| caller.field_access(index)),
+            )
+            .map(|table| ClientResponse::Table { table }),
+        ClientRequest::QueryString {
+            index,
+            query,
+            default_field,
+            default_operator,
+            limit,
+        } => parse_query_string(&query, &default_field, default_operator)
+            .map_err(HandlerError::from)
+            .and_then(|query| indexes.query(&index, &query, limit, cancel))
+            .map(|hits| ClientResponse::Hits { hits }),
+        ClientRequest::Get { index, key } => indexes
+            .get(&index, &key)
+            .map(|document| ClientResponse::Document { document }),
+        ClientRequest::Delete { index, key } => indexes
+            .delete(&index, &key)
+            .map(|row| ClientResponse::Deleted { row }),
+        ClientRequest::Stats { index: Some(index) } => {
+            indexes.info(&index).map(|info| ClientResponse::Stats {
+                indexes: vec![info],
+            })
+        }
+        ClientRequest::Stats { index: None } => Ok(ClientResponse::Stats {
+            indexes: indexes.infos(),
+        }),
+        ClientRequest::PathStats => Ok(ClientResponse::PathStats {
+            paths: indexes.path_usage(),
+        }),
+        ClientRequest::DeleteIndex { index } => indexes
+            .drop_index(&index)
+            .map(|()| ClientResponse::Acknowledged),
+        ClientRequest::ListIndexes => Ok(ClientResponse::Indexes {
+            // only the indexes the user may read
+            names: indexes
+                .names()
+                .into_iter()
+                .filter(|name| {
+                    caller.is_none_or(|caller| {
+                        caller
+                            .authorize(Permission::Read, &Resource::Index(name.clone()))
+                            .is_ok()
+                    })
+                })
+                .collect(),
+        }),
+        ClientRequest::GetMapping { index } => indexes
+            .mapping(&index)
+            .map(|schema| ClientResponse::Mapping { schema }),
+        ClientRequest::UpdateMapping { index, schema } => indexes
+            .update_mapping(&index, schema)
+            .map(|()| ClientResponse::Acknowledged),
+        ClientRequest::CreateSnapshot {
+            repository,
+            snapshot,
+        } => snapshots
+            .repository(&repository)
+            .map_err(HandlerError::from)
+            .and_then(|repository| snapshot::create(indexes, &*repository, &snapshot))
+            .map(|snapshot| ClientResponse::Snapshot { snapshot }),
+        ClientRequest::RestoreSnapshot {
+            repository,
+            snapshot,
+        } => snapshots
+            .repository(&repository)
+            .map_err(HandlerError::from)
+            .and_then(|repository| snapshot::restore(indexes, &*repository, &snapshot))
+            .map(|indexes| ClientResponse::Restored { indexes }),
+        ClientRequest::ListSnapshots { repository } => snapshots
+            .repository(&repository)
+            .map_err(HandlerError::from)
+            .and_then(|repository| Ok(repository.snapshots()?))
+            .map(|names| ClientResponse::Snapshots { names }),
+        ClientRequest::Subscribe {
+            index,
+            after,
+            filter,
+        } => filter
+            .query()
+            .map_err(HandlerError::from)
+            .and_then(|_| indexes.change_position(&index))
+            .and_then(|position| match after {
+                Some(after) if after > position.last => Err(ChangesError::Ahead {
+                    index,
+                    after,
+                    last: position.last,
+                }
+                .into()),
+                _ => Ok(ClientResponse::Subscribed {
+                    log: position.log,
+                    last: position.last,
+                }),
+            }),
+        ClientRequest::Promote => cluster
+            .and_then(Cluster::replica)
+            .ok_or(HandlerError::NotAReplica)
+            .and_then(|replica| replica.promote(indexes))
+            .map(|()| ClientResponse::Acknowledged),
+        ClientRequest::ClusterHealth => cluster
+            .map(Cluster::health)
+            .ok_or(HandlerError::NotClustered)
+            .map(|cluster| ClientResponse::ClusterHealth { cluster }),
+        ClientRequest::Auth { .. }
+        | ClientRequest::Logout
+        | ClientRequest::ChangePassword { .. }
+        | ClientRequest::Cancel { .. }
+        | ClientRequest::Health => {
+            unreachable!("authentication, cancel and health requests are handled when read")
+        }
+        ClientRequest::Traced { .. } | ClientRequest::Timed { .. } => {
+            unreachable!("traced and timed requests are unwrapped when read")
+        }
+        ClientRequest::AuditLog { .. }
+        | ClientRequest::RevokeSessions { .. }
+        | ClientRequest::CreateUser { .. }
+        | ClientRequest::ResetPassword { .. }
+        | ClientRequest::DeleteUser { .. }
+        | ClientRequest::ListUsers
+        | ClientRequest::CreateGroup { .. }
+        | ClientRequest::DeleteGroup { .. }
+        | ClientRequest::AddGroupMember { .. }
+        | ClientRequest::RemoveGroupMember { .. }
+        | ClientRequest::SetGroupRoles { .. }
+        | ClientRequest::ListGroups => {
+            unreachable!("audit log, session, user and group requests are handled first")
+        }
+    });
+    match result {
+        Ok(mut response) => {
+            if let Some(access) = &field_access {
+                strip_fields(&mut response, access);
+            }
+            response
+        }
+        Err(HandlerError::Cancelled(_)) => ClientResponse::Cancelled,
+        Err(e) => ClientResponse::Failed { error: e.into() },
+    }
+}
+
+/// Parses the query string of a request
+fn parse_query_string(
+    query: &str,
+    default_field: &Option<String>,
+    default_operator: Operator,
+) -> Result<Query, QueryStringError> {
+    let mut parser = QueryString::new().with_default_operator(default_operator);
+    if let Some(field) = default_field {
+        parser = parser.with_default_field(field);
+    }
+    parser.parse(query)
+}
+
+#[cfg(test)]
+mod tests {
+    use docatlas_core::auth::authentication::DEFAULT_USER;
+    use docatlas_core::auth::authorization::{AuthorizationError, Role};
+    use docatlas_core::auth::users::UserFactory;
+    use docatlas_core::document::Document;
+    use docatlas_core::fields::{Field, FieldData, FieldKind, Fields};
+    use docatlas_core::index::{Transaction, UpsertMode};
+    use docatlas_core::schema::{Schema, SchemaField};
+
+    use super::*;
+
+    fn schema(title: usize) -> Schema {
+        Schema::from_iter([
+            SchemaField {
+                name: "id".to_string(),
+                kind: FieldKind::Keyword(8),
+            },
+            SchemaField {
+                name: "title".to_string(),
+                kind: FieldKind::Text(title),
+            },
+        ])
+        .with_primary_key("id")
+    }
+
+    fn book(id: &str, title: &str) -> Document {
+        let mut fields = Fields::new();
+        for (name, kind, value) in [
+            ("id", FieldKind::Keyword(8), id),
+            ("title", FieldKind::Text(32), title),
+        ] {
+            let data = FieldData::Bytes(value.as_bytes().into());
+            fields.insert(name, Field::new(kind, [data]));
+        }
+        Document::from(fields)
+    }
+
+    #[test]
+    fn routes_requests_to_handlers() {
+        let indexes = Indexes::new();
+        let snapshots = Snapshots::new(tempfile::tempdir().unwrap().path());
+        let cancel = CancelToken::new();
+        let send = |request| handle(&indexes, &snapshots, None, request, &cancel, false, None);
+        let key = FieldData::Bytes(b"b1".as_slice().into());
+
+        assert!(matches!(
+            send(ClientRequest::CreateIndex {
+                index: "books".to_string(),
+                schema: schema(16),
+            }),
+            ClientResponse::Acknowledged
+        ));
+        assert!(matches!(
+            send(ClientRequest::PutSchema {
+                index: "books".to_string(),
+                schema: schema(32),
+            }),
+            ClientResponse::Acknowledged
+        ));
+        assert!(matches!(
+            send(ClientRequest::IndexDocument {
+                index: "books".to_string(),
+                document: book("b1", "Dune"),
+                partial: false,
+            }),
+            ClientResponse::Upserted {
+                row: 0,
+                inserted: true
+            }
+        ));
+        assert!(matches!(
+            send(ClientRequest::PutSchema {
+                index: "books".to_string(),
+                schema: schema(16),
+            }),
+            ClientResponse::Failed {
+                error: DocatlasError::Conflict(_)
+            }
+        ));
+        match send(ClientRequest::Stats { index: None }) {
+            ClientResponse::Stats { indexes } => {
+                assert_eq!(indexes.len(), 1);
+                assert_eq!(indexes[0].rows, 1);
+            }
+            response => panic!("unexpected response {response:?}"),
+        }
+        assert!(matches!(
+            send(ClientRequest::Get {
+                index: "books".to_string(),
+                key: key.clone(),
+            }),
+            ClientResponse::Document { document: Some(_) }
+        ));
+        assert!(matches!(
+            send(ClientRequest::IndexDocument {
+                index: "books".to_string(),
+                document: book("b2", "Emma"),
+                partial: false,
+            }),
+            ClientResponse::Upserted { row: 1, .. }
+        ));
+        let started = match send(ClientRequest::Scan {
+            index: "books".to_string(),
+            from: 0,
+            limit: 1,
+            filter: None,
+            at: None,
+        }) {
+            ClientResponse::Scanned { hits, next, at } => {
+                assert_eq!(hits.len(), 1);
+                assert_eq!(next, Some(1));
+                at
+            }
+            response => panic!("unexpected response {response:?}"),
+        };
+        assert!(matches!(
+            send(ClientRequest::Delete {
+                index: "books".to_string(),
+                key: key.clone(),
+            }),
+            ClientResponse::Deleted { row: Some(0) }
+        ));
+        assert!(matches!(
+            send(ClientRequest::Get {
+                index: "books".to_string(),
+                key,
+            }),
+            ClientResponse::Document { document: None }
+        ));
+        // the row of the deleted document is skipped
+        match send(ClientRequest::Scan {
+            index: "books".to_string(),
+            from: 0,
+            limit: 10,
+            filter: None,
+            at: None,
+        }) {
+            ClientResponse::Scanned { hits, next, .. } => {
+                assert_eq!(hits.iter().map(|hit| hit.row).collect::<Vec<_>>(), [1]);
+                assert_eq!(next, None);
+            }
+            response => panic!("unexpected response {response:?}"),
+        }
+        // scans continued at the sequence number they started at still see it
+        match send(ClientRequest::Scan {
+            index: "books".to_string(),
+            from: 0,
+            limit: 10,
+            filter: None,
+            at: started,
+        }) {
+            ClientResponse::Scanned { hits, next, at } => {
+                assert_eq!(hits.iter().map(|hit| hit.row).collect::<Vec<_>>(), [0, 1]);
+                assert_eq!((next, at), (None, started));
+            }
+            response => panic!("unexpected response {response:?}"),
+        }
+        assert!(matches!(
+            send(ClientRequest::Scan {
+                index: "books".to_string(),
+                from: 0,
+                limit: 10,
+                filter: None,
+                at: Some(u64::MAX),
+            }),
+            ClientResponse::Failed {
+                error: DocatlasError::NotFound(_)
+            }
+        ));
+        match send(ClientRequest::Scan {
+            index: "books".to_string(),
+            from: 0,
+            limit: 10,
+            filter: Some(ScanFilter {
+                field: "title".to_string(),
+                query: "dune".to_string(),
+            }),
+            at: None,
+        }) {
+            ClientResponse::Scanned { hits, next, .. } => {
+                assert!(hits.is_empty());
+                assert_eq!(next, None);
+            }
+            response => panic!("unexpected response {response:?}"),
+        }
+
+        let authorizer = Authorizer::new();
+        let reader = Caller {
+            user: &UserFactory.create("reader"),
+            authorizer: &authorizer,
+            denied: None,
+        };
+        let response = handle(
+            &indexes,
+            &snapshots,
+            None,
+            ClientRequest::CreateIndex {
+                index: "films".to_string(),
+                schema: schema(16),
+            },
+            &cancel,
+            false,
+            Some(reader),
+        );
+        assert!(matches!(
+            response,
+            ClientResponse::Failed {
+                error: DocatlasError::PermissionDenied(_)
+            }
+        ));
+        assert!(indexes.info("films").is_err());
+
+        // the authorization service may deny what the roles of the user allow
+        let admin = UserFactory.create(DEFAULT_USER);
+        let denied = AuthorizationError::new(&admin, Permission::Read, Resource::Cluster);
+        let overruled = Caller {
+            user: &admin,
+            authorizer: &authorizer,
+            denied: Some(&denied),
+        };
+        let response = handle(
+            &indexes,
+            &snapshots,
+            None,
+            ClientRequest::ListIndexes,
+            &cancel,
+            false,
+            Some(overruled),
+        );
+        assert!(matches!(
+            response,
+            ClientResponse::Failed {
+                error: DocatlasError::PermissionDenied(_)
+            }
+        ));
+
+        // roles may only cover some indexes
+        indexes.create("films", schema(16)).unwrap();
+        let authorizer = Authorizer::new()
+            .with_role(
+                Role::new("librarian")
+                    .with_cluster(Permission::Read)
+                    .with_indexes("books", Permission::Write),
+            )
+            .with_user_roles("librarian", vec!["librarian".to_string()]);
+        let librarian = Caller {
+            user: &UserFactory.create("librarian"),
+            authorizer: &authorizer,
+            denied: None,
+        };
+        let send = |request| {
+            handle(
+                &indexes,
+                &snapshots,
+                None,
+                request,
+                &cancel,
+                false,
+                Some(librarian),
+            )
+        };
+        assert!(matches!(
+            send(ClientRequest::IndexDocument {
+                index: "books".to_string(),
+                document: book("b2", "Emma"),
+                partial: false,
+            }),
+            ClientResponse::Upserted { .. }
+        ));
+        match send(ClientRequest::IndexDocument {
+            index: "films".to_string(),
+            document: book("f1", "Alien"),
+            partial: false,
+        }) {
+            ClientResponse::Failed {
+                error: DocatlasError::PermissionDenied(message),
+            } => {
+                assert!(message.contains("\"films\""), "{message}")
+            }
+            response => panic!("unexpected response {response:?}"),
+        }
+        match send(ClientRequest::ListIndexes) {
+            ClientResponse::Indexes { names } => assert_eq!(names, ["books"]),
+            response => panic!("unexpected response {response:?}"),
+        }
+        match send(ClientRequest::QueryString {
+            index: "books".to_string(),
+            query: "title:emma OR id:b3".to_string(),
+            default_field: None,
+            default_operator: Operator::Or,
+            limit: 10,
+        }) {
+            ClientResponse::Hits { hits } => assert_eq!(hits.len(), 1),
+            response => panic!("unexpected response {response:?}"),
+        }
+        // sql statements need to read every index they select from
+        match send(ClientRequest::Sql {
+            query: "SELECT id FROM books ORDER BY id".to_string(),
+        }) {
+            ClientResponse::Table { table } => assert_eq!(
+                table.rows,
+                [[Some(FieldData::Bytes(b"b2".as_slice().into()))]]
+            ),
+            response => panic!("unexpected response {response:?}"),
+        }
+        match send(ClientRequest::Sql {
+            query: "SELECT * FROM books JOIN films ON books.id = films.id".to_string(),
+        }) {
+            ClientResponse::Failed {
+                error: DocatlasError::PermissionDenied(message),
+            } => {
+                assert!(message.contains("\"films\""), "{message}")
+            }
+            response => panic!("unexpected response {response:?}"),
+        }
+
+        // and only some of their fields
+        let authorizer = authorizer
+            .with_role(Role::new("clerk").with_index_fields("books", Permission::Read, ["id"]))
+            .with_user_roles("clerk", vec!["clerk".to_string()]);
+        let clerk = Caller {
+            user: &UserFactory.create("clerk"),
+            authorizer: &authorizer,
+            denied: None,
+        };
+        let send = |request| {
+            handle(
+                &indexes,
+                &snapshots,
+                None,
+                request,
+                &cancel,
+                false,
+                Some(clerk),
+            )
+        };
+        match send(ClientRequest::Get {
+            index: "books".to_string(),
+            key: FieldData::Bytes(b"b2".as_slice().into()),
+        }) {
+            ClientResponse::Document {
+                document: Some(document),
+            } => {
+                assert!(document.get("id").is_some());
+                assert!(document.get("title").is_none());
+            }
+            response => panic!("unexpected response {response:?}"),
+        }
+        match send(ClientRequest::Scan {
+            index: "books".to_string(),
+            from: 0,
+            limit: 10,
+            filter: None,
+            at: None,
+        }) {
+            ClientResponse::Scanned { hits, .. } => {
+                assert!(hits.iter().all(|hit| hit.document.get("title").is_none()));
+            }
+            response => panic!("unexpected response {response:?}"),
+        }
+        match send(ClientRequest::Search {
+            index: "books".to_string(),
+            field: "title".to_string(),
+            query: "emma".to_string(),
+            limit: 10,
+        }) {
+            ClientResponse::Failed {
+                error: DocatlasError::PermissionDenied(message),
+            } => {
+                assert!(message.contains("by field \"title\""), "{message}")
+            }
+            response => panic!("unexpected response {response:?}"),
+        }
+        // scanning by a field is searching it
+        assert!(matches!(
+            send(ClientRequest::Scan {
+                index: "books".to_string(),
+                from: 0,
+                limit: 10,
+                filter: Some(ScanFilter {
+                    field: "title".to_string(),
+                    query: "emma".to_string(),
+                }),
+                at: None,
+            }),
+            ClientResponse::Failed {
+                error: DocatlasError::PermissionDenied(_)
+            }
+        ));
+        // or querying any of them
+        match send(ClientRequest::QueryString {
+            index: "books".to_string(),
+            query: "emma".to_string(),
+            default_field: Some("title".to_string()),
+            default_operator: Operator::Or,
+            limit: 10,
+        }) {
+            ClientResponse::Failed {
+                error: DocatlasError::PermissionDenied(message),
+            } => {
+                assert!(message.contains("by field \"title\""), "{message}")
+            }
+            response => panic!("unexpected response {response:?}"),
+        }
+        assert!(matches!(
+            send(ClientRequest::QueryString {
+                index: "books".to_string(),
+                query: "emma".to_string(),
+                default_field: None,
+                default_operator: Operator::Or,
+                limit: 10,
+            }),
+            ClientResponse::Failed {
+                error: DocatlasError::InvalidRequest(_)
+            }
+        ));
+        // fields that may not be read can not be selected by sql either
+        match send(ClientRequest::Sql {
+            query: "SELECT * FROM books".to_string(),
+        }) {
+            ClientResponse::Table { table } => assert_eq!(table.columns, ["id"]),
+            response => panic!("unexpected response {response:?}"),
+        }
+        assert!(matches!(
+            send(ClientRequest::Sql {
+                query: "SELECT id FROM books WHERE title = 'Emma'".to_string(),
+            }),
+            ClientResponse::Failed {
+                error: DocatlasError::InvalidRequest(_)
+            }
+        ));
+    }
+
+    #[test]
+    fn timed_out_searches() {
+        let indexes = Indexes::new();
+        let snapshots = Snapshots::new(tempfile::tempdir().unwrap().path());
+        indexes.create("books", schema(32)).unwrap();
+        indexes.upsert("books", book("b1", "Dune"), false).unwrap();
+        let search = || ClientRequest::Search {
+            index: "books".to_string(),
+            field: "title".to_string(),
+            query: "dune".to_string(),
+            limit: 10,
+        };
+
+        let expired = CancelToken::new().with_deadline(Instant::now());
+        let send =
+            |request, partial| handle(&indexes, &snapshots, None, request, &expired, partial, None);
+        assert!(matches!(send(search(), false), ClientResponse::Cancelled));
+        match send(search(), true) {
+            ClientResponse::Partial { response } => {
+                assert!(matches!(*response, ClientResponse::Hits { .. }))
+            }
+            response => panic!("unexpected response {response:?}"),
+        }
+
+        let cancel = CancelToken::new().with_deadline(Instant::now() + Duration::from_secs(60));
+        match handle(&indexes, &snapshots, None, search(), &cancel, true, None) {
+            ClientResponse::Hits { hits } => assert_eq!(hits.len(), 1),
+            response => panic!("unexpected response {response:?}"),
+        }
+    }
+
+    #[test]
+    fn manages_indexes_on_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = IndexManager::new(dir.path());
+        let indexes = Indexes::open(manager.clone()).unwrap();
+        let snapshots = Snapshots::new(dir.path().join("snapshots"));
+        let cancel = CancelToken::new();
+        let send = |request| handle(&indexes, &snapshots, None, request, &cancel, false, None);
+
+        for index in ["books", "films", "Bad Name"] {
+            send(ClientRequest::CreateIndex {
+                index: index.to_string(),
+                schema: schema(16),
+            });
+        }
+        assert!(matches!(
+            send(ClientRequest::UpdateMapping {
+                index: "books".to_string(),
+                schema: schema(32),
+            }),
+            ClientResponse::Acknowledged
+        ));
+        assert!(matches!(
+            send(ClientRequest::UpdateMapping {
+                index: "authors".to_string(),
+                schema: schema(32),
+            }),
+            ClientResponse::Failed {
+                error: DocatlasError::NotFound(_)
+            }
+        ));
+        assert!(matches!(
+            send(ClientRequest::DeleteIndex {
+                index: "films".to_string(),
+            }),
+            ClientResponse::Acknowledged
+        ));
+        match send(ClientRequest::ListIndexes) {
+            ClientResponse::Indexes { names } => assert_eq!(names, ["books"]),
+            response => panic!("unexpected response {response:?}"),
+        }
+
+        send(ClientRequest::IndexDocument {
+            index: "books".to_string(),
+            document: book("b1", "Dune"),
+            partial: false,
+        });
+        send(ClientRequest::Bulk {
+            index: "books".to_string(),
+            documents: vec![book("b2", "Emma"), book("b3", "Ulysses")],
+        });
+        send(ClientRequest::Delete {
+            index: "books".to_string(),
+            key: FieldData::Bytes(b"b2".as_slice().into()),
+        });
+        let transaction = Transaction::new()
+            .with_upsert(book("b4", "Beloved"), UpsertMode::Replace)
+            .with_delete(FieldData::Bytes(b"b1".as_slice().into()));
+        assert!(matches!(
+            send(ClientRequest::Transaction {
+                index: "books".to_string(),
+                transaction,
+            }),
+            ClientResponse::Committed { written } if written.len() == 2
+        ));
+        // the failed write keeps the write before it from being logged as well
+        let transaction = Transaction::new()
+            .with_upsert(book("b5", "Middlemarch"), UpsertMode::Replace)
+            .with_upsert(Document::from(Fields::new()), UpsertMode::Replace);
+        assert!(matches!(
+            send(ClientRequest::Transaction {
+                index: "books".to_string(),
+                transaction,
+            }),
+            ClientResponse::Failed {
+                error: DocatlasError::InvalidRequest(_)
+            }
+        ));
+
+        let reopened = Indexes::open(manager).unwrap();
+        assert_eq!(reopened.names(), ["books"]);
+        let recovery = &reopened.recovery()[0];
+        assert_eq!((recovery.replayed, recovery.failed), (4, 0));
+        assert!(reopened
+            .get("books", &FieldData::Bytes(b"b3".as_slice().into()))
+            .unwrap()
+            .is_some());
+        assert!(reopened
+            .get("books", &FieldData::Bytes(b"b2".as_slice().into()))
+            .unwrap()
+            .is_none());
+        assert!(reopened
+            .get("books", &FieldData::Bytes(b"b4".as_slice().into()))
+            .unwrap()
+            .is_some());
+        for deleted in [b"b1", b"b5"] {
+            assert!(reopened
+                .get("books", &FieldData::Bytes(deleted.as_slice().into()))
+                .unwrap()
+                .is_none());
+        }
+        match handle(
+            &reopened,
+            &snapshots,
+            None,
+            ClientRequest::GetMapping {
+                index: "books".to_string(),
+            },
+            &cancel,
+            false,
+            None,
+        ) {
+            ClientResponse::Mapping { schema } => {
+                assert_eq!(schema.get("title").unwrap().kind, FieldKind::Text(32))
+            }
+            response => panic!("unexpected response {response:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn streams_changes_to_subscribers() {
+        use docatlas_core::transport::queue::QueueConfig;
+        use futures::StreamExt;
+
+        use crate::changes::ChangeKind;
+
+        let dir = tempfile::tempdir().unwrap();
+        // only the latest change is kept, so older changes are read back from the log
+        let indexes = Indexes::open(IndexManager::new(dir.path()))
+            .unwrap()
+            .with_change_feed(Arc::new(ChangeFeed::new(1)));
+        let indexes = Arc::new(indexes);
+        let key = |id: &str| Some(FieldData::Bytes(id.as_bytes().into()));
+        indexes.create("books", schema(32)).unwrap();
+        indexes.upsert("books", book("b1", "Dune"), false).unwrap();
+        let documents = vec![book("b2", "Emma"), book("b3", "Dune Messiah")];
+        indexes.bulk("books", documents).unwrap();
+        indexes.delete("books", &key("b2").unwrap()).unwrap();
+
+        let (position, events) = indexes.changes("books", None, 0).unwrap();
+        assert_eq!(position.last, 3);
+        let changes = |events: &[crate::changes::ChangeEvent]| {
+            events
+                .iter()
+                .map(|event| (event.seq, event.kind, event.key.clone()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            changes(&events),
+            [
+                (1, ChangeKind::Added, key("b1")),
+                (2, ChangeKind::Added, key("b2")),
+                (2, ChangeKind::Added, key("b3")),
+                (3, ChangeKind::Deleted, key("b2")),
+            ]
+        );
+        let (_, kept) = indexes.changes("books", Some(position.log), 2).unwrap();
+        assert_eq!(changes(&kept), [(3, ChangeKind::Deleted, key("b2"))]);
+        assert!(matches!(
+            indexes.changes("books", None, 4),
+            Err(HandlerError::ChangesError(ChangesError::Ahead {
+                last: 3,
+                ..
+            }))
+        ));
+
+        let (sink, mut received) = futures::channel::mpsc::unbounded();
+        let (responses, driver) = SendQueue::new(sink, QueueConfig::default());
+        tokio::spawn(driver);
+        let subscribe = |after, subscriber: Subscriber| {
+            let indexes = indexes.clone();
+            let responses = responses.clone();
+            let cancel = CancelToken::new();
+            let subscription = Subscription {
+                index: "books".to_string(),
+                log: position.log,
+                after,
+                filter: ChangeFilter {
+                    kinds: vec![],
+                    query: Some("title:dune".to_string()),
+                },
+            };
+            let stream = {
+                let cancel = cancel.clone();
+                tokio::spawn(async move {
+                    stream_changes(
+                        &indexes,
+                        subscription,
+                        &subscriber,
+                        &responses,
+                        7,
+                        &cancel,
+                        "test",
+                    )
+                    .await
+                })
+            };
+            (stream, cancel)
+        };
+        async fn next_events(
+            received: &mut futures::channel::mpsc::UnboundedReceiver<Envelope<ClientResponse>>,
+        ) -> Vec<crate::changes::ChangeEvent> {
+            let envelope = received.next().await.unwrap();
+            assert!(!envelope.is_last());
+            match envelope.into_body() {
+                ClientResponse::Changes { events } => events,
+                response => panic!("unexpected response {response:?}"),
+            }
+        }
+
+        let anyone = || Subscriber {
+            user: None,
+            authorizer: Arc::new(Authorizer::new()),
+            authorization_service: None,
+            session: None,
+        };
+        // deletions carry no document, so they pass the query
+        let (stream, cancel) = subscribe(1, anyone());
+        assert_eq!(
+            changes(&next_events(&mut received).await),
+            [
+                (2, ChangeKind::Added, key("b3")),
+                (3, ChangeKind::Deleted, key("b2")),
+            ]
+        );
+        indexes.upsert("books", book("b4", "Emma"), false).unwrap();
+        indexes.upsert("books", book("b1", "Dune"), false).unwrap();
+        assert_eq!(
+            changes(&next_events(&mut received).await),
+            [(5, ChangeKind::Updated, key("b1"))]
+        );
+        cancel.cancel();
+        assert!(matches!(
+            stream.await.unwrap(),
+            Some(ClientResponse::Cancelled)
+        ));
+
+        // subscribers only see the fields they may read, including the keys of documents
+        let authorizer = Authorizer::new()
+            .with_role(Role::new("reader").with_index_fields("books", Permission::Read, ["title"]))
+            .with_user_roles("reader", vec!["reader".to_string()]);
+        let sessions = Arc::new(SessionService::default());
+        let reader = UserFactory.create("reader");
+        let token = sessions.issue(&reader).token;
+        let (stream, _) = subscribe(
+            5,
+            Subscriber {
+                user: Some(Arc::new(reader)),
+                authorizer: Arc::new(authorizer),
+                authorization_service: None,
+                session: Some((sessions.clone(), token.clone())),
+            },
+        );
+        indexes.upsert("books", book("b5", "Dune"), false).unwrap();
+        let events = next_events(&mut received).await;
+        assert_eq!(changes(&events), [(6, ChangeKind::Added, None)]);
+        let document = events[0].document.as_ref().unwrap();
+        assert!(document.get("id").is_none());
+        assert!(document.get("title").is_some());
+        // nor anything once the session they subscribed in ends
+        sessions.revoke(&token);
+        indexes.upsert("books", book("b6", "Dune"), false).unwrap();
+        assert!(matches!(
+            stream.await.unwrap(),
+            Some(ClientResponse::Failed {
+                error: DocatlasError::Unauthenticated(_)
+            })
+        ));
+
+        // the stream ends once the log starts again
+        let (stream, _) = subscribe(7, anyone());
+        indexes.change_feed().unwrap().restart("books", 0);
+        assert!(matches!(
+            stream.await.unwrap(),
+            Some(ClientResponse::Failed {
+                error: DocatlasError::Conflict(_)
+            })
+        ));
+    }
+}
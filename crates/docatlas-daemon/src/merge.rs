@@ -0,0 +1,415 @@
+//! Merges the write-ahead logs of indexes in the background.
+//!
+//! The [write-ahead log](crate::wal) of an index only grows, so the log of an index whose
+//! documents keep changing ends up much larger than the documents themselves, and takes ever
+//! longer to replay when the daemon starts. Once a log grows past [`MERGE_RATIO`] times the bytes
+//! of the rows of its index, and past a minimum size, the [`MergeScheduler`] merges it into a
+//! single import of the rows.
+//!
+//! Merges run on the blocking threads of the runtime, at most [`max_merges`](MergeScheduler::new)
+//! at a time, and write the merged log a chunk at a time, at most as fast as the throttle allows,
+//! so they do not take the disk away from requests. While searches are waiting for a thread of
+//! the [executor](Executor), no merge starts and running merges pause between chunks. The
+//! progress of every running merge is reported by [`Indexes::merges`].
+
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use docatlas_core::cancel::Cancelled;
+use serde::{Deserialize, Serialize};
+use tokio::task::{self, JoinHandle};
+use tokio::time;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::executor::Executor;
+use crate::handlers::{HandlerError, Indexes};
+
+/// The number of logs merged at the same time unless configured otherwise
+pub const DEFAULT_MAX_MERGES: usize = 1;
+
+/// The bytes merged logs are written at per second unless configured otherwise
+pub const DEFAULT_MERGE_THROTTLE: u64 = 16 * 1024 * 1024;
+
+/// The smallest log merged unless configured otherwise
+pub const DEFAULT_MIN_LOG_BYTES: u64 = 1024 * 1024;
+
+/// How many times larger than the rows of its index a log grows before it is merged
+pub const MERGE_RATIO: u64 = 2;
+
+/// The bytes of a merged log written between checking on the throttle and the load
+pub(crate) const MERGE_CHUNK_BYTES: usize = 256 * 1024;
+
+/// How often the logs are checked for merges
+const MERGE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long a paused merge waits before checking the load again
+const PAUSE: Duration = Duration::from_millis(100);
+
+/// The progress of a log being merged
+#[derive(Debug)]
+pub struct MergeProgress {
+    index: String,
+    bytes: AtomicU64,
+    written: AtomicU64,
+    paused: AtomicBool,
+    started: Instant,
+}
+
+/// The progress of a log being merged, as reported by the stats api
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MergeStatus {
+    pub index: String,
+    /// The bytes of the merged log, or 0 until they are known
+    pub bytes: u64,
+    /// The bytes of the merged log written so far
+    pub written: u64,
+    /// Whether the merge waits for the load of the daemon to drop
+    pub paused: bool,
+    pub elapsed_ms: u64,
+}
+
+/// How large the write-ahead log of an index is, compared to its rows
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogUsage {
+    pub index: String,
+    pub log_bytes: u64,
+    pub rows_bytes: u64,
+}
+
+/// Starts merging the logs that grew too large, without taking the daemon away from requests
+#[derive(Debug)]
+pub struct MergeScheduler {
+    max_merges: usize,
+    throttle: Option<u64>,
+    min_log_bytes: u64,
+    interval: Duration,
+    load: Option<Arc<Executor>>,
+    running: Arc<AtomicUsize>,
+}
+
+/// Holds up a merge after every chunk it writes, to keep it under the throttle and pause it
+/// while the daemon is busy
+#[derive(Debug)]
+struct Pacer {
+    throttle: Option<u64>,
+    load: Option<Arc<Executor>>,
+    stop: CancellationToken,
+    /// When the merge started or was last resumed, along with the bytes written by then
+    since: Instant,
+    written: u64,
+}
+
+/// Counts a merge as running until dropped
+struct Running(Arc<AtomicUsize>);
+
+impl MergeProgress {
+    /// Creates the progress of a merge of the log of an index, which starts now
+    pub fn new(index: impl Into<String>) -> Self {
+        Self {
+            index: index.into(),
+            bytes: AtomicU64::new(0),
+            written: AtomicU64::new(0),
+            paused: AtomicBool::new(false),
+            started: Instant::now(),
+        }
+    }
+
+    /// Gets the index whose log is merged
+    pub fn index(&self) -> &str {
+        &self.index
+    }
+
+    /// Gets the bytes of the merged log written so far
+    pub fn written(&self) -> u64 {
+        self.written.load(Ordering::Relaxed)
+    }
+
+    /// Records the bytes of the merged log written so far, out of all of its bytes
+    pub fn set_written(&self, written: u64, bytes: u64) {
+        self.bytes.store(bytes, Ordering::Relaxed);
+        self.written.store(written, Ordering::Relaxed);
+    }
+
+    /// Records whether the merge waits for the load of the daemon to drop
+    pub fn set_paused(&self, paused: bool) {
+        self.paused.store(paused, Ordering::Relaxed);
+    }
+
+    /// Gets the progress of the merge as reported by the stats api
+    pub fn status(&self) -> MergeStatus {
+        MergeStatus {
+            index: self.index.clone(),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            written: self.written(),
+            paused: self.paused.load(Ordering::Relaxed),
+            elapsed_ms: self.started.elapsed().as_millis() as u64,
+        }
+    }
+}
+
+impl LogUsage {
+    /// Checks whether the log grew large enough to be merged
+    pub fn needs_merge(&self, min_log_bytes: u64) -> bool {
+        self.log_bytes >= min_log_bytes && self.log_bytes > MERGE_RATIO * self.rows_bytes
+    }
+}
+
+impl MergeScheduler {
+    /// Creates a scheduler merging up to `max_merges` logs at a time, or none at all if it is 0
+    pub fn new(max_merges: usize) -> Self {
+        Self {
+            max_merges,
+            throttle: Some(DEFAULT_MERGE_THROTTLE),
+            min_log_bytes: DEFAULT_MIN_LOG_BYTES,
+            interval: MERGE_INTERVAL,
+            load: None,
+            running: Arc::default(),
+        }
+    }
+
+    /// Sets the bytes merged logs are written at per second, if they are throttled at all
+    pub fn with_throttle(mut self, throttle: Option<u64>) -> Self {
+        self.throttle = throttle;
+        self
+    }
+
+    /// Sets the smallest log merged
+    pub fn with_min_log_bytes(mut self, bytes: u64) -> Self {
+        self.min_log_bytes = bytes;
+        self
+    }
+
+    /// Sets how often the logs are checked for merges
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Sets the executor searches run on, whose queue pauses merges while it is not empty
+    pub fn with_load(mut self, executor: Arc<Executor>) -> Self {
+        self.load = Some(executor);
+        self
+    }
+
+    /// Gets the number of merges running
+    pub fn running(&self) -> usize {
+        self.running.load(Ordering::Acquire)
+    }
+
+    /// Checks the logs of the indexes for merges until stopped. Merges still running once
+    /// stopped are abandoned after their next chunk.
+    pub async fn run(&self, indexes: Arc<Indexes>, stop: &CancellationToken) {
+        if self.max_merges == 0 {
+            return;
+        }
+        let mut rounds = time::interval(self.interval);
+        loop {
+            tokio::select! {
+                _ = rounds.tick() => {}
+                _ = stop.cancelled() => return,
+            }
+            self.schedule(&indexes, stop);
+        }
+    }
+
+    /// Starts merging the logs that grew too large, unless searches are waiting for a thread or
+    /// as many merges as allowed are running already. Returns the merges started.
+    pub fn schedule(
+        &self,
+        indexes: &Arc<Indexes>,
+        stop: &CancellationToken,
+    ) -> Vec<JoinHandle<()>> {
+        let mut started = vec![];
+        if busy(self.load.as_deref()) {
+            return started;
+        }
+        let merging = indexes
+            .merges()
+            .into_iter()
+            .map(|status| status.index)
+            .collect::<HashSet<_>>();
+        for usage in indexes.log_usage() {
+            if self.running() >= self.max_merges {
+                break;
+            }
+            if merging.contains(&usage.index) || !usage.needs_merge(self.min_log_bytes) {
+                continue;
+            }
+            self.running.fetch_add(1, Ordering::AcqRel);
+            let running = Running(self.running.clone());
+            let indexes = indexes.clone();
+            let mut pacer = Pacer {
+                throttle: self.throttle,
+                load: self.load.clone(),
+                stop: stop.clone(),
+                since: Instant::now(),
+                written: 0,
+            };
+            started.push(task::spawn_blocking(move || {
+                let _running = running;
+                let LogUsage {
+                    index, log_bytes, ..
+                } = usage;
+                match indexes.merge_log(&index, |progress| pacer.pace(progress)) {
+                    Ok(Some(bytes)) => {
+                        info!("merged the write-ahead log of {index:?} from {log_bytes} bytes into {bytes}")
+                    }
+                    Ok(None) | Err(HandlerError::Cancelled(_)) => {}
+                    Err(e) => warn!("could not merge the write-ahead log of {index:?}: {e}"),
+                }
+            }));
+        }
+        started
+    }
+}
+
+impl Pacer {
+    /// Waits until the merge may write its next chunk, or fails once the daemon stops
+    fn pace(&mut self, progress: &MergeProgress) -> Result<(), Cancelled> {
+        if busy(self.load.as_deref()) {
+            progress.set_paused(true);
+            while busy(self.load.as_deref()) {
+                if self.stop.is_cancelled() {
+                    return Err(Cancelled);
+                }
+                std::thread::sleep(PAUSE);
+            }
+            progress.set_paused(false);
+            // the throttle starts over, rather than catching up on the time spent paused
+            self.since = Instant::now();
+            self.written = progress.written();
+        }
+        if self.stop.is_cancelled() {
+            return Err(Cancelled);
+        }
+        if let Some(throttle) = self.throttle.filter(|&throttle| throttle > 0) {
+            let written = progress.written() - self.written;
+            let due = Duration::from_secs_f64(written as f64 / throttle as f64);
+            if let Some(ahead) = due.checked_sub(self.since.elapsed()) {
+                std::thread::sleep(ahead);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Checks whether searches are waiting for a thread of the executor
+fn busy(load: Option<&Executor>) -> bool {
+    load.is_some_and(|executor| executor.queued() > 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use docatlas_core::document::Document;
+    use docatlas_core::fields::{Field, FieldData, FieldKind, Fields};
+    use docatlas_core::schema::{Schema, SchemaField};
+
+    use super::*;
+    use crate::index_manager::IndexManager;
+    use crate::wal::Wal;
+
+    fn book(id: &str, title: &str) -> Document {
+        let mut fields = Fields::new();
+        for (name, kind, value) in [
+            ("id", FieldKind::Keyword(8), id),
+            ("title", FieldKind::Text(32), title),
+        ] {
+            let data = FieldData::Bytes(value.as_bytes().into());
+            fields.insert(name, Field::new(kind, [data]));
+        }
+        Document::from(fields)
+    }
+
+    fn schema() -> Schema {
+        Schema::from_iter([
+            SchemaField {
+                name: "id".to_string(),
+                kind: FieldKind::Keyword(8),
+            },
+            SchemaField {
+                name: "title".to_string(),
+                kind: FieldKind::Text(32),
+            },
+        ])
+        .with_primary_key("id")
+    }
+
+    #[tokio::test]
+    async fn merge_logs_that_outgrew_their_index() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = IndexManager::new(dir.path());
+        let indexes = Arc::new(Indexes::open(manager.clone()).unwrap());
+        indexes.create("books", schema()).unwrap();
+        for edition in 0..20 {
+            let title = format!("Dune, edition {edition}");
+            indexes.upsert("books", book("b1", &title), false).unwrap();
+        }
+        indexes.upsert("books", book("b2", "Emma"), false).unwrap();
+
+        let stop = CancellationToken::new();
+        let scheduler = MergeScheduler::new(1).with_throttle(None);
+        assert!(scheduler.schedule(&indexes, &stop).is_empty());
+        let unmerged = indexes.log_usage()[0].log_bytes;
+        let scheduler = scheduler.with_min_log_bytes(0);
+        for merge in scheduler.schedule(&indexes, &stop) {
+            merge.await.unwrap();
+        }
+        assert_eq!(scheduler.running(), 0);
+        assert!(indexes.merges().is_empty());
+        assert!(indexes.log_usage()[0].log_bytes < unmerged);
+
+        // changes made after merging are logged after the merged rows
+        indexes
+            .upsert("books", book("b3", "Ulysses"), false)
+            .unwrap();
+        assert_eq!(Wal::read(manager.wal_path("books")).unwrap().len(), 2);
+        let reopened = Indexes::open(manager).unwrap();
+        assert_eq!(reopened.recovery()[0].rows, 3);
+        let title = reopened
+            .get("books", &FieldData::Bytes(b"b1".as_slice().into()))
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            title.get("title").unwrap().data(),
+            book("b1", "Dune, edition 19").get("title").unwrap().data()
+        );
+    }
+
+    #[test]
+    fn report_the_progress_of_merges() {
+        let dir = tempfile::tempdir().unwrap();
+        let manager = IndexManager::new(dir.path());
+        let indexes = Indexes::open(manager.clone()).unwrap();
+        indexes.create("books", schema()).unwrap();
+        indexes.upsert("books", book("b1", "Dune"), false).unwrap();
+        let mut seen = vec![];
+        let merged = indexes
+            .merge_log("books", |progress| {
+                seen.push(indexes.merges());
+                progress.set_paused(true);
+                Ok(())
+            })
+            .unwrap();
+        assert!(merged.is_some());
+        assert_eq!(seen.len(), 1);
+        assert_eq!(seen[0][0].index, "books");
+        assert_eq!(seen[0][0].written, seen[0][0].bytes);
+        assert!(indexes.merges().is_empty());
+
+        // merges can be abandoned, leaving the log alone
+        let cancelled = indexes.merge_log("books", |_| Err(Cancelled));
+        assert!(cancelled.is_err());
+        let log = manager.wal_path("books");
+        assert!(!log.with_extension("log.merging").exists());
+        assert_eq!(Wal::read(log).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,155 @@
+//! Metrics of the daemon, served by the http api at `/metrics` in the prometheus text format.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use docatlas_core::transport::metrics::TransportMetrics;
+use docatlas_core::transport::mux::Envelope;
+use docatlas_core::transport::queue::SendQueue;
+
+use crate::client::ClientResponse;
+use crate::executor::Executor;
+use crate::replication::Replica;
+
+/// The prefix of every metric of the daemon
+const PREFIX: &str = "docatlas";
+
+/// The metrics of the daemon
+#[derive(Debug, Default)]
+pub struct DaemonMetrics {
+    transport: Arc<TransportMetrics>,
+    connections: Mutex<BTreeMap<u64, Connection>>,
+    next_connection: AtomicU64,
+    /// The replication state, if the daemon is a replica
+    replica: Option<Arc<Replica>>,
+    executor: Option<Arc<Executor>>,
+}
+
+#[derive(Debug)]
+struct Connection {
+    peer: String,
+    responses: SendQueue<Envelope<ClientResponse>>,
+}
+
+impl DaemonMetrics {
+    /// Creates metrics with every counter at zero
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reports how far the given replica is behind its primary
+    pub fn with_replica(mut self, replica: Arc<Replica>) -> Self {
+        self.replica = Some(replica);
+        self
+    }
+
+    /// Reports the work waiting for a thread of the given executor
+    pub fn with_executor(mut self, executor: Arc<Executor>) -> Self {
+        self.executor = Some(executor);
+        self
+    }
+
+    /// Gets the metrics shared by the transports of every client
+    pub fn transport(&self) -> &Arc<TransportMetrics> {
+        &self.transport
+    }
+
+    /// Tracks the response queue of a connected client until the returned guard is dropped
+    pub fn connected(
+        self: &Arc<Self>,
+        peer: impl ToString,
+        responses: SendQueue<Envelope<ClientResponse>>,
+    ) -> ConnectionGuard {
+        let id = self.next_connection.fetch_add(1, Ordering::Relaxed);
+        self.connections.lock().expect("poisoned").insert(
+            id,
+            Connection {
+                peer: peer.to_string(),
+                responses,
+            },
+        );
+        ConnectionGuard {
+            metrics: self.clone(),
+            id,
+        }
+    }
+
+    /// Renders every metric in the prometheus text format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.transport.render(PREFIX, &mut out);
+        let connections = self.connections.lock().expect("poisoned");
+        let _ = writeln!(out, "# HELP {PREFIX}_connections Connected clients");
+        let _ = writeln!(out, "# TYPE {PREFIX}_connections gauge");
+        let _ = writeln!(out, "{PREFIX}_connections {}", connections.len());
+        let _ = writeln!(
+            out,
+            "# HELP {PREFIX}_send_queue_depth Responses waiting to be sent to a client"
+        );
+        let _ = writeln!(out, "# TYPE {PREFIX}_send_queue_depth gauge");
+        for (id, connection) in connections.iter() {
+            let _ = writeln!(
+                out,
+                "{PREFIX}_send_queue_depth{{connection=\"{id}\",peer=\"{}\"}} {}",
+                escape(&connection.peer),
+                connection.responses.depth()
+            );
+        }
+        if let Some(executor) = &self.executor {
+            let _ = writeln!(
+                out,
+                "# HELP {PREFIX}_search_queue_depth Requests waiting for a search thread"
+            );
+            let _ = writeln!(out, "# TYPE {PREFIX}_search_queue_depth gauge");
+            let _ = writeln!(out, "{PREFIX}_search_queue_depth {}", executor.queued());
+        }
+        if let Some(replica) = &self.replica {
+            let _ = writeln!(
+                out,
+                "# HELP {PREFIX}_replication_lag Changes made on the primary not yet applied by \
+                 this replica"
+            );
+            let _ = writeln!(out, "# TYPE {PREFIX}_replication_lag gauge");
+            let _ = writeln!(out, "{PREFIX}_replication_lag {}", replica.lag());
+            let _ = writeln!(
+                out,
+                "# HELP {PREFIX}_replication_connected Whether this replica is connected to its \
+                 primary"
+            );
+            let _ = writeln!(out, "# TYPE {PREFIX}_replication_connected gauge");
+            let _ = writeln!(
+                out,
+                "{PREFIX}_replication_connected {}",
+                u8::from(replica.is_connected())
+            );
+        }
+        out
+    }
+}
+
+/// Escapes a prometheus label value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Stops tracking a connection once dropped
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    metrics: Arc<DaemonMetrics>,
+    id: u64,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.metrics
+            .connections
+            .lock()
+            .expect("poisoned")
+            .remove(&self.id);
+    }
+}
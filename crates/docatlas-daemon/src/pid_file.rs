@@ -0,0 +1,138 @@
+//! The pid file of a running daemon, which stops a second daemon from using the same data
+//! directory.
+
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use thiserror::Error;
+
+/// The name of the pid file within the daemon path
+pub const PID_FILE: &str = "docatlasd.pid";
+
+/// The pid file of this process, removed once dropped
+#[derive(Debug)]
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Writes the id of this process to the pid file, replacing the pid file of a daemon that is no
+    /// longer running
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, PidFileError> {
+        let path = path.as_ref();
+        if check(path)?.is_some() {
+            fs::remove_file(path)?;
+        }
+        let mut file = match OpenOptions::new().write(true).create_new(true).open(path) {
+            Ok(file) => file,
+            // another daemon started after the check
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                return Err(PidFileError::AlreadyRunning {
+                    pid: read(path)?.unwrap_or_default(),
+                    path: path.to_path_buf(),
+                })
+            }
+            Err(e) => return Err(e.into()),
+        };
+        writeln!(file, "{}", std::process::id())?;
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Gets the path of the pid file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Checks that no daemon is running with the given pid file, returning the pid of the daemon
+/// that wrote it if that daemon is no longer running
+pub fn check(path: impl AsRef<Path>) -> Result<Option<u32>, PidFileError> {
+    let path = path.as_ref();
+    match read(path)? {
+        Some(pid) if is_running(pid) => Err(PidFileError::AlreadyRunning {
+            pid,
+            path: path.to_path_buf(),
+        }),
+        stale => Ok(stale),
+    }
+}
+
+/// Reads the pid in a pid file, if there is one
+fn read(path: &Path) -> Result<Option<u32>, PidFileError> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    contents
+        .trim()
+        .parse()
+        .map(Some)
+        .map_err(|_| PidFileError::Corrupt(path.to_path_buf()))
+}
+
+/// Checks if a process is running
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // signal 0 only checks that the process exists and could be signalled
+    let signalled = unsafe { libc::kill(pid, 0) } == 0;
+    signalled || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Checks if a process is running, which can not be known on this platform so the process is
+/// assumed to be running
+#[cfg(not(unix))]
+fn is_running(_pid: u32) -> bool {
+    true
+}
+
+/// The pid file could not be created
+#[derive(Debug, Error)]
+pub enum PidFileError {
+    #[error("docatlasd is already running with pid {pid}, stop it or remove {path:?} if it is not running")]
+    AlreadyRunning { pid: u32, path: PathBuf },
+    #[error("pid file {0:?} does not contain a pid, remove it if no daemon is running")]
+    Corrupt(PathBuf),
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::process::Command;
+
+    use super::*;
+
+    #[test]
+    fn refuses_live_pid_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(PID_FILE);
+        let pid_file = PidFile::create(&path).unwrap();
+        let error = PidFile::create(&path).unwrap_err();
+        assert!(
+            matches!(error, PidFileError::AlreadyRunning { pid, .. } if pid == std::process::id())
+        );
+        drop(pid_file);
+        assert!(!path.exists());
+
+        let mut exited = Command::new("true").spawn().unwrap();
+        exited.wait().unwrap();
+        fs::write(&path, exited.id().to_string()).unwrap();
+        assert_eq!(check(&path).unwrap(), Some(exited.id()));
+        let _pid_file = PidFile::create(&path).unwrap();
+        assert_eq!(read(&path).unwrap(), Some(std::process::id()));
+    }
+}
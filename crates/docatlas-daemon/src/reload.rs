@@ -0,0 +1,163 @@
+//! Reloads the config file while the daemon is running.
+//!
+//! Sending `SIGHUP` to the daemon re-reads its config file. Only the log level and rate limits
+//! take effect right away, every other changed setting is reported as requiring a restart.
+
+use std::sync::RwLock;
+
+use log::{info, warn};
+use tokio_util::sync::CancellationToken;
+
+use crate::audit::{AuditAction, AuditLog};
+use crate::config::DaemonConfig;
+use crate::limits::RateLimit;
+use crate::shutdown;
+
+/// The settings that can be changed without restarting the daemon
+const DYNAMIC_SETTINGS: &[&str] = &["log_level", "rate_limit"];
+
+/// The settings currently in effect that can change while the daemon is running
+#[derive(Debug)]
+pub struct DynamicSettings {
+    rate_limit: RwLock<Option<RateLimit>>,
+}
+
+/// The settings changed by reloading the config
+#[derive(Debug, Default, PartialEq)]
+pub struct ConfigChanges {
+    /// Changed settings that took effect
+    pub applied: Vec<&'static str>,
+    /// Changed settings that only take effect once the daemon restarts
+    pub restart_required: Vec<&'static str>,
+}
+
+impl DynamicSettings {
+    /// Creates the settings of a daemon started with the given config
+    pub fn new(config: &DaemonConfig) -> Self {
+        Self {
+            rate_limit: RwLock::new(config.rate_limit()),
+        }
+    }
+
+    /// Gets the rate limit of every client
+    pub fn rate_limit(&self) -> Option<RateLimit> {
+        *self.rate_limit.read().expect("poisoned")
+    }
+
+    /// Applies the dynamic settings of a reloaded config. Every other setting that differs from
+    /// the config the daemon started with requires a restart.
+    pub fn apply(&self, started: &DaemonConfig, reloaded: &DaemonConfig) -> ConfigChanges {
+        let mut changes = ConfigChanges::default();
+        if log::max_level() != *reloaded.log_level() {
+            log::set_max_level(*reloaded.log_level());
+            changes.applied.push("log_level");
+        }
+        let mut rate_limit = self.rate_limit.write().expect("poisoned");
+        if *rate_limit != reloaded.rate_limit() {
+            *rate_limit = reloaded.rate_limit();
+            changes.applied.push("rate_limit");
+        }
+        changes.restart_required = started
+            .changed_settings(reloaded)
+            .into_iter()
+            .filter(|setting| !DYNAMIC_SETTINGS.contains(setting))
+            .collect();
+        changes
+    }
+}
+
+/// Reloads the config file every time the daemon receives `SIGHUP`, until it shuts down. Settings
+/// given on the command line take precedence over the config file, like they do at startup.
+pub async fn reload_on_hangup(
+    started: &DaemonConfig,
+    command_line: &DaemonConfig,
+    settings: &DynamicSettings,
+    audit: Option<&AuditLog>,
+    stop: &CancellationToken,
+) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(e) => {
+                warn!("config can not be reloaded, could not listen for SIGHUP: {e}");
+                return;
+            }
+        };
+        while let Some(Some(())) = shutdown::until_shutdown(stop, hangups.recv()).await {
+            match command_line.clone().with_config_file() {
+                Ok(reloaded) => {
+                    let changes = settings.apply(started, &reloaded);
+                    report(&changes);
+                    if let Some(audit) = audit.filter(|_| !changes.applied.is_empty()) {
+                        let settings = changes.applied.iter().map(|s| s.to_string()).collect();
+                        let action = AuditAction::SettingsChanged { settings };
+                        if let Err(e) = audit.record(None, None, action, None) {
+                            warn!("could not audit reloading the config: {e}");
+                        }
+                    }
+                }
+                Err(e) => warn!("keeping the current config: {e}"),
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (started, command_line, settings, audit, stop);
+    }
+}
+
+/// Logs the settings changed by a reload
+fn report(changes: &ConfigChanges) {
+    if changes.applied.is_empty() && changes.restart_required.is_empty() {
+        info!("reloaded config, nothing changed");
+        return;
+    }
+    if !changes.applied.is_empty() {
+        info!("reloaded config, applied {}", changes.applied.join(", "));
+    }
+    if !changes.restart_required.is_empty() {
+        warn!(
+            "reloaded config, restart the daemon to apply {}",
+            changes.restart_required.join(", ")
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+    use log::LevelFilter;
+
+    use super::*;
+    use crate::config::CliDaemonConfig;
+
+    fn config(args: &[&str]) -> DaemonConfig {
+        CliDaemonConfig::parse_from(["docatlasd"].iter().chain(args)).into_config()
+    }
+
+    #[test]
+    fn only_dynamic_settings_are_applied() {
+        let started = config(&["--log", "info"]);
+        let settings = DynamicSettings::new(&started);
+        log::set_max_level(LevelFilter::Info);
+
+        let reloaded = config(&["--log", "info", "--rate-limit", "5", "--port", "4000"]);
+        let changes = settings.apply(&started, &reloaded);
+        assert_eq!(changes.applied, ["rate_limit"]);
+        assert_eq!(changes.restart_required, ["port"]);
+        assert_eq!(settings.rate_limit(), Some(RateLimit::new(5.0)));
+
+        // settings requiring a restart are reported until the daemon restarts
+        let changes = settings.apply(&started, &reloaded);
+        assert_eq!(
+            changes,
+            ConfigChanges {
+                applied: vec![],
+                restart_required: vec!["port"],
+            }
+        );
+    }
+}
@@ -0,0 +1,689 @@
+//! Asynchronous replication from a primary daemon to read-only replicas.
+//!
+//! Every change made to the indexes of a daemon with a `replication_port` is published to its
+//! [`ReplicationLog`](ReplicationLog), under an increasing sequence number. Replicas connect to
+//! that port and ask for every change after the last one they applied, which the primary streams
+//! to them as they are made. A replica that is new, was following another log, or fell further
+//! behind than the log keeps is sent a copy of every index instead.
+//!
+//! A replica (a daemon with `replica_of` set) serves searches, but refuses every request that
+//! would change its indexes until it is [promoted](Replica::promote), after which it stops
+//! following its primary.
+//!
+//! Replicas authenticate to their primary with the credentials of the `replica_user`, sent along
+//! with what they ask for. A primary requiring its clients to authenticate only streams changes
+//! to replicas whose user may manage the cluster, as they are sent every document of every index.
+
+use std::collections::VecDeque;
+use std::io;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use docatlas_core::auth::authorization::{Permission, Resource};
+use docatlas_core::document::Document;
+use docatlas_core::fields::FieldData;
+use docatlas_core::index::Transaction;
+use docatlas_core::schema::Schema;
+use docatlas_core::transport::handshake::{
+    self, ClientHello, HandshakeError, ServerCapabilities, ServerInfo,
+};
+use docatlas_core::transport::keepalive::Keepalive;
+use docatlas_core::transport::{wire, TcpTransport, Transport};
+use futures::{SinkExt, StreamExt};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::net;
+use tokio::sync::watch;
+use tokio::{task, time};
+use tokio_util::sync::CancellationToken;
+
+use crate::access::Access;
+use crate::client::Credentials;
+use crate::config::DaemonConfig;
+use crate::handlers::{HandlerError, Indexes};
+use crate::shutdown;
+
+/// The default number of changes kept for replicas that fall behind
+pub const DEFAULT_REPLICATION_BACKLOG: usize = 10_000;
+/// How often the primary tells idle replicas about its latest change
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+/// The longest a replica waits before reconnecting to its primary
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(30);
+
+/// A change made to the indexes of a primary
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Operation {
+    CreateIndex {
+        index: String,
+        schema: Schema,
+    },
+    /// Replaces the schema of an empty index, creating the index if needed
+    ReplaceSchema {
+        index: String,
+        schema: Schema,
+    },
+    /// Creates an index holding raw rows, such as one restored from a snapshot
+    Import {
+        index: String,
+        schema: Schema,
+        rows: Vec<u8>,
+    },
+    DropIndex {
+        index: String,
+    },
+    Upsert {
+        index: String,
+        document: Document,
+        partial: bool,
+    },
+    Bulk {
+        index: String,
+        documents: Vec<Document>,
+    },
+    Delete {
+        index: String,
+        key: FieldData,
+    },
+    /// Writes that are applied all at once or not at all
+    Transaction {
+        index: String,
+        transaction: Transaction,
+    },
+}
+
+/// A change, along with its position in the replication log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Publication {
+    pub seq: u64,
+    pub operation: Operation,
+}
+
+/// A copy of a single index
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IndexCopy {
+    pub name: String,
+    pub schema: Schema,
+    pub rows: Vec<u8>,
+}
+
+/// A copy of every index, taken at a position in a replication log
+#[derive(Debug, Serialize, Deserialize)]
+pub struct IndexesCopy {
+    /// The id of the log the copy was taken from
+    pub log: u64,
+    /// The last change included in the copy
+    pub seq: u64,
+    pub indexes: Vec<IndexCopy>,
+}
+
+/// Sent by a replica once connected to its primary
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Follow {
+    /// The id of the log the replica was following, if any
+    pub log: Option<u64>,
+    /// The last change the replica applied
+    pub after: u64,
+    /// What the replica authenticates as, if its primary requires it to
+    pub credentials: Option<Credentials>,
+}
+
+/// Sent by a primary to its replicas
+#[derive(Debug, Serialize, Deserialize)]
+pub enum PrimaryMessage {
+    /// Replaces every index of the replica
+    Copy(IndexesCopy),
+    /// A change to apply
+    Publish(Publication),
+    /// The latest change of the primary, sent while there is nothing to publish
+    Heartbeat { seq: u64 },
+    /// Why the replica may not follow the primary, before the primary disconnects
+    Refused(String),
+}
+
+/// The most recent changes made to the indexes of a primary
+#[derive(Debug)]
+pub struct ReplicationLog {
+    backlog: usize,
+    state: Mutex<LogState>,
+    latest: watch::Sender<u64>,
+}
+
+#[derive(Debug)]
+struct LogState {
+    id: u64,
+    publications: VecDeque<Publication>,
+}
+
+impl ReplicationLog {
+    /// Creates an empty log keeping up to `backlog` changes
+    pub fn new(backlog: usize) -> Self {
+        Self {
+            backlog,
+            state: Mutex::new(LogState {
+                id: new_log_id(),
+                publications: VecDeque::new(),
+            }),
+            latest: watch::channel(0).0,
+        }
+    }
+
+    /// Gets the id of this log, which changes every time the log is reset
+    pub fn id(&self) -> u64 {
+        self.state.lock().expect("log poisoned").id
+    }
+
+    /// Gets the sequence number of the latest change
+    pub fn last(&self) -> u64 {
+        *self.latest.borrow()
+    }
+
+    /// Adds a change to the log, dropping the oldest change once the backlog is full
+    pub fn publish(&self, operation: Operation) {
+        let mut state = self.state.lock().expect("log poisoned");
+        let seq = self.last() + 1;
+        state.publications.push_back(Publication { seq, operation });
+        while state.publications.len() > self.backlog {
+            state.publications.pop_front();
+        }
+        self.latest.send_replace(seq);
+    }
+
+    /// Gets every change after `after` in the log with the given id, or `None` if any of them are
+    /// no longer kept
+    pub fn since(&self, log: u64, after: u64) -> Option<Vec<Publication>> {
+        let state = self.state.lock().expect("log poisoned");
+        let last = self.last();
+        let first = state.publications.front().map_or(last + 1, |p| p.seq);
+        if log != state.id || after > last || after + 1 < first {
+            return None;
+        }
+        Some(
+            state
+                .publications
+                .iter()
+                .filter(|publication| publication.seq > after)
+                .cloned()
+                .collect(),
+        )
+    }
+
+    /// Waits for changes to be published
+    pub fn subscribe(&self) -> watch::Receiver<u64> {
+        self.latest.subscribe()
+    }
+
+    /// Starts a new log, so every replica following this one is sent a copy of every index
+    pub fn reset(&self) {
+        let mut state = self.state.lock().expect("log poisoned");
+        state.id = new_log_id();
+        state.publications.clear();
+    }
+}
+
+/// Creates an id that is different every time a log is started, and is never 0
+pub(crate) fn new_log_id() -> u64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    (nanos ^ (u64::from(std::process::id()) << 32)) | 1
+}
+
+/// The capabilities advertised to replicas during handshakes
+pub fn capabilities(config: &DaemonConfig) -> ServerCapabilities {
+    ServerCapabilities::default()
+        .with_keepalive(config.keepalive())
+        .with_server_info(ServerInfo {
+            name: env!("CARGO_PKG_NAME").to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+        })
+}
+
+/// Streams every change to a newly connected replica, at an address if it is known, until it
+/// disconnects or the daemon shuts down. Replicas are refused unless they authenticate as a user
+/// that may manage the cluster, if clients are required to authenticate.
+pub async fn serve_replica<T>(
+    mut transport: T,
+    capabilities: &ServerCapabilities,
+    indexes: Arc<Indexes>,
+    access: &Arc<Access>,
+    ip: Option<IpAddr>,
+    stop: &CancellationToken,
+) -> Result<(), ReplicationError>
+where
+    T: Transport<Vec<u8>, Vec<u8>, Error = io::Error> + Unpin,
+{
+    let log = indexes
+        .replication_log()
+        .cloned()
+        .ok_or(ReplicationError::NotAPrimary)?;
+    let (_, server) = handshake::accept(&mut transport, capabilities).await?;
+    let transport = Keepalive::new(transport, server.keepalive);
+    let mut transport =
+        wire::wire_transport::<PrimaryMessage, Follow, _, _>(transport, server.codec());
+    let follow = match shutdown::until_shutdown(stop, transport.next()).await {
+        Some(Some(follow)) => follow?,
+        _ => return Ok(()),
+    };
+    if let Err(e) = authorize_replica(access, follow.credentials, ip).await {
+        transport
+            .send(PrimaryMessage::Refused(e.to_string()))
+            .await?;
+        return Err(e.into());
+    }
+    let mut latest = log.subscribe();
+    let mut heartbeat = time::interval(HEARTBEAT_INTERVAL);
+    let (mut following, mut sent) = (follow.log.unwrap_or_default(), follow.after);
+    loop {
+        match log.since(following, sent) {
+            Some(publications) => {
+                for publication in publications {
+                    sent = publication.seq;
+                    transport.send(PrimaryMessage::Publish(publication)).await?;
+                }
+            }
+            None => {
+                let copied = indexes.clone();
+                let copy = task::spawn_blocking(move || copied.copy())
+                    .await
+                    .map_err(io::Error::other)??;
+                info!("sending a copy of every index at change {}", copy.seq);
+                (following, sent) = (copy.log, copy.seq);
+                transport.send(PrimaryMessage::Copy(copy)).await?;
+            }
+        }
+        tokio::select! {
+            _ = stop.cancelled() => return Ok(()),
+            changed = latest.changed() => {
+                if changed.is_err() {
+                    return Ok(());
+                }
+            }
+            _ = heartbeat.tick() => {
+                let seq = log.last();
+                transport.send(PrimaryMessage::Heartbeat { seq }).await?;
+            }
+        }
+    }
+}
+
+/// Checks that a replica authenticated as a user that may manage the cluster, off the runtime as
+/// its password may be verified
+async fn authorize_replica(
+    access: &Arc<Access>,
+    credentials: Option<Credentials>,
+    ip: Option<IpAddr>,
+) -> Result<(), HandlerError> {
+    let authenticated = match credentials {
+        Some(credentials) => {
+            let access = access.clone();
+            task::spawn_blocking(move || access.authenticate_credentials(&credentials, ip))
+                .await
+                .map_err(io::Error::other)??
+        }
+        // replicas never act as the anonymous user
+        None if access.is_required() => {
+            return Err(HandlerError::Unauthenticated(
+                "replicas must authenticate, by setting replica_user".to_string(),
+            ))
+        }
+        None => access.authenticate(None, ip)?,
+    };
+    authenticated.authorize(Permission::Manage, &Resource::Cluster)
+}
+
+/// The state of a daemon replicating the indexes of a primary
+#[derive(Debug)]
+pub struct Replica {
+    primary: String,
+    /// What the replica authenticates to its primary as
+    credentials: Option<Credentials>,
+    /// The id of the log being followed, or 0 before the first copy
+    log: AtomicU64,
+    applied: AtomicU64,
+    primary_seq: AtomicU64,
+    connected: AtomicBool,
+    promoted: CancellationToken,
+}
+
+impl Replica {
+    /// Creates a replica of the primary whose replication port is at the given address
+    pub fn new(primary: impl Into<String>) -> Self {
+        Self {
+            primary: primary.into(),
+            credentials: None,
+            log: AtomicU64::new(0),
+            applied: AtomicU64::new(0),
+            primary_seq: AtomicU64::new(0),
+            connected: AtomicBool::new(false),
+            promoted: CancellationToken::new(),
+        }
+    }
+
+    /// Sets what the replica authenticates to its primary as
+    pub fn with_credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    /// Gets the address of the replication port of the primary
+    pub fn primary(&self) -> &str {
+        &self.primary
+    }
+
+    /// Gets the number of changes made on the primary that this replica has not applied yet
+    pub fn lag(&self) -> u64 {
+        self.primary_seq
+            .load(Ordering::SeqCst)
+            .saturating_sub(self.applied.load(Ordering::SeqCst))
+    }
+
+    /// Checks whether the replica is connected to its primary
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::SeqCst)
+    }
+
+    /// Checks whether the replica was promoted, and no longer follows its primary
+    pub fn is_promoted(&self) -> bool {
+        self.promoted.is_cancelled()
+    }
+
+    /// Stops following the primary, and starts accepting changes to the indexes
+    pub fn promote(&self, indexes: &Indexes) -> Result<(), HandlerError> {
+        if self.is_promoted() {
+            return Err(HandlerError::NotAReplica);
+        }
+        self.promoted.cancel();
+        indexes.set_read_only(false);
+        info!(
+            "promoted from a replica of {}, {} changes behind it",
+            self.primary,
+            self.lag()
+        );
+        Ok(())
+    }
+}
+
+/// Follows the primary until the replica is promoted or the daemon shuts down, reconnecting
+/// whenever the connection is lost
+pub async fn follow(replica: Arc<Replica>, indexes: Arc<Indexes>, stop: &CancellationToken) {
+    let mut delay = HEARTBEAT_INTERVAL;
+    loop {
+        let followed = tokio::select! {
+            followed = follow_primary(&replica, &indexes) => followed,
+            _ = replica.promoted.cancelled() => return,
+            _ = stop.cancelled() => return,
+        };
+        replica.connected.store(false, Ordering::SeqCst);
+        match followed {
+            Ok(()) => {
+                warn!("primary at {} closed the connection", replica.primary);
+                delay = HEARTBEAT_INTERVAL;
+            }
+            Err(e) => warn!("lost the primary at {}: {e}", replica.primary),
+        }
+        tokio::select! {
+            _ = time::sleep(delay) => {}
+            _ = replica.promoted.cancelled() => return,
+            _ = stop.cancelled() => return,
+        }
+        delay = (delay * 2).min(MAX_RETRY_DELAY);
+    }
+}
+
+/// Connects to the primary and applies every change it sends
+async fn follow_primary(replica: &Replica, indexes: &Arc<Indexes>) -> Result<(), ReplicationError> {
+    let address = net::lookup_host(&replica.primary)
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address for the primary"))?;
+    let mut transport = TcpTransport::connect(address).await?;
+    let server = handshake::connect(&mut transport, &ClientHello::default()).await?;
+    let transport = Keepalive::new(transport, server.keepalive);
+    let mut transport =
+        wire::wire_transport::<Follow, PrimaryMessage, _, _>(transport, server.codec());
+    let log = replica.log.load(Ordering::SeqCst);
+    let follow = Follow {
+        log: (log != 0).then_some(log),
+        after: replica.applied.load(Ordering::SeqCst),
+        credentials: replica.credentials.clone(),
+    };
+    transport.send(follow).await?;
+    replica.connected.store(true, Ordering::SeqCst);
+    info!("following the primary at {}", replica.primary);
+    while let Some(message) = transport.next().await {
+        match message? {
+            PrimaryMessage::Copy(copy) => {
+                info!("replacing every index with a copy at change {}", copy.seq);
+                replica.primary_seq.store(copy.seq, Ordering::SeqCst);
+                let (log, seq) = (copy.log, copy.seq);
+                let replaced = indexes.clone();
+                task::spawn_blocking(move || replaced.replace_all(copy.indexes))
+                    .await
+                    .map_err(io::Error::other)??;
+                replica.log.store(log, Ordering::SeqCst);
+                replica.applied.store(seq, Ordering::SeqCst);
+            }
+            PrimaryMessage::Publish(publication) => {
+                let seq = publication.seq;
+                replica.primary_seq.fetch_max(seq, Ordering::SeqCst);
+                let applied = indexes.clone();
+                let result = task::spawn_blocking(move || applied.apply(publication.operation))
+                    .await
+                    .map_err(io::Error::other)?;
+                if let Err(e) = result {
+                    // the replica no longer matches the primary, so it starts over from a copy
+                    replica.log.store(0, Ordering::SeqCst);
+                    return Err(ReplicationError::Diverged(seq, e));
+                }
+                replica.applied.store(seq, Ordering::SeqCst);
+            }
+            PrimaryMessage::Heartbeat { seq } => replica.primary_seq.store(seq, Ordering::SeqCst),
+            PrimaryMessage::Refused(reason) => return Err(ReplicationError::Refused(reason)),
+        }
+    }
+    Ok(())
+}
+
+/// An error occurred replicating indexes
+#[derive(Debug, Error)]
+pub enum ReplicationError {
+    #[error("this daemon does not accept replicas")]
+    NotAPrimary,
+    #[error("the primary refused to be followed: {0}")]
+    Refused(String),
+    #[error("could not apply change {0}: {1}")]
+    Diverged(u64, HandlerError),
+    #[error(transparent)]
+    HandlerError(#[from] HandlerError),
+    #[error(transparent)]
+    HandshakeError(#[from] HandshakeError),
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use docatlas_core::auth::authentication::{
+        AuthenticationRequest, AuthenticationToolchain, SessionService,
+    };
+    use docatlas_core::auth::authorization::{Authorizer, Role};
+    use docatlas_core::auth::users::UserFactory;
+    use docatlas_core::fields::{Field, FieldKind, Fields};
+    use docatlas_core::schema::SchemaField;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::client::Secret;
+
+    fn schema() -> Schema {
+        Schema::from_iter([SchemaField {
+            name: "id".to_string(),
+            kind: FieldKind::Keyword(8),
+        }])
+        .with_primary_key("id")
+    }
+
+    fn document(id: usize) -> Document {
+        let mut fields = Fields::new();
+        let data = FieldData::Bytes(id.to_string().as_bytes().into());
+        fields.insert("id", Field::new(FieldKind::Keyword(8), [data]));
+        Document::from(fields)
+    }
+
+    #[test]
+    fn log_keeps_the_backlog() {
+        let log = ReplicationLog::new(2);
+        for index in ["a", "b", "c"] {
+            log.publish(Operation::DropIndex {
+                index: index.to_string(),
+            });
+        }
+        assert_eq!(log.last(), 3);
+        assert_eq!(log.since(log.id(), 1).unwrap().len(), 2);
+        assert_eq!(log.since(log.id(), 3).unwrap().len(), 0);
+        assert!(log.since(log.id(), 0).is_none());
+        assert!(log.since(log.id() + 1, 3).is_none());
+        let id = log.id();
+        log.reset();
+        assert!(log.since(id, 3).is_none());
+    }
+
+    #[tokio::test]
+    async fn replicas_follow_their_primary() {
+        let primary =
+            Arc::new(Indexes::new().with_replication_log(Arc::new(ReplicationLog::new(16))));
+        primary.create("books", schema()).unwrap();
+        primary.upsert("books", document(1), false).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let stop = CancellationToken::new();
+        let server = {
+            let primary = primary.clone();
+            let stop = stop.clone();
+            tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                let capabilities = ServerCapabilities::default();
+                let access = Arc::new(Access::new(
+                    Arc::new(SessionService::default()),
+                    Arc::new(Authorizer::new()),
+                ));
+                let transport = TcpTransport::new(stream);
+                serve_replica(transport, &capabilities, primary, &access, None, &stop).await
+            })
+        };
+        let replica = Arc::new(Replica::new(address.to_string()));
+        let indexes = Arc::new(Indexes::new());
+        indexes.set_read_only(true);
+        let following = {
+            let (replica, indexes, stop) = (replica.clone(), indexes.clone(), stop.clone());
+            tokio::spawn(async move { follow(replica, indexes, &stop).await })
+        };
+
+        primary.upsert("books", document(2), false).unwrap();
+        primary
+            .delete("books", &FieldData::Bytes(b"1".as_slice().into()))
+            .unwrap();
+        let deadline = time::Instant::now() + Duration::from_secs(10);
+        while replica.applied.load(Ordering::SeqCst) < 4 {
+            assert!(time::Instant::now() < deadline, "replica did not catch up");
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(replica.lag(), 0);
+        let key = FieldData::Bytes(b"2".as_slice().into());
+        assert!(indexes.get("books", &key).unwrap().is_some());
+        assert!(indexes
+            .get("books", &FieldData::Bytes(b"1".as_slice().into()))
+            .unwrap()
+            .is_none());
+        assert!(matches!(
+            indexes.upsert("books", document(3), false),
+            Err(HandlerError::ReadOnly)
+        ));
+
+        replica.promote(&indexes).unwrap();
+        following.await.unwrap();
+        indexes.upsert("books", document(3), false).unwrap();
+        assert!(replica.promote(&indexes).is_err());
+        stop.cancel();
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn refuse_replicas_that_may_not_manage_the_cluster() {
+        let dir = tempfile::tempdir().unwrap();
+        let auth = AuthenticationToolchain::open(&dir.path().join("admin")).unwrap();
+        // the default admin is refused until its password is changed
+        auth.change_password(
+            AuthenticationRequest::new().with_basic("admin", "admin"),
+            "not-the-default",
+        )
+        .unwrap();
+        let sessions = Arc::new(SessionService::default());
+        let authorizer = Authorizer::new()
+            .with_role(Role::new("reader").with_cluster(Permission::Read))
+            .with_user_roles("reader", vec!["reader".to_string()]);
+        let access = Access::new(sessions.clone(), Arc::new(authorizer)).with_auth(Arc::new(auth));
+        let access = Arc::new(access);
+        let primary =
+            Arc::new(Indexes::new().with_replication_log(Arc::new(ReplicationLog::new(16))));
+        primary.create("books", schema()).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let stop = CancellationToken::new();
+        let server = {
+            let stop = stop.clone();
+            tokio::spawn(async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    let (primary, access, stop) = (primary.clone(), access.clone(), stop.clone());
+                    tokio::spawn(async move {
+                        let capabilities = ServerCapabilities::default();
+                        let transport = TcpTransport::new(stream);
+                        serve_replica(transport, &capabilities, primary, &access, None, &stop).await
+                    });
+                }
+            })
+        };
+        let indexes = Arc::new(Indexes::new());
+        let anonymous = Replica::new(address.to_string());
+        assert!(matches!(
+            follow_primary(&anonymous, &indexes).await,
+            Err(ReplicationError::Refused(reason)) if reason.contains("replica_user")
+        ));
+        let token = sessions.issue(&UserFactory.create("reader")).token;
+        let reader = Replica::new(address.to_string()).with_credentials(Credentials::Token {
+            token: Secret::new(token),
+        });
+        assert!(matches!(
+            follow_primary(&reader, &indexes).await,
+            Err(ReplicationError::Refused(_))
+        ));
+        assert!(indexes.names().is_empty());
+
+        // the admin may manage the cluster
+        let admin = Arc::new(Replica::new(address.to_string()).with_credentials(
+            Credentials::Basic {
+                username: "admin".to_string(),
+                password: Secret::new("not-the-default"),
+            },
+        ));
+        let following = {
+            let (admin, indexes, stop) = (admin.clone(), indexes.clone(), stop.clone());
+            tokio::spawn(async move { follow(admin, indexes, &stop).await })
+        };
+        let deadline = time::Instant::now() + Duration::from_secs(10);
+        while indexes.names().is_empty() {
+            assert!(time::Instant::now() < deadline, "admin was not sent a copy");
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        stop.cancel();
+        following.await.unwrap();
+        server.abort();
+    }
+}
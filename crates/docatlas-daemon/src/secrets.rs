@@ -0,0 +1,167 @@
+//! Sensitive settings of the daemon, such as keys and secrets, which may be given in the
+//! configuration itself, or read from an environment variable or a file when the daemon starts.
+//! They are kept wrapped in a [`Secret`] so they are never written to the logs.
+
+use std::fmt::{Debug, Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::{env, fs, io};
+
+use secrecy::{ExposeSecret, Secret, SecretString};
+use serde::{Deserialize, Deserializer};
+use thiserror::Error;
+
+/// Prefix of settings naming the environment variable a secret is read from
+const ENV_PREFIX: &str = "env:";
+/// Prefix of settings naming the file a secret is read from
+const FILE_PREFIX: &str = "file:";
+
+/// Where the value of a sensitive setting comes from. Settings starting with `env:` name an
+/// environment variable, settings starting with `file:` name a file, and any other setting is the
+/// secret itself.
+#[derive(Clone)]
+pub enum SecretSource {
+    /// The secret is given in the configuration itself
+    Value(SecretString),
+    /// The secret is read from an environment variable
+    Env(String),
+    /// The secret is read from a file
+    File(PathBuf),
+}
+
+impl SecretSource {
+    /// Treats a secret given in the configuration itself as the path of the file it is read from,
+    /// for settings that have always been paths
+    pub fn or_file(self) -> Self {
+        match self {
+            SecretSource::Value(path) => SecretSource::File(PathBuf::from(path.expose_secret())),
+            source => source,
+        }
+    }
+
+    /// Reads the secret. Trailing newlines of files are dropped.
+    pub fn read(&self) -> Result<SecretString, SecretError> {
+        match self {
+            SecretSource::Value(secret) => Ok(secret.clone()),
+            SecretSource::Env(name) => env::var(name)
+                .map(Secret::new)
+                .map_err(|_| SecretError::MissingEnv(name.clone())),
+            SecretSource::File(path) => {
+                let mut secret = fs::read_to_string(path)
+                    .map_err(|e| SecretError::Unreadable(path.clone(), e))?;
+                secret.truncate(secret.trim_end_matches(['\r', '\n']).len());
+                Ok(Secret::new(secret))
+            }
+        }
+    }
+}
+
+impl Debug for SecretSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SecretSource::Value(_) => f.write_str("Value(****)"),
+            SecretSource::Env(name) => f.debug_tuple("Env").field(name).finish(),
+            SecretSource::File(path) => f.debug_tuple("File").field(path).finish(),
+        }
+    }
+}
+
+impl Display for SecretSource {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SecretSource::Value(_) => f.write_str("value given in the configuration"),
+            SecretSource::Env(name) => write!(f, "environment variable {name}"),
+            SecretSource::File(path) => write!(f, "file {path:?}"),
+        }
+    }
+}
+
+impl PartialEq for SecretSource {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (SecretSource::Value(this), SecretSource::Value(other)) => {
+                this.expose_secret() == other.expose_secret()
+            }
+            (SecretSource::Env(this), SecretSource::Env(other)) => this == other,
+            (SecretSource::File(this), SecretSource::File(other)) => this == other,
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for SecretSource {
+    type Err = std::convert::Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(if let Some(name) = s.strip_prefix(ENV_PREFIX) {
+            SecretSource::Env(name.to_string())
+        } else if let Some(path) = s.strip_prefix(FILE_PREFIX) {
+            SecretSource::File(PathBuf::from(path))
+        } else {
+            SecretSource::Value(Secret::new(s.to_string()))
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for SecretSource {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl From<&Path> for SecretSource {
+    fn from(path: &Path) -> Self {
+        SecretSource::File(path.to_path_buf())
+    }
+}
+
+impl From<&PathBuf> for SecretSource {
+    fn from(path: &PathBuf) -> Self {
+        SecretSource::File(path.clone())
+    }
+}
+
+impl From<PathBuf> for SecretSource {
+    fn from(path: PathBuf) -> Self {
+        SecretSource::File(path)
+    }
+}
+
+/// A secret could not be read
+#[derive(Debug, Error)]
+pub enum SecretError {
+    #[error("environment variable {0} is not set")]
+    MissingEnv(String),
+    #[error("could not read secret file {0:?}: {1}")]
+    Unreadable(PathBuf, io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_secrets() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("secret");
+        fs::write(&path, "hunter2\n").unwrap();
+        let setting = format!("file:{}", path.display());
+        let source: SecretSource = setting.parse().unwrap();
+        assert_eq!(source, SecretSource::File(path));
+        assert_eq!(source.read().unwrap().expose_secret(), "hunter2");
+
+        let source: SecretSource = serde_yaml::from_str("hunter2").unwrap();
+        assert_eq!(source.read().unwrap().expose_secret(), "hunter2");
+        assert_eq!(format!("{source:?}"), "Value(****)");
+        assert_eq!(
+            source.clone().or_file(),
+            SecretSource::File("hunter2".into())
+        );
+
+        let source: SecretSource = "env:DOCATLAS_TEST_UNSET_SECRET".parse().unwrap();
+        let error = source.read().unwrap_err();
+        assert!(matches!(error, SecretError::MissingEnv(_)));
+    }
+}
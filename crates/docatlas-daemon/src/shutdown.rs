@@ -0,0 +1,144 @@
+//! Graceful shutdown of the daemon.
+//!
+//! Once a shutdown signal is received, the daemon stops accepting connections and reading
+//! requests, and gives the requests in flight until the shutdown timeout to finish. Every index is
+//! then flushed to disk, and a marker is written so the next start can tell whether the daemon was
+//! shut down cleanly.
+
+use std::fs::{self, File};
+use std::future::Future;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use tokio::time;
+use tokio_util::sync::CancellationToken;
+
+/// The name of the marker file within the daemon path
+pub const SHUTDOWN_MARKER: &str = "shutdown.marker";
+
+const RUNNING: &str = "running";
+const CLEAN: &str = "clean";
+
+/// How the daemon was last shut down
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LastShutdown {
+    /// The daemon never ran from this path before
+    Never,
+    /// The daemon flushed everything before it exited
+    Clean,
+    /// The daemon was killed, or crashed, while it was running
+    Unclean,
+}
+
+/// Reads how the daemon was last shut down, then marks it as running until
+/// [`mark_clean`](mark_clean) is called
+pub fn mark_running(path: &Path) -> io::Result<LastShutdown> {
+    let marker = path.join(SHUTDOWN_MARKER);
+    let last = match fs::read_to_string(&marker) {
+        Ok(contents) if contents == CLEAN => LastShutdown::Clean,
+        Ok(_) => LastShutdown::Unclean,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => LastShutdown::Never,
+        Err(e) => return Err(e),
+    };
+    write_marker(path, RUNNING)?;
+    Ok(last)
+}
+
+/// Marks the daemon as shut down cleanly
+pub fn mark_clean(path: &Path) -> io::Result<()> {
+    write_marker(path, CLEAN)
+}
+
+/// Replaces the marker, so that a crash leaves either the old or the new marker behind and the
+/// new one survives a crash once this returns
+fn write_marker(path: &Path, contents: &str) -> io::Result<()> {
+    let marker = path.join(SHUTDOWN_MARKER);
+    let written = marker.with_extension("marker.tmp");
+    let mut file = File::create(&written)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&written, &marker)?;
+    File::open(path)?.sync_all()
+}
+
+/// Waits for the daemon to be asked to stop, by either SIGTERM or SIGINT
+#[cfg(unix)]
+pub async fn signal() -> io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        _ = terminate.recv() => {}
+        _ = interrupt.recv() => {}
+    }
+    Ok(())
+}
+
+/// Waits for the daemon to be asked to stop by ctrl-c
+#[cfg(not(unix))]
+pub async fn signal() -> io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
+
+/// Runs a future until it completes, returning `None` if shutdown begins first
+pub async fn until_shutdown<F: Future>(
+    shutdown: &CancellationToken,
+    future: F,
+) -> Option<F::Output> {
+    tokio::select! {
+        output = future => Some(output),
+        _ = shutdown.cancelled() => None,
+    }
+}
+
+/// Runs a future until it completes, returning `None` if it is still running once `deadline` has
+/// passed since shutdown began
+pub async fn until_deadline<F: Future>(
+    shutdown: &CancellationToken,
+    deadline: Duration,
+    future: F,
+) -> Option<F::Output> {
+    let expired = async {
+        shutdown.cancelled().await;
+        time::sleep(deadline).await;
+    };
+    tokio::select! {
+        output = future => Some(output),
+        _ = expired => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future;
+
+    use super::*;
+
+    #[test]
+    fn markers_track_clean_shutdowns() {
+        let dir = tempfile::tempdir().unwrap();
+        assert_eq!(mark_running(dir.path()).unwrap(), LastShutdown::Never);
+        assert_eq!(mark_running(dir.path()).unwrap(), LastShutdown::Unclean);
+        mark_clean(dir.path()).unwrap();
+        assert_eq!(mark_running(dir.path()).unwrap(), LastShutdown::Clean);
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[tokio::test]
+    async fn deadlines_start_at_shutdown() {
+        let shutdown = CancellationToken::new();
+        let deadline = Duration::from_millis(50);
+        let slow = until_deadline(&shutdown, deadline, time::sleep(Duration::from_secs(60)));
+        let cancel = async {
+            time::sleep(Duration::from_millis(10)).await;
+            shutdown.cancel();
+        };
+        let (finished, ()) = tokio::join!(slow, cancel);
+        assert_eq!(finished, None);
+
+        let pending = until_shutdown(&shutdown, future::pending::<()>());
+        assert_eq!(pending.await, None);
+    }
+}
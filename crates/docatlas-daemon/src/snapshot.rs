@@ -0,0 +1,362 @@
+//! Snapshots of indexes, stored in repositories so they can be restored later.
+//!
+//! The rows of every index are split into segments of [`SEGMENT_ROWS`](SEGMENT_ROWS) rows, which
+//! are stored by the hash of their contents. A segment that is already in the repository is never
+//! written again, so snapshots taken after the first only store the segments that changed.
+//!
+//! Every index is captured at a single point in time while its writer is locked, so the
+//! snapshot of an index never contains half of a request.
+//!
+//! Repositories are directories on disk, unless they are stored in an [object store](s3).
+
+use std::fmt::Debug;
+use std::fs;
+use std::io;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use blake2::{Blake2s256, Digest};
+use docatlas_core::schema::Schema;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::handlers::{HandlerError, Indexes};
+use crate::index_manager::{self, InvalidIndexName};
+use crate::snapshot::s3::{S3Client, S3Repository};
+
+pub mod s3;
+
+/// The number of rows stored in a single segment
+pub const SEGMENT_ROWS: usize = 1024;
+
+/// Describes a snapshot, and every segment it is made of
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub name: String,
+    /// When the snapshot was taken, in rfc3339
+    pub created: String,
+    pub indexes: Vec<IndexManifest>,
+}
+
+/// Describes the snapshot of a single index
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct IndexManifest {
+    pub name: String,
+    pub schema: Schema,
+    pub rows: usize,
+    /// The ids of the segments holding the rows of the index, in order
+    pub segments: Vec<String>,
+}
+
+/// A summary of a snapshot that was just taken
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SnapshotInfo {
+    pub name: String,
+    pub indexes: Vec<String>,
+    pub segments_written: usize,
+    pub segments_reused: usize,
+}
+
+/// Somewhere snapshots are stored
+pub trait Repository: Debug + Send + Sync {
+    /// Checks if a segment is stored
+    fn contains_segment(&self, id: &str) -> io::Result<bool>;
+
+    /// Stores a segment
+    fn write_segment(&self, id: &str, bytes: &[u8]) -> io::Result<()>;
+
+    /// Reads a stored segment
+    fn read_segment(&self, id: &str) -> io::Result<Vec<u8>>;
+
+    /// Stores the manifest of a snapshot, which is only written once all of its segments are
+    fn write_manifest(&self, manifest: &Manifest) -> io::Result<()>;
+
+    /// Reads the manifest of a snapshot, if the snapshot exists
+    fn read_manifest(&self, name: &str) -> io::Result<Option<Manifest>>;
+
+    /// Gets the names of every snapshot, in alphabetical order
+    fn snapshots(&self) -> io::Result<Vec<String>>;
+}
+
+/// A repository within a directory, holding every segment in `segments` and the manifest of every
+/// snapshot in `snapshots`
+#[derive(Debug, Clone)]
+pub struct FsRepository {
+    root: PathBuf,
+}
+
+impl FsRepository {
+    /// Creates a repository within the given directory
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    fn segment_path(&self, id: &str) -> PathBuf {
+        self.root.join("segments").join(id)
+    }
+
+    fn manifest_path(&self, name: &str) -> PathBuf {
+        self.root.join("snapshots").join(format!("{name}.json"))
+    }
+}
+
+impl Repository for FsRepository {
+    fn contains_segment(&self, id: &str) -> io::Result<bool> {
+        self.segment_path(id).try_exists()
+    }
+
+    fn write_segment(&self, id: &str, bytes: &[u8]) -> io::Result<()> {
+        write_atomic(&self.segment_path(id), bytes)
+    }
+
+    fn read_segment(&self, id: &str) -> io::Result<Vec<u8>> {
+        fs::read(self.segment_path(id))
+    }
+
+    fn write_manifest(&self, manifest: &Manifest) -> io::Result<()> {
+        let json = serde_json::to_vec_pretty(manifest).map_err(io::Error::other)?;
+        write_atomic(&self.manifest_path(&manifest.name), &json)
+    }
+
+    fn read_manifest(&self, name: &str) -> io::Result<Option<Manifest>> {
+        match fs::read(self.manifest_path(name)) {
+            Ok(json) => serde_json::from_slice(&json)
+                .map(Some)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn snapshots(&self) -> io::Result<Vec<String>> {
+        let entries = match fs::read_dir(self.root.join("snapshots")) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        let mut names = vec![];
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                if let Some(name) = path.file_stem().and_then(|stem| stem.to_str()) {
+                    names.push(name.to_string());
+                }
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+}
+
+/// Writes a file through a temporary file, so it is never partially written
+fn write_atomic(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    fs::write(&temp, bytes)?;
+    fs::rename(temp, path)
+}
+
+/// The repositories snapshots can be stored in, which are the directories within a root directory,
+/// or the prefixes within a bucket of an object store
+#[derive(Debug, Clone)]
+pub struct Snapshots {
+    root: PathBuf,
+    s3: Option<Arc<S3Client>>,
+}
+
+impl Snapshots {
+    /// Stores repositories within the given directory
+    pub fn new(root: impl AsRef<Path>) -> Self {
+        Self {
+            root: root.as_ref().to_path_buf(),
+            s3: None,
+        }
+    }
+
+    /// Stores repositories in an object store instead
+    pub fn with_s3(mut self, client: S3Client) -> Self {
+        self.s3 = Some(Arc::new(client));
+        self
+    }
+
+    /// Gets the repository with the given name, which follows the same rules as index names
+    pub fn repository(&self, name: &str) -> Result<Box<dyn Repository>, SnapshotError> {
+        index_manager::validate_name(name)?;
+        Ok(match &self.s3 {
+            Some(client) => Box::new(S3Repository::new(client.clone(), name)),
+            None => Box::new(FsRepository::new(self.root.join(name))),
+        })
+    }
+}
+
+/// Takes a snapshot of every index, storing the segments not yet in the repository
+pub fn create(
+    indexes: &Indexes,
+    repository: &dyn Repository,
+    name: &str,
+) -> Result<SnapshotInfo, HandlerError> {
+    index_manager::validate_name(name).map_err(SnapshotError::from)?;
+    if repository.read_manifest(name)?.is_some() {
+        return Err(SnapshotError::SnapshotExists(name.to_string()).into());
+    }
+    let mut info = SnapshotInfo {
+        name: name.to_string(),
+        indexes: vec![],
+        segments_written: 0,
+        segments_reused: 0,
+    };
+    let mut manifest = Manifest {
+        name: name.to_string(),
+        created: humantime::format_rfc3339(SystemTime::now()).to_string(),
+        indexes: vec![],
+    };
+    for index in indexes.names() {
+        let (schema, rows) = match indexes.export(&index) {
+            Ok(exported) => exported,
+            // dropped since listing the indexes
+            Err(HandlerError::NoSuchIndex(_)) => continue,
+            Err(e) => return Err(e),
+        };
+        let row_size = schema.row_size();
+        let mut segments = vec![];
+        for segment in rows.chunks((SEGMENT_ROWS * row_size).max(1)) {
+            let id = segment_id(segment);
+            if repository.contains_segment(&id)? {
+                info.segments_reused += 1;
+            } else {
+                repository.write_segment(&id, segment)?;
+                info.segments_written += 1;
+            }
+            segments.push(id);
+        }
+        manifest.indexes.push(IndexManifest {
+            name: index.clone(),
+            rows: rows.len().checked_div(row_size).unwrap_or_default(),
+            schema,
+            segments,
+        });
+        info.indexes.push(index);
+    }
+    repository.write_manifest(&manifest)?;
+    Ok(info)
+}
+
+/// Restores every index of a snapshot, none of which may exist, returning the names of the
+/// restored indexes. Nothing is restored unless every segment of the snapshot is stored, and every
+/// segment is checked against its id before its index is restored.
+pub fn restore(
+    indexes: &Indexes,
+    repository: &dyn Repository,
+    name: &str,
+) -> Result<Vec<String>, HandlerError> {
+    let manifest = repository
+        .read_manifest(name)?
+        .ok_or_else(|| SnapshotError::NoSuchSnapshot(name.to_string()))?;
+    let existing = indexes.names();
+    if let Some(index) = manifest.indexes.iter().find(|i| existing.contains(&i.name)) {
+        return Err(HandlerError::IndexExists(index.name.clone()));
+    }
+    for id in manifest.indexes.iter().flat_map(|index| &index.segments) {
+        if !repository.contains_segment(id)? {
+            return Err(SnapshotError::MissingSegment(id.clone()).into());
+        }
+    }
+    let mut restored = vec![];
+    for index in manifest.indexes {
+        let mut rows = vec![];
+        for id in &index.segments {
+            let segment = repository.read_segment(id)?;
+            if segment_id(&segment) != *id {
+                return Err(SnapshotError::CorruptSegment(id.clone()).into());
+            }
+            rows.extend_from_slice(&segment);
+        }
+        indexes.import(&index.name, index.schema, &rows)?;
+        restored.push(index.name);
+    }
+    Ok(restored)
+}
+
+/// Gets the id of a segment, which is the hash of its contents
+fn segment_id(segment: &[u8]) -> String {
+    Blake2s256::digest(segment)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// A snapshot could not be taken or restored
+#[derive(Debug, Error)]
+pub enum SnapshotError {
+    #[error(transparent)]
+    InvalidName(#[from] InvalidIndexName),
+    #[error("No snapshot named {0:?}")]
+    NoSuchSnapshot(String),
+    #[error("A snapshot named {0:?} already exists")]
+    SnapshotExists(String),
+    #[error("Segment {0} does not match its contents")]
+    CorruptSegment(String),
+    #[error("Segment {0} is missing from the repository")]
+    MissingSegment(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use docatlas_core::document::Document;
+    use docatlas_core::fields::{Field, FieldData, FieldKind, Fields};
+    use docatlas_core::schema::SchemaField;
+
+    use super::*;
+
+    fn schema() -> Schema {
+        Schema::from_iter([SchemaField {
+            name: "id".to_string(),
+            kind: FieldKind::Keyword(8),
+        }])
+        .with_primary_key("id")
+    }
+
+    fn document(id: usize) -> Document {
+        let mut fields = Fields::new();
+        let data = FieldData::Bytes(id.to_string().as_bytes().into());
+        fields.insert("id", Field::new(FieldKind::Keyword(8), [data]));
+        Document::from(fields)
+    }
+
+    #[test]
+    fn snapshots_reuse_unchanged_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let repository = FsRepository::new(dir.path());
+        let indexes = Indexes::new();
+        indexes.create("books", schema()).unwrap();
+        indexes
+            .bulk("books", (0..SEGMENT_ROWS + 1).map(document).collect())
+            .unwrap();
+
+        let first = create(&indexes, &repository, "first").unwrap();
+        assert_eq!((first.segments_written, first.segments_reused), (2, 0));
+        indexes
+            .upsert("books", document(SEGMENT_ROWS + 1), false)
+            .unwrap();
+        let second = create(&indexes, &repository, "second").unwrap();
+        assert_eq!((second.segments_written, second.segments_reused), (1, 1));
+        assert_eq!(repository.snapshots().unwrap(), ["first", "second"]);
+
+        let restored = Indexes::new();
+        assert_eq!(
+            restore(&restored, &repository, "second").unwrap(),
+            ["books"]
+        );
+        assert_eq!(restored.info("books").unwrap().rows, SEGMENT_ROWS + 2);
+        let key = FieldData::Bytes(b"7".as_slice().into());
+        assert!(restored.get("books", &key).unwrap().is_some());
+        assert!(restore(&restored, &repository, "first").is_err());
+    }
+}
@@ -0,0 +1,86 @@
+//! Integration with systemd, for `Type=notify` services and socket activation.
+//!
+//! Sockets passed by systemd are matched to the listeners of the daemon by their
+//! `FileDescriptorName`, which is one of `tcp`, `websocket`, `http`, `grpc`, `replication` or
+//! `cluster`. Unnamed sockets are matched in that order instead. Listeners without a socket from systemd are
+//! bound as usual.
+//!
+//! Everything here does nothing when the daemon was not started by systemd.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::TcpListener;
+
+use log::warn;
+
+/// The names of the listeners sockets can be passed for, in the order unnamed sockets are used
+pub const LISTENERS: [&str; 6] = ["tcp", "websocket", "http", "grpc", "replication", "cluster"];
+
+/// The listening sockets passed to the daemon by systemd
+#[derive(Debug, Default)]
+pub struct ActivatedSockets {
+    listeners: HashMap<String, TcpListener>,
+}
+
+impl ActivatedSockets {
+    /// Takes the sockets systemd passed to this process, if any. Can only be called once, as the
+    /// environment variables passing the sockets are removed.
+    #[cfg(unix)]
+    pub fn from_env() -> io::Result<Self> {
+        use std::os::fd::FromRawFd;
+
+        let mut listeners = HashMap::new();
+        for (index, (fd, name)) in sd_notify::listen_fds_with_names(true)?.enumerate() {
+            let name = match name.as_str() {
+                "unknown" => match LISTENERS.get(index) {
+                    Some(name) => name.to_string(),
+                    None => {
+                        warn!("ignoring unnamed socket {fd} passed by systemd");
+                        continue;
+                    }
+                },
+                _ => name,
+            };
+            // systemd hands the sockets over to this process, so nothing else owns them
+            let listener = unsafe { TcpListener::from_raw_fd(fd) };
+            listeners.insert(name, listener);
+        }
+        Ok(Self { listeners })
+    }
+
+    /// Sockets can only be passed by systemd on unix
+    #[cfg(not(unix))]
+    pub fn from_env() -> io::Result<Self> {
+        Ok(Self::default())
+    }
+
+    /// Takes the socket passed for the listener with the given name
+    pub fn take(&mut self, name: &str) -> Option<TcpListener> {
+        self.listeners.remove(name)
+    }
+
+    /// Gets the names of every socket that was not taken
+    pub fn unused(&self) -> impl Iterator<Item = &str> {
+        self.listeners.keys().map(String::as_str)
+    }
+}
+
+/// Tells systemd the daemon is ready to serve requests
+pub fn notify_ready() {
+    #[cfg(unix)]
+    notify(&[sd_notify::NotifyState::Ready]);
+}
+
+/// Tells systemd the daemon is shutting down
+pub fn notify_stopping() {
+    #[cfg(unix)]
+    notify(&[sd_notify::NotifyState::Stopping]);
+}
+
+/// Sends a notification to systemd, if the daemon is run by systemd
+#[cfg(unix)]
+fn notify(state: &[sd_notify::NotifyState]) {
+    if let Err(e) = sd_notify::notify(false, state) {
+        warn!("could not notify systemd: {e}");
+    }
+}
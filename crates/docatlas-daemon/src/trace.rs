@@ -0,0 +1,147 @@
+//! Traces of requests, following a request from the client through the daemon into the index
+//! operations it makes.
+//!
+//! Clients pass the trace a request is part of as a [W3C `traceparent`][traceparent], either as a
+//! header over http and grpc, or by wrapping the request in
+//! [`ClientRequest::Traced`](crate::client::ClientRequest::Traced) over the binary protocol.
+//! Requests without one start a new trace. Every request is handled within a `request` span
+//! carrying its trace id, and the spans of the operations it makes on indexes and their files are
+//! nested within it. Setting `log_spans` logs every span once it closes along with the time spent
+//! in it, so slow requests can be broken down.
+//!
+//! Spans are emitted through [`tracing`](tracing), so they can be exported by adding any layer,
+//! such as an OTLP exporter, to the subscriber.
+//!
+//! [traceparent]: https://www.w3.org/TR/trace-context/#traceparent-header
+
+use std::fmt::{Display, Formatter};
+
+use axum::http::HeaderMap;
+use thiserror::Error;
+use tracing::field::Empty;
+use tracing::Span;
+
+/// The name of the header carrying the trace of a request
+pub const TRACEPARENT: &str = "traceparent";
+
+/// The trace a request is part of
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceContext {
+    trace_id: u128,
+    /// The span the client made the request in, if any
+    parent_id: Option<u64>,
+}
+
+impl Default for TraceContext {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TraceContext {
+    /// Starts a new trace
+    pub fn new() -> Self {
+        Self {
+            trace_id: rand::random::<u128>().max(1),
+            parent_id: None,
+        }
+    }
+
+    /// Parses a `traceparent`, which is made of a version, trace id, parent id and flags
+    pub fn parse(traceparent: &str) -> Result<Self, InvalidTraceParent> {
+        let invalid = || InvalidTraceParent(traceparent.to_string());
+        let parts = traceparent.trim().split('-').collect::<Vec<_>>();
+        let [version, trace_id, parent_id, flags] = parts[..] else {
+            return Err(invalid());
+        };
+        let hex = |part: &str, len: usize| {
+            part.len() == len && part.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+        };
+        if !hex(version, 2) || version == "ff" || !hex(flags, 2) {
+            return Err(invalid());
+        }
+        if !hex(trace_id, 32) || !hex(parent_id, 16) {
+            return Err(invalid());
+        }
+        let trace_id = u128::from_str_radix(trace_id, 16).map_err(|_| invalid())?;
+        let parent_id = u64::from_str_radix(parent_id, 16).map_err(|_| invalid())?;
+        if trace_id == 0 || parent_id == 0 {
+            return Err(invalid());
+        }
+        Ok(Self {
+            trace_id,
+            parent_id: Some(parent_id),
+        })
+    }
+
+    /// Continues the trace passed in the `traceparent` header, or starts a new trace if there is
+    /// none or it is invalid
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        headers
+            .get(TRACEPARENT)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| Self::parse(value).ok())
+            .unwrap_or_default()
+    }
+
+    /// Gets the id of the trace, as 32 lowercase hex digits
+    pub fn trace_id(&self) -> String {
+        format!("{:032x}", self.trace_id)
+    }
+
+    /// Gets the id of the span the client made the request in, as 16 lowercase hex digits
+    pub fn parent_id(&self) -> Option<String> {
+        self.parent_id.map(|id| format!("{id:016x}"))
+    }
+
+    /// Creates the span a request is handled in. The request id, index, method and path of the
+    /// request are recorded by the protocol handling it, if it has them.
+    pub fn span(&self) -> Span {
+        tracing::info_span!(
+            "request",
+            trace_id = %self.trace_id(),
+            parent_id = self.parent_id().as_deref(),
+            request_id = Empty,
+            index = Empty,
+            method = Empty,
+            path = Empty,
+        )
+    }
+}
+
+impl Display for TraceContext {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.trace_id())
+    }
+}
+
+/// A `traceparent` is not formatted as described by the trace context specification
+#[derive(Debug, Error)]
+#[error("{0:?} is not a valid traceparent")]
+pub struct InvalidTraceParent(String);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_traceparent() {
+        let trace =
+            TraceContext::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").unwrap();
+        assert_eq!(trace.trace_id(), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(trace.parent_id().as_deref(), Some("00f067aa0ba902b7"));
+        for invalid in [
+            "",
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+            "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+            "00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01",
+            "ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+        ] {
+            TraceContext::parse(invalid).unwrap_err();
+        }
+        assert_ne!(
+            TraceContext::new().trace_id(),
+            TraceContext::new().trace_id()
+        );
+    }
+}
@@ -0,0 +1,837 @@
+//! The write-ahead log of an index, which makes its documents survive restarts.
+//!
+//! Every change made to the documents of an index is appended to `wal.log` within the directory
+//! of the index, and flushed to disk before the change is acknowledged. Changes are stored as
+//! frames:
+//!
+//! ```text
+//! | length (u32 le) | crc32 of the payload (u32 le) | payload (the change, as postcard) |
+//! ```
+//!
+//! A change is committed once its whole frame is on disk. When the daemon starts, the log of
+//! every index is read and its committed changes are replayed. Reading stops at the first frame
+//! that is cut short or does not match its checksum, such as the last frame written before a
+//! crash, and everything from that frame on is rolled back by truncating the log.
+//!
+//! Writers appending changes at the same time share their fsyncs. A change is [written](Wal::write)
+//! to the log right away, but only committed once the log is synced, which the writer
+//! [waits](Pending::commit) for without holding up other writers. The first writer to wait syncs
+//! every change written so far, after waiting out the commit window for more changes to join, and
+//! every writer arriving while it syncs is committed by the next sync. A change is never
+//! acknowledged before it is committed, and what a writer [does once it is](Wal::write_then), such
+//! as publishing it, is done in the order changes were written.
+//!
+//! If syncing fails, it is unknown which of the changes written since the last sync made it to
+//! disk, so all of them are rolled back by truncating the log, and waiting for any of them fails.
+//! Changes written from then on are committed by the next sync as usual.
+//!
+//! Replacing the schema of an index, or replacing the index with imported rows, starts a new log.
+//! The committed changes of a log can also be [read](Wal::read) while it is written, such as to
+//! stream them to the subscribers of [change data capture](crate::changes).
+//!
+//! A log only grows, so the log of an index whose documents keep changing ends up much larger
+//! than the documents themselves. Logs are [merged](Wal::start_merge) into a single import of the
+//! rows of their index by writing the import to `wal.log.merging` next to the log, while changes
+//! keep being written to the log itself. Once the import is written, the changes written since the
+//! merge started are copied after it, and the merged log replaces the log by renaming it.
+
+use std::collections::VecDeque;
+use std::fmt::{Debug, Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use thiserror::Error;
+
+use crate::replication::Operation;
+
+/// The size of the header preceding every change
+const HEADER_LEN: usize = 8;
+
+/// The extension of a log while it is merged
+const MERGING_EXTENSION: &str = "log.merging";
+
+/// The write-ahead log of a single index
+#[derive(Debug)]
+pub struct Wal {
+    path: PathBuf,
+    group: Arc<GroupCommit>,
+}
+
+/// Syncs the changes written to a log, committing the changes of every writer waiting at once
+#[derive(Debug)]
+struct GroupCommit {
+    file: File,
+    state: Mutex<SyncState>,
+    synced: Condvar,
+}
+
+#[derive(Debug, Default)]
+struct SyncState {
+    /// How long a writer waits for more changes before syncing
+    window: Duration,
+    /// The number of changes written to the log, including those rolled back
+    written: u64,
+    /// The length of the log, once every change written to it is
+    len: u64,
+    /// The number of the last change committed
+    committed: u64,
+    /// The length of the log once every committed change is, which is what it is rolled back to
+    committed_len: u64,
+    /// Set while a writer syncs the log
+    syncing: bool,
+    /// The number of times the log was synced
+    syncs: u64,
+    /// The changes rolled back because the sync that would have committed them failed
+    rolled_back: Vec<RangeInclusive<u64>>,
+    /// Set while the log still holds rolled back changes, as truncating it failed
+    truncate: bool,
+    /// What is done once each change is committed, in the order the changes were written
+    then: VecDeque<(u64, OnCommit)>,
+}
+
+/// What is done once a change is committed
+struct OnCommit(Box<dyn FnOnce() + Send>);
+
+impl Debug for OnCommit {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str("OnCommit")
+    }
+}
+
+/// A change written to a log, which is not committed until the log is synced
+#[derive(Debug)]
+#[must_use = "a change is not committed until waited for"]
+pub struct Pending {
+    group: Arc<GroupCommit>,
+    change: u64,
+}
+
+/// A log being merged into a single change, which replaces the log once it is
+/// [finished](WalMerge::finish). The merged log is removed if the merge is dropped before.
+#[derive(Debug)]
+pub struct WalMerge {
+    path: PathBuf,
+    file: Option<File>,
+    /// The log being merged
+    group: Arc<GroupCommit>,
+    /// The length of the log when the merge started
+    merged: u64,
+    /// The frame of the change the log is merged into
+    frame: Vec<u8>,
+    written: usize,
+}
+
+/// The committed changes read from a log when it was opened
+#[derive(Debug, Default)]
+pub struct WalReplay {
+    pub operations: Vec<Operation>,
+    /// The bytes after the last committed change, which were rolled back
+    pub rolled_back: u64,
+}
+
+impl Wal {
+    /// Starts a new, empty log, replacing any log already at the path
+    pub fn create(path: impl AsRef<Path>) -> Result<Self, WalError> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&path)?;
+        file.sync_all()?;
+        Ok(Self::new(path, file, 0))
+    }
+
+    /// Opens the log at the given path, creating it if it does not exist. Returns the committed
+    /// changes in the log, after rolling back any change that was not completely written.
+    pub fn open(path: impl AsRef<Path>) -> Result<(Self, WalReplay), WalError> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(&path)?;
+        let mut bytes = vec![];
+        file.read_to_end(&mut bytes)?;
+
+        let (operations, committed) = read_frames(&bytes);
+        let replay = WalReplay {
+            operations,
+            rolled_back: (bytes.len() - committed) as u64,
+        };
+        if replay.rolled_back > 0 {
+            file.set_len(committed as u64)?;
+            file.sync_all()?;
+        }
+        Ok((Self::new(path, file, committed as u64), replay))
+    }
+
+    /// Creates a log over a file holding `len` bytes of committed changes
+    fn new(path: PathBuf, file: File, len: u64) -> Self {
+        let group = Arc::new(GroupCommit {
+            file,
+            state: Mutex::new(SyncState {
+                len,
+                committed_len: len,
+                ..SyncState::default()
+            }),
+            synced: Condvar::new(),
+        });
+        Self { path, group }
+    }
+
+    /// Sets how long the first writer waiting for its change to be committed waits for more
+    /// changes to be written before syncing the log, so they share the sync. Waiting no time at
+    /// all still shares a sync between every change written while the log is being synced.
+    pub fn with_commit_window(self, window: Duration) -> Self {
+        self.group.state.lock().expect("log poisoned").window = window;
+        self
+    }
+
+    /// Reads the committed changes of the log at the given path, in order, without rolling back
+    /// the change being written. A log that does not exist holds no changes.
+    pub fn read(path: impl AsRef<Path>) -> Result<Vec<Operation>, WalError> {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(read_frames(&bytes).0)
+    }
+
+    /// Reads the changes written to the log that were not rolled back, in order. Unlike
+    /// [`read`](Self::read), this leaves out rolled back changes that could not be cut from the
+    /// log yet.
+    pub fn read_written(&self) -> Result<Vec<Operation>, WalError> {
+        let state = self.group.state.lock().expect("log poisoned");
+        let mut bytes = vec![];
+        File::open(&self.path)?
+            .take(state.len)
+            .read_to_end(&mut bytes)?;
+        Ok(read_frames(&bytes).0)
+    }
+
+    /// Gets the file the log is stored in
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Gets the number of times the log was synced to commit changes
+    pub fn syncs(&self) -> u64 {
+        self.group.state.lock().expect("log poisoned").syncs
+    }
+
+    /// Gets the number of bytes written to the log
+    pub fn len(&self) -> Result<u64, WalError> {
+        Ok(self.group.file.metadata()?.len())
+    }
+
+    /// Checks whether nothing was written to the log
+    pub fn is_empty(&self) -> Result<bool, WalError> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Starts merging every change written to the log so far into a single change, so the log
+    /// should be [settled](Self::settle) first, as the merge fails to finish if any of them is
+    /// rolled back. Changes written from now on are kept by the merged log, as long as no change is
+    /// written while the merge [finishes](WalMerge::finish).
+    pub fn start_merge(&self) -> Result<WalMerge, WalError> {
+        let path = self.path.with_extension(MERGING_EXTENSION);
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(&path)?;
+        Ok(WalMerge {
+            path,
+            file: Some(file),
+            group: self.group.clone(),
+            merged: self.group.state.lock().expect("log poisoned").len,
+            frame: vec![],
+            written: 0,
+        })
+    }
+
+    /// Appends a change to the log, returning once it is committed
+    pub fn append(&mut self, operation: &Operation) -> Result<(), WalError> {
+        self.write(operation)?.commit()
+    }
+
+    /// Writes a change to the log, which is committed once the returned change is
+    /// [waited for](Pending::commit). Changes are committed in the order they are written.
+    pub fn write(&mut self, operation: &Operation) -> Result<Pending, WalError> {
+        let frame = encode_frame(operation)?;
+        self.write_frame(&frame)
+    }
+
+    /// Writes a change to the log like [`write`](Self::write), handing it to `then` once it is
+    /// committed. Committed changes are handed over in the order they were written, while no other
+    /// change is being committed, and rolled back changes never are.
+    pub fn write_then(
+        &mut self,
+        operation: Operation,
+        then: impl FnOnce(Operation) + Send + 'static,
+    ) -> Result<Pending, WalError> {
+        let pending = self.write(&operation)?;
+        pending.then(move || then(operation));
+        Ok(pending)
+    }
+
+    fn write_frame(&mut self, frame: &[u8]) -> Result<Pending, WalError> {
+        let mut state = self.group.state.lock().expect("log poisoned");
+        if state.truncate {
+            truncate(&self.group.file, state.len)?;
+            state.truncate = false;
+        }
+        if let Err(e) = (&self.group.file).write_all(frame) {
+            // the part of the frame that was written would roll back every change after it
+            state.truncate = truncate(&self.group.file, state.len).is_err();
+            return Err(e.into());
+        }
+        state.written += 1;
+        state.len += frame.len() as u64;
+        Ok(Pending {
+            group: self.group.clone(),
+            change: state.written,
+        })
+    }
+
+    /// Waits for every change written to the log so far to be committed or rolled back, so
+    /// nothing is left to be done for them. Fails if the last of them was rolled back.
+    pub fn settle(&self) -> Result<(), WalError> {
+        let change = self.group.state.lock().expect("log poisoned").written;
+        let pending = Pending {
+            group: self.group.clone(),
+            change,
+        };
+        pending.commit()
+    }
+}
+
+impl SyncState {
+    /// Checks whether a change was rolled back
+    fn is_rolled_back(&self, change: u64) -> bool {
+        self.rolled_back
+            .iter()
+            .any(|changes| changes.contains(&change))
+    }
+
+    /// Commits every change up to `written`, which are the first `len` bytes of the log
+    fn commit(&mut self, written: u64, len: u64) {
+        self.committed = written;
+        self.committed_len = len;
+        while self
+            .then
+            .front()
+            .is_some_and(|(change, _)| *change <= written)
+        {
+            let (_, OnCommit(then)) = self.then.pop_front().expect("a change was committed");
+            then();
+        }
+    }
+
+    /// Rolls back every change written since the last sync that committed changes, as it is
+    /// unknown which of them made it to disk
+    fn roll_back(&mut self, file: &File) {
+        if self.written > self.committed {
+            self.rolled_back.push(self.committed + 1..=self.written);
+        }
+        self.then.clear();
+        self.len = self.committed_len;
+        self.truncate = truncate(file, self.len).is_err();
+    }
+}
+
+impl Pending {
+    /// Does something once the change is committed, or right away if it already is, such as when
+    /// the change was made after it was written. Nothing is done if the change is rolled back.
+    /// Must be called before the next change is written to the log, so what is done for changes
+    /// is done in the order they were written.
+    pub fn then(&self, then: impl FnOnce() + Send + 'static) {
+        let mut state = self.group.state.lock().expect("log poisoned");
+        if state.is_rolled_back(self.change) {
+            return;
+        }
+        if state.committed >= self.change {
+            then();
+        } else {
+            state
+                .then
+                .push_back((self.change, OnCommit(Box::new(then))));
+        }
+    }
+
+    /// Waits for the change to be committed, syncing the log unless another writer already is
+    pub fn commit(self) -> Result<(), WalError> {
+        let group = &*self.group;
+        let mut state = group.state.lock().expect("log poisoned");
+        loop {
+            if state.is_rolled_back(self.change) {
+                return Err(WalError::SyncFailed);
+            }
+            if state.committed >= self.change {
+                return Ok(());
+            }
+            if state.syncing {
+                state = group.synced.wait(state).expect("log poisoned");
+                continue;
+            }
+            state.syncing = true;
+            let window = state.window;
+            drop(state);
+            if !window.is_zero() {
+                std::thread::sleep(window);
+            }
+            // every change written before syncing is committed by it
+            let (written, len) = {
+                let state = group.state.lock().expect("log poisoned");
+                (state.written, state.len)
+            };
+            let synced = group.file.sync_data();
+            state = group.state.lock().expect("log poisoned");
+            state.syncing = false;
+            state.syncs += 1;
+            match synced {
+                Ok(()) => state.commit(written, len),
+                Err(_) => state.roll_back(&group.file),
+            }
+            group.synced.notify_all();
+            synced?;
+        }
+    }
+}
+
+impl WalMerge {
+    /// Sets the change the log is merged into, which is written by [`write_some`](Self::write_some)
+    pub fn set_merged(&mut self, operation: &Operation) -> Result<(), WalError> {
+        self.frame = encode_frame(operation)?;
+        self.written = 0;
+        Ok(())
+    }
+
+    /// Gets the number of bytes of the change the log is merged into
+    pub fn len(&self) -> usize {
+        self.frame.len()
+    }
+
+    /// Checks whether the change the log is merged into was not set yet
+    pub fn is_empty(&self) -> bool {
+        self.frame.is_empty()
+    }
+
+    /// Gets the number of bytes of the change the log is merged into written so far
+    pub fn written(&self) -> usize {
+        self.written
+    }
+
+    /// Writes up to `max` more bytes of the change the log is merged into, returning the number
+    /// of bytes written, which is 0 once all of it is
+    pub fn write_some(&mut self, max: usize) -> Result<usize, WalError> {
+        let end = self.frame.len().min(self.written.saturating_add(max));
+        let file = self.file.as_mut().expect("merges are only finished once");
+        file.write_all(&self.frame[self.written..end])?;
+        let written = end - self.written;
+        self.written = end;
+        Ok(written)
+    }
+
+    /// Finishes the merge, replacing the log with the merged log. The changes written to the log
+    /// since the merge started are committed or rolled back first, and those committed are copied
+    /// after the merged change, so no change may be written to the log until this returns. Returns
+    /// the merged log, along with the number of changes in it.
+    pub fn finish(mut self, wal: &Wal) -> Result<(Wal, u64), WalError> {
+        if !Arc::ptr_eq(&self.group, &wal.group) {
+            return Err(WalError::Replaced);
+        }
+        while self.write_some(usize::MAX)? > 0 {}
+        // changes that can not be committed are rolled back, which settles them just as well
+        let _ = wal.settle();
+        let len = wal.group.state.lock().expect("log poisoned").committed_len;
+        if len < self.merged {
+            // the merged change holds changes that are no longer in the log
+            return Err(WalError::RolledBack);
+        }
+        let mut since = vec![];
+        let mut log = File::open(&wal.path)?;
+        log.seek(SeekFrom::Start(self.merged))?;
+        log.take(len - self.merged).read_to_end(&mut since)?;
+        let (changes, _) = read_frames(&since);
+
+        let mut file = self.file.take().expect("merges are only finished once");
+        file.write_all(&since)?;
+        file.sync_all()?;
+        if let Err(e) = std::fs::rename(&self.path, &wal.path) {
+            self.file = Some(file);
+            return Err(e.into());
+        }
+        if let Some(dir) = wal.path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+        let window = wal.group.state.lock().expect("log poisoned").window;
+        let len = file.metadata()?.len();
+        let merged = Wal::new(wal.path.clone(), file, len).with_commit_window(window);
+        Ok((merged, 1 + changes.len() as u64))
+    }
+}
+
+impl Drop for WalMerge {
+    fn drop(&mut self) {
+        if self.file.is_some() {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Cuts a log down to its first `len` bytes, so the next change is written right after them even
+/// if the log was not opened to append
+fn truncate(file: &File, len: u64) -> io::Result<()> {
+    file.set_len(len)?;
+    (&*file).seek(SeekFrom::Start(len))?;
+    Ok(())
+}
+
+/// Encodes a change as a frame of the log
+fn encode_frame(operation: &Operation) -> Result<Vec<u8>, WalError> {
+    let payload = postcard::to_stdvec(operation)?;
+    let len = u32::try_from(payload.len()).map_err(|_| WalError::TooLarge(payload.len()))?;
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.extend_from_slice(&len.to_le_bytes());
+    frame.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+/// Reads every committed change, along with the length of the frames holding them
+fn read_frames(bytes: &[u8]) -> (Vec<Operation>, usize) {
+    let mut operations = vec![];
+    let mut committed = 0;
+    while let Some((operation, len)) = read_frame(&bytes[committed..]) {
+        operations.push(operation);
+        committed += len;
+    }
+    (operations, committed)
+}
+
+/// Reads the change at the start of the bytes, along with the length of its frame, or `None` if
+/// the frame is incomplete or corrupt
+fn read_frame(bytes: &[u8]) -> Option<(Operation, usize)> {
+    let header = bytes.get(..HEADER_LEN)?;
+    let len = u32::from_le_bytes(header[..4].try_into().expect("4 bytes")) as usize;
+    let checksum = u32::from_le_bytes(header[4..].try_into().expect("4 bytes"));
+    let payload = bytes.get(HEADER_LEN..HEADER_LEN + len)?;
+    if crc32fast::hash(payload) != checksum {
+        return None;
+    }
+    let operation = postcard::from_bytes(payload).ok()?;
+    Some((operation, HEADER_LEN + len))
+}
+
+/// How an index was recovered when the daemon started
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexRecovery {
+    pub index: String,
+    /// The committed changes that were replayed
+    pub replayed: usize,
+    /// The committed changes that could not be applied again
+    pub failed: usize,
+    /// The bytes of incomplete changes that were rolled back
+    pub rolled_back: u64,
+    /// The rows of the index once it was recovered
+    pub rows: usize,
+}
+
+impl Display for IndexRecovery {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "index {:?}: replayed {} changes, {} rows",
+            self.index, self.replayed, self.rows
+        )?;
+        if self.failed > 0 {
+            write!(f, ", {} changes could not be applied", self.failed)?;
+        }
+        if self.rolled_back > 0 {
+            write!(
+                f,
+                ", rolled back {} bytes of incomplete changes",
+                self.rolled_back
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// A write-ahead log could not be read or written
+#[derive(Debug, Error)]
+pub enum WalError {
+    #[error("A change of {0} bytes is too large to log")]
+    TooLarge(usize),
+    #[error("The log could not be synced, so the change was rolled back")]
+    SyncFailed,
+    #[error("The log was replaced while it was merged")]
+    Replaced,
+    #[error("Merged changes were rolled back while the log was merged")]
+    RolledBack,
+    #[error(transparent)]
+    PostcardError(#[from] postcard::Error),
+    #[error(transparent)]
+    IoError(#[from] io::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Barrier;
+
+    use docatlas_core::fields::FieldData;
+
+    use super::*;
+
+    fn delete(key: &str) -> Operation {
+        Operation::Delete {
+            index: "books".to_string(),
+            key: FieldData::Bytes(key.as_bytes().into()),
+        }
+    }
+
+    fn keys(operations: Vec<Operation>) -> Vec<String> {
+        operations
+            .into_iter()
+            .map(|operation| match operation {
+                Operation::Delete {
+                    key: FieldData::Bytes(key),
+                    ..
+                } => String::from_utf8(key.to_vec()).unwrap(),
+                operation => panic!("unexpected change {operation:?}"),
+            })
+            .collect()
+    }
+
+    /// Fails the sync of a change written between two committed changes
+    fn fail_sync_between_changes(wal: &mut Wal) {
+        wal.append(&delete("b1")).unwrap();
+        let committed = wal.len().unwrap();
+        let lost = wal
+            .write_then(delete("b2"), |_| panic!("b2 was rolled back"))
+            .unwrap();
+        wal.group.state.lock().unwrap().roll_back(&wal.group.file);
+        assert!(matches!(lost.commit(), Err(WalError::SyncFailed)));
+        assert_eq!(wal.len().unwrap(), committed);
+        wal.append(&delete("b3")).unwrap();
+    }
+
+    #[test]
+    fn rolls_back_incomplete_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+        let (mut wal, replay) = Wal::open(&path).unwrap();
+        assert!(replay.operations.is_empty());
+        wal.append(&delete("b1")).unwrap();
+        wal.append(&delete("b2")).unwrap();
+        drop(wal);
+
+        // a crash while writing leaves half a frame behind
+        let committed = std::fs::metadata(&path).unwrap().len();
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[12, 0, 0, 0, 1, 2]).unwrap();
+        drop(file);
+        // reading the log leaves the change being written alone
+        assert_eq!(Wal::read(&path).unwrap().len(), 2);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), committed + 6);
+
+        let (mut wal, replay) = Wal::open(&path).unwrap();
+        assert_eq!(replay.operations.len(), 2);
+        assert_eq!(replay.rolled_back, 6);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), committed);
+        wal.append(&delete("b3")).unwrap();
+        drop(wal);
+
+        let (_, replay) = Wal::open(&path).unwrap();
+        assert_eq!(replay.operations.len(), 3);
+        assert_eq!(replay.rolled_back, 0);
+
+        // a corrupt change rolls back every change after it
+        let mut bytes = std::fs::read(&path).unwrap();
+        bytes[HEADER_LEN + 1] ^= 0xff;
+        std::fs::write(&path, bytes).unwrap();
+        let (_, replay) = Wal::open(&path).unwrap();
+        assert!(replay.operations.is_empty());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn concurrent_changes_share_syncs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+        let (wal, _) = Wal::open(&path).unwrap();
+        let wal = Mutex::new(wal);
+        // every writer writes its change before any of them waits for it
+        let written = Barrier::new(8);
+        std::thread::scope(|scope| {
+            for writer in 0..8 {
+                let (wal, written) = (&wal, &written);
+                scope.spawn(move || {
+                    let pending = wal
+                        .lock()
+                        .unwrap()
+                        .write(&delete(&format!("b{writer}")))
+                        .unwrap();
+                    written.wait();
+                    pending.commit().unwrap();
+                });
+            }
+        });
+        let mut wal = wal.into_inner().unwrap();
+        assert_eq!(wal.syncs(), 1);
+        assert_eq!(Wal::read(&path).unwrap().len(), 8);
+
+        // a committed change needs no sync of its own
+        let syncs = wal.syncs();
+        let first = wal.write(&delete("b8")).unwrap();
+        let second = wal.write(&delete("b9")).unwrap();
+        second.commit().unwrap();
+        first.commit().unwrap();
+        assert_eq!(wal.syncs(), syncs + 1);
+    }
+
+    #[test]
+    fn act_on_committed_changes_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut wal, _) = Wal::open(dir.path().join("wal.log")).unwrap();
+        let committed = Arc::new(Mutex::new(vec![]));
+        let then = || {
+            let committed = committed.clone();
+            move |operation| committed.lock().unwrap().push(format!("{operation:?}"))
+        };
+        let first = wal.write_then(delete("b1"), then()).unwrap();
+        let second = wal.write_then(delete("b2"), then()).unwrap();
+        assert!(committed.lock().unwrap().is_empty());
+        second.commit().unwrap();
+        assert_eq!(
+            *committed.lock().unwrap(),
+            [format!("{:?}", delete("b1")), format!("{:?}", delete("b2"))]
+        );
+        first.commit().unwrap();
+        let third = wal.write_then(delete("b3"), then()).unwrap();
+        wal.settle().unwrap();
+        assert_eq!(committed.lock().unwrap().len(), 3);
+        third.commit().unwrap();
+
+        // what is done for a change that is already committed is done right away
+        let fourth = wal.write(&delete("b4")).unwrap();
+        wal.settle().unwrap();
+        let then = then();
+        fourth.then(move || then(delete("b4")));
+        assert_eq!(committed.lock().unwrap().len(), 4);
+        fourth.commit().unwrap();
+    }
+
+    #[test]
+    fn roll_back_changes_whose_sync_failed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+        let (mut wal, _) = Wal::open(&path).unwrap();
+        // changes written after the rollback are committed as usual
+        fail_sync_between_changes(&mut wal);
+        assert_eq!(keys(Wal::read(&path).unwrap()), ["b1", "b3"]);
+
+        // logs that were created or merged rather than opened write where the rollback left them
+        let mut wal = Wal::create(&path).unwrap();
+        fail_sync_between_changes(&mut wal);
+        drop(wal);
+        let (wal, replay) = Wal::open(&path).unwrap();
+        assert_eq!(replay.rolled_back, 0);
+        assert_eq!(keys(replay.operations), ["b1", "b3"]);
+
+        let mut merge = wal.start_merge().unwrap();
+        merge.set_merged(&delete("merged")).unwrap();
+        let (mut wal, _) = merge.finish(&wal).unwrap();
+        fail_sync_between_changes(&mut wal);
+        drop(wal);
+        let (_, replay) = Wal::open(&path).unwrap();
+        assert_eq!(replay.rolled_back, 0);
+        assert_eq!(keys(replay.operations), ["merged", "b1", "b3"]);
+    }
+
+    #[test]
+    fn leave_out_changes_that_could_not_be_cut() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+        let (mut wal, _) = Wal::open(&path).unwrap();
+        wal.append(&delete("b1")).unwrap();
+        let committed = wal.len().unwrap();
+        let lost = wal.write(&delete("b2")).unwrap();
+        {
+            // the rollback of the change fails to cut it from the log
+            let mut state = wal.group.state.lock().unwrap();
+            let lost = state.committed + 1..=state.written;
+            state.rolled_back.push(lost);
+            state.len = committed;
+            state.truncate = true;
+        }
+        assert!(matches!(lost.commit(), Err(WalError::SyncFailed)));
+        assert_eq!(keys(Wal::read(&path).unwrap()), ["b1", "b2"]);
+        assert_eq!(keys(wal.read_written().unwrap()), ["b1"]);
+
+        wal.append(&delete("b3")).unwrap();
+        assert_eq!(keys(Wal::read(&path).unwrap()), ["b1", "b3"]);
+        assert_eq!(keys(wal.read_written().unwrap()), ["b1", "b3"]);
+    }
+
+    #[test]
+    fn fail_merges_whose_changes_were_rolled_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+        let (mut wal, _) = Wal::open(&path).unwrap();
+        wal.append(&delete("b1")).unwrap();
+        let lost = wal.write(&delete("b2")).unwrap();
+        let merge = wal.start_merge().unwrap();
+        // the sync of a change that is merged fails while merging
+        wal.group.state.lock().unwrap().roll_back(&wal.group.file);
+        assert!(matches!(wal.settle(), Err(WalError::SyncFailed)));
+        assert!(matches!(lost.commit(), Err(WalError::SyncFailed)));
+        assert!(matches!(merge.finish(&wal), Err(WalError::RolledBack)));
+        assert!(!dir.path().join("wal.log.merging").exists());
+        assert_eq!(keys(Wal::read(&path).unwrap()), ["b1"]);
+
+        // changes rolled back after the merge started are left out of the merged log
+        let mut merge = wal.start_merge().unwrap();
+        merge.set_merged(&delete("merged")).unwrap();
+        let lost = wal.write(&delete("b3")).unwrap();
+        wal.group.state.lock().unwrap().roll_back(&wal.group.file);
+        assert!(matches!(lost.commit(), Err(WalError::SyncFailed)));
+        let (_, changes) = merge.finish(&wal).unwrap();
+        assert_eq!(changes, 1);
+        assert_eq!(keys(Wal::read(&path).unwrap()), ["merged"]);
+    }
+
+    #[test]
+    fn merge_logs() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("wal.log");
+        let (mut wal, _) = Wal::open(&path).unwrap();
+        for key in ["b1", "b2", "b3"] {
+            wal.append(&delete(key)).unwrap();
+        }
+        let mut merge = wal.start_merge().unwrap();
+        merge.set_merged(&delete("merged")).unwrap();
+        assert_eq!(merge.write_some(4).unwrap(), 4);
+        // changes written while merging are kept
+        wal.append(&delete("b4")).unwrap();
+        let (mut merged, changes) = merge.finish(&wal).unwrap();
+        assert_eq!(changes, 2);
+        assert!(!dir.path().join("wal.log.merging").exists());
+        merged.append(&delete("b5")).unwrap();
+        assert_eq!(keys(Wal::read(&path).unwrap()), ["merged", "b4", "b5"]);
+
+        // a log replaced while merging is left alone
+        let merge = merged.start_merge().unwrap();
+        let replaced = Wal::create(&path).unwrap();
+        assert!(matches!(merge.finish(&replaced), Err(WalError::Replaced)));
+        assert!(!dir.path().join("wal.log.merging").exists());
+        assert!(replaced.is_empty().unwrap());
+    }
+}
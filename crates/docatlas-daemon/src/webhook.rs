@@ -0,0 +1,199 @@
+//! Asks a web service, such as a policy engine, whether users may do what they request. Requests
+//! are only allowed if both the roles of the user and the web service allow them.
+//!
+//! The service is sent a json document describing the request, the way the data api of Open
+//! Policy Agent expects it:
+//!
+//! ```json
+//! {"input": {"user": "alice", "groups": ["ops"], "roles": [], "permission": "read", "index": "logs", "field": null}}
+//! ```
+//!
+//! It allows the request by answering `{"result": true}`, or `{"result": {"allow": true}}`.
+//! Any other answer, or no answer in time, denies it.
+
+use std::io;
+use std::io::ErrorKind;
+use std::time::Duration;
+
+use docatlas_core::auth::authorization::{AuthorizationService, Permission, Resource};
+use docatlas_core::auth::users::User;
+use hyper::client::HttpConnector;
+use hyper::header::CONTENT_TYPE;
+use hyper::http::uri::InvalidUri;
+use hyper::{Body, Client, Request, Uri};
+use serde::Deserialize;
+use serde_json::json;
+use thiserror::Error;
+
+/// How long to wait for the web service to answer unless configured otherwise
+pub const DEFAULT_WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Asks a web service whether users may do what they request
+#[derive(Debug)]
+pub struct WebhookAuthorizationService {
+    url: Uri,
+    timeout: Duration,
+    client: Client<HttpConnector>,
+}
+
+/// The answer of the web service
+#[derive(Debug, Deserialize)]
+struct Answer {
+    result: Option<Decision>,
+}
+
+/// Whether the web service allows a request
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Decision {
+    Allow(bool),
+    Object { allow: bool },
+}
+
+impl WebhookAuthorizationService {
+    /// Creates a service asking the web service at the given http url
+    pub fn new(url: &str) -> Result<Self, WebhookError> {
+        let uri = url
+            .parse::<Uri>()
+            .map_err(|e| WebhookError::InvalidUrl(url.to_string(), e))?;
+        if uri.scheme_str() != Some("http") {
+            return Err(WebhookError::UnsupportedScheme(url.to_string()));
+        }
+        Ok(Self {
+            url: uri,
+            timeout: DEFAULT_WEBHOOK_TIMEOUT,
+            client: Client::new(),
+        })
+    }
+
+    /// Sets how long to wait for the web service to answer
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Gets the url of the web service
+    pub fn url(&self) -> &Uri {
+        &self.url
+    }
+
+    /// Sends the description of a request to the web service, and reads its answer
+    async fn ask(&self, input: serde_json::Value) -> io::Result<bool> {
+        let body = serde_json::to_vec(&json!({ "input": input }))?;
+        let request = Request::post(self.url.clone())
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+        let response = tokio::time::timeout(self.timeout, self.client.request(request))
+            .await
+            .map_err(|_| io::Error::new(ErrorKind::TimedOut, "the web service did not answer"))?
+            .map_err(io::Error::other)?;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .map_err(io::Error::other)?;
+        if !status.is_success() {
+            return Err(io::Error::other(format!(
+                "the web service answered {status}"
+            )));
+        }
+        let answer: Answer = serde_json::from_slice(&body)?;
+        Ok(matches!(
+            answer.result,
+            Some(Decision::Allow(true) | Decision::Object { allow: true })
+        ))
+    }
+}
+
+#[tonic::async_trait]
+impl AuthorizationService for WebhookAuthorizationService {
+    async fn allows(
+        &self,
+        user: &User,
+        permission: Permission,
+        resource: &Resource,
+    ) -> io::Result<bool> {
+        let permission = match permission {
+            Permission::Read => "read",
+            Permission::Write => "write",
+            Permission::Manage => "manage",
+        };
+        let (index, field) = match resource {
+            Resource::Index(index) => (Some(index), None),
+            Resource::Field { index, field } => (Some(index), Some(field)),
+            Resource::Cluster => (None, None),
+        };
+        self.ask(json!({
+            "user": user.name(),
+            "groups": user.groups(),
+            "roles": user.roles(),
+            "permission": permission,
+            "index": index,
+            "field": field,
+        }))
+        .await
+    }
+}
+
+/// The url of the web service is invalid
+#[derive(Debug, Error)]
+pub enum WebhookError {
+    #[error("invalid url {0:?}: {1}")]
+    InvalidUrl(String, InvalidUri),
+    #[error("url {0:?} is not an http url")]
+    UnsupportedScheme(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::routing::post;
+    use axum::{Json, Router};
+    use docatlas_core::auth::users::UserFactory;
+    use std::net::TcpListener;
+
+    #[tokio::test]
+    async fn asks_web_service() {
+        // allows alice to do anything, and everyone else to read
+        let policy = Router::new().route(
+            "/v1/data/docatlas/allow",
+            post(|Json(body): Json<serde_json::Value>| async move {
+                let input = &body["input"];
+                let allow = input["user"] == "alice" || input["permission"] == "read";
+                Json(json!({ "result": { "allow": allow } }))
+            }),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(policy.into_make_service());
+        tokio::spawn(server);
+
+        let url = format!("http://{address}/v1/data/docatlas/allow");
+        let service = WebhookAuthorizationService::new(&url).unwrap();
+        let logs = Resource::Index("logs".to_string());
+        let alice = UserFactory.create("alice");
+        let bob = UserFactory.create("bob");
+        assert!(service
+            .allows(&alice, Permission::Manage, &logs)
+            .await
+            .unwrap());
+        assert!(service.allows(&bob, Permission::Read, &logs).await.unwrap());
+        assert!(!service
+            .allows(&bob, Permission::Write, &logs)
+            .await
+            .unwrap());
+
+        let missing = format!("http://{address}/missing");
+        let service = WebhookAuthorizationService::new(&missing).unwrap();
+        assert!(service
+            .allows(&alice, Permission::Read, &logs)
+            .await
+            .is_err());
+        assert!(matches!(
+            WebhookAuthorizationService::new("https://opa:8181"),
+            Err(WebhookError::UnsupportedScheme(_))
+        ));
+    }
+}
@@ -0,0 +1,132 @@
+//! Derive macros for docatlas.
+//!
+//! These are re-exported by `docatlas-core` when its `derive` feature is enabled.
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Error, Fields, LitInt, LitStr};
+
+/// Derives `ToFields` for a struct with named members. See `docatlas_core::fields::ToFields` for
+/// the supported attributes.
+#[proc_macro_derive(ToFields, attributes(field))]
+pub fn derive_to_fields(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    to_fields(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+/// The parsed `#[field(...)]` attribute of a struct member
+struct FieldAttr {
+    name: Option<String>,
+    kind: Option<(String, Span)>,
+    size: Option<usize>,
+    skip: bool,
+}
+
+fn to_fields(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let Data::Struct(data) = &input.data else {
+        return Err(Error::new_spanned(
+            &input,
+            "ToFields can only be derived for structs",
+        ));
+    };
+    let Fields::Named(named) = &data.fields else {
+        return Err(Error::new_spanned(
+            &data.fields,
+            "ToFields can only be derived for structs with named fields",
+        ));
+    };
+
+    let mut entries = vec![];
+    for member in &named.named {
+        let ident = member.ident.as_ref().expect("named fields have an ident");
+        let attr = parse_attr(member)?;
+        if attr.skip {
+            continue;
+        }
+        let Some((kind, span)) = attr.kind else {
+            return Err(Error::new_spanned(
+                member,
+                "missing #[field(kind = \"...\")] attribute",
+            ));
+        };
+        let kind_name = kind.as_str();
+        let kind = match (kind_name, attr.size) {
+            ("keyword", Some(size)) => quote!(::docatlas_core::fields::FieldKind::Keyword(#size)),
+            ("text", Some(size)) => quote!(::docatlas_core::fields::FieldKind::Text(#size)),
+            ("number", size) => {
+                let size = size.unwrap_or(8);
+                quote!(::docatlas_core::fields::FieldKind::Number(#size))
+            }
+            ("i64", None) => quote!(::docatlas_core::fields::FieldKind::I64),
+            ("u64", None) => quote!(::docatlas_core::fields::FieldKind::U64),
+            ("f64", None) => quote!(::docatlas_core::fields::FieldKind::F64),
+            ("blob", None) => quote!(::docatlas_core::fields::FieldKind::Blob),
+            ("i64" | "u64" | "f64" | "blob", Some(_)) => {
+                return Err(Error::new(span, format!("{kind} fields have no size")))
+            }
+            ("keyword" | "text", None) => {
+                return Err(Error::new(span, format!("{kind} fields require a size")))
+            }
+            _ => {
+                return Err(Error::new(
+                    span,
+                    format!(
+                        "unknown field kind {kind:?}, expected keyword, text, number, i64, u64, \
+                         f64 or blob"
+                    ),
+                ))
+            }
+        };
+        let mut data = quote!(::docatlas_core::fields::ToFieldData::to_field_data(&self.#ident));
+        if kind_name == "blob" {
+            // bytes are only stored outside of the row as blobs
+            data = quote!(#data.map(::docatlas_core::fields::FieldData::into_blob));
+        }
+        let name = attr.name.unwrap_or_else(|| ident.to_string());
+        entries.push(quote! {
+            fields.insert(#name, ::docatlas_core::fields::Field::new(#kind, #data));
+        });
+    }
+
+    let ident = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::docatlas_core::fields::ToFields for #ident #ty_generics #where_clause {
+            fn to_fields(&self) -> ::docatlas_core::fields::Fields {
+                let mut fields = ::docatlas_core::fields::Fields::new();
+                #(#entries)*
+                fields
+            }
+        }
+    })
+}
+
+fn parse_attr(member: &syn::Field) -> syn::Result<FieldAttr> {
+    let mut attr = FieldAttr {
+        name: None,
+        kind: None,
+        size: None,
+        skip: false,
+    };
+    for attribute in member.attrs.iter().filter(|a| a.path().is_ident("field")) {
+        attribute.parse_nested_meta(|meta| {
+            if meta.path.is_ident("skip") {
+                attr.skip = true;
+            } else if meta.path.is_ident("name") {
+                attr.name = Some(meta.value()?.parse::<LitStr>()?.value());
+            } else if meta.path.is_ident("kind") {
+                let kind = meta.value()?.parse::<LitStr>()?;
+                attr.kind = Some((kind.value(), kind.span()));
+            } else if meta.path.is_ident("size") {
+                attr.size = Some(meta.value()?.parse::<LitInt>()?.base10_parse()?);
+            } else {
+                return Err(meta.error("expected `name`, `kind`, `size` or `skip`"));
+            }
+            Ok(())
+        })?;
+    }
+    Ok(attr)
+}
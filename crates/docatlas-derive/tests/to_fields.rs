@@ -0,0 +1,65 @@
+use docatlas_core::fields::{FieldData, FieldKind, ToFields};
+
+#[derive(ToFields)]
+struct Article {
+    #[field(kind = "keyword", size = 16)]
+    id: String,
+    #[field(kind = "text", size = 256)]
+    body: &'static str,
+    #[field(kind = "u64", name = "view_count")]
+    views: u64,
+    #[field(kind = "number", size = 4)]
+    rating: Option<f32>,
+    #[field(skip)]
+    #[allow(dead_code)]
+    cached: bool,
+}
+
+#[derive(ToFields)]
+struct Attachment {
+    #[field(kind = "keyword", size = 8)]
+    id: &'static str,
+    #[field(kind = "blob")]
+    content: Vec<u8>,
+}
+
+#[test]
+fn derived_fields() {
+    let article = Article {
+        id: "a-1".to_string(),
+        body: "Hello world",
+        views: 12,
+        rating: None,
+        cached: true,
+    };
+
+    let fields = article.to_fields();
+    assert_eq!(fields.len(), 4);
+    assert_eq!(fields.get("id").unwrap().kind(), &FieldKind::Keyword(16));
+    assert_eq!(
+        fields.get("body").unwrap().data(),
+        &[FieldData::Bytes(b"Hello world"[..].into())]
+    );
+    assert_eq!(fields.get("view_count").unwrap().kind(), &FieldKind::U64);
+    assert_eq!(
+        fields.get("view_count").unwrap().data(),
+        &[FieldData::U64(12)]
+    );
+    assert!(fields.get("rating").unwrap().data().is_empty());
+    assert!(fields.get("cached").is_none());
+}
+
+#[test]
+fn blob_members_are_blobs() {
+    let attachment = Attachment {
+        id: "a-1",
+        content: b"some attachment".to_vec(),
+    };
+
+    let fields = attachment.to_fields();
+    assert_eq!(fields.get("content").unwrap().kind(), &FieldKind::Blob);
+    assert_eq!(
+        fields.get("content").unwrap().data(),
+        &[FieldData::Blob(b"some attachment"[..].into())]
+    );
+}